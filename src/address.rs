@@ -0,0 +1,205 @@
+use crate::{
+    base58::{decode_base58_checksum, encode_base58_checksum, hash160},
+    bech32::{decode_segwit_address, encode_segwit_address},
+    network::Network,
+    script::Script,
+    secp256k1::S256Point,
+};
+use std::{fmt, str::FromStr};
+
+/// What an address pays to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Payload {
+    P2pkh([u8; 20]),
+    P2sh([u8; 20]),
+    P2wpkh([u8; 20]),
+    P2wsh([u8; 32]),
+    P2tr([u8; 32]),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressError {
+    InvalidEncoding,
+    UnknownPrefix,
+    UnknownHrp,
+    UnsupportedWitnessProgram,
+}
+
+/// A Bitcoin address.
+///
+/// Base58 addresses do not distinguish testnet from regtest, so they are
+/// parsed as `Network::Testnet`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Address {
+    payload: Payload,
+    network: Network,
+}
+
+impl Address {
+    pub fn new(payload: Payload, network: Network) -> Self {
+        Self { payload, network }
+    }
+
+    pub fn get_payload(&self) -> Payload {
+        self.payload
+    }
+
+    pub fn get_network(&self) -> Network {
+        self.network
+    }
+
+    pub fn p2pkh(point: &S256Point, compressed: bool, network: Network) -> Self {
+        let h160 = point.hash160(compressed).try_into().unwrap();
+        Self::new(Payload::P2pkh(h160), network)
+    }
+
+    pub fn p2wpkh(point: &S256Point, network: Network) -> Self {
+        let h160 = point.hash160(true).try_into().unwrap();
+        Self::new(Payload::P2wpkh(h160), network)
+    }
+
+    /// P2SH address of `redeem_script`.
+    pub fn p2sh(redeem_script: &Script, network: Network) -> Self {
+        let h160 = hash160(redeem_script.as_bytes()).try_into().unwrap();
+        Self::new(Payload::P2sh(h160), network)
+    }
+
+    pub fn script_pubkey(&self) -> Script {
+        match &self.payload {
+            Payload::P2pkh(h160) => Script::p2pkh(h160),
+            Payload::P2sh(h160) => Script::p2sh(h160),
+            Payload::P2wpkh(h160) => Script::p2wpkh(h160),
+            Payload::P2wsh(h256) => Script::p2wsh(h256),
+            Payload::P2tr(key) => Script::p2tr(key),
+        }
+    }
+
+    /// Recovers the address of a standard output script.
+    pub fn from_script_pubkey(script_pubkey: &Script, network: Network) -> Option<Self> {
+        let raw = script_pubkey.as_bytes();
+        let payload = if script_pubkey.is_p2pkh() {
+            Payload::P2pkh(raw[3..23].try_into().unwrap())
+        } else if script_pubkey.is_p2sh() {
+            Payload::P2sh(raw[2..22].try_into().unwrap())
+        } else if script_pubkey.is_p2wpkh() {
+            Payload::P2wpkh(raw[2..].try_into().unwrap())
+        } else if script_pubkey.is_p2wsh() {
+            Payload::P2wsh(raw[2..].try_into().unwrap())
+        } else if script_pubkey.is_p2tr() {
+            Payload::P2tr(raw[2..].try_into().unwrap())
+        } else {
+            return None;
+        };
+        Some(Self::new(payload, network))
+    }
+
+    pub fn parse(s: &str) -> Result<Self, AddressError> {
+        if let Some((hrp, version, program)) = decode_segwit_address(s) {
+            let network = [Network::Mainnet, Network::Testnet, Network::Regtest]
+                .into_iter()
+                .find(|network| network.bech32_hrp() == hrp)
+                .ok_or(AddressError::UnknownHrp)?;
+            let payload = match (version, program.len()) {
+                (0, 20) => Payload::P2wpkh(program.try_into().unwrap()),
+                (0, 32) => Payload::P2wsh(program.try_into().unwrap()),
+                (1, 32) => Payload::P2tr(program.try_into().unwrap()),
+                _ => return Err(AddressError::UnsupportedWitnessProgram),
+            };
+            return Ok(Self::new(payload, network));
+        }
+        let b = decode_base58_checksum(s).ok_or(AddressError::InvalidEncoding)?;
+        if b.len() != 21 {
+            return Err(AddressError::InvalidEncoding);
+        }
+        let h160 = b[1..].try_into().unwrap();
+        match b[0] {
+            0x00 => Ok(Self::new(Payload::P2pkh(h160), Network::Mainnet)),
+            0x6f => Ok(Self::new(Payload::P2pkh(h160), Network::Testnet)),
+            0x05 => Ok(Self::new(Payload::P2sh(h160), Network::Mainnet)),
+            0xc4 => Ok(Self::new(Payload::P2sh(h160), Network::Testnet)),
+            _ => Err(AddressError::UnknownPrefix),
+        }
+    }
+}
+
+impl FromStr for Address {
+    type Err = AddressError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s)
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let hrp = self.network.bech32_hrp();
+        let encoded = match &self.payload {
+            Payload::P2pkh(h160) => {
+                encode_base58_checksum(&[&[self.network.p2pkh_prefix()], &h160[..]].concat())
+            }
+            Payload::P2sh(h160) => {
+                encode_base58_checksum(&[&[self.network.p2sh_prefix()], &h160[..]].concat())
+            }
+            Payload::P2wpkh(h160) => encode_segwit_address(hrp, 0, h160),
+            Payload::P2wsh(h256) => encode_segwit_address(hrp, 0, h256),
+            Payload::P2tr(key) => encode_segwit_address(hrp, 1, key),
+        };
+        write!(f, "{}", encoded)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use primitive_types::U256;
+
+    #[test]
+    fn test_parse() {
+        for (s, script_pubkey) in [
+            (
+                "132F25rTsvBdp9JzLLBHP5mvGY66i1xdiM",
+                "76a914162c5ea71c0b23f5b9022ef047c4a86470a5b07088ac",
+            ),
+            (
+                "33iFwdLuRpW1uK1RTRqsoi8rR4NpDzk66k",
+                "a914162c5ea71c0b23f5b9022ef047c4a86470a5b07087",
+            ),
+            (
+                "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4",
+                "0014751e76e8199196d454941c45d1b3a323f1433bd6",
+            ),
+            (
+                "tb1qrp33g0q5c5txsp9arysrx4k6zdkfs4nce4xj0gdcccefvpysxf3q0sl5k7",
+                "00201863143c14c5166804bd19203356da136c985678cd4d27a1b8c6329604903262",
+            ),
+            (
+                "bc1p0xlxvlhemja6c4dqv22uapctqupfhlxm9h8z3k2e72q4k9hcz7vqzk5jj0",
+                "512079be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798",
+            ),
+        ] {
+            let address: Address = s.parse().unwrap();
+            assert_eq!(
+                hex::encode(address.script_pubkey().as_bytes()),
+                script_pubkey
+            );
+            assert_eq!(address.to_string(), s);
+            let network = address.get_network();
+            assert_eq!(
+                Address::from_script_pubkey(&address.script_pubkey(), network),
+                Some(address)
+            );
+        }
+        assert_eq!(
+            Address::parse("bc1zw508d6qejxtdg4y5r3zarvaryvaxxpcs"),
+            Err(AddressError::UnsupportedWitnessProgram)
+        );
+    }
+
+    #[test]
+    fn test_p2pkh_matches_s256_point_address() {
+        let point = U256::from(5002) * S256Point::get_the_generic_point();
+        let address = Address::p2pkh(&point, false, Network::Testnet);
+        assert_eq!(address.to_string(), point.address(false, true));
+        assert_eq!(address.to_string(), "mmTPbXQFxboEtNRkwfh6K51jvdtHLxGeMA");
+    }
+}
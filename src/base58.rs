@@ -1,36 +1,61 @@
-use hmac::{Hmac, Mac};
-use primitive_types::{U256, U512};
-use sha2::{Digest, Sha256};
-type HmacSha256 = Hmac<Sha256>;
 use ripemd::Ripemd160;
+use sha2::{Digest, Sha256};
 
 const BASE58_ALPHABET: &str = "123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
 
 pub fn encode_base58(b: &[u8]) -> String {
-    let mut count = 0; // number of zeros
-    for &c in b {
-        if c == 0 {
-            count += 1;
-        } else {
-            break;
+    let zeros = b.iter().take_while(|&&c| c == 0).count();
+    // little endian base58 digits, built up one byte at a time
+    let mut digits: Vec<u8> = Vec::new();
+    for &byte in b {
+        let mut carry = byte as u32;
+        for digit in digits.iter_mut() {
+            carry += (*digit as u32) << 8;
+            *digit = (carry % 58) as u8;
+            carry /= 58;
+        }
+        while carry > 0 {
+            digits.push((carry % 58) as u8);
+            carry /= 58;
+        }
+    }
+    let alphabet = BASE58_ALPHABET.as_bytes();
+    let mut ret = "1".repeat(zeros);
+    ret.extend(digits.iter().rev().map(|&d| alphabet[d as usize] as char));
+    ret
+}
+
+/// Decodes a base58 string, returning `None` on characters outside the alphabet.
+pub fn decode_base58(s: &str) -> Option<Vec<u8>> {
+    let zeros = s.chars().take_while(|&c| c == '1').count();
+    // big endian base-256 digits, built up one base58 digit at a time
+    let mut num: Vec<u8> = Vec::new();
+    for c in s.chars() {
+        let mut carry = BASE58_ALPHABET.find(c)? as u32;
+        for byte in num.iter_mut().rev() {
+            carry += *byte as u32 * 58;
+            *byte = (carry & 0xff) as u8;
+            carry >>= 8;
         }
+        while carry > 0 {
+            num.insert(0, (carry & 0xff) as u8);
+            carry >>= 8;
+        }
+    }
+    Some([vec![0; zeros], num].concat())
+}
+
+/// Decodes a base58check string and strips its 4-byte checksum.
+pub fn decode_base58_checksum(s: &str) -> Option<Vec<u8>> {
+    let b = decode_base58(s)?;
+    if b.len() < 4 {
+        return None;
     }
-    let mut num = U512::from_big_endian(&b);
-    let mut prefix = "1".to_string().repeat(count);
-    let mut result = "".to_string();
-    while !num.is_zero() {
-        let rem;
-        (num, rem) = num.div_mod(U512::from(58));
-        result = BASE58_ALPHABET
-            .chars()
-            .nth(usize::try_from(rem).unwrap())
-            .unwrap()
-            .to_string()
-            + &result;
+    let (payload, checksum) = b.split_at(b.len() - 4);
+    if hash256(payload)[..4] != *checksum {
+        return None;
     }
-    println!("{}", prefix);
-    prefix.push_str(&result);
-    prefix
+    Some(payload.to_vec())
 }
 
 pub fn hash256(b: &[u8]) -> Vec<u8> {
@@ -57,4 +82,17 @@ mod tests {
         let ret = encode_base58(&ret);
         assert_eq!(ret, "9MA8fRQrT4u8Zj8ZRd6MAiiyaxb2Y1CMpvVkHQu5hVM6")
     }
+
+    #[test]
+    fn test_decode_base58() {
+        let b = decode_base58("9MA8fRQrT4u8Zj8ZRd6MAiiyaxb2Y1CMpvVkHQu5hVM6").unwrap();
+        assert_eq!(
+            hex::encode(b),
+            "7c076ff316692a3d7eb3c3bb0f8b1488cf72e1afcd929e29307032997a838a3d"
+        );
+        let b = decode_base58_checksum("132F25rTsvBdp9JzLLBHP5mvGY66i1xdiM").unwrap();
+        assert_eq!(hex::encode(b), "00162c5ea71c0b23f5b9022ef047c4a86470a5b070");
+        assert!(decode_base58_checksum("132F25rTsvBdp9JzLLBHP5mvGY66i1xdiN").is_none());
+        assert!(decode_base58("0OIl").is_none());
+    }
 }
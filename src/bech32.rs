@@ -0,0 +1,185 @@
+const CHARSET: &str = "qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+const BECH32_CONST: u32 = 1;
+const BECH32M_CONST: u32 = 0x2bc830a3;
+
+/// Checksum variant: BIP173 bech32 for witness v0, BIP350 bech32m for v1+.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Variant {
+    Bech32,
+    Bech32m,
+}
+
+impl Variant {
+    fn constant(&self) -> u32 {
+        match self {
+            Variant::Bech32 => BECH32_CONST,
+            Variant::Bech32m => BECH32M_CONST,
+        }
+    }
+}
+
+fn polymod(values: &[u8]) -> u32 {
+    const GEN: [u32; 5] = [0x3b6a57b2, 0x26508e6d, 0x1ea119fa, 0x3d4233dd, 0x2a1462b3];
+    let mut chk: u32 = 1;
+    for &v in values {
+        let b = chk >> 25;
+        chk = ((chk & 0x1ffffff) << 5) ^ v as u32;
+        for (i, g) in GEN.iter().enumerate() {
+            if (b >> i) & 1 == 1 {
+                chk ^= g;
+            }
+        }
+    }
+    chk
+}
+
+fn hrp_expand(hrp: &str) -> Vec<u8> {
+    let mut ret: Vec<u8> = hrp.bytes().map(|b| b >> 5).collect();
+    ret.push(0);
+    ret.extend(hrp.bytes().map(|b| b & 31));
+    ret
+}
+
+/// Encodes 5-bit `data` under `hrp` with the given checksum variant.
+pub fn encode(hrp: &str, data: &[u8], variant: Variant) -> String {
+    let values = [hrp_expand(hrp), data.to_vec(), vec![0; 6]].concat();
+    let pm = polymod(&values) ^ variant.constant();
+    let checksum = (0..6).map(|i| ((pm >> (5 * (5 - i))) & 31) as u8);
+    let charset = CHARSET.as_bytes();
+    let mut ret = format!("{}1", hrp);
+    for d in data.iter().copied().chain(checksum) {
+        ret.push(charset[d as usize] as char);
+    }
+    ret
+}
+
+/// Decodes a bech32 or bech32m string into its hrp and 5-bit data.
+pub fn decode(s: &str) -> Option<(String, Vec<u8>, Variant)> {
+    if s.len() > 90 || (s.to_lowercase() != s && s.to_uppercase() != s) {
+        return None;
+    }
+    let s = s.to_lowercase();
+    let pos = s.rfind('1')?;
+    if pos < 1 || pos + 7 > s.len() {
+        return None;
+    }
+    let hrp = &s[..pos];
+    if hrp.bytes().any(|b| !(33..=126).contains(&b)) {
+        return None;
+    }
+    let data = s[pos + 1..]
+        .chars()
+        .map(|c| CHARSET.find(c).map(|d| d as u8))
+        .collect::<Option<Vec<u8>>>()?;
+    let variant = match polymod(&[hrp_expand(hrp), data.clone()].concat()) {
+        BECH32_CONST => Variant::Bech32,
+        BECH32M_CONST => Variant::Bech32m,
+        _ => return None,
+    };
+    Some((hrp.to_string(), data[..data.len() - 6].to_vec(), variant))
+}
+
+/// Regroups bits, e.g. from 8-bit bytes into 5-bit groups and back.
+pub fn convert_bits(data: &[u8], from: u32, to: u32, pad: bool) -> Option<Vec<u8>> {
+    let mut acc: u32 = 0;
+    let mut bits: u32 = 0;
+    let maxv: u32 = (1 << to) - 1;
+    let mut ret = Vec::new();
+    for &value in data {
+        if (value as u32) >> from != 0 {
+            return None;
+        }
+        acc = (acc << from) | value as u32;
+        bits += from;
+        while bits >= to {
+            bits -= to;
+            ret.push(((acc >> bits) & maxv) as u8);
+        }
+    }
+    if pad {
+        if bits > 0 {
+            ret.push(((acc << (to - bits)) & maxv) as u8);
+        }
+    } else if bits >= from || ((acc << (to - bits)) & maxv) != 0 {
+        return None;
+    }
+    Some(ret)
+}
+
+/// Encodes a segwit address (BIP173/BIP350).
+pub fn encode_segwit_address(hrp: &str, version: u8, program: &[u8]) -> String {
+    let variant = if version == 0 {
+        Variant::Bech32
+    } else {
+        Variant::Bech32m
+    };
+    let data = [vec![version], convert_bits(program, 8, 5, true).unwrap()].concat();
+    encode(hrp, &data, variant)
+}
+
+/// Decodes a segwit address into its hrp, witness version and program.
+pub fn decode_segwit_address(s: &str) -> Option<(String, u8, Vec<u8>)> {
+    let (hrp, data, variant) = decode(s)?;
+    let (&version, program) = data.split_first()?;
+    let program = convert_bits(program, 5, 8, false)?;
+    if version > 16 || program.len() < 2 || program.len() > 40 {
+        return None;
+    }
+    if version == 0 && program.len() != 20 && program.len() != 32 {
+        return None;
+    }
+    let expected = if version == 0 {
+        Variant::Bech32
+    } else {
+        Variant::Bech32m
+    };
+    if variant != expected {
+        return None;
+    }
+    Some((hrp, version, program))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_segwit_address() {
+        let (hrp, version, program) =
+            decode_segwit_address("BC1QW508D6QEJXTDG4Y5R3ZARVARY0C5XW7KV8F3T4").unwrap();
+        assert_eq!(hrp, "bc");
+        assert_eq!(version, 0);
+        assert_eq!(
+            hex::encode(&program),
+            "751e76e8199196d454941c45d1b3a323f1433bd6"
+        );
+        assert_eq!(
+            encode_segwit_address(&hrp, version, &program),
+            "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4"
+        );
+
+        let addr = "bc1p0xlxvlhemja6c4dqv22uapctqupfhlxm9h8z3k2e72q4k9hcz7vqzk5jj0";
+        let (hrp, version, program) = decode_segwit_address(addr).unwrap();
+        assert_eq!(version, 1);
+        assert_eq!(
+            hex::encode(&program),
+            "79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798"
+        );
+        assert_eq!(encode_segwit_address(&hrp, version, &program), addr);
+    }
+
+    #[test]
+    fn test_invalid_segwit_address() {
+        // BIP350: witness v1 and v16 programs with bech32 instead of bech32m checksums
+        assert!(decode_segwit_address(
+            "bc1p0xlxvlhemja6c4dqv22uapctqupfhlxm9h8z3k2e72q4k9hcz7vqh2y7hd"
+        )
+        .is_none());
+        assert!(decode_segwit_address(
+            "BC1S0XLXVLHEMJA6C4DQV22UAPCTQUPFHLXM9H8Z3K2E72Q4K9HCZ7VQ54WELL"
+        )
+        .is_none());
+        // mixed case
+        assert!(decode_segwit_address("bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kV8F3T4").is_none());
+    }
+}
@@ -0,0 +1,595 @@
+use crate::{
+    base58::{decode_base58_checksum, encode_base58_checksum},
+    network::Network,
+    secp256k1::{S256Point, N},
+    signature::PrivateKey,
+};
+use hmac::{Mac, SimpleHmac};
+use primitive_types::{U256, U512};
+use sha2::Sha512;
+use std::{fmt, str::FromStr};
+
+/// Child numbers from this one up derive hardened keys.
+pub const HARDENED: u32 = 1 << 31;
+
+const XPRV_VERSION: [u8; 4] = [0x04, 0x88, 0xad, 0xe4];
+const XPUB_VERSION: [u8; 4] = [0x04, 0x88, 0xb2, 0x1e];
+const TPRV_VERSION: [u8; 4] = [0x04, 0x35, 0x83, 0x94];
+const TPUB_VERSION: [u8; 4] = [0x04, 0x35, 0x87, 0xcf];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Bip32Error {
+    /// Not base58check, or not 78 bytes.
+    InvalidEncoding,
+    UnknownVersion,
+    /// The key is out of range or not on the curve, or a master key has a
+    /// parent.
+    InvalidKey,
+    /// Public keys can only derive unhardened children.
+    HardenedFromPublic,
+    InvalidPath,
+    /// Keys at depth 255 have no children, as the depth is one byte.
+    MaxDepth,
+}
+
+fn hmac_sha512(key: &[u8], data: &[u8]) -> [u8; 64] {
+    let mut mac = SimpleHmac::<Sha512>::new_from_slice(key).unwrap();
+    mac.update(data);
+    mac.finalize().into_bytes().into()
+}
+
+fn order() -> U256 {
+    U256::from_str_radix(N, 16).unwrap()
+}
+
+/// Splits an HMAC into the key tweak, checked to be below the curve order,
+/// and the chain code.
+fn split(i: &[u8; 64]) -> Result<(U256, [u8; 32]), Bip32Error> {
+    let tweak = U256::from_big_endian(&i[..32]);
+    if tweak >= order() {
+        return Err(Bip32Error::InvalidKey);
+    }
+    Ok((tweak, i[32..].try_into().unwrap()))
+}
+
+/// Parses a derivation path like `m/84'/1'/0'/0`, with `h` also marking
+/// hardened steps. The `m/` is optional.
+pub fn parse_path(s: &str) -> Result<Vec<u32>, Bip32Error> {
+    let s = s.strip_prefix('m').unwrap_or(s);
+    let s = s.strip_prefix('/').unwrap_or(s);
+    if s.is_empty() {
+        return Ok(Vec::new());
+    }
+    s.split('/')
+        .map(|step| {
+            let (number, hardened) = match step.strip_suffix(['\'', 'h']) {
+                Some(number) => (number, true),
+                None => (step, false),
+            };
+            if !number.bytes().all(|b| b.is_ascii_digit()) {
+                return Err(Bip32Error::InvalidPath);
+            }
+            let index: u32 = number.parse().map_err(|_| Bip32Error::InvalidPath)?;
+            if index >= HARDENED {
+                return Err(Bip32Error::InvalidPath);
+            }
+            Ok(if hardened { index + HARDENED } else { index })
+        })
+        .collect()
+}
+
+/// Formats a derivation path with `'` for hardened steps, without the
+/// leading `m`.
+pub fn format_path(path: &[u32]) -> String {
+    path.iter()
+        .map(|&index| {
+            if index >= HARDENED {
+                format!("/{}'", index - HARDENED)
+            } else {
+                format!("/{}", index)
+            }
+        })
+        .collect()
+}
+
+/// The 78 byte serialization shared by both kinds of extended key.
+fn serialize(
+    version: [u8; 4],
+    depth: u8,
+    parent_fingerprint: [u8; 4],
+    child_number: u32,
+    chain_code: &[u8; 32],
+    key: &[u8],
+) -> Vec<u8> {
+    let mut ret = version.to_vec();
+    ret.push(depth);
+    ret.extend(parent_fingerprint);
+    ret.extend(child_number.to_be_bytes());
+    ret.extend(chain_code);
+    ret.extend(key);
+    ret
+}
+
+/// Version, depth, parent fingerprint, child number, chain code and key.
+type RawKey = ([u8; 4], u8, [u8; 4], u32, [u8; 32], [u8; 33]);
+
+/// Decodes an extended key into its fields, checking that a master key has
+/// no parent.
+fn deserialize(s: &str) -> Result<RawKey, Bip32Error> {
+    let raw = decode_base58_checksum(s).ok_or(Bip32Error::InvalidEncoding)?;
+    if raw.len() != 78 {
+        return Err(Bip32Error::InvalidEncoding);
+    }
+    let depth = raw[4];
+    let parent_fingerprint: [u8; 4] = raw[5..9].try_into().unwrap();
+    let child_number = u32::from_be_bytes(raw[9..13].try_into().unwrap());
+    if depth == 0 && (parent_fingerprint != [0; 4] || child_number != 0) {
+        return Err(Bip32Error::InvalidKey);
+    }
+    Ok((
+        raw[..4].try_into().unwrap(),
+        depth,
+        parent_fingerprint,
+        child_number,
+        raw[13..45].try_into().unwrap(),
+        raw[45..].try_into().unwrap(),
+    ))
+}
+
+/// A BIP32 extended private key.
+///
+/// Regtest keys serialize with testnet's `tprv` version, so they parse
+/// back as `Network::Testnet`.
+#[derive(Clone)]
+pub struct ExtendedPrivateKey {
+    pub network: Network,
+    pub depth: u8,
+    pub parent_fingerprint: [u8; 4],
+    pub child_number: u32,
+    pub chain_code: [u8; 32],
+    key: PrivateKey,
+}
+
+impl ExtendedPrivateKey {
+    /// The master key of `seed`, which should be 16 to 64 bytes.
+    pub fn new_master(seed: &[u8], network: Network) -> Result<Self, Bip32Error> {
+        let (secret, chain_code) = split(&hmac_sha512(b"Bitcoin seed", seed))?;
+        if secret.is_zero() {
+            return Err(Bip32Error::InvalidKey);
+        }
+        Ok(Self {
+            network,
+            depth: 0,
+            parent_fingerprint: [0; 4],
+            child_number: 0,
+            chain_code,
+            key: PrivateKey::new(secret),
+        })
+    }
+
+    pub fn get_private_key(&self) -> &PrivateKey {
+        &self.key
+    }
+
+    /// The first four bytes of the key's HASH160, which its children
+    /// record as their parent.
+    pub fn fingerprint(&self) -> [u8; 4] {
+        self.to_public().fingerprint()
+    }
+
+    pub fn to_public(&self) -> ExtendedPublicKey {
+        ExtendedPublicKey {
+            network: self.network,
+            depth: self.depth,
+            parent_fingerprint: self.parent_fingerprint,
+            child_number: self.child_number,
+            chain_code: self.chain_code,
+            point: self.key.get_point(),
+        }
+    }
+
+    pub fn derive_child(&self, index: u32) -> Result<Self, Bip32Error> {
+        let depth = self.depth.checked_add(1).ok_or(Bip32Error::MaxDepth)?;
+        let mut data = if index >= HARDENED {
+            let mut secret = [0; 33];
+            self.key.get_secret().to_big_endian(&mut secret[1..]);
+            secret.to_vec()
+        } else {
+            self.key.get_point().compressed_sec().to_vec()
+        };
+        data.extend(index.to_be_bytes());
+        let (tweak, chain_code) = split(&hmac_sha512(&self.chain_code, &data))?;
+        let secret = (U512::from(tweak) + U512::from(self.key.get_secret())) % U512::from(order());
+        let secret = U256::try_from(secret).unwrap();
+        if secret.is_zero() {
+            return Err(Bip32Error::InvalidKey);
+        }
+        Ok(Self {
+            network: self.network,
+            depth,
+            parent_fingerprint: self.fingerprint(),
+            child_number: index,
+            chain_code,
+            key: PrivateKey::new(secret),
+        })
+    }
+
+    pub fn derive_path(&self, path: &[u32]) -> Result<Self, Bip32Error> {
+        let mut ret = self.clone();
+        for &index in path {
+            ret = ret.derive_child(index)?;
+        }
+        Ok(ret)
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let version = match self.network {
+            Network::Mainnet => XPRV_VERSION,
+            Network::Testnet | Network::Regtest => TPRV_VERSION,
+        };
+        let mut key = [0; 33];
+        self.key.get_secret().to_big_endian(&mut key[1..]);
+        serialize(
+            version,
+            self.depth,
+            self.parent_fingerprint,
+            self.child_number,
+            &self.chain_code,
+            &key,
+        )
+    }
+
+    pub fn parse(s: &str) -> Result<Self, Bip32Error> {
+        let (version, depth, parent_fingerprint, child_number, chain_code, key) = deserialize(s)?;
+        let network = match version {
+            XPRV_VERSION => Network::Mainnet,
+            TPRV_VERSION => Network::Testnet,
+            _ => return Err(Bip32Error::UnknownVersion),
+        };
+        let secret = U256::from_big_endian(&key[1..]);
+        if key[0] != 0 || secret.is_zero() || secret >= order() {
+            return Err(Bip32Error::InvalidKey);
+        }
+        Ok(Self {
+            network,
+            depth,
+            parent_fingerprint,
+            child_number,
+            chain_code,
+            key: PrivateKey::new(secret),
+        })
+    }
+}
+
+impl FromStr for ExtendedPrivateKey {
+    type Err = Bip32Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s)
+    }
+}
+
+impl fmt::Display for ExtendedPrivateKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", encode_base58_checksum(&self.serialize()))
+    }
+}
+
+/// A BIP32 extended public key, which derives the public keys of the
+/// unhardened children of its private counterpart.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ExtendedPublicKey {
+    pub network: Network,
+    pub depth: u8,
+    pub parent_fingerprint: [u8; 4],
+    pub child_number: u32,
+    pub chain_code: [u8; 32],
+    pub point: S256Point,
+}
+
+impl ExtendedPublicKey {
+    pub fn fingerprint(&self) -> [u8; 4] {
+        self.point.hash160(true)[..4].try_into().unwrap()
+    }
+
+    pub fn derive_child(&self, index: u32) -> Result<Self, Bip32Error> {
+        if index >= HARDENED {
+            return Err(Bip32Error::HardenedFromPublic);
+        }
+        let depth = self.depth.checked_add(1).ok_or(Bip32Error::MaxDepth)?;
+        let mut data = self.point.compressed_sec().to_vec();
+        data.extend(index.to_be_bytes());
+        let (tweak, chain_code) = split(&hmac_sha512(&self.chain_code, &data))?;
+        let point = tweak * S256Point::get_the_generic_point() + self.point;
+        if point.as_point().get_coordinate().is_none() {
+            return Err(Bip32Error::InvalidKey);
+        }
+        Ok(Self {
+            network: self.network,
+            depth,
+            parent_fingerprint: self.fingerprint(),
+            child_number: index,
+            chain_code,
+            point,
+        })
+    }
+
+    pub fn derive_path(&self, path: &[u32]) -> Result<Self, Bip32Error> {
+        let mut ret = *self;
+        for &index in path {
+            ret = ret.derive_child(index)?;
+        }
+        Ok(ret)
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let version = match self.network {
+            Network::Mainnet => XPUB_VERSION,
+            Network::Testnet | Network::Regtest => TPUB_VERSION,
+        };
+        serialize(
+            version,
+            self.depth,
+            self.parent_fingerprint,
+            self.child_number,
+            &self.chain_code,
+            &self.point.compressed_sec(),
+        )
+    }
+
+    pub fn parse(s: &str) -> Result<Self, Bip32Error> {
+        let (version, depth, parent_fingerprint, child_number, chain_code, key) = deserialize(s)?;
+        let network = match version {
+            XPUB_VERSION => Network::Mainnet,
+            TPUB_VERSION => Network::Testnet,
+            _ => return Err(Bip32Error::UnknownVersion),
+        };
+        if key[0] != 2 && key[0] != 3 {
+            return Err(Bip32Error::InvalidKey);
+        }
+        let point = S256Point::try_parse(&key).ok_or(Bip32Error::InvalidKey)?;
+        Ok(Self {
+            network,
+            depth,
+            parent_fingerprint,
+            child_number,
+            chain_code,
+            point,
+        })
+    }
+}
+
+impl FromStr for ExtendedPublicKey {
+    type Err = Bip32Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s)
+    }
+}
+
+impl fmt::Display for ExtendedPublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", encode_base58_checksum(&self.serialize()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_vector_1() {
+        // BIP32 test vector 1
+        let seed = hex::decode("000102030405060708090a0b0c0d0e0f").unwrap();
+        let master = ExtendedPrivateKey::new_master(&seed, Network::Mainnet).unwrap();
+        assert_eq!(
+            master.to_string(),
+            "xprv9s21ZrQH143K3QTDL4LXw2F7HEK3wJUD2nW2nRk4stbPy6cq3jPPqjiChkVvvNKmPGJxWUtg6LnF5kejMRNNU3TGtRBeJgk33yuGBxrMPHi"
+        );
+        assert_eq!(
+            master.to_public().to_string(),
+            "xpub661MyMwAqRbcFtXgS5sYJABqqG9YLmC4Q1Rdap9gSE8NqtwybGhePY2gZ29ESFjqJoCu1Rupje8YtGqsefD265TMg7usUDFdp6W1EGMcet8"
+        );
+        let child = master.derive_path(&parse_path("m/0'").unwrap()).unwrap();
+        assert_eq!(
+            child.to_string(),
+            "xprv9uHRZZhk6KAJC1avXpDAp4MDc3sQKNxDiPvvkX8Br5ngLNv1TxvUxt4cV1rGL5hj6KCesnDYUhd7oWgT11eZG7XnxHrnYeSvkzY7d2bhkJ7"
+        );
+        // unhardened children from the public key alone
+        let xpub = child.to_public().derive_child(1).unwrap();
+        assert_eq!(
+            xpub.to_string(),
+            "xpub6ASuArnXKPbfEwhqN6e3mwBcDTgzisQN1wXN9BJcM47sSikHjJf3UFHKkNAWbWMiGj7Wf5uMash7SyYq527Hqck2AxYysAA7xmALppuCkwQ"
+        );
+        assert_eq!(
+            child.to_public().derive_child(HARDENED).err(),
+            Some(Bip32Error::HardenedFromPublic)
+        );
+
+        let parsed = ExtendedPrivateKey::parse(&child.to_string()).unwrap();
+        assert_eq!(parsed.to_public(), child.to_public());
+        assert_eq!(ExtendedPublicKey::parse(&xpub.to_string()), Ok(xpub));
+    }
+
+    #[test]
+    fn test_vectors_2_to_4() {
+        // BIP32 test vectors 2 to 4, each step deriving from the one before
+        // and giving an xpub and an xprv
+        type Step = (&'static str, &'static str, &'static str);
+        let vectors: [(&str, &[Step]); 3] = [
+            (
+                "fffcf9f6f3f0edeae7e4e1dedbd8d5d2cfccc9c6c3c0bdbab7b4b1aeaba8a5a29f9c999693908d8a8784817e7b7875726f6c696663605d5a5754514e4b484542",
+                &[
+                    (
+                        "m",
+                        "xpub661MyMwAqRbcFW31YEwpkMuc5THy2PSt5bDMsktWQcFF8syAmRUapSCGu8ED9W6oDMSgv6Zz8idoc4a6mr8BDzTJY47LJhkJ8UB7WEGuduB",
+                        "xprv9s21ZrQH143K31xYSDQpPDxsXRTUcvj2iNHm5NUtrGiGG5e2DtALGdso3pGz6ssrdK4PFmM8NSpSBHNqPqm55Qn3LqFtT2emdEXVYsCzC2U",
+                    ),
+                    (
+                        "0",
+                        "xpub69H7F5d8KSRgmmdJg2KhpAK8SR3DjMwAdkxj3ZuxV27CprR9LgpeyGmXUbC6wb7ERfvrnKZjXoUmmDznezpbZb7ap6r1D3tgFxHmwMkQTPH",
+                        "xprv9vHkqa6EV4sPZHYqZznhT2NPtPCjKuDKGY38FBWLvgaDx45zo9WQRUT3dKYnjwih2yJD9mkrocEZXo1ex8G81dwSM1fwqWpWkeS3v86pgKt",
+                    ),
+                    (
+                        "2147483647'",
+                        "xpub6ASAVgeehLbnwdqV6UKMHVzgqAG8Gr6riv3Fxxpj8ksbH9ebxaEyBLZ85ySDhKiLDBrQSARLq1uNRts8RuJiHjaDMBU4Zn9h8LZNnBC5y4a",
+                        "xprv9wSp6B7kry3Vj9m1zSnLvN3xH8RdsPP1Mh7fAaR7aRLcQMKTR2vidYEeEg2mUCTAwCd6vnxVrcjfy2kRgVsFawNzmjuHc2YmYRmagcEPdU9",
+                    ),
+                    (
+                        "1",
+                        "xpub6DF8uhdarytz3FWdA8TvFSvvAh8dP3283MY7p2V4SeE2wyWmG5mg5EwVvmdMVCQcoNJxGoWaU9DCWh89LojfZ537wTfunKau47EL2dhHKon",
+                        "xprv9zFnWC6h2cLgpmSA46vutJzBcfJ8yaJGg8cX1e5StJh45BBciYTRXSd25UEPVuesF9yog62tGAQtHjXajPPdbRCHuWS6T8XA2ECKADdw4Ef",
+                    ),
+                    (
+                        "2147483646'",
+                        "xpub6ERApfZwUNrhLCkDtcHTcxd75RbzS1ed54G1LkBUHQVHQKqhMkhgbmJbZRkrgZw4koxb5JaHWkY4ALHY2grBGRjaDMzQLcgJvLJuZZvRcEL",
+                        "xprvA1RpRA33e1JQ7ifknakTFpgNXPmW2YvmhqLQYMmrj4xJXXWYpDPS3xz7iAxn8L39njGVyuoseXzU6rcxFLJ8HFsTjSyQbLYnMpCqE2VbFWc",
+                    ),
+                    (
+                        "2",
+                        "xpub6FnCn6nSzZAw5Tw7cgR9bi15UV96gLZhjDstkXXxvCLsUXBGXPdSnLFbdpq8p9HmGsApME5hQTZ3emM2rnY5agb9rXpVGyy3bdW6EEgAtqt",
+                        "xprvA2nrNbFZABcdryreWet9Ea4LvTJcGsqrMzxHx98MMrotbir7yrKCEXw7nadnHM8Dq38EGfSh6dqA9QWTyefMLEcBYJUuekgW4BYPJcr9E7j",
+                    ),
+                ],
+            ),
+            // retention of leading zeros
+            (
+                "4b381541583be4423346c643850da4b320e46a87ae3d2a4e6da11eba819cd4acba45d239319ac14f863b8d5ab5a0d0c64d2e8a1e7d1457df2e5a3c51c73235be",
+                &[
+                    (
+                        "m",
+                        "xpub661MyMwAqRbcEZVB4dScxMAdx6d4nFc9nvyvH3v4gJL378CSRZiYmhRoP7mBy6gSPSCYk6SzXPTf3ND1cZAceL7SfJ1Z3GC8vBgp2epUt13",
+                        "xprv9s21ZrQH143K25QhxbucbDDuQ4naNntJRi4KUfWT7xo4EKsHt2QJDu7KXp1A3u7Bi1j8ph3EGsZ9Xvz9dGuVrtHHs7pXeTzjuxBrCmmhgC6",
+                    ),
+                    (
+                        "0'",
+                        "xpub68NZiKmJWnxxS6aaHmn81bvJeTESw724CRDs6HbuccFQN9Ku14VQrADWgqbhhTHBaohPX4CjNLf9fq9MYo6oDaPPLPxSb7gwQN3ih19Zm4Y",
+                        "xprv9uPDJpEQgRQfDcW7BkF7eTya6RPxXeJCqCJGHuCJ4GiRVLzkTXBAJMu2qaMWPrS7AANYqdq6vcBcBUdJCVVFceUvJFjaPdGZ2y9WACViL4L",
+                    ),
+                ],
+            ),
+            (
+                "3ddd5602285899a946114506157c7997e5444528f3003f6134712147db19b678",
+                &[
+                    (
+                        "m",
+                        "xpub661MyMwAqRbcGczjuMoRm6dXaLDEhW1u34gKenbeYqAix21mdUKJyuyu5F1rzYGVxyL6tmgBUAEPrEz92mBXjByMRiJdba9wpnN37RLLAXa",
+                        "xprv9s21ZrQH143K48vGoLGRPxgo2JNkJ3J3fqkirQC2zVdk5Dgd5w14S7fRDyHH4dWNHUgkvsvNDCkvAwcSHNAQwhwgNMgZhLtQC63zxwhQmRv",
+                    ),
+                    (
+                        "0'",
+                        "xpub69AUMk3qDBi3uW1sXgjCmVjJ2G6WQoYSnNHyzkmdCHEhSZ4tBok37xfFEqHd2AddP56Tqp4o56AePAgCjYdvpW2PU2jbUPFKsav5ut6Ch1m",
+                        "xprv9vB7xEWwNp9kh1wQRfCCQMnZUEG21LpbR9NPCNN1dwhiZkjjeGRnaALmPXCX7SgjFTiCTT6bXes17boXtjq3xLpcDjzEuGLQBM5ohqkao9G",
+                    ),
+                    (
+                        "1'",
+                        "xpub6BJA1jSqiukeaesWfxe6sNK9CCGaujFFSJLomWHprUL9DePQ4JDkM5d88n49sMGJxrhpjazuXYWdMf17C9T5XnxkopaeS7jGk1GyyVziaMt",
+                        "xprv9xJocDuwtYCMNAo3Zw76WENQeAS6WGXQ55RCy7tDJ8oALr4FWkuVoHJeHVAcAqiZLE7Je3vZJHxspZdFHfnBEjHqU5hG1Jaj32dVoS6XLT1",
+                    ),
+                ],
+            ),
+        ];
+        for (seed, steps) in vectors {
+            let seed = hex::decode(seed).unwrap();
+            let mut key = ExtendedPrivateKey::new_master(&seed, Network::Mainnet).unwrap();
+            for (step, xpub, xprv) in steps {
+                key = key.derive_path(&parse_path(step).unwrap()).unwrap();
+                assert_eq!(key.to_string(), *xprv);
+                assert_eq!(key.to_public().to_string(), *xpub);
+            }
+        }
+    }
+
+    #[test]
+    fn test_vector_5() {
+        // BIP32 test vector 5: keys that must not parse
+        for (s, expected) in [
+            // a private key under a public version, and the other way round
+            ("xpub661MyMwAqRbcEYS8w7XLSVeEsBXy79zSzH1J8vCdxAZningWLdN3zgtU6LBpB85b3D2yc8sfvZU521AAwdZafEz7mnzBBsz4wKY5fTtTQBm", Bip32Error::InvalidKey),
+            ("xprv9s21ZrQH143K24Mfq5zL5MhWK9hUhhGbd45hLXo2Pq2oqzMMo63oStZzFGTQQD3dC4H2D5GBj7vWvSQaaBv5cxi9gafk7NF3pnBju6dwKvH", Bip32Error::InvalidKey),
+            // key prefixes of 04 and 01
+            ("xpub661MyMwAqRbcEYS8w7XLSVeEsBXy79zSzH1J8vCdxAZningWLdN3zgtU6Txnt3siSujt9RCVYsx4qHZGc62TG4McvMGcAUjeuwZdduYEvFn", Bip32Error::InvalidKey),
+            ("xprv9s21ZrQH143K24Mfq5zL5MhWK9hUhhGbd45hLXo2Pq2oqzMMo63oStZzFGpWnsj83BHtEy5Zt8CcDr1UiRXuWCmTQLxEK9vbz5gPstX92JQ", Bip32Error::InvalidKey),
+            ("xpub661MyMwAqRbcEYS8w7XLSVeEsBXy79zSzH1J8vCdxAZningWLdN3zgtU6N8ZMMXctdiCjxTNq964yKkwrkBJJwpzZS4HS2fxvyYUA4q2Xe4", Bip32Error::InvalidKey),
+            ("xprv9s21ZrQH143K24Mfq5zL5MhWK9hUhhGbd45hLXo2Pq2oqzMMo63oStZzFAzHGBP2UuGCqWLTAPLcMtD9y5gkZ6Eq3Rjuahrv17fEQ3Qen6J", Bip32Error::InvalidKey),
+            // depth 0 with a parent fingerprint or a child number
+            ("xprv9s2SPatNQ9Vc6GTbVMFPFo7jsaZySyzk7L8n2uqKXJen3KUmvQNTuLh3fhZMBoG3G4ZW1N2kZuHEPY53qmbZzCHshoQnNf4GvELZfqTUrcv", Bip32Error::InvalidKey),
+            ("xpub661no6RGEX3uJkY4bNnPcw4URcQTrSibUZ4NqJEw5eBkv7ovTwgiT91XX27VbEXGENhYRCf7hyEbWrR3FewATdCEebj6znwMfQkhRYHRLpJ", Bip32Error::InvalidKey),
+            ("xprv9s21ZrQH4r4TsiLvyLXqM9P7k1K3EYhA1kkD6xuquB5i39AU8KF42acDyL3qsDbU9NmZn6MsGSUYZEsuoePmjzsB3eFKSUEh3Gu1N3cqVUN", Bip32Error::InvalidKey),
+            ("xpub661MyMwAuDcm6CRQ5N4qiHKrJ39Xe1R1NyfouMKTTWcguwVcfrZJaNvhpebzGerh7gucBvzEQWRugZDuDXjNDRmXzSZe4c7mnTK97pTvGS8", Bip32Error::InvalidKey),
+            ("DMwo58pR1QLEFihHiXPVykYB6fJmsTeHvyTp7hRThAtCX8CvYzgPcn8XnmdfHGMQzT7ayAmfo4z3gY5KfbrZWZ6St24UVf2Qgo6oujFktLHdHY4", Bip32Error::UnknownVersion),
+            ("DMwo58pR1QLEFihHiXPVykYB6fJmsTeHvyTp7hRThAtCX8CvYzgPcn8XnmdfHPmHJiEDXkTiJTVV9rHEBUem2mwVbbNfvT2MTcAqj3nesx8uBf9", Bip32Error::UnknownVersion),
+            // private keys of 0 and not below the group order
+            ("xprv9s21ZrQH143K24Mfq5zL5MhWK9hUhhGbd45hLXo2Pq2oqzMMo63oStZzF93Y5wvzdUayhgkkFoicQZcP3y52uPPxFnfoLZB21Teqt1VvEHx", Bip32Error::InvalidKey),
+            ("xprv9s21ZrQH143K24Mfq5zL5MhWK9hUhhGbd45hLXo2Pq2oqzMMo63oStZzFAzHGBP2UuGCqWLTAPLcMtD5SDKr24z3aiUvKr9bJpdrcLg1y3G", Bip32Error::InvalidKey),
+            // a public key not on the curve, and a bad checksum
+            ("xpub661MyMwAqRbcEYS8w7XLSVeEsBXy79zSzH1J8vCdxAZningWLdN3zgtU6Q5JXayek4PRsn35jii4veMimro1xefsM58PgBMrvdYre8QyULY", Bip32Error::InvalidKey),
+            ("xprv9s21ZrQH143K3QTDL4LXw2F7HEK3wJUD2nW2nRk4stbPy6cq3jPPqjiChkVvvNKmPGJxWUtg6LnF5Hejmtj", Bip32Error::InvalidEncoding),
+        ] {
+            let result = if s.starts_with("xpub") {
+                ExtendedPublicKey::parse(s).err()
+            } else {
+                ExtendedPrivateKey::parse(s).err()
+            };
+            assert_eq!(result, Some(expected), "{s}");
+        }
+    }
+
+    #[test]
+    fn test_max_depth() {
+        let mut key = ExtendedPrivateKey::new_master(&[1; 32], Network::Testnet).unwrap();
+        key.depth = u8::MAX;
+        assert_eq!(key.derive_child(0).err(), Some(Bip32Error::MaxDepth));
+        assert_eq!(key.to_public().derive_child(0), Err(Bip32Error::MaxDepth));
+        key.depth = u8::MAX - 1;
+        assert_eq!(key.derive_child(HARDENED).unwrap().depth, u8::MAX);
+    }
+
+    #[test]
+    fn test_invalid_keys() {
+        let master = ExtendedPrivateKey::new_master(&[1; 32], Network::Testnet).unwrap();
+        let xprv = master.serialize();
+        let xpub = master.to_public().serialize();
+        let encode =
+            |version: &[u8], rest: &[u8]| encode_base58_checksum(&[version, rest].concat());
+        // a master key with a parent
+        let mut orphan = xprv.clone();
+        orphan[5] = 1;
+        assert_eq!(
+            ExtendedPrivateKey::parse(&encode_base58_checksum(&orphan)).err(),
+            Some(Bip32Error::InvalidKey)
+        );
+        // a public key under a private version, and the other way round
+        assert_eq!(
+            ExtendedPrivateKey::parse(&encode(&TPRV_VERSION, &xpub[4..])).err(),
+            Some(Bip32Error::InvalidKey)
+        );
+        assert_eq!(
+            ExtendedPublicKey::parse(&encode(&TPUB_VERSION, &xprv[4..])),
+            Err(Bip32Error::InvalidKey)
+        );
+        assert_eq!(
+            ExtendedPublicKey::parse(&encode(&TPRV_VERSION, &xpub[4..])),
+            Err(Bip32Error::UnknownVersion)
+        );
+        let mut zero = xprv.clone();
+        zero[46..].fill(0);
+        assert_eq!(
+            ExtendedPrivateKey::parse(&encode_base58_checksum(&zero)).err(),
+            Some(Bip32Error::InvalidKey)
+        );
+        assert_eq!(
+            ExtendedPublicKey::parse(&encode_base58_checksum(&xpub[..77])),
+            Err(Bip32Error::InvalidEncoding)
+        );
+
+        assert_eq!(
+            parse_path("m/84'/1h/0"),
+            Ok(vec![84 + HARDENED, 1 + HARDENED, 0])
+        );
+        assert_eq!(parse_path("m"), Ok(vec![]));
+        assert_eq!(format_path(&[84 + HARDENED, 0]), "/84'/0");
+        assert_eq!(parse_path("m/x"), Err(Bip32Error::InvalidPath));
+        assert_eq!(parse_path("m/2147483648"), Err(Bip32Error::InvalidPath));
+    }
+}
@@ -0,0 +1,409 @@
+use crate::{
+    block_header::BlockHeader,
+    header_chain::ChainParams,
+    merkle::{
+        merkle_root_mutated, witness_commitment, witness_merkle_root, WITNESS_COMMITMENT_HEADER,
+    },
+    network::Network,
+    script::{Command, Script},
+    tx::{Tx, TxError},
+    varint::{encode_varint, read_varint},
+};
+use std::{
+    collections::HashSet,
+    fs,
+    io::{self, Cursor, Read},
+    path::Path,
+};
+
+/// Consensus limit on block weight (BIP141).
+pub const MAX_BLOCK_WEIGHT: u64 = 4_000_000;
+/// Consensus limit on signature operations, in weight units.
+pub const MAX_BLOCK_SIGOPS_COST: u64 = 80_000;
+pub const WITNESS_SCALE_FACTOR: u64 = 4;
+
+/// The coinbase of every network's genesis block.
+const GENESIS_COINBASE: &str = "01000000010000000000000000000000000000000000000000000000000000000000000000ffffffff4d04ffff001d0104455468652054696d65732030332f4a616e2f32303039204368616e63656c6c6f72206f6e206272696e6b206f66207365636f6e64206261696c6f757420666f722062616e6b73ffffffff0100f2052a01000000434104678afdb0fe5548271967f1a67130b7105cd6a828e03909a67962e0ea1f61deb649f6bc3f4cef38c4f35504e51ec112de5c384df7ba0b8d578a4c702b6bf11d5fac00000000";
+
+/// Why a block fails validation, named after Bitcoin Core's reject reasons.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlockError {
+    BadPow,
+    BadMerkleRoot,
+    /// Two transactions share a txid, including the CVE-2012-2459 merkle
+    /// tree mutation.
+    DuplicateTxid,
+    /// No transactions, or too large even without witnesses.
+    BadLength,
+    TooHeavy,
+    /// The first transaction is not a coinbase.
+    NoCoinbase,
+    MultipleCoinbase,
+    BadTx {
+        index: usize,
+        error: TxError,
+    },
+    TooManySigops,
+    /// The coinbase does not start with the block height (BIP34).
+    BadCoinbaseHeight,
+    /// The coinbase witness is not a single 32 byte reserved value.
+    BadWitnessNonceSize,
+    BadWitnessCommitment,
+    /// Witness data in a block without a witness commitment.
+    UnexpectedWitness,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Block {
+    pub header: BlockHeader,
+    pub txs: Vec<Tx>,
+}
+
+impl Block {
+    pub fn new(header: BlockHeader, txs: Vec<Tx>) -> Self {
+        Self { header, txs }
+    }
+
+    pub fn genesis(network: Network) -> Self {
+        let coinbase = Tx::parse(&mut Cursor::new(hex::decode(GENESIS_COINBASE).unwrap())).unwrap();
+        Self::new(ChainParams::new(network).genesis, vec![coinbase])
+    }
+
+    pub fn parse<R: Read>(stream: &mut R) -> io::Result<Self> {
+        let header = BlockHeader::parse(stream)?;
+        let count = read_varint(stream)?;
+        let mut txs = Vec::new();
+        for _ in 0..count {
+            txs.push(Tx::parse(stream)?);
+        }
+        Ok(Self { header, txs })
+    }
+
+    /// Reads a block stored either as raw bytes or as hex, e.g. the output
+    /// of `bitcoin-cli getblock <hash> 0`.
+    pub fn read_file<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let contents = fs::read(path)?;
+        let raw = std::str::from_utf8(&contents)
+            .ok()
+            .and_then(|s| hex::decode(s.trim()).ok())
+            .unwrap_or(contents);
+        let mut stream = Cursor::new(&raw);
+        let block = Self::parse(&mut stream)?;
+        if stream.position() as usize != raw.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "trailing data after block",
+            ));
+        }
+        Ok(block)
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut ret = self.header.serialize();
+        ret.extend(encode_varint(self.txs.len() as u64));
+        for tx in &self.txs {
+            ret.extend(tx.serialize());
+        }
+        ret
+    }
+
+    /// Serializes the block without witnesses.
+    pub fn serialize_legacy(&self) -> Vec<u8> {
+        let mut ret = self.header.serialize();
+        ret.extend(encode_varint(self.txs.len() as u64));
+        for tx in &self.txs {
+            ret.extend(tx.serialize_legacy());
+        }
+        ret
+    }
+
+    pub fn hash(&self) -> [u8; 32] {
+        self.header.hash()
+    }
+
+    pub fn id(&self) -> String {
+        self.header.id()
+    }
+
+    pub fn txids(&self) -> Vec<[u8; 32]> {
+        self.txs.iter().map(|tx| tx.txid()).collect()
+    }
+
+    /// Weight as defined in BIP141.
+    pub fn weight(&self) -> u64 {
+        (self.serialize_legacy().len() * 3 + self.serialize().len()) as u64
+    }
+
+    /// The checks of Bitcoin Core's `CheckBlock`, plus the weight limit and
+    /// the witness commitment: everything that needs neither the chain nor
+    /// the UTXO set.
+    pub fn check(&self) -> Result<(), BlockError> {
+        if !self.header.check_pow() {
+            return Err(BlockError::BadPow);
+        }
+        let txids = self.txids();
+        let (root, mutated) = merkle_root_mutated(&txids);
+        if root != self.header.merkle_root {
+            return Err(BlockError::BadMerkleRoot);
+        }
+        if mutated {
+            return Err(BlockError::DuplicateTxid);
+        }
+        if self.txs.is_empty()
+            || self.txs.len() as u64 * WITNESS_SCALE_FACTOR > MAX_BLOCK_WEIGHT
+            || self.serialize_legacy().len() as u64 * WITNESS_SCALE_FACTOR > MAX_BLOCK_WEIGHT
+        {
+            return Err(BlockError::BadLength);
+        }
+        if !self.txs[0].is_coinbase() {
+            return Err(BlockError::NoCoinbase);
+        }
+        if self.txs[1..].iter().any(|tx| tx.is_coinbase()) {
+            return Err(BlockError::MultipleCoinbase);
+        }
+        for (index, tx) in self.txs.iter().enumerate() {
+            tx.check()
+                .map_err(|error| BlockError::BadTx { index, error })?;
+        }
+        let sigops: u64 = self
+            .txs
+            .iter()
+            .map(|tx| tx.legacy_sigop_count() as u64)
+            .sum();
+        if sigops * WITNESS_SCALE_FACTOR > MAX_BLOCK_SIGOPS_COST {
+            return Err(BlockError::TooManySigops);
+        }
+        let mut seen = HashSet::new();
+        if !txids.iter().all(|txid| seen.insert(txid)) {
+            return Err(BlockError::DuplicateTxid);
+        }
+        self.check_witness_commitment()?;
+        if self.weight() > MAX_BLOCK_WEIGHT {
+            return Err(BlockError::TooHeavy);
+        }
+        Ok(())
+    }
+
+    /// Index of the coinbase output holding the witness commitment. If there
+    /// are several, the last one counts.
+    pub fn witness_commitment_index(&self) -> Option<usize> {
+        self.txs.first()?.tx_outs.iter().rposition(|tx_out| {
+            let script = tx_out.script_pubkey.as_bytes();
+            script.len() >= 38
+                && script[..2] == [0x6a, 0x24]
+                && script[2..6] == WITNESS_COMMITMENT_HEADER
+        })
+    }
+
+    /// Checks the BIP141 commitment to the witnesses, or that there are no
+    /// witnesses if the coinbase has no commitment.
+    pub fn check_witness_commitment(&self) -> Result<(), BlockError> {
+        let Some(index) = self.witness_commitment_index() else {
+            if self.txs.iter().any(|tx| tx.has_witness()) {
+                return Err(BlockError::UnexpectedWitness);
+            }
+            return Ok(());
+        };
+        let coinbase = &self.txs[0];
+        let reserved_value: [u8; 32] = match coinbase.tx_ins[0].witness.as_slice() {
+            [value] => value
+                .as_slice()
+                .try_into()
+                .map_err(|_| BlockError::BadWitnessNonceSize)?,
+            _ => return Err(BlockError::BadWitnessNonceSize),
+        };
+        let commitment = witness_commitment(&witness_merkle_root(&self.txs), &reserved_value);
+        if coinbase.tx_outs[index].script_pubkey.as_bytes()[6..38] != commitment {
+            return Err(BlockError::BadWitnessCommitment);
+        }
+        Ok(())
+    }
+
+    /// Checks that the coinbase scriptSig starts with `height` (BIP34).
+    pub fn check_coinbase_height(&self, height: u32) -> Result<(), BlockError> {
+        let expected = Script::new(&[Command::int(height as i64)]);
+        let script_sig = self
+            .txs
+            .first()
+            .and_then(|tx| tx.tx_ins.first())
+            .map(|tx_in| tx_in.script_sig.as_bytes())
+            .unwrap_or_default();
+        if !script_sig.starts_with(expected.as_bytes()) {
+            return Err(BlockError::BadCoinbaseHeight);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        merkle::{merkle_root, witness_commitment_script},
+        script::{OP_0, OP_CHECKSIG},
+        tx::{OutPoint, TxIn, TxOut, SEQUENCE_FINAL},
+    };
+
+    const GENESIS_BLOCK: &str = "0100000000000000000000000000000000000000000000000000000000000000000000003ba3edfd7a7b12b27ac72c3e67768f617fc81bc3888a51323a9fb8aa4b1e5e4a29ab5f49ffff001d1dac2b7c0101000000010000000000000000000000000000000000000000000000000000000000000000ffffffff4d04ffff001d0104455468652054696d65732030332f4a616e2f32303039204368616e63656c6c6f72206f6e206272696e6b206f66207365636f6e64206261696c6f757420666f722062616e6b73ffffffff0100f2052a01000000434104678afdb0fe5548271967f1a67130b7105cd6a828e03909a67962e0ea1f61deb649f6bc3f4cef38c4f35504e51ec112de5c384df7ba0b8d578a4c702b6bf11d5fac00000000";
+
+    fn mine(block: &mut Block) {
+        block.header.nonce = 0;
+        while !block.header.check_pow() {
+            block.header.nonce += 1;
+        }
+    }
+
+    /// Recomputes the merkle root and mines the block again.
+    fn remine(block: &mut Block) {
+        block.header.merkle_root = merkle_root(&block.txids());
+        mine(block);
+    }
+
+    /// A regtest block at height 101 with a segwit spend.
+    fn segwit_block() -> Block {
+        let mut coinbase_in = TxIn::new(OutPoint::null(), SEQUENCE_FINAL);
+        coinbase_in.script_sig = Script::new(&[Command::int(101), Command::Op(OP_0)]);
+        coinbase_in.witness = vec![vec![0; 32]];
+        let coinbase = Tx::new(
+            2,
+            vec![coinbase_in],
+            vec![TxOut::new(50_0000_0000, Script::p2wpkh(&[1; 20]))],
+            0,
+        );
+        let mut spend_in = TxIn::new(OutPoint::new([7; 32], 0), SEQUENCE_FINAL);
+        spend_in.witness = vec![vec![1; 71], vec![2; 33]];
+        let spend = Tx::new(
+            2,
+            vec![spend_in],
+            vec![TxOut::new(1_0000_0000, Script::p2wpkh(&[3; 20]))],
+            0,
+        );
+        let mut txs = vec![coinbase, spend];
+        let commitment = witness_commitment(&witness_merkle_root(&txs), &[0; 32]);
+        txs[0]
+            .tx_outs
+            .push(TxOut::new(0, witness_commitment_script(&commitment)));
+        let header = BlockHeader::new(0x2000_0000, [0x11; 32], [0; 32], 1296688700, 0x207fffff, 0);
+        let mut block = Block::new(header, txs);
+        remine(&mut block);
+        block
+    }
+
+    #[test]
+    fn test_genesis_block() {
+        let raw = hex::decode(GENESIS_BLOCK).unwrap();
+        let block = Block::parse(&mut Cursor::new(&raw)).unwrap();
+        assert_eq!(block.serialize(), raw);
+        assert_eq!(block, Block::genesis(Network::Mainnet));
+        assert_eq!(Block::genesis(Network::Regtest).check(), Ok(()));
+        assert_eq!(
+            block.id(),
+            "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f"
+        );
+        assert_eq!(block.weight(), 1140);
+        assert_eq!(block.txs[0].legacy_sigop_count(), 1);
+        assert_eq!(block.check(), Ok(()));
+        // it predates BIP34
+        assert_eq!(
+            block.check_coinbase_height(0),
+            Err(BlockError::BadCoinbaseHeight)
+        );
+    }
+
+    #[test]
+    fn test_check() {
+        let block = segwit_block();
+        assert_eq!(block.check(), Ok(()));
+        assert_eq!(block.check_coinbase_height(101), Ok(()));
+        assert_eq!(
+            block.check_coinbase_height(100),
+            Err(BlockError::BadCoinbaseHeight)
+        );
+
+        let mut bad = block.clone();
+        bad.header.merkle_root[0] ^= 1;
+        mine(&mut bad);
+        assert_eq!(bad.check(), Err(BlockError::BadMerkleRoot));
+
+        let mut bad = block.clone();
+        bad.txs.push(bad.txs[1].clone());
+        remine(&mut bad);
+        assert_eq!(bad.check(), Err(BlockError::DuplicateTxid));
+
+        let mut bad = block.clone();
+        bad.txs.swap(0, 1);
+        remine(&mut bad);
+        assert_eq!(bad.check(), Err(BlockError::NoCoinbase));
+
+        let mut bad = block.clone();
+        let mut second_coinbase = bad.txs[0].clone();
+        second_coinbase.tx_ins[0].script_sig = Script::new(&[Command::int(102)]);
+        bad.txs.push(second_coinbase);
+        remine(&mut bad);
+        assert_eq!(bad.check(), Err(BlockError::MultipleCoinbase));
+
+        let mut bad = block.clone();
+        bad.txs[1].tx_outs.clear();
+        remine(&mut bad);
+        assert_eq!(
+            bad.check(),
+            Err(BlockError::BadTx {
+                index: 1,
+                error: TxError::NoOutputs
+            })
+        );
+
+        let mut bad = block.clone();
+        bad.txs[1].tx_outs[0].script_pubkey = Script::from_bytes(vec![OP_CHECKSIG; 20_001]);
+        remine(&mut bad);
+        assert_eq!(bad.check(), Err(BlockError::TooManySigops));
+    }
+
+    #[test]
+    fn test_witness_commitment() {
+        let block = segwit_block();
+        assert_eq!(block.witness_commitment_index(), Some(1));
+
+        let mut bad = block.clone();
+        bad.txs[0].tx_ins[0].witness = vec![vec![1; 32]];
+        assert_eq!(
+            bad.check_witness_commitment(),
+            Err(BlockError::BadWitnessCommitment)
+        );
+        bad.txs[0].tx_ins[0].witness = vec![vec![0; 32], vec![]];
+        assert_eq!(
+            bad.check_witness_commitment(),
+            Err(BlockError::BadWitnessNonceSize)
+        );
+
+        let mut bad = block.clone();
+        bad.txs[0].tx_outs.pop();
+        assert_eq!(
+            bad.check_witness_commitment(),
+            Err(BlockError::UnexpectedWitness)
+        );
+        bad.txs[1].tx_ins[0].witness.clear();
+        bad.txs[0].tx_ins[0].witness.clear();
+        assert_eq!(bad.check_witness_commitment(), Ok(()));
+    }
+
+    #[test]
+    fn test_read_file() {
+        let block = segwit_block();
+        let dir = std::env::temp_dir();
+        let hex_path = dir.join(format!("block-{}.hex", std::process::id()));
+        let raw_path = dir.join(format!("block-{}.dat", std::process::id()));
+        fs::write(&hex_path, hex::encode(block.serialize()) + "\n").unwrap();
+        fs::write(&raw_path, block.serialize()).unwrap();
+        assert_eq!(Block::read_file(&hex_path).unwrap(), block);
+        assert_eq!(Block::read_file(&raw_path).unwrap(), block);
+
+        fs::write(&raw_path, [block.serialize(), vec![0]].concat()).unwrap();
+        assert_eq!(
+            Block::read_file(&raw_path).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+        fs::remove_file(hex_path).unwrap();
+        fs::remove_file(raw_path).unwrap();
+    }
+}
@@ -0,0 +1,330 @@
+use crate::{block::Block, block_header::BlockHeader, network::Network};
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{self, Cursor, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+/// Where a block sits in the blk*.dat files: `offset` points at the block
+/// itself, just past its magic and length.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockLocation {
+    pub file: usize,
+    pub offset: u64,
+    pub size: u32,
+}
+
+/// A copy of Bitcoin Core's `blocks/` directory.
+///
+/// Core appends blocks to blkNNNNN.dat in the order they arrive, so the
+/// files hold stale blocks and parents after children. Opening the directory
+/// scans every header and keeps the most-work chain starting at the
+/// network's genesis block, which is then read in height order.
+pub struct BlockFiles {
+    files: Vec<PathBuf>,
+    magic: [u8; 4],
+    xor_key: [u8; 8],
+    headers: HashMap<[u8; 32], (BlockHeader, BlockLocation)>,
+    chain: Vec<[u8; 32]>,
+}
+
+impl BlockFiles {
+    /// Scans `dir`, deobfuscating with blocks/xor.dat when present. The
+    /// chain is empty if the genesis block is not among the files.
+    pub fn open<P: AsRef<Path>>(dir: P, network: Network) -> io::Result<Self> {
+        let dir = dir.as_ref();
+        let xor_key = match fs::read(dir.join("xor.dat")) {
+            Ok(key) => key.try_into().map_err(|_| {
+                io::Error::new(io::ErrorKind::InvalidData, "xor.dat is not 8 bytes")
+            })?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => [0; 8],
+            Err(e) => return Err(e),
+        };
+        let mut files = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+            if name.starts_with("blk") && name.ends_with(".dat") {
+                files.push(path);
+            }
+        }
+        files.sort();
+
+        let mut ret = Self {
+            files,
+            magic: network.magic(),
+            xor_key,
+            headers: HashMap::new(),
+            chain: Vec::new(),
+        };
+        for file in 0..ret.files.len() {
+            ret.scan(file)?;
+        }
+        ret.chain = ret.best_chain(Block::genesis(network).hash());
+        Ok(ret)
+    }
+
+    pub fn get_xor_key(&self) -> [u8; 8] {
+        self.xor_key
+    }
+
+    /// Height of the best chain's tip, or None if the chain is empty.
+    pub fn height(&self) -> Option<u32> {
+        self.chain.len().checked_sub(1).map(|h| h as u32)
+    }
+
+    /// Best chain block hashes, genesis first.
+    pub fn chain(&self) -> &[[u8; 32]] {
+        &self.chain
+    }
+
+    pub fn get_header(&self, hash: &[u8; 32]) -> Option<BlockHeader> {
+        self.headers.get(hash).map(|(header, _)| *header)
+    }
+
+    pub fn get_location(&self, hash: &[u8; 32]) -> Option<BlockLocation> {
+        self.headers.get(hash).map(|(_, location)| *location)
+    }
+
+    /// Number of distinct blocks found, including stale ones.
+    pub fn block_count(&self) -> usize {
+        self.headers.len()
+    }
+
+    pub fn read_block(&self, location: &BlockLocation) -> io::Result<Block> {
+        let raw = self.read_at(
+            &mut File::open(&self.files[location.file])?,
+            location.offset,
+            location.size as usize,
+        )?;
+        let mut stream = Cursor::new(&raw);
+        let block = Block::parse(&mut stream)?;
+        if stream.position() as usize != raw.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "block shorter than its length prefix",
+            ));
+        }
+        Ok(block)
+    }
+
+    /// Best chain blocks in height order, read lazily.
+    pub fn blocks(&self) -> impl Iterator<Item = io::Result<Block>> + '_ {
+        self.chain
+            .iter()
+            .map(|hash| self.read_block(&self.headers[hash].1))
+    }
+
+    fn read_raw_at(file: &mut File, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+        file.seek(SeekFrom::Start(offset))?;
+        let mut buf = vec![0; len];
+        file.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+
+    fn deobfuscate(&self, offset: u64, buf: &mut [u8]) {
+        for (i, byte) in buf.iter_mut().enumerate() {
+            *byte ^= self.xor_key[(offset as usize + i) % 8];
+        }
+    }
+
+    fn read_at(&self, file: &mut File, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+        let mut buf = Self::read_raw_at(file, offset, len)?;
+        self.deobfuscate(offset, &mut buf);
+        Ok(buf)
+    }
+
+    /// Indexes the headers of one file. Core preallocates files with zeros,
+    /// which are not obfuscated, so raw zeros where a magic should be or a
+    /// frame running past the end marks the end of the data.
+    fn scan(&mut self, index: usize) -> io::Result<()> {
+        let mut file = File::open(&self.files[index])?;
+        let len = file.metadata()?.len();
+        let mut offset = 0;
+        while offset + 8 <= len {
+            let mut frame = Self::read_raw_at(&mut file, offset, 8)?;
+            if frame[..4] == [0; 4] {
+                break;
+            }
+            self.deobfuscate(offset, &mut frame);
+            if frame[..4] != self.magic {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "bad magic in block file",
+                ));
+            }
+            let size = u32::from_le_bytes(frame[4..].try_into().unwrap());
+            if size < 80 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "block smaller than a header",
+                ));
+            }
+            if offset + 8 + size as u64 > len {
+                break;
+            }
+            let raw = self.read_at(&mut file, offset + 8, 80)?;
+            let header = BlockHeader::parse(&mut Cursor::new(raw))?;
+            let location = BlockLocation {
+                file: index,
+                offset: offset + 8,
+                size,
+            };
+            self.headers
+                .entry(header.hash())
+                .or_insert((header, location));
+            offset += 8 + size as u64;
+        }
+        Ok(())
+    }
+
+    /// Walks the block tree from `genesis` and returns the path to the tip
+    /// with the most work, preferring the first stored on ties.
+    fn best_chain(&self, genesis: [u8; 32]) -> Vec<[u8; 32]> {
+        let Some((genesis_header, genesis_location)) = self.headers.get(&genesis) else {
+            return Vec::new();
+        };
+        let mut children: HashMap<[u8; 32], Vec<([u8; 32], BlockLocation)>> = HashMap::new();
+        for (hash, (header, location)) in &self.headers {
+            children
+                .entry(header.prev_block)
+                .or_default()
+                .push((*hash, *location));
+        }
+        let position = (genesis_location.file, genesis_location.offset);
+        let mut best = (genesis_header.work(), position, genesis);
+        let mut stack = vec![(genesis, genesis_header.work())];
+        while let Some((hash, work)) = stack.pop() {
+            for (child, location) in children.get(&hash).into_iter().flatten() {
+                let child_work = work + self.headers[child].0.work();
+                let position = (location.file, location.offset);
+                if child_work > best.0 || (child_work == best.0 && position < best.1) {
+                    best = (child_work, position, *child);
+                }
+                stack.push((*child, child_work));
+            }
+        }
+        let mut chain = vec![best.2];
+        while *chain.last().unwrap() != genesis {
+            chain.push(self.headers[chain.last().unwrap()].0.prev_block);
+        }
+        chain.reverse();
+        chain
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        merkle::merkle_root,
+        script::{Command, Script},
+        tx::{OutPoint, Tx, TxIn, TxOut, SEQUENCE_FINAL},
+    };
+
+    fn child(parent: &Block, tag: i64) -> Block {
+        let mut coinbase_in = TxIn::new(OutPoint::null(), SEQUENCE_FINAL);
+        coinbase_in.script_sig = Script::new(&[Command::int(tag), Command::int(tag)]);
+        let coinbase = Tx::new(
+            1,
+            vec![coinbase_in],
+            vec![TxOut::new(50_0000_0000, Script::p2wpkh(&[1; 20]))],
+            0,
+        );
+        let header = BlockHeader::new(
+            1,
+            parent.hash(),
+            merkle_root(&[coinbase.txid()]),
+            parent.header.timestamp + 600,
+            parent.header.bits,
+            0,
+        );
+        Block::new(header, vec![coinbase])
+    }
+
+    fn write_blocks(path: &Path, blocks: &[&Block], key: [u8; 8], padding: usize) {
+        let mut raw = Vec::new();
+        for block in blocks {
+            let serialized = block.serialize();
+            raw.extend(Network::Regtest.magic());
+            raw.extend((serialized.len() as u32).to_le_bytes());
+            raw.extend(serialized);
+        }
+        for (i, byte) in raw.iter_mut().enumerate() {
+            *byte ^= key[i % 8];
+        }
+        // preallocated space is left as plain zeros
+        raw.resize(raw.len() + padding, 0);
+        fs::write(path, raw).unwrap();
+    }
+
+    #[test]
+    fn test_chain_order() {
+        let genesis = Block::genesis(Network::Regtest);
+        let a = child(&genesis, 1);
+        let b = child(&a, 2);
+        let c = child(&b, 3);
+        let stale = child(&a, 4);
+        let key = [0x5a, 0x01, 0xff, 0x80, 0x13, 0x37, 0x00, 0xc3];
+
+        let dir = std::env::temp_dir().join(format!("blocks-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("xor.dat"), key).unwrap();
+        fs::write(dir.join("rev00000.dat"), [1, 2, 3]).unwrap();
+        write_blocks(&dir.join("blk00000.dat"), &[&genesis, &b, &stale], key, 0);
+        write_blocks(&dir.join("blk00001.dat"), &[&c, &a], key, 37);
+
+        let files = BlockFiles::open(&dir, Network::Regtest).unwrap();
+        assert_eq!(files.get_xor_key(), key);
+        assert_eq!(files.block_count(), 5);
+        assert_eq!(files.height(), Some(3));
+        assert_eq!(
+            files.chain(),
+            [genesis.hash(), a.hash(), b.hash(), c.hash()]
+        );
+        let blocks: Vec<Block> = files.blocks().collect::<io::Result<_>>().unwrap();
+        assert_eq!(blocks, [genesis.clone(), a, b, c.clone()]);
+        assert_eq!(
+            files.get_location(&c.hash()),
+            Some(BlockLocation {
+                file: 1,
+                offset: 8,
+                size: c.serialize().len() as u32
+            })
+        );
+        assert_eq!(files.get_header(&stale.hash()), Some(stale.header));
+
+        // another network's magic
+        assert_eq!(
+            BlockFiles::open(&dir, Network::Mainnet)
+                .err()
+                .unwrap()
+                .kind(),
+            io::ErrorKind::InvalidData
+        );
+        fs::remove_file(dir.join("xor.dat")).unwrap();
+        assert_eq!(
+            BlockFiles::open(&dir, Network::Regtest)
+                .err()
+                .unwrap()
+                .kind(),
+            io::ErrorKind::InvalidData
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_missing_genesis() {
+        let genesis = Block::genesis(Network::Regtest);
+        let a = child(&genesis, 1);
+        let dir = std::env::temp_dir().join(format!("blocks-orphan-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        write_blocks(&dir.join("blk00000.dat"), &[&a], [0; 8], 8);
+        let files = BlockFiles::open(&dir, Network::Regtest).unwrap();
+        assert_eq!(files.block_count(), 1);
+        assert_eq!(files.height(), None);
+        assert_eq!(files.blocks().count(), 0);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,249 @@
+use crate::base58::hash256;
+use primitive_types::U256;
+use std::io::{self, Read};
+
+/// Compact target of difficulty 1, the mainnet proof-of-work limit.
+pub const MAX_BITS: u32 = 0x1d00ffff;
+
+/// The 80 byte header committing to a block.
+///
+/// Hashes are kept in internal byte order, like `OutPoint::txid`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BlockHeader {
+    pub version: u32,
+    pub prev_block: [u8; 32],
+    pub merkle_root: [u8; 32],
+    pub timestamp: u32,
+    pub bits: u32,
+    pub nonce: u32,
+}
+
+impl BlockHeader {
+    pub fn new(
+        version: u32,
+        prev_block: [u8; 32],
+        merkle_root: [u8; 32],
+        timestamp: u32,
+        bits: u32,
+        nonce: u32,
+    ) -> Self {
+        Self {
+            version,
+            prev_block,
+            merkle_root,
+            timestamp,
+            bits,
+            nonce,
+        }
+    }
+
+    pub fn parse<R: Read>(stream: &mut R) -> io::Result<Self> {
+        let mut buf = [0u8; 80];
+        stream.read_exact(&mut buf)?;
+        let u32_at = |i: usize| u32::from_le_bytes(buf[i..i + 4].try_into().unwrap());
+        Ok(Self {
+            version: u32_at(0),
+            prev_block: buf[4..36].try_into().unwrap(),
+            merkle_root: buf[36..68].try_into().unwrap(),
+            timestamp: u32_at(68),
+            bits: u32_at(72),
+            nonce: u32_at(76),
+        })
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut ret = Vec::with_capacity(80);
+        ret.extend(self.version.to_le_bytes());
+        ret.extend(self.prev_block);
+        ret.extend(self.merkle_root);
+        ret.extend(self.timestamp.to_le_bytes());
+        ret.extend(self.bits.to_le_bytes());
+        ret.extend(self.nonce.to_le_bytes());
+        ret
+    }
+
+    /// Block hash in internal byte order.
+    pub fn hash(&self) -> [u8; 32] {
+        hash256(&self.serialize()).try_into().unwrap()
+    }
+
+    /// Human-readable block hash, as shown by block explorers.
+    pub fn id(&self) -> String {
+        let mut hash = self.hash();
+        hash.reverse();
+        hex::encode(hash)
+    }
+
+    /// The target the hash must not exceed, or None if `bits` is negative
+    /// or overflows.
+    pub fn target(&self) -> Option<U256> {
+        bits_to_target(self.bits)
+    }
+
+    /// How many times harder than `MAX_BITS` the target is, computed from
+    /// `bits` the way Bitcoin Core's `GetDifficulty` does.
+    pub fn difficulty(&self) -> f64 {
+        let mut shift = (self.bits >> 24) & 0xff;
+        let mut difficulty = 0xffff as f64 / (self.bits & 0x00ff_ffff) as f64;
+        while shift < 29 {
+            difficulty *= 256.0;
+            shift += 1;
+        }
+        while shift > 29 {
+            difficulty /= 256.0;
+            shift -= 1;
+        }
+        difficulty
+    }
+
+    /// Whether the hash, read as a little endian number, meets the target.
+    /// A zero, negative or overflowing target never does.
+    pub fn check_pow(&self) -> bool {
+        match self.target() {
+            Some(target) if !target.is_zero() => U256::from_little_endian(&self.hash()) <= target,
+            _ => false,
+        }
+    }
+
+    /// Expected number of hashes needed to meet the target, 2^256 / (target + 1).
+    pub fn work(&self) -> U256 {
+        match self.target() {
+            Some(target) if !target.is_zero() => (!target / (target + 1)) + 1,
+            _ => U256::zero(),
+        }
+    }
+
+    /// Whether the version uses BIP9 signaling (top bits 001).
+    pub fn bip9(&self) -> bool {
+        self.version >> 29 == 0b001
+    }
+
+    /// Whether the header signals readiness for the BIP9 deployment on
+    /// `bit`.
+    pub fn signals(&self, bit: u8) -> bool {
+        assert!(bit < 29);
+        self.bip9() && (self.version >> bit) & 1 == 1
+    }
+}
+
+/// Decodes a compact target: a one byte base 256 exponent and a three byte
+/// mantissa whose top bit is a sign bit. Returns None for negative or
+/// overflowing values.
+pub fn bits_to_target(bits: u32) -> Option<U256> {
+    let exponent = bits >> 24;
+    let mut mantissa = bits & 0x007f_ffff;
+    if exponent <= 3 {
+        mantissa >>= 8 * (3 - exponent);
+    }
+    if mantissa != 0
+        && (bits & 0x0080_0000 != 0
+            || exponent > 34
+            || (mantissa > 0xff && exponent > 33)
+            || (mantissa > 0xffff && exponent > 32))
+    {
+        return None;
+    }
+    if exponent <= 3 {
+        Some(U256::from(mantissa))
+    } else {
+        Some(U256::from(mantissa) << (8 * (exponent - 3)))
+    }
+}
+
+/// Encodes a target in compact form, rounding it down to three significant
+/// bytes.
+pub fn target_to_bits(target: U256) -> u32 {
+    let mut size = target.bits().div_ceil(8) as u32;
+    let mut mantissa = if size <= 3 {
+        target.low_u32() << (8 * (3 - size))
+    } else {
+        (target >> (8 * (size - 3))).low_u32()
+    };
+    // keep the sign bit clear
+    if mantissa & 0x0080_0000 != 0 {
+        mantissa >>= 8;
+        size += 1;
+    }
+    mantissa | size << 24
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn parse_header(s: &str) -> BlockHeader {
+        BlockHeader::parse(&mut Cursor::new(hex::decode(s).unwrap())).unwrap()
+    }
+
+    #[test]
+    fn test_parse() {
+        let raw = "0100000000000000000000000000000000000000000000000000000000000000000000003ba3edfd7a7b12b27ac72c3e67768f617fc81bc3888a51323a9fb8aa4b1e5e4a29ab5f49ffff001d1dac2b7c";
+        let genesis = parse_header(raw);
+        assert_eq!(genesis.version, 1);
+        assert_eq!(genesis.prev_block, [0; 32]);
+        assert_eq!(genesis.timestamp, 1231006505);
+        assert_eq!(genesis.bits, MAX_BITS);
+        assert_eq!(genesis.nonce, 2083236893);
+        assert_eq!(hex::encode(genesis.serialize()), raw);
+        assert_eq!(
+            genesis.id(),
+            "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f"
+        );
+        assert!(genesis.check_pow());
+        assert_eq!(genesis.difficulty(), 1.0);
+        assert_eq!(genesis.work(), U256::from(0x0001_0001_0001u64));
+        assert!(!genesis.bip9());
+    }
+
+    #[test]
+    fn test_pow() {
+        let header = parse_header("020000208ec39428b17323fa0ddec8e887b4a7c53b8c0a0a220cfd0000000000000000005b0750fce0a889502d40508d39576821155e9c9e3f5c3157f961db38fd8b25be1e77a759e93c0118a4ffd71d");
+        assert_eq!(
+            header.id(),
+            "0000000000000000007e9e4c586439b0cdbe13b1370bdd9435d76a644d047523"
+        );
+        assert_eq!(
+            header.target(),
+            Some(
+                U256::from_str_radix("13ce9000000000000000000000000000000000000000000", 16)
+                    .unwrap()
+            )
+        );
+        assert_eq!(header.difficulty(), 888171856257.3206);
+        assert!(header.check_pow());
+        assert!(header.bip9());
+        assert!(header.signals(1));
+        assert!(!header.signals(4));
+
+        let mut header = header;
+        header.nonce += 1;
+        assert!(!header.check_pow());
+    }
+
+    #[test]
+    fn test_compact() {
+        // from Bitcoin Core's arith_uint256 tests
+        for bits in [
+            0, 0x00123456, 0x01003456, 0x02000056, 0x03000000, 0x04000000, 0x00923456,
+        ] {
+            assert_eq!(bits_to_target(bits), Some(U256::zero()));
+        }
+        for (bits, target, compact) in [
+            (0x01123456, U256::from(0x12), 0x01120000),
+            (0x02123456, U256::from(0x1234), 0x02123400),
+            (0x03123456, U256::from(0x123456), 0x03123456),
+            (0x04123456, U256::from(0x12345600), 0x04123456),
+            (0x05009234, U256::from(0x92340000u32), 0x05009234),
+            (0x20123456, U256::from(0x123456) << 232, 0x20123456),
+        ] {
+            assert_eq!(bits_to_target(bits), Some(target));
+            assert_eq!(target_to_bits(target), compact);
+        }
+        assert_eq!(target_to_bits(U256::zero()), 0);
+        assert_eq!(bits_to_target(0x01fedcba), None);
+        assert_eq!(bits_to_target(0x04923456), None);
+        assert_eq!(bits_to_target(0xff123456), None);
+        assert_eq!(target_to_bits(bits_to_target(MAX_BITS).unwrap()), MAX_BITS);
+    }
+}
@@ -0,0 +1,346 @@
+use crate::{
+    script::{Command, Script, OP_1, OP_16, OP_CHECKMULTISIG, OP_CHECKSIG},
+    tx::{OutPoint, Tx},
+    varint::{encode_varint, read_varint},
+};
+use std::io::{self, Read};
+
+pub const MAX_BLOOM_FILTER_SIZE: usize = 36_000;
+pub const MAX_HASH_FUNCS: u32 = 50;
+/// Largest element a `filteradd` may carry, the script push limit.
+pub const MAX_FILTER_ADD_SIZE: u64 = 520;
+
+/// Never add outpoints of matched outputs to the filter.
+pub const BLOOM_UPDATE_NONE: u8 = 0;
+/// Add the outpoint of every output whose script matched.
+pub const BLOOM_UPDATE_ALL: u8 = 1;
+/// Only add outpoints of matched pay-to-pubkey and bare multisig outputs.
+pub const BLOOM_UPDATE_P2PUBKEY_ONLY: u8 = 2;
+const BLOOM_UPDATE_MASK: u8 = 3;
+
+const SEED_MULTIPLIER: u32 = 0xfba4_c795;
+
+/// MurmurHash3 x86_32, the hash BIP37 filters use.
+pub fn murmur3(data: &[u8], seed: u32) -> u32 {
+    const C1: u32 = 0xcc9e_2d51;
+    const C2: u32 = 0x1b87_3593;
+    let mut h = seed;
+    let mut chunks = data.chunks_exact(4);
+    for chunk in &mut chunks {
+        let k = u32::from_le_bytes(chunk.try_into().unwrap())
+            .wrapping_mul(C1)
+            .rotate_left(15)
+            .wrapping_mul(C2);
+        h = (h ^ k)
+            .rotate_left(13)
+            .wrapping_mul(5)
+            .wrapping_add(0xe654_6b64);
+    }
+    let tail = chunks.remainder();
+    if !tail.is_empty() {
+        let k = tail
+            .iter()
+            .rev()
+            .fold(0u32, |acc, b| acc << 8 | *b as u32)
+            .wrapping_mul(C1)
+            .rotate_left(15)
+            .wrapping_mul(C2);
+        h ^= k;
+    }
+    h ^= data.len() as u32;
+    h ^= h >> 16;
+    h = h.wrapping_mul(0x85eb_ca6b);
+    h ^= h >> 13;
+    h = h.wrapping_mul(0xc2b2_ae35);
+    h ^ (h >> 16)
+}
+
+/// A BIP37 filter, the payload of `filterload`.
+///
+/// `flags` decides whether matching a transaction output also adds its
+/// outpoint, so that the spending transaction matches too.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BloomFilter {
+    pub data: Vec<u8>,
+    pub hash_funcs: u32,
+    pub tweak: u32,
+    pub flags: u8,
+}
+
+impl BloomFilter {
+    pub fn new(size: usize, hash_funcs: u32, tweak: u32, flags: u8) -> Self {
+        Self {
+            data: vec![0; size],
+            hash_funcs,
+            tweak,
+            flags,
+        }
+    }
+
+    /// Sizes a filter for `elements` items with false positive rate
+    /// `fp_rate`, capped at the protocol limits, like Bitcoin Core.
+    pub fn for_elements(elements: u32, fp_rate: f64, tweak: u32, flags: u8) -> Self {
+        let ln2 = std::f64::consts::LN_2;
+        let bits = (-1.0 / (ln2 * ln2) * elements as f64 * fp_rate.ln()) as u32;
+        let size = bits.min(MAX_BLOOM_FILTER_SIZE as u32 * 8) as usize / 8;
+        let hash_funcs = ((size * 8) as f64 / elements as f64 * ln2) as u32;
+        Self::new(size, hash_funcs.min(MAX_HASH_FUNCS), tweak, flags)
+    }
+
+    fn bit(&self, n: u32, item: &[u8]) -> usize {
+        let seed = n.wrapping_mul(SEED_MULTIPLIER).wrapping_add(self.tweak);
+        murmur3(item, seed) as usize % (self.data.len() * 8)
+    }
+
+    pub fn insert(&mut self, item: &[u8]) {
+        if self.data.is_empty() {
+            return;
+        }
+        for n in 0..self.hash_funcs {
+            let bit = self.bit(n, item);
+            self.data[bit >> 3] |= 1 << (bit & 7);
+        }
+    }
+
+    /// Whether `item` may have been inserted. An empty filter matches
+    /// everything.
+    pub fn contains(&self, item: &[u8]) -> bool {
+        if self.data.is_empty() {
+            return true;
+        }
+        (0..self.hash_funcs).all(|n| {
+            let bit = self.bit(n, item);
+            self.data[bit >> 3] & (1 << (bit & 7)) != 0
+        })
+    }
+
+    pub fn insert_outpoint(&mut self, outpoint: &OutPoint) {
+        self.insert(&outpoint.serialize());
+    }
+
+    pub fn contains_outpoint(&self, outpoint: &OutPoint) -> bool {
+        self.contains(&outpoint.serialize())
+    }
+
+    pub fn is_within_size_constraints(&self) -> bool {
+        self.data.len() <= MAX_BLOOM_FILTER_SIZE && self.hash_funcs <= MAX_HASH_FUNCS
+    }
+
+    /// Matches a transaction the way a BIP37 node does before relaying it
+    /// or including it in a `merkleblock`: by txid, data pushed in output
+    /// scripts, spent outpoints or data pushed in input scripts. Matched
+    /// outputs are added to the filter as `flags` asks.
+    pub fn is_relevant_and_update(&mut self, tx: &Tx) -> bool {
+        let txid = tx.txid();
+        let mut found = self.contains(&txid);
+        for (vout, tx_out) in tx.tx_outs.iter().enumerate() {
+            let script = &tx_out.script_pubkey;
+            if !script
+                .pushed_data()
+                .iter()
+                .any(|data| !data.is_empty() && self.contains(data))
+            {
+                continue;
+            }
+            found = true;
+            let update = match self.flags & BLOOM_UPDATE_MASK {
+                BLOOM_UPDATE_ALL => true,
+                BLOOM_UPDATE_P2PUBKEY_ONLY => is_pubkey_or_multisig(script),
+                _ => false,
+            };
+            if update {
+                self.insert_outpoint(&OutPoint::new(txid, vout as u32));
+            }
+        }
+        if found {
+            return true;
+        }
+        tx.tx_ins.iter().any(|tx_in| {
+            self.contains_outpoint(&tx_in.prev_out)
+                || tx_in
+                    .script_sig
+                    .pushed_data()
+                    .iter()
+                    .any(|data| !data.is_empty() && self.contains(data))
+        })
+    }
+
+    /// Parses a `filterload` payload.
+    pub fn parse<R: Read>(stream: &mut R) -> io::Result<Self> {
+        let size = read_varint(stream)?;
+        if size > MAX_BLOOM_FILTER_SIZE as u64 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "bloom filter too large",
+            ));
+        }
+        let mut data = vec![0; size as usize];
+        stream.read_exact(&mut data)?;
+        let mut buf = [0u8; 9];
+        stream.read_exact(&mut buf)?;
+        let filter = Self {
+            data,
+            hash_funcs: u32::from_le_bytes(buf[..4].try_into().unwrap()),
+            tweak: u32::from_le_bytes(buf[4..8].try_into().unwrap()),
+            flags: buf[8],
+        };
+        if !filter.is_within_size_constraints() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "too many bloom filter hash functions",
+            ));
+        }
+        Ok(filter)
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut ret = encode_varint(self.data.len() as u64);
+        ret.extend(&self.data);
+        ret.extend(self.hash_funcs.to_le_bytes());
+        ret.extend(self.tweak.to_le_bytes());
+        ret.push(self.flags);
+        ret
+    }
+}
+
+fn is_pubkey(b: &[u8]) -> bool {
+    (b.len() == 33 && (b[0] == 2 || b[0] == 3)) || (b.len() == 65 && b[0] == 4)
+}
+
+/// Whether the script is `<pubkey> OP_CHECKSIG` or bare
+/// `OP_m <pubkey>... OP_n OP_CHECKMULTISIG`.
+fn is_pubkey_or_multisig(script: &Script) -> bool {
+    let Ok(cmds) = script.commands() else {
+        return false;
+    };
+    match cmds.as_slice() {
+        [Command::Data(pubkey), Command::Op(OP_CHECKSIG)] => is_pubkey(pubkey),
+        [Command::Op(m @ OP_1..=OP_16), keys @ .., Command::Op(n @ OP_1..=OP_16), Command::Op(OP_CHECKMULTISIG)] => {
+            m <= n
+                && (n - OP_1 + 1) as usize == keys.len()
+                && keys
+                    .iter()
+                    .all(|key| matches!(key, Command::Data(pubkey) if is_pubkey(pubkey)))
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tx::{TxIn, TxOut, SEQUENCE_FINAL};
+
+    #[test]
+    fn test_murmur3() {
+        // from Bitcoin Core's hash tests
+        for (expected, seed, data) in [
+            (0x00000000, 0x00000000, ""),
+            (0x6a396f08, 0xfba4c795, ""),
+            (0x81f16f39, 0xffffffff, ""),
+            (0x514e28b7, 0x00000000, "00"),
+            (0xea3f0b17, 0xfba4c795, "00"),
+            (0xfd6cf10d, 0x00000000, "ff"),
+            (0x16c6b7ab, 0x00000000, "0011"),
+            (0x8eb51c3d, 0x00000000, "001122"),
+            (0xb4471bf8, 0x00000000, "00112233"),
+            (0xe2301fa8, 0x00000000, "0011223344"),
+            (0xfc2e4a15, 0x00000000, "001122334455"),
+            (0xb074502c, 0x00000000, "00112233445566"),
+            (0x8034d2a0, 0x00000000, "0011223344556677"),
+            (0xb4698def, 0x00000000, "001122334455667788"),
+        ] {
+            assert_eq!(murmur3(&hex::decode(data).unwrap(), seed), expected);
+        }
+    }
+
+    #[test]
+    fn test_insert_and_serialize() {
+        // from Bitcoin Core's bloom tests
+        for (tweak, expected) in [
+            (0, "03614e9b050000000000000001"),
+            (2147483649, "03ce4299050000000100008001"),
+        ] {
+            let mut filter = BloomFilter::for_elements(3, 0.01, tweak, BLOOM_UPDATE_ALL);
+            let item = hex::decode("99108ad8ed9bb6274d3980bab5a85c048f0950c8").unwrap();
+            filter.insert(&item);
+            assert!(filter.contains(&item));
+            let other = hex::decode("19108ad8ed9bb6274d3980bab5a85c048f0950c8").unwrap();
+            assert!(!filter.contains(&other));
+            for item in [
+                "b5a2c786d9ef4658287ced5914b37a1b4aa32eee",
+                "b9300670b4c5366e95b2699e8b18bc75e5f729c5",
+            ] {
+                filter.insert(&hex::decode(item).unwrap());
+            }
+            assert_eq!(hex::encode(filter.serialize()), expected);
+            let raw = filter.serialize();
+            assert_eq!(BloomFilter::parse(&mut &raw[..]).unwrap(), filter);
+        }
+
+        let mut filter = BloomFilter::new(10, 5, 99, BLOOM_UPDATE_ALL);
+        filter.insert(b"Hello World");
+        assert_eq!(hex::encode(&filter.data), "0000000a080000000140");
+        filter.insert(b"Goodbye!");
+        assert_eq!(
+            hex::encode(filter.serialize()),
+            "0a4000600a080000010940050000006300000001"
+        );
+
+        let too_many_funcs = BloomFilter::new(1, MAX_HASH_FUNCS + 1, 0, 0).serialize();
+        assert!(BloomFilter::parse(&mut &too_many_funcs[..]).is_err());
+        assert!(BloomFilter::new(0, 1, 0, 0).contains(b"anything"));
+    }
+
+    #[test]
+    fn test_relevant_and_update() {
+        let pubkey = [&[0x02][..], &[0x55; 32]].concat();
+        let p2pk = Script::new(&[Command::Data(pubkey.clone()), Command::Op(OP_CHECKSIG)]);
+        let funding = Tx::new(
+            1,
+            vec![TxIn::new(OutPoint::new([1; 32], 0), SEQUENCE_FINAL)],
+            vec![
+                TxOut::new(1000, Script::p2pkh(&[0x66; 20])),
+                TxOut::new(2000, p2pk),
+            ],
+            0,
+        );
+        let mut spend_in = TxIn::new(OutPoint::new(funding.txid(), 1), SEQUENCE_FINAL);
+        spend_in.script_sig = Script::new(&[Command::Data(vec![0x30; 71])]);
+        let spend = Tx::new(
+            1,
+            vec![spend_in],
+            vec![TxOut::new(1500, Script::p2wpkh(&[0x77; 20]))],
+            0,
+        );
+
+        // the funding output matches and its outpoint is added, unless the
+        // flags say otherwise
+        for (flags, spend_matches) in [
+            (BLOOM_UPDATE_NONE, false),
+            (BLOOM_UPDATE_ALL, true),
+            (BLOOM_UPDATE_P2PUBKEY_ONLY, true),
+        ] {
+            let mut filter = BloomFilter::for_elements(10, 0.000001, 0, flags);
+            filter.insert(&pubkey);
+            assert!(filter.is_relevant_and_update(&funding));
+            assert_eq!(filter.is_relevant_and_update(&spend), spend_matches);
+        }
+        let mut filter = BloomFilter::for_elements(10, 0.000001, 0, BLOOM_UPDATE_P2PUBKEY_ONLY);
+        filter.insert(&[0x66; 20]);
+        assert!(filter.is_relevant_and_update(&funding));
+        assert!(!filter.contains_outpoint(&OutPoint::new(funding.txid(), 0)));
+
+        // by txid, spent outpoint and input script push
+        for item in [
+            spend.txid().to_vec(),
+            OutPoint::new(funding.txid(), 1).serialize(),
+            vec![0x30; 71],
+        ] {
+            let mut filter = BloomFilter::for_elements(10, 0.000001, 0, BLOOM_UPDATE_NONE);
+            filter.insert(&item);
+            assert!(filter.is_relevant_and_update(&spend));
+            assert!(!filter.is_relevant_and_update(&funding));
+        }
+    }
+}
@@ -0,0 +1,552 @@
+use crate::{
+    fee::{dust_threshold, weight_to_vsize, FeeRate},
+    random::Rng,
+    script::Script,
+    tx::TxOut,
+    tx_builder::{SpendType, Utxo},
+};
+use std::cmp::Reverse;
+
+/// Branch-and-Bound gives up after exploring this many nodes.
+const TOTAL_TRIES: usize = 100_000;
+/// Knapsack rounds of random subset search.
+const KNAPSACK_ITERATIONS: usize = 1000;
+/// Bounds of the randomized change target.
+pub const CHANGE_LOWER: u64 = 50_000;
+pub const CHANGE_UPPER: u64 = 1_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Algorithm {
+    BranchAndBound,
+    Knapsack,
+    SingleRandomDraw,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CoinSelectionParams {
+    pub effective_fee_rate: FeeRate,
+    /// The fee rate we expect to pay in the long run. Spending inputs now
+    /// is wasteful when it is above this rate, and thrifty when below.
+    pub long_term_fee_rate: FeeRate,
+    /// Fee for adding a change output at the effective fee rate.
+    pub change_fee: u64,
+    /// `change_fee` plus the fee to spend the change later.
+    pub cost_of_change: u64,
+    /// Change below this is not worth creating and goes to fees instead.
+    pub min_viable_change: u64,
+}
+
+impl CoinSelectionParams {
+    /// Derives the change costs from the change output and how it will be
+    /// spent. The later spend is priced at the long-term fee rate. `None`
+    /// if the rates are too high for the costs to fit in a u64.
+    pub fn new(
+        effective_fee_rate: FeeRate,
+        long_term_fee_rate: FeeRate,
+        change_script: &Script,
+        change_spend_type: SpendType,
+    ) -> Option<Self> {
+        let change_output_size = TxOut::new(0, change_script.clone()).serialize().len() as u64;
+        let change_fee = effective_fee_rate.fee(change_output_size)?;
+        let change_spend_fee =
+            long_term_fee_rate.fee(weight_to_vsize(change_spend_type.input_weight()))?;
+        Some(Self {
+            effective_fee_rate,
+            long_term_fee_rate,
+            change_fee,
+            cost_of_change: change_fee.checked_add(change_spend_fee)?,
+            min_viable_change: change_spend_fee
+                .checked_add(1)?
+                .max(dust_threshold(change_script)),
+        })
+    }
+}
+
+/// The outcome of one selection algorithm.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Selection {
+    pub algorithm: Algorithm,
+    pub selected: Vec<Utxo>,
+    /// Sum of the effective values of `selected`.
+    pub effective_value: u64,
+    pub waste: i64,
+}
+
+impl Selection {
+    /// Change left after paying `target` and the change output, or 0 if
+    /// it is too small to be worth creating.
+    pub fn change(&self, target: u64, params: &CoinSelectionParams) -> u64 {
+        match self.effective_value.checked_sub(target + params.change_fee) {
+            Some(change) if change >= params.min_viable_change => change,
+            _ => 0,
+        }
+    }
+}
+
+/// A UTXO as seen by the algorithms.
+#[derive(Debug, Clone, Copy)]
+struct Candidate {
+    index: usize,
+    effective_value: u64,
+    fee: u64,
+    long_term_fee: u64,
+}
+
+impl Candidate {
+    /// Cost of spending this input now rather than at the long-term rate.
+    fn waste(&self) -> i64 {
+        self.fee as i64 - self.long_term_fee as i64
+    }
+}
+
+/// Candidates with a positive effective value; the others cost more to
+/// spend than they are worth.
+fn candidates(utxos: &[Utxo], params: &CoinSelectionParams) -> Vec<Candidate> {
+    utxos
+        .iter()
+        .enumerate()
+        .filter_map(|(index, utxo)| {
+            let vsize = weight_to_vsize(utxo.spend_type.input_weight());
+            let fee = params.effective_fee_rate.fee(vsize)?;
+            let long_term_fee = params.long_term_fee_rate.fee(vsize)?;
+            let effective_value = utxo.tx_out.amount.checked_sub(fee)?;
+            (effective_value > 0).then_some(Candidate {
+                index,
+                effective_value,
+                fee,
+                long_term_fee,
+            })
+        })
+        .collect()
+}
+
+/// Bitcoin Core's `GetSelectionWaste`: the input waste, plus either the
+/// cost of the change output or the excess given up to fees.
+fn waste(selected: &[Candidate], target: u64, params: &CoinSelectionParams) -> i64 {
+    let mut waste: i64 = selected.iter().map(|c| c.waste()).sum();
+    let effective_value: u64 = selected.iter().map(|c| c.effective_value).sum();
+    match effective_value.checked_sub(target + params.change_fee) {
+        Some(change) if change >= params.min_viable_change => {
+            waste += params.cost_of_change as i64;
+        }
+        _ => waste += (effective_value - target) as i64,
+    }
+    waste
+}
+
+fn make_selection(
+    algorithm: Algorithm,
+    utxos: &[Utxo],
+    selected: &[Candidate],
+    target: u64,
+    params: &CoinSelectionParams,
+) -> Selection {
+    Selection {
+        algorithm,
+        selected: selected.iter().map(|c| utxos[c.index].clone()).collect(),
+        effective_value: selected.iter().map(|c| c.effective_value).sum(),
+        waste: waste(selected, target, params),
+    }
+}
+
+/// Depth-first search for a changeless input set whose effective value
+/// lies in `target..=target + cost_of_change`, keeping the one with the
+/// least waste.
+pub fn select_coins_bnb(
+    utxos: &[Utxo],
+    target: u64,
+    params: &CoinSelectionParams,
+) -> Option<Selection> {
+    let mut pool = candidates(utxos, params);
+    let mut available: u64 = pool.iter().map(|c| c.effective_value).sum();
+    if available < target || pool.is_empty() {
+        return None;
+    }
+    pool.sort_by_key(|c| Reverse(c.effective_value));
+    // with a high fee rate every extra input only adds waste, so branches
+    // that are already more wasteful than the best can be cut
+    let is_fee_rate_high = pool[0].fee > pool[0].long_term_fee;
+
+    let mut value = 0;
+    let mut waste = 0i64;
+    let mut selection: Vec<usize> = Vec::new();
+    let mut best: Vec<usize> = Vec::new();
+    let mut best_waste = i64::MAX;
+    let mut index = 0;
+    for _ in 0..TOTAL_TRIES {
+        let mut backtrack = false;
+        if value + available < target
+            || value > target + params.cost_of_change
+            || (waste > best_waste && is_fee_rate_high)
+        {
+            backtrack = true;
+        } else if value >= target {
+            // the excess is given up to fees, which counts as waste
+            let total_waste = waste + (value - target) as i64;
+            if total_waste <= best_waste {
+                best = selection.clone();
+                best_waste = total_waste;
+            }
+            backtrack = true;
+        }
+
+        if backtrack {
+            let Some(&last) = selection.last() else {
+                break;
+            };
+            // put the UTXOs after the last inclusion back into the lookahead,
+            // then try the branch that omits it
+            index -= 1;
+            while index > last {
+                available += pool[index].effective_value;
+                index -= 1;
+            }
+            value -= pool[index].effective_value;
+            waste -= pool[index].waste();
+            selection.pop();
+        } else {
+            let candidate = pool[index];
+            available -= candidate.effective_value;
+            // skip a UTXO equivalent to the previous one if that one was
+            // omitted, as the branch would repeat an explored one
+            if selection.is_empty()
+                || selection.last() == Some(&(index - 1))
+                || candidate.effective_value != pool[index - 1].effective_value
+                || candidate.fee != pool[index - 1].fee
+            {
+                selection.push(index);
+                value += candidate.effective_value;
+                waste += candidate.waste();
+            }
+        }
+        index += 1;
+    }
+    if best.is_empty() {
+        return None;
+    }
+    let selected: Vec<Candidate> = best.iter().map(|&i| pool[i]).collect();
+    Some(make_selection(
+        Algorithm::BranchAndBound,
+        utxos,
+        &selected,
+        target,
+        params,
+    ))
+}
+
+/// The change amount the knapsack solver aims for, randomized so that
+/// change outputs are harder to tell apart from payments.
+fn change_target(payment: u64, change_fee: u64, rng: &mut Rng) -> u64 {
+    if payment <= CHANGE_LOWER / 2 {
+        change_fee + CHANGE_LOWER
+    } else {
+        let upper = (payment * 2).min(CHANGE_UPPER);
+        change_fee + rng.rand_range(upper - CHANGE_LOWER) + CHANGE_LOWER
+    }
+}
+
+/// Randomly includes candidates looking for the smallest sum reaching
+/// `target`. Returns the inclusion flags and their sum.
+fn approximate_best_subset(
+    pool: &[Candidate],
+    total_lower: u64,
+    target: u64,
+    rng: &mut Rng,
+) -> (Vec<bool>, u64) {
+    let mut best_included = vec![true; pool.len()];
+    let mut best = total_lower;
+    for _ in 0..KNAPSACK_ITERATIONS {
+        if best == target {
+            break;
+        }
+        let mut included = vec![false; pool.len()];
+        let mut total = 0;
+        let mut reached_target = false;
+        for pass in 0..2 {
+            if reached_target {
+                break;
+            }
+            for i in 0..pool.len() {
+                // the first pass includes at random, the second fills in the rest
+                let include = if pass == 0 {
+                    rng.rand_bool()
+                } else {
+                    !included[i]
+                };
+                if include {
+                    total += pool[i].effective_value;
+                    included[i] = true;
+                    if total >= target {
+                        reached_target = true;
+                        if total < best {
+                            best = total;
+                            best_included = included.clone();
+                        }
+                        total -= pool[i].effective_value;
+                        included[i] = false;
+                    }
+                }
+            }
+        }
+    }
+    (best_included, best)
+}
+
+/// Core's knapsack solver: an exact match, or the smallest single UTXO
+/// above the target, or a random subset search, whichever is closest.
+pub fn select_coins_knapsack(
+    utxos: &[Utxo],
+    target: u64,
+    params: &CoinSelectionParams,
+    rng: &mut Rng,
+) -> Option<Selection> {
+    let mut pool = candidates(utxos, params);
+    rng.shuffle(&mut pool);
+    let change_target = change_target(target, params.change_fee, rng);
+    let select = |selected: &[Candidate]| {
+        make_selection(Algorithm::Knapsack, utxos, selected, target, params)
+    };
+
+    let mut lowest_larger: Option<Candidate> = None;
+    let mut applicable = Vec::new();
+    let mut total_lower = 0;
+    for candidate in pool {
+        if candidate.effective_value == target {
+            return Some(select(&[candidate]));
+        } else if candidate.effective_value < target + change_target {
+            applicable.push(candidate);
+            total_lower += candidate.effective_value;
+        } else if lowest_larger.is_none_or(|l| candidate.effective_value < l.effective_value) {
+            lowest_larger = Some(candidate);
+        }
+    }
+    if total_lower == target {
+        return Some(select(&applicable));
+    }
+    if total_lower < target {
+        return lowest_larger.map(|l| select(&[l]));
+    }
+
+    applicable.sort_by_key(|c| Reverse(c.effective_value));
+    let (mut best_included, mut best) =
+        approximate_best_subset(&applicable, total_lower, target, rng);
+    if best != target && total_lower >= target + change_target {
+        (best_included, best) =
+            approximate_best_subset(&applicable, total_lower, target + change_target, rng);
+    }
+    // prefer the single larger UTXO if the subset neither matches exactly
+    // nor leaves enough change, or if it is not any smaller
+    if let Some(l) = lowest_larger {
+        if (best != target && best < target + change_target) || l.effective_value <= best {
+            return Some(select(&[l]));
+        }
+    }
+    let selected: Vec<Candidate> = applicable
+        .iter()
+        .zip(best_included)
+        .filter(|(_, included)| *included)
+        .map(|(c, _)| *c)
+        .collect();
+    Some(select(&selected))
+}
+
+/// Picks UTXOs in random order until they cover the target and leave at
+/// least `CHANGE_LOWER` of change.
+pub fn select_coins_srd(
+    utxos: &[Utxo],
+    target: u64,
+    params: &CoinSelectionParams,
+    rng: &mut Rng,
+) -> Option<Selection> {
+    let mut pool = candidates(utxos, params);
+    rng.shuffle(&mut pool);
+    let srd_target = target + CHANGE_LOWER + params.change_fee;
+    let mut value = 0;
+    for (i, candidate) in pool.iter().enumerate() {
+        value += candidate.effective_value;
+        if value >= srd_target {
+            return Some(make_selection(
+                Algorithm::SingleRandomDraw,
+                utxos,
+                &pool[..=i],
+                target,
+                params,
+            ));
+        }
+    }
+    None
+}
+
+/// Selects UTXOs the way Bitcoin Core's wallet does: runs Branch-and-Bound,
+/// the knapsack solver and Single Random Draw and keeps the least wasteful
+/// result, preferring more inputs on a tie.
+///
+/// UTXOs are compared by effective value, their amount minus the fee to
+/// spend them at the effective fee rate. `target` is what the inputs have
+/// to pay for: the recipients plus the fee for the rest of the transaction.
+pub fn select_coins(
+    utxos: &[Utxo],
+    target: u64,
+    params: &CoinSelectionParams,
+    rng: &mut Rng,
+) -> Option<Selection> {
+    [
+        select_coins_bnb(utxos, target, params),
+        select_coins_knapsack(utxos, target, params, rng),
+        select_coins_srd(utxos, target, params, rng),
+    ]
+    .into_iter()
+    .flatten()
+    .min_by(|a, b| {
+        a.waste
+            .cmp(&b.waste)
+            .then(b.selected.len().cmp(&a.selected.len()))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tx::OutPoint;
+
+    /// P2WPKH UTXOs with these amounts, the n-th spending output n.
+    fn utxos(amounts: &[u64]) -> Vec<Utxo> {
+        amounts
+            .iter()
+            .enumerate()
+            .map(|(i, &amount)| {
+                Utxo::new(
+                    OutPoint::new([0x11; 32], i as u32),
+                    TxOut::new(amount, Script::p2wpkh(&[0x22; 20])),
+                    SpendType::P2wpkh,
+                )
+            })
+            .collect()
+    }
+
+    fn params(sat_per_vb: u64, long_term_sat_per_vb: u64) -> CoinSelectionParams {
+        CoinSelectionParams::new(
+            FeeRate::from_sat_per_vb(sat_per_vb).unwrap(),
+            FeeRate::from_sat_per_vb(long_term_sat_per_vb).unwrap(),
+            &Script::p2wpkh(&[0x33; 20]),
+            SpendType::P2wpkh,
+        )
+        .unwrap()
+    }
+
+    fn vouts(selection: &Selection) -> Vec<u32> {
+        let mut vouts: Vec<u32> = selection.selected.iter().map(|u| u.outpoint.vout).collect();
+        vouts.sort();
+        vouts
+    }
+
+    #[test]
+    fn test_params() {
+        let params = params(10, 5);
+        // a 31 byte P2WPKH output at 10 sat/vB, spent later as 68 vB at 5 sat/vB
+        assert_eq!(params.change_fee, 310);
+        assert_eq!(params.cost_of_change, 310 + 340);
+        assert_eq!(params.min_viable_change, 341);
+    }
+
+    #[test]
+    fn test_bnb_exact_match() {
+        // every P2WPKH input costs 68 vB, i.e. 68 sats at 1 sat/vB
+        let pool = utxos(&[100_068, 200_068, 300_068, 400_068]);
+        let params = params(1, 1);
+        let selection = select_coins_bnb(&pool, 500_000, &params).unwrap();
+        assert_eq!(selection.algorithm, Algorithm::BranchAndBound);
+        assert_eq!(selection.effective_value, 500_000);
+        assert_eq!(selection.waste, 0);
+        // {400k, 100k} and {300k, 200k} tie and the later one found wins
+        assert_eq!(vouts(&selection), vec![1, 2]);
+
+        assert_eq!(
+            select_coins_bnb(&pool, 1_000_000, &params)
+                .unwrap()
+                .selected
+                .len(),
+            4
+        );
+        // nothing lands within cost_of_change above the target
+        assert!(select_coins_bnb(&pool, 50_000, &params).is_none());
+        assert!(select_coins_bnb(&pool, 1_000_001, &params).is_none());
+    }
+
+    #[test]
+    fn test_bnb_waste_follows_fee_rate() {
+        // at 20 sat/vB, inputs cost 1360 sats now and 680 in the long run,
+        // so the single input is less wasteful
+        let pool = utxos(&[101_360, 61_360, 41_360]);
+        let selection = select_coins_bnb(&pool, 100_000, &params(20, 10)).unwrap();
+        assert_eq!(vouts(&selection), vec![0]);
+        assert_eq!(selection.waste, 680);
+
+        // at 5 sat/vB spending now is cheap, so consolidate
+        let pool = utxos(&[100_340, 60_340, 40_340]);
+        let selection = select_coins_bnb(&pool, 100_000, &params(5, 10)).unwrap();
+        assert_eq!(vouts(&selection), vec![1, 2]);
+        assert_eq!(selection.waste, -680);
+    }
+
+    #[test]
+    fn test_knapsack() {
+        let params = params(1, 1);
+        let mut rng = Rng::new(1);
+        // an exact single match is taken right away
+        let pool = utxos(&[100_068, 250_068, 1_000_068]);
+        let selection = select_coins_knapsack(&pool, 250_000, &params, &mut rng).unwrap();
+        assert_eq!(selection.algorithm, Algorithm::Knapsack);
+        assert_eq!(vouts(&selection), vec![1]);
+
+        // the smaller UTXOs cannot reach the target, so the smallest larger one is used
+        let pool = utxos(&[10_068, 20_068, 5_000_068, 3_000_068]);
+        let selection = select_coins_knapsack(&pool, 100_000, &params, &mut rng).unwrap();
+        assert_eq!(vouts(&selection), vec![3]);
+
+        // a subset adding up to the target exactly is found
+        let pool = utxos(&[10_068, 20_068, 30_068, 40_068, 50_068]);
+        let selection = select_coins_knapsack(&pool, 70_000, &params, &mut rng).unwrap();
+        assert_eq!(selection.effective_value, 70_000);
+
+        assert!(select_coins_knapsack(&pool, 150_001, &params, &mut rng).is_none());
+    }
+
+    #[test]
+    fn test_srd() {
+        let params = params(1, 1);
+        let pool = utxos(&[30_068, 40_068, 50_068, 60_068, 70_068]);
+        let mut rng = Rng::new(7);
+        let selection = select_coins_srd(&pool, 60_000, &params, &mut rng).unwrap();
+        assert_eq!(selection.algorithm, Algorithm::SingleRandomDraw);
+        assert!(selection.effective_value >= 60_000 + CHANGE_LOWER + params.change_fee);
+        // dropping the last drawn UTXO would not have been enough
+        let last = selection.selected.last().unwrap().tx_out.amount - 68;
+        assert!(selection.effective_value - last < 60_000 + CHANGE_LOWER + params.change_fee);
+        // the same seed draws the same UTXOs
+        assert_eq!(
+            select_coins_srd(&pool, 60_000, &params, &mut Rng::new(7)),
+            Some(selection)
+        );
+        assert!(select_coins_srd(&pool, 200_000, &params, &mut rng).is_none());
+    }
+
+    #[test]
+    fn test_select_coins_prefers_least_waste() {
+        let params = params(20, 10);
+        let pool = utxos(&[101_360, 61_360, 41_360, 500_000]);
+        let selection = select_coins(&pool, 100_000, &params, &mut Rng::new(3)).unwrap();
+        // BnB's changeless single input beats anything creating change
+        assert_eq!(selection.algorithm, Algorithm::BranchAndBound);
+        assert_eq!(vouts(&selection), vec![0]);
+        assert_eq!(selection.change(100_000, &params), 0);
+
+        let selection = select_coins(&pool, 300_000, &params, &mut Rng::new(3)).unwrap();
+        assert!(selection.change(300_000, &params) > 0);
+        assert_eq!(
+            selection.waste,
+            params.cost_of_change as i64 + 680 * selection.selected.len() as i64
+        );
+        assert!(select_coins(&pool, 1_000_000, &params, &mut Rng::new(3)).is_none());
+    }
+}
@@ -0,0 +1,1091 @@
+use crate::{
+    address::{Address, AddressError},
+    base58::{decode_base58_checksum, hash160},
+    bip32::{format_path, parse_path, Bip32Error, ExtendedPrivateKey, ExtendedPublicKey, HARDENED},
+    interpreter::MAX_SCRIPT_ELEMENT_SIZE,
+    network::Network,
+    psbt::KeySource,
+    script::{Command, Script, OP_CHECKMULTISIG, OP_CHECKSIG},
+    secp256k1::{S256Point, N},
+    signature::PrivateKey,
+    taproot::{lift_x, tap_branch_hash, tap_leaf_hash, tweak_key, TAPROOT_LEAF_TAPSCRIPT},
+};
+use primitive_types::U256;
+use sha2::{Digest, Sha256};
+use std::{fmt, str::FromStr};
+
+/// Characters a descriptor may contain, grouped so that the checksum
+/// catches the most common typos (BIP380).
+const INPUT_CHARSET: &str =
+    "0123456789()[],'/*abcdefgh@:$%{}IJKLMNOPQRSTUVWXYZ&+-.;<=>?!^_|~ijklmnopqrstuvwxyzABCDEFGH`#\"\\ ";
+const CHECKSUM_CHARSET: &[u8] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+/// Keys allowed in a bare `multi()`, as in Bitcoin Core.
+const MAX_BARE_MULTISIG_KEYS: usize = 3;
+/// Keys allowed in a `multi()` inside `wsh()`.
+const MAX_WSH_MULTISIG_KEYS: usize = 20;
+/// Deepest script tree BIP341 allows.
+const TAPROOT_CONTROL_MAX_NODE_COUNT: usize = 128;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DescriptorError {
+    /// The checksum after `#` is missing characters or does not match.
+    InvalidChecksum,
+    /// An unknown function, a missing or extra argument, or a character
+    /// outside the descriptor character set.
+    InvalidSyntax,
+    /// The function is not allowed where it appears, e.g. `wpkh()` inside
+    /// `wsh()`.
+    InvalidContext,
+    InvalidKey,
+    /// An uncompressed key in a segwit or taproot context.
+    UncompressedKey,
+    InvalidPath,
+    /// A threshold out of range, too many keys, or a redeem script over
+    /// 520 bytes.
+    InvalidMultisig,
+    InvalidAddress(AddressError),
+    /// Deriving a key failed, e.g. a hardened step from an xpub.
+    Bip32(Bip32Error),
+}
+
+impl From<Bip32Error> for DescriptorError {
+    fn from(e: Bip32Error) -> Self {
+        DescriptorError::Bip32(e)
+    }
+}
+
+fn polymod(symbols: &[u64]) -> u64 {
+    const GENERATOR: [u64; 5] = [
+        0xf5dee51989,
+        0xa9fdca3312,
+        0x1bab10e32d,
+        0x3706b1677a,
+        0x644d626ffd,
+    ];
+    let mut chk = 1;
+    for value in symbols {
+        let top = chk >> 35;
+        chk = (chk & 0x7ffffffff) << 5 ^ value;
+        for (i, g) in GENERATOR.iter().enumerate() {
+            if (top >> i) & 1 == 1 {
+                chk ^= g;
+            }
+        }
+    }
+    chk
+}
+
+/// The 8 character checksum of a descriptor without one, or `None` if it
+/// has characters outside the descriptor character set.
+pub fn descriptor_checksum(s: &str) -> Option<String> {
+    let mut symbols = Vec::new();
+    let mut groups = Vec::new();
+    for c in s.chars() {
+        let v = INPUT_CHARSET.find(c)? as u64;
+        symbols.push(v & 31);
+        groups.push(v >> 5);
+        if groups.len() == 3 {
+            symbols.push(groups[0] * 9 + groups[1] * 3 + groups[2]);
+            groups.clear();
+        }
+    }
+    match groups[..] {
+        [a] => symbols.push(a),
+        [a, b] => symbols.push(a * 3 + b),
+        _ => {}
+    }
+    symbols.extend([0; 8]);
+    let checksum = polymod(&symbols) ^ 1;
+    Some(
+        (0..8)
+            .map(|i| CHECKSUM_CHARSET[(checksum >> (5 * (7 - i)) & 31) as usize] as char)
+            .collect(),
+    )
+}
+
+/// Where an expression appears, which decides the functions and keys it
+/// may use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Context {
+    Top,
+    Sh,
+    Wsh,
+    /// The internal key or a leaf of `tr()`.
+    Tap,
+}
+
+/// Splits `name(args)` into its name and arguments.
+pub fn split_call(s: &str) -> Option<(&str, &str)> {
+    let (name, rest) = s.split_once('(')?;
+    Some((name, rest.strip_suffix(')')?))
+}
+
+/// Splits on the commas outside any parentheses or braces.
+pub fn split_args(s: &str) -> Vec<&str> {
+    let mut ret = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+    for (i, c) in s.char_indices() {
+        match c {
+            '(' | '{' => depth += 1,
+            ')' | '}' => depth -= 1,
+            ',' if depth == 0 => {
+                ret.push(&s[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    ret.push(&s[start..]);
+    ret
+}
+
+/// What a ranged extended key derives at its last step.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Wildcard {
+    None,
+    /// `/*`
+    Unhardened,
+    /// `/*'`
+    Hardened,
+}
+
+#[derive(Clone)]
+pub enum ExtendedKey {
+    Public(ExtendedPublicKey),
+    Private(ExtendedPrivateKey),
+}
+
+/// A key expression without its origin.
+#[derive(Clone)]
+pub enum KeyExpr {
+    /// A hex public key: a 33 or 65 byte SEC encoding, or a 32 byte x-only
+    /// key inside `tr()`.
+    Public(Vec<u8>),
+    /// A WIF private key.
+    Private {
+        key: PrivateKey,
+        compressed: bool,
+        network: Network,
+    },
+    /// An extended key, the path derived from it and the wildcard step
+    /// taking the derivation index.
+    Extended {
+        key: ExtendedKey,
+        path: Vec<u32>,
+        wildcard: Wildcard,
+    },
+}
+
+/// A key with the fingerprint and path it was derived along, if given.
+#[derive(Clone)]
+pub struct DescriptorKey {
+    pub origin: Option<KeySource>,
+    pub key: KeyExpr,
+}
+
+/// `path` with the wildcard step for derivation `index` added.
+fn full_path(path: &[u32], wildcard: Wildcard, index: u32) -> Result<Vec<u32>, DescriptorError> {
+    let mut path = path.to_vec();
+    match wildcard {
+        Wildcard::None => {}
+        Wildcard::Unhardened if index < HARDENED => path.push(index),
+        Wildcard::Hardened if index < HARDENED => path.push(index + HARDENED),
+        _ => return Err(DescriptorError::InvalidPath),
+    }
+    Ok(path)
+}
+
+/// Decodes a WIF private key.
+fn parse_wif(s: &str) -> Option<KeyExpr> {
+    let raw = decode_base58_checksum(s)?;
+    let network = match raw.first()? {
+        0x80 => Network::Mainnet,
+        0xef => Network::Testnet,
+        _ => return None,
+    };
+    let compressed = match raw.len() {
+        33 => false,
+        34 if raw[33] == 1 => true,
+        _ => return None,
+    };
+    let secret = U256::from_big_endian(&raw[1..33]);
+    if secret.is_zero() || secret >= U256::from_str_radix(N, 16).unwrap() {
+        return None;
+    }
+    Some(KeyExpr::Private {
+        key: PrivateKey::new(secret),
+        compressed,
+        network,
+    })
+}
+
+impl DescriptorKey {
+    fn parse(s: &str, ctx: Context) -> Result<Self, DescriptorError> {
+        let (origin, s) = match s.strip_prefix('[') {
+            Some(rest) => {
+                let (origin, s) = rest.split_once(']').ok_or(DescriptorError::InvalidSyntax)?;
+                let (fingerprint, path) = origin.split_at(origin.find('/').unwrap_or(origin.len()));
+                let fingerprint =
+                    hex::decode(fingerprint).map_err(|_| DescriptorError::InvalidKey)?;
+                let fingerprint = fingerprint
+                    .try_into()
+                    .map_err(|_| DescriptorError::InvalidKey)?;
+                if path == "/" {
+                    return Err(DescriptorError::InvalidPath);
+                }
+                let path = parse_path(path).map_err(|_| DescriptorError::InvalidPath)?;
+                (Some(KeySource::new(fingerprint, path)), s)
+            }
+            None => (None, s),
+        };
+
+        let key = if let Ok(raw) = hex::decode(s) {
+            let valid = match raw.len() {
+                32 => ctx == Context::Tap && lift_x(raw[..].try_into().unwrap()).is_some(),
+                33 | 65 => S256Point::try_parse(&raw).is_some(),
+                _ => false,
+            };
+            if !valid {
+                return Err(DescriptorError::InvalidKey);
+            }
+            KeyExpr::Public(raw)
+        } else if let Some(key) = parse_wif(s) {
+            key
+        } else {
+            let mut steps = s.split('/');
+            let encoded = steps.next().unwrap();
+            let key = match ExtendedPublicKey::parse(encoded) {
+                Ok(key) => ExtendedKey::Public(key),
+                Err(Bip32Error::UnknownVersion) => {
+                    ExtendedKey::Private(ExtendedPrivateKey::parse(encoded)?)
+                }
+                Err(_) => return Err(DescriptorError::InvalidKey),
+            };
+            let mut steps: Vec<&str> = steps.collect();
+            let wildcard = match steps.last() {
+                Some(&"*") => Wildcard::Unhardened,
+                Some(&"*'" | &"*h") => Wildcard::Hardened,
+                _ => Wildcard::None,
+            };
+            if wildcard != Wildcard::None {
+                steps.pop();
+            }
+            if steps.iter().any(|step| step.is_empty()) {
+                return Err(DescriptorError::InvalidPath);
+            }
+            let path = parse_path(&steps.join("/")).map_err(|_| DescriptorError::InvalidPath)?;
+            let hardened = wildcard == Wildcard::Hardened || path.iter().any(|&i| i >= HARDENED);
+            if hardened && matches!(key, ExtendedKey::Public(_)) {
+                return Err(DescriptorError::Bip32(Bip32Error::HardenedFromPublic));
+            }
+            KeyExpr::Extended {
+                key,
+                path,
+                wildcard,
+            }
+        };
+        let ret = Self { origin, key };
+        if matches!(ctx, Context::Wsh | Context::Tap) && !ret.is_compressed() {
+            return Err(DescriptorError::UncompressedKey);
+        }
+        Ok(ret)
+    }
+
+    /// Whether the key is used compressed; x-only keys count as compressed.
+    pub fn is_compressed(&self) -> bool {
+        match &self.key {
+            KeyExpr::Public(raw) => raw.len() != 65,
+            KeyExpr::Private { compressed, .. } => *compressed,
+            KeyExpr::Extended { .. } => true,
+        }
+    }
+
+    pub fn is_ranged(&self) -> bool {
+        matches!(&self.key, KeyExpr::Extended { wildcard, .. } if *wildcard != Wildcard::None)
+    }
+
+    /// The public key at derivation `index`, as SEC or, for a hex x-only
+    /// key, 32 bytes. Keys without a wildcard ignore `index`.
+    pub fn derive(&self, index: u32) -> Result<Vec<u8>, DescriptorError> {
+        match &self.key {
+            KeyExpr::Public(raw) => Ok(raw.clone()),
+            KeyExpr::Private {
+                key, compressed, ..
+            } => Ok(if *compressed {
+                key.get_point().compressed_sec().to_vec()
+            } else {
+                key.get_point().sec().to_vec()
+            }),
+            KeyExpr::Extended {
+                key,
+                path,
+                wildcard,
+            } => {
+                let path = full_path(path, *wildcard, index)?;
+                let point = match key {
+                    ExtendedKey::Public(key) => key.derive_path(&path)?.point,
+                    ExtendedKey::Private(key) => key.derive_path(&path)?.to_public().point,
+                };
+                Ok(point.compressed_sec().to_vec())
+            }
+        }
+    }
+
+    /// The private key at derivation `index`, or `None` for a public key.
+    pub fn derive_private(&self, index: u32) -> Result<Option<PrivateKey>, DescriptorError> {
+        match &self.key {
+            KeyExpr::Private { key, .. } => Ok(Some(key.clone())),
+            KeyExpr::Extended {
+                key: ExtendedKey::Private(key),
+                path,
+                wildcard,
+            } => {
+                let path = full_path(path, *wildcard, index)?;
+                Ok(Some(key.derive_path(&path)?.get_private_key().clone()))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// The key with any private key replaced by its public key. An
+    /// extended private key is derived along its path up to the last
+    /// hardened step, which moves into the origin.
+    pub fn to_public(&self) -> Result<Self, DescriptorError> {
+        let mut origin = self.origin.clone();
+        let key = match &self.key {
+            KeyExpr::Public(_)
+            | KeyExpr::Extended {
+                key: ExtendedKey::Public(_),
+                ..
+            } => return Ok(self.clone()),
+            KeyExpr::Private {
+                key, compressed, ..
+            } => KeyExpr::Public(if *compressed {
+                key.get_point().compressed_sec().to_vec()
+            } else {
+                key.get_point().sec().to_vec()
+            }),
+            KeyExpr::Extended {
+                key: ExtendedKey::Private(key),
+                path,
+                wildcard,
+            } => {
+                if *wildcard == Wildcard::Hardened {
+                    return Err(DescriptorError::Bip32(Bip32Error::HardenedFromPublic));
+                }
+                let split = path
+                    .iter()
+                    .rposition(|&i| i >= HARDENED)
+                    .map_or(0, |i| i + 1);
+                let (hardened, rest) = path.split_at(split);
+                if !hardened.is_empty() {
+                    origin = Some(match &self.origin {
+                        Some(origin) => {
+                            KeySource::new(origin.fingerprint, [&origin.path, hardened].concat())
+                        }
+                        None => KeySource::new(key.fingerprint(), hardened.to_vec()),
+                    });
+                }
+                KeyExpr::Extended {
+                    key: ExtendedKey::Public(key.derive_path(hardened)?.to_public()),
+                    path: rest.to_vec(),
+                    wildcard: *wildcard,
+                }
+            }
+        };
+        Ok(Self { origin, key })
+    }
+
+    /// The x-only key at derivation `index`.
+    fn derive_x_only(&self, index: u32) -> Result<[u8; 32], DescriptorError> {
+        let key = self.derive(index)?;
+        Ok(key[key.len() - 32..].try_into().unwrap())
+    }
+}
+
+impl fmt::Display for DescriptorKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(origin) = &self.origin {
+            write!(
+                f,
+                "[{}{}]",
+                hex::encode(origin.fingerprint),
+                format_path(&origin.path)
+            )?;
+        }
+        match &self.key {
+            KeyExpr::Public(raw) => write!(f, "{}", hex::encode(raw)),
+            KeyExpr::Private {
+                key,
+                compressed,
+                network,
+            } => write!(f, "{}", key.wif(*compressed, *network != Network::Mainnet)),
+            KeyExpr::Extended {
+                key,
+                path,
+                wildcard,
+            } => {
+                match key {
+                    ExtendedKey::Public(key) => write!(f, "{}", key)?,
+                    ExtendedKey::Private(key) => write!(f, "{}", key)?,
+                }
+                write!(f, "{}", format_path(path))?;
+                match wildcard {
+                    Wildcard::None => Ok(()),
+                    Wildcard::Unhardened => write!(f, "/*"),
+                    Wildcard::Hardened => write!(f, "/*'"),
+                }
+            }
+        }
+    }
+}
+
+/// The script tree of a `tr()` descriptor.
+#[derive(Clone)]
+pub enum TapTree {
+    Leaf(Box<Descriptor>),
+    Branch(Box<TapTree>, Box<TapTree>),
+}
+
+impl TapTree {
+    fn parse(s: &str, depth: usize) -> Result<Self, DescriptorError> {
+        if depth > TAPROOT_CONTROL_MAX_NODE_COUNT {
+            return Err(DescriptorError::InvalidSyntax);
+        }
+        match s.strip_prefix('{') {
+            Some(rest) => {
+                let inner = rest
+                    .strip_suffix('}')
+                    .ok_or(DescriptorError::InvalidSyntax)?;
+                match split_args(inner)[..] {
+                    [a, b] => Ok(TapTree::Branch(
+                        Box::new(Self::parse(a, depth + 1)?),
+                        Box::new(Self::parse(b, depth + 1)?),
+                    )),
+                    _ => Err(DescriptorError::InvalidSyntax),
+                }
+            }
+            None => Ok(TapTree::Leaf(Box::new(Descriptor::parse_inner(
+                s,
+                Context::Tap,
+            )?))),
+        }
+    }
+
+    fn is_ranged(&self) -> bool {
+        match self {
+            TapTree::Leaf(leaf) => leaf.is_ranged(),
+            TapTree::Branch(a, b) => a.is_ranged() || b.is_ranged(),
+        }
+    }
+
+    fn to_public(&self) -> Result<Self, DescriptorError> {
+        Ok(match self {
+            TapTree::Leaf(leaf) => TapTree::Leaf(Box::new(leaf.to_public()?)),
+            TapTree::Branch(a, b) => {
+                TapTree::Branch(Box::new(a.to_public()?), Box::new(b.to_public()?))
+            }
+        })
+    }
+
+    /// The merkle root of the tree at derivation `index`.
+    pub fn merkle_root(&self, index: u32) -> Result<[u8; 32], DescriptorError> {
+        match self {
+            TapTree::Leaf(leaf) => Ok(tap_leaf_hash(
+                TAPROOT_LEAF_TAPSCRIPT,
+                &leaf.script(index, Context::Tap)?,
+            )),
+            TapTree::Branch(a, b) => Ok(tap_branch_hash(
+                &a.merkle_root(index)?,
+                &b.merkle_root(index)?,
+            )),
+        }
+    }
+}
+
+impl fmt::Display for TapTree {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TapTree::Leaf(leaf) => leaf.fmt_inner(f),
+            TapTree::Branch(a, b) => write!(f, "{{{},{}}}", a, b),
+        }
+    }
+}
+
+/// An output script descriptor (BIP380-386).
+///
+/// Parsing checks the checksum when one is given and the rules of each
+/// context: segwit and taproot keys must be compressed, `sh()` only wraps
+/// at top level, `wsh()` only at top level or in `sh()`. Inside `tr()`
+/// script leaves are `pk()` of x-only keys.
+///
+/// Displays with its checksum.
+#[derive(Clone)]
+pub enum Descriptor {
+    Pk(DescriptorKey),
+    Pkh(DescriptorKey),
+    Wpkh(DescriptorKey),
+    /// P2PK and P2PKH, plus P2WPKH and P2SH-P2WPKH for a compressed key.
+    Combo(DescriptorKey),
+    Sh(Box<Descriptor>),
+    Wsh(Box<Descriptor>),
+    /// `multi()`, or `sortedmulti()` with the keys sorted once derived.
+    Multi {
+        threshold: usize,
+        keys: Vec<DescriptorKey>,
+        sorted: bool,
+    },
+    Tr {
+        internal_key: DescriptorKey,
+        tree: Option<TapTree>,
+    },
+    Addr(Address),
+    Raw(Script),
+}
+
+impl Descriptor {
+    pub fn parse(s: &str) -> Result<Self, DescriptorError> {
+        let s = match s.rsplit_once('#') {
+            Some((s, checksum)) => {
+                if descriptor_checksum(s).ok_or(DescriptorError::InvalidSyntax)? != checksum {
+                    return Err(DescriptorError::InvalidChecksum);
+                }
+                s
+            }
+            None => s,
+        };
+        if descriptor_checksum(s).is_none() {
+            return Err(DescriptorError::InvalidSyntax);
+        }
+        Self::parse_inner(s, Context::Top)
+    }
+
+    fn parse_inner(s: &str, ctx: Context) -> Result<Self, DescriptorError> {
+        let (name, args) = split_call(s).ok_or(DescriptorError::InvalidSyntax)?;
+        let allowed = match name {
+            "pk" => true,
+            "pkh" => ctx != Context::Tap,
+            "wpkh" => matches!(ctx, Context::Top | Context::Sh),
+            "wsh" => matches!(ctx, Context::Top | Context::Sh),
+            "multi" | "sortedmulti" => ctx != Context::Tap,
+            "sh" | "combo" | "tr" | "addr" | "raw" => ctx == Context::Top,
+            _ => return Err(DescriptorError::InvalidSyntax),
+        };
+        if !allowed {
+            return Err(DescriptorError::InvalidContext);
+        }
+        Ok(match name {
+            "pk" => Descriptor::Pk(DescriptorKey::parse(args, ctx)?),
+            "pkh" => Descriptor::Pkh(DescriptorKey::parse(args, ctx)?),
+            "wpkh" => Descriptor::Wpkh(DescriptorKey::parse(args, Context::Wsh)?),
+            "combo" => Descriptor::Combo(DescriptorKey::parse(args, ctx)?),
+            "sh" => Descriptor::Sh(Box::new(Self::parse_inner(args, Context::Sh)?)),
+            "wsh" => Descriptor::Wsh(Box::new(Self::parse_inner(args, Context::Wsh)?)),
+            "multi" | "sortedmulti" => {
+                let args = split_args(args);
+                let threshold = args[0]
+                    .parse()
+                    .map_err(|_| DescriptorError::InvalidMultisig)?;
+                let keys = args[1..]
+                    .iter()
+                    .map(|key| DescriptorKey::parse(key, ctx))
+                    .collect::<Result<Vec<_>, _>>()?;
+                let max_keys = match ctx {
+                    Context::Top => MAX_BARE_MULTISIG_KEYS,
+                    _ => MAX_WSH_MULTISIG_KEYS,
+                };
+                if threshold == 0 || threshold > keys.len() || keys.len() > max_keys {
+                    return Err(DescriptorError::InvalidMultisig);
+                }
+                if ctx == Context::Sh {
+                    // OP_k, the pushes, OP_n and OP_CHECKMULTISIG
+                    let size: usize = keys
+                        .iter()
+                        .map(|key| if key.is_compressed() { 34 } else { 66 })
+                        .sum::<usize>()
+                        + 3;
+                    if size > MAX_SCRIPT_ELEMENT_SIZE {
+                        return Err(DescriptorError::InvalidMultisig);
+                    }
+                }
+                Descriptor::Multi {
+                    threshold,
+                    keys,
+                    sorted: name == "sortedmulti",
+                }
+            }
+            "tr" => {
+                let (key, tree) = match split_args(args)[..] {
+                    [key] => (key, None),
+                    [key, tree] => (key, Some(TapTree::parse(tree, 0)?)),
+                    _ => return Err(DescriptorError::InvalidSyntax),
+                };
+                Descriptor::Tr {
+                    internal_key: DescriptorKey::parse(key, Context::Tap)?,
+                    tree,
+                }
+            }
+            "addr" => {
+                Descriptor::Addr(Address::parse(args).map_err(DescriptorError::InvalidAddress)?)
+            }
+            "raw" => Descriptor::Raw(Script::from_bytes(
+                hex::decode(args).map_err(|_| DescriptorError::InvalidSyntax)?,
+            )),
+            _ => unreachable!(),
+        })
+    }
+
+    /// Whether the descriptor has a wildcard and so expands differently at
+    /// each index.
+    pub fn is_ranged(&self) -> bool {
+        match self {
+            Descriptor::Pk(key)
+            | Descriptor::Pkh(key)
+            | Descriptor::Wpkh(key)
+            | Descriptor::Combo(key) => key.is_ranged(),
+            Descriptor::Sh(inner) | Descriptor::Wsh(inner) => inner.is_ranged(),
+            Descriptor::Multi { keys, .. } => keys.iter().any(|key| key.is_ranged()),
+            Descriptor::Tr { internal_key, tree } => {
+                internal_key.is_ranged() || tree.as_ref().is_some_and(|t| t.is_ranged())
+            }
+            Descriptor::Addr(_) | Descriptor::Raw(_) => false,
+        }
+    }
+
+    /// The script this expression contributes at `index`: the output script
+    /// at top level, the redeem or witness script inside `sh()` or `wsh()`,
+    /// the leaf script inside `tr()`.
+    fn script(&self, index: u32, ctx: Context) -> Result<Script, DescriptorError> {
+        Ok(match self {
+            Descriptor::Pk(key) => {
+                let key = if ctx == Context::Tap {
+                    key.derive_x_only(index)?.to_vec()
+                } else {
+                    key.derive(index)?
+                };
+                Script::new(&[Command::Data(key), Command::Op(OP_CHECKSIG)])
+            }
+            Descriptor::Pkh(key) => {
+                Script::p2pkh(&hash160(&key.derive(index)?).try_into().unwrap())
+            }
+            Descriptor::Wpkh(key) => {
+                Script::p2wpkh(&hash160(&key.derive(index)?).try_into().unwrap())
+            }
+            Descriptor::Sh(inner) => {
+                let redeem_script = inner.script(index, Context::Sh)?;
+                Script::p2sh(&hash160(redeem_script.as_bytes()).try_into().unwrap())
+            }
+            Descriptor::Wsh(inner) => {
+                let witness_script = inner.script(index, Context::Wsh)?;
+                Script::p2wsh(&Sha256::digest(witness_script.as_bytes()).into())
+            }
+            Descriptor::Multi {
+                threshold,
+                keys,
+                sorted,
+            } => {
+                let mut keys = keys
+                    .iter()
+                    .map(|key| key.derive(index))
+                    .collect::<Result<Vec<_>, _>>()?;
+                if *sorted {
+                    keys.sort();
+                }
+                let mut cmds = vec![Command::int(*threshold as i64)];
+                cmds.extend(keys.into_iter().map(Command::Data));
+                cmds.push(Command::int(cmds.len() as i64 - 1));
+                cmds.push(Command::Op(OP_CHECKMULTISIG));
+                Script::new(&cmds)
+            }
+            Descriptor::Tr { internal_key, tree } => {
+                let merkle_root = tree.as_ref().map(|t| t.merkle_root(index)).transpose()?;
+                let (output_key, _) =
+                    tweak_key(&internal_key.derive_x_only(index)?, merkle_root.as_ref())
+                        .ok_or(DescriptorError::InvalidKey)?;
+                Script::p2tr(&output_key)
+            }
+            Descriptor::Addr(address) => address.script_pubkey(),
+            Descriptor::Raw(script) => script.clone(),
+            Descriptor::Combo(_) => unreachable!(),
+        })
+    }
+
+    /// The output scripts at derivation `index`; only `combo()` has more
+    /// than one.
+    pub fn script_pubkeys(&self, index: u32) -> Result<Vec<Script>, DescriptorError> {
+        let Descriptor::Combo(key) = self else {
+            return Ok(vec![self.script(index, Context::Top)?]);
+        };
+        let pubkey = key.derive(index)?;
+        let h160: [u8; 20] = hash160(&pubkey).try_into().unwrap();
+        let mut ret = vec![
+            Script::new(&[Command::Data(pubkey), Command::Op(OP_CHECKSIG)]),
+            Script::p2pkh(&h160),
+        ];
+        if key.is_compressed() {
+            let p2wpkh = Script::p2wpkh(&h160);
+            ret.push(Script::p2sh(
+                &hash160(p2wpkh.as_bytes()).try_into().unwrap(),
+            ));
+            ret.insert(2, p2wpkh);
+        }
+        Ok(ret)
+    }
+
+    /// The addresses of the output scripts at `index` that have one; bare
+    /// `pk()`, `multi()` and most `raw()` scripts do not.
+    pub fn addresses(&self, index: u32, network: Network) -> Result<Vec<Address>, DescriptorError> {
+        Ok(self
+            .script_pubkeys(index)?
+            .iter()
+            .filter_map(|s| Address::from_script_pubkey(s, network))
+            .collect())
+    }
+
+    /// The descriptor with every private key replaced by its public key,
+    /// to store or share without the secrets.
+    pub fn to_public(&self) -> Result<Self, DescriptorError> {
+        Ok(match self {
+            Descriptor::Pk(key) => Descriptor::Pk(key.to_public()?),
+            Descriptor::Pkh(key) => Descriptor::Pkh(key.to_public()?),
+            Descriptor::Wpkh(key) => Descriptor::Wpkh(key.to_public()?),
+            Descriptor::Combo(key) => Descriptor::Combo(key.to_public()?),
+            Descriptor::Sh(inner) => Descriptor::Sh(Box::new(inner.to_public()?)),
+            Descriptor::Wsh(inner) => Descriptor::Wsh(Box::new(inner.to_public()?)),
+            Descriptor::Multi {
+                threshold,
+                keys,
+                sorted,
+            } => Descriptor::Multi {
+                threshold: *threshold,
+                keys: keys
+                    .iter()
+                    .map(|key| key.to_public())
+                    .collect::<Result<_, _>>()?,
+                sorted: *sorted,
+            },
+            Descriptor::Tr { internal_key, tree } => Descriptor::Tr {
+                internal_key: internal_key.to_public()?,
+                tree: tree.as_ref().map(|t| t.to_public()).transpose()?,
+            },
+            Descriptor::Addr(_) | Descriptor::Raw(_) => self.clone(),
+        })
+    }
+
+    /// Whether any key is private, so the descriptor can sign.
+    pub fn has_private_keys(&self) -> bool {
+        fn is_private(key: &DescriptorKey) -> bool {
+            matches!(
+                key.key,
+                KeyExpr::Private { .. }
+                    | KeyExpr::Extended {
+                        key: ExtendedKey::Private(_),
+                        ..
+                    }
+            )
+        }
+        fn tree_has_private(tree: &TapTree) -> bool {
+            match tree {
+                TapTree::Leaf(leaf) => leaf.has_private_keys(),
+                TapTree::Branch(a, b) => tree_has_private(a) || tree_has_private(b),
+            }
+        }
+        match self {
+            Descriptor::Pk(key)
+            | Descriptor::Pkh(key)
+            | Descriptor::Wpkh(key)
+            | Descriptor::Combo(key) => is_private(key),
+            Descriptor::Sh(inner) | Descriptor::Wsh(inner) => inner.has_private_keys(),
+            Descriptor::Multi { keys, .. } => keys.iter().any(is_private),
+            Descriptor::Tr { internal_key, tree } => {
+                is_private(internal_key) || tree.as_ref().is_some_and(tree_has_private)
+            }
+            Descriptor::Addr(_) | Descriptor::Raw(_) => false,
+        }
+    }
+
+    fn fmt_inner(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Descriptor::Pk(key) => write!(f, "pk({})", key),
+            Descriptor::Pkh(key) => write!(f, "pkh({})", key),
+            Descriptor::Wpkh(key) => write!(f, "wpkh({})", key),
+            Descriptor::Combo(key) => write!(f, "combo({})", key),
+            Descriptor::Sh(inner) => {
+                write!(f, "sh(")?;
+                inner.fmt_inner(f)?;
+                write!(f, ")")
+            }
+            Descriptor::Wsh(inner) => {
+                write!(f, "wsh(")?;
+                inner.fmt_inner(f)?;
+                write!(f, ")")
+            }
+            Descriptor::Multi {
+                threshold,
+                keys,
+                sorted,
+            } => {
+                let name = if *sorted { "sortedmulti" } else { "multi" };
+                write!(f, "{}({}", name, threshold)?;
+                for key in keys {
+                    write!(f, ",{}", key)?;
+                }
+                write!(f, ")")
+            }
+            Descriptor::Tr { internal_key, tree } => match tree {
+                Some(tree) => write!(f, "tr({},{})", internal_key, tree),
+                None => write!(f, "tr({})", internal_key),
+            },
+            Descriptor::Addr(address) => write!(f, "addr({})", address),
+            Descriptor::Raw(script) => write!(f, "raw({})", hex::encode(script.as_bytes())),
+        }
+    }
+}
+
+impl FromStr for Descriptor {
+    type Err = DescriptorError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s)
+    }
+}
+
+impl fmt::Display for Descriptor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        struct Inner<'a>(&'a Descriptor);
+        impl fmt::Display for Inner<'_> {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                self.0.fmt_inner(f)
+            }
+        }
+        let s = Inner(self).to_string();
+        write!(f, "{}#{}", s, descriptor_checksum(&s).unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The generator point, whose HASH160 is 751e76e8...
+    const G: &str = "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+
+    fn expand(s: &str, index: u32) -> Vec<String> {
+        Descriptor::parse(s)
+            .unwrap()
+            .script_pubkeys(index)
+            .unwrap()
+            .iter()
+            .map(|s| hex::encode(s.as_bytes()))
+            .collect()
+    }
+
+    #[test]
+    fn test_checksum() {
+        assert_eq!(descriptor_checksum("raw(deadbeef)").unwrap(), "89f8spxm");
+        let desc = Descriptor::parse("raw(deadbeef)#89f8spxm").unwrap();
+        assert_eq!(desc.to_string(), "raw(deadbeef)#89f8spxm");
+        assert_eq!(
+            Descriptor::parse("raw(deadbeef)#89f8spxn").err(),
+            Some(DescriptorError::InvalidChecksum)
+        );
+        assert_eq!(
+            Descriptor::parse("raw(deadbeef)#").err(),
+            Some(DescriptorError::InvalidChecksum)
+        );
+        assert_eq!(
+            Descriptor::parse("raw(deadbeef)\u{e9}").err(),
+            Some(DescriptorError::InvalidSyntax)
+        );
+    }
+
+    #[test]
+    fn test_single_key() {
+        assert_eq!(expand(&format!("pk({})", G), 0), [format!("21{}ac", G)]);
+        assert_eq!(
+            expand(&format!("pkh({})", G), 0),
+            ["76a914751e76e8199196d454941c45d1b3a323f1433bd688ac"]
+        );
+        assert_eq!(
+            expand(&format!("sh(wpkh({}))", G), 0),
+            ["a914bcfeb728b584253d5f3f70bcb780e9ef218a68f487"]
+        );
+        assert_eq!(
+            expand(&format!("combo({})", G), 0),
+            [
+                format!("21{}ac", G),
+                "76a914751e76e8199196d454941c45d1b3a323f1433bd688ac".to_string(),
+                "0014751e76e8199196d454941c45d1b3a323f1433bd6".to_string(),
+                "a914bcfeb728b584253d5f3f70bcb780e9ef218a68f487".to_string(),
+            ]
+        );
+        let desc = Descriptor::parse(&format!("wpkh([d34db33f/84'/0h/0']{})", G)).unwrap();
+        assert_eq!(
+            desc.addresses(0, Network::Mainnet).unwrap()[0].to_string(),
+            "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4"
+        );
+        assert!(desc
+            .to_string()
+            .starts_with("wpkh([d34db33f/84'/0'/0']0279"));
+        assert!(!desc.is_ranged());
+        assert!(!desc.has_private_keys());
+
+        // uncompressed keys are only allowed outside segwit
+        let uncompressed = "04a34b99f22c790c4e36b2b3c2c35a36db06226e41c692fc82b8b56ac1c540c5bd5b8dec5235a0fa8722476c7709c02559e3aa73aa03918ba2d492eea75abea235";
+        assert!(Descriptor::parse(&format!("pkh({})", uncompressed)).is_ok());
+        assert_eq!(
+            Descriptor::parse(&format!("wpkh({})", uncompressed)).err(),
+            Some(DescriptorError::UncompressedKey)
+        );
+        assert_eq!(
+            Descriptor::parse(&format!("wsh(pk({}))", uncompressed)).err(),
+            Some(DescriptorError::UncompressedKey)
+        );
+        assert_eq!(
+            Descriptor::parse(&format!("wsh(wpkh({}))", G)).err(),
+            Some(DescriptorError::InvalidContext)
+        );
+        assert_eq!(
+            Descriptor::parse(&format!("sh(sh(pk({})))", G)).err(),
+            Some(DescriptorError::InvalidContext)
+        );
+        assert_eq!(
+            Descriptor::parse(&format!("pkh({}00)", G)).err(),
+            Some(DescriptorError::InvalidKey)
+        );
+    }
+
+    #[test]
+    fn test_multi() {
+        let a = "03a34b99f22c790c4e36b2b3c2c35a36db06226e41c692fc82b8b56ac1c540c5bd";
+        let multi = expand(&format!("multi(1,{},{})", a, G), 0);
+        let sorted = expand(&format!("sortedmulti(1,{},{})", a, G), 0);
+        assert_eq!(multi, [format!("5121{}21{}52ae", a, G)]);
+        assert_eq!(sorted, [format!("5121{}21{}52ae", G, a)]);
+        let wsh = Descriptor::parse(&format!("wsh(sortedmulti(1,{},{}))", a, G)).unwrap();
+        let witness_script = hex::decode(&sorted[0]).unwrap();
+        assert_eq!(
+            wsh.script_pubkeys(0).unwrap(),
+            [Script::p2wsh(&Sha256::digest(&witness_script).into())]
+        );
+
+        for bad in [
+            format!("multi(0,{})", G),
+            format!("multi(2,{})", G),
+            format!("multi(1,{},{},{},{})", G, G, G, G),
+            format!("sh(multi(1{}))", format!(",{}", G).repeat(16)),
+        ] {
+            assert_eq!(
+                Descriptor::parse(&bad).err(),
+                Some(DescriptorError::InvalidMultisig)
+            );
+        }
+        assert!(
+            Descriptor::parse(&format!("sh(multi(1{}))", format!(",{}", G).repeat(15))).is_ok()
+        );
+    }
+
+    #[test]
+    fn test_ranged_xpub() {
+        // BIP382 test vector
+        let s = "wpkh([ffffffff/13']xpub69H7F5d8KSRgmmdJg2KhpAK8SR3DjMwAdkxj3ZuxV27CprR9LgpeyGmXUbC6wb7ERfvrnKZjXoUmmDznezpbZb7ap6r1D3tgFxHmwMkQTPH/1/2/*)";
+        let desc = Descriptor::parse(s).unwrap();
+        assert!(desc.is_ranged());
+        assert_eq!(
+            desc.to_string(),
+            format!("{}#{}", s, descriptor_checksum(s).unwrap())
+        );
+        assert_eq!(
+            expand(s, 1),
+            ["0014af0bd98abc2f2cae66e36896a39ffe2d32984fb7"]
+        );
+        assert_eq!(
+            Descriptor::parse(&s.replace("/2/*", "/2'/*")).err(),
+            Some(DescriptorError::Bip32(Bip32Error::HardenedFromPublic))
+        );
+        assert_eq!(
+            Descriptor::parse(&s.replace("/2/*", "//*")).err(),
+            Some(DescriptorError::InvalidPath)
+        );
+    }
+
+    #[test]
+    fn test_to_public() {
+        // BIP32 test vector 1
+        let xprv = "xprv9s21ZrQH143K3QTDL4LXw2F7HEK3wJUD2nW2nRk4stbPy6cq3jPPqjiChkVvvNKmPGJxWUtg6LnF5kejMRNNU3TGtRBeJgk33yuGBxrMPHi";
+        let xpub = "xpub68Gmy5EdvgibQVfPdqkBBCHxA5htiqg55crXYuXoQRKfDBFA1WEjWgP6LHhwBZeNK1VTsfTFUHCdrfp1bgwQ9xv5ski8PX9rL2dZXvgGDnw";
+        let desc = Descriptor::parse(&format!("wpkh({}/0'/1/*)", xprv)).unwrap();
+        let public = desc.to_public().unwrap();
+        assert!(desc.has_private_keys());
+        assert!(!public.has_private_keys());
+        let s = format!("wpkh([3442193e/0']{}/1/*)", xpub);
+        assert_eq!(
+            public.to_string(),
+            format!("{}#{}", s, descriptor_checksum(&s).unwrap())
+        );
+        for i in 0..2 {
+            assert_eq!(
+                public.script_pubkeys(i).unwrap(),
+                desc.script_pubkeys(i).unwrap()
+            );
+        }
+
+        let desc = Descriptor::parse(&format!("wpkh({}/0/*')", xprv)).unwrap();
+        assert_eq!(
+            desc.to_public().err(),
+            Some(DescriptorError::Bip32(Bip32Error::HardenedFromPublic))
+        );
+    }
+
+    #[test]
+    fn test_tr() {
+        // BIP386 test vector
+        let key = "a34b99f22c790c4e36b2b3c2c35a36db06226e41c692fc82b8b56ac1c540c5bd";
+        let output_key = "77aab6e066f8a7419c5ab714c12c67d25007ed55a43cadcacb4d7a970a093f11";
+        assert_eq!(
+            expand(&format!("tr({})", key), 0),
+            [format!("5120{}", output_key)]
+        );
+
+        let desc = Descriptor::parse(&format!("tr({},{{pk({}),pk({})}})", key, G, key)).unwrap();
+        let Descriptor::Tr {
+            tree: Some(tree), ..
+        } = &desc
+        else {
+            panic!("not a tr() with a tree");
+        };
+        let leaf_a = tap_leaf_hash(
+            TAPROOT_LEAF_TAPSCRIPT,
+            &Script::new(&[
+                Command::Data(hex::decode(&G[2..]).unwrap()),
+                Command::Op(OP_CHECKSIG),
+            ]),
+        );
+        let leaf_b = tap_leaf_hash(
+            TAPROOT_LEAF_TAPSCRIPT,
+            &Script::new(&[
+                Command::Data(hex::decode(key).unwrap()),
+                Command::Op(OP_CHECKSIG),
+            ]),
+        );
+        let merkle_root = tap_branch_hash(&leaf_a, &leaf_b);
+        assert_eq!(tree.merkle_root(0).unwrap(), merkle_root);
+        let (tweaked, _) = tweak_key(
+            &hex::decode(key).unwrap().try_into().unwrap(),
+            Some(&merkle_root),
+        )
+        .unwrap();
+        assert_eq!(desc.script_pubkeys(0).unwrap(), [Script::p2tr(&tweaked)]);
+        assert!(desc.to_string().starts_with(&format!("tr({},{{pk(", key)));
+
+        assert_eq!(
+            Descriptor::parse(&format!("tr({},pkh({}))", key, G)).err(),
+            Some(DescriptorError::InvalidContext)
+        );
+        assert_eq!(
+            Descriptor::parse(&format!("wsh(pk({}))", key)).err(),
+            Some(DescriptorError::InvalidKey)
+        );
+    }
+}
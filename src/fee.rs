@@ -0,0 +1,143 @@
+use crate::script::Script;
+use std::fmt;
+
+/// A fee rate, kept in satoshis per 1000 virtual bytes so that fractional
+/// sat/vB rates can be expressed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct FeeRate {
+    sat_per_kvb: u64,
+}
+
+/// Bitcoin Core's default `-dustrelayfee`.
+pub const DUST_RELAY_FEE: FeeRate = FeeRate { sat_per_kvb: 3000 };
+
+impl FeeRate {
+    /// `None` if the rate doesn't fit in sat/kvB.
+    pub fn from_sat_per_vb(sat_per_vb: u64) -> Option<Self> {
+        Some(Self {
+            sat_per_kvb: sat_per_vb.checked_mul(1000)?,
+        })
+    }
+
+    pub fn from_sat_per_kvb(sat_per_kvb: u64) -> Self {
+        Self { sat_per_kvb }
+    }
+
+    /// The rate paid by `fee` over `vsize` virtual bytes, rounded down.
+    /// `None` for an empty size or a rate that doesn't fit.
+    pub fn from_fee_and_vsize(fee: u64, vsize: u64) -> Option<Self> {
+        if vsize == 0 {
+            return None;
+        }
+        let sat_per_kvb = fee as u128 * 1000 / vsize as u128;
+        Some(Self {
+            sat_per_kvb: sat_per_kvb.try_into().ok()?,
+        })
+    }
+
+    pub fn from_fee_and_weight(fee: u64, weight: u64) -> Option<Self> {
+        Self::from_fee_and_vsize(fee, weight_to_vsize(weight))
+    }
+
+    pub fn get_sat_per_kvb(&self) -> u64 {
+        self.sat_per_kvb
+    }
+
+    /// Fee for `vsize` virtual bytes, rounded up like Core's `CFeeRate::GetFee`.
+    /// `None` if it doesn't fit in a u64.
+    pub fn fee(&self, vsize: u64) -> Option<u64> {
+        (self.sat_per_kvb as u128 * vsize as u128)
+            .div_ceil(1000)
+            .try_into()
+            .ok()
+    }
+
+    pub fn fee_for_weight(&self, weight: u64) -> Option<u64> {
+        self.fee(weight_to_vsize(weight))
+    }
+}
+
+impl fmt::Display for FeeRate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}.{:03} sat/vB",
+            self.sat_per_kvb / 1000,
+            self.sat_per_kvb % 1000
+        )
+    }
+}
+
+/// Virtual size of `weight` weight units (BIP141), rounded up.
+pub fn weight_to_vsize(weight: u64) -> u64 {
+    weight.div_ceil(4)
+}
+
+/// The smallest output value that is not dust, as in Core's `GetDustThreshold`.
+///
+/// An output is dust when spending it would cost more than a third of its
+/// value at `DUST_RELAY_FEE`.
+pub fn dust_threshold(script_pubkey: &Script) -> u64 {
+    if script_pubkey.is_op_return() || script_pubkey.len() > 10_000 {
+        return 0;
+    }
+    // serialized output: amount, script length and script
+    let mut size = 8 + script_pubkey.serialize().len() as u64;
+    // plus the input spending it: outpoint, script length, sequence and
+    // a 107 byte signature and public key, discounted for witness programs
+    size += if script_pubkey.witness_version_and_program().is_some() {
+        32 + 4 + 1 + 107 / 4 + 4
+    } else {
+        32 + 4 + 1 + 107 + 4
+    };
+    // the size is bounded, so this can't overflow
+    DUST_RELAY_FEE.fee(size).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fee() {
+        let fee_rate = FeeRate::from_sat_per_kvb(1500);
+        assert_eq!(fee_rate.fee(141), Some(212));
+        assert_eq!(fee_rate.fee_for_weight(562), Some(212));
+        let ten = FeeRate::from_sat_per_vb(10).unwrap();
+        assert_eq!(ten.fee(141), Some(1410));
+        assert_eq!(
+            FeeRate::from_fee_and_vsize(212, 141),
+            Some(FeeRate::from_sat_per_kvb(1503))
+        );
+        assert_eq!(FeeRate::from_fee_and_weight(1410, 562), Some(ten));
+        assert_eq!(FeeRate::from_sat_per_kvb(1503).to_string(), "1.503 sat/vB");
+    }
+
+    #[test]
+    fn test_fee_overflow() {
+        assert_eq!(FeeRate::from_sat_per_vb(u64::MAX / 1000 + 1), None);
+        assert_eq!(FeeRate::from_fee_and_vsize(1000, 0), None);
+        assert_eq!(FeeRate::from_fee_and_weight(1000, 0), None);
+        assert_eq!(FeeRate::from_fee_and_vsize(u64::MAX, 1), None);
+        assert_eq!(
+            FeeRate::from_fee_and_vsize(u64::MAX, 1000),
+            Some(FeeRate::from_sat_per_kvb(u64::MAX))
+        );
+        let max = FeeRate::from_sat_per_kvb(u64::MAX);
+        assert_eq!(max.fee(1000), Some(u64::MAX));
+        assert_eq!(max.fee(1001), None);
+        assert_eq!(
+            FeeRate::from_sat_per_kvb(1000).fee(u64::MAX),
+            Some(u64::MAX)
+        );
+    }
+
+    #[test]
+    fn test_dust_threshold() {
+        assert_eq!(dust_threshold(&Script::p2pkh(&[0; 20])), 546);
+        assert_eq!(dust_threshold(&Script::p2sh(&[0; 20])), 540);
+        assert_eq!(dust_threshold(&Script::p2wpkh(&[0; 20])), 294);
+        assert_eq!(dust_threshold(&Script::p2wsh(&[0; 32])), 330);
+        assert_eq!(dust_threshold(&Script::p2tr(&[0; 32])), 330);
+    }
+}
@@ -51,12 +51,11 @@ impl Add for FieldElement {
     type Output = Self;
     fn add(self, rhs: FieldElement) -> Self::Output {
         assert_eq!(self.prime, rhs.prime);
-        let num;
-        if self.prime - self.num <= rhs.num {
-            num = self.prime - (self.prime - self.num) - (self.prime - rhs.num);
+        let num = if self.prime - self.num <= rhs.num {
+            self.prime - (self.prime - self.num) - (self.prime - rhs.num)
         } else {
-            num = rhs.num + self.num;
-        }
+            rhs.num + self.num
+        };
         FieldElement {
             num,
             prime: self.prime,
@@ -68,12 +67,11 @@ impl Sub for FieldElement {
     type Output = Self;
     fn sub(self, rhs: FieldElement) -> Self::Output {
         assert_eq!(self.prime, rhs.prime);
-        let tmp;
-        if self.num >= rhs.num {
-            tmp = self.num - rhs.num;
+        let tmp = if self.num >= rhs.num {
+            self.num - rhs.num
         } else {
-            tmp = (self.prime - rhs.num) + self.num;
-        }
+            (self.prime - rhs.num) + self.num
+        };
         let num = tmp % self.prime;
         FieldElement {
             num,
@@ -164,7 +162,7 @@ mod tests {
         assert_eq!(fp + fp2, FieldElement::new(U256::from(20), prime));
 
         let fp = FieldElement::new(U256::from(9), prime);
-        let fp2 = FieldElement::new(U256::from(prime - 29), prime);
+        let fp2 = FieldElement::new(prime - 29, prime);
         assert_eq!(fp + fp2, FieldElement::new(U256::from(37), prime));
     }
 
@@ -214,6 +212,6 @@ mod tests {
 
     #[bench]
     fn bench_multiple1(b: &mut Bencher) {
-        b.iter(|| test_overflow());
+        b.iter(test_overflow);
     }
 }
@@ -0,0 +1,552 @@
+use crate::{
+    block_header::{bits_to_target, target_to_bits, BlockHeader},
+    network::Network,
+};
+use primitive_types::{U256, U512};
+use std::collections::HashMap;
+
+/// Blocks between difficulty adjustments.
+pub const RETARGET_INTERVAL: u32 = 2016;
+/// Number of previous blocks whose median time a new block must exceed.
+const MEDIAN_TIME_SPAN: usize = 11;
+
+/// Consensus parameters for validating headers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChainParams {
+    pub genesis: BlockHeader,
+    pub pow_limit: U256,
+    /// Intended duration of a retarget interval, in seconds.
+    pub target_timespan: u32,
+    /// Intended time between blocks, in seconds.
+    pub target_spacing: u32,
+    /// The testnet rule allowing a minimum difficulty block after 20 minutes
+    /// without one.
+    pub allow_min_difficulty_blocks: bool,
+    pub no_retargeting: bool,
+    /// Heights from which the coinbase must start with the height (BIP34),
+    /// and OP_CHECKLOCKTIMEVERIFY (BIP65), strict DER signatures (BIP66),
+    /// relative locktimes (BIP68/112/113) and segwit (BIP141) are enforced.
+    pub bip34_height: u32,
+    pub bip65_height: u32,
+    pub bip66_height: u32,
+    pub csv_height: u32,
+    pub segwit_height: u32,
+    /// Blocks between halvings of the block subsidy.
+    pub subsidy_halving_interval: u32,
+}
+
+impl ChainParams {
+    pub fn new(network: Network) -> Self {
+        let merkle_root =
+            hex::decode("3ba3edfd7a7b12b27ac72c3e67768f617fc81bc3888a51323a9fb8aa4b1e5e4a")
+                .unwrap()
+                .try_into()
+                .unwrap();
+        let genesis = |timestamp, bits, nonce| {
+            BlockHeader::new(1, [0; 32], merkle_root, timestamp, bits, nonce)
+        };
+        let mainnet_limit = bits_to_target(0x1d00ffff).unwrap();
+        let params = Self {
+            genesis: genesis(1231006505, 0x1d00ffff, 2083236893),
+            pow_limit: mainnet_limit,
+            target_timespan: 14 * 24 * 60 * 60,
+            target_spacing: 10 * 60,
+            allow_min_difficulty_blocks: false,
+            no_retargeting: false,
+            bip34_height: 227_931,
+            bip65_height: 388_381,
+            bip66_height: 363_725,
+            csv_height: 419_328,
+            segwit_height: 481_824,
+            subsidy_halving_interval: 210_000,
+        };
+        match network {
+            Network::Mainnet => params,
+            Network::Testnet => Self {
+                genesis: genesis(1296688602, 0x1d00ffff, 414098458),
+                allow_min_difficulty_blocks: true,
+                bip34_height: 21_111,
+                bip65_height: 581_885,
+                bip66_height: 330_776,
+                csv_height: 770_112,
+                segwit_height: 834_624,
+                ..params
+            },
+            Network::Regtest => Self {
+                genesis: genesis(1296688602, 0x207fffff, 2),
+                pow_limit: U256::MAX >> 1,
+                allow_min_difficulty_blocks: true,
+                no_retargeting: true,
+                bip34_height: 1,
+                bip65_height: 1,
+                bip66_height: 1,
+                csv_height: 1,
+                segwit_height: 0,
+                subsidy_halving_interval: 150,
+                ..params
+            },
+        }
+    }
+
+    /// The new coins a block at `height` may pay itself, 50 bitcoin halved
+    /// every `subsidy_halving_interval` blocks.
+    pub fn block_subsidy(&self, height: u32) -> u64 {
+        let halvings = height / self.subsidy_halving_interval;
+        if halvings >= 64 {
+            return 0;
+        }
+        (50 * 100_000_000) >> halvings
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HeaderError {
+    /// The previous block is not known.
+    Orphan,
+    BadPow,
+    /// `bits` is not the required difficulty.
+    BadBits {
+        expected: u32,
+        actual: u32,
+    },
+    /// The timestamp is not after the median of the previous 11 blocks.
+    TimeTooOld,
+}
+
+/// What adding a header did to the active chain.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Accepted {
+    AlreadyKnown,
+    /// The header extends the active chain.
+    Extended,
+    /// The header is on a chain with less work than the active one.
+    SideChain,
+    /// A side chain overtook the active chain. Holds the hashes removed from
+    /// the active chain, tip first.
+    Reorg {
+        disconnected: Vec<[u8; 32]>,
+    },
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    header: BlockHeader,
+    height: u32,
+    chainwork: U256,
+}
+
+/// Validated block headers, following the chain with the most work.
+///
+/// Headers on side chains are kept so that a reorg only needs the headers
+/// past the fork point.
+#[derive(Debug, Clone)]
+pub struct HeaderChain {
+    params: ChainParams,
+    entries: HashMap<[u8; 32], Entry>,
+    /// Hashes of the active chain by height.
+    active: Vec<[u8; 32]>,
+}
+
+impl HeaderChain {
+    pub fn new(params: ChainParams) -> Self {
+        let genesis = params.genesis;
+        let entry = Entry {
+            header: genesis,
+            height: 0,
+            chainwork: genesis.work(),
+        };
+        Self {
+            params,
+            entries: HashMap::from([(genesis.hash(), entry)]),
+            active: vec![genesis.hash()],
+        }
+    }
+
+    pub fn get_params(&self) -> &ChainParams {
+        &self.params
+    }
+
+    /// Height of the active tip.
+    pub fn height(&self) -> u32 {
+        self.active.len() as u32 - 1
+    }
+
+    pub fn tip(&self) -> &BlockHeader {
+        &self.entries[self.active.last().unwrap()].header
+    }
+
+    /// Total work of the active chain.
+    pub fn chainwork(&self) -> U256 {
+        self.entries[self.active.last().unwrap()].chainwork
+    }
+
+    /// The header at `height` in the active chain.
+    pub fn get_header(&self, height: u32) -> Option<&BlockHeader> {
+        let hash = self.active.get(height as usize)?;
+        Some(&self.entries[hash].header)
+    }
+
+    /// Height of a known header, on any chain.
+    pub fn get_height(&self, hash: &[u8; 32]) -> Option<u32> {
+        Some(self.entries.get(hash)?.height)
+    }
+
+    pub fn is_active(&self, hash: &[u8; 32]) -> bool {
+        self.get_height(hash)
+            .is_some_and(|h| self.active.get(h as usize) == Some(hash))
+    }
+
+    /// Active chain hashes for `getheaders`, tip first: the last ten, then
+    /// exponentially sparser back to genesis.
+    pub fn locator(&self) -> Vec<[u8; 32]> {
+        let mut ret = Vec::new();
+        let mut height = self.height();
+        let mut step = 1;
+        loop {
+            ret.push(self.active[height as usize]);
+            if height == 0 {
+                return ret;
+            }
+            height = height.saturating_sub(step);
+            if ret.len() > 10 {
+                step *= 2;
+            }
+        }
+    }
+
+    /// Validates a header whose parent is known and adds it, switching to
+    /// its chain if that now has the most work.
+    pub fn add_header(&mut self, header: BlockHeader) -> Result<Accepted, HeaderError> {
+        let hash = header.hash();
+        if self.entries.contains_key(&hash) {
+            return Ok(Accepted::AlreadyKnown);
+        }
+        let prev = self
+            .entries
+            .get(&header.prev_block)
+            .ok_or(HeaderError::Orphan)?;
+        if !header.check_pow() {
+            return Err(HeaderError::BadPow);
+        }
+        let expected = self.next_bits(prev, &header);
+        if header.bits != expected {
+            return Err(HeaderError::BadBits {
+                expected,
+                actual: header.bits,
+            });
+        }
+        if header.timestamp <= self.median_time_past(&header.prev_block) {
+            return Err(HeaderError::TimeTooOld);
+        }
+
+        let entry = Entry {
+            header,
+            height: prev.height + 1,
+            chainwork: prev.chainwork + header.work(),
+        };
+        let extends_tip = header.prev_block == *self.active.last().unwrap();
+        let more_work = entry.chainwork > self.chainwork();
+        self.entries.insert(hash, entry);
+        if extends_tip {
+            self.active.push(hash);
+            Ok(Accepted::Extended)
+        } else if more_work {
+            Ok(Accepted::Reorg {
+                disconnected: self.activate(hash),
+            })
+        } else {
+            Ok(Accepted::SideChain)
+        }
+    }
+
+    /// Makes the chain ending in `tip` active, returning the disconnected
+    /// hashes.
+    fn activate(&mut self, tip: [u8; 32]) -> Vec<[u8; 32]> {
+        let mut connected = Vec::new();
+        let mut hash = tip;
+        while !self.is_active(&hash) {
+            connected.push(hash);
+            hash = self.entries[&hash].header.prev_block;
+        }
+        let fork_height = self.entries[&hash].height as usize;
+        let mut disconnected = self.active.split_off(fork_height + 1);
+        disconnected.reverse();
+        self.active.extend(connected.into_iter().rev());
+        disconnected
+    }
+
+    /// Median timestamp of the block `hash` and up to 10 of its ancestors.
+    pub fn median_time_past(&self, hash: &[u8; 32]) -> u32 {
+        let mut times = Vec::with_capacity(MEDIAN_TIME_SPAN);
+        let mut entry = self.entries.get(hash);
+        while let Some(e) = entry {
+            if times.len() == MEDIAN_TIME_SPAN {
+                break;
+            }
+            times.push(e.header.timestamp);
+            entry = self.entries.get(&e.header.prev_block);
+        }
+        times.sort_unstable();
+        times.get(times.len() / 2).copied().unwrap_or(0)
+    }
+
+    /// The `bits` required of a block on the tip with `timestamp`.
+    pub fn next_bits_on_tip(&self, timestamp: u32) -> u32 {
+        let prev = &self.entries[self.active.last().unwrap()];
+        let header = BlockHeader::new(0, prev.header.hash(), [0; 32], timestamp, 0, 0);
+        self.next_bits(prev, &header)
+    }
+
+    /// The `bits` required of `header`, whose parent is `prev`.
+    fn next_bits(&self, prev: &Entry, header: &BlockHeader) -> u32 {
+        let params = &self.params;
+        let limit_bits = target_to_bits(params.pow_limit);
+        let height = prev.height + 1;
+        if !height.is_multiple_of(RETARGET_INTERVAL) {
+            if !params.allow_min_difficulty_blocks {
+                return prev.header.bits;
+            }
+            // in i64 as Bitcoin Core does, so a parent timestamp near
+            // u32::MAX can't overflow
+            let late = prev.header.timestamp as i64 + 2 * params.target_spacing as i64;
+            if header.timestamp as i64 > late {
+                return limit_bits;
+            }
+            // the last block that did not use the 20 minute rule
+            let mut entry = prev;
+            while !entry.height.is_multiple_of(RETARGET_INTERVAL) && entry.header.bits == limit_bits
+            {
+                entry = &self.entries[&entry.header.prev_block];
+            }
+            return entry.header.bits;
+        }
+        if params.no_retargeting {
+            return prev.header.bits;
+        }
+
+        // Bitcoin Core measures the 2015 intervals from the first block of
+        // the period, not the 2016 from the last block of the previous one.
+        let mut first = prev;
+        for _ in 0..RETARGET_INTERVAL - 1 {
+            first = &self.entries[&first.header.prev_block];
+        }
+        let timespan = (prev.header.timestamp as i64 - first.header.timestamp as i64).clamp(
+            params.target_timespan as i64 / 4,
+            params.target_timespan as i64 * 4,
+        );
+        let target = bits_to_target(prev.header.bits).unwrap_or_default();
+        let target = target.full_mul(U256::from(timespan)) / U512::from(params.target_timespan);
+        let target = U256::try_from(target).unwrap_or(U256::MAX);
+        target_to_bits(target.min(params.pow_limit))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Regtest parameters with mainnet's difficulty rules, so retargets can
+    /// be tested on cheap headers.
+    fn retarget_params(allow_min_difficulty_blocks: bool) -> ChainParams {
+        ChainParams {
+            no_retargeting: false,
+            allow_min_difficulty_blocks,
+            ..ChainParams::new(Network::Regtest)
+        }
+    }
+
+    fn mine(prev: &BlockHeader, timestamp: u32, bits: u32, tag: u8) -> BlockHeader {
+        let mut header = BlockHeader::new(0x2000_0000, prev.hash(), [tag; 32], timestamp, bits, 0);
+        while !header.check_pow() {
+            header.nonce += 1;
+        }
+        header
+    }
+
+    /// Mines `count` headers on `chain`'s tip, `spacing` seconds apart.
+    fn extend(chain: &mut HeaderChain, count: u32, spacing: u32, tag: u8) -> Vec<BlockHeader> {
+        let mut headers = Vec::new();
+        for _ in 0..count {
+            let tip = headers.last().unwrap_or(chain.tip());
+            let header = mine(tip, tip.timestamp + spacing, tip.bits, tag);
+            headers.push(header);
+            assert_eq!(chain.add_header(header), Ok(Accepted::Extended));
+        }
+        headers
+    }
+
+    #[test]
+    fn test_genesis() {
+        for (network, hash) in [
+            (
+                Network::Mainnet,
+                "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f",
+            ),
+            (
+                Network::Testnet,
+                "000000000933ea01ad0ee984209779baaec3ced90fa3f408719526f8d77f4943",
+            ),
+            (
+                Network::Regtest,
+                "0f9188f13cb7b2c71f2a335e3a4fc328bf5beb436012afca590b1a11466e2206",
+            ),
+        ] {
+            let chain = HeaderChain::new(ChainParams::new(network));
+            assert_eq!(chain.tip().id(), hash);
+            assert!(chain.tip().check_pow());
+            assert_eq!(chain.height(), 0);
+        }
+    }
+
+    #[test]
+    fn test_block_subsidy() {
+        let params = ChainParams::new(Network::Mainnet);
+        assert_eq!(params.block_subsidy(209_999), 50_0000_0000);
+        assert_eq!(params.block_subsidy(420_000), 12_5000_0000);
+        assert_eq!(params.block_subsidy(6_929_999), 1);
+        assert_eq!(params.block_subsidy(6_930_000), 0);
+        assert_eq!(params.block_subsidy(u32::MAX), 0);
+        let params = ChainParams::new(Network::Regtest);
+        assert_eq!(params.block_subsidy(150), 25_0000_0000);
+    }
+
+    #[test]
+    fn test_add_header() {
+        let mut chain = HeaderChain::new(ChainParams::new(Network::Regtest));
+        let genesis = *chain.tip();
+        let headers = extend(&mut chain, 11, 600, 0);
+        assert_eq!(chain.height(), 11);
+        assert_eq!(chain.get_header(5), Some(&headers[4]));
+        assert_eq!(chain.add_header(headers[3]), Ok(Accepted::AlreadyKnown));
+        assert_eq!(chain.chainwork(), genesis.work() * U256::from(12));
+
+        let tip = *chain.tip();
+        let mut orphan = tip;
+        orphan.prev_block = [0xff; 32];
+        assert_eq!(chain.add_header(orphan), Err(HeaderError::Orphan));
+
+        let mut bad_pow = mine(&tip, tip.timestamp + 1, tip.bits, 1);
+        while bad_pow.check_pow() {
+            bad_pow.nonce += 1;
+        }
+        assert_eq!(chain.add_header(bad_pow), Err(HeaderError::BadPow));
+
+        // the median of the last 11 timestamps is that of headers[5]
+        let mtp = chain.median_time_past(&tip.hash());
+        assert_eq!(mtp, headers[5].timestamp);
+        let too_old = mine(&tip, mtp, tip.bits, 1);
+        assert_eq!(chain.add_header(too_old), Err(HeaderError::TimeTooOld));
+        let just_after = mine(&tip, mtp + 1, tip.bits, 1);
+        assert_eq!(chain.add_header(just_after), Ok(Accepted::Extended));
+
+        let easier = mine(&just_after, just_after.timestamp + 1, 0x2100ffff, 1);
+        assert_eq!(
+            chain.add_header(easier),
+            Err(HeaderError::BadBits {
+                expected: 0x207fffff,
+                actual: 0x2100ffff
+            })
+        );
+    }
+
+    #[test]
+    fn test_locator() {
+        let mut chain = HeaderChain::new(ChainParams::new(Network::Regtest));
+        assert_eq!(chain.locator(), [chain.tip().hash()]);
+        let headers = extend(&mut chain, 20, 600, 0);
+        let heights: Vec<u32> = chain
+            .locator()
+            .iter()
+            .map(|hash| chain.get_height(hash).unwrap())
+            .collect();
+        assert_eq!(
+            heights,
+            [20, 19, 18, 17, 16, 15, 14, 13, 12, 11, 10, 9, 7, 3, 0]
+        );
+        assert_eq!(chain.locator()[0], headers[19].hash());
+    }
+
+    #[test]
+    fn test_reorg() {
+        let mut chain = HeaderChain::new(ChainParams::new(Network::Regtest));
+        let main = extend(&mut chain, 3, 600, 0);
+
+        // a fork from height 1 only takes over once it has more work
+        let mut fork = vec![mine(&main[0], main[0].timestamp + 1, main[0].bits, 1)];
+        assert_eq!(chain.add_header(fork[0]), Ok(Accepted::SideChain));
+        let next = mine(&fork[0], fork[0].timestamp + 1, fork[0].bits, 1);
+        fork.push(next);
+        assert_eq!(chain.add_header(next), Ok(Accepted::SideChain));
+        let next = mine(&fork[1], fork[1].timestamp + 1, fork[1].bits, 1);
+        fork.push(next);
+        assert_eq!(
+            chain.add_header(next),
+            Ok(Accepted::Reorg {
+                disconnected: vec![main[2].hash(), main[1].hash()]
+            })
+        );
+        assert_eq!(chain.height(), 4);
+        assert_eq!(chain.tip(), &fork[2]);
+        assert_eq!(chain.get_header(1), Some(&main[0]));
+        assert_eq!(chain.get_header(2), Some(&fork[0]));
+        assert!(!chain.is_active(&main[2].hash()));
+
+        // the old chain can come back
+        let next = mine(&main[2], main[2].timestamp + 600, main[2].bits, 0);
+        assert_eq!(chain.add_header(next), Ok(Accepted::SideChain));
+        let next = mine(&next, next.timestamp + 600, next.bits, 0);
+        assert_eq!(
+            chain.add_header(next),
+            Ok(Accepted::Reorg {
+                disconnected: vec![fork[2].hash(), fork[1].hash(), fork[0].hash()]
+            })
+        );
+        assert_eq!(chain.height(), 5);
+    }
+
+    #[test]
+    fn test_retarget() {
+        let mut chain = HeaderChain::new(retarget_params(false));
+        let limit = chain.tip().bits;
+        // twice as fast as intended: 2015 intervals of 300s
+        extend(&mut chain, RETARGET_INTERVAL - 1, 300, 0);
+        let tip = *chain.tip();
+        let stale = mine(&tip, tip.timestamp + 300, limit, 0);
+        assert_eq!(
+            chain.add_header(stale),
+            Err(HeaderError::BadBits {
+                expected: 0x203ff7de,
+                actual: limit
+            })
+        );
+        let header = mine(&tip, tip.timestamp + 300, 0x203ff7de, 0);
+        assert_eq!(chain.add_header(header), Ok(Accepted::Extended));
+
+        // far too fast, so the target only shrinks by the 4x limit
+        let mut chain = HeaderChain::new(retarget_params(true));
+        extend(&mut chain, RETARGET_INTERVAL - 1, 1, 0);
+        let tip = *chain.tip();
+        let header = mine(&tip, tip.timestamp + 1, 0x201fffff, 0);
+        assert_eq!(chain.add_header(header), Ok(Accepted::Extended));
+
+        // testnet: minimum difficulty after 20 minutes, then back to the
+        // last real difficulty
+        let slow = mine(&header, header.timestamp + 1201, limit, 0);
+        assert_eq!(chain.add_header(slow), Ok(Accepted::Extended));
+        let next = mine(&slow, slow.timestamp + 1, limit, 0);
+        assert_eq!(
+            chain.add_header(next),
+            Err(HeaderError::BadBits {
+                expected: 0x201fffff,
+                actual: limit
+            })
+        );
+        let next = mine(&slow, slow.timestamp + 1, 0x201fffff, 0);
+        assert_eq!(chain.add_header(next), Ok(Accepted::Extended));
+
+        // a parent timestamp near u32::MAX doesn't overflow the 20 minutes
+        let late = mine(&next, u32::MAX - 1000, limit, 0);
+        assert_eq!(chain.add_header(late), Ok(Accepted::Extended));
+        assert_eq!(chain.next_bits_on_tip(u32::MAX), 0x201fffff);
+    }
+}
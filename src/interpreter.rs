@@ -0,0 +1,2029 @@
+use crate::{
+    base58::{hash160, hash256},
+    script::*,
+    secp256k1::{S256Point, N},
+    sighash::{
+        legacy_sighash, segwit_v0_sighash, taproot_sighash, SIGHASH_ANYONECANPAY, SIGHASH_DEFAULT,
+    },
+    signature::Signature,
+    taproot::{
+        tap_leaf_hash, ControlBlock, XOnlyPublicKey, TAPROOT_CONTROL_BASE_SIZE,
+        TAPROOT_CONTROL_MAX_NODE_COUNT, TAPROOT_CONTROL_NODE_SIZE, TAPROOT_LEAF_MASK,
+        TAPROOT_LEAF_TAPSCRIPT,
+    },
+    tx::{serialize_witness, Tx, TxOut},
+};
+use primitive_types::U256;
+use ripemd::Ripemd160;
+use sha2::{Digest, Sha256};
+
+/// Script verification flags, with Bitcoin Core's bit values.
+pub const VERIFY_NONE: u32 = 0;
+pub const VERIFY_P2SH: u32 = 1 << 0;
+pub const VERIFY_STRICTENC: u32 = 1 << 1;
+pub const VERIFY_DERSIG: u32 = 1 << 2;
+pub const VERIFY_LOW_S: u32 = 1 << 3;
+pub const VERIFY_NULLDUMMY: u32 = 1 << 4;
+pub const VERIFY_SIGPUSHONLY: u32 = 1 << 5;
+pub const VERIFY_MINIMALDATA: u32 = 1 << 6;
+pub const VERIFY_DISCOURAGE_UPGRADABLE_NOPS: u32 = 1 << 7;
+pub const VERIFY_CLEANSTACK: u32 = 1 << 8;
+pub const VERIFY_CHECKLOCKTIMEVERIFY: u32 = 1 << 9;
+pub const VERIFY_CHECKSEQUENCEVERIFY: u32 = 1 << 10;
+pub const VERIFY_WITNESS: u32 = 1 << 11;
+pub const VERIFY_DISCOURAGE_UPGRADABLE_WITNESS_PROGRAM: u32 = 1 << 12;
+pub const VERIFY_MINIMALIF: u32 = 1 << 13;
+pub const VERIFY_NULLFAIL: u32 = 1 << 14;
+pub const VERIFY_WITNESS_PUBKEYTYPE: u32 = 1 << 15;
+pub const VERIFY_TAPROOT: u32 = 1 << 17;
+pub const VERIFY_DISCOURAGE_UPGRADABLE_TAPROOT_VERSION: u32 = 1 << 18;
+pub const VERIFY_DISCOURAGE_OP_SUCCESS: u32 = 1 << 19;
+pub const VERIFY_DISCOURAGE_UPGRADABLE_PUBKEYTYPE: u32 = 1 << 20;
+
+/// Largest element that can be pushed onto the stack.
+pub const MAX_SCRIPT_ELEMENT_SIZE: usize = 520;
+/// Most non-push operations a script may execute.
+pub const MAX_OPS_PER_SCRIPT: usize = 201;
+/// Most elements on the stack and alt stack together.
+pub const MAX_STACK_SIZE: usize = 1000;
+/// Validation weight each executed tapscript signature check uses up
+/// (BIP342).
+pub const VALIDATION_WEIGHT_PER_SIGOP_PASSED: i64 = 50;
+/// Validation weight a tapscript has beyond the size of its witness.
+pub const VALIDATION_WEIGHT_OFFSET: i64 = 50;
+/// First byte of a taproot annex, the last witness element if present.
+pub const ANNEX_TAG: u8 = 0x50;
+/// nLockTime values from here on are timestamps rather than heights.
+pub const LOCKTIME_THRESHOLD: u32 = 500_000_000;
+
+/// BIP68: a set bit disables the input's relative lock.
+pub const SEQUENCE_LOCKTIME_DISABLE_FLAG: u32 = 1 << 31;
+/// BIP68: a set bit makes the relative lock a time in units of 512 seconds.
+pub const SEQUENCE_LOCKTIME_TYPE_FLAG: u32 = 1 << 22;
+pub const SEQUENCE_LOCKTIME_MASK: u32 = 0x0000_ffff;
+/// Relative time locks count in units of `1 << SEQUENCE_LOCKTIME_GRANULARITY`
+/// seconds.
+pub const SEQUENCE_LOCKTIME_GRANULARITY: u32 = 9;
+
+/// Why a script failed, following Bitcoin Core's `ScriptError`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScriptError {
+    /// The script finished with an empty stack or a false top element.
+    EvalFalse,
+    OpReturn,
+    ScriptSize,
+    PushSize,
+    OpCount,
+    StackSize,
+    SigCount,
+    PubkeyCount,
+    Verify,
+    EqualVerify,
+    CheckSigVerify,
+    CheckMultisigVerify,
+    NumEqualVerify,
+    BadOpcode,
+    DisabledOpcode,
+    InvalidStackOperation,
+    InvalidAltstackOperation,
+    UnbalancedConditional,
+    /// A number is longer than allowed.
+    NumOverflow,
+    NegativeLocktime,
+    UnsatisfiedLocktime,
+    SigHashType,
+    SigDer,
+    MinimalData,
+    SigPushOnly,
+    SigHighS,
+    SigNullDummy,
+    PubkeyType,
+    CleanStack,
+    MinimalIf,
+    SigNullFail,
+    DiscourageUpgradableNops,
+    DiscourageUpgradableWitnessProgram,
+    WitnessProgramWrongLength,
+    WitnessProgramWitnessEmpty,
+    WitnessProgramMismatch,
+    WitnessMalleated,
+    WitnessMalleatedP2sh,
+    WitnessUnexpected,
+    WitnessPubkeyType,
+    SchnorrSig,
+    SchnorrSigSize,
+    SchnorrSigHashType,
+    TaprootWrongControlSize,
+    /// Too many signature checks for the size of the tapscript's witness.
+    TapscriptValidationWeight,
+    TapscriptCheckMultisig,
+    TapscriptMinimalIf,
+    DiscourageUpgradableTaprootVersion,
+    DiscourageOpSuccess,
+    DiscourageUpgradablePubkeyType,
+}
+
+/// Which rules a script runs under: legacy scripts, segwit v0 scripts with
+/// BIP143 signature hashes, or BIP342 tapscripts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SigVersion {
+    Base,
+    WitnessV0,
+    Tapscript,
+}
+
+/// Checks signatures and locktimes against the input being verified.
+#[derive(Debug, Clone, Copy)]
+pub struct TxChecker<'a> {
+    pub tx: &'a Tx,
+    pub input_index: usize,
+    /// Value of the output being spent, signed by segwit signatures.
+    pub amount: u64,
+    /// The outputs spent by every input, signed by taproot signatures.
+    /// Empty if unknown, failing every taproot signature.
+    pub spent_outputs: &'a [TxOut],
+}
+
+impl<'a> TxChecker<'a> {
+    pub fn new(tx: &'a Tx, input_index: usize, amount: u64) -> Self {
+        Self {
+            tx,
+            input_index,
+            amount,
+            spent_outputs: &[],
+        }
+    }
+
+    /// A checker for taproot spends too, knowing the outputs spent by every
+    /// input of `tx`.
+    pub fn with_spent_outputs(tx: &'a Tx, input_index: usize, spent_outputs: &'a [TxOut]) -> Self {
+        Self {
+            tx,
+            input_index,
+            amount: spent_outputs[input_index].amount,
+            spent_outputs,
+        }
+    }
+
+    /// Whether `sig`, a DER signature followed by its sighash type, signs
+    /// this input for `pubkey`.
+    pub fn check_sig(
+        &self,
+        sig: &[u8],
+        pubkey: &[u8],
+        script_code: &Script,
+        sig_version: SigVersion,
+    ) -> bool {
+        let Some((sighash_type, der)) = sig.split_last() else {
+            return false;
+        };
+        let (Some(point), Some(signature)) =
+            (S256Point::try_parse(pubkey), Signature::parse_der(der))
+        else {
+            return false;
+        };
+        let sighash_type = *sighash_type as u32;
+        let z = match sig_version {
+            SigVersion::Base => {
+                legacy_sighash(self.tx, self.input_index, script_code, sighash_type)
+            }
+            SigVersion::WitnessV0 => segwit_v0_sighash(
+                self.tx,
+                self.input_index,
+                script_code,
+                self.amount,
+                sighash_type,
+            ),
+            SigVersion::Tapscript => return false,
+        };
+        point.verify(U256::from_big_endian(&z), signature)
+    }
+
+    /// Checks that `sig`, a BIP340 signature optionally followed by its
+    /// sighash type, signs this input for the x-only `pubkey` (BIP341).
+    /// `leaf` is the tapleaf hash and OP_CODESEPARATOR position of a script
+    /// path spend.
+    pub fn check_schnorr_sig(
+        &self,
+        sig: &[u8],
+        pubkey: &[u8],
+        annex: Option<&[u8]>,
+        leaf: Option<(&[u8; 32], u32)>,
+    ) -> Result<(), ScriptError> {
+        let (sig, sighash_type) = match sig {
+            [sig @ .., sighash_type] if sig.len() == 64 => {
+                // the default type is only given by leaving it out
+                if *sighash_type as u32 == SIGHASH_DEFAULT {
+                    return Err(ScriptError::SchnorrSigHashType);
+                }
+                (sig, *sighash_type as u32)
+            }
+            _ if sig.len() == 64 => (sig, SIGHASH_DEFAULT),
+            _ => return Err(ScriptError::SchnorrSigSize),
+        };
+        if self.spent_outputs.len() != self.tx.tx_ins.len() {
+            return Err(ScriptError::SchnorrSig);
+        }
+        let sighash = taproot_sighash(
+            self.tx,
+            self.input_index,
+            self.spent_outputs,
+            sighash_type,
+            annex,
+            leaf,
+        )
+        .ok_or(ScriptError::SchnorrSigHashType)?;
+        let valid = XOnlyPublicKey::parse(pubkey)
+            .is_some_and(|key| key.verify_schnorr(&sighash, sig.try_into().unwrap()));
+        if !valid {
+            return Err(ScriptError::SchnorrSig);
+        }
+        Ok(())
+    }
+
+    /// OP_CHECKLOCKTIMEVERIFY (BIP65): the transaction's nLockTime is of
+    /// the same kind, at least `locktime` and enforced by the input.
+    pub fn check_locktime(&self, locktime: i64) -> bool {
+        let tx_locktime = self.tx.locktime as i64;
+        let threshold = LOCKTIME_THRESHOLD as i64;
+        if (tx_locktime < threshold) != (locktime < threshold) || locktime > tx_locktime {
+            return false;
+        }
+        self.tx.tx_ins[self.input_index].sequence != u32::MAX
+    }
+
+    /// OP_CHECKSEQUENCEVERIFY (BIP112): the input's relative lock is of the
+    /// same kind and at least `sequence`.
+    pub fn check_sequence(&self, sequence: i64) -> bool {
+        let tx_sequence = self.tx.tx_ins[self.input_index].sequence as i64;
+        if (self.tx.version as i32) < 2 || tx_sequence & SEQUENCE_LOCKTIME_DISABLE_FLAG as i64 != 0
+        {
+            return false;
+        }
+        let mask = (SEQUENCE_LOCKTIME_TYPE_FLAG | SEQUENCE_LOCKTIME_MASK) as i64;
+        let (tx_sequence, sequence) = (tx_sequence & mask, sequence & mask);
+        let type_flag = SEQUENCE_LOCKTIME_TYPE_FLAG as i64;
+        if (tx_sequence < type_flag) != (sequence < type_flag) {
+            return false;
+        }
+        sequence <= tx_sequence
+    }
+}
+
+/// Decodes a script number of at most `max_size` bytes.
+pub fn decode_num(data: &[u8], require_minimal: bool, max_size: usize) -> Result<i64, ScriptError> {
+    if data.len() > max_size {
+        return Err(ScriptError::NumOverflow);
+    }
+    let Some((last, rest)) = data.split_last() else {
+        return Ok(0);
+    };
+    // a last byte of just the sign bit is only needed if the byte before
+    // it has its top bit set
+    if require_minimal && last & 0x7f == 0 && rest.last().is_none_or(|b| b & 0x80 == 0) {
+        return Err(ScriptError::MinimalData);
+    }
+    let magnitude =
+        data.iter().rev().fold(0i64, |acc, b| acc << 8 | *b as i64) & !(0x80 << (8 * rest.len()));
+    Ok(if last & 0x80 != 0 {
+        -magnitude
+    } else {
+        magnitude
+    })
+}
+
+/// Any non-zero byte makes an element true, except a negative zero.
+pub fn cast_to_bool(data: &[u8]) -> bool {
+    match data.split_last() {
+        Some((last, rest)) => rest.iter().any(|b| *b != 0) || (*last != 0 && *last != 0x80),
+        None => false,
+    }
+}
+
+/// Whether `data` was pushed with the smallest opcode possible.
+fn is_minimal_push(op: u8, data: &[u8]) -> bool {
+    match data {
+        [] => op == OP_0,
+        [n @ 1..=16] => op == OP_1 + n - 1,
+        [0x81] => op == OP_1NEGATE,
+        _ if data.len() <= 75 => op as usize == data.len(),
+        _ if data.len() <= 0xff => op == OP_PUSHDATA1,
+        _ if data.len() <= 0xffff => op == OP_PUSHDATA2,
+        _ => true,
+    }
+}
+
+/// Removes every push of `data` found at an opcode boundary, as Bitcoin
+/// Core's `FindAndDelete` does to signatures in legacy script code.
+fn find_and_delete(script: &Script, data: &[u8]) -> Script {
+    let pattern = push_data(data);
+    let raw = script.as_bytes();
+    let mut ret = Vec::with_capacity(raw.len());
+    let mut found = false;
+    let (mut pc, mut copied) = (0, 0);
+    loop {
+        ret.extend(&raw[copied..pc]);
+        while raw[pc..].starts_with(&pattern) {
+            pc += pattern.len();
+            found = true;
+        }
+        copied = pc;
+        if read_op(raw, &mut pc).is_none() {
+            break;
+        }
+    }
+    if !found {
+        return script.clone();
+    }
+    ret.extend(&raw[copied..]);
+    Script::from_bytes(ret)
+}
+
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [
+        0x6745_2301,
+        0xefcd_ab89,
+        0x98ba_dcfe,
+        0x1032_5476,
+        0xc3d2_e1f0,
+    ];
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend((data.len() as u64 * 8).to_be_bytes());
+    for block in message.chunks_exact(64) {
+        let mut w = [0u32; 80];
+        for (i, word) in block.chunks_exact(4).enumerate() {
+            w[i] = u32::from_be_bytes(word.try_into().unwrap());
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e] = h;
+        for (i, word) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5a82_7999),
+                20..=39 => (b ^ c ^ d, 0x6ed9_eba1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8f1b_bcdc),
+                _ => (b ^ c ^ d, 0xca62_c1d6),
+            };
+            let temp = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(*word);
+            (e, d, c, b, a) = (d, c, b.rotate_left(30), a, temp);
+        }
+        for (h, x) in h.iter_mut().zip([a, b, c, d, e]) {
+            *h = h.wrapping_add(x);
+        }
+    }
+    let mut ret = [0; 20];
+    for (chunk, word) in ret.chunks_exact_mut(4).zip(h) {
+        chunk.copy_from_slice(&word.to_be_bytes());
+    }
+    ret
+}
+
+/// BIP66 strict DER, checked on the signature with its sighash byte.
+fn is_valid_signature_encoding(sig: &[u8]) -> bool {
+    let len = sig.len();
+    if !(9..=73).contains(&len) || sig[0] != 0x30 || sig[1] as usize != len - 3 {
+        return false;
+    }
+    let len_r = sig[3] as usize;
+    if 5 + len_r >= len {
+        return false;
+    }
+    let len_s = sig[5 + len_r] as usize;
+    if len_r + len_s + 7 != len {
+        return false;
+    }
+    let valid_int = |start: usize, int_len: usize| {
+        sig[start - 2] == 0x02
+            && int_len != 0
+            && sig[start] & 0x80 == 0
+            && !(int_len > 1 && sig[start] == 0 && sig[start + 1] & 0x80 == 0)
+    };
+    valid_int(4, len_r) && valid_int(len_r + 6, len_s)
+}
+
+fn check_signature_encoding(sig: &[u8], flags: u32) -> Result<(), ScriptError> {
+    // an empty signature is a valid way to fail a check
+    if sig.is_empty() {
+        return Ok(());
+    }
+    if flags & (VERIFY_DERSIG | VERIFY_LOW_S | VERIFY_STRICTENC) != 0
+        && !is_valid_signature_encoding(sig)
+    {
+        return Err(ScriptError::SigDer);
+    }
+    if flags & VERIFY_LOW_S != 0 {
+        let high = Signature::parse_der(&sig[..sig.len() - 1])
+            .is_none_or(|sig| sig.get_s() > U256::from_str_radix(N, 16).unwrap() / 2);
+        if high {
+            return Err(ScriptError::SigHighS);
+        }
+    }
+    if flags & VERIFY_STRICTENC != 0 {
+        let base_type = sig[sig.len() - 1] as u32 & !SIGHASH_ANYONECANPAY;
+        if !(1..=3).contains(&base_type) {
+            return Err(ScriptError::SigHashType);
+        }
+    }
+    Ok(())
+}
+
+fn check_pubkey_encoding(
+    pubkey: &[u8],
+    flags: u32,
+    sig_version: SigVersion,
+) -> Result<(), ScriptError> {
+    let compressed = pubkey.len() == 33 && (pubkey[0] == 2 || pubkey[0] == 3);
+    let uncompressed = pubkey.len() == 65 && pubkey[0] == 4;
+    if flags & VERIFY_STRICTENC != 0 && !compressed && !uncompressed {
+        return Err(ScriptError::PubkeyType);
+    }
+    if flags & VERIFY_WITNESS_PUBKEYTYPE != 0 && sig_version == SigVersion::WitnessV0 && !compressed
+    {
+        return Err(ScriptError::WitnessPubkeyType);
+    }
+    Ok(())
+}
+
+/// Whether `op` is one of the OP_SUCCESSx opcodes that make a tapscript
+/// succeed, reserved for soft forks (BIP342).
+fn is_op_success(op: u8) -> bool {
+    matches!(
+        op,
+        80 | 98 | 126..=129 | 131..=134 | 137..=138 | 141..=142 | 149..=153 | 187..=254
+    )
+}
+
+/// What a tapscript's signatures commit to besides the transaction, and its
+/// signature check budget. Bitcoin Core's `ScriptExecutionData`.
+#[derive(Debug, Clone, Default)]
+struct ExecData {
+    annex: Option<Vec<u8>>,
+    tapleaf_hash: [u8; 32],
+    /// Position, in opcodes, of the last executed OP_CODESEPARATOR.
+    codeseparator_pos: u32,
+    validation_weight_left: i64,
+}
+
+/// OP_CHECKSIG and friends in a tapscript (BIP342). An empty signature is
+/// a valid way to fail, any other invalid one fails the script.
+fn check_sig_tapscript(
+    sig: &[u8],
+    pubkey: &[u8],
+    flags: u32,
+    checker: &TxChecker,
+    exec: &mut ExecData,
+) -> Result<bool, ScriptError> {
+    let success = !sig.is_empty();
+    if success {
+        exec.validation_weight_left -= VALIDATION_WEIGHT_PER_SIGOP_PASSED;
+        if exec.validation_weight_left < 0 {
+            return Err(ScriptError::TapscriptValidationWeight);
+        }
+    }
+    match pubkey.len() {
+        0 => return Err(ScriptError::PubkeyType),
+        32 => {
+            if success {
+                let leaf = (&exec.tapleaf_hash, exec.codeseparator_pos);
+                checker.check_schnorr_sig(sig, pubkey, exec.annex.as_deref(), Some(leaf))?;
+            }
+        }
+        // other key types are left for soft forks, and always succeed
+        _ => {
+            if flags & VERIFY_DISCOURAGE_UPGRADABLE_PUBKEYTYPE != 0 {
+                return Err(ScriptError::DiscourageUpgradablePubkeyType);
+            }
+        }
+    }
+    Ok(success)
+}
+
+/// The stack during execution, with Bitcoin Core's `stacktop(-i)` access.
+struct Stack(Vec<Vec<u8>>);
+
+impl Stack {
+    fn pop(&mut self) -> Result<Vec<u8>, ScriptError> {
+        self.0.pop().ok_or(ScriptError::InvalidStackOperation)
+    }
+
+    /// The `i`th element from the top, 1 being the top.
+    fn top(&self, i: usize) -> Result<&Vec<u8>, ScriptError> {
+        self.0
+            .len()
+            .checked_sub(i)
+            .map(|index| &self.0[index])
+            .ok_or(ScriptError::InvalidStackOperation)
+    }
+
+    fn require(&self, size: usize) -> Result<(), ScriptError> {
+        if self.0.len() < size {
+            return Err(ScriptError::InvalidStackOperation);
+        }
+        Ok(())
+    }
+
+    fn pop_num(&mut self, flags: u32) -> Result<i64, ScriptError> {
+        let num = decode_num(self.top(1)?, flags & VERIFY_MINIMALDATA != 0, 4)?;
+        self.0.pop();
+        Ok(num)
+    }
+
+    fn push_bool(&mut self, value: bool) {
+        self.0.push(if value { vec![1] } else { vec![] });
+    }
+}
+
+/// Runs `script` on `stack`, Bitcoin Core's `EvalScript`.
+pub fn eval_script(
+    stack: &mut Vec<Vec<u8>>,
+    script: &Script,
+    flags: u32,
+    checker: &TxChecker,
+    sig_version: SigVersion,
+) -> Result<(), ScriptError> {
+    let raw = script.as_bytes();
+    if sig_version != SigVersion::Tapscript && raw.len() > MAX_SCRIPT_SIZE {
+        return Err(ScriptError::ScriptSize);
+    }
+    let mut wrapped = Stack(std::mem::take(stack));
+    let result = execute(
+        &mut wrapped,
+        raw,
+        flags,
+        checker,
+        sig_version,
+        &mut ExecData::default(),
+    );
+    *stack = wrapped.0;
+    result
+}
+
+fn execute(
+    stack: &mut Stack,
+    raw: &[u8],
+    flags: u32,
+    checker: &TxChecker,
+    sig_version: SigVersion,
+    exec: &mut ExecData,
+) -> Result<(), ScriptError> {
+    let require_minimal = flags & VERIFY_MINIMALDATA != 0;
+    let mut alt_stack: Vec<Vec<u8>> = Vec::new();
+    // whether each enclosing OP_IF branch is being executed
+    let mut conditions: Vec<bool> = Vec::new();
+    let mut op_count = 0;
+    let mut pc = 0;
+    let mut code_start = 0;
+    let mut opcode_pos = 0;
+    while pc < raw.len() {
+        let executing = !conditions.contains(&false);
+        let (op, data) = read_op(raw, &mut pc).ok_or(ScriptError::BadOpcode)?;
+        opcode_pos += 1;
+        if data.is_some_and(|data| data.len() > MAX_SCRIPT_ELEMENT_SIZE) {
+            return Err(ScriptError::PushSize);
+        }
+        // tapscripts are limited by their validation weight instead
+        if op > OP_16 && sig_version != SigVersion::Tapscript {
+            op_count += 1;
+            if op_count > MAX_OPS_PER_SCRIPT {
+                return Err(ScriptError::OpCount);
+            }
+        }
+        if matches!(
+            op,
+            OP_CAT
+                | OP_SUBSTR
+                | OP_LEFT
+                | OP_RIGHT
+                | OP_INVERT
+                | OP_AND
+                | OP_OR
+                | OP_XOR
+                | OP_2MUL
+                | OP_2DIV
+                | OP_MUL
+                | OP_DIV
+                | OP_MOD
+                | OP_LSHIFT
+                | OP_RSHIFT
+        ) {
+            return Err(ScriptError::DisabledOpcode);
+        }
+
+        if let Some(data) = data {
+            if executing {
+                if require_minimal && !is_minimal_push(op, data) {
+                    return Err(ScriptError::MinimalData);
+                }
+                stack.0.push(data.to_vec());
+            }
+        } else if executing || (OP_IF..=OP_ENDIF).contains(&op) {
+            match op {
+                OP_1NEGATE | OP_1..=OP_16 => {
+                    stack.0.push(encode_num(op as i64 - (OP_1 - 1) as i64));
+                }
+                OP_NOP => {}
+                OP_CHECKLOCKTIMEVERIFY if flags & VERIFY_CHECKLOCKTIMEVERIFY != 0 => {
+                    // five bytes, as times past 2038 need them
+                    let locktime = decode_num(stack.top(1)?, require_minimal, 5)?;
+                    if locktime < 0 {
+                        return Err(ScriptError::NegativeLocktime);
+                    }
+                    if !checker.check_locktime(locktime) {
+                        return Err(ScriptError::UnsatisfiedLocktime);
+                    }
+                }
+                OP_CHECKSEQUENCEVERIFY if flags & VERIFY_CHECKSEQUENCEVERIFY != 0 => {
+                    let sequence = decode_num(stack.top(1)?, require_minimal, 5)?;
+                    if sequence < 0 {
+                        return Err(ScriptError::NegativeLocktime);
+                    }
+                    if sequence & SEQUENCE_LOCKTIME_DISABLE_FLAG as i64 == 0
+                        && !checker.check_sequence(sequence)
+                    {
+                        return Err(ScriptError::UnsatisfiedLocktime);
+                    }
+                }
+                OP_NOP1 | OP_CHECKLOCKTIMEVERIFY | OP_CHECKSEQUENCEVERIFY | OP_NOP4..=OP_NOP10 => {
+                    if flags & VERIFY_DISCOURAGE_UPGRADABLE_NOPS != 0 {
+                        return Err(ScriptError::DiscourageUpgradableNops);
+                    }
+                }
+                OP_IF | OP_NOTIF => {
+                    let mut value = false;
+                    if executing {
+                        let top = stack
+                            .pop()
+                            .map_err(|_| ScriptError::UnbalancedConditional)?;
+                        let minimal = top.is_empty() || top == [1];
+                        if sig_version == SigVersion::Tapscript && !minimal {
+                            return Err(ScriptError::TapscriptMinimalIf);
+                        }
+                        if sig_version == SigVersion::WitnessV0
+                            && flags & VERIFY_MINIMALIF != 0
+                            && !minimal
+                        {
+                            return Err(ScriptError::MinimalIf);
+                        }
+                        value = cast_to_bool(&top) == (op == OP_IF);
+                    }
+                    conditions.push(value);
+                }
+                OP_ELSE => {
+                    let last = conditions
+                        .last_mut()
+                        .ok_or(ScriptError::UnbalancedConditional)?;
+                    *last = !*last;
+                }
+                OP_ENDIF => {
+                    conditions.pop().ok_or(ScriptError::UnbalancedConditional)?;
+                }
+                OP_VERIFY => {
+                    if !cast_to_bool(&stack.pop()?) {
+                        return Err(ScriptError::Verify);
+                    }
+                }
+                OP_RETURN => return Err(ScriptError::OpReturn),
+
+                OP_TOALTSTACK => alt_stack.push(stack.pop()?),
+                OP_FROMALTSTACK => {
+                    let item = alt_stack
+                        .pop()
+                        .ok_or(ScriptError::InvalidAltstackOperation)?;
+                    stack.0.push(item);
+                }
+                OP_2DROP => {
+                    stack.require(2)?;
+                    stack.0.truncate(stack.0.len() - 2);
+                }
+                OP_2DUP | OP_3DUP => {
+                    let count = if op == OP_2DUP { 2 } else { 3 };
+                    stack.require(count)?;
+                    let items = stack.0[stack.0.len() - count..].to_vec();
+                    stack.0.extend(items);
+                }
+                OP_2OVER => {
+                    stack.require(4)?;
+                    let items = stack.0[stack.0.len() - 4..stack.0.len() - 2].to_vec();
+                    stack.0.extend(items);
+                }
+                OP_2ROT => {
+                    stack.require(6)?;
+                    let start = stack.0.len() - 6;
+                    let items: Vec<_> = stack.0.drain(start..start + 2).collect();
+                    stack.0.extend(items);
+                }
+                OP_2SWAP => {
+                    stack.require(4)?;
+                    let len = stack.0.len();
+                    stack.0[len - 4..].rotate_left(2);
+                }
+                OP_IFDUP => {
+                    let top = stack.top(1)?.clone();
+                    if cast_to_bool(&top) {
+                        stack.0.push(top);
+                    }
+                }
+                OP_DEPTH => stack.0.push(encode_num(stack.0.len() as i64)),
+                OP_DROP => {
+                    stack.pop()?;
+                }
+                OP_DUP => stack.0.push(stack.top(1)?.clone()),
+                OP_NIP => {
+                    stack.require(2)?;
+                    let index = stack.0.len() - 2;
+                    stack.0.remove(index);
+                }
+                OP_OVER => stack.0.push(stack.top(2)?.clone()),
+                OP_PICK | OP_ROLL => {
+                    stack.require(2)?;
+                    let n = stack.pop_num(flags)?;
+                    if n < 0 || n as usize >= stack.0.len() {
+                        return Err(ScriptError::InvalidStackOperation);
+                    }
+                    let index = stack.0.len() - 1 - n as usize;
+                    let item = if op == OP_ROLL {
+                        stack.0.remove(index)
+                    } else {
+                        stack.0[index].clone()
+                    };
+                    stack.0.push(item);
+                }
+                OP_ROT => {
+                    stack.require(3)?;
+                    let len = stack.0.len();
+                    stack.0[len - 3..].rotate_left(1);
+                }
+                OP_SWAP => {
+                    stack.require(2)?;
+                    let len = stack.0.len();
+                    stack.0.swap(len - 2, len - 1);
+                }
+                OP_TUCK => {
+                    stack.require(2)?;
+                    let top = stack.top(1)?.clone();
+                    let index = stack.0.len() - 2;
+                    stack.0.insert(index, top);
+                }
+                OP_SIZE => stack.0.push(encode_num(stack.top(1)?.len() as i64)),
+
+                OP_EQUAL | OP_EQUALVERIFY => {
+                    stack.require(2)?;
+                    let equal = stack.pop()? == stack.pop()?;
+                    if op == OP_EQUALVERIFY {
+                        if !equal {
+                            return Err(ScriptError::EqualVerify);
+                        }
+                    } else {
+                        stack.push_bool(equal);
+                    }
+                }
+
+                OP_1ADD | OP_1SUB | OP_NEGATE | OP_ABS | OP_NOT | OP_0NOTEQUAL => {
+                    let n = stack.pop_num(flags)?;
+                    let result = match op {
+                        OP_1ADD => n + 1,
+                        OP_1SUB => n - 1,
+                        OP_NEGATE => -n,
+                        OP_ABS => n.abs(),
+                        OP_NOT => (n == 0) as i64,
+                        _ => (n != 0) as i64,
+                    };
+                    stack.0.push(encode_num(result));
+                }
+                OP_ADD
+                | OP_SUB
+                | OP_BOOLAND
+                | OP_BOOLOR
+                | OP_NUMEQUAL
+                | OP_NUMEQUALVERIFY
+                | OP_NUMNOTEQUAL
+                | OP_LESSTHAN
+                | OP_GREATERTHAN
+                | OP_LESSTHANOREQUAL
+                | OP_GREATERTHANOREQUAL
+                | OP_MIN
+                | OP_MAX => {
+                    stack.require(2)?;
+                    let b = stack.pop_num(flags)?;
+                    let a = stack.pop_num(flags)?;
+                    let result = match op {
+                        OP_ADD => a + b,
+                        OP_SUB => a - b,
+                        OP_BOOLAND => (a != 0 && b != 0) as i64,
+                        OP_BOOLOR => (a != 0 || b != 0) as i64,
+                        OP_NUMEQUAL | OP_NUMEQUALVERIFY => (a == b) as i64,
+                        OP_NUMNOTEQUAL => (a != b) as i64,
+                        OP_LESSTHAN => (a < b) as i64,
+                        OP_GREATERTHAN => (a > b) as i64,
+                        OP_LESSTHANOREQUAL => (a <= b) as i64,
+                        OP_GREATERTHANOREQUAL => (a >= b) as i64,
+                        OP_MIN => a.min(b),
+                        _ => a.max(b),
+                    };
+                    if op == OP_NUMEQUALVERIFY {
+                        if result == 0 {
+                            return Err(ScriptError::NumEqualVerify);
+                        }
+                    } else {
+                        stack.0.push(encode_num(result));
+                    }
+                }
+                OP_WITHIN => {
+                    stack.require(3)?;
+                    let max = stack.pop_num(flags)?;
+                    let min = stack.pop_num(flags)?;
+                    let x = stack.pop_num(flags)?;
+                    stack.push_bool(min <= x && x < max);
+                }
+
+                OP_RIPEMD160 | OP_SHA1 | OP_SHA256 | OP_HASH160 | OP_HASH256 => {
+                    let item = stack.pop()?;
+                    stack.0.push(match op {
+                        OP_RIPEMD160 => Ripemd160::digest(&item).to_vec(),
+                        OP_SHA1 => sha1(&item).to_vec(),
+                        OP_SHA256 => Sha256::digest(&item).to_vec(),
+                        OP_HASH160 => hash160(&item),
+                        _ => hash256(&item),
+                    });
+                }
+                OP_CODESEPARATOR => {
+                    code_start = pc;
+                    exec.codeseparator_pos = opcode_pos - 1;
+                }
+                OP_CHECKSIG | OP_CHECKSIGVERIFY if sig_version == SigVersion::Tapscript => {
+                    stack.require(2)?;
+                    let pubkey = stack.pop()?;
+                    let sig = stack.pop()?;
+                    let success = check_sig_tapscript(&sig, &pubkey, flags, checker, exec)?;
+                    if op == OP_CHECKSIGVERIFY {
+                        if !success {
+                            return Err(ScriptError::CheckSigVerify);
+                        }
+                    } else {
+                        stack.push_bool(success);
+                    }
+                }
+                OP_CHECKSIGADD if sig_version == SigVersion::Tapscript => {
+                    stack.require(3)?;
+                    let n = decode_num(stack.top(2)?, require_minimal, 4)?;
+                    let pubkey = stack.pop()?;
+                    stack.pop()?;
+                    let sig = stack.pop()?;
+                    let success = check_sig_tapscript(&sig, &pubkey, flags, checker, exec)?;
+                    stack.0.push(encode_num(n + success as i64));
+                }
+                OP_CHECKMULTISIG | OP_CHECKMULTISIGVERIFY
+                    if sig_version == SigVersion::Tapscript =>
+                {
+                    return Err(ScriptError::TapscriptCheckMultisig);
+                }
+                OP_CHECKSIG | OP_CHECKSIGVERIFY => {
+                    stack.require(2)?;
+                    let pubkey = stack.pop()?;
+                    let sig = stack.pop()?;
+                    let mut script_code = Script::from_bytes(raw[code_start..].to_vec());
+                    if sig_version == SigVersion::Base {
+                        script_code = find_and_delete(&script_code, &sig);
+                    }
+                    check_signature_encoding(&sig, flags)?;
+                    check_pubkey_encoding(&pubkey, flags, sig_version)?;
+                    let success = checker.check_sig(&sig, &pubkey, &script_code, sig_version);
+                    if !success && flags & VERIFY_NULLFAIL != 0 && !sig.is_empty() {
+                        return Err(ScriptError::SigNullFail);
+                    }
+                    if op == OP_CHECKSIGVERIFY {
+                        if !success {
+                            return Err(ScriptError::CheckSigVerify);
+                        }
+                    } else {
+                        stack.push_bool(success);
+                    }
+                }
+                OP_CHECKMULTISIG | OP_CHECKMULTISIGVERIFY => {
+                    let mut i = 1;
+                    let key_count = decode_num(stack.top(i)?, require_minimal, 4)?;
+                    if !(0..=MAX_PUBKEYS_PER_MULTISIG as i64).contains(&key_count) {
+                        return Err(ScriptError::PubkeyCount);
+                    }
+                    let mut key_count = key_count as usize;
+                    // the count, keys and signature count are popped first
+                    let mut unchecked_pops = key_count + 2;
+                    op_count += key_count;
+                    if op_count > MAX_OPS_PER_SCRIPT {
+                        return Err(ScriptError::OpCount);
+                    }
+                    i += 1;
+                    let mut key = i;
+                    i += key_count;
+                    let sig_count = decode_num(stack.top(i)?, require_minimal, 4)?;
+                    if !(0..=key_count as i64).contains(&sig_count) {
+                        return Err(ScriptError::SigCount);
+                    }
+                    let mut sig_count = sig_count as usize;
+                    i += 1;
+                    let mut sig = i;
+                    i += sig_count;
+                    stack.require(i)?;
+
+                    let mut script_code = Script::from_bytes(raw[code_start..].to_vec());
+                    if sig_version == SigVersion::Base {
+                        for k in 0..sig_count {
+                            script_code = find_and_delete(&script_code, stack.top(sig + k)?);
+                        }
+                    }
+                    let mut success = true;
+                    while success && sig_count > 0 {
+                        let signature = stack.top(sig)?;
+                        let pubkey = stack.top(key)?;
+                        check_signature_encoding(signature, flags)?;
+                        check_pubkey_encoding(pubkey, flags, sig_version)?;
+                        if checker.check_sig(signature, pubkey, &script_code, sig_version) {
+                            sig += 1;
+                            sig_count -= 1;
+                        }
+                        key += 1;
+                        key_count -= 1;
+                        // more signatures left than keys to match them
+                        if sig_count > key_count {
+                            success = false;
+                        }
+                    }
+                    for _ in 1..i {
+                        let item = stack.pop()?;
+                        if !success
+                            && flags & VERIFY_NULLFAIL != 0
+                            && unchecked_pops == 0
+                            && !item.is_empty()
+                        {
+                            return Err(ScriptError::SigNullFail);
+                        }
+                        unchecked_pops = unchecked_pops.saturating_sub(1);
+                    }
+                    // the extra element consumed by an off-by-one in the
+                    // original implementation
+                    if !stack.pop()?.is_empty() && flags & VERIFY_NULLDUMMY != 0 {
+                        return Err(ScriptError::SigNullDummy);
+                    }
+                    if op == OP_CHECKMULTISIGVERIFY {
+                        if !success {
+                            return Err(ScriptError::CheckMultisigVerify);
+                        }
+                    } else {
+                        stack.push_bool(success);
+                    }
+                }
+                _ => return Err(ScriptError::BadOpcode),
+            }
+        }
+        if stack.0.len() + alt_stack.len() > MAX_STACK_SIZE {
+            return Err(ScriptError::StackSize);
+        }
+    }
+    if !conditions.is_empty() {
+        return Err(ScriptError::UnbalancedConditional);
+    }
+    Ok(())
+}
+
+/// Runs a witness script on its witness stack, Bitcoin Core's
+/// `ExecuteWitnessScript`.
+fn execute_witness_script(
+    stack: Vec<Vec<u8>>,
+    script: &Script,
+    flags: u32,
+    checker: &TxChecker,
+    sig_version: SigVersion,
+    exec: &mut ExecData,
+) -> Result<(), ScriptError> {
+    if sig_version == SigVersion::Tapscript {
+        // an OP_SUCCESSx anywhere makes the script succeed unexecuted
+        let raw = script.as_bytes();
+        let mut pc = 0;
+        while pc < raw.len() {
+            let (op, _) = read_op(raw, &mut pc).ok_or(ScriptError::BadOpcode)?;
+            if is_op_success(op) {
+                if flags & VERIFY_DISCOURAGE_OP_SUCCESS != 0 {
+                    return Err(ScriptError::DiscourageOpSuccess);
+                }
+                return Ok(());
+            }
+        }
+        if stack.len() > MAX_STACK_SIZE {
+            return Err(ScriptError::StackSize);
+        }
+    }
+    if stack
+        .iter()
+        .any(|item| item.len() > MAX_SCRIPT_ELEMENT_SIZE)
+    {
+        return Err(ScriptError::PushSize);
+    }
+    let mut stack = Stack(stack);
+    if sig_version == SigVersion::Tapscript {
+        execute(
+            &mut stack,
+            script.as_bytes(),
+            flags,
+            checker,
+            sig_version,
+            exec,
+        )?;
+    } else {
+        eval_script(&mut stack.0, script, flags, checker, sig_version)?;
+    }
+    // witness scripts must leave exactly one true element
+    if stack.0.len() != 1 {
+        return Err(ScriptError::CleanStack);
+    }
+    if !cast_to_bool(&stack.0[0]) {
+        return Err(ScriptError::EvalFalse);
+    }
+    Ok(())
+}
+
+/// Verifies a spend of a taproot output with the x-only key `program`:
+/// a signature for the key path, or a script of the tree it commits to
+/// with its control block (BIP341).
+fn verify_taproot(
+    witness: &[Vec<u8>],
+    program: &[u8],
+    flags: u32,
+    checker: &TxChecker,
+) -> Result<(), ScriptError> {
+    let mut stack = witness.to_vec();
+    if stack.is_empty() {
+        return Err(ScriptError::WitnessProgramWitnessEmpty);
+    }
+    let annex = match stack.last() {
+        Some(last) if stack.len() >= 2 && last.first() == Some(&ANNEX_TAG) => stack.pop(),
+        _ => None,
+    };
+    if stack.len() == 1 {
+        return checker.check_schnorr_sig(&stack[0], program, annex.as_deref(), None);
+    }
+    let control = stack.pop().unwrap();
+    let script = Script::from_bytes(stack.pop().unwrap());
+    let nodes = control.len().saturating_sub(TAPROOT_CONTROL_BASE_SIZE);
+    if control.len() < TAPROOT_CONTROL_BASE_SIZE
+        || !nodes.is_multiple_of(TAPROOT_CONTROL_NODE_SIZE)
+        || nodes / TAPROOT_CONTROL_NODE_SIZE > TAPROOT_CONTROL_MAX_NODE_COUNT
+    {
+        return Err(ScriptError::TaprootWrongControlSize);
+    }
+    let committed = ControlBlock::parse(&control)
+        .zip(XOnlyPublicKey::parse(program))
+        .is_some_and(|(control_block, output_key)| control_block.verify(&output_key, &script));
+    if !committed {
+        return Err(ScriptError::WitnessProgramMismatch);
+    }
+    let leaf_version = control[0] & TAPROOT_LEAF_MASK;
+    if leaf_version != TAPROOT_LEAF_TAPSCRIPT {
+        // unknown leaf versions are left for soft forks
+        if flags & VERIFY_DISCOURAGE_UPGRADABLE_TAPROOT_VERSION != 0 {
+            return Err(ScriptError::DiscourageUpgradableTaprootVersion);
+        }
+        return Ok(());
+    }
+    let mut exec = ExecData {
+        annex,
+        tapleaf_hash: tap_leaf_hash(leaf_version, &script),
+        codeseparator_pos: u32::MAX,
+        validation_weight_left: serialize_witness(witness).len() as i64 + VALIDATION_WEIGHT_OFFSET,
+    };
+    execute_witness_script(
+        stack,
+        &script,
+        flags,
+        checker,
+        SigVersion::Tapscript,
+        &mut exec,
+    )
+}
+
+/// Runs a segwit program with its witness (BIP141). Version 1 programs of
+/// 32 bytes not nested in P2SH are taproot outputs, verified under
+/// VERIFY_TAPROOT; other versions are left for future soft forks and
+/// succeed.
+fn verify_witness_program(
+    witness: &[Vec<u8>],
+    version: u8,
+    program: &[u8],
+    flags: u32,
+    checker: &TxChecker,
+    is_p2sh: bool,
+) -> Result<(), ScriptError> {
+    if version == 1 && program.len() == 32 && !is_p2sh {
+        if flags & VERIFY_TAPROOT == 0 {
+            return Ok(());
+        }
+        return verify_taproot(witness, program, flags, checker);
+    }
+    if version != 0 {
+        if flags & VERIFY_DISCOURAGE_UPGRADABLE_WITNESS_PROGRAM != 0 {
+            return Err(ScriptError::DiscourageUpgradableWitnessProgram);
+        }
+        return Ok(());
+    }
+    let (stack, script) = match program.len() {
+        32 => {
+            let (script, stack) = witness
+                .split_last()
+                .ok_or(ScriptError::WitnessProgramWitnessEmpty)?;
+            if Sha256::digest(script)[..] != *program {
+                return Err(ScriptError::WitnessProgramMismatch);
+            }
+            (stack.to_vec(), Script::from_bytes(script.clone()))
+        }
+        20 => {
+            if witness.len() != 2 {
+                return Err(ScriptError::WitnessProgramMismatch);
+            }
+            (witness.to_vec(), Script::p2pkh(program.try_into().unwrap()))
+        }
+        _ => return Err(ScriptError::WitnessProgramWrongLength),
+    };
+    execute_witness_script(
+        stack,
+        &script,
+        flags,
+        checker,
+        SigVersion::WitnessV0,
+        &mut ExecData::default(),
+    )
+}
+
+/// Verifies that `script_sig` and `witness` satisfy `script_pubkey`, with
+/// P2SH and segwit as enabled by `flags`. Bitcoin Core's `VerifyScript`.
+pub fn verify_script(
+    script_sig: &Script,
+    script_pubkey: &Script,
+    witness: &[Vec<u8>],
+    flags: u32,
+    checker: &TxChecker,
+) -> Result<(), ScriptError> {
+    if flags & VERIFY_SIGPUSHONLY != 0 && !script_sig.is_push_only() {
+        return Err(ScriptError::SigPushOnly);
+    }
+    let mut stack = Vec::new();
+    eval_script(&mut stack, script_sig, flags, checker, SigVersion::Base)?;
+    let p2sh_stack = stack.clone();
+    eval_script(&mut stack, script_pubkey, flags, checker, SigVersion::Base)?;
+    if !stack.last().is_some_and(|top| cast_to_bool(top)) {
+        return Err(ScriptError::EvalFalse);
+    }
+
+    let mut had_witness = false;
+    if flags & VERIFY_WITNESS != 0 {
+        if let Some((version, program)) = script_pubkey.witness_version_and_program() {
+            had_witness = true;
+            if !script_sig.is_empty() {
+                return Err(ScriptError::WitnessMalleated);
+            }
+            verify_witness_program(witness, version, program, flags, checker, false)?;
+            stack.truncate(1);
+        }
+    }
+
+    if flags & VERIFY_P2SH != 0 && script_pubkey.is_p2sh() {
+        if !script_sig.is_push_only() {
+            return Err(ScriptError::SigPushOnly);
+        }
+        stack = p2sh_stack;
+        // the scriptSig leaves at least one element, as it made the hash
+        // comparison succeed
+        let redeem_script = Script::from_bytes(stack.pop().unwrap());
+        eval_script(&mut stack, &redeem_script, flags, checker, SigVersion::Base)?;
+        if !stack.last().is_some_and(|top| cast_to_bool(top)) {
+            return Err(ScriptError::EvalFalse);
+        }
+        if flags & VERIFY_WITNESS != 0 {
+            if let Some((version, program)) = redeem_script.witness_version_and_program() {
+                had_witness = true;
+                if script_sig.as_bytes() != push_data(redeem_script.as_bytes()) {
+                    return Err(ScriptError::WitnessMalleatedP2sh);
+                }
+                verify_witness_program(witness, version, program, flags, checker, true)?;
+                stack.truncate(1);
+            }
+        }
+    }
+
+    if flags & VERIFY_CLEANSTACK != 0 && stack.len() != 1 {
+        return Err(ScriptError::CleanStack);
+    }
+    if flags & VERIFY_WITNESS != 0 && !had_witness && !witness.is_empty() {
+        return Err(ScriptError::WitnessUnexpected);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        sighash::{p2wpkh_script_code, SIGHASH_ALL},
+        signature::PrivateKey,
+        taproot::{sign_schnorr, tweak_private_key, TapLeaf, TapTree, TaprootSpendInfo},
+        tx::{OutPoint, TxIn},
+    };
+    use std::{collections::HashMap, io::Cursor};
+
+    fn spend(version: u32, sequence: u32, locktime: u32) -> Tx {
+        Tx::new(
+            version,
+            vec![TxIn::new(OutPoint::new([1; 32], 0), sequence)],
+            vec![TxOut::new(40_000, Script::p2wpkh(&[2; 20]))],
+            locktime,
+        )
+    }
+
+    fn run(tx: &Tx, script: &Script, flags: u32) -> Result<Vec<Vec<u8>>, ScriptError> {
+        let mut stack = Vec::new();
+        let checker = TxChecker::new(tx, 0, 50_000);
+        eval_script(&mut stack, script, flags, &checker, SigVersion::Base)?;
+        Ok(stack)
+    }
+
+    #[test]
+    fn test_sha1() {
+        assert_eq!(
+            hex::encode(sha1(b"")),
+            "da39a3ee5e6b4b0d3255bfef95601890afd80709"
+        );
+        assert_eq!(
+            hex::encode(sha1(&[b'a'; 1000])),
+            "291e9a6c66994949b57ba5e650361e98fc36b1ba"
+        );
+    }
+
+    #[test]
+    fn test_eval_script() {
+        let tx = spend(1, SEQUENCE_LOCKTIME_DISABLE_FLAG, 0);
+        let ops = |cmds: &[Command]| Script::new(cmds);
+        let script = ops(&[
+            Command::int(2),
+            Command::int(1000),
+            Command::Op(OP_ADD),
+            Command::int(1002),
+            Command::Op(OP_EQUAL),
+        ]);
+        assert_eq!(run(&tx, &script, VERIFY_NONE), Ok(vec![vec![1]]));
+        let script = ops(&[
+            Command::int(0),
+            Command::Op(OP_IF),
+            Command::int(2),
+            Command::Op(OP_ELSE),
+            Command::int(-1),
+            Command::Op(OP_ENDIF),
+        ]);
+        assert_eq!(run(&tx, &script, VERIFY_NONE), Ok(vec![vec![0x81]]));
+        let script = ops(&[Command::int(1), Command::Op(OP_IF)]);
+        assert_eq!(
+            run(&tx, &script, VERIFY_NONE),
+            Err(ScriptError::UnbalancedConditional)
+        );
+        // disabled opcodes fail even in a branch not taken
+        let script = ops(&[
+            Command::int(0),
+            Command::Op(OP_IF),
+            Command::Op(OP_CAT),
+            Command::Op(OP_ENDIF),
+        ]);
+        assert_eq!(
+            run(&tx, &script, VERIFY_NONE),
+            Err(ScriptError::DisabledOpcode)
+        );
+        assert_eq!(
+            run(&tx, &ops(&[Command::Op(OP_RETURN)]), VERIFY_NONE),
+            Err(ScriptError::OpReturn)
+        );
+
+        // 5 pushed as data rather than OP_5
+        let script = Script::from_bytes(vec![0x01, 0x05]);
+        assert_eq!(run(&tx, &script, VERIFY_NONE), Ok(vec![vec![5]]));
+        assert_eq!(
+            run(&tx, &script, VERIFY_MINIMALDATA),
+            Err(ScriptError::MinimalData)
+        );
+        assert_eq!(decode_num(&[0xff, 0x80], true, 4), Ok(-0xff));
+        assert_eq!(
+            decode_num(&[0x05, 0x00], true, 4),
+            Err(ScriptError::MinimalData)
+        );
+        assert_eq!(decode_num(&[1; 5], false, 4), Err(ScriptError::NumOverflow));
+        assert!(!cast_to_bool(&[0, 0, 0x80]));
+    }
+
+    #[test]
+    fn test_locktime_ops() {
+        let cltv = |n: i64| Script::new(&[Command::int(n), Command::Op(OP_CHECKLOCKTIMEVERIFY)]);
+        let tx = spend(2, 10, 500);
+        let flags = VERIFY_CHECKLOCKTIMEVERIFY | VERIFY_CHECKSEQUENCEVERIFY;
+        assert!(run(&tx, &cltv(500), flags).is_ok());
+        assert_eq!(
+            run(&tx, &cltv(501), flags),
+            Err(ScriptError::UnsatisfiedLocktime)
+        );
+        assert_eq!(
+            run(&tx, &cltv(-1), flags),
+            Err(ScriptError::NegativeLocktime)
+        );
+        // a timestamp against a height
+        assert_eq!(
+            run(&tx, &cltv(LOCKTIME_THRESHOLD as i64), flags),
+            Err(ScriptError::UnsatisfiedLocktime)
+        );
+        // before BIP65 the opcode was OP_NOP2
+        assert!(run(&tx, &cltv(501), VERIFY_NONE).is_ok());
+        // a final sequence disables the locktime
+        let final_tx = spend(2, u32::MAX, 500);
+        assert_eq!(
+            run(&final_tx, &cltv(500), flags),
+            Err(ScriptError::UnsatisfiedLocktime)
+        );
+
+        let csv = |n: i64| Script::new(&[Command::int(n), Command::Op(OP_CHECKSEQUENCEVERIFY)]);
+        assert!(run(&tx, &csv(10), flags).is_ok());
+        assert_eq!(
+            run(&tx, &csv(11), flags),
+            Err(ScriptError::UnsatisfiedLocktime)
+        );
+        // a disabled relative lock in the script always passes
+        assert!(run(&tx, &csv(1 << 31), flags).is_ok());
+        assert_eq!(
+            run(&spend(1, 10, 500), &csv(10), flags),
+            Err(ScriptError::UnsatisfiedLocktime)
+        );
+    }
+
+    #[test]
+    fn test_signed_spends() {
+        let key = PrivateKey::new(U256::from(12345));
+        let pubkey = key.get_point().compressed_sec().to_vec();
+        let h160: [u8; 20] = hash160(&pubkey).try_into().unwrap();
+        let flags = VERIFY_P2SH
+            | VERIFY_WITNESS
+            | VERIFY_DERSIG
+            | VERIFY_NULLFAIL
+            | VERIFY_CLEANSTACK
+            | VERIFY_MINIMALDATA;
+
+        let mut tx = spend(2, SEQUENCE_LOCKTIME_DISABLE_FLAG, 0);
+        let script_pubkey = Script::p2pkh(&h160);
+        let z = legacy_sighash(&tx, 0, &script_pubkey, SIGHASH_ALL);
+        let mut sig = key.sign_low_r(U256::from_big_endian(&z)).der();
+        sig.push(SIGHASH_ALL as u8);
+        tx.tx_ins[0].script_sig = Script::new(&[Command::Data(sig), Command::Data(pubkey.clone())]);
+        let checker = TxChecker::new(&tx, 0, 50_000);
+        assert_eq!(
+            verify_script(
+                &tx.tx_ins[0].script_sig,
+                &script_pubkey,
+                &[],
+                flags,
+                &checker
+            ),
+            Ok(())
+        );
+
+        let mut tx = spend(2, SEQUENCE_LOCKTIME_DISABLE_FLAG, 0);
+        let script_pubkey = Script::p2wpkh(&h160);
+        let z = segwit_v0_sighash(&tx, 0, &p2wpkh_script_code(&h160), 50_000, SIGHASH_ALL);
+        let mut sig = key.sign_low_r(U256::from_big_endian(&z)).der();
+        sig.push(SIGHASH_ALL as u8);
+        tx.tx_ins[0].witness = vec![sig, pubkey.clone()];
+        let witness = tx.tx_ins[0].witness.clone();
+        let empty = Script::new(&[]);
+        let checker = TxChecker::new(&tx, 0, 50_000);
+        assert_eq!(
+            verify_script(&empty, &script_pubkey, &witness, flags, &checker),
+            Ok(())
+        );
+        // the signature commits to the amount, and NULLFAIL rejects the
+        // failed signature instead of leaving false on the stack
+        let checker = TxChecker::new(&tx, 0, 50_001);
+        assert_eq!(
+            verify_script(&empty, &script_pubkey, &witness, flags, &checker),
+            Err(ScriptError::SigNullFail)
+        );
+        // nothing may remain in the scriptSig of a native segwit spend
+        let script_sig = Script::new(&[Command::int(1)]);
+        assert_eq!(
+            verify_script(&script_sig, &script_pubkey, &witness, flags, &checker),
+            Err(ScriptError::WitnessMalleated)
+        );
+
+        // legacy script code is signed with only its OP_CODESEPARATORs
+        // removed, keeping a non-minimal push as it is
+        let mut tx = spend(2, SEQUENCE_LOCKTIME_DISABLE_FLAG, 0);
+        let script = |ops: &[u8]| Script::from_bytes([&push_data(&pubkey)[..], ops].concat());
+        let script_pubkey = script(&[OP_CHECKSIGVERIFY, OP_CODESEPARATOR, OP_PUSHDATA1, 1, 7]);
+        let script_code = script(&[OP_CHECKSIGVERIFY, OP_PUSHDATA1, 1, 7]);
+        let z = legacy_sighash(&tx, 0, &script_code, SIGHASH_ALL);
+        let mut sig = key.sign_low_r(U256::from_big_endian(&z)).der();
+        sig.push(SIGHASH_ALL as u8);
+        tx.tx_ins[0].script_sig = Script::new(&[Command::Data(sig)]);
+        let checker = TxChecker::new(&tx, 0, 50_000);
+        assert_eq!(
+            verify_script(
+                &tx.tx_ins[0].script_sig,
+                &script_pubkey,
+                &[],
+                flags & !VERIFY_MINIMALDATA,
+                &checker
+            ),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn test_taproot_spends() {
+        let key = PrivateKey::new(U256::from(12345));
+        let internal_key = XOnlyPublicKey::from_point(&key.get_point());
+        let checksig = TapLeaf::new(Script::new(&[
+            Command::Data(internal_key.serialize().to_vec()),
+            Command::Op(OP_CHECKSIG),
+        ]));
+        let equal_two = TapLeaf::new(Script::new(&[Command::int(2), Command::Op(OP_EQUAL)]));
+        let op_success = TapLeaf::new(Script::from_bytes(vec![0x50]));
+        let tree = TapTree::branch(
+            TapTree::Leaf(checksig.clone()),
+            TapTree::branch(
+                TapTree::Leaf(equal_two.clone()),
+                TapTree::Leaf(op_success.clone()),
+            ),
+        );
+        let info = TaprootSpendInfo::new(internal_key, Some(&tree)).unwrap();
+        let spent_outputs = vec![TxOut::new(50_000, info.script_pubkey())];
+        let tx = spend(2, SEQUENCE_LOCKTIME_DISABLE_FLAG, 0);
+        let flags = VERIFY_P2SH | VERIFY_WITNESS | VERIFY_TAPROOT;
+        let empty = Script::new(&[]);
+        let verify = |witness: &[Vec<u8>], flags: u32| {
+            let checker = TxChecker::with_spent_outputs(&tx, 0, &spent_outputs);
+            verify_script(&empty, &info.script_pubkey(), witness, flags, &checker)
+        };
+
+        // the key path, with the key tweaked by the tree
+        let tweaked = tweak_private_key(&key, info.merkle_root.as_ref()).unwrap();
+        let sighash = taproot_sighash(&tx, 0, &spent_outputs, SIGHASH_DEFAULT, None, None);
+        let sig = sign_schnorr(&tweaked, &sighash.unwrap(), &[0; 32]).to_vec();
+        assert_eq!(verify(std::slice::from_ref(&sig), flags), Ok(()));
+        // the annex is signed too
+        let annex = vec![ANNEX_TAG, 1];
+        assert_eq!(
+            verify(&[sig.clone(), annex], flags),
+            Err(ScriptError::SchnorrSig)
+        );
+        // SIGHASH_DEFAULT may not be given explicitly
+        let mut explicit = sig.clone();
+        explicit.push(SIGHASH_DEFAULT as u8);
+        assert_eq!(
+            verify(&[explicit], flags),
+            Err(ScriptError::SchnorrSigHashType)
+        );
+        // taproot spends need every spent output
+        let checker = TxChecker::new(&tx, 0, 50_000);
+        assert_eq!(
+            verify_script(&empty, &info.script_pubkey(), &[sig], flags, &checker),
+            Err(ScriptError::SchnorrSig)
+        );
+        assert_eq!(verify(&[vec![0; 64]], flags), Err(ScriptError::SchnorrSig));
+        // before taproot, anything spends a version 1 output
+        assert_eq!(verify(&[vec![0; 64]], VERIFY_P2SH | VERIFY_WITNESS), Ok(()));
+        assert_eq!(
+            verify(&[vec![1; 10]], flags),
+            Err(ScriptError::SchnorrSigSize)
+        );
+
+        // the script path
+        let control_block = info.control_block(&equal_two).unwrap().serialize();
+        let script = equal_two.script.as_bytes().to_vec();
+        assert_eq!(
+            verify(&[vec![2], script.clone(), control_block.clone()], flags),
+            Ok(())
+        );
+        assert_eq!(
+            verify(&[vec![3], script.clone(), control_block.clone()], flags),
+            Err(ScriptError::EvalFalse)
+        );
+        let mut wrong_parity = control_block.clone();
+        wrong_parity[0] ^= 1;
+        assert_eq!(
+            verify(&[vec![2], script.clone(), wrong_parity], flags),
+            Err(ScriptError::WitnessProgramMismatch)
+        );
+        assert_eq!(
+            verify(&[vec![2], script, control_block[..40].to_vec()], flags),
+            Err(ScriptError::TaprootWrongControlSize)
+        );
+
+        let control_block = info.control_block(&checksig).unwrap().serialize();
+        let script = checksig.script.as_bytes().to_vec();
+        let leaf = (&checksig.leaf_hash(), u32::MAX);
+        let sighash = taproot_sighash(&tx, 0, &spent_outputs, SIGHASH_DEFAULT, None, Some(leaf));
+        let sig = sign_schnorr(&key, &sighash.unwrap(), &[0; 32]).to_vec();
+        assert_eq!(
+            verify(&[sig, script.clone(), control_block.clone()], flags),
+            Ok(())
+        );
+        // an empty signature fails without failing the script, but not a
+        // wrong one
+        assert_eq!(
+            verify(&[vec![], script.clone(), control_block.clone()], flags),
+            Err(ScriptError::EvalFalse)
+        );
+        assert_eq!(
+            verify(&[vec![0; 64], script, control_block], flags),
+            Err(ScriptError::SchnorrSig)
+        );
+
+        // OP_SUCCESSx is left for soft forks
+        let control_block = info.control_block(&op_success).unwrap().serialize();
+        let witness = [vec![0x50], control_block];
+        assert_eq!(verify(&witness, flags), Ok(()));
+        assert_eq!(
+            verify(&witness, flags | VERIFY_DISCOURAGE_OP_SUCCESS),
+            Err(ScriptError::DiscourageOpSuccess)
+        );
+    }
+
+    #[test]
+    fn test_tapscript_rules() {
+        let tx = spend(2, SEQUENCE_LOCKTIME_DISABLE_FLAG, 0);
+        let checker = TxChecker::new(&tx, 0, 50_000);
+        let run_tapscript = |stack: Vec<Vec<u8>>, cmds: &[Command], weight: i64| {
+            let mut exec = ExecData {
+                validation_weight_left: weight,
+                ..Default::default()
+            };
+            let script = Script::new(cmds);
+            execute_witness_script(
+                stack,
+                &script,
+                VERIFY_NONE,
+                &checker,
+                SigVersion::Tapscript,
+                &mut exec,
+            )
+        };
+        // MINIMALIF is consensus
+        let script = [Command::Op(OP_IF), Command::int(1), Command::Op(OP_ENDIF)];
+        assert_eq!(run_tapscript(vec![vec![1]], &script, 0), Ok(()));
+        assert_eq!(
+            run_tapscript(vec![vec![2]], &script, 0),
+            Err(ScriptError::TapscriptMinimalIf)
+        );
+        assert_eq!(
+            run_tapscript(vec![], &[Command::int(0), Command::Op(OP_CHECKMULTISIG)], 0),
+            Err(ScriptError::TapscriptCheckMultisig)
+        );
+        // unknown key types succeed, but still use up validation weight
+        let checksigadd = [
+            Command::Data(vec![1; 64]),
+            Command::int(0),
+            Command::Data(vec![2; 33]),
+            Command::Op(OP_CHECKSIGADD),
+            Command::int(1),
+            Command::Op(OP_NUMEQUAL),
+        ];
+        assert_eq!(run_tapscript(vec![], &checksigadd, 50), Ok(()));
+        assert_eq!(
+            run_tapscript(vec![], &checksigadd, 49),
+            Err(ScriptError::TapscriptValidationWeight)
+        );
+        // no limit of 201 opcodes
+        let nops = vec![Command::Op(OP_NOP); 300];
+        assert_eq!(run_tapscript(vec![vec![1]], &nops, 0), Ok(()));
+        assert_eq!(
+            run(&tx, &Script::new(&nops), VERIFY_NONE),
+            Err(ScriptError::OpCount)
+        );
+        // OP_CHECKSIGADD is only defined in tapscript
+        assert_eq!(
+            run(&tx, &Script::new(&checksigadd), VERIFY_NONE),
+            Err(ScriptError::BadOpcode)
+        );
+    }
+
+    /// Opcode names from OP_1NEGATE to OP_CHECKSIGADD, without the OP_.
+    const OPCODE_NAMES: &str = "1NEGATE RESERVED 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15 16 NOP \
+        VER IF NOTIF VERIF VERNOTIF ELSE ENDIF VERIFY RETURN TOALTSTACK FROMALTSTACK 2DROP 2DUP \
+        3DUP 2OVER 2ROT 2SWAP IFDUP DEPTH DROP DUP NIP OVER PICK ROLL ROT SWAP TUCK CAT SUBSTR \
+        LEFT RIGHT SIZE INVERT AND OR XOR EQUAL EQUALVERIFY RESERVED1 RESERVED2 1ADD 1SUB 2MUL \
+        2DIV NEGATE ABS NOT 0NOTEQUAL ADD SUB MUL DIV MOD LSHIFT RSHIFT BOOLAND BOOLOR NUMEQUAL \
+        NUMEQUALVERIFY NUMNOTEQUAL LESSTHAN GREATERTHAN LESSTHANOREQUAL GREATERTHANOREQUAL MIN \
+        MAX WITHIN RIPEMD160 SHA1 SHA256 HASH160 HASH256 CODESEPARATOR CHECKSIG CHECKSIGVERIFY \
+        CHECKMULTISIG CHECKMULTISIGVERIFY NOP1 CHECKLOCKTIMEVERIFY CHECKSEQUENCEVERIFY NOP4 NOP5 \
+        NOP6 NOP7 NOP8 NOP9 NOP10 CHECKSIGADD";
+
+    /// Parses the script notation of Bitcoin Core's test data: numbers,
+    /// 'strings', raw 0x bytes, and opcode names with or without the OP_.
+    fn parse_asm(asm: &str) -> Script {
+        let mut raw = Vec::new();
+        for token in asm.split_whitespace() {
+            if let Some(bytes) = token.strip_prefix("0x") {
+                raw.extend(hex::decode(bytes).unwrap());
+            } else if let Some(string) = token.strip_prefix('\'') {
+                raw.extend(push_data(string.trim_end_matches('\'').as_bytes()));
+            } else if let Ok(n) = token.parse::<i64>() {
+                raw.extend(Script::new(&[Command::int(n)]).as_bytes());
+            } else {
+                let name = match token.trim_start_matches("OP_") {
+                    "NOP2" => "CHECKLOCKTIMEVERIFY",
+                    "NOP3" => "CHECKSEQUENCEVERIFY",
+                    name => name,
+                };
+                let index = OPCODE_NAMES.split_whitespace().position(|n| n == name);
+                raw.push(OP_1NEGATE + index.unwrap_or_else(|| panic!("unknown {token}")) as u8);
+            }
+        }
+        Script::from_bytes(raw)
+    }
+
+    fn parse_flags(names: &str) -> u32 {
+        names
+            .split(',')
+            .filter(|name| !name.is_empty())
+            .map(|name| match name {
+                "P2SH" => VERIFY_P2SH,
+                "STRICTENC" => VERIFY_STRICTENC,
+                "DERSIG" => VERIFY_DERSIG,
+                "LOW_S" => VERIFY_LOW_S,
+                "NULLDUMMY" => VERIFY_NULLDUMMY,
+                "SIGPUSHONLY" => VERIFY_SIGPUSHONLY,
+                "MINIMALDATA" => VERIFY_MINIMALDATA,
+                "DISCOURAGE_UPGRADABLE_NOPS" => VERIFY_DISCOURAGE_UPGRADABLE_NOPS,
+                "CLEANSTACK" => VERIFY_CLEANSTACK,
+                "CHECKLOCKTIMEVERIFY" => VERIFY_CHECKLOCKTIMEVERIFY,
+                "CHECKSEQUENCEVERIFY" => VERIFY_CHECKSEQUENCEVERIFY,
+                "WITNESS" => VERIFY_WITNESS,
+                "DISCOURAGE_UPGRADABLE_WITNESS_PROGRAM" => {
+                    VERIFY_DISCOURAGE_UPGRADABLE_WITNESS_PROGRAM
+                }
+                "MINIMALIF" => VERIFY_MINIMALIF,
+                "NULLFAIL" => VERIFY_NULLFAIL,
+                "WITNESS_PUBKEYTYPE" => VERIFY_WITNESS_PUBKEYTYPE,
+                "TAPROOT" => VERIFY_TAPROOT,
+                "DISCOURAGE_OP_SUCCESS" => VERIFY_DISCOURAGE_OP_SUCCESS,
+                _ => panic!("unknown flag {name}"),
+            })
+            .fold(VERIFY_NONE, |flags, flag| flags | flag)
+    }
+
+    /// Whether `result` is what Bitcoin Core reports as `expected`, the
+    /// name of a `ScriptError` in upper case with underscores. Core gives
+    /// UNKNOWN_ERROR for script numbers that are too long or not minimal.
+    fn is_expected(result: Result<(), ScriptError>, expected: &str) -> bool {
+        match (result, expected) {
+            (Ok(()), "OK") => true,
+            (Err(ScriptError::NumOverflow | ScriptError::MinimalData), "UNKNOWN_ERROR") => true,
+            (Err(error), _) => format!("{error:?}").to_uppercase() == expected.replace('_', ""),
+            _ => false,
+        }
+    }
+
+    /// Runs a test the way Bitcoin Core's script_tests does: `script_sig`
+    /// spends an output with `script_pubkey` created just for it.
+    fn run_script_test(
+        witness: &[&str],
+        amount: u64,
+        script_sig: &str,
+        script_pubkey: &str,
+        flags: &str,
+    ) -> Result<(), ScriptError> {
+        let script_pubkey = parse_asm(script_pubkey);
+        let mut credit = Tx::new(
+            1,
+            vec![TxIn::new(OutPoint::null(), u32::MAX)],
+            vec![TxOut::new(amount, script_pubkey.clone())],
+            0,
+        );
+        credit.tx_ins[0].script_sig = Script::new(&[Command::int(0), Command::int(0)]);
+        let mut tx = Tx::new(
+            1,
+            vec![TxIn::new(OutPoint::new(credit.txid(), 0), u32::MAX)],
+            vec![TxOut::new(amount, Script::new(&[]))],
+            0,
+        );
+        tx.tx_ins[0].script_sig = parse_asm(script_sig);
+        tx.tx_ins[0].witness = witness.iter().map(|w| hex::decode(w).unwrap()).collect();
+        let checker = TxChecker::with_spent_outputs(&tx, 0, &credit.tx_outs);
+        let tx_in = &tx.tx_ins[0];
+        verify_script(
+            &tx_in.script_sig,
+            &script_pubkey,
+            &tx_in.witness,
+            parse_flags(flags),
+            &checker,
+        )
+    }
+
+    #[test]
+    fn test_script_cases() {
+        // scriptSig, scriptPubKey, flags and expected result in the
+        // notation of Bitcoin Core's script_tests.json. A hand-picked set,
+        // not that file: run its full suite to check consensus agreement.
+        let tests = [
+            ("", "DEPTH 0 EQUAL", "P2SH,STRICTENC", "OK"),
+            ("1 2", "2 EQUALVERIFY 1 EQUAL", "P2SH,STRICTENC", "OK"),
+            ("0x01 0x0b", "11 EQUAL", "P2SH,STRICTENC", "OK"),
+            ("0x02 0x417a", "'Az' EQUAL", "P2SH,STRICTENC", "OK"),
+            ("0x4c 0x01 0x07", "7 EQUAL", "P2SH,STRICTENC", "OK"),
+            ("0x4d 0x0100 0x08", "8 EQUAL", "P2SH,STRICTENC", "OK"),
+            ("0x4e 0x01000000 0x09", "9 EQUAL", "P2SH,STRICTENC", "OK"),
+            ("0", "IF 0x50 ENDIF 1", "P2SH,STRICTENC", "OK"),
+            ("0x51", "0x5f ADD 0x60 EQUAL", "P2SH,STRICTENC", "OK"),
+            ("0", "IF VER ELSE 1 ENDIF", "P2SH,STRICTENC", "OK"),
+            ("0", "IF RESERVED RESERVED1 RESERVED2 ELSE 1 ENDIF", "P2SH,STRICTENC", "OK"),
+            ("1 0", "NOTIF IF 1 ELSE 0 ENDIF ENDIF", "P2SH,STRICTENC", "OK"),
+            ("0", "IF 0 ELSE 1 ELSE 0 ENDIF", "P2SH,STRICTENC", "OK"),
+            ("1", "IF 1 ELSE 0 ELSE ENDIF", "P2SH,STRICTENC", "OK"),
+            ("0", "IF RETURN ENDIF 1", "P2SH,STRICTENC", "OK"),
+            ("1 0x05 0x01 0x00 0x00 0x00 0x00", "VERIFY", "P2SH,STRICTENC", "OK"),
+            ("1 0x01 0x80", "IF 0 ENDIF", "P2SH,STRICTENC", "OK"),
+            ("10 0 11 TOALTSTACK DROP FROMALTSTACK", "ADD 21 EQUAL", "P2SH,STRICTENC", "OK"),
+            ("0x05 0x0100000000 IFDUP", "DEPTH 2 EQUALVERIFY 0x05 0x0100000000 EQUAL", "P2SH,STRICTENC", "OK"),
+            ("22 21 20", "2 ROLL 22 EQUALVERIFY DEPTH 2 EQUAL", "P2SH,STRICTENC", "OK"),
+            ("25 24 23 22 21 20", "2ROT 24 EQUAL", "P2SH,STRICTENC", "OK"),
+            ("-1 0 1 2", "3DUP DEPTH 7 EQUALVERIFY ADD ADD 3 EQUALVERIFY 2DROP 0 EQUALVERIFY", "P2SH,STRICTENC", "OK"),
+            ("1 2 3 5", "2OVER ADD ADD 8 EQUALVERIFY ADD ADD 6 EQUAL", "P2SH,STRICTENC", "OK"),
+            ("1 3 5 7", "2SWAP ADD 4 EQUALVERIFY ADD 12 EQUAL", "P2SH,STRICTENC", "OK"),
+            ("2147483648", "SIZE 5 EQUAL", "P2SH,STRICTENC", "OK"),
+            ("-128", "SIZE 2 EQUAL", "P2SH,STRICTENC", "OK"),
+            ("42", "SIZE 1 EQUALVERIFY 42 EQUAL", "P2SH,STRICTENC", "OK"),
+            ("2147483647 -2147483647 ADD", "0 EQUAL", "P2SH,STRICTENC", "OK"),
+            ("-16 ABS", "-16 NEGATE EQUAL", "P2SH,STRICTENC", "OK"),
+            ("-111 0NOTEQUAL", "1 EQUAL", "P2SH,STRICTENC", "OK"),
+            ("11 10 1 ADD", "NUMNOTEQUAL NOT", "P2SH,STRICTENC", "OK"),
+            ("-11 -10", "LESSTHAN", "P2SH,STRICTENC", "OK"),
+            ("0 -2147483647 2147483647", "WITHIN", "P2SH,STRICTENC", "OK"),
+            ("2147483647 DUP ADD", "4294967294 EQUAL", "P2SH,STRICTENC", "OK"),
+            ("''", "RIPEMD160 0x14 0x9c1185a5c5e9fc54612808977ee8f548b2258d31 EQUAL", "P2SH,STRICTENC", "OK"),
+            ("'a'", "SHA1 0x14 0x86f7e437faa5a7fce15d1ddcb9eaeaea377667b8 EQUAL", "P2SH,STRICTENC", "OK"),
+            ("'a'", "SHA256 0x20 0xca978112ca1bbdcafac231b39a23dc4da786eff8147c4e72b9807785afee48bb EQUAL", "P2SH,STRICTENC", "OK"),
+            ("'a'", "HASH160 NOP 0x14 0x994355199e516ff76c4fa4aab39337b9d84cf12b EQUAL", "P2SH,STRICTENC", "OK"),
+            ("''", "HASH256 0x20 0x5df6e0e2761359d30a8275058e299fcc0381534545f55cf43e41983f5d4c9456 EQUAL", "P2SH,STRICTENC", "OK"),
+            ("1", "NOP1 CHECKLOCKTIMEVERIFY CHECKSEQUENCEVERIFY NOP4 NOP5 NOP6 NOP7 NOP8 NOP9 NOP10 1 EQUAL", "P2SH,STRICTENC", "OK"),
+            ("0", "IF 0xba ELSE 1 ENDIF", "P2SH,STRICTENC", "OK"),
+            ("0x01 0x51", "HASH160 0x14 0xda1745e9b549bd0bfa1a569971c77eba30cd5a4b EQUAL", "P2SH,STRICTENC", "OK"),
+            ("", "DEPTH", "P2SH,STRICTENC", "EVAL_FALSE"),
+            ("", "", "P2SH,STRICTENC", "EVAL_FALSE"),
+            ("0x4c01", "0x01 NOP", "P2SH,STRICTENC", "BAD_OPCODE"),
+            ("0x4d0200ff", "0x01 NOP", "P2SH,STRICTENC", "BAD_OPCODE"),
+            ("1", "IF 0x50 ENDIF 1", "P2SH,STRICTENC", "BAD_OPCODE"),
+            ("1", "IF VER ELSE 1 ENDIF", "P2SH,STRICTENC", "BAD_OPCODE"),
+            ("0", "IF VERIF ELSE 1 ENDIF", "P2SH,STRICTENC", "BAD_OPCODE"),
+            ("1", "0xba", "P2SH,STRICTENC", "BAD_OPCODE"),
+            ("1 IF", "1 ENDIF", "P2SH,STRICTENC", "UNBALANCED_CONDITIONAL"),
+            ("", "IF", "P2SH,STRICTENC", "UNBALANCED_CONDITIONAL"),
+            ("", "ELSE ENDIF", "P2SH,STRICTENC", "UNBALANCED_CONDITIONAL"),
+            ("0", "IF 1 ELSE ENDIF", "P2SH,STRICTENC", "EVAL_FALSE"),
+            ("1", "RETURN 'data'", "P2SH,STRICTENC", "OP_RETURN"),
+            ("0", "VERIFY 1", "P2SH,STRICTENC", "VERIFY"),
+            ("1", "TOALTSTACK FROMALTSTACK FROMALTSTACK 1", "P2SH,STRICTENC", "INVALID_ALTSTACK_OPERATION"),
+            ("", "IFDUP DEPTH 0 EQUAL", "P2SH,STRICTENC", "INVALID_STACK_OPERATION"),
+            ("22 21 20", "3 PICK", "P2SH,STRICTENC", "INVALID_STACK_OPERATION"),
+            ("2147483648", "1ADD 1", "P2SH,STRICTENC", "UNKNOWN_ERROR"),
+            ("2147483647", "1ADD 1SUB 1", "P2SH,STRICTENC", "UNKNOWN_ERROR"),
+            ("'a' 'b' 0", "IF CAT ELSE 1 ENDIF", "P2SH,STRICTENC", "DISABLED_OPCODE"),
+            ("2 0", "IF 2MUL ELSE 1 ENDIF", "P2SH,STRICTENC", "DISABLED_OPCODE"),
+            ("1", "NOP10", "DISCOURAGE_UPGRADABLE_NOPS", "DISCOURAGE_UPGRADABLE_NOPS"),
+            ("0x01 0x81", "DROP 1", "MINIMALDATA", "MINIMALDATA"),
+            ("0x4c 0x00", "DROP 1", "MINIMALDATA", "MINIMALDATA"),
+            ("0x01 0x00", "NOT DROP 1", "MINIMALDATA", "UNKNOWN_ERROR"),
+            ("NOP 0x01 0x51", "HASH160 0x14 0xda1745e9b549bd0bfa1a569971c77eba30cd5a4b EQUAL", "P2SH", "SIG_PUSHONLY"),
+            ("NOP", "1", "SIGPUSHONLY", "SIG_PUSHONLY"),
+            ("1 1", "1", "P2SH,CLEANSTACK", "CLEANSTACK"),
+            ("-1", "CHECKLOCKTIMEVERIFY", "CHECKLOCKTIMEVERIFY", "NEGATIVE_LOCKTIME"),
+            ("1", "CHECKLOCKTIMEVERIFY", "CHECKLOCKTIMEVERIFY", "UNSATISFIED_LOCKTIME"),
+        ];
+        for (script_sig, script_pubkey, flags, expected) in tests {
+            let result = run_script_test(&[], 0, script_sig, script_pubkey, flags);
+            assert!(
+                is_expected(result, expected),
+                "{script_sig:?} {script_pubkey:?}: {result:?}, not {expected}"
+            );
+        }
+
+        // the same with a witness and the amount spent first
+        let p2wsh = "0 0x20 0x4ae81572f06e1b88fd5ced7a1a000945432e83e1551e6f721ee9c00b8cc33260";
+        let p2sh_p2wsh = "HASH160 0x14 0x72c44f957fc011d97e3406667dca5b1c930c4026 EQUAL";
+        let redeem_script =
+            "0x22 0x00204ae81572f06e1b88fd5ced7a1a000945432e83e1551e6f721ee9c00b8cc33260";
+        // a tapscript of OP_SUCCESS80 under the generator as internal key
+        let p2tr = "1 0x20 0xb24dd91099ea57f2f942a0177f1ab650eb5bec51083311ed9e1d115092ba81af";
+        let control_block = "c079be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+        let witness_tests: [(&[&str], _, _, _, _); 14] = [
+            (&["51"], "", p2wsh, "P2SH,WITNESS", "OK"),
+            (&["00", "51"], "", p2wsh, "P2SH,WITNESS", "CLEANSTACK"),
+            (
+                &["52"],
+                "",
+                p2wsh,
+                "P2SH,WITNESS",
+                "WITNESS_PROGRAM_MISMATCH",
+            ),
+            (
+                &[],
+                "",
+                p2wsh,
+                "P2SH,WITNESS",
+                "WITNESS_PROGRAM_WITNESS_EMPTY",
+            ),
+            (&["51"], "1", p2wsh, "P2SH,WITNESS", "WITNESS_MALLEATED"),
+            (&["51"], redeem_script, p2sh_p2wsh, "P2SH,WITNESS", "OK"),
+            (
+                &["51"],
+                "0x4c 0x22 0x00204ae81572f06e1b88fd5ced7a1a000945432e83e1551e6f721ee9c00b8cc33260",
+                p2sh_p2wsh,
+                "P2SH,WITNESS",
+                "WITNESS_MALLEATED_P2SH",
+            ),
+            (&["00"], "", "1", "P2SH,WITNESS", "WITNESS_UNEXPECTED"),
+            (
+                &["51"],
+                "",
+                "0 0x1f 0x4ae81572f06e1b88fd5ced7a1a000945432e83e1551e6f721ee9c00b8cc332",
+                "P2SH,WITNESS",
+                "WITNESS_PROGRAM_WRONG_LENGTH",
+            ),
+            (&["51"], "", "2 0x02 0x0001", "P2SH,WITNESS", "OK"),
+            (
+                &["51"],
+                "",
+                "2 0x02 0x0001",
+                "P2SH,WITNESS,DISCOURAGE_UPGRADABLE_WITNESS_PROGRAM",
+                "DISCOURAGE_UPGRADABLE_WITNESS_PROGRAM",
+            ),
+            (
+                &["01"],
+                "",
+                p2tr,
+                "P2SH,WITNESS,TAPROOT",
+                "SCHNORR_SIG_SIZE",
+            ),
+            (
+                &["50", control_block],
+                "",
+                p2tr,
+                "P2SH,WITNESS,TAPROOT",
+                "OK",
+            ),
+            (
+                &["50", control_block],
+                "",
+                p2tr,
+                "P2SH,WITNESS,TAPROOT,DISCOURAGE_OP_SUCCESS",
+                "DISCOURAGE_OP_SUCCESS",
+            ),
+        ];
+        for (witness, script_sig, script_pubkey, flags, expected) in witness_tests {
+            let result = run_script_test(witness, 1, script_sig, script_pubkey, flags);
+            assert!(
+                is_expected(result, expected),
+                "{witness:?} {script_pubkey:?}: {result:?}, not {expected}"
+            );
+        }
+    }
+
+    /// Verifies every input of `tx` against `prevouts`, given in the
+    /// notation of Bitcoin Core's tx_valid.json: txid in display order,
+    /// output index, script and amount.
+    fn verify_tx(
+        prevouts: &[(&str, u32, &str, u64)],
+        tx: &str,
+        flags: &str,
+    ) -> Result<(), ScriptError> {
+        let coins: HashMap<OutPoint, TxOut> = prevouts
+            .iter()
+            .map(|(txid, vout, script_pubkey, amount)| {
+                let mut txid: [u8; 32] = hex::decode(txid).unwrap().try_into().unwrap();
+                txid.reverse();
+                let tx_out = TxOut::new(*amount, parse_asm(script_pubkey));
+                (OutPoint::new(txid, *vout), tx_out)
+            })
+            .collect();
+        let tx = Tx::parse(&mut Cursor::new(hex::decode(tx).unwrap())).unwrap();
+        let spent_outputs: Vec<TxOut> = tx
+            .tx_ins
+            .iter()
+            .map(|tx_in| coins[&tx_in.prev_out].clone())
+            .collect();
+        for (i, tx_in) in tx.tx_ins.iter().enumerate() {
+            let checker = TxChecker::with_spent_outputs(&tx, i, &spent_outputs);
+            verify_script(
+                &tx_in.script_sig,
+                &spent_outputs[i].script_pubkey,
+                &tx_in.witness,
+                parse_flags(flags),
+                &checker,
+            )?;
+        }
+        Ok(())
+    }
+
+    const TX_452C629D: &str = "0100000001813f79011acb80925dfe69b3def355fe914bd1d96a3f5f71bf8303c6a989c7d1000000006b483045022100ed81ff192e75a3fd2304004dcadb746fa5e24c5031ccfcf21320b0277457c98f02207a986d955c6e0cb35d446a89d3f56100f4d7f67801c31967743a9c8e10615bed01210349fc4e631e3624a545de3f89f5d8684c7b8138bd94bdd531d2e213bf016b278afeffffff02a135ef01000000001976a914bc3b654dca7e56b04dca18f2566cdaf02e8d9ada88ac99c39800000000001976a9141c4bc762dd5423e332166702cb75f40df79fea1288ac19430600";
+    const PREVOUT_452C629D: (&str, u32, &str, u64) = (
+        "d1c789a9c60383bf715f3f6ad9d14b91fe55f3deb369fe5d9280cb1a01793f81",
+        0,
+        "DUP HASH160 0x14 0xa802fc56c704ce87c42d7c92eb75e7896bdc41ae EQUALVERIFY CHECKSIG",
+        0,
+    );
+    /// The signed BIP143 native P2WPKH example, spending a P2PK and a
+    /// P2WPKH output.
+    const TX_BIP143_P2WPKH: &str = "01000000000102fff7f7881a8099afa6940d42d1e7f6362bec38171ea3edf433541db4e4ad969f00000000494830450221008b9d1dc26ba6a9cb62127b02742fa9d754cd3bebf337f7a55d114c8e5cdd30be022040529b194ba3f9281a99f2b1c0a19c0489bc22ede944ccf4ecbab4cc618ef3ed01eeffffffef51e1b804cc89d182d279655c3aa89e815b1b309fe287d9b2b55d57b90ec68a0100000000ffffffff02202cb206000000001976a9148280b37df378db99f66f85c95a783a76ac7a6d5988ac9093510d000000001976a9143bde42dbee7e4dbe6a21b2d50ce2f0167faa815988ac000247304402203609e17b84f6a7d30c80bfa610b5b4542f32a8a0d5447a12fb1366d7f01cc44a0220573a954c4518331561406f90300e8f3358f51928d43c212a8caed02de67eebee0121025476c2e83188368da1ff3e292e7acafcdb3566bb0ad253f62fc70f07aeee635711000000";
+    const PREVOUTS_BIP143_P2WPKH: [(&str, u32, &str, u64); 2] = [
+        (
+            "9f96ade4b41d5433f4eda31e1738ec2b36f6e7d1420d94a6af99801a88f7f7ff",
+            0,
+            "0x21 0x03c9f4836b9a4f77fc0d81f7bcb01b7f1b35916864b9476c241ce9fc198bd25432 CHECKSIG",
+            625_000_000,
+        ),
+        (
+            "8ac60eb9575db5b2d987e29f301b5b819ea83a5c6579d282d189cc04b8e151ef",
+            1,
+            "0 0x14 0x1d0f172a0ecb48aee1be1f2687d2963ae33f71a1",
+            600_000_000,
+        ),
+    ];
+    /// The signed BIP143 P2SH-P2WPKH example.
+    const TX_BIP143_P2SH_P2WPKH: &str = "01000000000101db6b1b20aa0fd7b23880be2ecbd4a98130974cf4748fb66092ac4d3ceb1a5477010000001716001479091972186c449eb1ded22b78e40d009bdf0089feffffff02b8b4eb0b000000001976a914a457b684d7f0d539a46a45bbc043f35b59d0d96388ac0008af2f000000001976a914fd270b1ee6abcaea97fea7ad0402e8bd8ad6d77c88ac02473044022047ac8e878352d3ebbde1c94ce3a10d057c24175747116f8288e5d794d12d482f0220217f36a485cae903c713331d877c1f64677e3622ad4010726870540656fe9dcb012103ad1d8e89212f0b92c74d23bb710c00662ad1470198ac48c43f7d6f93a2a2687392040000";
+    const PREVOUT_BIP143_P2SH_P2WPKH: (&str, u32, &str, u64) = (
+        "77541aeb3c4dac9260b68f74f44c973081a9d4cb2ebe8038b2d70faa201b6bdb",
+        1,
+        "HASH160 0x14 0x4733f37cf4db86fbc2efed2500b4f4e49f312023 EQUAL",
+        1_000_000_000,
+    );
+
+    #[test]
+    fn test_signed_txs_valid() {
+        let flags = "P2SH,STRICTENC,DERSIG,LOW_S,NULLFAIL,WITNESS,WITNESS_PUBKEYTYPE";
+        assert_eq!(verify_tx(&[PREVOUT_452C629D], TX_452C629D, flags), Ok(()));
+        assert_eq!(
+            verify_tx(&PREVOUTS_BIP143_P2WPKH, TX_BIP143_P2WPKH, flags),
+            Ok(())
+        );
+        assert_eq!(
+            verify_tx(&[PREVOUT_BIP143_P2SH_P2WPKH], TX_BIP143_P2SH_P2WPKH, flags),
+            Ok(())
+        );
+        // 23b397ed..., a 1-of-2 CHECKMULTISIG whose
+        // signature's S is not strict DER, being negative
+        let prevout = (
+            "60a20bd93aa49ab4b28d514ec10b06e1829ce6818ec06cd3aabd013ebcdc4bb1",
+            0,
+            "1 0x41 0x04cc71eb30d653c0c3163990c47b976f3fb3f37cccdcbedb169a1dfef58bbfbfaff7d8a473e7e2e6d317b87bafe8bde97e3cf8f065dec022b51d11fcdd0d348ac4 0x41 0x0461cbdcc5409fb4b4d42b51d33381354d80e550078cb532a34bfa2fcfdeb7d76519aecc62770f5b0e4ef8551946d8a540911abe3e7854a26f39f58b25c15342af 2 CHECKMULTISIG",
+            0,
+        );
+        let tx = "0100000001b14bdcbc3e01bdaad36cc08e81e69c82e1060bc14e518db2b49aa43ad90ba26000000000490047304402203f16c6f40162ab686621ef3000b04e75418a0c0cb2d8aebeac894ae360ac1e780220ddc15ecdfc3507ac48e1681a33eb60996631bf6bf5bc0a0682c4db743ce7ca2b01ffffffff0140420f00000000001976a914660d4ef3a743e3e696ad990364e555c271ad504b88ac00000000";
+        assert_eq!(verify_tx(&[prevout], tx, "P2SH"), Ok(()));
+        assert_eq!(
+            verify_tx(&[prevout], tx, "P2SH,DERSIG"),
+            Err(ScriptError::SigDer)
+        );
+    }
+
+    #[test]
+    fn test_signed_txs_invalid() {
+        // 452c629d... with its signature's S negated, valid but high
+        let high_s = "0100000001813f79011acb80925dfe69b3def355fe914bd1d96a3f5f71bf8303c6a989c7d1000000006c493046022100ed81ff192e75a3fd2304004dcadb746fa5e24c5031ccfcf21320b0277457c98f0221008567926aa391f34ca2bb95762c0a9efdc5d6e66ead8586d44b97c1febfd4e55401210349fc4e631e3624a545de3f89f5d8684c7b8138bd94bdd531d2e213bf016b278afeffffff02a135ef01000000001976a914bc3b654dca7e56b04dca18f2566cdaf02e8d9ada88ac99c39800000000001976a9141c4bc762dd5423e332166702cb75f40df79fea1288ac19430600";
+        assert_eq!(verify_tx(&[PREVOUT_452C629D], high_s, "P2SH"), Ok(()));
+        assert_eq!(
+            verify_tx(&[PREVOUT_452C629D], high_s, "P2SH,LOW_S"),
+            Err(ScriptError::SigHighS)
+        );
+        // 452c629d... paying one satoshi less to its first output
+        let changed_output = TX_452C629D.replace("02a135ef01", "02a035ef01");
+        assert_eq!(
+            verify_tx(&[PREVOUT_452C629D], &changed_output, "P2SH"),
+            Err(ScriptError::EvalFalse)
+        );
+        // segwit signatures commit to the amount spent
+        let mut prevouts = PREVOUTS_BIP143_P2WPKH;
+        prevouts[1].3 += 1;
+        assert_eq!(
+            verify_tx(&prevouts, TX_BIP143_P2WPKH, "P2SH,WITNESS"),
+            Err(ScriptError::EvalFalse)
+        );
+        assert_eq!(
+            verify_tx(&prevouts, TX_BIP143_P2WPKH, "P2SH,WITNESS,NULLFAIL"),
+            Err(ScriptError::SigNullFail)
+        );
+        // the P2SH-P2WPKH redeem script pushed with OP_PUSHDATA1
+        let malleated = TX_BIP143_P2SH_P2WPKH.replace("0100000017160014", "01000000184c160014");
+        assert_eq!(
+            verify_tx(&[PREVOUT_BIP143_P2SH_P2WPKH], &malleated, "P2SH,WITNESS"),
+            Err(ScriptError::WitnessMalleatedP2sh)
+        );
+    }
+}
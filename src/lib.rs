@@ -0,0 +1,44 @@
+#![feature(test)]
+extern crate test;
+
+pub mod address;
+pub mod base58;
+pub mod bech32;
+pub mod bip32;
+pub mod block;
+pub mod block_files;
+pub mod block_filter;
+pub mod block_header;
+pub mod bloom;
+pub mod coin_selection;
+pub mod descriptor;
+pub mod fee;
+pub mod field_element;
+pub mod header_chain;
+pub mod interpreter;
+pub mod mempool;
+pub mod merkle;
+pub mod message;
+pub mod miner;
+pub mod miniscript;
+pub mod muhash;
+pub mod musig;
+pub mod network;
+pub mod peer;
+pub mod point;
+pub mod psbt;
+pub mod random;
+pub mod rfc6979;
+pub mod script;
+pub mod secp256k1;
+pub mod sighash;
+pub mod signature;
+pub mod spv;
+pub mod store;
+pub mod taproot;
+pub mod tx;
+pub mod tx_builder;
+pub mod utxo;
+pub mod validation;
+pub mod varint;
+pub mod wallet;
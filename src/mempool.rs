@@ -0,0 +1,962 @@
+use crate::{
+    block::Block,
+    fee::FeeRate,
+    header_chain::HeaderChain,
+    interpreter::*,
+    tx::{OutPoint, Tx, TxError, SEQUENCE_ENABLE_RBF},
+    utxo::Coin,
+    validation::{check_tx_inputs, script_flags, TxValidationError, UtxoView},
+};
+use std::{
+    cmp::Ordering,
+    collections::{HashMap, HashSet},
+    io,
+};
+
+/// Bitcoin Core's default `-limitancestorcount` and `-limitdescendantcount`.
+pub const DEFAULT_ANCESTOR_LIMIT: usize = 25;
+pub const DEFAULT_DESCENDANT_LIMIT: usize = 25;
+/// Bitcoin Core's default `-limitancestorsize` and `-limitdescendantsize`,
+/// in virtual bytes.
+pub const DEFAULT_ANCESTOR_SIZE_LIMIT: u64 = 101_000;
+pub const DEFAULT_DESCENDANT_SIZE_LIMIT: u64 = 101_000;
+/// Default total virtual size the pool is trimmed to.
+pub const DEFAULT_MAX_MEMPOOL_SIZE: u64 = 300_000_000;
+/// Most transactions a replacement may evict (BIP125 rule 5).
+pub const MAX_REPLACEMENT_CANDIDATES: usize = 100;
+
+/// Script checks relay adds to the consensus ones, as in Bitcoin Core's
+/// `STANDARD_SCRIPT_VERIFY_FLAGS`. Taproot is checked whatever the height,
+/// and spends relying on rules left for soft forks are not relayed.
+pub const STANDARD_VERIFY_FLAGS: u32 = VERIFY_STRICTENC
+    | VERIFY_LOW_S
+    | VERIFY_MINIMALDATA
+    | VERIFY_DISCOURAGE_UPGRADABLE_NOPS
+    | VERIFY_CLEANSTACK
+    | VERIFY_MINIMALIF
+    | VERIFY_NULLFAIL
+    | VERIFY_WITNESS_PUBKEYTYPE
+    | VERIFY_TAPROOT
+    | VERIFY_DISCOURAGE_UPGRADABLE_WITNESS_PROGRAM
+    | VERIFY_DISCOURAGE_UPGRADABLE_TAPROOT_VERSION
+    | VERIFY_DISCOURAGE_OP_SUCCESS
+    | VERIFY_DISCOURAGE_UPGRADABLE_PUBKEYTYPE;
+
+/// Policy limits of a `Mempool`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MempoolLimits {
+    /// Most in-pool ancestors of a transaction, counting itself.
+    pub ancestor_count: usize,
+    /// Most virtual size of a transaction and its in-pool ancestors.
+    pub ancestor_size: u64,
+    /// Most in-pool descendants of a transaction, counting itself.
+    pub descendant_count: usize,
+    pub descendant_size: u64,
+    /// Virtual size above which the lowest fee rate packages are evicted.
+    pub max_size: u64,
+    pub min_relay_fee: FeeRate,
+    /// What a replacement, or a transaction after an eviction, must pay on
+    /// top of what it displaces.
+    pub incremental_relay_fee: FeeRate,
+}
+
+impl Default for MempoolLimits {
+    fn default() -> Self {
+        Self {
+            ancestor_count: DEFAULT_ANCESTOR_LIMIT,
+            ancestor_size: DEFAULT_ANCESTOR_SIZE_LIMIT,
+            descendant_count: DEFAULT_DESCENDANT_LIMIT,
+            descendant_size: DEFAULT_DESCENDANT_SIZE_LIMIT,
+            max_size: DEFAULT_MAX_MEMPOOL_SIZE,
+            min_relay_fee: FeeRate::from_sat_per_kvb(1000),
+            incremental_relay_fee: FeeRate::from_sat_per_kvb(1000),
+        }
+    }
+}
+
+/// Why a transaction was not accepted. Transaction ids are of the in-pool
+/// transaction the rule was checked against.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MempoolError {
+    Tx(TxError),
+    Coinbase,
+    AlreadyInMempool,
+    Inputs(TxValidationError),
+    /// The fee is below the pool's minimum for the transaction's size.
+    FeeTooLow {
+        fee: u64,
+        required: u64,
+    },
+    TooManyAncestors,
+    AncestorsTooLarge,
+    TooManyDescendants([u8; 32]),
+    DescendantsTooLarge([u8; 32]),
+    /// A conflicting transaction does not signal replaceability (BIP125
+    /// rule 1).
+    NotReplaceable([u8; 32]),
+    /// The replacement spends an output of a transaction it replaces.
+    ReplacementSpendsConflicting([u8; 32]),
+    /// The input spends an in-pool output none of the replaced
+    /// transactions spent (BIP125 rule 2).
+    ReplacementAddsUnconfirmed(usize),
+    /// The fee does not cover the replaced transactions plus its own relay
+    /// (BIP125 rules 3 and 4).
+    ReplacementFeeTooLow {
+        fee: u64,
+        required: u64,
+    },
+    /// The fee rate is not above that of a directly conflicting
+    /// transaction.
+    ReplacementFeeRateTooLow([u8; 32]),
+    /// More than `MAX_REPLACEMENT_CANDIDATES` would be evicted (BIP125
+    /// rule 5).
+    TooManyReplacements(usize),
+    /// The transaction was evicted right away to keep the pool in size.
+    MempoolFull,
+}
+
+impl From<TxValidationError> for MempoolError {
+    fn from(e: TxValidationError) -> Self {
+        MempoolError::Inputs(e)
+    }
+}
+
+/// A pool transaction with the totals of its in-pool ancestors and
+/// descendants, both counting itself.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MempoolEntry {
+    pub tx: Tx,
+    pub txid: [u8; 32],
+    pub fee: u64,
+    pub vsize: u64,
+    pub ancestor_count: usize,
+    pub ancestor_size: u64,
+    pub ancestor_fees: u64,
+    pub descendant_count: usize,
+    pub descendant_size: u64,
+    pub descendant_fees: u64,
+    parents: HashSet<[u8; 32]>,
+    children: HashSet<[u8; 32]>,
+}
+
+impl MempoolEntry {
+    pub fn fee_rate(&self) -> FeeRate {
+        // entries have a size, and fees below MAX_MONEY
+        FeeRate::from_fee_and_vsize(self.fee, self.vsize).unwrap()
+    }
+
+    /// The rate of the package mining this transaction requires.
+    pub fn ancestor_fee_rate(&self) -> FeeRate {
+        FeeRate::from_fee_and_vsize(self.ancestor_fees, self.ancestor_size).unwrap()
+    }
+
+    pub fn descendant_fee_rate(&self) -> FeeRate {
+        FeeRate::from_fee_and_vsize(self.descendant_fees, self.descendant_size).unwrap()
+    }
+
+    /// In-pool transactions this one spends from.
+    pub fn get_parents(&self) -> &HashSet<[u8; 32]> {
+        &self.parents
+    }
+
+    pub fn get_children(&self) -> &HashSet<[u8; 32]> {
+        &self.children
+    }
+}
+
+/// Transactions picked for a block, parents first.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BlockTemplate {
+    pub txs: Vec<Tx>,
+    pub fees: u64,
+    pub weight: u64,
+}
+
+/// Coins of `base` plus the outputs of pool transactions, which count as
+/// confirmed in the next block.
+pub struct MempoolView<'a, V> {
+    mempool: &'a Mempool,
+    base: &'a V,
+    height: u32,
+}
+
+impl<'a, V: UtxoView> MempoolView<'a, V> {
+    pub fn new(mempool: &'a Mempool, base: &'a V, height: u32) -> Self {
+        Self {
+            mempool,
+            base,
+            height,
+        }
+    }
+}
+
+impl<V: UtxoView> UtxoView for MempoolView<'_, V> {
+    fn get_coin(&self, outpoint: &OutPoint) -> io::Result<Option<Coin>> {
+        match self.mempool.entries.get(&outpoint.txid) {
+            Some(entry) => Ok(entry
+                .tx
+                .tx_outs
+                .get(outpoint.vout as usize)
+                .map(|tx_out| Coin::new(tx_out.clone(), self.height, false))),
+            None => self.base.get_coin(outpoint),
+        }
+    }
+}
+
+/// Fees and virtual size of a transaction or package.
+type FeeAndSize = (u64, u64);
+
+/// Whether `a` pays a higher rate than `b`.
+fn higher_rate(a: FeeAndSize, b: FeeAndSize) -> bool {
+    a.0 as u128 * b.1 as u128 > b.0 as u128 * a.1 as u128
+}
+
+/// Unconfirmed transactions that could go in the next block, like Bitcoin
+/// Core's `CTxMemPool` with its pre-cluster package limits.
+///
+/// Evicting a package raises a minimum fee rate, so it is not readmitted
+/// right away. Core lets that floor decay over hours; here it is dropped
+/// once a block leaves the pool less than half full.
+#[derive(Debug, Clone, Default)]
+pub struct Mempool {
+    limits: MempoolLimits,
+    entries: HashMap<[u8; 32], MempoolEntry>,
+    /// The pool transaction spending each outpoint.
+    spenders: HashMap<OutPoint, [u8; 32]>,
+    total_size: u64,
+    rolling_min_fee: FeeRate,
+}
+
+impl Mempool {
+    pub fn new(limits: MempoolLimits) -> Self {
+        Self {
+            limits,
+            ..Self::default()
+        }
+    }
+
+    pub fn get_limits(&self) -> &MempoolLimits {
+        &self.limits
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn contains(&self, txid: &[u8; 32]) -> bool {
+        self.entries.contains_key(txid)
+    }
+
+    pub fn get(&self, txid: &[u8; 32]) -> Option<&MempoolEntry> {
+        self.entries.get(txid)
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = &MempoolEntry> {
+        self.entries.values()
+    }
+
+    /// The pool transaction spending `outpoint`.
+    pub fn get_spender(&self, outpoint: &OutPoint) -> Option<&[u8; 32]> {
+        self.spenders.get(outpoint)
+    }
+
+    /// Virtual size of all pool transactions.
+    pub fn total_size(&self) -> u64 {
+        self.total_size
+    }
+
+    /// The lowest fee rate a new transaction must pay.
+    pub fn min_fee(&self) -> FeeRate {
+        self.limits.min_relay_fee.max(self.rolling_min_fee)
+    }
+
+    /// Whether `txid` or one of its in-pool ancestors signals BIP125
+    /// replaceability.
+    pub fn signals_rbf(&self, txid: &[u8; 32]) -> bool {
+        let signals = |txid| {
+            self.entries[txid]
+                .tx
+                .tx_ins
+                .iter()
+                .any(|tx_in| tx_in.sequence <= SEQUENCE_ENABLE_RBF)
+        };
+        signals(txid)
+            || self
+                .ancestors(&self.entries[txid].parents)
+                .iter()
+                .any(signals)
+    }
+
+    /// `parents` and all their in-pool ancestors.
+    fn ancestors(&self, parents: &HashSet<[u8; 32]>) -> HashSet<[u8; 32]> {
+        let mut ret = HashSet::new();
+        let mut todo: Vec<_> = parents.iter().copied().collect();
+        while let Some(txid) = todo.pop() {
+            if ret.insert(txid) {
+                todo.extend(&self.entries[&txid].parents);
+            }
+        }
+        ret
+    }
+
+    /// `children` and all their in-pool descendants.
+    fn descendants(&self, children: &HashSet<[u8; 32]>) -> HashSet<[u8; 32]> {
+        let mut ret = HashSet::new();
+        let mut todo: Vec<_> = children.iter().copied().collect();
+        while let Some(txid) = todo.pop() {
+            if ret.insert(txid) {
+                todo.extend(&self.entries[&txid].children);
+            }
+        }
+        ret
+    }
+
+    /// Validates `tx` against `view`, the confirmed coins at `chain`'s tip,
+    /// and adds it, returning the ids of the transactions it replaced.
+    pub fn accept<V: UtxoView>(
+        &mut self,
+        tx: Tx,
+        view: &V,
+        chain: &HeaderChain,
+    ) -> Result<Vec<[u8; 32]>, MempoolError> {
+        tx.check().map_err(MempoolError::Tx)?;
+        if tx.is_coinbase() {
+            return Err(MempoolError::Coinbase);
+        }
+        let txid = tx.txid();
+        if self.entries.contains_key(&txid) {
+            return Err(MempoolError::AlreadyInMempool);
+        }
+        let mut conflicts = HashSet::new();
+        for tx_in in &tx.tx_ins {
+            if let Some(spender) = self.spenders.get(&tx_in.prev_out) {
+                conflicts.insert(*spender);
+            }
+        }
+        if let Some(conflict) = conflicts.iter().find(|txid| !self.signals_rbf(txid)) {
+            return Err(MempoolError::NotReplaceable(*conflict));
+        }
+
+        let height = chain.height() + 1;
+        let flags = script_flags(chain.get_params(), height) | STANDARD_VERIFY_FLAGS;
+        let fee = check_tx_inputs(
+            &tx,
+            &MempoolView::new(self, view, height),
+            chain,
+            height,
+            flags,
+        )?;
+        let vsize = tx.vsize();
+        // a fee too large for a u64 can't be paid
+        let required = self.min_fee().fee(vsize).unwrap_or(u64::MAX);
+        if fee < required {
+            return Err(MempoolError::FeeTooLow { fee, required });
+        }
+
+        let parents: HashSet<_> = tx
+            .tx_ins
+            .iter()
+            .map(|tx_in| tx_in.prev_out.txid)
+            .filter(|txid| self.entries.contains_key(txid))
+            .collect();
+        let ancestors = self.ancestors(&parents);
+        self.check_package_limits(&ancestors, vsize)?;
+
+        if !conflicts.is_empty() {
+            self.check_replacement(&tx, fee, vsize, &conflicts)?;
+        }
+        let mut replaced = self.descendants(&conflicts);
+        replaced.extend(&conflicts);
+        let replaced: Vec<_> = replaced.into_iter().collect();
+        self.remove_recursive(&replaced);
+
+        let mut entry = MempoolEntry {
+            tx,
+            txid,
+            fee,
+            vsize,
+            ancestor_count: ancestors.len() + 1,
+            ancestor_size: vsize,
+            ancestor_fees: fee,
+            descendant_count: 1,
+            descendant_size: vsize,
+            descendant_fees: fee,
+            parents,
+            children: HashSet::new(),
+        };
+        for ancestor in &ancestors {
+            let ancestor = self.entries.get_mut(ancestor).unwrap();
+            entry.ancestor_size += ancestor.vsize;
+            entry.ancestor_fees += ancestor.fee;
+            ancestor.descendant_count += 1;
+            ancestor.descendant_size += vsize;
+            ancestor.descendant_fees += fee;
+        }
+        for parent in &entry.parents {
+            self.entries.get_mut(parent).unwrap().children.insert(txid);
+        }
+        for tx_in in &entry.tx.tx_ins {
+            self.spenders.insert(tx_in.prev_out, txid);
+        }
+        self.total_size += vsize;
+        self.entries.insert(txid, entry);
+
+        self.trim_to_size();
+        if !self.entries.contains_key(&txid) {
+            return Err(MempoolError::MempoolFull);
+        }
+        Ok(replaced)
+    }
+
+    fn check_package_limits(
+        &self,
+        ancestors: &HashSet<[u8; 32]>,
+        vsize: u64,
+    ) -> Result<(), MempoolError> {
+        let limits = &self.limits;
+        if ancestors.len() + 1 > limits.ancestor_count {
+            return Err(MempoolError::TooManyAncestors);
+        }
+        let ancestor_size: u64 = ancestors.iter().map(|txid| self.entries[txid].vsize).sum();
+        if ancestor_size + vsize > limits.ancestor_size {
+            return Err(MempoolError::AncestorsTooLarge);
+        }
+        for txid in ancestors {
+            let ancestor = &self.entries[txid];
+            if ancestor.descendant_count + 1 > limits.descendant_count {
+                return Err(MempoolError::TooManyDescendants(*txid));
+            }
+            if ancestor.descendant_size + vsize > limits.descendant_size {
+                return Err(MempoolError::DescendantsTooLarge(*txid));
+            }
+        }
+        Ok(())
+    }
+
+    /// The BIP125 rules, with Bitcoin Core's additional requirement of a
+    /// higher fee rate than each direct conflict.
+    fn check_replacement(
+        &self,
+        tx: &Tx,
+        fee: u64,
+        vsize: u64,
+        conflicts: &HashSet<[u8; 32]>,
+    ) -> Result<(), MempoolError> {
+        let mut replaced = self.descendants(conflicts);
+        replaced.extend(conflicts);
+        if replaced.len() > MAX_REPLACEMENT_CANDIDATES {
+            return Err(MempoolError::TooManyReplacements(replaced.len()));
+        }
+        // the descendants of what is replaced are replaced too, so checking
+        // the parents covers all ancestors
+        if let Some(tx_in) = tx
+            .tx_ins
+            .iter()
+            .find(|tx_in| replaced.contains(&tx_in.prev_out.txid))
+        {
+            return Err(MempoolError::ReplacementSpendsConflicting(
+                tx_in.prev_out.txid,
+            ));
+        }
+        let conflict_parents: HashSet<_> = conflicts
+            .iter()
+            .flat_map(|txid| &self.entries[txid].parents)
+            .collect();
+        for (i, tx_in) in tx.tx_ins.iter().enumerate() {
+            let parent = &tx_in.prev_out.txid;
+            if self.entries.contains_key(parent) && !conflict_parents.contains(parent) {
+                return Err(MempoolError::ReplacementAddsUnconfirmed(i));
+            }
+        }
+        for txid in conflicts {
+            let conflict = &self.entries[txid];
+            if !higher_rate((fee, vsize), (conflict.fee, conflict.vsize)) {
+                return Err(MempoolError::ReplacementFeeRateTooLow(*txid));
+            }
+        }
+        let replaced_fees: u64 = replaced.iter().map(|txid| self.entries[txid].fee).sum();
+        let incremental_fee = self.limits.incremental_relay_fee.fee(vsize);
+        let required = replaced_fees.saturating_add(incremental_fee.unwrap_or(u64::MAX));
+        if fee < required {
+            return Err(MempoolError::ReplacementFeeTooLow { fee, required });
+        }
+        Ok(())
+    }
+
+    /// Removes `txid`, updating the totals of what remains. Descendants must
+    /// go first, or their ancestors keep counting them.
+    fn remove_entry(&mut self, txid: &[u8; 32]) -> MempoolEntry {
+        let entry = self.entries.remove(txid).unwrap();
+        for ancestor in self.ancestors(&entry.parents) {
+            let ancestor = self.entries.get_mut(&ancestor).unwrap();
+            ancestor.descendant_count -= 1;
+            ancestor.descendant_size -= entry.vsize;
+            ancestor.descendant_fees -= entry.fee;
+        }
+        for descendant in self.descendants(&entry.children) {
+            let descendant = self.entries.get_mut(&descendant).unwrap();
+            descendant.ancestor_count -= 1;
+            descendant.ancestor_size -= entry.vsize;
+            descendant.ancestor_fees -= entry.fee;
+        }
+        for parent in &entry.parents {
+            self.entries.get_mut(parent).unwrap().children.remove(txid);
+        }
+        for child in &entry.children {
+            self.entries.get_mut(child).unwrap().parents.remove(txid);
+        }
+        for tx_in in &entry.tx.tx_ins {
+            self.spenders.remove(&tx_in.prev_out);
+        }
+        self.total_size -= entry.vsize;
+        entry
+    }
+
+    /// Removes `txids`, which must include all their descendants.
+    fn remove_recursive(&mut self, txids: &[[u8; 32]]) {
+        let mut txids = txids.to_vec();
+        // a descendant has more ancestors than any of its ancestors
+        txids.sort_by_key(|txid| std::cmp::Reverse(self.entries[txid].ancestor_count));
+        for txid in &txids {
+            self.remove_entry(txid);
+        }
+    }
+
+    /// Removes `txid` and its descendants, returning how many were removed.
+    pub fn remove(&mut self, txid: &[u8; 32]) -> usize {
+        let Some(entry) = self.entries.get(txid) else {
+            return 0;
+        };
+        let mut txids: Vec<_> = self.descendants(&entry.children).into_iter().collect();
+        txids.push(*txid);
+        self.remove_recursive(&txids);
+        txids.len()
+    }
+
+    /// Removes the transactions `block` confirmed, and those conflicting
+    /// with it along with their descendants.
+    pub fn remove_for_block(&mut self, block: &Block) {
+        for tx in block.txs.iter().skip(1) {
+            let txid = tx.txid();
+            // parents come first in a block, so a confirmed transaction has
+            // no pool ancestors left
+            if self.entries.contains_key(&txid) {
+                self.remove_entry(&txid);
+            }
+            for tx_in in &tx.tx_ins {
+                if let Some(spender) = self.spenders.get(&tx_in.prev_out).copied() {
+                    self.remove(&spender);
+                }
+            }
+        }
+        if self.total_size < self.limits.max_size / 2 {
+            self.rolling_min_fee = FeeRate::default();
+        }
+    }
+
+    /// Evicts the packages with the lowest descendant fee rates until the
+    /// pool fits in `max_size`, raising the minimum fee above them.
+    fn trim_to_size(&mut self) {
+        while self.total_size > self.limits.max_size {
+            // the better of an entry's own rate and its package's, so a
+            // child paying for its parent protects it
+            let score = |entry: &MempoolEntry| {
+                if higher_rate(
+                    (entry.fee, entry.vsize),
+                    (entry.descendant_fees, entry.descendant_size),
+                ) {
+                    (entry.fee, entry.vsize)
+                } else {
+                    (entry.descendant_fees, entry.descendant_size)
+                }
+            };
+            let worst = self
+                .entries
+                .values()
+                .min_by(|a, b| {
+                    if higher_rate(score(a), score(b)) {
+                        Ordering::Greater
+                    } else if higher_rate(score(b), score(a)) {
+                        Ordering::Less
+                    } else {
+                        a.txid.cmp(&b.txid)
+                    }
+                })
+                .unwrap();
+            let removed = worst.descendant_fee_rate().get_sat_per_kvb()
+                + self.limits.incremental_relay_fee.get_sat_per_kvb();
+            self.rolling_min_fee = self.rolling_min_fee.max(FeeRate::from_sat_per_kvb(removed));
+            let txid = worst.txid;
+            self.remove(&txid);
+        }
+    }
+
+    /// Picks transactions for a block of at most `max_weight`, not counting
+    /// the coinbase, by Bitcoin Core's ancestor fee rate selection: the
+    /// unmined transaction whose package of unmined ancestors pays the
+    /// highest rate goes in next, with that package.
+    pub fn block_template(&self, max_weight: u64) -> BlockTemplate {
+        let mut ret = BlockTemplate::default();
+        let mut in_block = HashSet::new();
+        let mut failed = HashSet::new();
+        loop {
+            let mut best: Option<(Vec<[u8; 32]>, FeeAndSize)> = None;
+            for (txid, entry) in &self.entries {
+                if in_block.contains(txid) || failed.contains(txid) {
+                    continue;
+                }
+                let mut package: Vec<_> = self
+                    .ancestors(&entry.parents)
+                    .into_iter()
+                    .filter(|txid| !in_block.contains(txid))
+                    .collect();
+                package.push(*txid);
+                let rate = package.iter().fold((0, 0), |(fees, size), txid| {
+                    let entry = &self.entries[txid];
+                    (fees + entry.fee, size + entry.vsize)
+                });
+                let better = match &best {
+                    None => true,
+                    Some((best_package, best_rate)) => {
+                        higher_rate(rate, *best_rate)
+                            || (!higher_rate(*best_rate, rate)
+                                && *txid < *best_package.last().unwrap())
+                    }
+                };
+                if better {
+                    best = Some((package, rate));
+                }
+            }
+            let Some((mut package, rate)) = best else {
+                break;
+            };
+            let weight: u64 = package
+                .iter()
+                .map(|txid| self.entries[txid].tx.weight())
+                .sum();
+            if ret.weight + weight > max_weight {
+                failed.insert(*package.last().unwrap());
+                continue;
+            }
+            package.sort_by_key(|txid| self.entries[txid].ancestor_count);
+            for txid in package {
+                ret.txs.push(self.entries[&txid].tx.clone());
+                in_block.insert(txid);
+            }
+            ret.fees += rate.0;
+            ret.weight += weight;
+        }
+        ret
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        block_header::BlockHeader,
+        header_chain::ChainParams,
+        network::Network,
+        script::{Command, Script},
+        tx::{TxIn, TxOut, SEQUENCE_FINAL},
+    };
+
+    fn anyone() -> Script {
+        Script::new(&[Command::int(1)])
+    }
+
+    /// Regtest genesis and ten confirmed coins of 100000 satoshis, each
+    /// spendable by anyone.
+    fn setup() -> (HeaderChain, HashMap<OutPoint, Coin>) {
+        let chain = HeaderChain::new(ChainParams::new(Network::Regtest));
+        let view = (0..10)
+            .map(|i| {
+                let coin = Coin::new(TxOut::new(100_000, anyone()), 0, false);
+                (OutPoint::new([i; 32], 0), coin)
+            })
+            .collect();
+        (chain, view)
+    }
+
+    /// A 61 vbyte transaction.
+    fn spend(prev_out: OutPoint, amount: u64, sequence: u32) -> Tx {
+        Tx::new(
+            2,
+            vec![TxIn::new(prev_out, sequence)],
+            vec![TxOut::new(amount, anyone())],
+            0,
+        )
+    }
+
+    fn confirmed(i: u8) -> OutPoint {
+        OutPoint::new([i; 32], 0)
+    }
+
+    fn output(tx: &Tx) -> OutPoint {
+        OutPoint::new(tx.txid(), 0)
+    }
+
+    #[test]
+    fn test_accept() {
+        let (chain, view) = setup();
+        let limits = MempoolLimits {
+            ancestor_count: 3,
+            ..MempoolLimits::default()
+        };
+        let mut mempool = Mempool::new(limits);
+        let a = spend(confirmed(0), 99_000, SEQUENCE_FINAL);
+        let b = spend(output(&a), 98_000, SEQUENCE_FINAL);
+        let c = spend(output(&b), 96_000, SEQUENCE_FINAL);
+        for tx in [&a, &b, &c] {
+            assert_eq!(mempool.accept(tx.clone(), &view, &chain), Ok(vec![]));
+        }
+        assert_eq!(mempool.len(), 3);
+        assert_eq!(mempool.total_size(), 183);
+        let entry = mempool.get(&b.txid()).unwrap();
+        assert_eq!(entry.vsize, 61);
+        assert_eq!((entry.ancestor_count, entry.ancestor_fees), (2, 2000));
+        assert_eq!((entry.descendant_count, entry.descendant_fees), (2, 3000));
+        assert_eq!(entry.get_parents(), &HashSet::from([a.txid()]));
+        let entry = mempool.get(&a.txid()).unwrap();
+        assert_eq!((entry.descendant_count, entry.descendant_size), (3, 183));
+        assert_eq!(mempool.get_spender(&confirmed(0)), Some(&a.txid()));
+
+        assert_eq!(
+            mempool.accept(a.clone(), &view, &chain),
+            Err(MempoolError::AlreadyInMempool)
+        );
+        let d = spend(output(&c), 95_000, SEQUENCE_FINAL);
+        assert_eq!(
+            mempool.accept(d, &view, &chain),
+            Err(MempoolError::TooManyAncestors)
+        );
+        assert_eq!(
+            mempool.accept(spend(output(&c), 97_000, SEQUENCE_FINAL), &view, &chain),
+            Err(MempoolError::Inputs(
+                TxValidationError::InputsBelowOutputs {
+                    input_value: 96_000,
+                    output_value: 97_000
+                }
+            ))
+        );
+        assert_eq!(
+            mempool.accept(spend(confirmed(10), 0, SEQUENCE_FINAL), &view, &chain),
+            Err(MempoolError::Inputs(TxValidationError::MissingInput(0)))
+        );
+        assert_eq!(
+            mempool.accept(spend(confirmed(1), 99_990, SEQUENCE_FINAL), &view, &chain),
+            Err(MempoolError::FeeTooLow {
+                fee: 10,
+                required: 61
+            })
+        );
+
+        assert_eq!(mempool.remove(&b.txid()), 2);
+        assert_eq!(mempool.len(), 1);
+        let entry = mempool.get(&a.txid()).unwrap();
+        assert_eq!((entry.descendant_count, entry.descendant_fees), (1, 1000));
+        assert!(entry.get_children().is_empty());
+        assert_eq!(mempool.get_spender(&output(&a)), None);
+    }
+
+    #[test]
+    fn test_script_policy() {
+        let (chain, mut view) = setup();
+        let mut mempool = Mempool::new(MempoolLimits::default());
+        let generator_x =
+            hex::decode("79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798")
+                .unwrap();
+        for (i, script_pubkey) in [
+            Script::p2tr(&generator_x.clone().try_into().unwrap()),
+            Script::witness_program(2, &generator_x),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            let coin = Coin::new(TxOut::new(100_000, script_pubkey), 0, false);
+            view.insert(OutPoint::new([20 + i as u8; 32], 0), coin);
+        }
+
+        // a taproot key path spend with a garbage signature
+        let mut tx = spend(OutPoint::new([20; 32], 0), 99_000, SEQUENCE_FINAL);
+        tx.tx_ins[0].witness = vec![vec![1; 64]];
+        assert_eq!(
+            mempool.accept(tx, &view, &chain),
+            Err(MempoolError::Inputs(TxValidationError::Script {
+                input: 0,
+                error: ScriptError::SchnorrSig
+            }))
+        );
+        // witness versions after taproot are valid, but not relayed
+        let mut tx = spend(OutPoint::new([21; 32], 0), 99_000, SEQUENCE_FINAL);
+        tx.tx_ins[0].witness = vec![vec![1; 64]];
+        assert_eq!(
+            mempool.accept(tx, &view, &chain),
+            Err(MempoolError::Inputs(TxValidationError::Script {
+                input: 0,
+                error: ScriptError::DiscourageUpgradableWitnessProgram
+            }))
+        );
+        assert!(mempool.is_empty());
+    }
+
+    #[test]
+    fn test_replacement() {
+        let (chain, view) = setup();
+        let mut mempool = Mempool::new(MempoolLimits::default());
+        let a = spend(confirmed(0), 90_000, SEQUENCE_ENABLE_RBF);
+        let b = spend(output(&a), 80_000, SEQUENCE_FINAL);
+        let x = spend(confirmed(1), 90_000, SEQUENCE_FINAL);
+        for tx in [&a, &b, &x] {
+            mempool.accept(tx.clone(), &view, &chain).unwrap();
+        }
+        // b signals through its parent
+        assert!(mempool.signals_rbf(&b.txid()));
+        assert!(!mempool.signals_rbf(&x.txid()));
+        assert_eq!(
+            mempool.accept(spend(confirmed(1), 50_000, SEQUENCE_FINAL), &view, &chain),
+            Err(MempoolError::NotReplaceable(x.txid()))
+        );
+
+        // a and b paid 20000, and 61 more pays for the replacement itself
+        assert_eq!(
+            mempool.accept(spend(confirmed(0), 79_990, SEQUENCE_FINAL), &view, &chain),
+            Err(MempoolError::ReplacementFeeTooLow {
+                fee: 20_010,
+                required: 20_061
+            })
+        );
+        let mut adds_unconfirmed = spend(confirmed(0), 10_000, SEQUENCE_FINAL);
+        adds_unconfirmed
+            .tx_ins
+            .push(TxIn::new(output(&x), SEQUENCE_FINAL));
+        assert_eq!(
+            mempool.accept(adds_unconfirmed, &view, &chain),
+            Err(MempoolError::ReplacementAddsUnconfirmed(1))
+        );
+        let mut spends_conflicting = spend(confirmed(2), 10_000, SEQUENCE_FINAL);
+        spends_conflicting
+            .tx_ins
+            .push(TxIn::new(output(&b), SEQUENCE_FINAL));
+        spends_conflicting
+            .tx_ins
+            .push(TxIn::new(confirmed(0), SEQUENCE_FINAL));
+        assert_eq!(
+            mempool.accept(spends_conflicting, &view, &chain),
+            Err(MempoolError::ReplacementSpendsConflicting(b.txid()))
+        );
+
+        let replacement = spend(confirmed(0), 70_000, SEQUENCE_FINAL);
+        let mut replaced = mempool.accept(replacement.clone(), &view, &chain).unwrap();
+        replaced.sort();
+        let mut expected = vec![a.txid(), b.txid()];
+        expected.sort();
+        assert_eq!(replaced, expected);
+        assert_eq!(mempool.len(), 2);
+        assert_eq!(
+            mempool.get_spender(&confirmed(0)),
+            Some(&replacement.txid())
+        );
+        assert_eq!(mempool.total_size(), 122);
+    }
+
+    #[test]
+    fn test_eviction() {
+        let (chain, view) = setup();
+        let limits = MempoolLimits {
+            max_size: 183,
+            ..MempoolLimits::default()
+        };
+        let mut mempool = Mempool::new(limits);
+        // 10, 20 and 30 sat/vB
+        let txs: Vec<_> = (0..3)
+            .map(|i| spend(confirmed(i), 100_000 - 610 * (i as u64 + 1), SEQUENCE_FINAL))
+            .collect();
+        for tx in &txs {
+            mempool.accept(tx.clone(), &view, &chain).unwrap();
+        }
+        assert_eq!(
+            mempool.accept(
+                spend(confirmed(3), 100_000 - 305, SEQUENCE_FINAL),
+                &view,
+                &chain
+            ),
+            Err(MempoolError::MempoolFull)
+        );
+        assert_eq!(mempool.len(), 3);
+        assert_eq!(mempool.min_fee(), FeeRate::from_sat_per_vb(6).unwrap());
+        mempool
+            .accept(
+                spend(confirmed(4), 100_000 - 2440, SEQUENCE_FINAL),
+                &view,
+                &chain,
+            )
+            .unwrap();
+        assert!(!mempool.contains(&txs[0].txid()));
+        assert_eq!(mempool.min_fee(), FeeRate::from_sat_per_vb(11).unwrap());
+        assert_eq!(
+            mempool.accept(
+                spend(confirmed(5), 100_000 - 610, SEQUENCE_FINAL),
+                &view,
+                &chain
+            ),
+            Err(MempoolError::FeeTooLow {
+                fee: 610,
+                required: 671
+            })
+        );
+
+        // a block leaving the pool less than half full resets the floor
+        let mut coinbase_in = TxIn::new(OutPoint::null(), SEQUENCE_FINAL);
+        coinbase_in.script_sig = Script::new(&[Command::int(1)]);
+        let coinbase = Tx::new(1, vec![coinbase_in], vec![], 0);
+        let header = BlockHeader::new(4, chain.tip().hash(), [0; 32], 0, 0x207fffff, 0);
+        let block = Block::new(header, vec![coinbase, txs[1].clone(), txs[2].clone()]);
+        mempool.remove_for_block(&block);
+        assert_eq!(mempool.len(), 1);
+        assert_eq!(mempool.min_fee(), FeeRate::from_sat_per_vb(1).unwrap());
+    }
+
+    #[test]
+    fn test_block_template() {
+        let (chain, view) = setup();
+        let mut mempool = Mempool::new(MempoolLimits::default());
+        // a 1 sat/vB parent whose child pays 100 sat/vB, and a 20 sat/vB
+        // transaction on its own
+        let parent = spend(confirmed(0), 100_000 - 61, SEQUENCE_FINAL);
+        let child = spend(output(&parent), 100_000 - 61 - 6100, SEQUENCE_FINAL);
+        let single = spend(confirmed(1), 100_000 - 1220, SEQUENCE_FINAL);
+        for tx in [&single, &parent, &child] {
+            mempool.accept(tx.clone(), &view, &chain).unwrap();
+        }
+        assert_eq!(
+            mempool.get(&child.txid()).unwrap().ancestor_fee_rate(),
+            FeeRate::from_sat_per_kvb(50_500)
+        );
+        let template = mempool.block_template(4_000_000);
+        assert_eq!(
+            template.txs,
+            vec![parent.clone(), child.clone(), single.clone()]
+        );
+        assert_eq!(template.fees, 61 + 6100 + 1220);
+        assert_eq!(template.weight, 3 * 244);
+        // the package does not fit, but the single transaction does
+        let template = mempool.block_template(300);
+        assert_eq!(template.txs, vec![single.clone()]);
+
+        // a block spending the parent's input evicts the package
+        let mut coinbase_in = TxIn::new(OutPoint::null(), SEQUENCE_FINAL);
+        coinbase_in.script_sig = Script::new(&[Command::int(1)]);
+        let coinbase = Tx::new(1, vec![coinbase_in], vec![], 0);
+        let double_spend = spend(confirmed(0), 1000, SEQUENCE_FINAL);
+        let header = BlockHeader::new(4, chain.tip().hash(), [0; 32], 0, 0x207fffff, 0);
+        mempool.remove_for_block(&Block::new(header, vec![coinbase, double_spend]));
+        assert_eq!(mempool.len(), 1);
+        assert!(mempool.contains(&single.txid()));
+        assert_eq!(mempool.total_size(), 61);
+    }
+}
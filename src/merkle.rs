@@ -0,0 +1,400 @@
+use crate::{
+    base58::hash256,
+    block::MAX_BLOCK_WEIGHT,
+    block_header::BlockHeader,
+    script::{Command, Script, OP_RETURN},
+    tx::Tx,
+    varint::{encode_varint, read_varint},
+};
+use std::io::{self, Read};
+
+/// Prefix of the coinbase output committing to the witness merkle root
+/// (BIP141).
+pub const WITNESS_COMMITMENT_HEADER: [u8; 4] = [0xaa, 0x21, 0xa9, 0xed];
+
+/// Upper bound on the transactions in a block: the weight limit over the
+/// weight of the smallest transaction.
+const MAX_TRANSACTIONS: u32 = (MAX_BLOCK_WEIGHT / 240) as u32;
+
+/// A txid and its position in the block.
+pub type IndexedTxid = (u32, [u8; 32]);
+
+pub fn merkle_parent(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+    hash256(&[&left[..], &right[..]].concat())
+        .try_into()
+        .unwrap()
+}
+
+/// Merkle root of `hashes`, duplicating the last hash of odd levels.
+/// An empty list gives the zero hash.
+pub fn merkle_root(hashes: &[[u8; 32]]) -> [u8; 32] {
+    merkle_root_mutated(hashes).0
+}
+
+/// Merkle root of `hashes` and whether the list is a CVE-2012-2459
+/// mutation, i.e. some level has two equal siblings. Such a list has the
+/// same root as a shorter one, so a block with it is invalid but must not
+/// mark the header as invalid.
+pub fn merkle_root_mutated(hashes: &[[u8; 32]]) -> ([u8; 32], bool) {
+    if hashes.is_empty() {
+        return ([0; 32], false);
+    }
+    let mut level = hashes.to_vec();
+    let mut mutated = false;
+    while level.len() > 1 {
+        mutated |= level
+            .chunks(2)
+            .any(|pair| pair.len() == 2 && pair[0] == pair[1]);
+        if level.len() % 2 == 1 {
+            level.push(*level.last().unwrap());
+        }
+        level = level
+            .chunks(2)
+            .map(|pair| merkle_parent(&pair[0], &pair[1]))
+            .collect();
+    }
+    (level[0], mutated)
+}
+
+/// Merkle root of the wtxids, with the coinbase's replaced by zeros.
+pub fn witness_merkle_root(txs: &[Tx]) -> [u8; 32] {
+    let wtxids: Vec<[u8; 32]> = txs
+        .iter()
+        .enumerate()
+        .map(|(i, tx)| if i == 0 { [0; 32] } else { tx.wtxid() })
+        .collect();
+    merkle_root(&wtxids)
+}
+
+/// The commitment to `witness_root` placed in the coinbase, where
+/// `witness_reserved_value` is the coinbase input's single witness item.
+pub fn witness_commitment(witness_root: &[u8; 32], witness_reserved_value: &[u8; 32]) -> [u8; 32] {
+    merkle_parent(witness_root, witness_reserved_value)
+}
+
+/// The coinbase output script carrying a witness commitment.
+pub fn witness_commitment_script(commitment: &[u8; 32]) -> Script {
+    let data = [&WITNESS_COMMITMENT_HEADER[..], &commitment[..]].concat();
+    Script::new(&[Command::Op(OP_RETURN), Command::Data(data)])
+}
+
+/// The subset of a block's merkle tree proving that some transactions are
+/// in it, as used by `merkleblock` messages and `gettxoutproof`.
+///
+/// Flags are read depth first: a set flag on an inner node means it is the
+/// ancestor of a matched txid and its children follow, otherwise its hash is
+/// the next one in `hashes`. At the leaves, a set flag marks a match.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PartialMerkleTree {
+    pub total: u32,
+    pub hashes: Vec<[u8; 32]>,
+    pub flags: Vec<bool>,
+}
+
+impl PartialMerkleTree {
+    /// Builds the proof for the txids whose entry in `matches` is set.
+    /// `None` without txids, or without one match flag per txid.
+    pub fn new(txids: &[[u8; 32]], matches: &[bool]) -> Option<Self> {
+        if txids.is_empty() || txids.len() != matches.len() {
+            return None;
+        }
+        let mut tree = Self {
+            total: txids.len() as u32,
+            hashes: Vec::new(),
+            flags: Vec::new(),
+        };
+        tree.build(tree.height(), 0, txids, matches);
+        Some(tree)
+    }
+
+    /// Number of nodes at `height` above the leaves.
+    fn width(&self, height: u32) -> u32 {
+        (self.total + (1 << height) - 1) >> height
+    }
+
+    fn height(&self) -> u32 {
+        let mut height = 0;
+        while self.width(height) > 1 {
+            height += 1;
+        }
+        height
+    }
+
+    fn node_hash(&self, height: u32, pos: u32, txids: &[[u8; 32]]) -> [u8; 32] {
+        if height == 0 {
+            return txids[pos as usize];
+        }
+        let left = self.node_hash(height - 1, pos * 2, txids);
+        let right = if pos * 2 + 1 < self.width(height - 1) {
+            self.node_hash(height - 1, pos * 2 + 1, txids)
+        } else {
+            left
+        };
+        merkle_parent(&left, &right)
+    }
+
+    fn build(&mut self, height: u32, pos: u32, txids: &[[u8; 32]], matches: &[bool]) {
+        let start = (pos << height) as usize;
+        let end = (((pos + 1) << height) as usize).min(txids.len());
+        let parent_of_match = matches[start..end].iter().any(|m| *m);
+        self.flags.push(parent_of_match);
+        if height == 0 || !parent_of_match {
+            let hash = self.node_hash(height, pos, txids);
+            self.hashes.push(hash);
+        } else {
+            self.build(height - 1, pos * 2, txids, matches);
+            if pos * 2 + 1 < self.width(height - 1) {
+                self.build(height - 1, pos * 2 + 1, txids, matches);
+            }
+        }
+    }
+
+    /// The merkle root and the matched txids with their positions in the
+    /// block, or None if the tree is malformed.
+    pub fn extract_matches(&self) -> Option<([u8; 32], Vec<IndexedTxid>)> {
+        if self.total == 0
+            || self.total > MAX_TRANSACTIONS
+            || self.hashes.len() > self.total as usize
+            || self.flags.len() < self.hashes.len()
+        {
+            return None;
+        }
+        let mut matches = Vec::new();
+        let (mut flags_used, mut hashes_used) = (0, 0);
+        let root = self.extract(
+            self.height(),
+            0,
+            &mut flags_used,
+            &mut hashes_used,
+            &mut matches,
+        )?;
+        // everything must be used, except the padding of the last flag byte
+        if flags_used.div_ceil(8) != self.flags.len().div_ceil(8)
+            || hashes_used != self.hashes.len()
+        {
+            return None;
+        }
+        Some((root, matches))
+    }
+
+    fn extract(
+        &self,
+        height: u32,
+        pos: u32,
+        flags_used: &mut usize,
+        hashes_used: &mut usize,
+        matches: &mut Vec<IndexedTxid>,
+    ) -> Option<[u8; 32]> {
+        let parent_of_match = *self.flags.get(*flags_used)?;
+        *flags_used += 1;
+        if height == 0 || !parent_of_match {
+            let hash = *self.hashes.get(*hashes_used)?;
+            *hashes_used += 1;
+            if height == 0 && parent_of_match {
+                matches.push((pos, hash));
+            }
+            return Some(hash);
+        }
+        let left = self.extract(height - 1, pos * 2, flags_used, hashes_used, matches)?;
+        let right = if pos * 2 + 1 < self.width(height - 1) {
+            let right = self.extract(height - 1, pos * 2 + 1, flags_used, hashes_used, matches)?;
+            // equal siblings would allow the CVE-2012-2459 mutation
+            if right == left {
+                return None;
+            }
+            right
+        } else {
+            left
+        };
+        Some(merkle_parent(&left, &right))
+    }
+
+    pub fn parse<R: Read>(stream: &mut R) -> io::Result<Self> {
+        let mut total = [0u8; 4];
+        stream.read_exact(&mut total)?;
+        let count = read_varint(stream)?;
+        let mut hashes = Vec::new();
+        for _ in 0..count {
+            let mut hash = [0u8; 32];
+            stream.read_exact(&mut hash)?;
+            hashes.push(hash);
+        }
+        let mut flag_bytes = Vec::new();
+        let length = read_varint(stream)?;
+        stream.take(length).read_to_end(&mut flag_bytes)?;
+        if flag_bytes.len() as u64 != length {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let flags = flag_bytes
+            .iter()
+            .flat_map(|byte| (0..8).map(move |i| byte >> i & 1 == 1))
+            .collect();
+        Ok(Self {
+            total: u32::from_le_bytes(total),
+            hashes,
+            flags,
+        })
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut ret = self.total.to_le_bytes().to_vec();
+        ret.extend(encode_varint(self.hashes.len() as u64));
+        for hash in &self.hashes {
+            ret.extend(hash);
+        }
+        let mut flag_bytes = vec![0u8; self.flags.len().div_ceil(8)];
+        for (i, flag) in self.flags.iter().enumerate() {
+            flag_bytes[i / 8] |= (*flag as u8) << (i % 8);
+        }
+        ret.extend(encode_varint(flag_bytes.len() as u64));
+        ret.extend(flag_bytes);
+        ret
+    }
+}
+
+/// A block header with a proof of some of its transactions, the payload of
+/// a `merkleblock` message and the output of `gettxoutproof`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MerkleBlock {
+    pub header: BlockHeader,
+    pub tree: PartialMerkleTree,
+}
+
+impl MerkleBlock {
+    /// `None` under the same conditions as `PartialMerkleTree::new`.
+    pub fn new(header: BlockHeader, txids: &[[u8; 32]], matches: &[bool]) -> Option<Self> {
+        Some(Self {
+            header,
+            tree: PartialMerkleTree::new(txids, matches)?,
+        })
+    }
+
+    pub fn parse<R: Read>(stream: &mut R) -> io::Result<Self> {
+        Ok(Self {
+            header: BlockHeader::parse(stream)?,
+            tree: PartialMerkleTree::parse(stream)?,
+        })
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        [self.header.serialize(), self.tree.serialize()].concat()
+    }
+
+    /// The proven txids, if the proof is well formed and commits to the
+    /// header's merkle root.
+    pub fn verify(&self) -> Option<Vec<[u8; 32]>> {
+        let (root, matches) = self.tree.extract_matches()?;
+        if root != self.header.merkle_root {
+            return None;
+        }
+        Some(matches.into_iter().map(|(_, txid)| txid).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// Parses a txid as shown by block explorers.
+    fn txid(s: &str) -> [u8; 32] {
+        let mut hash: [u8; 32] = hex::decode(s).unwrap().try_into().unwrap();
+        hash.reverse();
+        hash
+    }
+
+    fn block_100000_txids() -> Vec<[u8; 32]> {
+        [
+            "8c14f0db3df150123e6f3dbbf30f8b955a8249b62ac1d1ff16284aefa3d06d87",
+            "fff2525b8931402dd09222c50775608f75787bd2b87e56995a7bdd30f79702c4",
+            "6359f0868171b1d194cbee1af2f16ea598ae8fad666d9b012c8ed2b79a236ec4",
+            "e9a66845e05d5abc0ad04ec80f774a7e585c6e8db975962d069a522137b80c1d",
+        ]
+        .iter()
+        .map(|s| txid(s))
+        .collect()
+    }
+
+    #[test]
+    fn test_merkle_root() {
+        let txids = block_100000_txids();
+        let root = txid("f3e94742aca4b5ef85488dc37c06c3282295ffec960994b2c0d5ac2a25a95766");
+        assert_eq!(merkle_root_mutated(&txids), (root, false));
+
+        // duplicating the odd last txid keeps the root but is a mutation
+        let (root, mutated) = merkle_root_mutated(&txids[..3]);
+        assert!(!mutated);
+        let duplicated = [&txids[..3], &txids[2..3]].concat();
+        assert_eq!(merkle_root_mutated(&duplicated), (root, true));
+
+        assert_eq!(merkle_root(&txids[..1]), txids[0]);
+        assert_eq!(merkle_root(&[]), [0; 32]);
+    }
+
+    #[test]
+    fn test_witness_commitment() {
+        // what Bitcoin Core puts in a coinbase-only block
+        let coinbase = Tx::new(1, vec![], vec![], 0);
+        let commitment = witness_commitment(&witness_merkle_root(&[coinbase]), &[0; 32]);
+        assert_eq!(
+            hex::encode(witness_commitment_script(&commitment).as_bytes()),
+            "6a24aa21a9ede2f61c3f71d1defd3fa999dfa36953755c690689799962b48bebd836974e8cf9"
+        );
+    }
+
+    #[test]
+    fn test_merkle_block() {
+        let raw = hex::decode("00000020df3b053dc46f162a9b00c7f0d5124e2676d47bbe7c5d0793a500000000000000ef445fef2ed495c275892206ca533e7411907971013ab83e3b47bd0d692d14d4dc7c835b67d8001ac157e670bf0d00000aba412a0d1480e370173072c9562becffe87aa661c1e4a6dbc305d38ec5dc088a7cf92e6458aca7b32edae818f9c2c98c37e06bf72ae0ce80649a38655ee1e27d34d9421d940b16732f24b94023e9d572a7f9ab8023434a4feb532d2adfc8c2c2158785d1bd04eb99df2e86c54bc13e139862897217400def5d72c280222c4cbaee7261831e1550dbb8fa82853e9fe506fc5fda3f7b919d8fe74b6282f92763cef8e625f977af7c8619c32a369b832bc2d051ecd9c73c51e76370ceabd4f25097c256597fa898d404ed53425de608ac6bfe426f6e2bb457f1c554866eb69dcb8d6bf6f880e9a59b3cd053e6c7060eeacaacf4dac6697dac20e4bd3f38a2ea2543d1ab7953e3430790a9f81e1c67f5b58c825acf46bd02848384eebe9af917274cdfbb1a28a5d58a23a17977def0de10d644258d9c54f886d47d293a411cb6226103b55635").unwrap();
+        let merkle_block = MerkleBlock::parse(&mut Cursor::new(raw.clone())).unwrap();
+        assert_eq!(merkle_block.tree.total, 3519);
+        assert_eq!(merkle_block.serialize(), raw);
+        let (_, matches) = merkle_block.tree.extract_matches().unwrap();
+        assert_eq!(
+            matches,
+            vec![(
+                3518,
+                txid("6122b61c413a297dd486f8549c8d2544d610def0de7779a1238ad5a5281abbdf")
+            )]
+        );
+        assert!(merkle_block.verify().is_some());
+
+        let mut bad = merkle_block.clone();
+        bad.tree.hashes[3][0] ^= 1;
+        assert_eq!(bad.verify(), None);
+        let mut bad = merkle_block;
+        bad.tree.hashes.pop();
+        assert_eq!(bad.verify(), None);
+    }
+
+    #[test]
+    fn test_partial_merkle_tree() {
+        let txids = block_100000_txids();
+        let root = merkle_root(&txids);
+        for matches in [
+            [false, false, true, false],
+            [true, false, false, true],
+            [false; 4],
+            [true; 4],
+        ] {
+            let tree = PartialMerkleTree::new(&txids, &matches).unwrap();
+            let tree = PartialMerkleTree::parse(&mut Cursor::new(tree.serialize())).unwrap();
+            let (extracted_root, found) = tree.extract_matches().unwrap();
+            assert_eq!(extracted_root, root);
+            let expected: Vec<IndexedTxid> = (0..4)
+                .filter(|i| matches[*i])
+                .map(|i| (i as u32, txids[i]))
+                .collect();
+            assert_eq!(found, expected);
+        }
+
+        // a proof for the mutated list is rejected
+        let mutated = [&txids[..3], &txids[2..3]].concat();
+        let tree = PartialMerkleTree::new(&mutated, &[false, false, true, true]).unwrap();
+        assert_eq!(tree.extract_matches(), None);
+
+        assert_eq!(PartialMerkleTree::new(&[], &[]), None);
+        assert_eq!(PartialMerkleTree::new(&txids, &[true; 3]), None);
+        let header = BlockHeader::new(1, [0; 32], root, 0, 0, 0);
+        assert_eq!(MerkleBlock::new(header, &[], &[]), None);
+    }
+}
@@ -0,0 +1,963 @@
+use crate::{
+    base58::hash256,
+    block::Block,
+    block_filter::BlockFilter,
+    block_header::BlockHeader,
+    bloom::{BloomFilter, MAX_FILTER_ADD_SIZE},
+    merkle::MerkleBlock,
+    network::Network,
+    tx::Tx,
+    varint::{encode_varint, read_varint},
+};
+use std::{
+    io::{self, Cursor, Read},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+/// Largest payload a peer may send, Core's `MAX_PROTOCOL_MESSAGE_LENGTH`.
+pub const MAX_PAYLOAD_SIZE: u32 = 4_000_000;
+pub const MAX_INV_SIZE: u64 = 50_000;
+pub const MAX_ADDR_SIZE: u64 = 1_000;
+pub const MAX_HEADERS_SIZE: u64 = 2_000;
+pub const MAX_LOCATOR_SIZE: u64 = 101;
+pub const MAX_USER_AGENT_SIZE: u64 = 256;
+/// Largest address in an `addrv2` entry, from BIP155.
+pub const MAX_ADDRV2_SIZE: u64 = 512;
+/// Most filters a `getcfilters` may ask for, from BIP157.
+pub const MAX_GETCFILTERS_SIZE: u32 = 1_000;
+/// Most filter hashes in a `cfheaders`, from BIP157.
+pub const MAX_CFHEADERS_SIZE: u64 = 2_000;
+
+pub const NODE_NETWORK: u64 = 1;
+pub const NODE_BLOOM: u64 = 1 << 2;
+pub const NODE_WITNESS: u64 = 1 << 3;
+pub const NODE_COMPACT_FILTERS: u64 = 1 << 6;
+pub const NODE_NETWORK_LIMITED: u64 = 1 << 10;
+
+pub const MSG_TX: u32 = 1;
+pub const MSG_BLOCK: u32 = 2;
+pub const MSG_FILTERED_BLOCK: u32 = 3;
+pub const MSG_CMPCT_BLOCK: u32 = 4;
+pub const MSG_WTX: u32 = 5;
+/// Or'ed into an inventory type to ask for witness data.
+pub const MSG_WITNESS_FLAG: u32 = 1 << 30;
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+fn read_array<R: Read, const N: usize>(stream: &mut R) -> io::Result<[u8; N]> {
+    let mut buf = [0u8; N];
+    stream.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+/// Reads a CompactSize item count, refusing more than `max`.
+fn read_count<R: Read>(stream: &mut R, max: u64) -> io::Result<u64> {
+    let count = read_varint(stream)?;
+    if count > max {
+        return Err(invalid("too many items"));
+    }
+    Ok(count)
+}
+
+fn read_bytes<R: Read>(stream: &mut R, max: u64) -> io::Result<Vec<u8>> {
+    let length = read_count(stream, max)?;
+    let mut ret = Vec::new();
+    stream.take(length).read_to_end(&mut ret)?;
+    if ret.len() as u64 != length {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(ret)
+}
+
+/// The 24 byte header framing every P2P message, followed by its payload.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NetworkEnvelope {
+    pub network: Network,
+    pub command: String,
+    pub payload: Vec<u8>,
+}
+
+impl NetworkEnvelope {
+    pub fn new(command: &str, payload: Vec<u8>, network: Network) -> Self {
+        Self {
+            network,
+            command: command.to_string(),
+            payload,
+        }
+    }
+
+    /// Parses a message for `network`, rejecting other magics, malformed
+    /// commands, oversized payloads and bad checksums.
+    pub fn parse<R: Read>(stream: &mut R, network: Network) -> io::Result<Self> {
+        let magic: [u8; 4] = read_array(stream)?;
+        if magic != network.magic() {
+            return Err(invalid("wrong network magic"));
+        }
+        let raw_command: [u8; 12] = read_array(stream)?;
+        let end = raw_command.iter().position(|b| *b == 0).unwrap_or(12);
+        if raw_command[end..].iter().any(|b| *b != 0)
+            || raw_command[..end].iter().any(|b| !b.is_ascii_graphic())
+        {
+            return Err(invalid("malformed command"));
+        }
+        let command = String::from_utf8(raw_command[..end].to_vec()).unwrap();
+        let length = u32::from_le_bytes(read_array(stream)?);
+        if length > MAX_PAYLOAD_SIZE {
+            return Err(invalid("payload too large"));
+        }
+        let checksum: [u8; 4] = read_array(stream)?;
+        let mut payload = Vec::new();
+        stream.take(length as u64).read_to_end(&mut payload)?;
+        if payload.len() as u32 != length {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        if hash256(&payload)[..4] != checksum {
+            return Err(invalid("bad checksum"));
+        }
+        Ok(Self {
+            network,
+            command,
+            payload,
+        })
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut command = [0u8; 12];
+        command[..self.command.len()].copy_from_slice(self.command.as_bytes());
+        let mut ret = self.network.magic().to_vec();
+        ret.extend(command);
+        ret.extend((self.payload.len() as u32).to_le_bytes());
+        ret.extend(&hash256(&self.payload)[..4]);
+        ret.extend(&self.payload);
+        ret
+    }
+}
+
+/// A peer's services, address and port as carried in `version` and `addr`.
+/// IPv4 addresses are stored IPv4-mapped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct NetAddress {
+    pub services: u64,
+    pub ip: Ipv6Addr,
+    pub port: u16,
+}
+
+impl NetAddress {
+    pub fn new(addr: SocketAddr, services: u64) -> Self {
+        let ip = match addr.ip() {
+            IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+            IpAddr::V6(ip) => ip,
+        };
+        Self {
+            services,
+            ip,
+            port: addr.port(),
+        }
+    }
+
+    pub fn socket_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.ip.to_canonical(), self.port)
+    }
+
+    pub fn parse<R: Read>(stream: &mut R) -> io::Result<Self> {
+        Ok(Self {
+            services: u64::from_le_bytes(read_array(stream)?),
+            ip: Ipv6Addr::from(read_array::<R, 16>(stream)?),
+            port: u16::from_be_bytes(read_array(stream)?),
+        })
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut ret = self.services.to_le_bytes().to_vec();
+        ret.extend(self.ip.octets());
+        ret.extend(self.port.to_be_bytes());
+        ret
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VersionMessage {
+    pub version: i32,
+    pub services: u64,
+    pub timestamp: i64,
+    pub receiver: NetAddress,
+    pub sender: NetAddress,
+    pub nonce: u64,
+    pub user_agent: String,
+    pub start_height: i32,
+    /// Whether to announce transactions before a filter is loaded, BIP37.
+    pub relay: bool,
+}
+
+impl VersionMessage {
+    /// Parses the payload. `relay` predates BIP37 and defaults to true when
+    /// missing.
+    pub fn parse(payload: &[u8]) -> io::Result<Self> {
+        let mut stream = Cursor::new(payload);
+        let version = i32::from_le_bytes(read_array(&mut stream)?);
+        let services = u64::from_le_bytes(read_array(&mut stream)?);
+        let timestamp = i64::from_le_bytes(read_array(&mut stream)?);
+        let receiver = NetAddress::parse(&mut stream)?;
+        let sender = NetAddress::parse(&mut stream)?;
+        let nonce = u64::from_le_bytes(read_array(&mut stream)?);
+        let user_agent = String::from_utf8(read_bytes(&mut stream, MAX_USER_AGENT_SIZE)?)
+            .map_err(|_| invalid("user agent is not utf-8"))?;
+        let start_height = i32::from_le_bytes(read_array(&mut stream)?);
+        let relay = if stream.position() as usize == payload.len() {
+            true
+        } else {
+            let [relay] = read_array(&mut stream)?;
+            relay != 0
+        };
+        if stream.position() as usize != payload.len() {
+            return Err(invalid("trailing data after payload"));
+        }
+        Ok(Self {
+            version,
+            services,
+            timestamp,
+            receiver,
+            sender,
+            nonce,
+            user_agent,
+            start_height,
+            relay,
+        })
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut ret = self.version.to_le_bytes().to_vec();
+        ret.extend(self.services.to_le_bytes());
+        ret.extend(self.timestamp.to_le_bytes());
+        ret.extend(self.receiver.serialize());
+        ret.extend(self.sender.serialize());
+        ret.extend(self.nonce.to_le_bytes());
+        ret.extend(encode_varint(self.user_agent.len() as u64));
+        ret.extend(self.user_agent.as_bytes());
+        ret.extend(self.start_height.to_le_bytes());
+        ret.push(self.relay as u8);
+        ret
+    }
+}
+
+/// An entry of an `addr` message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AddrEntry {
+    pub time: u32,
+    pub address: NetAddress,
+}
+
+/// The address formats BIP155 gives a fixed length.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AddrV2 {
+    Ipv4(Ipv4Addr),
+    Ipv6(Ipv6Addr),
+    TorV3([u8; 32]),
+    I2p([u8; 32]),
+    Cjdns(Ipv6Addr),
+    Unknown { network_id: u8, addr: Vec<u8> },
+}
+
+impl AddrV2 {
+    pub fn parse<R: Read>(stream: &mut R) -> io::Result<Self> {
+        let [network_id] = read_array(stream)?;
+        let addr = read_bytes(stream, MAX_ADDRV2_SIZE)?;
+        let expected = match network_id {
+            1 => 4,
+            2 | 6 => 16,
+            4 | 5 => 32,
+            _ => return Ok(AddrV2::Unknown { network_id, addr }),
+        };
+        if addr.len() != expected {
+            return Err(invalid("bad address length for network"));
+        }
+        Ok(match network_id {
+            1 => AddrV2::Ipv4(Ipv4Addr::from(<[u8; 4]>::try_from(addr).unwrap())),
+            2 => AddrV2::Ipv6(Ipv6Addr::from(<[u8; 16]>::try_from(addr).unwrap())),
+            4 => AddrV2::TorV3(addr.try_into().unwrap()),
+            5 => AddrV2::I2p(addr.try_into().unwrap()),
+            _ => AddrV2::Cjdns(Ipv6Addr::from(<[u8; 16]>::try_from(addr).unwrap())),
+        })
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let (network_id, addr) = match self {
+            AddrV2::Ipv4(ip) => (1, ip.octets().to_vec()),
+            AddrV2::Ipv6(ip) => (2, ip.octets().to_vec()),
+            AddrV2::TorV3(key) => (4, key.to_vec()),
+            AddrV2::I2p(hash) => (5, hash.to_vec()),
+            AddrV2::Cjdns(ip) => (6, ip.octets().to_vec()),
+            AddrV2::Unknown { network_id, addr } => (*network_id, addr.clone()),
+        };
+        let mut ret = vec![network_id];
+        ret.extend(encode_varint(addr.len() as u64));
+        ret.extend(addr);
+        ret
+    }
+}
+
+/// An entry of an `addrv2` message, BIP155.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AddrV2Entry {
+    pub time: u32,
+    pub services: u64,
+    pub addr: AddrV2,
+    pub port: u16,
+}
+
+/// A typed reference to a transaction or block in `inv`, `getdata` and
+/// `notfound`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Inventory {
+    pub kind: u32,
+    pub hash: [u8; 32],
+}
+
+impl Inventory {
+    pub fn new(kind: u32, hash: [u8; 32]) -> Self {
+        Self { kind, hash }
+    }
+}
+
+/// The payload of both `getheaders` and `getblocks`: block hashes from the
+/// tip backwards, and the hash to stop at, zero for as many as allowed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GetHeadersMessage {
+    pub version: u32,
+    pub locator: Vec<[u8; 32]>,
+    pub hash_stop: [u8; 32],
+}
+
+impl GetHeadersMessage {
+    pub fn new(version: u32, locator: Vec<[u8; 32]>) -> Self {
+        Self {
+            version,
+            locator,
+            hash_stop: [0; 32],
+        }
+    }
+}
+
+/// The payload of both `getcfilters` and `getcfheaders`: BIP157 filters
+/// from `start_height` up to the block `stop_hash`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GetCFiltersMessage {
+    pub filter_type: u8,
+    pub start_height: u32,
+    pub stop_hash: [u8; 32],
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CFilterMessage {
+    pub filter_type: u8,
+    pub block_hash: [u8; 32],
+    pub filter: BlockFilter,
+}
+
+/// Filter hashes up to `stop_hash`, each block's header following from
+/// `previous_filter_header` by `BlockFilter::header`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CFHeadersMessage {
+    pub filter_type: u8,
+    pub stop_hash: [u8; 32],
+    pub previous_filter_header: [u8; 32],
+    pub filter_hashes: Vec<[u8; 32]>,
+}
+
+/// Filter headers at every 1000th block up to `stop_hash`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CFCheckptMessage {
+    pub filter_type: u8,
+    pub stop_hash: [u8; 32],
+    pub filter_headers: Vec<[u8; 32]>,
+}
+
+/// A decoded P2P message. Commands without a type here are kept as
+/// `Unknown` so they can be passed on or ignored.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    Version(VersionMessage),
+    Verack,
+    Ping(u64),
+    Pong(u64),
+    Addr(Vec<AddrEntry>),
+    AddrV2(Vec<AddrV2Entry>),
+    Inv(Vec<Inventory>),
+    GetData(Vec<Inventory>),
+    NotFound(Vec<Inventory>),
+    GetHeaders(GetHeadersMessage),
+    GetBlocks(GetHeadersMessage),
+    Headers(Vec<BlockHeader>),
+    Block(Block),
+    Tx(Tx),
+    MerkleBlock(MerkleBlock),
+    /// Minimum fee rate in satoshis per 1000 virtual bytes, BIP133.
+    FeeFilter(u64),
+    SendHeaders,
+    /// BIP152 compact block negotiation.
+    SendCmpct {
+        announce: bool,
+        version: u64,
+    },
+    /// BIP37: match relayed transactions and `merkleblock`s against a filter.
+    FilterLoad(BloomFilter),
+    FilterAdd(Vec<u8>),
+    FilterClear,
+    /// BIP157 compact filter requests and replies.
+    GetCFilters(GetCFiltersMessage),
+    CFilter(CFilterMessage),
+    GetCFHeaders(GetCFiltersMessage),
+    CFHeaders(CFHeadersMessage),
+    GetCFCheckpt {
+        filter_type: u8,
+        stop_hash: [u8; 32],
+    },
+    CFCheckpt(CFCheckptMessage),
+    Unknown {
+        command: String,
+        payload: Vec<u8>,
+    },
+}
+
+impl Message {
+    pub fn command(&self) -> &str {
+        match self {
+            Message::Version(_) => "version",
+            Message::Verack => "verack",
+            Message::Ping(_) => "ping",
+            Message::Pong(_) => "pong",
+            Message::Addr(_) => "addr",
+            Message::AddrV2(_) => "addrv2",
+            Message::Inv(_) => "inv",
+            Message::GetData(_) => "getdata",
+            Message::NotFound(_) => "notfound",
+            Message::GetHeaders(_) => "getheaders",
+            Message::GetBlocks(_) => "getblocks",
+            Message::Headers(_) => "headers",
+            Message::Block(_) => "block",
+            Message::Tx(_) => "tx",
+            Message::MerkleBlock(_) => "merkleblock",
+            Message::FeeFilter(_) => "feefilter",
+            Message::SendHeaders => "sendheaders",
+            Message::SendCmpct { .. } => "sendcmpct",
+            Message::FilterLoad(_) => "filterload",
+            Message::FilterAdd(_) => "filteradd",
+            Message::FilterClear => "filterclear",
+            Message::GetCFilters(_) => "getcfilters",
+            Message::CFilter(_) => "cfilter",
+            Message::GetCFHeaders(_) => "getcfheaders",
+            Message::CFHeaders(_) => "cfheaders",
+            Message::GetCFCheckpt { .. } => "getcfcheckpt",
+            Message::CFCheckpt(_) => "cfcheckpt",
+            Message::Unknown { command, .. } => command,
+        }
+    }
+
+    /// Decodes the payload of `command`. The payload has to be consumed
+    /// exactly.
+    pub fn parse(command: &str, payload: &[u8]) -> io::Result<Self> {
+        if command == "version" {
+            return VersionMessage::parse(payload).map(Message::Version);
+        }
+        let mut stream = Cursor::new(payload);
+        let message = match command {
+            "verack" => Message::Verack,
+            "ping" => Message::Ping(u64::from_le_bytes(read_array(&mut stream)?)),
+            "pong" => Message::Pong(u64::from_le_bytes(read_array(&mut stream)?)),
+            "addr" => {
+                let mut entries = Vec::new();
+                for _ in 0..read_count(&mut stream, MAX_ADDR_SIZE)? {
+                    entries.push(AddrEntry {
+                        time: u32::from_le_bytes(read_array(&mut stream)?),
+                        address: NetAddress::parse(&mut stream)?,
+                    });
+                }
+                Message::Addr(entries)
+            }
+            "addrv2" => {
+                let mut entries = Vec::new();
+                for _ in 0..read_count(&mut stream, MAX_ADDR_SIZE)? {
+                    entries.push(AddrV2Entry {
+                        time: u32::from_le_bytes(read_array(&mut stream)?),
+                        services: read_varint(&mut stream)?,
+                        addr: AddrV2::parse(&mut stream)?,
+                        port: u16::from_be_bytes(read_array(&mut stream)?),
+                    });
+                }
+                Message::AddrV2(entries)
+            }
+            "inv" => Message::Inv(parse_inventory(&mut stream)?),
+            "getdata" => Message::GetData(parse_inventory(&mut stream)?),
+            "notfound" => Message::NotFound(parse_inventory(&mut stream)?),
+            "getheaders" => Message::GetHeaders(parse_locator(&mut stream)?),
+            "getblocks" => Message::GetBlocks(parse_locator(&mut stream)?),
+            "headers" => {
+                let mut headers = Vec::new();
+                for _ in 0..read_count(&mut stream, MAX_HEADERS_SIZE)? {
+                    headers.push(BlockHeader::parse(&mut stream)?);
+                    if read_varint(&mut stream)? != 0 {
+                        return Err(invalid("header with transactions"));
+                    }
+                }
+                Message::Headers(headers)
+            }
+            "block" => Message::Block(Block::parse(&mut stream)?),
+            "tx" => Message::Tx(Tx::parse(&mut stream)?),
+            "merkleblock" => Message::MerkleBlock(MerkleBlock::parse(&mut stream)?),
+            "feefilter" => Message::FeeFilter(u64::from_le_bytes(read_array(&mut stream)?)),
+            "sendheaders" => Message::SendHeaders,
+            "sendcmpct" => {
+                let [announce] = read_array(&mut stream)?;
+                if announce > 1 {
+                    return Err(invalid("bad sendcmpct announce flag"));
+                }
+                Message::SendCmpct {
+                    announce: announce == 1,
+                    version: u64::from_le_bytes(read_array(&mut stream)?),
+                }
+            }
+            "filterload" => Message::FilterLoad(BloomFilter::parse(&mut stream)?),
+            "filteradd" => Message::FilterAdd(read_bytes(&mut stream, MAX_FILTER_ADD_SIZE)?),
+            "filterclear" => Message::FilterClear,
+            "getcfilters" => Message::GetCFilters(parse_cfilters_request(&mut stream)?),
+            "cfilter" => Message::CFilter(CFilterMessage {
+                filter_type: read_array::<_, 1>(&mut stream)?[0],
+                block_hash: read_array(&mut stream)?,
+                filter: BlockFilter::parse(&mut stream)?,
+            }),
+            "getcfheaders" => Message::GetCFHeaders(parse_cfilters_request(&mut stream)?),
+            "cfheaders" => {
+                let [filter_type] = read_array(&mut stream)?;
+                let stop_hash = read_array(&mut stream)?;
+                let previous_filter_header = read_array(&mut stream)?;
+                let mut filter_hashes = Vec::new();
+                for _ in 0..read_count(&mut stream, MAX_CFHEADERS_SIZE)? {
+                    filter_hashes.push(read_array(&mut stream)?);
+                }
+                Message::CFHeaders(CFHeadersMessage {
+                    filter_type,
+                    stop_hash,
+                    previous_filter_header,
+                    filter_hashes,
+                })
+            }
+            "getcfcheckpt" => Message::GetCFCheckpt {
+                filter_type: read_array::<_, 1>(&mut stream)?[0],
+                stop_hash: read_array(&mut stream)?,
+            },
+            "cfcheckpt" => {
+                let [filter_type] = read_array(&mut stream)?;
+                let stop_hash = read_array(&mut stream)?;
+                let mut filter_headers = Vec::new();
+                for _ in 0..read_count(&mut stream, MAX_PAYLOAD_SIZE as u64 / 32)? {
+                    filter_headers.push(read_array(&mut stream)?);
+                }
+                Message::CFCheckpt(CFCheckptMessage {
+                    filter_type,
+                    stop_hash,
+                    filter_headers,
+                })
+            }
+            _ => {
+                return Ok(Message::Unknown {
+                    command: command.to_string(),
+                    payload: payload.to_vec(),
+                })
+            }
+        };
+        if stream.position() as usize != payload.len() {
+            return Err(invalid("trailing data after payload"));
+        }
+        Ok(message)
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        match self {
+            Message::Version(version) => version.serialize(),
+            Message::Verack | Message::SendHeaders | Message::FilterClear => Vec::new(),
+            Message::Ping(nonce) | Message::Pong(nonce) => nonce.to_le_bytes().to_vec(),
+            Message::Addr(entries) => {
+                let mut ret = encode_varint(entries.len() as u64);
+                for entry in entries {
+                    ret.extend(entry.time.to_le_bytes());
+                    ret.extend(entry.address.serialize());
+                }
+                ret
+            }
+            Message::AddrV2(entries) => {
+                let mut ret = encode_varint(entries.len() as u64);
+                for entry in entries {
+                    ret.extend(entry.time.to_le_bytes());
+                    ret.extend(encode_varint(entry.services));
+                    ret.extend(entry.addr.serialize());
+                    ret.extend(entry.port.to_be_bytes());
+                }
+                ret
+            }
+            Message::Inv(items) | Message::GetData(items) | Message::NotFound(items) => {
+                let mut ret = encode_varint(items.len() as u64);
+                for item in items {
+                    ret.extend(item.kind.to_le_bytes());
+                    ret.extend(item.hash);
+                }
+                ret
+            }
+            Message::GetHeaders(locator) | Message::GetBlocks(locator) => {
+                let mut ret = locator.version.to_le_bytes().to_vec();
+                ret.extend(encode_varint(locator.locator.len() as u64));
+                for hash in &locator.locator {
+                    ret.extend(hash);
+                }
+                ret.extend(locator.hash_stop);
+                ret
+            }
+            Message::Headers(headers) => {
+                let mut ret = encode_varint(headers.len() as u64);
+                for header in headers {
+                    ret.extend(header.serialize());
+                    ret.push(0);
+                }
+                ret
+            }
+            Message::Block(block) => block.serialize(),
+            Message::Tx(tx) => tx.serialize(),
+            Message::MerkleBlock(merkle_block) => merkle_block.serialize(),
+            Message::FeeFilter(fee_rate) => fee_rate.to_le_bytes().to_vec(),
+            Message::SendCmpct { announce, version } => {
+                let mut ret = vec![*announce as u8];
+                ret.extend(version.to_le_bytes());
+                ret
+            }
+            Message::FilterLoad(filter) => filter.serialize(),
+            Message::FilterAdd(data) => {
+                let mut ret = encode_varint(data.len() as u64);
+                ret.extend(data);
+                ret
+            }
+            Message::GetCFilters(request) | Message::GetCFHeaders(request) => {
+                let mut ret = vec![request.filter_type];
+                ret.extend(request.start_height.to_le_bytes());
+                ret.extend(request.stop_hash);
+                ret
+            }
+            Message::CFilter(cfilter) => {
+                let mut ret = vec![cfilter.filter_type];
+                ret.extend(cfilter.block_hash);
+                ret.extend(cfilter.filter.serialize());
+                ret
+            }
+            Message::CFHeaders(cfheaders) => {
+                let mut ret = vec![cfheaders.filter_type];
+                ret.extend(cfheaders.stop_hash);
+                ret.extend(cfheaders.previous_filter_header);
+                ret.extend(encode_varint(cfheaders.filter_hashes.len() as u64));
+                for hash in &cfheaders.filter_hashes {
+                    ret.extend(hash);
+                }
+                ret
+            }
+            Message::GetCFCheckpt {
+                filter_type,
+                stop_hash,
+            } => [&[*filter_type][..], stop_hash].concat(),
+            Message::CFCheckpt(cfcheckpt) => {
+                let mut ret = vec![cfcheckpt.filter_type];
+                ret.extend(cfcheckpt.stop_hash);
+                ret.extend(encode_varint(cfcheckpt.filter_headers.len() as u64));
+                for header in &cfcheckpt.filter_headers {
+                    ret.extend(header);
+                }
+                ret
+            }
+            Message::Unknown { payload, .. } => payload.clone(),
+        }
+    }
+
+    pub fn from_envelope(envelope: &NetworkEnvelope) -> io::Result<Self> {
+        Self::parse(&envelope.command, &envelope.payload)
+    }
+
+    pub fn to_envelope(&self, network: Network) -> NetworkEnvelope {
+        NetworkEnvelope::new(self.command(), self.serialize(), network)
+    }
+}
+
+fn parse_inventory<R: Read>(stream: &mut R) -> io::Result<Vec<Inventory>> {
+    let mut items = Vec::new();
+    for _ in 0..read_count(stream, MAX_INV_SIZE)? {
+        items.push(Inventory {
+            kind: u32::from_le_bytes(read_array(stream)?),
+            hash: read_array(stream)?,
+        });
+    }
+    Ok(items)
+}
+
+fn parse_locator<R: Read>(stream: &mut R) -> io::Result<GetHeadersMessage> {
+    let version = u32::from_le_bytes(read_array(stream)?);
+    let mut locator = Vec::new();
+    for _ in 0..read_count(stream, MAX_LOCATOR_SIZE)? {
+        locator.push(read_array(stream)?);
+    }
+    Ok(GetHeadersMessage {
+        version,
+        locator,
+        hash_stop: read_array(stream)?,
+    })
+}
+
+fn parse_cfilters_request<R: Read>(stream: &mut R) -> io::Result<GetCFiltersMessage> {
+    Ok(GetCFiltersMessage {
+        filter_type: read_array::<_, 1>(stream)?[0],
+        start_height: u32::from_le_bytes(read_array(stream)?),
+        stop_hash: read_array(stream)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        block_filter::BASIC_FILTER,
+        script::Script,
+        tx::{OutPoint, TxIn, TxOut, SEQUENCE_FINAL},
+    };
+
+    fn parse_envelope(s: &str) -> io::Result<NetworkEnvelope> {
+        NetworkEnvelope::parse(&mut Cursor::new(hex::decode(s).unwrap()), Network::Mainnet)
+    }
+
+    #[test]
+    fn test_envelope() {
+        let raw = "f9beb4d976657261636b000000000000000000005df6e0e2";
+        let envelope = parse_envelope(raw).unwrap();
+        assert_eq!(envelope.command, "verack");
+        assert_eq!(envelope.payload, b"");
+        assert_eq!(hex::encode(envelope.serialize()), raw);
+        assert_eq!(Message::from_envelope(&envelope).unwrap(), Message::Verack);
+
+        let raw = "f9beb4d976657273696f6e0000000000650000005f1a69d2721101000100000000000000bc8f5e5400000000010000000000000000000000000000000000ffffc61b6409208d010000000000000000000000000000000000ffffcb0071c0208d128035cbc97953f80f2f5361746f7368693a302e392e332fcf05050001";
+        let envelope = parse_envelope(raw).unwrap();
+        assert_eq!(hex::encode(envelope.serialize()), raw);
+        let Message::Version(version) = Message::from_envelope(&envelope).unwrap() else {
+            panic!("not a version message");
+        };
+        assert_eq!(version.version, 70002);
+        assert_eq!(version.services, NODE_NETWORK);
+        assert_eq!(version.user_agent, "/Satoshi:0.9.3/");
+        assert_eq!(version.start_height, 329167);
+        assert!(version.relay);
+        assert_eq!(
+            version.receiver.socket_addr(),
+            "198.27.100.9:8333".parse().unwrap()
+        );
+        assert_eq!(version.serialize(), envelope.payload);
+
+        // bad checksum, magic, command padding and declared length
+        for bad in [
+            "f9beb4d976657261636b000000000000000000005df6e0e3",
+            "0b11090776657261636b000000000000000000005df6e0e2",
+            "f9beb4d976657261636b000000000001000000005df6e0e2",
+            "f9beb4d976657261636b00000000000001093d005df6e0e2",
+        ] {
+            assert_eq!(
+                parse_envelope(bad).unwrap_err().kind(),
+                io::ErrorKind::InvalidData
+            );
+        }
+        assert_eq!(
+            parse_envelope("f9beb4d976657261636b0000000000000100000000000000")
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+    }
+
+    #[test]
+    fn test_version() {
+        let version = VersionMessage {
+            version: 70015,
+            services: 0,
+            timestamp: 0,
+            receiver: NetAddress::new("0.0.0.0:8333".parse().unwrap(), 0),
+            sender: NetAddress::new("0.0.0.0:8333".parse().unwrap(), 0),
+            nonce: 0,
+            user_agent: "/programmingbitcoin:0.1/".to_string(),
+            start_height: 0,
+            relay: false,
+        };
+        let raw = version.serialize();
+        assert_eq!(hex::encode(&raw), "7f11010000000000000000000000000000000000000000000000000000000000000000000000ffff00000000208d000000000000000000000000000000000000ffff00000000208d0000000000000000182f70726f6772616d6d696e67626974636f696e3a302e312f0000000000");
+        assert_eq!(VersionMessage::parse(&raw).unwrap(), version);
+        // relay is optional
+        assert!(VersionMessage::parse(&raw[..raw.len() - 1]).unwrap().relay);
+        assert!(VersionMessage::parse(&[raw.clone(), vec![0]].concat()).is_err());
+    }
+
+    #[test]
+    fn test_headers() {
+        let mut start =
+            hex::decode("0000000000000000001237f46acddf58578a37e213d2a6edc4884a2fcad05ba3")
+                .unwrap();
+        start.reverse();
+        let getheaders = Message::GetHeaders(GetHeadersMessage::new(
+            70015,
+            vec![start.try_into().unwrap()],
+        ));
+        assert_eq!(hex::encode(getheaders.serialize()), "7f11010001a35bd0ca2f4a88c4eda6d213e2378a5758dfcd6af437120000000000000000000000000000000000000000000000000000000000000000000000000000000000");
+
+        let raw = hex::decode("0200000020df3b053dc46f162a9b00c7f0d5124e2676d47bbe7c5d0793a500000000000000ef445fef2ed495c275892206ca533e7411907971013ab83e3b47bd0d692d14d4dc7c835b67d8001ac157e670000000002030eb2540c41025690160a1014c577061596e32e426b712c7ca00000000000000768b89f07044e6130ead292a3f51951adbd2202df447d98789339937fd006bd44880835b67d8001ade09204600").unwrap();
+        let Message::Headers(headers) = Message::parse("headers", &raw).unwrap() else {
+            panic!("not a headers message");
+        };
+        assert_eq!(headers.len(), 2);
+        assert!(headers.iter().all(|header| header.check_pow()));
+        assert_eq!(Message::Headers(headers).serialize(), raw);
+
+        let mut with_txs = raw.clone();
+        with_txs[81] = 1;
+        assert!(Message::parse("headers", &with_txs).is_err());
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut tx_in = TxIn::new(OutPoint::new([1; 32], 0), SEQUENCE_FINAL);
+        tx_in.witness = vec![vec![2; 72]];
+        let tx = Tx::new(
+            2,
+            vec![tx_in],
+            vec![TxOut::new(1000, Script::p2wpkh(&[3; 20]))],
+            0,
+        );
+        let block = Block::genesis(Network::Mainnet);
+        let address = NetAddress::new(
+            "[2001:db8::1]:8333".parse().unwrap(),
+            NODE_NETWORK | NODE_WITNESS,
+        );
+        // parsing pads the flag bits to whole bytes
+        let merkle_block = MerkleBlock::new(block.header, &block.txids(), &[true])
+            .unwrap()
+            .serialize();
+        let merkle_block = MerkleBlock::parse(&mut Cursor::new(merkle_block)).unwrap();
+        let filter = BlockFilter::basic(&block, &[]);
+        let request = GetCFiltersMessage {
+            filter_type: BASIC_FILTER,
+            start_height: 0,
+            stop_hash: block.hash(),
+        };
+        let inventory = vec![
+            Inventory::new(MSG_WITNESS_FLAG | MSG_TX, tx.wtxid()),
+            Inventory::new(MSG_BLOCK, block.hash()),
+        ];
+        let messages = [
+            Message::Verack,
+            Message::Ping(0x0123_4567_89ab_cdef),
+            Message::Pong(7),
+            Message::Addr(vec![AddrEntry {
+                time: 1_700_000_000,
+                address,
+            }]),
+            Message::AddrV2(vec![
+                AddrV2Entry {
+                    time: 1,
+                    services: NODE_NETWORK_LIMITED,
+                    addr: AddrV2::Ipv4(Ipv4Addr::new(1, 2, 3, 4)),
+                    port: 8333,
+                },
+                AddrV2Entry {
+                    time: 2,
+                    services: 0,
+                    addr: AddrV2::TorV3([9; 32]),
+                    port: 9050,
+                },
+                AddrV2Entry {
+                    time: 3,
+                    services: 0,
+                    addr: AddrV2::Unknown {
+                        network_id: 42,
+                        addr: vec![1, 2, 3],
+                    },
+                    port: 1,
+                },
+            ]),
+            Message::Inv(inventory.clone()),
+            Message::GetData(inventory.clone()),
+            Message::NotFound(inventory),
+            Message::GetBlocks(GetHeadersMessage::new(70016, vec![block.hash(); 3])),
+            Message::Headers(vec![block.header; 2]),
+            Message::Block(block.clone()),
+            Message::Tx(tx),
+            Message::MerkleBlock(merkle_block),
+            Message::FeeFilter(1000),
+            Message::SendHeaders,
+            Message::SendCmpct {
+                announce: true,
+                version: 2,
+            },
+            Message::FilterLoad(BloomFilter::new(10, 5, 99, 1)),
+            Message::FilterAdd(vec![0xab; 20]),
+            Message::FilterClear,
+            Message::GetCFilters(request.clone()),
+            Message::CFilter(CFilterMessage {
+                filter_type: BASIC_FILTER,
+                block_hash: block.hash(),
+                filter: filter.clone(),
+            }),
+            Message::GetCFHeaders(request),
+            Message::CFHeaders(CFHeadersMessage {
+                filter_type: BASIC_FILTER,
+                stop_hash: block.hash(),
+                previous_filter_header: [0; 32],
+                filter_hashes: vec![filter.filter_hash()],
+            }),
+            Message::GetCFCheckpt {
+                filter_type: BASIC_FILTER,
+                stop_hash: block.hash(),
+            },
+            Message::CFCheckpt(CFCheckptMessage {
+                filter_type: BASIC_FILTER,
+                stop_hash: block.hash(),
+                filter_headers: vec![filter.header(&[0; 32]); 2],
+            }),
+            Message::Unknown {
+                command: "wtxidrelay".to_string(),
+                payload: vec![],
+            },
+        ];
+        for message in messages {
+            let raw = message.to_envelope(Network::Regtest).serialize();
+            let envelope = NetworkEnvelope::parse(&mut Cursor::new(raw), Network::Regtest).unwrap();
+            assert_eq!(envelope.command, message.command());
+            assert_eq!(Message::from_envelope(&envelope).unwrap(), message);
+            // trailing bytes are rejected
+            let padded = [envelope.payload, vec![0]].concat();
+            if !matches!(message, Message::Unknown { .. }) {
+                assert!(Message::parse(message.command(), &padded).is_err());
+            }
+        }
+    }
+
+    #[test]
+    fn test_limits() {
+        let too_many = encode_varint(MAX_INV_SIZE + 1);
+        assert!(Message::parse("inv", &too_many).is_err());
+        let too_many = encode_varint(MAX_ADDR_SIZE + 1);
+        assert!(Message::parse("addr", &too_many).is_err());
+        // an IPv4 addrv2 entry with a 5 byte address
+        let bad = hex::decode("01010000000001050102030405208d").unwrap();
+        assert!(Message::parse("addrv2", &bad).is_err());
+        let locator = [vec![0; 4], encode_varint(MAX_LOCATOR_SIZE + 1)].concat();
+        assert!(Message::parse("getheaders", &locator).is_err());
+        assert!(Message::parse("verack", &[0]).is_err());
+        let too_large = [encode_varint(MAX_FILTER_ADD_SIZE + 1), vec![0; 521]].concat();
+        assert!(Message::parse("filteradd", &too_large).is_err());
+        assert!(Message::parse("sendcmpct", &[2, 0, 0, 0, 0, 0, 0, 0, 0]).is_err());
+        let cfheaders = [vec![0; 65], encode_varint(MAX_CFHEADERS_SIZE + 1)].concat();
+        assert!(Message::parse("cfheaders", &cfheaders).is_err());
+        // a filter longer than the payload
+        let cfilter = [vec![0; 33], encode_varint(10), vec![0; 9]].concat();
+        assert!(Message::parse("cfilter", &cfilter).is_err());
+    }
+}
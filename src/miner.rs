@@ -0,0 +1,172 @@
+use crate::{
+    address::Address,
+    block::Block,
+    block_header::BlockHeader,
+    header_chain::HeaderChain,
+    mempool::BlockTemplate,
+    merkle::{merkle_root, witness_commitment, witness_commitment_script, witness_merkle_root},
+    script::{Command, Script},
+    tx::{OutPoint, Tx, TxIn, TxOut, SEQUENCE_FINAL},
+};
+
+/// Weight kept free for the coinbase when filling a block from a template,
+/// Bitcoin Core's `DEFAULT_BLOCK_RESERVED_WEIGHT`.
+pub const COINBASE_RESERVED_WEIGHT: u64 = 8000;
+/// Version of mined blocks: BIP9 bits set, signalling nothing.
+pub const BLOCK_VERSION: u32 = 0x2000_0000;
+
+/// A coinbase for a block at `height` paying `value` to `script_pubkey`.
+///
+/// The scriptSig is the height (BIP34) followed by `extra_nonce`, as in
+/// Bitcoin Core, and the input carries the all zero witness reserved value
+/// a witness commitment is made with.
+pub fn create_coinbase(height: u32, script_pubkey: Script, value: u64, extra_nonce: u64) -> Tx {
+    let mut tx_in = TxIn::new(OutPoint::null(), SEQUENCE_FINAL);
+    tx_in.script_sig = Script::new(&[
+        Command::int(height as i64),
+        Command::int(extra_nonce as i64),
+    ]);
+    tx_in.witness = vec![vec![0; 32]];
+    Tx::new(2, vec![tx_in], vec![TxOut::new(value, script_pubkey)], 0)
+}
+
+/// An unmined block on `chain`'s tip holding `template`'s transactions,
+/// its coinbase paying the subsidy and fees to `address`.
+///
+/// The timestamp is raised past the tip's median time past if needed.
+/// Blocks before segwit activates get no witness commitment.
+pub fn create_block(
+    chain: &HeaderChain,
+    template: &BlockTemplate,
+    address: &Address,
+    timestamp: u32,
+    extra_nonce: u64,
+) -> Block {
+    let params = chain.get_params();
+    let height = chain.height() + 1;
+    let value = params.block_subsidy(height) + template.fees;
+    let mut coinbase = create_coinbase(height, address.script_pubkey(), value, extra_nonce);
+    let mut txs = [vec![coinbase.clone()], template.txs.clone()].concat();
+    if height >= params.segwit_height {
+        let commitment = witness_commitment(&witness_merkle_root(&txs), &[0; 32]);
+        coinbase
+            .tx_outs
+            .push(TxOut::new(0, witness_commitment_script(&commitment)));
+    } else {
+        coinbase.tx_ins[0].witness.clear();
+    }
+    txs[0] = coinbase;
+
+    let tip = chain.tip();
+    let timestamp = timestamp.max(chain.median_time_past(&tip.hash()) + 1);
+    let header = BlockHeader::new(
+        BLOCK_VERSION,
+        tip.hash(),
+        [0; 32],
+        timestamp,
+        chain.next_bits_on_tip(timestamp),
+        0,
+    );
+    let mut block = Block::new(header, txs);
+    block.header.merkle_root = merkle_root(&block.txids());
+    block
+}
+
+/// Grinds the nonce from zero until the header meets its target. Returns
+/// false if no nonce does.
+pub fn mine(header: &mut BlockHeader) -> bool {
+    header.nonce = 0;
+    loop {
+        if header.check_pow() {
+            return true;
+        }
+        if header.nonce == u32::MAX {
+            return false;
+        }
+        header.nonce += 1;
+    }
+}
+
+/// Creates and mines a block on `chain`'s tip, moving on to the next extra
+/// nonce, and so a new merkle root, whenever the nonces run out.
+pub fn generate_block(
+    chain: &HeaderChain,
+    template: &BlockTemplate,
+    address: &Address,
+    timestamp: u32,
+) -> Block {
+    let mut extra_nonce = 0;
+    loop {
+        let mut block = create_block(chain, template, address, timestamp, extra_nonce);
+        if mine(&mut block.header) {
+            return block;
+        }
+        extra_nonce += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        address::Payload,
+        block::MAX_BLOCK_WEIGHT,
+        header_chain::{Accepted, ChainParams},
+        mempool::{Mempool, MempoolLimits},
+        network::Network,
+        store::MemoryStore,
+        utxo::UtxoSet,
+    };
+    use sha2::{Digest, Sha256};
+
+    #[test]
+    fn test_generate_chain() {
+        // a P2WSH of OP_1, spendable without signatures
+        let witness_script = Script::new(&[Command::int(1)]);
+        let h256 = Sha256::digest(witness_script.as_bytes()).into();
+        let address = Address::new(Payload::P2wsh(h256), Network::Regtest);
+        let mut chain = HeaderChain::new(ChainParams::new(Network::Regtest));
+        let mut utxos = UtxoSet::open(MemoryStore::new()).unwrap();
+        utxos
+            .connect_block(&Block::genesis(Network::Regtest), chain.get_params())
+            .unwrap();
+        let mut mempool = Mempool::new(MempoolLimits::default());
+
+        let mut blocks = Vec::new();
+        for _ in 0..101 {
+            let template = BlockTemplate::default();
+            let block = generate_block(&chain, &template, &address, chain.tip().timestamp + 600);
+            assert_eq!(chain.add_header(block.header), Ok(Accepted::Extended));
+            utxos.connect_block(&block, chain.get_params()).unwrap();
+            blocks.push(block);
+        }
+        assert_eq!(utxos.len(), 101);
+
+        // the first coinbase is now mature
+        let mut tx_in = TxIn::new(OutPoint::new(blocks[0].txs[0].txid(), 0), SEQUENCE_FINAL);
+        tx_in.witness = vec![witness_script.as_bytes().to_vec()];
+        let spend = Tx::new(
+            2,
+            vec![tx_in],
+            vec![TxOut::new(49_9999_0000, address.script_pubkey())],
+            0,
+        );
+        mempool.accept(spend.clone(), &utxos, &chain).unwrap();
+        let template = mempool.block_template(MAX_BLOCK_WEIGHT - COINBASE_RESERVED_WEIGHT);
+        assert_eq!(template.fees, 10_000);
+
+        let block = generate_block(&chain, &template, &address, 0);
+        assert_eq!(block.check(), Ok(()));
+        assert_eq!(block.check_coinbase_height(102), Ok(()));
+        assert!(block.witness_commitment_index().is_some());
+        assert_eq!(block.txs[1], spend);
+        assert_eq!(block.txs[0].tx_outs[0].amount, 50_0000_0000 + 10_000);
+        // the timestamp was raised past the median time past
+        assert!(block.header.timestamp > chain.median_time_past(&chain.tip().hash()));
+        assert_eq!(chain.add_header(block.header), Ok(Accepted::Extended));
+        utxos.connect_block(&block, chain.get_params()).unwrap();
+        mempool.remove_for_block(&block);
+        assert!(mempool.is_empty());
+        assert_eq!(utxos.height(), Some(102));
+    }
+}
@@ -0,0 +1,1835 @@
+use crate::{
+    base58::hash160,
+    descriptor::{split_args, split_call},
+    interpreter::{
+        LOCKTIME_THRESHOLD, MAX_OPS_PER_SCRIPT, SEQUENCE_LOCKTIME_DISABLE_FLAG,
+        SEQUENCE_LOCKTIME_MASK, SEQUENCE_LOCKTIME_TYPE_FLAG,
+    },
+    script::{
+        Command, Script, OP_0, OP_0NOTEQUAL, OP_1, OP_16, OP_ADD, OP_BOOLAND, OP_BOOLOR,
+        OP_CHECKLOCKTIMEVERIFY, OP_CHECKMULTISIG, OP_CHECKMULTISIGVERIFY, OP_CHECKSEQUENCEVERIFY,
+        OP_CHECKSIG, OP_CHECKSIGADD, OP_CHECKSIGVERIFY, OP_DUP, OP_ELSE, OP_ENDIF, OP_EQUAL,
+        OP_EQUALVERIFY, OP_FROMALTSTACK, OP_HASH160, OP_HASH256, OP_IF, OP_IFDUP, OP_NOTIF,
+        OP_NUMEQUAL, OP_NUMEQUALVERIFY, OP_RIPEMD160, OP_SHA256, OP_SIZE, OP_SWAP, OP_TOALTSTACK,
+        OP_VERIFY,
+    },
+    secp256k1::S256Point,
+    taproot::lift_x,
+    varint::encode_varint,
+};
+use std::{collections::BTreeMap, fmt};
+
+/// Largest standard P2WSH witness script.
+pub const MAX_STANDARD_P2WSH_SCRIPT_SIZE: usize = 3600;
+/// Keys allowed in a `multi()`.
+const MAX_PUBKEYS_PER_MULTISIG: usize = 20;
+/// Keys allowed in a `multi_a()`, bounded by the tapscript stack size.
+const MAX_PUBKEYS_PER_MULTI_A: usize = 999;
+
+/// The script a miniscript is encoded into, which decides its key format
+/// and the multisig fragment it can use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScriptContext {
+    /// 33 byte keys, `multi()`.
+    P2wsh,
+    /// 32 byte x-only keys, `multi_a()` (BIP342).
+    Tapscript,
+}
+
+impl ScriptContext {
+    /// Largest signature with its sighash byte.
+    fn max_sig_size(&self) -> usize {
+        match self {
+            ScriptContext::P2wsh => 73,
+            ScriptContext::Tapscript => 65,
+        }
+    }
+
+    fn key_size(&self) -> usize {
+        match self {
+            ScriptContext::P2wsh => 33,
+            ScriptContext::Tapscript => 32,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MiniscriptError {
+    /// An unknown fragment or wrapper, or the wrong number of arguments.
+    InvalidSyntax,
+    InvalidKey,
+    InvalidHash,
+    /// A timelock of 0 or at least 2^31.
+    InvalidTimelock,
+    InvalidThreshold,
+    /// `multi()` in tapscript or `multi_a()` outside it.
+    InvalidContext,
+    /// The arguments of this fragment do not have the types it needs.
+    TypeCheck(&'static str),
+    /// The top level is not of type B.
+    NotTopLevel,
+    /// Some satisfaction can be changed by a third party.
+    Malleable,
+    /// Some satisfaction needs no signature.
+    NoSignature,
+    /// A satisfaction would need both height and time locks of one kind.
+    TimelockMixing,
+    DuplicateKey,
+    ScriptTooLarge,
+    TooManyOps,
+    /// No non-malleable satisfaction can be made from what the satisfier
+    /// has.
+    Unsatisfiable,
+}
+
+/// The basic type of an expression: what it takes from and leaves on the
+/// stack.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Base {
+    /// Pushes nonzero on satisfaction, an exact 0 on dissatisfaction.
+    B,
+    /// Continues on satisfaction, aborts otherwise.
+    V,
+    /// Pushes a key for a signature check.
+    K,
+    /// Like B, but takes its input from below the top element.
+    W,
+}
+
+/// The type of an expression: its basic type, its correctness modifiers
+/// (`z`, `o`, `n`, `d`, `u`), its malleability properties (`s`, `f`, `e`,
+/// `m`) and the timelock kinds it uses (`g`, `h`, `i`, `j`), with `k` when
+/// no satisfaction mixes heights and times.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Type {
+    pub base: Base,
+    /// Consumes no stack elements.
+    pub z: bool,
+    /// Consumes exactly one stack element.
+    pub o: bool,
+    /// The top input is never zero when satisfying.
+    pub n: bool,
+    /// Has a dissatisfaction that needs no signature.
+    pub d: bool,
+    /// Leaves exactly 1 when satisfied.
+    pub u: bool,
+    /// Every satisfaction needs a signature.
+    pub s: bool,
+    /// Every dissatisfaction needs a signature, or there is none.
+    pub f: bool,
+    /// There is a unique dissatisfaction without signatures.
+    pub e: bool,
+    /// A non-malleable satisfaction always exists.
+    pub m: bool,
+    /// Relative time lock.
+    pub g: bool,
+    /// Relative height lock.
+    pub h: bool,
+    /// Absolute time lock.
+    pub i: bool,
+    /// Absolute height lock.
+    pub j: bool,
+    pub k: bool,
+}
+
+impl Type {
+    /// A type with the properties named by the letters in `props`.
+    fn new(base: Base, props: &str) -> Self {
+        let has = |c| props.contains(c);
+        Self {
+            base,
+            z: has('z'),
+            o: has('o'),
+            n: has('n'),
+            d: has('d'),
+            u: has('u'),
+            s: has('s'),
+            f: has('f'),
+            e: has('e'),
+            m: has('m'),
+            g: false,
+            h: false,
+            i: false,
+            j: false,
+            k: true,
+        }
+    }
+
+    /// Takes the timelocks of both `a` and `b` when only one of them is
+    /// satisfied.
+    fn or_timelocks(mut self, a: &Type, b: &Type) -> Self {
+        self.g = a.g || b.g;
+        self.h = a.h || b.h;
+        self.i = a.i || b.i;
+        self.j = a.j || b.j;
+        self.k = a.k && b.k;
+        self
+    }
+
+    /// Takes the timelocks of both `a` and `b` when both are satisfied
+    /// together, which mixes them if one locks by height and the other by
+    /// time.
+    fn and_timelocks(self, a: &Type, b: &Type) -> Self {
+        let mut ret = self.or_timelocks(a, b);
+        ret.k = ret.k && !((a.g && b.h) || (a.h && b.g) || (a.i && b.j) || (a.j && b.i));
+        ret
+    }
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.base)?;
+        let props = [
+            (self.z, 'z'),
+            (self.o, 'o'),
+            (self.n, 'n'),
+            (self.d, 'd'),
+            (self.u, 'u'),
+            (self.e, 'e'),
+            (self.f, 'f'),
+            (self.s, 's'),
+            (self.m, 'm'),
+            (self.g, 'g'),
+            (self.h, 'h'),
+            (self.i, 'i'),
+            (self.j, 'j'),
+            (self.k, 'k'),
+        ];
+        for (has, c) in props {
+            if has {
+                write!(f, "{}", c)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A Miniscript fragment. The sugar `pk()`, `pkh()`, `and_n()` and the
+/// `t:`, `l:` and `u:` wrappers are parsed into the fragments they stand
+/// for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Fragment {
+    False,
+    True,
+    PkK(Vec<u8>),
+    PkH(Vec<u8>),
+    Older(u32),
+    After(u32),
+    Sha256([u8; 32]),
+    Hash256([u8; 32]),
+    Ripemd160([u8; 20]),
+    Hash160([u8; 20]),
+    AndOr(Box<Miniscript>, Box<Miniscript>, Box<Miniscript>),
+    AndV(Box<Miniscript>, Box<Miniscript>),
+    AndB(Box<Miniscript>, Box<Miniscript>),
+    OrB(Box<Miniscript>, Box<Miniscript>),
+    OrC(Box<Miniscript>, Box<Miniscript>),
+    OrD(Box<Miniscript>, Box<Miniscript>),
+    OrI(Box<Miniscript>, Box<Miniscript>),
+    Thresh(usize, Vec<Miniscript>),
+    Multi(usize, Vec<Vec<u8>>),
+    MultiA(usize, Vec<Vec<u8>>),
+    /// `a:`
+    Alt(Box<Miniscript>),
+    /// `s:`
+    Swap(Box<Miniscript>),
+    /// `c:`
+    Check(Box<Miniscript>),
+    /// `d:`
+    DupIf(Box<Miniscript>),
+    /// `v:`
+    Verify(Box<Miniscript>),
+    /// `j:`
+    NonZero(Box<Miniscript>),
+    /// `n:`
+    ZeroNotEqual(Box<Miniscript>),
+}
+
+/// What a satisfaction can be made from: signatures, hash preimages and
+/// the timelocks of the spending input.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Satisfier {
+    /// Signatures with their sighash byte, by public key as it appears in
+    /// the script.
+    pub signatures: BTreeMap<Vec<u8>, Vec<u8>>,
+    pub sha256_preimages: BTreeMap<[u8; 32], Vec<u8>>,
+    pub hash256_preimages: BTreeMap<[u8; 32], Vec<u8>>,
+    pub ripemd160_preimages: BTreeMap<[u8; 20], Vec<u8>>,
+    pub hash160_preimages: BTreeMap<[u8; 20], Vec<u8>>,
+    /// nSequence of the spending input, for `older()`.
+    pub sequence: u32,
+    /// nLockTime of the spending transaction, for `after()`.
+    pub locktime: u32,
+}
+
+impl Satisfier {
+    /// Whether `sequence` meets a BIP68 relative lock of `n`.
+    fn check_older(&self, n: u32) -> bool {
+        self.sequence & SEQUENCE_LOCKTIME_DISABLE_FLAG == 0
+            && n & SEQUENCE_LOCKTIME_TYPE_FLAG == self.sequence & SEQUENCE_LOCKTIME_TYPE_FLAG
+            && n & SEQUENCE_LOCKTIME_MASK <= self.sequence & SEQUENCE_LOCKTIME_MASK
+    }
+
+    fn check_after(&self, n: u32) -> bool {
+        (n < LOCKTIME_THRESHOLD) == (self.locktime < LOCKTIME_THRESHOLD) && n <= self.locktime
+    }
+}
+
+/// A candidate witness for part of a script, as in Bitcoin Core's
+/// `InputStack`.
+#[derive(Debug, Clone)]
+struct Witness {
+    /// `None` when it cannot be made.
+    stack: Option<Vec<Vec<u8>>>,
+    has_sig: bool,
+    /// A third party could replace it with another valid witness.
+    malleable: bool,
+}
+
+impl Witness {
+    fn invalid() -> Self {
+        Self {
+            stack: None,
+            has_sig: false,
+            malleable: false,
+        }
+    }
+
+    fn empty() -> Self {
+        Self {
+            stack: Some(Vec::new()),
+            has_sig: false,
+            malleable: false,
+        }
+    }
+
+    fn push(item: Vec<u8>) -> Self {
+        Self {
+            stack: Some(vec![item]),
+            has_sig: false,
+            malleable: false,
+        }
+    }
+
+    /// `item` if it is available.
+    fn push_if(item: Option<Vec<u8>>) -> Self {
+        item.map_or_else(Self::invalid, Self::push)
+    }
+
+    fn zero() -> Self {
+        Self::push(Vec::new())
+    }
+
+    fn one() -> Self {
+        Self::push(vec![1])
+    }
+
+    fn with_sig(mut self) -> Self {
+        self.has_sig = true;
+        self
+    }
+
+    fn malleable(mut self) -> Self {
+        self.malleable = true;
+        self
+    }
+
+    fn size(&self) -> usize {
+        self.stack.as_ref().map_or(0, |stack| {
+            stack
+                .iter()
+                .map(|item| encode_varint(item.len() as u64).len() + item.len())
+                .sum()
+        })
+    }
+
+    /// This witness with `top` pushed after it, so `top` is consumed
+    /// first.
+    fn then(self, top: Witness) -> Self {
+        let stack = match (self.stack, top.stack) {
+            (Some(mut a), Some(b)) => {
+                a.extend(b);
+                Some(a)
+            }
+            _ => None,
+        };
+        Self {
+            stack,
+            has_sig: self.has_sig || top.has_sig,
+            malleable: self.malleable || top.malleable,
+        }
+    }
+
+    /// Picks between two ways of satisfying the same thing the way
+    /// Bitcoin Core does: one that needs no signature wins, since a third
+    /// party could otherwise use it instead; then a non-malleable one; then
+    /// the smaller.
+    fn or(mut self, mut other: Witness) -> Self {
+        if self.stack.is_none() {
+            return other;
+        }
+        if other.stack.is_none() {
+            return self;
+        }
+        match (self.has_sig, other.has_sig) {
+            (false, true) => return self,
+            (true, false) => return other,
+            (false, false) => {
+                self.malleable = true;
+                other.malleable = true;
+            }
+            (true, true) => {
+                if self.malleable != other.malleable {
+                    return if self.malleable { other } else { self };
+                }
+            }
+        }
+        if self.size() <= other.size() {
+            self
+        } else {
+            other
+        }
+    }
+}
+
+/// Largest satisfaction and dissatisfaction of an expression in witness
+/// bytes, each stack element with its length prefix.
+type WitnessSizes = (Option<usize>, Option<usize>);
+
+fn add_sizes(a: Option<usize>, b: Option<usize>) -> Option<usize> {
+    Some(a? + b?)
+}
+
+fn max_size(a: Option<usize>, b: Option<usize>) -> Option<usize> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.max(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+/// A Miniscript expression with its type, valid in `ctx`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Miniscript {
+    pub fragment: Fragment,
+    pub ty: Type,
+    pub ctx: ScriptContext,
+}
+
+fn parse_key(s: &str, ctx: ScriptContext) -> Result<Vec<u8>, MiniscriptError> {
+    let key = hex::decode(s).map_err(|_| MiniscriptError::InvalidKey)?;
+    let valid = match ctx {
+        ScriptContext::P2wsh => key.len() == 33 && S256Point::try_parse(&key).is_some(),
+        ScriptContext::Tapscript => {
+            key.len() == 32 && lift_x(key[..].try_into().unwrap()).is_some()
+        }
+    };
+    if !valid {
+        return Err(MiniscriptError::InvalidKey);
+    }
+    Ok(key)
+}
+
+fn parse_hash<const N: usize>(s: &str) -> Result<[u8; N], MiniscriptError> {
+    hex::decode(s)
+        .ok()
+        .and_then(|h| h.try_into().ok())
+        .ok_or(MiniscriptError::InvalidHash)
+}
+
+fn parse_timelock(s: &str) -> Result<u32, MiniscriptError> {
+    match s.parse::<u32>() {
+        Ok(n) if n != 0 && n < 1 << 31 => Ok(n),
+        _ => Err(MiniscriptError::InvalidTimelock),
+    }
+}
+
+fn parse_threshold(s: &str, n: usize) -> Result<usize, MiniscriptError> {
+    match s.parse() {
+        Ok(k) if k >= 1 && k <= n => Ok(k),
+        _ => Err(MiniscriptError::InvalidThreshold),
+    }
+}
+
+impl Miniscript {
+    /// Type checks `fragment` in `ctx`. Its sub-expressions must already be
+    /// in `ctx`.
+    pub fn new(fragment: Fragment, ctx: ScriptContext) -> Result<Self, MiniscriptError> {
+        let ty = Self::type_check(&fragment, ctx)?;
+        Ok(Self { fragment, ty, ctx })
+    }
+
+    fn type_check(fragment: &Fragment, ctx: ScriptContext) -> Result<Type, MiniscriptError> {
+        use Base::*;
+        Ok(match fragment {
+            Fragment::False => Type::new(B, "zudesm"),
+            Fragment::True => Type::new(B, "zufm"),
+            Fragment::PkK(_) => Type::new(K, "onduesm"),
+            Fragment::PkH(_) => Type::new(K, "nduesm"),
+            Fragment::Older(n) => {
+                let mut ty = Type::new(B, "zfm");
+                if n & SEQUENCE_LOCKTIME_TYPE_FLAG != 0 {
+                    ty.g = true;
+                } else {
+                    ty.h = true;
+                }
+                ty
+            }
+            Fragment::After(n) => {
+                let mut ty = Type::new(B, "zfm");
+                if *n >= LOCKTIME_THRESHOLD {
+                    ty.i = true;
+                } else {
+                    ty.j = true;
+                }
+                ty
+            }
+            Fragment::Sha256(_)
+            | Fragment::Hash256(_)
+            | Fragment::Ripemd160(_)
+            | Fragment::Hash160(_) => Type::new(B, "ondum"),
+            Fragment::AndOr(x, y, z) => {
+                let (x, y, z) = (&x.ty, &y.ty, &z.ty);
+                if x.base != B || !x.d || !x.u || y.base != z.base || y.base == W {
+                    return Err(MiniscriptError::TypeCheck("andor"));
+                }
+                Type {
+                    base: y.base,
+                    z: x.z && y.z && z.z,
+                    o: (x.z && y.o && z.o) || (x.o && y.z && z.z),
+                    n: false,
+                    d: z.d,
+                    u: y.u && z.u,
+                    s: z.s && (x.s || y.s),
+                    f: z.f && (x.s || y.f),
+                    e: z.e && (x.s || y.f),
+                    m: x.m && y.m && z.m && x.e && (x.s || y.s || z.s),
+                    ..Type::new(B, "")
+                }
+                .or_timelocks(&Type::new(B, "").and_timelocks(x, y), z)
+            }
+            Fragment::AndV(x, y) => {
+                let (x, y) = (&x.ty, &y.ty);
+                if x.base != V || y.base == W {
+                    return Err(MiniscriptError::TypeCheck("and_v"));
+                }
+                Type {
+                    base: y.base,
+                    z: x.z && y.z,
+                    o: (x.z && y.o) || (x.o && y.z),
+                    n: x.n || (x.z && y.n),
+                    d: false,
+                    u: y.u,
+                    s: x.s || y.s,
+                    f: y.f || x.s,
+                    e: false,
+                    m: x.m && y.m,
+                    ..Type::new(B, "")
+                }
+                .and_timelocks(x, y)
+            }
+            Fragment::AndB(x, y) => {
+                let (x, y) = (&x.ty, &y.ty);
+                if x.base != B || y.base != W {
+                    return Err(MiniscriptError::TypeCheck("and_b"));
+                }
+                Type {
+                    base: B,
+                    z: x.z && y.z,
+                    o: (x.z && y.o) || (x.o && y.z),
+                    n: x.n || (x.z && y.n),
+                    d: x.d && y.d,
+                    u: true,
+                    s: x.s || y.s,
+                    f: (x.f && y.f) || (x.s && x.f) || (y.s && y.f),
+                    e: x.e && y.e && x.s && y.s,
+                    m: x.m && y.m,
+                    ..Type::new(B, "")
+                }
+                .and_timelocks(x, y)
+            }
+            Fragment::OrB(x, z) => {
+                let (x, z) = (&x.ty, &z.ty);
+                if x.base != B || !x.d || z.base != W || !z.d {
+                    return Err(MiniscriptError::TypeCheck("or_b"));
+                }
+                Type {
+                    base: B,
+                    z: x.z && z.z,
+                    o: (x.z && z.o) || (x.o && z.z),
+                    d: true,
+                    u: true,
+                    s: x.s && z.s,
+                    e: x.e && z.e,
+                    m: x.m && z.m && x.e && z.e && (x.s || z.s),
+                    ..Type::new(B, "")
+                }
+                .or_timelocks(x, z)
+            }
+            Fragment::OrC(x, z) => {
+                let (x, z) = (&x.ty, &z.ty);
+                if x.base != B || !x.d || !x.u || z.base != V {
+                    return Err(MiniscriptError::TypeCheck("or_c"));
+                }
+                Type {
+                    base: V,
+                    z: x.z && z.z,
+                    o: x.o && z.z,
+                    s: x.s && z.s,
+                    f: true,
+                    m: x.m && z.m && x.e && (x.s || z.s),
+                    ..Type::new(B, "")
+                }
+                .or_timelocks(x, z)
+            }
+            Fragment::OrD(x, z) => {
+                let (x, z) = (&x.ty, &z.ty);
+                if x.base != B || !x.d || !x.u || z.base != B {
+                    return Err(MiniscriptError::TypeCheck("or_d"));
+                }
+                Type {
+                    base: B,
+                    z: x.z && z.z,
+                    o: x.o && z.z,
+                    d: z.d,
+                    u: z.u,
+                    s: x.s && z.s,
+                    f: z.f,
+                    e: x.e && z.e,
+                    m: x.m && z.m && x.e && (x.s || z.s),
+                    ..Type::new(B, "")
+                }
+                .or_timelocks(x, z)
+            }
+            Fragment::OrI(x, z) => {
+                let (x, z) = (&x.ty, &z.ty);
+                if x.base != z.base || x.base == W {
+                    return Err(MiniscriptError::TypeCheck("or_i"));
+                }
+                Type {
+                    base: x.base,
+                    o: x.z && z.z,
+                    d: x.d || z.d,
+                    u: x.u && z.u,
+                    s: x.s && z.s,
+                    f: x.f && z.f,
+                    e: (x.e && z.f) || (z.e && x.f),
+                    m: x.m && z.m && (x.s || z.s),
+                    ..Type::new(B, "")
+                }
+                .or_timelocks(x, z)
+            }
+            Fragment::Thresh(k, subs) => {
+                let valid = subs.iter().enumerate().all(|(i, sub)| {
+                    let base = if i == 0 { B } else { W };
+                    sub.ty.base == base && sub.ty.d && sub.ty.u
+                });
+                if !valid {
+                    return Err(MiniscriptError::TypeCheck("thresh"));
+                }
+                let n = subs.len();
+                let count = |f: fn(&Type) -> bool| subs.iter().filter(|sub| f(&sub.ty)).count();
+                let (zs, os, ss) = (count(|t| t.z), count(|t| t.o), count(|t| t.s));
+                let all_e = count(|t| t.e) == n;
+                let mut ty = Type {
+                    base: B,
+                    z: zs == n,
+                    o: zs == n - 1 && os == 1,
+                    d: true,
+                    u: true,
+                    s: ss > n - k,
+                    e: all_e && ss == n,
+                    m: all_e && count(|t| t.m) == n && ss >= n - k,
+                    ..Type::new(B, "")
+                };
+                for (i, a) in subs.iter().enumerate() {
+                    ty = ty.or_timelocks(&ty, &a.ty);
+                    if *k > 1 {
+                        for b in &subs[i + 1..] {
+                            ty.k &= Type::new(B, "").and_timelocks(&a.ty, &b.ty).k;
+                        }
+                    }
+                }
+                ty
+            }
+            Fragment::Multi(_, keys) => {
+                if ctx != ScriptContext::P2wsh || keys.len() > MAX_PUBKEYS_PER_MULTISIG {
+                    return Err(MiniscriptError::InvalidContext);
+                }
+                Type::new(B, "nudesm")
+            }
+            Fragment::MultiA(_, keys) => {
+                if ctx != ScriptContext::Tapscript || keys.len() > MAX_PUBKEYS_PER_MULTI_A {
+                    return Err(MiniscriptError::InvalidContext);
+                }
+                Type::new(B, "udesm")
+            }
+            Fragment::Alt(x) | Fragment::Swap(x) => {
+                let x = &x.ty;
+                if x.base != B || (matches!(fragment, Fragment::Swap(_)) && !x.o) {
+                    return Err(MiniscriptError::TypeCheck("a: or s:"));
+                }
+                Type {
+                    base: W,
+                    z: false,
+                    o: false,
+                    n: false,
+                    ..*x
+                }
+            }
+            Fragment::Check(x) => {
+                let x = &x.ty;
+                if x.base != K {
+                    return Err(MiniscriptError::TypeCheck("c:"));
+                }
+                Type {
+                    base: B,
+                    z: false,
+                    u: true,
+                    s: true,
+                    ..*x
+                }
+            }
+            Fragment::DupIf(x) => {
+                let x = &x.ty;
+                if x.base != V || !x.z {
+                    return Err(MiniscriptError::TypeCheck("d:"));
+                }
+                Type {
+                    base: B,
+                    z: false,
+                    o: true,
+                    n: true,
+                    d: true,
+                    u: ctx == ScriptContext::Tapscript,
+                    f: false,
+                    e: true,
+                    ..*x
+                }
+            }
+            Fragment::Verify(x) => {
+                let x = &x.ty;
+                if x.base != B {
+                    return Err(MiniscriptError::TypeCheck("v:"));
+                }
+                Type {
+                    base: V,
+                    d: false,
+                    u: false,
+                    f: true,
+                    e: false,
+                    ..*x
+                }
+            }
+            Fragment::NonZero(x) => {
+                let x = &x.ty;
+                if x.base != B || !x.n {
+                    return Err(MiniscriptError::TypeCheck("j:"));
+                }
+                Type {
+                    z: false,
+                    n: true,
+                    d: true,
+                    f: false,
+                    e: x.f,
+                    ..*x
+                }
+            }
+            Fragment::ZeroNotEqual(x) => {
+                let x = &x.ty;
+                if x.base != B {
+                    return Err(MiniscriptError::TypeCheck("n:"));
+                }
+                Type { u: true, ..*x }
+            }
+        })
+    }
+
+    /// Parses the string form, e.g. `and_v(v:pk(K),older(144))`, with hex
+    /// keys in the format of `ctx`.
+    pub fn parse(s: &str, ctx: ScriptContext) -> Result<Self, MiniscriptError> {
+        let colon = s.find(':').filter(|&i| !s[..i].contains('('));
+        if let Some(i) = colon {
+            let mut ret = Self::parse(&s[i + 1..], ctx)?;
+            for wrapper in s[..i].chars().rev() {
+                let x = Box::new(ret);
+                let fragment = match wrapper {
+                    'a' => Fragment::Alt(x),
+                    's' => Fragment::Swap(x),
+                    'c' => Fragment::Check(x),
+                    'd' => Fragment::DupIf(x),
+                    'v' => Fragment::Verify(x),
+                    'j' => Fragment::NonZero(x),
+                    'n' => Fragment::ZeroNotEqual(x),
+                    't' => Fragment::AndV(x, Box::new(Self::new(Fragment::True, ctx)?)),
+                    'l' => Fragment::OrI(Box::new(Self::new(Fragment::False, ctx)?), x),
+                    'u' => Fragment::OrI(x, Box::new(Self::new(Fragment::False, ctx)?)),
+                    _ => return Err(MiniscriptError::InvalidSyntax),
+                };
+                ret = Self::new(fragment, ctx)?;
+            }
+            return Ok(ret);
+        }
+        match s {
+            "0" => return Self::new(Fragment::False, ctx),
+            "1" => return Self::new(Fragment::True, ctx),
+            _ => {}
+        }
+
+        let (name, args) = split_call(s).ok_or(MiniscriptError::InvalidSyntax)?;
+        let args = split_args(args);
+        let sub = |i: usize| -> Result<Box<Self>, MiniscriptError> {
+            Ok(Box::new(Self::parse(args[i], ctx)?))
+        };
+        let arity = match name {
+            "pk_k" | "pk_h" | "pk" | "pkh" | "older" | "after" | "sha256" | "hash256"
+            | "ripemd160" | "hash160" => 1,
+            "and_v" | "and_b" | "and_n" | "or_b" | "or_c" | "or_d" | "or_i" => 2,
+            "andor" => 3,
+            "thresh" | "multi" | "multi_a" => args.len().max(2),
+            _ => return Err(MiniscriptError::InvalidSyntax),
+        };
+        if args.len() != arity {
+            return Err(MiniscriptError::InvalidSyntax);
+        }
+        let fragment = match name {
+            "pk_k" => Fragment::PkK(parse_key(args[0], ctx)?),
+            "pk_h" => Fragment::PkH(parse_key(args[0], ctx)?),
+            "pk" | "pkh" => {
+                let key = parse_key(args[0], ctx)?;
+                let inner = if name == "pk" {
+                    Fragment::PkK(key)
+                } else {
+                    Fragment::PkH(key)
+                };
+                Fragment::Check(Box::new(Self::new(inner, ctx)?))
+            }
+            "older" => Fragment::Older(parse_timelock(args[0])?),
+            "after" => Fragment::After(parse_timelock(args[0])?),
+            "sha256" => Fragment::Sha256(parse_hash(args[0])?),
+            "hash256" => Fragment::Hash256(parse_hash(args[0])?),
+            "ripemd160" => Fragment::Ripemd160(parse_hash(args[0])?),
+            "hash160" => Fragment::Hash160(parse_hash(args[0])?),
+            "andor" => Fragment::AndOr(sub(0)?, sub(1)?, sub(2)?),
+            "and_v" => Fragment::AndV(sub(0)?, sub(1)?),
+            "and_b" => Fragment::AndB(sub(0)?, sub(1)?),
+            "and_n" => {
+                Fragment::AndOr(sub(0)?, sub(1)?, Box::new(Self::new(Fragment::False, ctx)?))
+            }
+            "or_b" => Fragment::OrB(sub(0)?, sub(1)?),
+            "or_c" => Fragment::OrC(sub(0)?, sub(1)?),
+            "or_d" => Fragment::OrD(sub(0)?, sub(1)?),
+            "or_i" => Fragment::OrI(sub(0)?, sub(1)?),
+            "thresh" => {
+                let k = parse_threshold(args[0], args.len() - 1)?;
+                let subs = (1..args.len())
+                    .map(|i| Self::parse(args[i], ctx))
+                    .collect::<Result<_, _>>()?;
+                Fragment::Thresh(k, subs)
+            }
+            "multi" | "multi_a" => {
+                let k = parse_threshold(args[0], args.len() - 1)?;
+                let keys = args[1..]
+                    .iter()
+                    .map(|key| parse_key(key, ctx))
+                    .collect::<Result<_, _>>()?;
+                if name == "multi" {
+                    Fragment::Multi(k, keys)
+                } else {
+                    Fragment::MultiA(k, keys)
+                }
+            }
+            _ => unreachable!(),
+        };
+        Self::new(fragment, ctx)
+    }
+
+    fn commands(&self) -> Vec<Command> {
+        use Command::{Data, Op};
+        let hash_check = |op, hash: &[u8]| {
+            vec![
+                Op(OP_SIZE),
+                Command::int(32),
+                Op(OP_EQUALVERIFY),
+                Op(op),
+                Data(hash.to_vec()),
+                Op(OP_EQUAL),
+            ]
+        };
+        let join = |parts: Vec<Vec<Command>>| parts.concat();
+        match &self.fragment {
+            Fragment::False => vec![Op(OP_0)],
+            Fragment::True => vec![Op(OP_1)],
+            Fragment::PkK(key) => vec![Data(key.clone())],
+            Fragment::PkH(key) => vec![
+                Op(OP_DUP),
+                Op(OP_HASH160),
+                Data(hash160(key)),
+                Op(OP_EQUALVERIFY),
+            ],
+            Fragment::Older(n) => vec![Command::int(*n as i64), Op(OP_CHECKSEQUENCEVERIFY)],
+            Fragment::After(n) => vec![Command::int(*n as i64), Op(OP_CHECKLOCKTIMEVERIFY)],
+            Fragment::Sha256(h) => hash_check(OP_SHA256, h),
+            Fragment::Hash256(h) => hash_check(OP_HASH256, h),
+            Fragment::Ripemd160(h) => hash_check(OP_RIPEMD160, h),
+            Fragment::Hash160(h) => hash_check(OP_HASH160, h),
+            Fragment::AndOr(x, y, z) => join(vec![
+                x.commands(),
+                vec![Op(OP_NOTIF)],
+                z.commands(),
+                vec![Op(OP_ELSE)],
+                y.commands(),
+                vec![Op(OP_ENDIF)],
+            ]),
+            Fragment::AndV(x, y) => join(vec![x.commands(), y.commands()]),
+            Fragment::AndB(x, y) => join(vec![x.commands(), y.commands(), vec![Op(OP_BOOLAND)]]),
+            Fragment::OrB(x, z) => join(vec![x.commands(), z.commands(), vec![Op(OP_BOOLOR)]]),
+            Fragment::OrC(x, z) => join(vec![
+                x.commands(),
+                vec![Op(OP_NOTIF)],
+                z.commands(),
+                vec![Op(OP_ENDIF)],
+            ]),
+            Fragment::OrD(x, z) => join(vec![
+                x.commands(),
+                vec![Op(OP_IFDUP), Op(OP_NOTIF)],
+                z.commands(),
+                vec![Op(OP_ENDIF)],
+            ]),
+            Fragment::OrI(x, z) => join(vec![
+                vec![Op(OP_IF)],
+                x.commands(),
+                vec![Op(OP_ELSE)],
+                z.commands(),
+                vec![Op(OP_ENDIF)],
+            ]),
+            Fragment::Thresh(k, subs) => {
+                let mut ret = subs[0].commands();
+                for sub in &subs[1..] {
+                    ret.extend(sub.commands());
+                    ret.push(Op(OP_ADD));
+                }
+                ret.extend([Command::int(*k as i64), Op(OP_EQUAL)]);
+                ret
+            }
+            Fragment::Multi(k, keys) => {
+                let mut ret = vec![Command::int(*k as i64)];
+                ret.extend(keys.iter().map(|key| Data(key.clone())));
+                ret.extend([Command::int(keys.len() as i64), Op(OP_CHECKMULTISIG)]);
+                ret
+            }
+            Fragment::MultiA(k, keys) => {
+                let mut ret = vec![Data(keys[0].clone()), Op(OP_CHECKSIG)];
+                for key in &keys[1..] {
+                    ret.extend([Data(key.clone()), Op(OP_CHECKSIGADD)]);
+                }
+                ret.extend([Command::int(*k as i64), Op(OP_NUMEQUAL)]);
+                ret
+            }
+            Fragment::Alt(x) => join(vec![
+                vec![Op(OP_TOALTSTACK)],
+                x.commands(),
+                vec![Op(OP_FROMALTSTACK)],
+            ]),
+            Fragment::Swap(x) => join(vec![vec![Op(OP_SWAP)], x.commands()]),
+            Fragment::Check(x) => join(vec![x.commands(), vec![Op(OP_CHECKSIG)]]),
+            Fragment::DupIf(x) => join(vec![
+                vec![Op(OP_DUP), Op(OP_IF)],
+                x.commands(),
+                vec![Op(OP_ENDIF)],
+            ]),
+            Fragment::Verify(x) => {
+                let mut ret = x.commands();
+                // fold the VERIFY into the last opcode where there is a
+                // VERIFY form of it
+                let verify_op = match ret.last() {
+                    Some(Op(OP_EQUAL)) => Some(OP_EQUALVERIFY),
+                    Some(Op(OP_CHECKSIG)) => Some(OP_CHECKSIGVERIFY),
+                    Some(Op(OP_CHECKMULTISIG)) => Some(OP_CHECKMULTISIGVERIFY),
+                    Some(Op(OP_NUMEQUAL)) => Some(OP_NUMEQUALVERIFY),
+                    _ => None,
+                };
+                match verify_op {
+                    Some(op) => *ret.last_mut().unwrap() = Op(op),
+                    None => ret.push(Op(OP_VERIFY)),
+                }
+                ret
+            }
+            Fragment::NonZero(x) => join(vec![
+                vec![Op(OP_SIZE), Op(OP_0NOTEQUAL), Op(OP_IF)],
+                x.commands(),
+                vec![Op(OP_ENDIF)],
+            ]),
+            Fragment::ZeroNotEqual(x) => join(vec![x.commands(), vec![Op(OP_0NOTEQUAL)]]),
+        }
+    }
+
+    /// The script the expression encodes to.
+    pub fn script(&self) -> Script {
+        Script::new(&self.commands())
+    }
+
+    /// The keys the expression checks signatures against.
+    pub fn keys(&self) -> Vec<&[u8]> {
+        match &self.fragment {
+            Fragment::PkK(key) | Fragment::PkH(key) => vec![key],
+            Fragment::Multi(_, keys) | Fragment::MultiA(_, keys) => {
+                keys.iter().map(|k| &k[..]).collect()
+            }
+            Fragment::AndOr(x, y, z) => [x.keys(), y.keys(), z.keys()].concat(),
+            Fragment::AndV(x, y)
+            | Fragment::AndB(x, y)
+            | Fragment::OrB(x, y)
+            | Fragment::OrC(x, y)
+            | Fragment::OrD(x, y)
+            | Fragment::OrI(x, y) => [x.keys(), y.keys()].concat(),
+            Fragment::Thresh(_, subs) => subs.iter().flat_map(|sub| sub.keys()).collect(),
+            Fragment::Alt(x)
+            | Fragment::Swap(x)
+            | Fragment::Check(x)
+            | Fragment::DupIf(x)
+            | Fragment::Verify(x)
+            | Fragment::NonZero(x)
+            | Fragment::ZeroNotEqual(x) => x.keys(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Checks what Bitcoin Core requires of a top-level miniscript: type
+    /// B, non-malleable, needing a signature, no timelock mixing, no
+    /// repeated keys and within the P2WSH size and opcode limits.
+    pub fn sanity_check(&self) -> Result<(), MiniscriptError> {
+        if self.ty.base != Base::B {
+            return Err(MiniscriptError::NotTopLevel);
+        }
+        if !self.ty.m {
+            return Err(MiniscriptError::Malleable);
+        }
+        if !self.ty.s {
+            return Err(MiniscriptError::NoSignature);
+        }
+        if !self.ty.k {
+            return Err(MiniscriptError::TimelockMixing);
+        }
+        let mut keys = self.keys();
+        keys.sort();
+        if keys.windows(2).any(|w| w[0] == w[1]) {
+            return Err(MiniscriptError::DuplicateKey);
+        }
+        if self.ctx == ScriptContext::P2wsh {
+            let cmds = self.commands();
+            if Script::new(&cmds).len() > MAX_STANDARD_P2WSH_SCRIPT_SIZE {
+                return Err(MiniscriptError::ScriptTooLarge);
+            }
+            // CHECKMULTISIG counts its keys as executed opcodes
+            let ops: usize = cmds
+                .iter()
+                .map(|cmd| match cmd {
+                    Command::Op(op) if *op > OP_16 => 1,
+                    _ => 0,
+                })
+                .sum::<usize>()
+                + self.multisig_keys();
+            if ops > MAX_OPS_PER_SCRIPT {
+                return Err(MiniscriptError::TooManyOps);
+            }
+        }
+        Ok(())
+    }
+
+    fn multisig_keys(&self) -> usize {
+        match &self.fragment {
+            Fragment::Multi(_, keys) => keys.len(),
+            Fragment::AndOr(x, y, z) => x.multisig_keys() + y.multisig_keys() + z.multisig_keys(),
+            Fragment::AndV(x, y)
+            | Fragment::AndB(x, y)
+            | Fragment::OrB(x, y)
+            | Fragment::OrC(x, y)
+            | Fragment::OrD(x, y)
+            | Fragment::OrI(x, y) => x.multisig_keys() + y.multisig_keys(),
+            Fragment::Thresh(_, subs) => subs.iter().map(|sub| sub.multisig_keys()).sum(),
+            Fragment::Alt(x)
+            | Fragment::Swap(x)
+            | Fragment::Check(x)
+            | Fragment::DupIf(x)
+            | Fragment::Verify(x)
+            | Fragment::NonZero(x)
+            | Fragment::ZeroNotEqual(x) => x.multisig_keys(),
+            _ => 0,
+        }
+    }
+
+    /// The best dissatisfaction and satisfaction, following Bitcoin Core's
+    /// `ProduceInput`.
+    fn produce(&self, satisfier: &Satisfier) -> (Witness, Witness) {
+        let sign =
+            |key: &Vec<u8>| Witness::push_if(satisfier.signatures.get(key).cloned()).with_sig();
+        // a 32 byte value that is not the preimage; anyone can pick another
+        let zero32 = || Witness::push(vec![0; 32]).malleable();
+        match &self.fragment {
+            Fragment::False => (Witness::empty(), Witness::invalid()),
+            Fragment::True => (Witness::invalid(), Witness::empty()),
+            Fragment::PkK(key) => (Witness::zero(), sign(key)),
+            Fragment::PkH(key) => (
+                Witness::zero().then(Witness::push(key.clone())),
+                sign(key).then(Witness::push(key.clone())),
+            ),
+            Fragment::Older(n) => (
+                Witness::invalid(),
+                if satisfier.check_older(*n) {
+                    Witness::empty()
+                } else {
+                    Witness::invalid()
+                },
+            ),
+            Fragment::After(n) => (
+                Witness::invalid(),
+                if satisfier.check_after(*n) {
+                    Witness::empty()
+                } else {
+                    Witness::invalid()
+                },
+            ),
+            Fragment::Sha256(h) => (
+                zero32(),
+                Witness::push_if(satisfier.sha256_preimages.get(h).cloned()),
+            ),
+            Fragment::Hash256(h) => (
+                zero32(),
+                Witness::push_if(satisfier.hash256_preimages.get(h).cloned()),
+            ),
+            Fragment::Ripemd160(h) => (
+                zero32(),
+                Witness::push_if(satisfier.ripemd160_preimages.get(h).cloned()),
+            ),
+            Fragment::Hash160(h) => (
+                zero32(),
+                Witness::push_if(satisfier.hash160_preimages.get(h).cloned()),
+            ),
+            Fragment::AndOr(x, y, z) => {
+                let ((x_dsat, x_sat), (y_dsat, y_sat), (z_dsat, z_sat)) = (
+                    x.produce(satisfier),
+                    y.produce(satisfier),
+                    z.produce(satisfier),
+                );
+                (
+                    y_dsat.then(x_sat.clone()).or(z_dsat.then(x_dsat.clone())),
+                    y_sat.then(x_sat).or(z_sat.then(x_dsat)),
+                )
+            }
+            Fragment::AndV(x, y) => {
+                let ((_, x_sat), (y_dsat, y_sat)) = (x.produce(satisfier), y.produce(satisfier));
+                (y_dsat.then(x_sat.clone()), y_sat.then(x_sat))
+            }
+            Fragment::AndB(x, y) => {
+                let ((x_dsat, x_sat), (y_dsat, y_sat)) =
+                    (x.produce(satisfier), y.produce(satisfier));
+                (
+                    y_dsat
+                        .clone()
+                        .then(x_dsat.clone())
+                        .or(y_sat.clone().then(x_dsat).malleable())
+                        .or(y_dsat.then(x_sat.clone()).malleable()),
+                    y_sat.then(x_sat),
+                )
+            }
+            Fragment::OrB(x, z) => {
+                let ((x_dsat, x_sat), (z_dsat, z_sat)) =
+                    (x.produce(satisfier), z.produce(satisfier));
+                (
+                    z_dsat.clone().then(x_dsat.clone()),
+                    z_dsat
+                        .then(x_sat.clone())
+                        .or(z_sat.clone().then(x_dsat))
+                        .or(z_sat.then(x_sat).malleable()),
+                )
+            }
+            Fragment::OrC(x, z) => {
+                let ((x_dsat, x_sat), (_, z_sat)) = (x.produce(satisfier), z.produce(satisfier));
+                (Witness::invalid(), x_sat.or(z_sat.then(x_dsat)))
+            }
+            Fragment::OrD(x, z) => {
+                let ((x_dsat, x_sat), (z_dsat, z_sat)) =
+                    (x.produce(satisfier), z.produce(satisfier));
+                (z_dsat.then(x_dsat.clone()), x_sat.or(z_sat.then(x_dsat)))
+            }
+            Fragment::OrI(x, z) => {
+                let ((x_dsat, x_sat), (z_dsat, z_sat)) =
+                    (x.produce(satisfier), z.produce(satisfier));
+                (
+                    x_dsat.then(Witness::one()).or(z_dsat.then(Witness::zero())),
+                    x_sat.then(Witness::one()).or(z_sat.then(Witness::zero())),
+                )
+            }
+            Fragment::Thresh(k, subs) => {
+                // sats[j]: the best witness with j of the subs satisfied,
+                // built from the last sub since the first one runs first
+                let mut sats = vec![Witness::empty()];
+                for sub in subs.iter().rev() {
+                    let (dsat, sat) = sub.produce(satisfier);
+                    let mut next = vec![sats[0].clone().then(dsat.clone())];
+                    for j in 1..sats.len() {
+                        next.push(
+                            sats[j]
+                                .clone()
+                                .then(dsat.clone())
+                                .or(sats[j - 1].clone().then(sat.clone())),
+                        );
+                    }
+                    next.push(sats.last().unwrap().clone().then(sat));
+                    sats = next;
+                }
+                let mut dsat = Witness::invalid();
+                for (j, witness) in sats.iter().enumerate() {
+                    if j == 0 {
+                        dsat = dsat.or(witness.clone());
+                    } else if j != *k {
+                        dsat = dsat.or(witness.clone().malleable());
+                    }
+                }
+                (dsat, sats.swap_remove(*k))
+            }
+            Fragment::Multi(k, keys) => {
+                // the dummy element CHECKMULTISIG pops, then signatures in
+                // key order
+                let mut sats = vec![Witness::zero()];
+                for key in keys {
+                    let sat = sign(key);
+                    let mut next = vec![sats[0].clone()];
+                    for j in 1..sats.len() {
+                        next.push(sats[j].clone().or(sats[j - 1].clone().then(sat.clone())));
+                    }
+                    next.push(sats.last().unwrap().clone().then(sat));
+                    sats = next;
+                }
+                let dsat = (0..*k).fold(Witness::zero(), |w, _| w.then(Witness::zero()));
+                (dsat, sats.swap_remove(*k))
+            }
+            Fragment::MultiA(k, keys) => {
+                // one element per key, the first key's on top
+                let mut sats = vec![Witness::empty()];
+                for key in keys.iter().rev() {
+                    let sat = sign(key);
+                    let mut next = vec![sats[0].clone().then(Witness::zero())];
+                    for j in 1..sats.len() {
+                        next.push(
+                            sats[j]
+                                .clone()
+                                .then(Witness::zero())
+                                .or(sats[j - 1].clone().then(sat.clone())),
+                        );
+                    }
+                    next.push(sats.last().unwrap().clone().then(sat));
+                    sats = next;
+                }
+                let dsat = sats[0].clone();
+                (dsat, sats.swap_remove(*k))
+            }
+            Fragment::Alt(x)
+            | Fragment::Swap(x)
+            | Fragment::Check(x)
+            | Fragment::ZeroNotEqual(x) => x.produce(satisfier),
+            Fragment::DupIf(x) => {
+                let (_, x_sat) = x.produce(satisfier);
+                (Witness::zero(), x_sat.then(Witness::one()))
+            }
+            Fragment::Verify(x) => (Witness::invalid(), x.produce(satisfier).1),
+            Fragment::NonZero(x) => {
+                let (x_dsat, x_sat) = x.produce(satisfier);
+                // a dissatisfaction of x with a nonzero top element may
+                // exist and be used instead
+                let mut dsat = Witness::zero();
+                dsat.malleable = x_dsat.stack.is_some() && !x_dsat.has_sig;
+                (dsat, x_sat)
+            }
+        }
+    }
+
+    /// The smallest non-malleable witness stack satisfying the expression,
+    /// bottom element first, not including the witness script.
+    pub fn satisfy(&self, satisfier: &Satisfier) -> Result<Vec<Vec<u8>>, MiniscriptError> {
+        let (_, sat) = self.produce(satisfier);
+        let Some(stack) = sat.stack else {
+            return Err(MiniscriptError::Unsatisfiable);
+        };
+        if sat.malleable || !sat.has_sig {
+            return Err(MiniscriptError::Malleable);
+        }
+        Ok(stack)
+    }
+
+    fn witness_sizes(&self) -> WitnessSizes {
+        let sig = 1 + self.ctx.max_sig_size();
+        let key = 1 + self.ctx.key_size();
+        match &self.fragment {
+            Fragment::False => (None, Some(0)),
+            Fragment::True => (Some(0), None),
+            Fragment::PkK(_) => (Some(sig), Some(1)),
+            Fragment::PkH(_) => (Some(sig + key), Some(1 + key)),
+            Fragment::Older(_) | Fragment::After(_) => (Some(0), None),
+            Fragment::Sha256(_)
+            | Fragment::Hash256(_)
+            | Fragment::Ripemd160(_)
+            | Fragment::Hash160(_) => (Some(33), Some(33)),
+            Fragment::AndOr(x, y, z) => {
+                let ((x_sat, x_dsat), (y_sat, _), (z_sat, z_dsat)) =
+                    (x.witness_sizes(), y.witness_sizes(), z.witness_sizes());
+                (
+                    max_size(add_sizes(y_sat, x_sat), add_sizes(z_sat, x_dsat)),
+                    add_sizes(z_dsat, x_dsat),
+                )
+            }
+            Fragment::AndV(x, y) => (add_sizes(x.witness_sizes().0, y.witness_sizes().0), None),
+            Fragment::AndB(x, y) => {
+                let ((x_sat, x_dsat), (y_sat, y_dsat)) = (x.witness_sizes(), y.witness_sizes());
+                (add_sizes(x_sat, y_sat), add_sizes(x_dsat, y_dsat))
+            }
+            Fragment::OrB(x, z) => {
+                let ((x_sat, x_dsat), (z_sat, z_dsat)) = (x.witness_sizes(), z.witness_sizes());
+                (
+                    max_size(add_sizes(x_sat, z_dsat), add_sizes(x_dsat, z_sat)),
+                    add_sizes(x_dsat, z_dsat),
+                )
+            }
+            Fragment::OrC(x, z) => {
+                let ((x_sat, x_dsat), (z_sat, _)) = (x.witness_sizes(), z.witness_sizes());
+                (max_size(x_sat, add_sizes(x_dsat, z_sat)), None)
+            }
+            Fragment::OrD(x, z) => {
+                let ((x_sat, x_dsat), (z_sat, z_dsat)) = (x.witness_sizes(), z.witness_sizes());
+                (
+                    max_size(x_sat, add_sizes(x_dsat, z_sat)),
+                    add_sizes(x_dsat, z_dsat),
+                )
+            }
+            Fragment::OrI(x, z) => {
+                let ((x_sat, x_dsat), (z_sat, z_dsat)) = (x.witness_sizes(), z.witness_sizes());
+                (
+                    max_size(add_sizes(x_sat, Some(2)), add_sizes(z_sat, Some(1))),
+                    max_size(add_sizes(x_dsat, Some(2)), add_sizes(z_dsat, Some(1))),
+                )
+            }
+            Fragment::Thresh(k, subs) => {
+                // best[j]: the largest witness with j subs satisfied
+                let mut best = vec![Some(0)];
+                for sub in subs {
+                    let (sat, dsat) = sub.witness_sizes();
+                    let mut next = vec![add_sizes(best[0], dsat)];
+                    for j in 1..best.len() {
+                        next.push(max_size(
+                            add_sizes(best[j], dsat),
+                            add_sizes(best[j - 1], sat),
+                        ));
+                    }
+                    next.push(add_sizes(*best.last().unwrap(), sat));
+                    best = next;
+                }
+                (best[*k], best[0])
+            }
+            Fragment::Multi(k, _) => (Some(1 + k * sig), Some(1 + k)),
+            Fragment::MultiA(k, keys) => (Some(k * sig + keys.len() - k), Some(keys.len())),
+            Fragment::Alt(x)
+            | Fragment::Swap(x)
+            | Fragment::Check(x)
+            | Fragment::ZeroNotEqual(x) => x.witness_sizes(),
+            Fragment::DupIf(x) => (add_sizes(x.witness_sizes().0, Some(2)), Some(1)),
+            Fragment::Verify(x) => (x.witness_sizes().0, None),
+            Fragment::NonZero(x) => (x.witness_sizes().0, Some(1)),
+        }
+    }
+
+    /// Largest witness a satisfaction needs, in bytes: every stack element
+    /// with its length prefix, assuming the largest signatures. Does not
+    /// count the witness script or the element count.
+    pub fn max_satisfaction_size(&self) -> Option<usize> {
+        self.witness_sizes().0
+    }
+
+    fn fmt_inner(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut wrappers = String::new();
+        let mut node = self;
+        loop {
+            let (wrapper, inner) = match &node.fragment {
+                Fragment::Alt(x) => ('a', x),
+                Fragment::Swap(x) => ('s', x),
+                Fragment::Check(x)
+                    if !matches!(x.fragment, Fragment::PkK(_) | Fragment::PkH(_)) =>
+                {
+                    ('c', x)
+                }
+                Fragment::DupIf(x) => ('d', x),
+                Fragment::Verify(x) => ('v', x),
+                Fragment::NonZero(x) => ('j', x),
+                Fragment::ZeroNotEqual(x) => ('n', x),
+                Fragment::AndV(x, y) if y.fragment == Fragment::True => ('t', x),
+                Fragment::OrI(x, z) if x.fragment == Fragment::False => ('l', z),
+                Fragment::OrI(x, z) if z.fragment == Fragment::False => ('u', x),
+                _ => break,
+            };
+            wrappers.push(wrapper);
+            node = inner;
+        }
+        if !wrappers.is_empty() {
+            write!(f, "{}:", wrappers)?;
+        }
+        let keys = |keys: &[Vec<u8>]| {
+            keys.iter()
+                .map(|key| format!(",{}", hex::encode(key)))
+                .collect::<String>()
+        };
+        match &node.fragment {
+            Fragment::False => write!(f, "0"),
+            Fragment::True => write!(f, "1"),
+            Fragment::PkK(key) => write!(f, "pk_k({})", hex::encode(key)),
+            Fragment::PkH(key) => write!(f, "pk_h({})", hex::encode(key)),
+            Fragment::Check(x) => match &x.fragment {
+                Fragment::PkK(key) => write!(f, "pk({})", hex::encode(key)),
+                Fragment::PkH(key) => write!(f, "pkh({})", hex::encode(key)),
+                _ => unreachable!(),
+            },
+            Fragment::Older(n) => write!(f, "older({})", n),
+            Fragment::After(n) => write!(f, "after({})", n),
+            Fragment::Sha256(h) => write!(f, "sha256({})", hex::encode(h)),
+            Fragment::Hash256(h) => write!(f, "hash256({})", hex::encode(h)),
+            Fragment::Ripemd160(h) => write!(f, "ripemd160({})", hex::encode(h)),
+            Fragment::Hash160(h) => write!(f, "hash160({})", hex::encode(h)),
+            Fragment::AndOr(x, y, z) if z.fragment == Fragment::False => {
+                write!(f, "and_n({},{})", x, y)
+            }
+            Fragment::AndOr(x, y, z) => write!(f, "andor({},{},{})", x, y, z),
+            Fragment::AndV(x, y) => write!(f, "and_v({},{})", x, y),
+            Fragment::AndB(x, y) => write!(f, "and_b({},{})", x, y),
+            Fragment::OrB(x, z) => write!(f, "or_b({},{})", x, z),
+            Fragment::OrC(x, z) => write!(f, "or_c({},{})", x, z),
+            Fragment::OrD(x, z) => write!(f, "or_d({},{})", x, z),
+            Fragment::OrI(x, z) => write!(f, "or_i({},{})", x, z),
+            Fragment::Thresh(k, subs) => {
+                write!(f, "thresh({}", k)?;
+                for sub in subs {
+                    write!(f, ",{}", sub)?;
+                }
+                write!(f, ")")
+            }
+            Fragment::Multi(k, ks) => write!(f, "multi({}{})", k, keys(ks)),
+            Fragment::MultiA(k, ks) => write!(f, "multi_a({}{})", k, keys(ks)),
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl fmt::Display for Miniscript {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.fmt_inner(f)
+    }
+}
+
+/// A spending policy: the conditions without the script they are encoded
+/// in. `or()` branches may carry relative probabilities as `n@`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Policy {
+    Key(Vec<u8>),
+    Older(u32),
+    After(u32),
+    Sha256([u8; 32]),
+    Hash256([u8; 32]),
+    Ripemd160([u8; 20]),
+    Hash160([u8; 20]),
+    And(Box<Policy>, Box<Policy>),
+    Or(u32, Box<Policy>, u32, Box<Policy>),
+    Thresh(usize, Vec<Policy>),
+}
+
+impl Policy {
+    fn parse(s: &str, ctx: ScriptContext) -> Result<Self, MiniscriptError> {
+        let (name, args) = split_call(s).ok_or(MiniscriptError::InvalidSyntax)?;
+        let args = split_args(args);
+        let one = |f: fn(&str) -> Result<Policy, MiniscriptError>| match args[..] {
+            [arg] => f(arg),
+            _ => Err(MiniscriptError::InvalidSyntax),
+        };
+        match name {
+            "pk" => match args[..] {
+                [key] => Ok(Policy::Key(parse_key(key, ctx)?)),
+                _ => Err(MiniscriptError::InvalidSyntax),
+            },
+            "older" => one(|a| Ok(Policy::Older(parse_timelock(a)?))),
+            "after" => one(|a| Ok(Policy::After(parse_timelock(a)?))),
+            "sha256" => one(|a| Ok(Policy::Sha256(parse_hash(a)?))),
+            "hash256" => one(|a| Ok(Policy::Hash256(parse_hash(a)?))),
+            "ripemd160" => one(|a| Ok(Policy::Ripemd160(parse_hash(a)?))),
+            "hash160" => one(|a| Ok(Policy::Hash160(parse_hash(a)?))),
+            "and" => match args[..] {
+                [a, b] => Ok(Policy::And(
+                    Box::new(Self::parse(a, ctx)?),
+                    Box::new(Self::parse(b, ctx)?),
+                )),
+                _ => Err(MiniscriptError::InvalidSyntax),
+            },
+            "or" => {
+                let [a, b] = args[..] else {
+                    return Err(MiniscriptError::InvalidSyntax);
+                };
+                let weighted = |s: &str| -> Result<(u32, Policy), MiniscriptError> {
+                    match s.split_once('@') {
+                        Some((weight, p)) if !weight.contains('(') => Ok((
+                            weight.parse().map_err(|_| MiniscriptError::InvalidSyntax)?,
+                            Self::parse(p, ctx)?,
+                        )),
+                        _ => Ok((1, Self::parse(s, ctx)?)),
+                    }
+                };
+                let ((wa, a), (wb, b)) = (weighted(a)?, weighted(b)?);
+                Ok(Policy::Or(wa, Box::new(a), wb, Box::new(b)))
+            }
+            "thresh" => {
+                let k = parse_threshold(args[0], args.len() - 1)?;
+                let subs = args[1..]
+                    .iter()
+                    .map(|s| Self::parse(s, ctx))
+                    .collect::<Result<_, _>>()?;
+                Ok(Policy::Thresh(k, subs))
+            }
+            _ => Err(MiniscriptError::InvalidSyntax),
+        }
+    }
+
+    fn compile(&self, ctx: ScriptContext) -> Result<Miniscript, MiniscriptError> {
+        let node = |fragment| Miniscript::new(fragment, ctx);
+        let boxed = |fragment| Ok::<_, MiniscriptError>(Box::new(node(fragment)?));
+        match self {
+            Policy::Key(key) => node(Fragment::Check(boxed(Fragment::PkK(key.clone()))?)),
+            Policy::Older(n) => node(Fragment::Older(*n)),
+            Policy::After(n) => node(Fragment::After(*n)),
+            Policy::Sha256(h) => node(Fragment::Sha256(*h)),
+            Policy::Hash256(h) => node(Fragment::Hash256(*h)),
+            Policy::Ripemd160(h) => node(Fragment::Ripemd160(*h)),
+            Policy::Hash160(h) => node(Fragment::Hash160(*h)),
+            Policy::And(a, b) => {
+                let a = a.compile(ctx)?;
+                node(Fragment::AndV(
+                    boxed(Fragment::Verify(Box::new(a)))?,
+                    Box::new(b.compile(ctx)?),
+                ))
+            }
+            Policy::Or(wa, a, wb, b) => {
+                // the likelier branch goes first, where it is cheaper
+                let (x, z) = if wa >= wb { (a, b) } else { (b, a) };
+                let (x, z) = (x.compile(ctx)?, z.compile(ctx)?);
+                let dissatisfiable = |m: &Miniscript| m.ty.d && m.ty.u && m.ty.e;
+                if dissatisfiable(&x) {
+                    node(Fragment::OrD(Box::new(x), Box::new(z)))
+                } else if dissatisfiable(&z) {
+                    node(Fragment::OrD(Box::new(z), Box::new(x)))
+                } else {
+                    node(Fragment::OrI(Box::new(x), Box::new(z)))
+                }
+            }
+            Policy::Thresh(k, subs) => {
+                let keys: Vec<Vec<u8>> = subs
+                    .iter()
+                    .filter_map(|p| match p {
+                        Policy::Key(key) => Some(key.clone()),
+                        _ => None,
+                    })
+                    .collect();
+                if keys.len() == subs.len() {
+                    return match ctx {
+                        ScriptContext::P2wsh if keys.len() <= MAX_PUBKEYS_PER_MULTISIG => {
+                            node(Fragment::Multi(*k, keys))
+                        }
+                        ScriptContext::Tapscript => node(Fragment::MultiA(*k, keys)),
+                        _ => Err(MiniscriptError::InvalidThreshold),
+                    };
+                }
+                let mut compiled = Vec::new();
+                for (i, sub) in subs.iter().enumerate() {
+                    let mut m = sub.compile(ctx)?;
+                    // make it dissatisfiable and unit with l:n: if needed
+                    if !m.ty.u {
+                        m = node(Fragment::ZeroNotEqual(Box::new(m)))?;
+                    }
+                    if !m.ty.d {
+                        m = node(Fragment::OrI(boxed(Fragment::False)?, Box::new(m)))?;
+                    }
+                    if i > 0 {
+                        m = if m.ty.o {
+                            node(Fragment::Swap(Box::new(m)))?
+                        } else {
+                            node(Fragment::Alt(Box::new(m)))?
+                        };
+                    }
+                    compiled.push(m);
+                }
+                node(Fragment::Thresh(*k, compiled))
+            }
+        }
+    }
+}
+
+/// Compiles a policy such as `or(99@pk(A),1@and(pk(B),older(1000)))` into
+/// a miniscript implementing it.
+///
+/// This is a simple compiler, not the optimizing one of the reference
+/// implementation: `and` becomes `and_v`, `or` becomes `or_d` when one side
+/// can be dissatisfied cheaply and `or_i` otherwise, and a `thresh` of only
+/// keys becomes `multi()` or `multi_a()`. The result is sanity checked.
+pub fn compile_policy(s: &str, ctx: ScriptContext) -> Result<Miniscript, MiniscriptError> {
+    let ret = Policy::parse(s, ctx)?.compile(ctx)?;
+    ret.sanity_check()?;
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        interpreter::{
+            verify_script, TxChecker, VERIFY_CHECKSEQUENCEVERIFY, VERIFY_P2SH, VERIFY_WITNESS,
+        },
+        mempool::STANDARD_VERIFY_FLAGS,
+        sighash::{segwit_v0_sighash, SIGHASH_ALL},
+        signature::PrivateKey,
+        tx::{OutPoint, Tx, TxIn, TxOut},
+    };
+    use primitive_types::U256;
+    use sha2::{Digest, Sha256};
+
+    // G, 2G and 3G
+    const A: &str = "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+    const B: &str = "02c6047f9441ed7d6d3045406e95c07cd85c778e4b8cef3ca7abac09b95c709ee5";
+    const C: &str = "02f9308a019258c31049344f85f89d5229b531c845836f99b08601f113bce036f9";
+
+    fn ms(s: &str) -> Miniscript {
+        let s = s.replace("A", A).replace("B", B).replace("C", C);
+        Miniscript::parse(&s, ScriptContext::P2wsh).unwrap()
+    }
+
+    fn hex_script(m: &Miniscript) -> String {
+        hex::encode(m.script().as_bytes())
+    }
+
+    #[test]
+    fn test_parse_and_encode() {
+        let m = ms("and_v(v:pk(A),older(144))");
+        assert_eq!(hex_script(&m), format!("21{}ad029000b2", A));
+        assert_eq!(m.ty.to_string(), "Bonfsmhk");
+        let m = ms("or_d(pk(A),pkh(B))");
+        let h = hex::encode(hash160(&hex::decode(B).unwrap()));
+        assert_eq!(hex_script(&m), format!("21{}ac736476a914{}88ac68", A, h));
+        let m = ms("multi(2,A,B,C)");
+        assert_eq!(hex_script(&m), format!("5221{}21{}21{}53ae", A, B, C));
+        // v: folds into the last opcode where it can
+        let m = ms("and_v(v:multi(1,A,B),v:sha256(0000000000000000000000000000000000000000000000000000000000000000))");
+        assert!(hex_script(&m).contains("52af82012088a820"));
+        assert!(hex_script(&m).ends_with("88"));
+
+        let x = &A[2..];
+        let m = Miniscript::parse(
+            &format!("multi_a(1,{},{})", x, &B[2..]),
+            ScriptContext::Tapscript,
+        )
+        .unwrap();
+        assert_eq!(hex_script(&m), format!("20{}ac20{}ba519c", x, &B[2..]));
+
+        for s in [
+            "andor(pk(A),older(1),pkh(B))",
+            "and_n(pk(A),older(1))",
+            "t:or_c(pk(A),v:pkh(B))",
+            "or_i(pk(A),l:pk(B))",
+            "thresh(2,pk(A),s:pk(B),sln:older(100))",
+            "or_b(pk(A),a:pk(B))",
+            "c:and_v(vdv:after(10),pk_k(C))",
+        ] {
+            let s = s.replace("A", A).replace("B", B).replace("C", C);
+            let m = Miniscript::parse(&s, ScriptContext::P2wsh).unwrap();
+            assert_eq!(m.to_string(), s);
+        }
+    }
+
+    #[test]
+    fn test_type_check() {
+        assert_eq!(ms("pk(A)").ty.to_string(), "Bonduesmk");
+        assert_eq!(ms("older(1)").ty.to_string(), "Bzfmhk");
+        assert_eq!(ms("after(500000000)").ty.to_string(), "Bzfmik");
+        assert_eq!(
+            ms("sha256(0000000000000000000000000000000000000000000000000000000000000000)")
+                .ty
+                .to_string(),
+            "Bondumk"
+        );
+        let parse = |s: &str| {
+            let s = s.replace("A", A).replace("B", B);
+            Miniscript::parse(&s, ScriptContext::P2wsh)
+        };
+        assert_eq!(
+            parse("and_v(pk(A),pk(B))"),
+            Err(MiniscriptError::TypeCheck("and_v"))
+        );
+        assert_eq!(
+            parse("s:older(1)"),
+            Err(MiniscriptError::TypeCheck("a: or s:"))
+        );
+        assert_eq!(parse("older(0)"), Err(MiniscriptError::InvalidTimelock));
+        assert_eq!(
+            parse("multi(3,A,B)"),
+            Err(MiniscriptError::InvalidThreshold)
+        );
+        assert_eq!(parse("pk(A,B)"), Err(MiniscriptError::InvalidSyntax));
+        assert_eq!(parse("x:pk(A)"), Err(MiniscriptError::InvalidSyntax));
+        assert_eq!(
+            Miniscript::parse(&format!("multi(1,{})", &A[2..]), ScriptContext::Tapscript),
+            Err(MiniscriptError::InvalidContext)
+        );
+        assert_eq!(
+            Miniscript::parse(&format!("pk({})", A), ScriptContext::Tapscript),
+            Err(MiniscriptError::InvalidKey)
+        );
+
+        assert_eq!(ms("and_v(v:pk(A),older(144))").sanity_check(), Ok(()));
+        assert_eq!(
+            ms("v:pk(A)").sanity_check(),
+            Err(MiniscriptError::NotTopLevel)
+        );
+        assert_eq!(
+            ms("older(144)").sanity_check(),
+            Err(MiniscriptError::NoSignature)
+        );
+        assert_eq!(
+            ms("or_b(sha256(0000000000000000000000000000000000000000000000000000000000000000),a:pk(A))").sanity_check(),
+            Err(MiniscriptError::Malleable)
+        );
+        assert_eq!(
+            ms("and_v(v:pk(A),and_v(v:after(100),after(500000000)))").sanity_check(),
+            Err(MiniscriptError::TimelockMixing)
+        );
+        assert_eq!(
+            ms("or_d(pk(A),and_v(v:pk(B),after(100)))").sanity_check(),
+            Ok(())
+        );
+        assert_eq!(
+            ms("or_i(pk(A),pk(A))").sanity_check(),
+            Err(MiniscriptError::DuplicateKey)
+        );
+    }
+
+    #[test]
+    fn test_satisfy() {
+        let key = |s: &str| hex::decode(s).unwrap();
+        let (sig_a, sig_b) = (vec![0xaa; 72], vec![0xbb; 72]);
+        let mut satisfier = Satisfier {
+            sequence: 0xffffffff,
+            ..Default::default()
+        };
+        let m = ms("or_d(pk(A),pkh(B))");
+        assert_eq!(m.satisfy(&satisfier), Err(MiniscriptError::Unsatisfiable));
+        satisfier.signatures.insert(key(B), sig_b.clone());
+        assert_eq!(
+            m.satisfy(&satisfier),
+            Ok(vec![sig_b.clone(), key(B), vec![]])
+        );
+        satisfier.signatures.insert(key(A), sig_a.clone());
+        assert_eq!(m.satisfy(&satisfier), Ok(vec![sig_a.clone()]));
+
+        // the timelock must be met by the input's sequence
+        let m = ms("and_v(v:pk(A),older(144))");
+        assert_eq!(m.satisfy(&satisfier), Err(MiniscriptError::Unsatisfiable));
+        satisfier.sequence = 144;
+        assert_eq!(m.satisfy(&satisfier), Ok(vec![sig_a.clone()]));
+
+        let preimage = vec![1; 32];
+        let hash: [u8; 32] = Sha256::digest(&preimage).into();
+        let m = ms(&format!("and_v(v:pk(A),sha256({}))", hex::encode(hash)));
+        assert_eq!(m.satisfy(&satisfier), Err(MiniscriptError::Unsatisfiable));
+        satisfier.sha256_preimages.insert(hash, preimage.clone());
+        assert_eq!(m.satisfy(&satisfier), Ok(vec![preimage, sig_a.clone()]));
+
+        // the dummy element, then signatures in key order
+        let m = ms("multi(2,A,B,C)");
+        assert_eq!(
+            m.satisfy(&satisfier),
+            Ok(vec![vec![], sig_a.clone(), sig_b.clone()])
+        );
+        let m = ms("thresh(2,pk(A),s:pk(C),s:pk(B))");
+        assert_eq!(
+            m.satisfy(&satisfier),
+            Ok(vec![sig_b.clone(), vec![], sig_a.clone()])
+        );
+        let m = Miniscript::parse(
+            &format!("multi_a(1,{},{})", &A[2..], &B[2..]),
+            ScriptContext::Tapscript,
+        )
+        .unwrap();
+        satisfier
+            .signatures
+            .insert(key(A)[1..].to_vec(), vec![0xcc; 64]);
+        assert_eq!(m.satisfy(&satisfier), Ok(vec![vec![], vec![0xcc; 64]]));
+    }
+
+    #[test]
+    fn test_max_satisfaction_size() {
+        assert_eq!(ms("pk(A)").max_satisfaction_size(), Some(74));
+        assert_eq!(ms("or_d(pk(A),pkh(B))").max_satisfaction_size(), Some(109));
+        assert_eq!(ms("multi(2,A,B,C)").max_satisfaction_size(), Some(149));
+        assert_eq!(ms("or_i(pk(A),pk(B))").max_satisfaction_size(), Some(76));
+        let m = Miniscript::parse(&format!("pk({})", &A[2..]), ScriptContext::Tapscript).unwrap();
+        assert_eq!(m.max_satisfaction_size(), Some(66));
+    }
+
+    #[test]
+    fn test_compile_policy() {
+        let compile = |s: &str| {
+            let s = s.replace("A", A).replace("B", B).replace("C", C);
+            compile_policy(&s, ScriptContext::P2wsh).map(|m| {
+                m.to_string()
+                    .replace(A, "A")
+                    .replace(B, "B")
+                    .replace(C, "C")
+            })
+        };
+        assert_eq!(
+            compile("or(99@pk(A),1@and(pk(B),older(1000)))"),
+            Ok("or_d(pk(A),and_v(v:pk(B),older(1000)))".to_string())
+        );
+        assert_eq!(
+            compile("thresh(2,pk(A),pk(B),pk(C))"),
+            Ok("multi(2,A,B,C)".to_string())
+        );
+        assert_eq!(
+            compile("thresh(2,pk(A),pk(B),older(100))"),
+            Ok("thresh(2,pk(A),s:pk(B),sln:older(100))".to_string())
+        );
+        assert_eq!(compile("older(100)"), Err(MiniscriptError::NoSignature));
+    }
+
+    #[test]
+    fn test_spend() {
+        // a real P2WSH spend of a satisfaction through the interpreter
+        let key = PrivateKey::new(U256::from(1));
+        let m = ms("and_v(v:pk(A),older(144))");
+        let witness_script = m.script();
+        let script_pubkey = Script::p2wsh(&Sha256::digest(witness_script.as_bytes()).into());
+        let mut tx = Tx::new(
+            2,
+            vec![TxIn::new(OutPoint::new([1; 32], 0), 144)],
+            vec![TxOut::new(40_000, Script::p2wpkh(&[2; 20]))],
+            0,
+        );
+        let z = segwit_v0_sighash(&tx, 0, &witness_script, 50_000, SIGHASH_ALL);
+        let mut sig = key.sign_low_r(U256::from_big_endian(&z)).der();
+        sig.push(SIGHASH_ALL as u8);
+        let mut satisfier = Satisfier {
+            sequence: 144,
+            ..Default::default()
+        };
+        satisfier.signatures.insert(hex::decode(A).unwrap(), sig);
+        let mut witness = m.satisfy(&satisfier).unwrap();
+        witness.push(witness_script.as_bytes().to_vec());
+        tx.tx_ins[0].witness = witness.clone();
+        let checker = TxChecker::new(&tx, 0, 50_000);
+        let flags =
+            STANDARD_VERIFY_FLAGS | VERIFY_P2SH | VERIFY_WITNESS | VERIFY_CHECKSEQUENCEVERIFY;
+        assert_eq!(
+            verify_script(&Script::new(&[]), &script_pubkey, &witness, flags, &checker),
+            Ok(())
+        );
+    }
+}
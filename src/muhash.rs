@@ -0,0 +1,281 @@
+use sha2::{Digest, Sha256};
+use std::io::{self, Read};
+
+const LIMBS: usize = 48;
+/// The MuHash3072 modulus is `2^3072 - MODULUS_OFFSET`, the largest 3072
+/// bit safe prime.
+const MODULUS_OFFSET: u64 = 1_103_717;
+
+/// An integer modulo `2^3072 - MODULUS_OFFSET` as little endian 64 bit
+/// limbs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Num3072([u64; LIMBS]);
+
+impl Num3072 {
+    fn one() -> Self {
+        let mut limbs = [0; LIMBS];
+        limbs[0] = 1;
+        Self(limbs)
+    }
+
+    fn from_bytes(bytes: &[u8; 8 * LIMBS]) -> Self {
+        let mut limbs = [0; LIMBS];
+        for (limb, chunk) in limbs.iter_mut().zip(bytes.chunks_exact(8)) {
+            *limb = u64::from_le_bytes(chunk.try_into().unwrap());
+        }
+        Self(limbs)
+    }
+
+    fn to_bytes(self) -> Vec<u8> {
+        self.0.iter().flat_map(|limb| limb.to_le_bytes()).collect()
+    }
+
+    /// Whether the value is at least the modulus.
+    fn is_overflow(&self) -> bool {
+        self.0[0] > u64::MAX - MODULUS_OFFSET && self.0[1..].iter().all(|limb| *limb == u64::MAX)
+    }
+
+    /// Adds `n`, returning the carry out of the top limb.
+    fn add_small(&mut self, mut n: u64) -> bool {
+        for limb in &mut self.0 {
+            let (sum, overflow) = limb.overflowing_add(n);
+            *limb = sum;
+            if !overflow {
+                return false;
+            }
+            n = 1;
+        }
+        true
+    }
+
+    fn mul(&self, other: &Self) -> Self {
+        let mut wide = [0u64; 2 * LIMBS];
+        for i in 0..LIMBS {
+            let mut carry = 0u128;
+            for j in 0..LIMBS {
+                let t = self.0[i] as u128 * other.0[j] as u128 + wide[i + j] as u128 + carry;
+                wide[i + j] = t as u64;
+                carry = t >> 64;
+            }
+            wide[i + LIMBS] = carry as u64;
+        }
+        // 2^3072 is MODULUS_OFFSET modulo the prime, so fold the high half
+        // down until nothing is carried out
+        let mut ret = Self([0; LIMBS]);
+        let mut carry = 0u128;
+        for i in 0..LIMBS {
+            let t = wide[i] as u128 + wide[i + LIMBS] as u128 * MODULUS_OFFSET as u128 + carry;
+            ret.0[i] = t as u64;
+            carry = t >> 64;
+        }
+        let mut carry = carry as u64;
+        while carry > 0 {
+            carry = ret.add_small(carry * MODULUS_OFFSET) as u64;
+        }
+        if ret.is_overflow() {
+            // subtracting the modulus is adding the offset past 2^3072
+            ret.add_small(MODULUS_OFFSET);
+        }
+        ret
+    }
+
+    /// The inverse by Fermat's little theorem.
+    fn inverse(&self) -> Self {
+        let mut exponent = [u64::MAX; LIMBS];
+        exponent[0] = u64::MAX - MODULUS_OFFSET - 1;
+        let mut ret = Self::one();
+        for limb in exponent.iter().rev() {
+            for bit in (0..64).rev() {
+                ret = ret.mul(&ret);
+                if limb >> bit & 1 == 1 {
+                    ret = ret.mul(self);
+                }
+            }
+        }
+        ret
+    }
+}
+
+fn quarter_round(state: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(16);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(12);
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(8);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(7);
+}
+
+/// One 64 byte block of RFC 8439 ChaCha20 keystream.
+fn chacha20_block(key: &[u8; 32], counter: u32, nonce: &[u8; 12]) -> [u8; 64] {
+    let mut input = [0u32; 16];
+    input[..4].copy_from_slice(&[0x6170_7865, 0x3320_646e, 0x7962_2d32, 0x6b20_6574]);
+    for (word, chunk) in input[4..12].iter_mut().zip(key.chunks_exact(4)) {
+        *word = u32::from_le_bytes(chunk.try_into().unwrap());
+    }
+    input[12] = counter;
+    for (word, chunk) in input[13..].iter_mut().zip(nonce.chunks_exact(4)) {
+        *word = u32::from_le_bytes(chunk.try_into().unwrap());
+    }
+    let mut state = input;
+    for _ in 0..10 {
+        quarter_round(&mut state, 0, 4, 8, 12);
+        quarter_round(&mut state, 1, 5, 9, 13);
+        quarter_round(&mut state, 2, 6, 10, 14);
+        quarter_round(&mut state, 3, 7, 11, 15);
+        quarter_round(&mut state, 0, 5, 10, 15);
+        quarter_round(&mut state, 1, 6, 11, 12);
+        quarter_round(&mut state, 2, 7, 8, 13);
+        quarter_round(&mut state, 3, 4, 9, 14);
+    }
+    let mut ret = [0; 64];
+    for (i, chunk) in ret.chunks_exact_mut(4).enumerate() {
+        chunk.copy_from_slice(&state[i].wrapping_add(input[i]).to_le_bytes());
+    }
+    ret
+}
+
+/// Maps data to a number: SHA256 of the data keys a ChaCha20 stream whose
+/// first 384 bytes are the number.
+fn to_num3072(data: &[u8]) -> Num3072 {
+    let key: [u8; 32] = Sha256::digest(data).into();
+    let mut bytes = [0; 8 * LIMBS];
+    for (counter, chunk) in bytes.chunks_exact_mut(64).enumerate() {
+        chunk.copy_from_slice(&chacha20_block(&key, counter as u32, &[0; 12]));
+    }
+    Num3072::from_bytes(&bytes)
+}
+
+/// Bitcoin Core's MuHash3072 rolling set hash: the product of the mapped
+/// elements modulo a 3072 bit prime. Elements can be added and removed in
+/// any order, so it commits to a set such as the UTXO set and is cheap to
+/// update block by block.
+///
+/// Removals are kept as a separate denominator so updates need no modular
+/// inversion; only `finalize` pays for one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MuHash3072 {
+    numerator: Num3072,
+    denominator: Num3072,
+}
+
+impl Default for MuHash3072 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MuHash3072 {
+    /// The hash of the empty set.
+    pub fn new() -> Self {
+        Self {
+            numerator: Num3072::one(),
+            denominator: Num3072::one(),
+        }
+    }
+
+    pub fn insert(&mut self, data: &[u8]) {
+        self.numerator = self.numerator.mul(&to_num3072(data));
+    }
+
+    /// Removes an element previously inserted; removing anything else gives
+    /// a hash no set has.
+    pub fn remove(&mut self, data: &[u8]) {
+        self.denominator = self.denominator.mul(&to_num3072(data));
+    }
+
+    /// Adds all elements of `other`.
+    pub fn combine(&mut self, other: &Self) {
+        self.numerator = self.numerator.mul(&other.numerator);
+        self.denominator = self.denominator.mul(&other.denominator);
+    }
+
+    /// SHA256 of the set's number as 384 little endian bytes.
+    pub fn finalize(&self) -> [u8; 32] {
+        let mut num = self.numerator;
+        if self.denominator != Num3072::one() {
+            num = num.mul(&self.denominator.inverse());
+        }
+        Sha256::digest(num.to_bytes()).into()
+    }
+
+    pub fn parse<R: Read>(stream: &mut R) -> io::Result<Self> {
+        let mut bytes = [0; 8 * LIMBS];
+        stream.read_exact(&mut bytes)?;
+        let numerator = Num3072::from_bytes(&bytes);
+        stream.read_exact(&mut bytes)?;
+        let denominator = Num3072::from_bytes(&bytes);
+        if numerator.is_overflow() || denominator.is_overflow() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "muhash state out of range",
+            ));
+        }
+        Ok(Self {
+            numerator,
+            denominator,
+        })
+    }
+
+    /// The numerator and denominator, 768 bytes.
+    pub fn serialize(&self) -> Vec<u8> {
+        [self.numerator.to_bytes(), self.denominator.to_bytes()].concat()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_chacha20() {
+        // RFC 8439 section 2.3.2
+        let key: Vec<u8> = (0..32).collect();
+        let nonce = hex::decode("000000090000004a00000000").unwrap();
+        let block = chacha20_block(&key.try_into().unwrap(), 1, &nonce.try_into().unwrap());
+        assert_eq!(
+            hex::encode(block),
+            "10f1e7e4d13b5915500fdd1fa32071c4c7d1f4c733c068030422aa9ac3d46c4e\
+             d2826446079faa0914c2d705d98b02a2b5129cd1de164eb9cbd083e8a2503c4e"
+        );
+    }
+
+    #[test]
+    fn test_muhash() {
+        let element = |i: u8| [&[i][..], &[0; 31]].concat();
+        // from Bitcoin Core's muhash_tests
+        let mut acc = MuHash3072::new();
+        acc.insert(&element(0));
+        acc.insert(&element(1));
+        acc.remove(&element(2));
+        let mut hash = acc.finalize();
+        hash.reverse();
+        assert_eq!(
+            hex::encode(hash),
+            "10d312b100cbd32ada024a6646e40d3482fcff103668d2625f10002a607d5863"
+        );
+
+        // order does not matter and removal undoes insertion
+        let mut other = MuHash3072::new();
+        other.insert(&element(1));
+        other.insert(&element(3));
+        other.remove(&element(2));
+        other.insert(&element(0));
+        other.remove(&element(3));
+        assert_eq!(other.finalize(), acc.finalize());
+        let mut empty = acc;
+        let mut inverse = MuHash3072::new();
+        inverse.insert(&element(2));
+        inverse.remove(&element(0));
+        inverse.remove(&element(1));
+        empty.combine(&inverse);
+        assert_eq!(empty.finalize(), MuHash3072::new().finalize());
+
+        let raw = acc.serialize();
+        assert_eq!(raw.len(), 768);
+        assert_eq!(MuHash3072::parse(&mut Cursor::new(raw)).unwrap(), acc);
+        assert!(MuHash3072::parse(&mut Cursor::new(vec![0xff; 768])).is_err());
+    }
+}
@@ -0,0 +1,915 @@
+use crate::{
+    field_element::FieldElement,
+    secp256k1::{S256Point, N, P},
+    taproot::{tagged_hash, XOnlyPublicKey},
+};
+use primitive_types::U256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MusigError {
+    /// The public key of the signer at this index is not a valid point.
+    InvalidPubkey(usize),
+    /// The public nonce of the signer at this index is not two valid
+    /// points.
+    InvalidPubnonce(usize),
+    InvalidAggnonce,
+    /// The partial signature of the signer at this index is out of range
+    /// or does not verify.
+    InvalidPartialSig(usize),
+    /// The keys add up to the point at infinity.
+    InfiniteAggregateKey,
+    /// The tweak is out of range or gives the point at infinity.
+    InvalidTweak,
+    /// The secret key is out of range, or its public key is not one of the
+    /// signers' or not the one the secret nonce was made for.
+    InvalidSecretKey,
+    InvalidSecNonce,
+}
+
+fn order() -> U256 {
+    U256::from_str_radix(N, 16).unwrap()
+}
+
+fn scalar(x: U256) -> FieldElement {
+    FieldElement::new(x % order(), order())
+}
+
+fn hash_to_scalar(tag: &str, data: &[u8]) -> U256 {
+    U256::from_big_endian(&tagged_hash(tag, data)) % order()
+}
+
+fn to_bytes(x: U256) -> [u8; 32] {
+    let mut ret = [0; 32];
+    x.to_big_endian(&mut ret);
+    ret
+}
+
+fn infinity() -> S256Point {
+    S256Point::new(None, None)
+}
+
+fn is_infinity(point: &S256Point) -> bool {
+    point.as_point().get_coordinate().is_none()
+}
+
+fn has_even_y(point: &S256Point) -> bool {
+    !point.get_y().get_num().bit(0)
+}
+
+fn negate(point: &S256Point) -> S256Point {
+    let p = U256::from_str_radix(P, 16).unwrap();
+    S256Point::new(
+        Some(point.get_x().get_num()),
+        Some(p - point.get_y().get_num()),
+    )
+}
+
+/// `cpoint` of BIP327: a compressed SEC point, or `None`.
+fn parse_point(bytes: &[u8]) -> Option<S256Point> {
+    match bytes.first() {
+        Some(2 | 3) => S256Point::try_parse(bytes),
+        _ => None,
+    }
+}
+
+/// `cpoint_ext`: 33 zero bytes stand for the point at infinity.
+fn parse_point_ext(bytes: &[u8]) -> Option<S256Point> {
+    if bytes.iter().all(|&b| b == 0) {
+        return Some(infinity());
+    }
+    parse_point(bytes)
+}
+
+/// `cbytes_ext`
+fn serialize_point_ext(point: &S256Point) -> [u8; 33] {
+    if is_infinity(point) {
+        return [0; 33];
+    }
+    point.compressed_sec()
+}
+
+/// Sorts public keys lexicographically, so signers can agree on an
+/// aggregate key without agreeing on an order (`KeySort`).
+pub fn key_sort(pubkeys: &[[u8; 33]]) -> Vec<[u8; 33]> {
+    let mut ret = pubkeys.to_vec();
+    ret.sort();
+    ret
+}
+
+/// The aggregate key of a set of signers, with the tweaks applied to it so
+/// far (`KeyAggContext`).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct KeyAggContext {
+    q: S256Point,
+    /// Product of the negations made by x-only tweaks.
+    gacc: U256,
+    /// Accumulated tweak.
+    tacc: U256,
+}
+
+/// The key aggregation coefficients of `pubkeys`, in order.
+fn key_agg_coefficients(pubkeys: &[[u8; 33]]) -> Vec<U256> {
+    let list_hash = tagged_hash("KeyAgg list", &pubkeys.concat());
+    // the second distinct key gets coefficient 1, which saves signers a
+    // multiplication
+    let second_key = pubkeys.iter().find(|&pk| *pk != pubkeys[0]);
+    pubkeys
+        .iter()
+        .map(|pk| {
+            if Some(pk) == second_key {
+                U256::one()
+            } else {
+                hash_to_scalar("KeyAgg coefficient", &[&list_hash[..], pk].concat())
+            }
+        })
+        .collect()
+}
+
+/// Aggregates the public keys of the signers, in the order given
+/// (`KeyAgg`).
+pub fn key_agg(pubkeys: &[[u8; 33]]) -> Result<KeyAggContext, MusigError> {
+    let mut q = infinity();
+    for (i, (pk, a)) in pubkeys
+        .iter()
+        .zip(key_agg_coefficients(pubkeys))
+        .enumerate()
+    {
+        let point = parse_point(pk).ok_or(MusigError::InvalidPubkey(i))?;
+        q += a * point;
+    }
+    if is_infinity(&q) {
+        return Err(MusigError::InfiniteAggregateKey);
+    }
+    Ok(KeyAggContext {
+        q,
+        gacc: U256::one(),
+        tacc: U256::zero(),
+    })
+}
+
+impl KeyAggContext {
+    /// The aggregate key a BIP340 signature of the signers verifies with.
+    pub fn get_xonly_pk(&self) -> XOnlyPublicKey {
+        XOnlyPublicKey::from_point(&self.q)
+    }
+
+    pub fn get_plain_pk(&self) -> [u8; 33] {
+        self.q.compressed_sec()
+    }
+
+    /// Adds `tweak` times the generator to the aggregate key, first
+    /// negating it to have an even y for an x-only tweak such as the
+    /// taproot one (`ApplyTweak`).
+    pub fn apply_tweak(&self, tweak: &[u8; 32], is_xonly: bool) -> Result<Self, MusigError> {
+        let n = order();
+        let t = U256::from_big_endian(tweak);
+        if t >= n {
+            return Err(MusigError::InvalidTweak);
+        }
+        let (q, g) = if is_xonly && !has_even_y(&self.q) {
+            (negate(&self.q), n - 1)
+        } else {
+            (self.q, U256::one())
+        };
+        let q = q + t * S256Point::get_the_generic_point();
+        if is_infinity(&q) {
+            return Err(MusigError::InvalidTweak);
+        }
+        Ok(Self {
+            q,
+            gacc: (scalar(g) * scalar(self.gacc)).get_num(),
+            tacc: (scalar(t) + scalar(g) * scalar(self.tacc)).get_num(),
+        })
+    }
+
+    /// Applies the BIP341 tweak committing to `merkle_root`, or to no
+    /// scripts, making the aggregate key a taproot output key.
+    pub fn apply_taproot_tweak(&self, merkle_root: Option<&[u8; 32]>) -> Result<Self, MusigError> {
+        let internal_key = self.get_xonly_pk().serialize();
+        let data = [&internal_key[..], merkle_root.map_or(&[][..], |r| &r[..])].concat();
+        self.apply_tweak(&tagged_hash("TapTweak", &data), true)
+    }
+}
+
+/// A signer's secret nonce. It is neither `Clone` nor serializable, and
+/// `SessionContext::sign` takes it by value, so a nonce cannot be used for
+/// two signatures, which would reveal the secret key.
+pub struct SecNonce {
+    k1: U256,
+    k2: U256,
+    /// The public key the nonce is for.
+    pk: [u8; 33],
+}
+
+/// Makes a secret nonce and the public nonce to send to the other
+/// signers (`NonceGen`).
+///
+/// `rand` must be fresh randomness from a secure source for every call.
+/// The optional secret key, aggregate key, message and extra input are
+/// mixed in, so a nonce is not repeated even if `rand` is.
+pub fn nonce_gen(
+    rand: &[u8; 32],
+    sk: Option<&[u8; 32]>,
+    pk: &[u8; 33],
+    aggpk: Option<&[u8; 32]>,
+    msg: Option<&[u8]>,
+    extra_in: Option<&[u8]>,
+) -> Result<(SecNonce, [u8; 66]), MusigError> {
+    let mut rand = *rand;
+    if let Some(sk) = sk {
+        let aux = tagged_hash("MuSig/aux", &rand);
+        for (r, (s, a)) in rand.iter_mut().zip(sk.iter().zip(aux)) {
+            *r = s ^ a;
+        }
+    }
+    let aggpk = aggpk.map_or(&[][..], |pk| &pk[..]);
+    let msg_prefixed = match msg {
+        None => vec![0],
+        Some(msg) => [&[1][..], &(msg.len() as u64).to_be_bytes(), msg].concat(),
+    };
+    let extra_in = extra_in.unwrap_or(&[]);
+    let k = |i: u8| {
+        let data = [
+            &rand[..],
+            &[pk.len() as u8],
+            pk,
+            &[aggpk.len() as u8],
+            aggpk,
+            &msg_prefixed,
+            &(extra_in.len() as u32).to_be_bytes(),
+            extra_in,
+            &[i],
+        ]
+        .concat();
+        hash_to_scalar("MuSig/nonce", &data)
+    };
+    let (k1, k2) = (k(0), k(1));
+    if k1.is_zero() || k2.is_zero() {
+        return Err(MusigError::InvalidSecNonce);
+    }
+    let g = S256Point::get_the_generic_point();
+    let pubnonce = [(k1 * g).compressed_sec(), (k2 * g).compressed_sec()].concat();
+    Ok((SecNonce { k1, k2, pk: *pk }, pubnonce.try_into().unwrap()))
+}
+
+/// Sums the signers' public nonces into the one used for signing
+/// (`NonceAgg`).
+pub fn nonce_agg(pubnonces: &[[u8; 66]]) -> Result<[u8; 66], MusigError> {
+    let mut ret = [0; 66];
+    for j in 0..2 {
+        let mut r = infinity();
+        for (i, pubnonce) in pubnonces.iter().enumerate() {
+            r += parse_point(&pubnonce[33 * j..33 * (j + 1)])
+                .ok_or(MusigError::InvalidPubnonce(i))?;
+        }
+        ret[33 * j..33 * (j + 1)].copy_from_slice(&serialize_point_ext(&r));
+    }
+    Ok(ret)
+}
+
+/// What every signer of a message must agree on: the aggregate nonce, the
+/// keys in order, the tweaks and the message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionContext {
+    pub aggnonce: [u8; 66],
+    pub pubkeys: Vec<[u8; 33]>,
+    /// Tweaks in the order they are applied, each with whether it is
+    /// x-only.
+    pub tweaks: Vec<([u8; 32], bool)>,
+    pub msg: Vec<u8>,
+}
+
+/// `GetSessionValues`
+struct SessionValues {
+    keyagg: KeyAggContext,
+    b: U256,
+    r: S256Point,
+    e: U256,
+}
+
+impl SessionContext {
+    fn values(&self) -> Result<SessionValues, MusigError> {
+        let mut keyagg = key_agg(&self.pubkeys)?;
+        for (tweak, is_xonly) in &self.tweaks {
+            keyagg = keyagg.apply_tweak(tweak, *is_xonly)?;
+        }
+        let q = keyagg.get_xonly_pk().serialize();
+        let b = hash_to_scalar(
+            "MuSig/noncecoef",
+            &[&self.aggnonce[..], &q, &self.msg].concat(),
+        );
+        let r1 = parse_point_ext(&self.aggnonce[..33]).ok_or(MusigError::InvalidAggnonce)?;
+        let r2 = parse_point_ext(&self.aggnonce[33..]).ok_or(MusigError::InvalidAggnonce)?;
+        let mut r = r1 + b * r2;
+        // only possible if the nonces are chosen adversarially, and then the
+        // signature does not verify anyway
+        if is_infinity(&r) {
+            r = S256Point::get_the_generic_point();
+        }
+        let e = hash_to_scalar(
+            "BIP0340/challenge",
+            &[
+                &XOnlyPublicKey::from_point(&r).serialize()[..],
+                &q,
+                &self.msg,
+            ]
+            .concat(),
+        );
+        Ok(SessionValues { keyagg, b, r, e })
+    }
+
+    /// The negation signers apply to their keys to sign for the aggregate
+    /// key: `g * gacc` of BIP327.
+    fn key_negation(keyagg: &KeyAggContext) -> FieldElement {
+        let g = if has_even_y(&keyagg.q) {
+            U256::one()
+        } else {
+            order() - 1
+        };
+        scalar(g) * scalar(keyagg.gacc)
+    }
+
+    /// Makes this signer's partial signature with its secret key, using up
+    /// `secnonce` (`Sign`).
+    pub fn sign(&self, secnonce: SecNonce, sk: &[u8; 32]) -> Result<[u8; 32], MusigError> {
+        let n = order();
+        let values = self.values()?;
+        let (mut k1, mut k2) = (secnonce.k1, secnonce.k2);
+        if k1.is_zero() || k1 >= n || k2.is_zero() || k2 >= n {
+            return Err(MusigError::InvalidSecNonce);
+        }
+        if !has_even_y(&values.r) {
+            (k1, k2) = (n - k1, n - k2);
+        }
+        let d = U256::from_big_endian(sk);
+        if d.is_zero() || d >= n {
+            return Err(MusigError::InvalidSecretKey);
+        }
+        let pk = (d * S256Point::get_the_generic_point()).compressed_sec();
+        if pk != secnonce.pk {
+            return Err(MusigError::InvalidSecretKey);
+        }
+        let index = self
+            .pubkeys
+            .iter()
+            .position(|key| *key == pk)
+            .ok_or(MusigError::InvalidSecretKey)?;
+        let a = key_agg_coefficients(&self.pubkeys)[index];
+        let d = Self::key_negation(&values.keyagg) * scalar(d);
+        let s = scalar(k1) + scalar(values.b) * scalar(k2) + scalar(values.e) * scalar(a) * d;
+        Ok(to_bytes(s.get_num()))
+    }
+
+    /// Checks the partial signature of the signer at index `signer`, made
+    /// with `pubnonce` (`PartialSigVerify`). The aggregate signature is only
+    /// valid if every partial one is, and this says whose is not.
+    pub fn partial_sig_verify(
+        &self,
+        psig: &[u8; 32],
+        pubnonce: &[u8; 66],
+        signer: usize,
+    ) -> Result<(), MusigError> {
+        let values = self.values()?;
+        let s = U256::from_big_endian(psig);
+        if s >= order() {
+            return Err(MusigError::InvalidPartialSig(signer));
+        }
+        let r1 = parse_point(&pubnonce[..33]).ok_or(MusigError::InvalidPubnonce(signer))?;
+        let r2 = parse_point(&pubnonce[33..]).ok_or(MusigError::InvalidPubnonce(signer))?;
+        let mut r = r1 + values.b * r2;
+        if !has_even_y(&values.r) {
+            r = negate(&r);
+        }
+        let pk = self
+            .pubkeys
+            .get(signer)
+            .ok_or(MusigError::InvalidPartialSig(signer))?;
+        let point = parse_point(pk).ok_or(MusigError::InvalidPubkey(signer))?;
+        let a = key_agg_coefficients(&self.pubkeys)[signer];
+        let g = Self::key_negation(&values.keyagg);
+        let e = (scalar(values.e) * scalar(a) * g).get_num();
+        if s * S256Point::get_the_generic_point() != r + e * point {
+            return Err(MusigError::InvalidPartialSig(signer));
+        }
+        Ok(())
+    }
+
+    /// Combines the partial signatures of all signers into a BIP340
+    /// signature for the aggregate key (`PartialSigAgg`).
+    pub fn partial_sig_agg(&self, psigs: &[[u8; 32]]) -> Result<[u8; 64], MusigError> {
+        let values = self.values()?;
+        let mut s = scalar(U256::zero());
+        for (i, psig) in psigs.iter().enumerate() {
+            let psig = U256::from_big_endian(psig);
+            if psig >= order() {
+                return Err(MusigError::InvalidPartialSig(i));
+            }
+            s += scalar(psig);
+        }
+        // the tweaks are added once for everyone
+        let g = if has_even_y(&values.keyagg.q) {
+            U256::one()
+        } else {
+            order() - 1
+        };
+        s += scalar(values.e) * scalar(g) * scalar(values.keyagg.tacc);
+        let mut ret = [0; 64];
+        ret[..32].copy_from_slice(&XOnlyPublicKey::from_point(&values.r).serialize());
+        ret[32..].copy_from_slice(&to_bytes(s.get_num()));
+        Ok(ret)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::taproot::tweak_key;
+
+    // from the BIP327 reference vectors
+    const SK: &str = "7FB9E0E687ADA1EEBF7ECFE2F21E73EBDB51A7D450948DFE8D76D7F2D1007671";
+    const SECNONCE: &str = "508B81A611F100A6B2B6B29656590898AF488BCF2E1F55CF22E5CFB84421FE61FA27FD49B1D50085B481285E1CA205D55C82CC1B31FF5CD54A489829355901F703935F972DA013F80AE011890FA89B67A27B7BE6CCB24D3274D18B2D4067F261A9";
+    const PUBNONCES: [&str; 3] = [
+        "0337C87821AFD50A8644D820A8F3E02E499C931865C2360FB43D0A0D20DAFE07EA0287BF891D2A6DEAEBADC909352AA9405D1428C15F4B75F04DAE642A95C2548480",
+        "0279BE667EF9DCBBAC55A06295CE870B07029BFCDB2DCE28D959F2815B16F817980279BE667EF9DCBBAC55A06295CE870B07029BFCDB2DCE28D959F2815B16F81798",
+        "032DE2662628C90B03F5E720284EB52FF7D71F4284F627B68A853D78C78E1FFE9303E4C5524E83FFE1493B9077CF1CA6BEB2090C93D930321071AD40B2F44E599046",
+    ];
+    const MSG: &str = "F95466D086770E689964664219266FE5ED215C92AE20BAB5C9D79ADDDDF3C0CF";
+
+    fn bytes<const L: usize>(s: &str) -> [u8; L] {
+        hex::decode(s).unwrap().try_into().unwrap()
+    }
+
+    fn secnonce(s: &str) -> SecNonce {
+        let b = hex::decode(s).unwrap();
+        SecNonce {
+            k1: U256::from_big_endian(&b[..32]),
+            k2: U256::from_big_endian(&b[32..64]),
+            pk: b[64..].try_into().unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_key_agg() {
+        let pubkeys: Vec<[u8; 33]> = [
+            "02F9308A019258C31049344F85F89D5229B531C845836F99B08601F113BCE036F9",
+            "03DFF1D77F2A671C5F36183726DB2341BE58FEAE1DA2DECED843240F7B502BA659",
+            "023590A94E768F8E1815C2F24B4D80A8E3149316C3518CE7B7AD338368D038CA66",
+        ]
+        .iter()
+        .map(|s| bytes(s))
+        .collect();
+        for (indices, expected) in [
+            (
+                &[0, 1, 2][..],
+                "90539EEDE565F5D054F32CC0C220126889ED1E5D193BAF15AEF344FE59D4610C",
+            ),
+            (
+                &[2, 1, 0],
+                "6204DE8B083426DC6EAF9502D27024D53FC826BF7D2012148A0575435DF54B2B",
+            ),
+            (
+                &[0, 0, 0],
+                "B436E3BAD62B8CD409969A224731C193D051162D8C5AE8B109306127DA3AA935",
+            ),
+            (
+                &[0, 0, 1, 1],
+                "69BC22BFA5D106306E48A20679DE1D7389386124D07571D0D872686028C26A3E",
+            ),
+        ] {
+            let keys: Vec<[u8; 33]> = indices.iter().map(|&i| pubkeys[i]).collect();
+            let keyagg = key_agg(&keys).unwrap();
+            assert_eq!(keyagg.get_xonly_pk().to_string(), expected.to_lowercase());
+        }
+        assert_eq!(
+            key_sort(&[pubkeys[1], pubkeys[2], pubkeys[0]])[0],
+            pubkeys[2]
+        );
+
+        // x is not on the curve, and x is not below the field size
+        let invalid: [u8; 33] =
+            bytes("020000000000000000000000000000000000000000000000000000000000000005");
+        assert_eq!(key_agg(&[invalid]), Err(MusigError::InvalidPubkey(0)));
+        let invalid: [u8; 33] =
+            bytes("02FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFEFFFFFC30");
+        assert_eq!(key_agg(&[invalid]), Err(MusigError::InvalidPubkey(0)));
+    }
+
+    #[test]
+    fn test_nonce_gen() {
+        let pk = bytes("024D4B6CD1361032CA9BD2AEB9D900AA4D45D9EAD80AC9423374C451A7254D0766");
+        let (secnonce, pubnonce) = nonce_gen(
+            &[0x0f; 32],
+            Some(&[0x02; 32]),
+            &pk,
+            Some(&[0x07; 32]),
+            Some(&[0x01; 32]),
+            Some(&[0x08; 32]),
+        )
+        .unwrap();
+        assert_eq!(
+            hex::encode_upper([to_bytes(secnonce.k1), to_bytes(secnonce.k2)].concat()),
+            "B114E502BEAA4E301DD08A50264172C84E41650E6CB726B410C0694D59EFFB6495B5CAF28D045B973D63E3C99A44B807BDE375FD6CB39E46DC4A511708D0E9D2"
+        );
+        let g = S256Point::get_the_generic_point();
+        assert_eq!(pubnonce[..33], (secnonce.k1 * g).compressed_sec());
+
+        // an empty message is not the same as none, and is prefixed with
+        // its length like a long one
+        for (msg, expected) in [
+            (
+                &[][..],
+                "E862B068500320088138468D47E0E6F147E01B6024244AE45EAC40ACE5929B9F0789E051170B9E705D0B9EB49049A323BBBBB206D8E05C19F46C6228742AA7A9",
+            ),
+            (
+                &[0x26; 38],
+                "3221975ACBDEA6820EABF02A02B7F27D3A8EF68EE42787B88CBEFD9AA06AF3632EE85B1A61D8EF31126D4663A00DD96E9D1D4959E72D70FE5EBB6E7696EBA66F",
+            ),
+        ] {
+            let (secnonce, _) = nonce_gen(
+                &[0x0f; 32],
+                Some(&[0x02; 32]),
+                &pk,
+                Some(&[0x07; 32]),
+                Some(msg),
+                Some(&[0x08; 32]),
+            )
+            .unwrap();
+            assert_eq!(
+                hex::encode_upper([to_bytes(secnonce.k1), to_bytes(secnonce.k2)].concat()),
+                expected
+            );
+        }
+        let pk = bytes("02F9308A019258C31049344F85F89D5229B531C845836F99B08601F113BCE036F9");
+        let (secnonce, _) = nonce_gen(&[0x0f; 32], None, &pk, None, None, None).unwrap();
+        assert_eq!(
+            hex::encode_upper([to_bytes(secnonce.k1), to_bytes(secnonce.k2)].concat()),
+            "89BDD787D0284E5E4D5FC572E49E316BAB7E21E3B1830DE37DFE80156FA41A6D0B17AE8D024C53679699A6FD7944D9C4A366B514BAF43088E0708B1023DD2897"
+        );
+        assert_eq!(secnonce.pk, pk);
+    }
+
+    #[test]
+    fn test_nonce_agg() {
+        let pubnonces: [[u8; 66]; 7] = [
+            "020151C80F435648DF67A22B749CD798CE54E0321D034B92B709B567D60A42E66603BA47FBC1834437B3212E89A84D8425E7BF12E0245D98262268EBDCB385D50641",
+            "03FF406FFD8ADB9CD29877E4985014F66A59F6CD01C0E88CAA8E5F3166B1F676A60248C264CDD57D3C24D79990B0F865674EB62A0F9018277A95011B41BFC193B833",
+            "020151C80F435648DF67A22B749CD798CE54E0321D034B92B709B567D60A42E6660279BE667EF9DCBBAC55A06295CE870B07029BFCDB2DCE28D959F2815B16F81798",
+            "03FF406FFD8ADB9CD29877E4985014F66A59F6CD01C0E88CAA8E5F3166B1F676A60379BE667EF9DCBBAC55A06295CE870B07029BFCDB2DCE28D959F2815B16F81798",
+            // a bad tag, an x not on the curve and an x not below the field
+            // size
+            "04FF406FFD8ADB9CD29877E4985014F66A59F6CD01C0E88CAA8E5F3166B1F676A60248C264CDD57D3C24D79990B0F865674EB62A0F9018277A95011B41BFC193B833",
+            "03FF406FFD8ADB9CD29877E4985014F66A59F6CD01C0E88CAA8E5F3166B1F676A60248C264CDD57D3C24D79990B0F865674EB62A0F9018277A95011B41BFC193B831",
+            "03FF406FFD8ADB9CD29877E4985014F66A59F6CD01C0E88CAA8E5F3166B1F676A602FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFEFFFFFC30",
+        ]
+        .map(bytes);
+        assert_eq!(
+            hex::encode_upper(nonce_agg(&pubnonces[..2]).unwrap()),
+            "035FE1873B4F2967F52FEA4A06AD5A8ECCBE9D0FD73068012C894E2E87CCB5804B024725377345BDE0E9C33AF3C43C0A29A9249F2F2956FA8CFEB55C8573D0262DC8"
+        );
+        // the second points cancel out
+        assert_eq!(
+            hex::encode_upper(nonce_agg(&pubnonces[2..4]).unwrap()),
+            "035FE1873B4F2967F52FEA4A06AD5A8ECCBE9D0FD73068012C894E2E87CCB5804B000000000000000000000000000000000000000000000000000000000000000000"
+        );
+        for (indices, signer) in [([0, 4], 1), ([5, 0], 0), ([0, 6], 1)] {
+            assert_eq!(
+                nonce_agg(&indices.map(|i| pubnonces[i])),
+                Err(MusigError::InvalidPubnonce(signer))
+            );
+        }
+    }
+
+    #[test]
+    fn test_sign() {
+        let pubkeys: [[u8; 33]; 3] = [
+            bytes("03935F972DA013F80AE011890FA89B67A27B7BE6CCB24D3274D18B2D4067F261A9"),
+            bytes("02F9308A019258C31049344F85F89D5229B531C845836F99B08601F113BCE036F9"),
+            bytes("02DFF1D77F2A671C5F36183726DB2341BE58FEAE1DA2DECED843240F7B502BA661"),
+        ];
+        let pubnonces: Vec<[u8; 66]> = PUBNONCES.iter().map(|s| bytes(s)).collect();
+        let aggnonce = nonce_agg(&pubnonces).unwrap();
+        assert_eq!(
+            hex::encode_upper(aggnonce),
+            "028465FCF0BBDBCF443AABCCE533D42B4B5A10966AC09A49655E8C42DAAB8FCD61037496A3CC86926D452CAFCFD55D25972CA1675D549310DE296BFF42F72EEEA8C9"
+        );
+        for (indices, expected) in [
+            (
+                [0, 1, 2],
+                "012ABBCB52B3016AC03AD82395A1A415C48B93DEF78718E62A7A90052FE224FB",
+            ),
+            (
+                [1, 0, 2],
+                "9FF2F7AAA856150CC8819254218D3ADEEB0535269051897724F9DB3789513A52",
+            ),
+            (
+                [1, 2, 0],
+                "FA23C359F6FAC4E7796BB93BC9F0532A95468C539BA20FF86D7C76ED92227900",
+            ),
+        ] {
+            let session = SessionContext {
+                aggnonce,
+                pubkeys: indices.iter().map(|&i| pubkeys[i]).collect(),
+                tweaks: Vec::new(),
+                msg: bytes::<32>(MSG).to_vec(),
+            };
+            let psig = session.sign(secnonce(SECNONCE), &bytes(SK)).unwrap();
+            assert_eq!(hex::encode_upper(psig), expected);
+        }
+
+        let mut invalid = pubnonces.clone();
+        invalid[1][0] = 4;
+        assert_eq!(nonce_agg(&invalid), Err(MusigError::InvalidPubnonce(1)));
+        // the secret key must be the one the nonce was made for
+        let session = SessionContext {
+            aggnonce,
+            pubkeys: pubkeys.to_vec(),
+            tweaks: Vec::new(),
+            msg: Vec::new(),
+        };
+        let mut sk: [u8; 32] = bytes(SK);
+        sk[31] ^= 1;
+        assert_eq!(
+            session.sign(secnonce(SECNONCE), &sk),
+            Err(MusigError::InvalidSecretKey)
+        );
+    }
+
+    #[test]
+    fn test_partial_sig_verify() {
+        let pubkeys: [[u8; 33]; 4] = [
+            bytes("03935F972DA013F80AE011890FA89B67A27B7BE6CCB24D3274D18B2D4067F261A9"),
+            bytes("02F9308A019258C31049344F85F89D5229B531C845836F99B08601F113BCE036F9"),
+            bytes("02DFF1D77F2A671C5F36183726DB2341BE58FEAE1DA2DECED843240F7B502BA661"),
+            bytes("020000000000000000000000000000000000000000000000000000000000000007"),
+        ];
+        let pubnonces: Vec<[u8; 66]> = PUBNONCES.iter().map(|s| bytes(s)).collect();
+        let aggnonce = nonce_agg(&pubnonces).unwrap();
+        let session = |indices: [usize; 3]| SessionContext {
+            aggnonce,
+            pubkeys: indices.iter().map(|&i| pubkeys[i]).collect(),
+            tweaks: Vec::new(),
+            msg: bytes::<32>(MSG).to_vec(),
+        };
+        // the partial signatures of test_sign, by the signer with the first
+        // public nonce
+        let psig = bytes("012ABBCB52B3016AC03AD82395A1A415C48B93DEF78718E62A7A90052FE224FB");
+        for (indices, psig, signer) in [
+            ([0, 1, 2], psig, 0),
+            (
+                [1, 0, 2],
+                bytes("9FF2F7AAA856150CC8819254218D3ADEEB0535269051897724F9DB3789513A52"),
+                1,
+            ),
+            (
+                [1, 2, 0],
+                bytes("FA23C359F6FAC4E7796BB93BC9F0532A95468C539BA20FF86D7C76ED92227900"),
+                2,
+            ),
+        ] {
+            assert_eq!(
+                session(indices).partial_sig_verify(&psig, &pubnonces[0], signer),
+                Ok(())
+            );
+        }
+
+        let session_012 = session([0, 1, 2]);
+        // the negated signature, the wrong signer, and a signature not below
+        // the group order
+        let negated = to_bytes(order() - U256::from_big_endian(&psig));
+        let too_big = bytes("FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFEBAAEDCE6AF48A03BBFD25E8CD0364141");
+        for (psig, signer) in [(negated, 0), (psig, 1), (too_big, 0)] {
+            assert_eq!(
+                session_012.partial_sig_verify(&psig, &pubnonces[0], signer),
+                Err(MusigError::InvalidPartialSig(signer))
+            );
+        }
+        let mut invalid = pubnonces[0];
+        invalid[..33].copy_from_slice(&bytes::<33>(
+            "020000000000000000000000000000000000000000000000000000000000000009",
+        ));
+        assert_eq!(
+            session_012.partial_sig_verify(&psig, &invalid, 0),
+            Err(MusigError::InvalidPubnonce(0))
+        );
+        assert_eq!(
+            session([3, 1, 2]).partial_sig_verify(&psig, &pubnonces[0], 0),
+            Err(MusigError::InvalidPubkey(0))
+        );
+    }
+
+    #[test]
+    fn test_sig_agg() {
+        // BIP327 sig_agg_vectors
+        let pubkeys: [[u8; 33]; 4] = [
+            "03935F972DA013F80AE011890FA89B67A27B7BE6CCB24D3274D18B2D4067F261A9",
+            "02D2DC6F5DF7C56ACF38C7FA0AE7A759AE30E19B37359DFDE015872324C7EF6E05",
+            "03C7FB101D97FF930ACD0C6760852EF64E69083DE0B06AC6335724754BB4B0522C",
+            "02352433B21E7E05D3B452B81CAE566E06D2E003ECE16D1074AABA4289E0E3D581",
+        ]
+        .map(bytes);
+        let pubnonces: [[u8; 66]; 6] = [
+            "036E5EE6E28824029FEA3E8A9DDD2C8483F5AF98F7177C3AF3CB6F47CAF8D94AE902DBA67E4A1F3680826172DA15AFB1A8CA85C7C5CC88900905C8DC8C328511B53E",
+            "03E4F798DA48A76EEC1C9CC5AB7A880FFBA201A5F064E627EC9CB0031D1D58FC5103E06180315C5A522B7EC7C08B69DCD721C313C940819296D0A7AB8E8795AC1F00",
+            "02C0068FD25523A31578B8077F24F78F5BD5F2422AFF47C1FADA0F36B3CEB6C7D202098A55D1736AA5FCC21CF0729CCE852575C06C081125144763C2C4C4A05C09B6",
+            "031F5C87DCFBFCF330DEE4311D85E8F1DEA01D87A6F1C14CDFC7E4F1D8C441CFA40277BF176E9F747C34F81B0D9F072B1B404A86F402C2D86CF9EA9E9C69876EA3B9",
+            "023F7042046E0397822C4144A17F8B63D78748696A46C3B9F0A901D296EC3406C302022B0B464292CF9751D699F10980AC764E6F671EFCA15069BBE62B0D1C62522A",
+            "02D97DDA5988461DF58C5897444F116A7C74E5711BF77A9446E27806563F3B6C47020CBAD9C363A7737F99FA06B6BE093CEAFF5397316C5AC46915C43767AE867C00",
+        ]
+        .map(bytes);
+        let tweaks: [[u8; 32]; 3] = [
+            "B511DA492182A91B0FFB9A98020D55F260AE86D7ECBD0399C7383D59A5F2AF7C",
+            "A815FE049EE3C5AAB66310477FBC8BCCCAC2F3395F59F921C364ACD78A2F48DC",
+            "75448A87274B056468B977BE06EB1E9F657577B7320B0A3376EA51FD420D18A8",
+        ]
+        .map(bytes);
+        let psigs: [[u8; 32]; 9] = [
+            "B15D2CD3C3D22B04DAE438CE653F6B4ECF042F42CFDED7C41B64AAF9B4AF53FB",
+            "6193D6AC61B354E9105BBDC8937A3454A6D705B6D57322A5A472A02CE99FCB64",
+            "9A87D3B79EC67228CB97878B76049B15DBD05B8158D17B5B9114D3C226887505",
+            "66F82EA90923689B855D36C6B7E032FB9970301481B99E01CDB4D6AC7C347A15",
+            "4F5AEE41510848A6447DCD1BBC78457EF69024944C87F40250D3EF2C25D33EFE",
+            "DDEF427BBB847CC027BEFF4EDB01038148917832253EBC355FC33F4A8E2FCCE4",
+            "97B890A26C981DA8102D3BC294159D171D72810FDF7C6A691DEF02F0F7AF3FDC",
+            "53FA9E08BA5243CBCB0D797C5EE83BC6728E539EB76C2D0BF0F971EE4E909971",
+            "FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFEBAAEDCE6AF48A03BBFD25E8CD0364141",
+        ]
+        .map(bytes);
+        let msg = bytes::<32>("599C67EA410D005B9DA90817CF03ED3B1C868E4DA4EDF00A5880B0082C237869");
+        let session =
+            |nonces: [usize; 2], keys: [usize; 2], tweak_indices: &[usize], is_xonly: &[bool]| {
+                SessionContext {
+                    aggnonce: nonce_agg(&nonces.map(|i| pubnonces[i])).unwrap(),
+                    pubkeys: keys.iter().map(|&i| pubkeys[i]).collect(),
+                    tweaks: tweak_indices
+                        .iter()
+                        .map(|&i| tweaks[i])
+                        .zip(is_xonly.iter().copied())
+                        .collect(),
+                    msg: msg.to_vec(),
+                }
+            };
+        for (nonces, keys, tweak_indices, is_xonly, psig_indices, expected) in [
+            (
+                [0, 1],
+                [0, 1],
+                &[][..],
+                &[][..],
+                [0, 1],
+                "041DA22223CE65C92C9A0D6C2CAC828AAF1EEE56304FEC371DDF91EBB2B9EF0912F1038025857FEDEB3FF696F8B99FA4BB2C5812F6095A2E0004EC99CE18DE1E",
+            ),
+            (
+                [0, 2],
+                [0, 2],
+                &[],
+                &[],
+                [2, 3],
+                "1069B67EC3D2F3C7C08291ACCB17A9C9B8F2819A52EB5DF8726E17E7D6B52E9F01800260A7E9DAC450F4BE522DE4CE12BA91AEAF2B4279219EF74BE1D286ADD9",
+            ),
+            (
+                [0, 3],
+                [0, 2],
+                &[0],
+                &[false],
+                [4, 5],
+                "5C558E1DCADE86DA0B2F02626A512E30A22CF5255CAEA7EE32C38E9A71A0E9148BA6C0E6EC7683B64220F0298696F1B878CD47B107B81F7188812D593971E0CC",
+            ),
+            (
+                [0, 4],
+                [0, 3],
+                &[0, 1, 2],
+                &[true, false, true],
+                [6, 7],
+                "839B08820B681DBA8DAF4CC7B104E8F2638F9388F8D7A555DC17B6E6971D7426CE07BF6AB01F1DB50E4E33719295F4094572B79868E440FB3DEFD3FAC1DB589E",
+            ),
+        ] {
+            let session = session(nonces, keys, tweak_indices, is_xonly);
+            let sig = session
+                .partial_sig_agg(&psig_indices.map(|i| psigs[i]))
+                .unwrap();
+            assert_eq!(hex::encode_upper(sig), expected);
+        }
+        // the second partial signature is not below the group order
+        let session = session([0, 4], [0, 3], &[0, 1, 2], &[true, false, true]);
+        assert_eq!(
+            session.partial_sig_agg(&[psigs[7], psigs[8]]),
+            Err(MusigError::InvalidPartialSig(1))
+        );
+    }
+
+    #[test]
+    fn test_tweaks() {
+        let pubkeys: [[u8; 33]; 3] = [
+            bytes("02F9308A019258C31049344F85F89D5229B531C845836F99B08601F113BCE036F9"),
+            bytes("02DFF1D77F2A671C5F36183726DB2341BE58FEAE1DA2DECED843240F7B502BA659"),
+            bytes("03935F972DA013F80AE011890FA89B67A27B7BE6CCB24D3274D18B2D4067F261A9"),
+        ];
+        let aggnonce = nonce_agg(&PUBNONCES.map(bytes)).unwrap();
+        let tweaks: [[u8; 32]; 4] = [
+            bytes("E8F791FF9225A2AF0102AFFF4A9A723D9612A682A25EBE79802B263CDFCD83BB"),
+            bytes("AE2EA797CC0FE72AC5B97B97F3C6957D7E4199A167A58EB08BCAFFDA70AC0455"),
+            bytes("F52ECBC565B3D8BEA2DFD5B75A4F457E54369809322E4120831626F290FA87E0"),
+            bytes("1969AD73CC177FA0B4FCED6DF1F7BF9907E665FDE9BA196A74FED0A3CF5AEF9D"),
+        ];
+        for (is_xonly, expected) in [
+            (
+                &[true][..],
+                "E28A5C66E61E178C2BA19DB77B6CF9F7E2F0F56C17918CD13135E60CC848FE91",
+            ),
+            (
+                &[false],
+                "38B0767798252F21BF5702C48028B095428320F73A4B14DB1E25DE58543D2D2D",
+            ),
+            (
+                &[false, true],
+                "408A0A21C4A0F5DACAF9646AD6EB6FECD7F7A11F03ED1F48DFFF2185BC2C2408",
+            ),
+            (
+                &[true, false, true, false],
+                "B255FDCAC27B40C7CE7848E2D3B7BF5EA0ED756DA81565AC804CCCA3E1D5D239",
+            ),
+        ] {
+            let session = SessionContext {
+                aggnonce,
+                pubkeys: pubkeys.to_vec(),
+                tweaks: tweaks
+                    .iter()
+                    .copied()
+                    .zip(is_xonly.iter().copied())
+                    .collect(),
+                msg: bytes::<32>(MSG).to_vec(),
+            };
+            let psig = session.sign(secnonce(SECNONCE), &bytes(SK)).unwrap();
+            assert_eq!(hex::encode_upper(psig), expected);
+        }
+
+        let keyagg = key_agg(&pubkeys[..1]).unwrap();
+        assert_eq!(
+            keyagg.apply_tweak(&bytes(&N.to_lowercase()), true),
+            Err(MusigError::InvalidTweak)
+        );
+    }
+
+    #[test]
+    fn test_taproot_signing() {
+        // two signers spend a key path only taproot output of their keys
+        let sks: [[u8; 32]; 2] = [[1; 32], [2; 32]];
+        let g = S256Point::get_the_generic_point();
+        let pubkeys = key_sort(&sks.map(|sk| (U256::from_big_endian(&sk) * g).compressed_sec()));
+        let keyagg = key_agg(&pubkeys).unwrap();
+        let internal_key = keyagg.get_xonly_pk();
+        let keyagg = keyagg.apply_taproot_tweak(None).unwrap();
+        let output_key = keyagg.get_xonly_pk();
+        assert_eq!(
+            tweak_key(&internal_key.serialize(), None).unwrap().0,
+            output_key.serialize()
+        );
+
+        let msg = [0x42; 32];
+        let mut secnonces = Vec::new();
+        let mut pubnonces = Vec::new();
+        for (i, sk) in sks.iter().enumerate() {
+            let pk = (U256::from_big_endian(sk) * g).compressed_sec();
+            let (secnonce, pubnonce) = nonce_gen(
+                &[i as u8; 32],
+                Some(sk),
+                &pk,
+                Some(&output_key.serialize()),
+                Some(&msg),
+                None,
+            )
+            .unwrap();
+            secnonces.push(secnonce);
+            pubnonces.push(pubnonce);
+        }
+        // signing applies the same tweak as apply_taproot_tweak above
+        let session = SessionContext {
+            aggnonce: nonce_agg(&pubnonces).unwrap(),
+            pubkeys,
+            tweaks: vec![(tagged_hash("TapTweak", &internal_key.serialize()), true)],
+            msg: msg.to_vec(),
+        };
+        let psigs: Vec<[u8; 32]> = secnonces
+            .into_iter()
+            .zip(&sks)
+            .map(|(secnonce, sk)| session.sign(secnonce, sk).unwrap())
+            .collect();
+        let signer = session
+            .pubkeys
+            .iter()
+            .position(|pk| *pk == (U256::from_big_endian(&sks[0]) * g).compressed_sec())
+            .unwrap();
+        assert_eq!(
+            session.partial_sig_verify(&psigs[0], &pubnonces[0], signer),
+            Ok(())
+        );
+        assert_eq!(
+            session.partial_sig_verify(&psigs[1], &pubnonces[0], signer),
+            Err(MusigError::InvalidPartialSig(signer))
+        );
+        let sig = session.partial_sig_agg(&psigs).unwrap();
+        assert!(output_key.verify_schnorr(&msg, &sig));
+        let mut sig = sig;
+        sig[63] ^= 1;
+        assert!(!output_key.verify_schnorr(&msg, &sig));
+    }
+}
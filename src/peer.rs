@@ -0,0 +1,548 @@
+use crate::{
+    message::{
+        Message, NetAddress, NetworkEnvelope, VersionMessage, MAX_PAYLOAD_SIZE, NODE_NETWORK,
+        NODE_WITNESS,
+    },
+    network::Network,
+    random::Rng,
+};
+use std::{
+    collections::HashMap,
+    io::{self, Cursor},
+    net::SocketAddr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
+    net::{tcp::OwnedWriteHalf, TcpStream},
+    sync::mpsc,
+    task::JoinHandle,
+    time::{self, Instant},
+};
+
+/// The version we announce: BIP339 wtxid relay.
+pub const PROTOCOL_VERSION: i32 = 70016;
+/// Oldest version we talk to, the first with BIP37's relay flag.
+pub const MIN_PROTOCOL_VERSION: i32 = 70001;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PeerError {
+    Io(io::ErrorKind),
+    /// The peer closed the connection.
+    Disconnected,
+    /// The handshake did not finish in time, or the peer went silent or
+    /// stopped answering pings.
+    Timeout,
+    /// A message the handshake does not allow at this point.
+    UnexpectedMessage(String),
+    ObsoleteVersion(i32),
+    MissingServices {
+        required: u64,
+        offered: u64,
+    },
+    /// The peer echoed our version nonce, so we are talking to ourselves.
+    SelfConnection,
+}
+
+impl From<io::Error> for PeerError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::UnexpectedEof => PeerError::Disconnected,
+            kind => PeerError::Io(kind),
+        }
+    }
+}
+
+/// What a handler wants done after seeing a message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    Continue,
+    Reply(Vec<Message>),
+    Disconnect,
+}
+
+pub type Handler = Box<dyn FnMut(&Message) -> Action + Send>;
+
+/// Our side of the handshake and the timeouts to enforce. The defaults
+/// follow Bitcoin Core.
+#[derive(Debug, Clone)]
+pub struct PeerConfig {
+    pub network: Network,
+    pub services: u64,
+    /// Services the peer must offer for the handshake to succeed.
+    pub required_services: u64,
+    pub user_agent: String,
+    pub start_height: i32,
+    pub relay: bool,
+    pub handshake_timeout: Duration,
+    pub ping_interval: Duration,
+    /// How long the peer may stay silent, or leave a ping unanswered.
+    pub timeout: Duration,
+}
+
+impl PeerConfig {
+    pub fn new(network: Network) -> Self {
+        Self {
+            network,
+            services: 0,
+            required_services: NODE_NETWORK | NODE_WITNESS,
+            user_agent: "/bitcoin-rs:0.1.0/".to_string(),
+            start_height: 0,
+            relay: false,
+            handshake_timeout: Duration::from_secs(60),
+            ping_interval: Duration::from_secs(2 * 60),
+            timeout: Duration::from_secs(20 * 60),
+        }
+    }
+
+    fn version_message(&self, addr: SocketAddr, nonce: u64) -> VersionMessage {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+        VersionMessage {
+            version: PROTOCOL_VERSION,
+            services: self.services,
+            timestamp,
+            receiver: NetAddress::new(addr, 0),
+            sender: NetAddress::new(SocketAddr::from(([0; 4], 0)), self.services),
+            nonce,
+            user_agent: self.user_agent.clone(),
+            start_height: self.start_height,
+            relay: self.relay,
+        }
+    }
+}
+
+/// Reads one message, checking the declared length before allocating.
+pub async fn read_envelope<R: AsyncRead + Unpin>(
+    stream: &mut R,
+    network: Network,
+) -> Result<NetworkEnvelope, PeerError> {
+    let mut raw = vec![0u8; 24];
+    stream.read_exact(&mut raw).await?;
+    let length = u32::from_le_bytes(raw[16..20].try_into().unwrap());
+    if length > MAX_PAYLOAD_SIZE {
+        return Err(PeerError::Io(io::ErrorKind::InvalidData));
+    }
+    raw.resize(24 + length as usize, 0);
+    stream.read_exact(&mut raw[24..]).await?;
+    Ok(NetworkEnvelope::parse(&mut Cursor::new(raw), network)?)
+}
+
+/// The handshake as seen from our side: once the peer's version arrives we
+/// wait for its verack.
+enum Handshake {
+    AwaitingVersion,
+    AwaitingVerack(VersionMessage),
+}
+
+/// A connection to another node after a completed version handshake.
+///
+/// A background task reads and decodes messages; `receive` answers pings,
+/// sends our own and fails with `Timeout` when the peer stalls. `run`
+/// passes every other message to the handler registered for its command.
+pub struct Peer {
+    config: PeerConfig,
+    addr: SocketAddr,
+    writer: OwnedWriteHalf,
+    incoming: mpsc::Receiver<Result<NetworkEnvelope, PeerError>>,
+    reader: JoinHandle<()>,
+    remote: VersionMessage,
+    rng: Rng,
+    last_received: Instant,
+    next_ping: Instant,
+    pending_ping: Option<(u64, Instant)>,
+    handlers: HashMap<String, Handler>,
+}
+
+impl Peer {
+    pub async fn connect(addr: SocketAddr, config: PeerConfig) -> Result<Self, PeerError> {
+        let stream = time::timeout(config.handshake_timeout, TcpStream::connect(addr))
+            .await
+            .map_err(|_| PeerError::Timeout)??;
+        Self::handshake(stream, config, false).await
+    }
+
+    /// Runs the handshake on an inbound connection, where the peer speaks
+    /// first.
+    pub async fn accept(stream: TcpStream, config: PeerConfig) -> Result<Self, PeerError> {
+        Self::handshake(stream, config, true).await
+    }
+
+    async fn handshake(
+        stream: TcpStream,
+        config: PeerConfig,
+        inbound: bool,
+    ) -> Result<Self, PeerError> {
+        let addr = stream.peer_addr()?;
+        let (mut reader, writer) = stream.into_split();
+        let (sender, incoming) = mpsc::channel(16);
+        let network = config.network;
+        let reader = tokio::spawn(async move {
+            loop {
+                let envelope = read_envelope(&mut reader, network).await;
+                let failed = envelope.is_err();
+                if sender.send(envelope).await.is_err() || failed {
+                    break;
+                }
+            }
+        });
+        let mut rng = Rng::from_entropy();
+        let ours = config.version_message(addr, rng.next_u64());
+        let now = Instant::now();
+        let mut peer = Self {
+            addr,
+            writer,
+            incoming,
+            reader,
+            remote: ours.clone(),
+            rng,
+            last_received: now,
+            next_ping: now + config.ping_interval,
+            pending_ping: None,
+            handlers: HashMap::new(),
+            config,
+        };
+        let timeout = peer.config.handshake_timeout;
+        time::timeout(timeout, peer.exchange_versions(ours, inbound))
+            .await
+            .map_err(|_| PeerError::Timeout)??;
+        Ok(peer)
+    }
+
+    async fn exchange_versions(
+        &mut self,
+        ours: VersionMessage,
+        inbound: bool,
+    ) -> Result<(), PeerError> {
+        if !inbound {
+            self.send(&Message::Version(ours.clone())).await?;
+        }
+        let mut state = Handshake::AwaitingVersion;
+        loop {
+            let envelope = self.next_envelope().await?;
+            let message = Message::from_envelope(&envelope)?;
+            state = match (state, message) {
+                (Handshake::AwaitingVersion, Message::Version(theirs)) => {
+                    if theirs.nonce == ours.nonce {
+                        return Err(PeerError::SelfConnection);
+                    }
+                    if theirs.version < MIN_PROTOCOL_VERSION {
+                        return Err(PeerError::ObsoleteVersion(theirs.version));
+                    }
+                    let required = self.config.required_services;
+                    if theirs.services & required != required {
+                        return Err(PeerError::MissingServices {
+                            required,
+                            offered: theirs.services,
+                        });
+                    }
+                    if inbound {
+                        self.send(&Message::Version(ours.clone())).await?;
+                    }
+                    self.send(&Message::Verack).await?;
+                    Handshake::AwaitingVerack(theirs)
+                }
+                (Handshake::AwaitingVerack(theirs), Message::Verack) => {
+                    self.remote = theirs;
+                    return Ok(());
+                }
+                // feature negotiation between version and verack, BIP155 and BIP339
+                (Handshake::AwaitingVerack(theirs), Message::Unknown { command, .. })
+                    if command == "sendaddrv2" || command == "wtxidrelay" =>
+                {
+                    Handshake::AwaitingVerack(theirs)
+                }
+                (_, message) => {
+                    return Err(PeerError::UnexpectedMessage(message.command().to_string()))
+                }
+            };
+        }
+    }
+
+    async fn next_envelope(&mut self) -> Result<NetworkEnvelope, PeerError> {
+        let envelope = self
+            .incoming
+            .recv()
+            .await
+            .unwrap_or(Err(PeerError::Disconnected))?;
+        self.last_received = Instant::now();
+        Ok(envelope)
+    }
+
+    pub fn get_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// The peer's version message.
+    pub fn get_remote(&self) -> &VersionMessage {
+        &self.remote
+    }
+
+    /// The protocol version both sides speak.
+    pub fn get_version(&self) -> i32 {
+        self.remote.version.min(PROTOCOL_VERSION)
+    }
+
+    pub fn get_services(&self) -> u64 {
+        self.remote.services
+    }
+
+    pub async fn send(&mut self, message: &Message) -> Result<(), PeerError> {
+        let raw = message.to_envelope(self.config.network).serialize();
+        self.writer.write_all(&raw).await?;
+        Ok(())
+    }
+
+    /// Waits for the next message other than ping and pong.
+    pub async fn receive(&mut self) -> Result<Message, PeerError> {
+        loop {
+            let mut deadline = (self.last_received + self.config.timeout).min(self.next_ping);
+            if let Some((_, sent)) = self.pending_ping {
+                deadline = deadline.min(sent + self.config.timeout);
+            }
+            tokio::select! {
+                envelope = self.next_envelope() => {
+                    match Message::from_envelope(&envelope?)? {
+                        Message::Ping(nonce) => self.send(&Message::Pong(nonce)).await?,
+                        Message::Pong(nonce) => {
+                            if matches!(self.pending_ping, Some((sent, _)) if sent == nonce) {
+                                self.pending_ping = None;
+                            }
+                        }
+                        message => return Ok(message),
+                    }
+                }
+                _ = time::sleep_until(deadline) => {
+                    let now = Instant::now();
+                    let unanswered = matches!(
+                        self.pending_ping,
+                        Some((_, sent)) if now >= sent + self.config.timeout
+                    );
+                    if unanswered || now >= self.last_received + self.config.timeout {
+                        return Err(PeerError::Timeout);
+                    }
+                    if now >= self.next_ping {
+                        self.next_ping = now + self.config.ping_interval;
+                        if self.pending_ping.is_none() {
+                            let nonce = self.rng.next_u64();
+                            self.pending_ping = Some((nonce, now));
+                            self.send(&Message::Ping(nonce)).await?;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Registers the handler for messages with `command`, replacing any
+    /// previous one.
+    pub fn on<F>(&mut self, command: &str, handler: F)
+    where
+        F: FnMut(&Message) -> Action + Send + 'static,
+    {
+        self.handlers.insert(command.to_string(), Box::new(handler));
+    }
+
+    /// Dispatches messages to the handlers until one asks to disconnect or
+    /// the peer hangs up. Messages without a handler are dropped.
+    pub async fn run(&mut self) -> Result<(), PeerError> {
+        loop {
+            let message = match self.receive().await {
+                Err(PeerError::Disconnected) => return Ok(()),
+                message => message?,
+            };
+            let Some(handler) = self.handlers.get_mut(message.command()) else {
+                continue;
+            };
+            match handler(&message) {
+                Action::Continue => {}
+                Action::Reply(replies) => {
+                    for reply in &replies {
+                        self.send(reply).await?;
+                    }
+                }
+                Action::Disconnect => return Ok(()),
+            }
+        }
+    }
+}
+
+impl Drop for Peer {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{Inventory, MSG_BLOCK};
+    use tokio::net::TcpListener;
+
+    /// The other end of the connection, driven by hand.
+    struct MockPeer {
+        stream: TcpStream,
+    }
+
+    impl MockPeer {
+        async fn send(&mut self, message: &Message) {
+            let raw = message.to_envelope(Network::Regtest).serialize();
+            self.stream.write_all(&raw).await.unwrap();
+        }
+
+        async fn receive(&mut self) -> Message {
+            let envelope = read_envelope(&mut self.stream, Network::Regtest)
+                .await
+                .unwrap();
+            Message::from_envelope(&envelope).unwrap()
+        }
+
+        /// Answers an outbound handshake offering `services`.
+        async fn handshake(&mut self, services: u64) -> VersionMessage {
+            let theirs = self.send_version(services).await;
+            assert_eq!(self.receive().await, Message::Verack);
+            self.send(&Message::Verack).await;
+            theirs
+        }
+
+        async fn send_version(&mut self, services: u64) -> VersionMessage {
+            let Message::Version(theirs) = self.receive().await else {
+                panic!("expected version");
+            };
+            let mut ours = PeerConfig::new(Network::Regtest).version_message(
+                self.stream.peer_addr().unwrap(),
+                theirs.nonce.wrapping_add(1),
+            );
+            ours.services = services;
+            ours.version = 70015;
+            ours.user_agent = "/mock:0.1/".to_string();
+            self.send(&Message::Version(ours)).await;
+            self.send(&Message::Unknown {
+                command: "sendaddrv2".to_string(),
+                payload: vec![],
+            })
+            .await;
+            theirs
+        }
+    }
+
+    fn config() -> PeerConfig {
+        let mut config = PeerConfig::new(Network::Regtest);
+        config.handshake_timeout = Duration::from_millis(500);
+        config.ping_interval = Duration::from_millis(50);
+        config.timeout = Duration::from_millis(200);
+        config
+    }
+
+    async fn listen() -> (TcpListener, SocketAddr) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        (listener, addr)
+    }
+
+    #[tokio::test]
+    async fn test_handshake_and_handlers() {
+        let (listener, addr) = listen().await;
+        let mock = tokio::spawn(async move {
+            let mut mock = MockPeer {
+                stream: listener.accept().await.unwrap().0,
+            };
+            let theirs = mock.handshake(NODE_NETWORK | NODE_WITNESS).await;
+            assert_eq!(theirs.version, PROTOCOL_VERSION);
+
+            mock.send(&Message::Ping(42)).await;
+            assert_eq!(mock.receive().await, Message::Pong(42));
+            mock.send(&Message::SendHeaders).await;
+            let inv = vec![Inventory::new(MSG_BLOCK, [7; 32])];
+            mock.send(&Message::Inv(inv.clone())).await;
+            assert_eq!(mock.receive().await, Message::GetData(inv));
+            mock.send(&Message::FeeFilter(1000)).await;
+        });
+
+        let mut config = config();
+        config.ping_interval = Duration::from_secs(5);
+        config.timeout = Duration::from_secs(5);
+        let mut peer = Peer::connect(addr, config).await.unwrap();
+        assert_eq!(peer.get_addr(), addr);
+        assert_eq!(peer.get_version(), 70015);
+        assert_eq!(peer.get_services(), NODE_NETWORK | NODE_WITNESS);
+        assert_eq!(peer.get_remote().user_agent, "/mock:0.1/");
+        peer.on("inv", |message| match message {
+            Message::Inv(items) => Action::Reply(vec![Message::GetData(items.clone())]),
+            _ => unreachable!(),
+        });
+        peer.on("feefilter", |_| Action::Disconnect);
+        peer.run().await.unwrap();
+        mock.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_handshakes() {
+        let (listener, addr) = listen().await;
+        let mock = tokio::spawn(async move {
+            let mut mock = MockPeer {
+                stream: listener.accept().await.unwrap().0,
+            };
+            mock.send_version(NODE_NETWORK).await;
+            time::sleep(Duration::from_secs(5)).await;
+        });
+        assert_eq!(
+            Peer::connect(addr, config()).await.err(),
+            Some(PeerError::MissingServices {
+                required: NODE_NETWORK | NODE_WITNESS,
+                offered: NODE_NETWORK,
+            })
+        );
+        mock.abort();
+
+        // a silent peer
+        let (listener, addr) = listen().await;
+        let mock = tokio::spawn(async move {
+            let stream = listener.accept().await.unwrap().0;
+            time::sleep(Duration::from_secs(5)).await;
+            drop(stream);
+        });
+        assert_eq!(
+            Peer::connect(addr, config()).await.err(),
+            Some(PeerError::Timeout)
+        );
+        mock.abort();
+
+        // the inbound side speaks second
+        let (listener, addr) = listen().await;
+        let inbound = tokio::spawn(async move {
+            let stream = listener.accept().await.unwrap().0;
+            let mut config = config();
+            config.services = NODE_NETWORK | NODE_WITNESS;
+            config.required_services = 0;
+            Peer::accept(stream, config)
+                .await
+                .map(|peer| peer.get_services())
+        });
+        let outbound = Peer::connect(addr, config()).await.unwrap();
+        assert_eq!(outbound.get_services(), NODE_NETWORK | NODE_WITNESS);
+        assert_eq!(outbound.get_version(), PROTOCOL_VERSION);
+        assert_eq!(inbound.await.unwrap(), Ok(0));
+    }
+
+    #[tokio::test]
+    async fn test_stalled_peer() {
+        let (listener, addr) = listen().await;
+        let mock = tokio::spawn(async move {
+            let mut mock = MockPeer {
+                stream: listener.accept().await.unwrap().0,
+            };
+            mock.handshake(NODE_NETWORK | NODE_WITNESS).await;
+            // pings go unanswered
+            assert!(matches!(mock.receive().await, Message::Ping(_)));
+            time::sleep(Duration::from_secs(5)).await;
+        });
+        let mut peer = Peer::connect(addr, config()).await.unwrap();
+        let started = Instant::now();
+        assert_eq!(peer.run().await, Err(PeerError::Timeout));
+        assert!(started.elapsed() >= Duration::from_millis(200));
+        mock.abort();
+    }
+}
@@ -79,10 +79,10 @@ impl Add for Point {
         if (self.a != rhs.a) || (self.b != rhs.b) {
             panic!("Points {:?}, {:?} are not on the same curve", self, rhs);
         }
-        if self.z == None {
+        if self.z.is_none() {
             return rhs;
         }
-        if rhs.z == None {
+        if rhs.z.is_none() {
             return self;
         }
         let sz = self.z.unwrap();
@@ -170,10 +170,12 @@ impl MulAssign<U256> for Point {
 
 impl fmt::Display for Point {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        if self.z == None {
+        if let Some(z) = self.z {
             write!(
                 f,
-                "Identity on y^2=x^3+{}x+{} mod {}",
+                "({}, {}) on y^2=x^3+{}x+{} mod {}",
+                z.x.get_num(),
+                z.y.get_num(),
                 self.a.get_num(),
                 self.b.get_num(),
                 self.a.get_prime()
@@ -181,9 +183,7 @@ impl fmt::Display for Point {
         } else {
             write!(
                 f,
-                "({}, {}) on y^2=x^3+{}x+{} mod {}",
-                self.z.unwrap().x.get_num(),
-                self.z.unwrap().y.get_num(),
+                "Identity on y^2=x^3+{}x+{} mod {}",
                 self.a.get_num(),
                 self.b.get_num(),
                 self.a.get_prime()
@@ -0,0 +1,1733 @@
+use crate::{
+    base58::{hash160, hash256},
+    script::{Command, Script, OP_1, OP_16, OP_CHECKMULTISIG, OP_CHECKSIG},
+    sighash::{legacy_sighash, p2wpkh_script_code, segwit_v0_sighash, SIGHASH_ALL},
+    signature::PrivateKey,
+    tx::{parse_witness, serialize_witness, OutPoint, Tx, TxIn, TxOut, SEQUENCE_FINAL},
+    varint::{encode_varint, read_varint},
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use primitive_types::U256;
+use ripemd::Ripemd160;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::BTreeMap,
+    fmt,
+    io::{self, Cursor, Read},
+    str::FromStr,
+};
+
+const PSBT_MAGIC: &[u8; 5] = b"psbt\xff";
+
+const PSBT_GLOBAL_UNSIGNED_TX: u64 = 0x00;
+const PSBT_GLOBAL_XPUB: u64 = 0x01;
+const PSBT_GLOBAL_TX_VERSION: u64 = 0x02;
+const PSBT_GLOBAL_FALLBACK_LOCKTIME: u64 = 0x03;
+const PSBT_GLOBAL_INPUT_COUNT: u64 = 0x04;
+const PSBT_GLOBAL_OUTPUT_COUNT: u64 = 0x05;
+const PSBT_GLOBAL_TX_MODIFIABLE: u64 = 0x06;
+const PSBT_GLOBAL_VERSION: u64 = 0xfb;
+
+const PSBT_IN_NON_WITNESS_UTXO: u64 = 0x00;
+const PSBT_IN_WITNESS_UTXO: u64 = 0x01;
+const PSBT_IN_PARTIAL_SIG: u64 = 0x02;
+const PSBT_IN_SIGHASH_TYPE: u64 = 0x03;
+const PSBT_IN_REDEEM_SCRIPT: u64 = 0x04;
+const PSBT_IN_WITNESS_SCRIPT: u64 = 0x05;
+const PSBT_IN_BIP32_DERIVATION: u64 = 0x06;
+const PSBT_IN_FINAL_SCRIPTSIG: u64 = 0x07;
+const PSBT_IN_FINAL_SCRIPTWITNESS: u64 = 0x08;
+const PSBT_IN_POR_COMMITMENT: u64 = 0x09;
+const PSBT_IN_RIPEMD160: u64 = 0x0a;
+const PSBT_IN_SHA256: u64 = 0x0b;
+const PSBT_IN_HASH160: u64 = 0x0c;
+const PSBT_IN_HASH256: u64 = 0x0d;
+const PSBT_IN_PREVIOUS_TXID: u64 = 0x0e;
+const PSBT_IN_OUTPUT_INDEX: u64 = 0x0f;
+const PSBT_IN_SEQUENCE: u64 = 0x10;
+const PSBT_IN_REQUIRED_TIME_LOCKTIME: u64 = 0x11;
+const PSBT_IN_REQUIRED_HEIGHT_LOCKTIME: u64 = 0x12;
+const PSBT_IN_TAP_KEY_SIG: u64 = 0x13;
+const PSBT_IN_TAP_SCRIPT_SIG: u64 = 0x14;
+const PSBT_IN_TAP_LEAF_SCRIPT: u64 = 0x15;
+const PSBT_IN_TAP_BIP32_DERIVATION: u64 = 0x16;
+const PSBT_IN_TAP_INTERNAL_KEY: u64 = 0x17;
+const PSBT_IN_TAP_MERKLE_ROOT: u64 = 0x18;
+
+const PSBT_OUT_REDEEM_SCRIPT: u64 = 0x00;
+const PSBT_OUT_WITNESS_SCRIPT: u64 = 0x01;
+const PSBT_OUT_BIP32_DERIVATION: u64 = 0x02;
+const PSBT_OUT_AMOUNT: u64 = 0x03;
+const PSBT_OUT_SCRIPT: u64 = 0x04;
+const PSBT_OUT_TAP_INTERNAL_KEY: u64 = 0x05;
+const PSBT_OUT_TAP_TREE: u64 = 0x06;
+const PSBT_OUT_TAP_BIP32_DERIVATION: u64 = 0x07;
+
+const PSBT_PROPRIETARY: u64 = 0xfc;
+
+/// Key types whose key is only the type. Those only in version 2 are
+/// checked separately, since they are unknown types in version 0.
+const PSBT_GLOBAL_KEYLESS: &[u64] = &[PSBT_GLOBAL_UNSIGNED_TX, PSBT_GLOBAL_VERSION];
+const PSBT_IN_KEYLESS: &[u64] = &[
+    PSBT_IN_NON_WITNESS_UTXO,
+    PSBT_IN_WITNESS_UTXO,
+    PSBT_IN_SIGHASH_TYPE,
+    PSBT_IN_REDEEM_SCRIPT,
+    PSBT_IN_WITNESS_SCRIPT,
+    PSBT_IN_FINAL_SCRIPTSIG,
+    PSBT_IN_FINAL_SCRIPTWITNESS,
+    PSBT_IN_POR_COMMITMENT,
+    PSBT_IN_TAP_KEY_SIG,
+    PSBT_IN_TAP_INTERNAL_KEY,
+    PSBT_IN_TAP_MERKLE_ROOT,
+];
+const PSBT_OUT_KEYLESS: &[u64] = &[
+    PSBT_OUT_REDEEM_SCRIPT,
+    PSBT_OUT_WITNESS_SCRIPT,
+    PSBT_OUT_TAP_INTERNAL_KEY,
+    PSBT_OUT_TAP_TREE,
+];
+
+/// nLockTime values below this are block heights, above it timestamps.
+const LOCKTIME_THRESHOLD: u32 = 500_000_000;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PsbtError {
+    Io(io::ErrorKind),
+    InvalidBase64,
+    InvalidMagic,
+    DuplicateKey(Vec<u8>),
+    /// The key data does not fit the key type.
+    InvalidKey(Vec<u8>),
+    /// The value for this key does not parse.
+    InvalidValue(Vec<u8>),
+    UnsupportedVersion(u32),
+    /// This key is not allowed in the PSBT's version.
+    KeyNotAllowed(Vec<u8>),
+    MissingField(&'static str),
+    UnsignedTxHasScriptSigs,
+    /// The non-witness UTXO of this input is not the transaction it spends.
+    UtxoMismatch(usize),
+    /// The redeem or witness script of this input does not hash to its output.
+    ScriptMismatch(usize),
+    /// No locktime satisfies every input's required locktime (BIP370).
+    LocktimeConflict,
+    /// Combining PSBTs of different transactions.
+    DifferentTx,
+    CannotFinalize(usize),
+    NotFinalized(usize),
+}
+
+impl From<io::Error> for PsbtError {
+    fn from(e: io::Error) -> Self {
+        PsbtError::Io(e.kind())
+    }
+}
+
+/// The master key fingerprint and BIP32 derivation path of a key.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct KeySource {
+    pub fingerprint: [u8; 4],
+    pub path: Vec<u32>,
+}
+
+impl KeySource {
+    pub fn new(fingerprint: [u8; 4], path: Vec<u32>) -> Self {
+        Self { fingerprint, path }
+    }
+
+    pub fn parse(b: &[u8]) -> Option<Self> {
+        if b.len() < 4 || !b.len().is_multiple_of(4) {
+            return None;
+        }
+        let path = b[4..]
+            .chunks(4)
+            .map(|c| u32::from_le_bytes(c.try_into().unwrap()))
+            .collect();
+        Some(Self::new(b[..4].try_into().unwrap(), path))
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut ret = self.fingerprint.to_vec();
+        for i in &self.path {
+            ret.extend(i.to_le_bytes());
+        }
+        ret
+    }
+}
+
+/// Key origin of a taproot key and the hashes of the leaves it signs in.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct TapKeySource {
+    pub leaf_hashes: Vec<[u8; 32]>,
+    pub source: KeySource,
+}
+
+impl TapKeySource {
+    pub fn parse(b: &[u8]) -> Option<Self> {
+        let mut stream = Cursor::new(b);
+        let count = read_varint(&mut stream).ok()?;
+        let mut leaf_hashes = Vec::new();
+        for _ in 0..count {
+            let mut hash = [0u8; 32];
+            stream.read_exact(&mut hash).ok()?;
+            leaf_hashes.push(hash);
+        }
+        let source = KeySource::parse(&b[stream.position() as usize..])?;
+        Some(Self {
+            leaf_hashes,
+            source,
+        })
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut ret = encode_varint(self.leaf_hashes.len() as u64);
+        for hash in &self.leaf_hashes {
+            ret.extend(hash);
+        }
+        ret.extend(self.source.serialize());
+        ret
+    }
+}
+
+/// A leaf of a PSBT_OUT_TAP_TREE, listed depth first.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TapTreeLeaf {
+    pub depth: u8,
+    pub leaf_version: u8,
+    pub script: Script,
+}
+
+/// Per-input fields (BIP174, BIP370 and BIP371).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Input {
+    pub non_witness_utxo: Option<Tx>,
+    pub witness_utxo: Option<TxOut>,
+    /// Signatures with their sighash byte, by SEC public key.
+    pub partial_sigs: BTreeMap<Vec<u8>, Vec<u8>>,
+    pub sighash_type: Option<u32>,
+    pub redeem_script: Option<Script>,
+    pub witness_script: Option<Script>,
+    pub bip32_derivation: BTreeMap<Vec<u8>, KeySource>,
+    pub final_script_sig: Option<Script>,
+    pub final_script_witness: Option<Vec<Vec<u8>>>,
+    pub por_commitment: Option<String>,
+    pub ripemd160_preimages: BTreeMap<[u8; 20], Vec<u8>>,
+    pub sha256_preimages: BTreeMap<[u8; 32], Vec<u8>>,
+    pub hash160_preimages: BTreeMap<[u8; 20], Vec<u8>>,
+    pub hash256_preimages: BTreeMap<[u8; 32], Vec<u8>>,
+    /// Version 2 only, in internal byte order.
+    pub previous_txid: Option<[u8; 32]>,
+    /// Version 2 only.
+    pub output_index: Option<u32>,
+    /// Version 2 only.
+    pub sequence: Option<u32>,
+    /// Version 2 only.
+    pub required_time_locktime: Option<u32>,
+    /// Version 2 only.
+    pub required_height_locktime: Option<u32>,
+    pub tap_key_sig: Option<Vec<u8>>,
+    /// Script path signatures by x-only key and leaf hash.
+    pub tap_script_sigs: BTreeMap<([u8; 32], [u8; 32]), Vec<u8>>,
+    /// Leaf scripts and their leaf versions by control block.
+    pub tap_leaf_scripts: BTreeMap<Vec<u8>, (Script, u8)>,
+    pub tap_bip32_derivation: BTreeMap<[u8; 32], TapKeySource>,
+    pub tap_internal_key: Option<[u8; 32]>,
+    pub tap_merkle_root: Option<[u8; 32]>,
+    pub proprietary: BTreeMap<Vec<u8>, Vec<u8>>,
+    pub unknown: BTreeMap<Vec<u8>, Vec<u8>>,
+}
+
+/// Per-output fields (BIP174, BIP370 and BIP371).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Output {
+    pub redeem_script: Option<Script>,
+    pub witness_script: Option<Script>,
+    pub bip32_derivation: BTreeMap<Vec<u8>, KeySource>,
+    /// Version 2 only.
+    pub amount: Option<u64>,
+    /// Version 2 only.
+    pub script: Option<Script>,
+    pub tap_internal_key: Option<[u8; 32]>,
+    pub tap_tree: Option<Vec<TapTreeLeaf>>,
+    pub tap_bip32_derivation: BTreeMap<[u8; 32], TapKeySource>,
+    pub proprietary: BTreeMap<Vec<u8>, Vec<u8>>,
+    pub unknown: BTreeMap<Vec<u8>, Vec<u8>>,
+}
+
+/// A Partially Signed Bitcoin Transaction (BIP174), in version 0 or the
+/// version 2 layout of BIP370.
+///
+/// Version 0 carries the unsigned transaction in `tx`. Version 2 spreads it
+/// over `tx_version`, `fallback_locktime` and the per-input and per-output
+/// fields; `unsigned_tx` rebuilds it for either version.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Psbt {
+    /// Version 0 only.
+    pub tx: Option<Tx>,
+    /// Key origins by serialized extended public key.
+    pub xpubs: BTreeMap<Vec<u8>, KeySource>,
+    /// Version 2 only.
+    pub tx_version: Option<u32>,
+    /// Version 2 only.
+    pub fallback_locktime: Option<u32>,
+    /// Version 2 only.
+    pub tx_modifiable: Option<u8>,
+    pub version: u32,
+    pub proprietary: BTreeMap<Vec<u8>, Vec<u8>>,
+    pub unknown: BTreeMap<Vec<u8>, Vec<u8>>,
+    pub inputs: Vec<Input>,
+    pub outputs: Vec<Output>,
+}
+
+type Pair = (Vec<u8>, Vec<u8>);
+
+fn read_bytes<R: Read>(stream: &mut R, length: u64) -> io::Result<Vec<u8>> {
+    let mut ret = Vec::new();
+    stream.take(length).read_to_end(&mut ret)?;
+    if ret.len() as u64 != length {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(ret)
+}
+
+/// Reads key-value pairs up to the 0x00 separator.
+fn read_map<R: Read>(stream: &mut R) -> Result<Vec<Pair>, PsbtError> {
+    let mut pairs: Vec<Pair> = Vec::new();
+    loop {
+        let key_len = read_varint(stream)?;
+        if key_len == 0 {
+            return Ok(pairs);
+        }
+        let key = read_bytes(stream, key_len)?;
+        let value_len = read_varint(stream)?;
+        let value = read_bytes(stream, value_len)?;
+        if pairs.iter().any(|(k, _)| *k == key) {
+            return Err(PsbtError::DuplicateKey(key));
+        }
+        pairs.push((key, value));
+    }
+}
+
+/// Splits a key into its type and key data.
+fn split_key(key: &[u8]) -> Result<(u64, &[u8]), PsbtError> {
+    let key_type =
+        read_varint(&mut Cursor::new(key)).map_err(|_| PsbtError::InvalidKey(key.to_vec()))?;
+    Ok((key_type, &key[encode_varint(key_type).len()..]))
+}
+
+/// Splits a key like `split_key`, rejecting key data on a type that is
+/// known in this version and takes none. The version 2 only types are
+/// unknown in version 0, so keys of those types are kept there.
+fn split_known_key<'a>(
+    key: &'a [u8],
+    keyless: &[u64],
+    version_2_only: impl Fn(u64) -> bool,
+    version: u32,
+) -> Result<(u64, &'a [u8]), PsbtError> {
+    let (key_type, key_data) = split_key(key)?;
+    let known = keyless.contains(&key_type) || (version >= 2 && version_2_only(key_type));
+    if known && !key_data.is_empty() {
+        return Err(PsbtError::InvalidKey(key.to_vec()));
+    }
+    Ok((key_type, key_data))
+}
+
+fn write_pair(ret: &mut Vec<u8>, key_type: u64, key_data: &[u8], value: &[u8]) {
+    let key_type = encode_varint(key_type);
+    ret.extend(encode_varint((key_type.len() + key_data.len()) as u64));
+    ret.extend(key_type);
+    ret.extend(key_data);
+    ret.extend(encode_varint(value.len() as u64));
+    ret.extend(value);
+}
+
+fn write_raw_pairs(ret: &mut Vec<u8>, pairs: &BTreeMap<Vec<u8>, Vec<u8>>) {
+    for (key, value) in pairs {
+        ret.extend(encode_varint(key.len() as u64));
+        ret.extend(key);
+        ret.extend(encode_varint(value.len() as u64));
+        ret.extend(value);
+    }
+}
+
+fn to_array<const N: usize>(
+    b: &[u8],
+    err: impl FnOnce() -> PsbtError,
+) -> Result<[u8; N], PsbtError> {
+    b.try_into().map_err(|_| err())
+}
+
+fn parse_u32(key: &[u8], value: &[u8]) -> Result<u32, PsbtError> {
+    let b = to_array(value, || PsbtError::InvalidValue(key.to_vec()))?;
+    Ok(u32::from_le_bytes(b))
+}
+
+/// Parses `value` with `parse`, which must consume all of it.
+fn parse_all<'a, T>(
+    key: &[u8],
+    value: &'a [u8],
+    parse: impl FnOnce(&mut Cursor<&'a [u8]>) -> io::Result<T>,
+) -> Result<T, PsbtError> {
+    let mut stream = Cursor::new(value);
+    match parse(&mut stream) {
+        Ok(ret) if stream.position() as usize == value.len() => Ok(ret),
+        _ => Err(PsbtError::InvalidValue(key.to_vec())),
+    }
+}
+
+fn is_pubkey(b: &[u8]) -> bool {
+    (b.len() == 33 && (b[0] == 2 || b[0] == 3)) || (b.len() == 65 && b[0] == 4)
+}
+
+fn is_taproot_sig(b: &[u8]) -> bool {
+    b.len() == 64 || b.len() == 65
+}
+
+fn sha256(b: &[u8]) -> Vec<u8> {
+    Sha256::digest(b).to_vec()
+}
+
+/// Merges `other` into `map`, keeping existing entries.
+fn merge_map<K: Ord, V>(map: &mut BTreeMap<K, V>, other: BTreeMap<K, V>) {
+    for (k, v) in other {
+        map.entry(k).or_insert(v);
+    }
+}
+
+impl Input {
+    fn parse_map(pairs: Vec<Pair>, version: u32) -> Result<Self, PsbtError> {
+        let mut input = Self::default();
+        for (key, value) in pairs {
+            let version_2_only = |key_type| {
+                (PSBT_IN_PREVIOUS_TXID..=PSBT_IN_REQUIRED_HEIGHT_LOCKTIME).contains(&key_type)
+            };
+            let (key_type, key_data) =
+                split_known_key(&key, PSBT_IN_KEYLESS, version_2_only, version)?;
+            let invalid_key = || PsbtError::InvalidKey(key.clone());
+            let invalid_value = || PsbtError::InvalidValue(key.clone());
+            if version_2_only(key_type) && version < 2 {
+                if key_data.is_empty() {
+                    return Err(PsbtError::KeyNotAllowed(key));
+                }
+                input.unknown.insert(key, value);
+                continue;
+            }
+            match key_type {
+                PSBT_IN_NON_WITNESS_UTXO => {
+                    input.non_witness_utxo = Some(parse_all(&key, &value, Tx::parse)?);
+                }
+                PSBT_IN_WITNESS_UTXO => {
+                    input.witness_utxo = Some(parse_all(&key, &value, TxOut::parse)?);
+                }
+                PSBT_IN_PARTIAL_SIG => {
+                    if !is_pubkey(key_data) {
+                        return Err(invalid_key());
+                    }
+                    input.partial_sigs.insert(key_data.to_vec(), value);
+                }
+                PSBT_IN_SIGHASH_TYPE => {
+                    input.sighash_type = Some(parse_u32(&key, &value)?);
+                }
+                PSBT_IN_REDEEM_SCRIPT => {
+                    input.redeem_script = Some(Script::from_bytes(value));
+                }
+                PSBT_IN_WITNESS_SCRIPT => {
+                    input.witness_script = Some(Script::from_bytes(value));
+                }
+                PSBT_IN_BIP32_DERIVATION => {
+                    if !is_pubkey(key_data) {
+                        return Err(invalid_key());
+                    }
+                    let source = KeySource::parse(&value).ok_or_else(invalid_value)?;
+                    input.bip32_derivation.insert(key_data.to_vec(), source);
+                }
+                PSBT_IN_FINAL_SCRIPTSIG => {
+                    input.final_script_sig = Some(Script::from_bytes(value));
+                }
+                PSBT_IN_FINAL_SCRIPTWITNESS => {
+                    input.final_script_witness = Some(parse_all(&key, &value, parse_witness)?);
+                }
+                PSBT_IN_POR_COMMITMENT => {
+                    let commitment = String::from_utf8(value).map_err(|_| invalid_value())?;
+                    input.por_commitment = Some(commitment);
+                }
+                PSBT_IN_RIPEMD160 => {
+                    let hash = to_array(key_data, invalid_key)?;
+                    if Ripemd160::digest(&value)[..] != hash {
+                        return Err(invalid_value());
+                    }
+                    input.ripemd160_preimages.insert(hash, value);
+                }
+                PSBT_IN_SHA256 => {
+                    let hash = to_array(key_data, invalid_key)?;
+                    if sha256(&value) != hash {
+                        return Err(invalid_value());
+                    }
+                    input.sha256_preimages.insert(hash, value);
+                }
+                PSBT_IN_HASH160 => {
+                    let hash = to_array(key_data, invalid_key)?;
+                    if hash160(&value) != hash {
+                        return Err(invalid_value());
+                    }
+                    input.hash160_preimages.insert(hash, value);
+                }
+                PSBT_IN_HASH256 => {
+                    let hash = to_array(key_data, invalid_key)?;
+                    if hash256(&value) != hash {
+                        return Err(invalid_value());
+                    }
+                    input.hash256_preimages.insert(hash, value);
+                }
+                PSBT_IN_PREVIOUS_TXID => {
+                    input.previous_txid = Some(to_array(&value, invalid_value)?);
+                }
+                PSBT_IN_OUTPUT_INDEX => {
+                    input.output_index = Some(parse_u32(&key, &value)?);
+                }
+                PSBT_IN_SEQUENCE => {
+                    input.sequence = Some(parse_u32(&key, &value)?);
+                }
+                PSBT_IN_REQUIRED_TIME_LOCKTIME => {
+                    let locktime = parse_u32(&key, &value)?;
+                    if locktime < LOCKTIME_THRESHOLD {
+                        return Err(invalid_value());
+                    }
+                    input.required_time_locktime = Some(locktime);
+                }
+                PSBT_IN_REQUIRED_HEIGHT_LOCKTIME => {
+                    let locktime = parse_u32(&key, &value)?;
+                    if locktime == 0 || locktime >= LOCKTIME_THRESHOLD {
+                        return Err(invalid_value());
+                    }
+                    input.required_height_locktime = Some(locktime);
+                }
+                PSBT_IN_TAP_KEY_SIG => {
+                    if !is_taproot_sig(&value) {
+                        return Err(invalid_value());
+                    }
+                    input.tap_key_sig = Some(value);
+                }
+                PSBT_IN_TAP_SCRIPT_SIG => {
+                    if key_data.len() != 64 {
+                        return Err(invalid_key());
+                    }
+                    if !is_taproot_sig(&value) {
+                        return Err(invalid_value());
+                    }
+                    let xonly = key_data[..32].try_into().unwrap();
+                    let leaf_hash = key_data[32..].try_into().unwrap();
+                    input.tap_script_sigs.insert((xonly, leaf_hash), value);
+                }
+                PSBT_IN_TAP_LEAF_SCRIPT => {
+                    // leaf version and internal key, then up to 128 hashes
+                    if key_data.len() < 33
+                        || !(key_data.len() - 33).is_multiple_of(32)
+                        || key_data.len() > 33 + 32 * 128
+                    {
+                        return Err(invalid_key());
+                    }
+                    let (leaf_version, script) = value.split_last().ok_or_else(invalid_value)?;
+                    let leaf = (Script::from_bytes(script.to_vec()), *leaf_version);
+                    input.tap_leaf_scripts.insert(key_data.to_vec(), leaf);
+                }
+                PSBT_IN_TAP_BIP32_DERIVATION => {
+                    let xonly = to_array(key_data, invalid_key)?;
+                    let source = TapKeySource::parse(&value).ok_or_else(invalid_value)?;
+                    input.tap_bip32_derivation.insert(xonly, source);
+                }
+                PSBT_IN_TAP_INTERNAL_KEY => {
+                    input.tap_internal_key = Some(to_array(&value, invalid_value)?);
+                }
+                PSBT_IN_TAP_MERKLE_ROOT => {
+                    input.tap_merkle_root = Some(to_array(&value, invalid_value)?);
+                }
+                PSBT_PROPRIETARY => {
+                    input.proprietary.insert(key, value);
+                }
+                _ => {
+                    input.unknown.insert(key, value);
+                }
+            }
+        }
+        if version >= 2 && (input.previous_txid.is_none() || input.output_index.is_none()) {
+            return Err(PsbtError::MissingField("previous outpoint"));
+        }
+        Ok(input)
+    }
+
+    fn serialize(&self, version: u32) -> Vec<u8> {
+        let mut ret = Vec::new();
+        if let Some(tx) = &self.non_witness_utxo {
+            write_pair(&mut ret, PSBT_IN_NON_WITNESS_UTXO, &[], &tx.serialize());
+        }
+        if let Some(tx_out) = &self.witness_utxo {
+            write_pair(&mut ret, PSBT_IN_WITNESS_UTXO, &[], &tx_out.serialize());
+        }
+        for (pubkey, sig) in &self.partial_sigs {
+            write_pair(&mut ret, PSBT_IN_PARTIAL_SIG, pubkey, sig);
+        }
+        if let Some(sighash_type) = self.sighash_type {
+            write_pair(
+                &mut ret,
+                PSBT_IN_SIGHASH_TYPE,
+                &[],
+                &sighash_type.to_le_bytes(),
+            );
+        }
+        if let Some(script) = &self.redeem_script {
+            write_pair(&mut ret, PSBT_IN_REDEEM_SCRIPT, &[], script.as_bytes());
+        }
+        if let Some(script) = &self.witness_script {
+            write_pair(&mut ret, PSBT_IN_WITNESS_SCRIPT, &[], script.as_bytes());
+        }
+        for (pubkey, source) in &self.bip32_derivation {
+            write_pair(
+                &mut ret,
+                PSBT_IN_BIP32_DERIVATION,
+                pubkey,
+                &source.serialize(),
+            );
+        }
+        if let Some(script) = &self.final_script_sig {
+            write_pair(&mut ret, PSBT_IN_FINAL_SCRIPTSIG, &[], script.as_bytes());
+        }
+        if let Some(witness) = &self.final_script_witness {
+            write_pair(
+                &mut ret,
+                PSBT_IN_FINAL_SCRIPTWITNESS,
+                &[],
+                &serialize_witness(witness),
+            );
+        }
+        if let Some(commitment) = &self.por_commitment {
+            write_pair(&mut ret, PSBT_IN_POR_COMMITMENT, &[], commitment.as_bytes());
+        }
+        for (hash, preimage) in &self.ripemd160_preimages {
+            write_pair(&mut ret, PSBT_IN_RIPEMD160, hash, preimage);
+        }
+        for (hash, preimage) in &self.sha256_preimages {
+            write_pair(&mut ret, PSBT_IN_SHA256, hash, preimage);
+        }
+        for (hash, preimage) in &self.hash160_preimages {
+            write_pair(&mut ret, PSBT_IN_HASH160, hash, preimage);
+        }
+        for (hash, preimage) in &self.hash256_preimages {
+            write_pair(&mut ret, PSBT_IN_HASH256, hash, preimage);
+        }
+        if version >= 2 {
+            if let Some(txid) = &self.previous_txid {
+                write_pair(&mut ret, PSBT_IN_PREVIOUS_TXID, &[], txid);
+            }
+            if let Some(index) = self.output_index {
+                write_pair(&mut ret, PSBT_IN_OUTPUT_INDEX, &[], &index.to_le_bytes());
+            }
+            if let Some(sequence) = self.sequence {
+                write_pair(&mut ret, PSBT_IN_SEQUENCE, &[], &sequence.to_le_bytes());
+            }
+            if let Some(locktime) = self.required_time_locktime {
+                write_pair(
+                    &mut ret,
+                    PSBT_IN_REQUIRED_TIME_LOCKTIME,
+                    &[],
+                    &locktime.to_le_bytes(),
+                );
+            }
+            if let Some(locktime) = self.required_height_locktime {
+                write_pair(
+                    &mut ret,
+                    PSBT_IN_REQUIRED_HEIGHT_LOCKTIME,
+                    &[],
+                    &locktime.to_le_bytes(),
+                );
+            }
+        }
+        if let Some(sig) = &self.tap_key_sig {
+            write_pair(&mut ret, PSBT_IN_TAP_KEY_SIG, &[], sig);
+        }
+        for ((xonly, leaf_hash), sig) in &self.tap_script_sigs {
+            let key_data = [&xonly[..], &leaf_hash[..]].concat();
+            write_pair(&mut ret, PSBT_IN_TAP_SCRIPT_SIG, &key_data, sig);
+        }
+        for (control_block, (script, leaf_version)) in &self.tap_leaf_scripts {
+            let value = [script.as_bytes(), &[*leaf_version]].concat();
+            write_pair(&mut ret, PSBT_IN_TAP_LEAF_SCRIPT, control_block, &value);
+        }
+        for (xonly, source) in &self.tap_bip32_derivation {
+            write_pair(
+                &mut ret,
+                PSBT_IN_TAP_BIP32_DERIVATION,
+                xonly,
+                &source.serialize(),
+            );
+        }
+        if let Some(key) = &self.tap_internal_key {
+            write_pair(&mut ret, PSBT_IN_TAP_INTERNAL_KEY, &[], key);
+        }
+        if let Some(root) = &self.tap_merkle_root {
+            write_pair(&mut ret, PSBT_IN_TAP_MERKLE_ROOT, &[], root);
+        }
+        write_raw_pairs(&mut ret, &self.proprietary);
+        write_raw_pairs(&mut ret, &self.unknown);
+        ret.push(0x00);
+        ret
+    }
+
+    pub fn is_finalized(&self) -> bool {
+        self.final_script_sig.is_some() || self.final_script_witness.is_some()
+    }
+
+    fn combine(&mut self, other: Input) {
+        self.non_witness_utxo = self.non_witness_utxo.take().or(other.non_witness_utxo);
+        self.witness_utxo = self.witness_utxo.take().or(other.witness_utxo);
+        merge_map(&mut self.partial_sigs, other.partial_sigs);
+        self.sighash_type = self.sighash_type.or(other.sighash_type);
+        self.redeem_script = self.redeem_script.take().or(other.redeem_script);
+        self.witness_script = self.witness_script.take().or(other.witness_script);
+        merge_map(&mut self.bip32_derivation, other.bip32_derivation);
+        self.final_script_sig = self.final_script_sig.take().or(other.final_script_sig);
+        self.final_script_witness = self
+            .final_script_witness
+            .take()
+            .or(other.final_script_witness);
+        self.por_commitment = self.por_commitment.take().or(other.por_commitment);
+        merge_map(&mut self.ripemd160_preimages, other.ripemd160_preimages);
+        merge_map(&mut self.sha256_preimages, other.sha256_preimages);
+        merge_map(&mut self.hash160_preimages, other.hash160_preimages);
+        merge_map(&mut self.hash256_preimages, other.hash256_preimages);
+        self.required_time_locktime = self.required_time_locktime.or(other.required_time_locktime);
+        self.required_height_locktime = self
+            .required_height_locktime
+            .or(other.required_height_locktime);
+        self.tap_key_sig = self.tap_key_sig.take().or(other.tap_key_sig);
+        merge_map(&mut self.tap_script_sigs, other.tap_script_sigs);
+        merge_map(&mut self.tap_leaf_scripts, other.tap_leaf_scripts);
+        merge_map(&mut self.tap_bip32_derivation, other.tap_bip32_derivation);
+        self.tap_internal_key = self.tap_internal_key.or(other.tap_internal_key);
+        self.tap_merkle_root = self.tap_merkle_root.or(other.tap_merkle_root);
+        merge_map(&mut self.proprietary, other.proprietary);
+        merge_map(&mut self.unknown, other.unknown);
+    }
+}
+
+impl Output {
+    fn parse_map(pairs: Vec<Pair>, version: u32) -> Result<Self, PsbtError> {
+        let mut output = Self::default();
+        for (key, value) in pairs {
+            let version_2_only =
+                |key_type| key_type == PSBT_OUT_AMOUNT || key_type == PSBT_OUT_SCRIPT;
+            let (key_type, key_data) =
+                split_known_key(&key, PSBT_OUT_KEYLESS, version_2_only, version)?;
+            let invalid_key = || PsbtError::InvalidKey(key.clone());
+            let invalid_value = || PsbtError::InvalidValue(key.clone());
+            if version_2_only(key_type) && version < 2 {
+                if key_data.is_empty() {
+                    return Err(PsbtError::KeyNotAllowed(key));
+                }
+                output.unknown.insert(key, value);
+                continue;
+            }
+            match key_type {
+                PSBT_OUT_REDEEM_SCRIPT => {
+                    output.redeem_script = Some(Script::from_bytes(value));
+                }
+                PSBT_OUT_WITNESS_SCRIPT => {
+                    output.witness_script = Some(Script::from_bytes(value));
+                }
+                PSBT_OUT_BIP32_DERIVATION => {
+                    if !is_pubkey(key_data) {
+                        return Err(invalid_key());
+                    }
+                    let source = KeySource::parse(&value).ok_or_else(invalid_value)?;
+                    output.bip32_derivation.insert(key_data.to_vec(), source);
+                }
+                PSBT_OUT_AMOUNT => {
+                    let amount = to_array(&value, invalid_value)?;
+                    output.amount = Some(u64::from_le_bytes(amount));
+                }
+                PSBT_OUT_SCRIPT => {
+                    output.script = Some(Script::from_bytes(value));
+                }
+                PSBT_OUT_TAP_INTERNAL_KEY => {
+                    output.tap_internal_key = Some(to_array(&value, invalid_value)?);
+                }
+                PSBT_OUT_TAP_TREE => {
+                    let leaves = parse_all(&key, &value, |stream| {
+                        let mut leaves = Vec::new();
+                        while (stream.position() as usize) < value.len() {
+                            let mut header = [0u8; 2];
+                            stream.read_exact(&mut header)?;
+                            leaves.push(TapTreeLeaf {
+                                depth: header[0],
+                                leaf_version: header[1],
+                                script: Script::parse(stream)?,
+                            });
+                        }
+                        Ok(leaves)
+                    })?;
+                    if leaves.is_empty() || leaves.iter().any(|leaf| leaf.depth > 128) {
+                        return Err(invalid_value());
+                    }
+                    output.tap_tree = Some(leaves);
+                }
+                PSBT_OUT_TAP_BIP32_DERIVATION => {
+                    let xonly = to_array(key_data, invalid_key)?;
+                    let source = TapKeySource::parse(&value).ok_or_else(invalid_value)?;
+                    output.tap_bip32_derivation.insert(xonly, source);
+                }
+                PSBT_PROPRIETARY => {
+                    output.proprietary.insert(key, value);
+                }
+                _ => {
+                    output.unknown.insert(key, value);
+                }
+            }
+        }
+        if version >= 2 && (output.amount.is_none() || output.script.is_none()) {
+            return Err(PsbtError::MissingField("output amount and script"));
+        }
+        Ok(output)
+    }
+
+    fn serialize(&self, version: u32) -> Vec<u8> {
+        let mut ret = Vec::new();
+        if let Some(script) = &self.redeem_script {
+            write_pair(&mut ret, PSBT_OUT_REDEEM_SCRIPT, &[], script.as_bytes());
+        }
+        if let Some(script) = &self.witness_script {
+            write_pair(&mut ret, PSBT_OUT_WITNESS_SCRIPT, &[], script.as_bytes());
+        }
+        for (pubkey, source) in &self.bip32_derivation {
+            write_pair(
+                &mut ret,
+                PSBT_OUT_BIP32_DERIVATION,
+                pubkey,
+                &source.serialize(),
+            );
+        }
+        if version >= 2 {
+            if let Some(amount) = self.amount {
+                write_pair(&mut ret, PSBT_OUT_AMOUNT, &[], &amount.to_le_bytes());
+            }
+            if let Some(script) = &self.script {
+                write_pair(&mut ret, PSBT_OUT_SCRIPT, &[], script.as_bytes());
+            }
+        }
+        if let Some(key) = &self.tap_internal_key {
+            write_pair(&mut ret, PSBT_OUT_TAP_INTERNAL_KEY, &[], key);
+        }
+        if let Some(leaves) = &self.tap_tree {
+            let mut value = Vec::new();
+            for leaf in leaves {
+                value.extend([leaf.depth, leaf.leaf_version]);
+                value.extend(leaf.script.serialize());
+            }
+            write_pair(&mut ret, PSBT_OUT_TAP_TREE, &[], &value);
+        }
+        for (xonly, source) in &self.tap_bip32_derivation {
+            write_pair(
+                &mut ret,
+                PSBT_OUT_TAP_BIP32_DERIVATION,
+                xonly,
+                &source.serialize(),
+            );
+        }
+        write_raw_pairs(&mut ret, &self.proprietary);
+        write_raw_pairs(&mut ret, &self.unknown);
+        ret.push(0x00);
+        ret
+    }
+
+    fn combine(&mut self, other: Output) {
+        self.redeem_script = self.redeem_script.take().or(other.redeem_script);
+        self.witness_script = self.witness_script.take().or(other.witness_script);
+        merge_map(&mut self.bip32_derivation, other.bip32_derivation);
+        self.tap_internal_key = self.tap_internal_key.or(other.tap_internal_key);
+        self.tap_tree = self.tap_tree.take().or(other.tap_tree);
+        merge_map(&mut self.tap_bip32_derivation, other.tap_bip32_derivation);
+        merge_map(&mut self.proprietary, other.proprietary);
+        merge_map(&mut self.unknown, other.unknown);
+    }
+}
+
+impl Psbt {
+    /// Creator: a version 0 PSBT for an unsigned transaction.
+    pub fn from_unsigned_tx(tx: Tx) -> Result<Self, PsbtError> {
+        if tx
+            .tx_ins
+            .iter()
+            .any(|tx_in| !tx_in.script_sig.is_empty() || !tx_in.witness.is_empty())
+        {
+            return Err(PsbtError::UnsignedTxHasScriptSigs);
+        }
+        Ok(Self {
+            inputs: vec![Input::default(); tx.tx_ins.len()],
+            outputs: vec![Output::default(); tx.tx_outs.len()],
+            tx: Some(tx),
+            ..Default::default()
+        })
+    }
+
+    /// Creator: a version 2 PSBT for an unsigned transaction. Its locktime
+    /// becomes the fallback locktime.
+    pub fn from_unsigned_tx_v2(tx: Tx) -> Result<Self, PsbtError> {
+        let mut psbt = Self::from_unsigned_tx(tx)?;
+        let tx = psbt.tx.take().unwrap();
+        psbt.version = 2;
+        psbt.tx_version = Some(tx.version);
+        psbt.fallback_locktime = Some(tx.locktime);
+        for (input, tx_in) in psbt.inputs.iter_mut().zip(&tx.tx_ins) {
+            input.previous_txid = Some(tx_in.prev_out.txid);
+            input.output_index = Some(tx_in.prev_out.vout);
+            input.sequence = Some(tx_in.sequence);
+        }
+        for (output, tx_out) in psbt.outputs.iter_mut().zip(tx.tx_outs) {
+            output.amount = Some(tx_out.amount);
+            output.script = Some(tx_out.script_pubkey);
+        }
+        Ok(psbt)
+    }
+
+    pub fn parse<R: Read>(stream: &mut R) -> Result<Self, PsbtError> {
+        let mut magic = [0u8; 5];
+        stream.read_exact(&mut magic)?;
+        if &magic != PSBT_MAGIC {
+            return Err(PsbtError::InvalidMagic);
+        }
+        let mut psbt = Self::default();
+        let mut input_count = None;
+        let mut output_count = None;
+        let pairs = read_map(stream)?;
+        for (key, value) in &pairs {
+            if split_key(key)? == (PSBT_GLOBAL_VERSION, &[][..]) {
+                psbt.version = parse_u32(key, value)?;
+            }
+        }
+        if psbt.version != 0 && psbt.version != 2 {
+            return Err(PsbtError::UnsupportedVersion(psbt.version));
+        }
+        for (key, value) in pairs {
+            let version_2_only =
+                |key_type| (PSBT_GLOBAL_TX_VERSION..=PSBT_GLOBAL_TX_MODIFIABLE).contains(&key_type);
+            let (key_type, key_data) =
+                split_known_key(&key, PSBT_GLOBAL_KEYLESS, version_2_only, psbt.version)?;
+            let invalid_value = || PsbtError::InvalidValue(key.clone());
+            let allowed = match key_type {
+                PSBT_GLOBAL_UNSIGNED_TX => psbt.version == 0,
+                _ => psbt.version == 2 || !version_2_only(key_type),
+            };
+            if !allowed {
+                if !key_data.is_empty() {
+                    psbt.unknown.insert(key, value);
+                    continue;
+                }
+                return Err(PsbtError::KeyNotAllowed(key));
+            }
+            match key_type {
+                PSBT_GLOBAL_UNSIGNED_TX => {
+                    let tx = parse_all(&key, &value, Tx::parse)?;
+                    if tx
+                        .tx_ins
+                        .iter()
+                        .any(|tx_in| !tx_in.script_sig.is_empty() || !tx_in.witness.is_empty())
+                    {
+                        return Err(PsbtError::UnsignedTxHasScriptSigs);
+                    }
+                    // the unsigned transaction is always in the legacy format
+                    if tx.serialize() != value {
+                        return Err(invalid_value());
+                    }
+                    psbt.tx = Some(tx);
+                }
+                PSBT_GLOBAL_XPUB => {
+                    if key_data.len() != 78 {
+                        return Err(PsbtError::InvalidKey(key));
+                    }
+                    let source = KeySource::parse(&value).ok_or_else(invalid_value)?;
+                    psbt.xpubs.insert(key_data.to_vec(), source);
+                }
+                PSBT_GLOBAL_TX_VERSION => {
+                    psbt.tx_version = Some(parse_u32(&key, &value)?);
+                }
+                PSBT_GLOBAL_FALLBACK_LOCKTIME => {
+                    psbt.fallback_locktime = Some(parse_u32(&key, &value)?);
+                }
+                PSBT_GLOBAL_INPUT_COUNT => {
+                    input_count = Some(parse_all(&key, &value, read_varint)?);
+                }
+                PSBT_GLOBAL_OUTPUT_COUNT => {
+                    output_count = Some(parse_all(&key, &value, read_varint)?);
+                }
+                PSBT_GLOBAL_TX_MODIFIABLE => {
+                    let [flags] = to_array(&value, invalid_value)?;
+                    psbt.tx_modifiable = Some(flags);
+                }
+                PSBT_GLOBAL_VERSION => {}
+                PSBT_PROPRIETARY => {
+                    psbt.proprietary.insert(key, value);
+                }
+                _ => {
+                    psbt.unknown.insert(key, value);
+                }
+            }
+        }
+
+        let (input_count, output_count) = match &psbt.tx {
+            Some(tx) => (tx.tx_ins.len() as u64, tx.tx_outs.len() as u64),
+            None if psbt.version == 0 => return Err(PsbtError::MissingField("unsigned tx")),
+            None => {
+                if psbt.tx_version.is_none() {
+                    return Err(PsbtError::MissingField("tx version"));
+                }
+                (
+                    input_count.ok_or(PsbtError::MissingField("input count"))?,
+                    output_count.ok_or(PsbtError::MissingField("output count"))?,
+                )
+            }
+        };
+        for _ in 0..input_count {
+            let input = Input::parse_map(read_map(stream)?, psbt.version)?;
+            psbt.inputs.push(input);
+        }
+        for _ in 0..output_count {
+            let output = Output::parse_map(read_map(stream)?, psbt.version)?;
+            psbt.outputs.push(output);
+        }
+        Ok(psbt)
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut ret = PSBT_MAGIC.to_vec();
+        if self.version == 0 {
+            if let Some(tx) = &self.tx {
+                write_pair(&mut ret, PSBT_GLOBAL_UNSIGNED_TX, &[], &tx.serialize());
+            }
+        }
+        for (xpub, source) in &self.xpubs {
+            write_pair(&mut ret, PSBT_GLOBAL_XPUB, xpub, &source.serialize());
+        }
+        if self.version >= 2 {
+            if let Some(tx_version) = self.tx_version {
+                write_pair(
+                    &mut ret,
+                    PSBT_GLOBAL_TX_VERSION,
+                    &[],
+                    &tx_version.to_le_bytes(),
+                );
+            }
+            if let Some(locktime) = self.fallback_locktime {
+                write_pair(
+                    &mut ret,
+                    PSBT_GLOBAL_FALLBACK_LOCKTIME,
+                    &[],
+                    &locktime.to_le_bytes(),
+                );
+            }
+            write_pair(
+                &mut ret,
+                PSBT_GLOBAL_INPUT_COUNT,
+                &[],
+                &encode_varint(self.inputs.len() as u64),
+            );
+            write_pair(
+                &mut ret,
+                PSBT_GLOBAL_OUTPUT_COUNT,
+                &[],
+                &encode_varint(self.outputs.len() as u64),
+            );
+            if let Some(flags) = self.tx_modifiable {
+                write_pair(&mut ret, PSBT_GLOBAL_TX_MODIFIABLE, &[], &[flags]);
+            }
+        }
+        if self.version != 0 {
+            write_pair(
+                &mut ret,
+                PSBT_GLOBAL_VERSION,
+                &[],
+                &self.version.to_le_bytes(),
+            );
+        }
+        write_raw_pairs(&mut ret, &self.proprietary);
+        write_raw_pairs(&mut ret, &self.unknown);
+        ret.push(0x00);
+        for input in &self.inputs {
+            ret.extend(input.serialize(self.version));
+        }
+        for output in &self.outputs {
+            ret.extend(output.serialize(self.version));
+        }
+        ret
+    }
+
+    /// The transaction being signed, without any signatures.
+    pub fn unsigned_tx(&self) -> Result<Tx, PsbtError> {
+        if let Some(tx) = &self.tx {
+            return Ok(tx.clone());
+        }
+        let mut tx_ins = Vec::new();
+        for input in &self.inputs {
+            let (Some(txid), Some(vout)) = (input.previous_txid, input.output_index) else {
+                return Err(PsbtError::MissingField("previous outpoint"));
+            };
+            let sequence = input.sequence.unwrap_or(SEQUENCE_FINAL);
+            tx_ins.push(TxIn::new(OutPoint::new(txid, vout), sequence));
+        }
+        let mut tx_outs = Vec::new();
+        for output in &self.outputs {
+            let (Some(amount), Some(script)) = (output.amount, &output.script) else {
+                return Err(PsbtError::MissingField("output amount and script"));
+            };
+            tx_outs.push(TxOut::new(amount, script.clone()));
+        }
+        let version = self
+            .tx_version
+            .ok_or(PsbtError::MissingField("tx version"))?;
+        Ok(Tx::new(version, tx_ins, tx_outs, self.locktime()?))
+    }
+
+    /// Locktime of a version 2 PSBT, chosen as described in BIP370.
+    ///
+    /// When inputs require a locktime, the kind every one of them accepts is
+    /// used, preferring heights, and its largest value wins.
+    fn locktime(&self) -> Result<u32, PsbtError> {
+        let constrained: Vec<&Input> = self
+            .inputs
+            .iter()
+            .filter(|i| i.required_time_locktime.is_some() || i.required_height_locktime.is_some())
+            .collect();
+        if constrained.is_empty() {
+            return Ok(self.fallback_locktime.unwrap_or(0));
+        }
+        if constrained
+            .iter()
+            .all(|i| i.required_height_locktime.is_some())
+        {
+            Ok(constrained
+                .iter()
+                .filter_map(|i| i.required_height_locktime)
+                .max()
+                .unwrap())
+        } else if constrained
+            .iter()
+            .all(|i| i.required_time_locktime.is_some())
+        {
+            Ok(constrained
+                .iter()
+                .filter_map(|i| i.required_time_locktime)
+                .max()
+                .unwrap())
+        } else {
+            Err(PsbtError::LocktimeConflict)
+        }
+    }
+
+    /// The output spent by an input, from its witness or non-witness UTXO.
+    pub fn spent_output(&self, index: usize) -> Result<Option<TxOut>, PsbtError> {
+        let input = &self.inputs[index];
+        if let Some(tx_out) = &input.witness_utxo {
+            return Ok(Some(tx_out.clone()));
+        }
+        let Some(prev_tx) = &input.non_witness_utxo else {
+            return Ok(None);
+        };
+        let prev_out = self.unsigned_tx()?.tx_ins[index].prev_out;
+        if prev_tx.txid() != prev_out.txid {
+            return Err(PsbtError::UtxoMismatch(index));
+        }
+        prev_tx
+            .tx_outs
+            .get(prev_out.vout as usize)
+            .cloned()
+            .map(Some)
+            .ok_or(PsbtError::UtxoMismatch(index))
+    }
+
+    /// Signer: adds an ECDSA signature with `key` to every input whose
+    /// script uses its compressed public key. Returns how many were signed.
+    ///
+    /// Inputs without a UTXO, already finalized inputs and taproot inputs
+    /// are left alone.
+    pub fn sign(&mut self, key: &PrivateKey) -> Result<usize, PsbtError> {
+        let tx = self.unsigned_tx()?;
+        let pubkey = key.get_point().compressed_sec().to_vec();
+        let pubkey_hash = hash160(&pubkey);
+        let mut signed = 0;
+        for index in 0..self.inputs.len() {
+            if self.inputs[index].is_finalized() {
+                continue;
+            }
+            let Some(utxo) = self.spent_output(index)? else {
+                continue;
+            };
+            let input = &self.inputs[index];
+            let mut script = utxo.script_pubkey.clone();
+            if script.is_p2sh() {
+                let Some(redeem_script) = &input.redeem_script else {
+                    continue;
+                };
+                if Script::p2sh(&hash160(redeem_script.as_bytes()).try_into().unwrap()) != script {
+                    return Err(PsbtError::ScriptMismatch(index));
+                }
+                script = redeem_script.clone();
+            }
+            let (script_code, segwit) = match script.witness_version_and_program() {
+                Some((0, program)) if program.len() == 20 => {
+                    (p2wpkh_script_code(program.try_into().unwrap()), true)
+                }
+                Some((0, program)) if program.len() == 32 => {
+                    let Some(witness_script) = &input.witness_script else {
+                        continue;
+                    };
+                    if sha256(witness_script.as_bytes()) != program {
+                        return Err(PsbtError::ScriptMismatch(index));
+                    }
+                    (witness_script.clone(), true)
+                }
+                Some(_) => continue,
+                None => (script, false),
+            };
+            let uses_key = script_code.commands().is_ok_and(|cmds| {
+                cmds.iter()
+                    .any(|cmd| matches!(cmd, Command::Data(d) if *d == pubkey || *d == pubkey_hash))
+            });
+            if !uses_key {
+                continue;
+            }
+
+            let sighash_type = input.sighash_type.unwrap_or(SIGHASH_ALL);
+            let sighash = if segwit {
+                segwit_v0_sighash(&tx, index, &script_code, utxo.amount, sighash_type)
+            } else {
+                legacy_sighash(&tx, index, &script_code, sighash_type)
+            };
+            let sig = key.sign_low_r(U256::from_big_endian(&sighash));
+            let mut sig = sig.der();
+            sig.push(sighash_type as u8);
+            self.inputs[index].partial_sigs.insert(pubkey.clone(), sig);
+            signed += 1;
+        }
+        Ok(signed)
+    }
+
+    /// Combiner: merges the fields of another PSBT of the same transaction.
+    pub fn combine(&mut self, other: Psbt) -> Result<(), PsbtError> {
+        if self.version != other.version || self.unsigned_tx()? != other.unsigned_tx()? {
+            return Err(PsbtError::DifferentTx);
+        }
+        merge_map(&mut self.xpubs, other.xpubs);
+        merge_map(&mut self.proprietary, other.proprietary);
+        merge_map(&mut self.unknown, other.unknown);
+        for (input, other) in self.inputs.iter_mut().zip(other.inputs) {
+            input.combine(other);
+        }
+        for (output, other) in self.outputs.iter_mut().zip(other.outputs) {
+            output.combine(other);
+        }
+        Ok(())
+    }
+
+    /// Finalizer: builds the final scriptSig and witness of every input.
+    ///
+    /// Handles P2PKH, P2PK, bare CHECKMULTISIG, any of them in P2SH or P2WSH,
+    /// P2WPKH (also nested in P2SH) and taproot key path spends.
+    pub fn finalize(&mut self) -> Result<(), PsbtError> {
+        for index in 0..self.inputs.len() {
+            if self.inputs[index].is_finalized() {
+                continue;
+            }
+            let utxo = self
+                .spent_output(index)?
+                .ok_or(PsbtError::CannotFinalize(index))?;
+            let input = &self.inputs[index];
+            let (script_sig, witness) = input
+                .finalize_scripts(&utxo.script_pubkey)
+                .ok_or(PsbtError::CannotFinalize(index))?;
+            let input = &mut self.inputs[index];
+            *input = Input {
+                non_witness_utxo: input.non_witness_utxo.take(),
+                witness_utxo: input.witness_utxo.take(),
+                final_script_sig: (!script_sig.is_empty()).then_some(script_sig),
+                final_script_witness: (!witness.is_empty()).then_some(witness),
+                previous_txid: input.previous_txid,
+                output_index: input.output_index,
+                sequence: input.sequence,
+                required_time_locktime: input.required_time_locktime,
+                required_height_locktime: input.required_height_locktime,
+                proprietary: std::mem::take(&mut input.proprietary),
+                unknown: std::mem::take(&mut input.unknown),
+                ..Default::default()
+            };
+        }
+        Ok(())
+    }
+
+    /// Extractor: the signed transaction.
+    pub fn extract_tx(&self) -> Result<Tx, PsbtError> {
+        let mut tx = self.unsigned_tx()?;
+        for (index, (tx_in, input)) in tx.tx_ins.iter_mut().zip(&self.inputs).enumerate() {
+            if !input.is_finalized() {
+                return Err(PsbtError::NotFinalized(index));
+            }
+            tx_in.script_sig = input.final_script_sig.clone().unwrap_or_default();
+            tx_in.witness = input.final_script_witness.clone().unwrap_or_default();
+        }
+        Ok(tx)
+    }
+}
+
+impl Input {
+    /// The scriptSig and witness spending `script_pubkey` with the
+    /// signatures collected so far.
+    fn finalize_scripts(&self, script_pubkey: &Script) -> Option<(Script, Vec<Vec<u8>>)> {
+        if script_pubkey.is_p2tr() {
+            return Some((Script::default(), vec![self.tap_key_sig.clone()?]));
+        }
+        let mut script_sig = Vec::new();
+        let mut script = script_pubkey.clone();
+        if script.is_p2sh() {
+            let redeem_script = self.redeem_script.clone()?;
+            script_sig.push(redeem_script.as_bytes().to_vec());
+            script = redeem_script;
+        }
+        let witness = match script.witness_version_and_program() {
+            Some((0, program)) if program.len() == 20 => {
+                let (pubkey, sig) = self
+                    .partial_sigs
+                    .iter()
+                    .find(|(pubkey, _)| hash160(pubkey) == program)?;
+                vec![sig.clone(), pubkey.clone()]
+            }
+            Some((0, _)) => {
+                let witness_script = self.witness_script.as_ref()?;
+                let mut witness = self.satisfy(witness_script)?;
+                witness.push(witness_script.as_bytes().to_vec());
+                witness
+            }
+            Some(_) => return None,
+            None => {
+                let mut stack = self.satisfy(&script)?;
+                stack.extend(script_sig);
+                script_sig = stack;
+                Vec::new()
+            }
+        };
+        let cmds: Vec<Command> = script_sig.into_iter().map(Command::Data).collect();
+        Some((Script::new(&cmds), witness))
+    }
+
+    /// Stack items satisfying a P2PKH, P2PK or CHECKMULTISIG script.
+    fn satisfy(&self, script: &Script) -> Option<Vec<Vec<u8>>> {
+        if script.is_p2pkh() {
+            let h160 = &script.as_bytes()[3..23];
+            let (pubkey, sig) = self
+                .partial_sigs
+                .iter()
+                .find(|(pubkey, _)| hash160(pubkey) == h160)?;
+            return Some(vec![sig.clone(), pubkey.clone()]);
+        }
+        let cmds = script.commands().ok()?;
+        match cmds.as_slice() {
+            [Command::Data(pubkey), Command::Op(OP_CHECKSIG)] => {
+                Some(vec![self.partial_sigs.get(pubkey)?.clone()])
+            }
+            [Command::Op(m @ OP_1..=OP_16), keys @ .., Command::Op(n @ OP_1..=OP_16), Command::Op(OP_CHECKMULTISIG)]
+                if (n - OP_1 + 1) as usize == keys.len() =>
+            {
+                let m = (m - OP_1 + 1) as usize;
+                // the dummy element consumed by CHECKMULTISIG, then the
+                // signatures in key order
+                let mut stack = vec![Vec::new()];
+                for key in keys {
+                    let Command::Data(pubkey) = key else {
+                        return None;
+                    };
+                    if let Some(sig) = self.partial_sigs.get(pubkey) {
+                        if stack.len() <= m {
+                            stack.push(sig.clone());
+                        }
+                    }
+                }
+                (stack.len() == m + 1).then_some(stack)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Psbt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", STANDARD.encode(self.serialize()))
+    }
+}
+
+impl FromStr for Psbt {
+    type Err = PsbtError;
+
+    /// Parses a base64 PSBT.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let raw = STANDARD.decode(s).map_err(|_| PsbtError::InvalidBase64)?;
+        Self::parse(&mut Cursor::new(raw))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use primitive_types::U256;
+
+    fn parse_hex(s: &str) -> Result<Psbt, PsbtError> {
+        Psbt::parse(&mut Cursor::new(hex::decode(s).unwrap()))
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        // BIP174 invalid vectors: a raw transaction, an input without its
+        // map separator, an unsigned tx with scriptSigs, no unsigned tx and
+        // a duplicate unsigned tx
+        assert_eq!(parse_hex("0200000001268171371edff285e937adeea4b37b78000c0566cbb3ad64641713ca42171bf6000000006a473044022070b2245123e6bf474d60c5b50c043d4c691a5d2435f09a34a7662a9dc251790a022001329ca9dacf280bdf30740ec0390422422c81cb45839457aeb76fc12edd95b3012102657d118d3357b8e0f4c2cd46db7b39f6d9c38d9a70abcb9b2de5dc8dbfe4ce31feffffff02d3dff505000000001976a914d0c59903c5bac2868760e90fd521a4665aa7652088ac00e1f5050000000017a9143545e6e33b832c47050f24d3eeb93c9c03948bc787b32e1300").unwrap_err(), PsbtError::InvalidMagic);
+        assert_eq!(
+            parse_hex("70736274ff0100750200000001268171371edff285e937adeea4b37b78000c0566cbb3ad64641713ca42171bf60000000000feffffff02d3dff505000000001976a914d0c59903c5bac2868760e90fd521a4665aa7652088ac00e1f5050000000017a9143545e6e33b832c47050f24d3eeb93c9c03948bc787b32e1300000100fda5010100000000010289a3c71eab4d20e0371bbba4cc698fa295c9463afa2e397f8533ccb62f9567e50100000017160014be18d152a9b012039daf3da7de4f53349eecb985ffffffff86f8aa43a71dff1448893a530a7237ef6b4608bbb2dd2d0171e63aec6a4890b40100000017160014fe3e9ef1a745e974d902c4355943abcb34bd5353ffffffff0200c2eb0b000000001976a91485cff1097fd9e008bb34af709c62197b38978a4888ac72fef84e2c00000017a914339725ba21efd62ac753a9bcd067d6c7a6a39d05870247304402202712be22e0270f394f568311dc7ca9a68970b8025fdd3b240229f07f8a5f3a240220018b38d7dcd314e734c9276bd6fb40f673325bc4baa144c800d2f2f02db2765c012103d2e15674941bad4a996372cb87e1856d3652606d98562fe39c5e9e7e413f210502483045022100d12b852d85dcd961d2f5f4ab660654df6eedcc794c0c33ce5cc309ffb5fce58d022067338a8e0e1725c197fb1a88af59f51e44e4255b20167c8684031c05d1f2592a01210223b72beef0965d10be0778efecd61fcac6f79a4ea169393380734464f84f2ab30000000000").unwrap_err(),
+            PsbtError::Io(io::ErrorKind::UnexpectedEof)
+        );
+        assert_eq!(
+            parse_hex("70736274ff0100fd0a010200000002ab0949a08c5af7c49b8212f417e2f15ab3f5c33dcf153821a8139f877a5b7be4000000006a47304402204759661797c01b036b25928948686218347d89864b719e1f7fcf57d1e511658702205309eabf56aa4d8891ffd111fdf1336f3a29da866d7f8486d75546ceedaf93190121035cdc61fc7ba971c0b501a646a2a83b102cb43881217ca682dc86e2d73fa88292feffffffab0949a08c5af7c49b8212f417e2f15ab3f5c33dcf153821a8139f877a5b7be40100000000feffffff02603bea0b000000001976a914768a40bbd740cbe81d988e71de2a4d5c71396b1d88ac8e240000000000001976a9146f4620b553fa095e721b9ee0efe9fa039cca459788ac00000000000001012000e1f5050000000017a9143545e6e33b832c47050f24d3eeb93c9c03948bc787010416001485d13537f2e265405a34dbafa9e3dda01fb82308000000").unwrap_err(),
+            PsbtError::UnsignedTxHasScriptSigs
+        );
+        assert_eq!(
+            parse_hex("70736274ff000100fda5010100000000010289a3c71eab4d20e0371bbba4cc698fa295c9463afa2e397f8533ccb62f9567e50100000017160014be18d152a9b012039daf3da7de4f53349eecb985ffffffff86f8aa43a71dff1448893a530a7237ef6b4608bbb2dd2d0171e63aec6a4890b40100000017160014fe3e9ef1a745e974d902c4355943abcb34bd5353ffffffff0200c2eb0b000000001976a91485cff1097fd9e008bb34af709c62197b38978a4888ac72fef84e2c00000017a914339725ba21efd62ac753a9bcd067d6c7a6a39d05870247304402202712be22e0270f394f568311dc7ca9a68970b8025fdd3b240229f07f8a5f3a240220018b38d7dcd314e734c9276bd6fb40f673325bc4baa144c800d2f2f02db2765c012103d2e15674941bad4a996372cb87e1856d3652606d98562fe39c5e9e7e413f210502483045022100d12b852d85dcd961d2f5f4ab660654df6eedcc794c0c33ce5cc309ffb5fce58d022067338a8e0e1725c197fb1a88af59f51e44e4255b20167c8684031c05d1f2592a01210223b72beef0965d10be0778efecd61fcac6f79a4ea169393380734464f84f2ab30000000000").unwrap_err(),
+            PsbtError::MissingField("unsigned tx")
+        );
+        assert_eq!(
+            parse_hex("70736274ff0100750200000001268171371edff285e937adeea4b37b78000c0566cbb3ad64641713ca42171bf60000000000feffffff02d3dff505000000001976a914d0c59903c5bac2868760e90fd521a4665aa7652088ac00e1f5050000000017a9143545e6e33b832c47050f24d3eeb93c9c03948bc787b32e1300000100fda5010100000000010289a3c71eab4d20e0371bbba4cc698fa295c9463afa2e397f8533ccb62f9567e50100000017160014be18d152a9b012039daf3da7de4f53349eecb985ffffffff86f8aa43a71dff1448893a530a7237ef6b4608bbb2dd2d0171e63aec6a4890b40100000017160014fe3e9ef1a745e974d902c4355943abcb34bd5353ffffffff0200c2eb0b000000001976a91485cff1097fd9e008bb34af709c62197b38978a4888ac72fef84e2c00000017a914339725ba21efd62ac753a9bcd067d6c7a6a39d05870247304402202712be22e0270f394f568311dc7ca9a68970b8025fdd3b240229f07f8a5f3a240220018b38d7dcd314e734c9276bd6fb40f673325bc4baa144c800d2f2f02db2765c012103d2e15674941bad4a996372cb87e1856d3652606d98562fe39c5e9e7e413f210502483045022100d12b852d85dcd961d2f5f4ab660654df6eedcc794c0c33ce5cc309ffb5fce58d022067338a8e0e1725c197fb1a88af59f51e44e4255b20167c8684031c05d1f2592a01210223b72beef0965d10be0778efecd61fcac6f79a4ea169393380734464f84f2ab30000000001003f0200000001ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff0000000000ffffffff010000000000000000036a010000000000000000").unwrap_err(),
+            PsbtError::DuplicateKey(vec![0])
+        );
+
+        // the BIP174 invalid vectors with known key types carrying key data,
+        // or keys of the wrong length
+        for s in [
+            // global unsigned tx with key data
+            "70736274ff020001550200000001279a2323a5dfb51fc45f220fa58b0fc13e1e3342792a85d7e36cd6333b5cbc390000000000ffffffff01a05aea0b000000001976a914ffe9c0061097cc3b636f2cb0460fa4fc427d2b4588ac0000000000010120955eea0b0000000017a9146345200f68d189e1adc0df1c4d16ea8f14c0dbeb87220203b1341ccba7683b6af4f1238cd6e97e7167d569fac47f1e48d47541844355bd4646304302200424b58effaaa694e1559ea5c93bbfd4a89064224055cdf070b6771469442d07021f5c8eb0fea6516d60b8acb33ad64ede60e8785bfb3aa94b99bdf86151db9a9a010104220020771fd18ad459666dd49f3d564e3dbc42f4c84774e360ada16816a8ed488d5681010547522103b1341ccba7683b6af4f1238cd6e97e7167d569fac47f1e48d47541844355bd462103de55d1e1dac805e3f8a58c1fbf9b94c02f3dbaafe127fefca4995f26f82083bd52ae220603b1341ccba7683b6af4f1238cd6e97e7167d569fac47f1e48d47541844355bd4610b4a6ba67000000800000008004000080220603de55d1e1dac805e3f8a58c1fbf9b94c02f3dbaafe127fefca4995f26f82083bd10b4a6ba670000008000000080050000800000",
+            // input witness utxo with key data
+            "70736274ff0100550200000001279a2323a5dfb51fc45f220fa58b0fc13e1e3342792a85d7e36cd6333b5cbc390000000000ffffffff01a05aea0b000000001976a914ffe9c0061097cc3b636f2cb0460fa4fc427d2b4588ac000000000002010020955eea0b0000000017a9146345200f68d189e1adc0df1c4d16ea8f14c0dbeb87220203b1341ccba7683b6af4f1238cd6e97e7167d569fac47f1e48d47541844355bd4646304302200424b58effaaa694e1559ea5c93bbfd4a89064224055cdf070b6771469442d07021f5c8eb0fea6516d60b8acb33ad64ede60e8785bfb3aa94b99bdf86151db9a9a010104220020771fd18ad459666dd49f3d564e3dbc42f4c84774e360ada16816a8ed488d5681010547522103b1341ccba7683b6af4f1238cd6e97e7167d569fac47f1e48d47541844355bd462103de55d1e1dac805e3f8a58c1fbf9b94c02f3dbaafe127fefca4995f26f82083bd52ae220603b1341ccba7683b6af4f1238cd6e97e7167d569fac47f1e48d47541844355bd4610b4a6ba67000000800000008004000080220603de55d1e1dac805e3f8a58c1fbf9b94c02f3dbaafe127fefca4995f26f82083bd10b4a6ba670000008000000080050000800000",
+            // input partial sig with a 32 byte key
+            "70736274ff0100550200000001279a2323a5dfb51fc45f220fa58b0fc13e1e3342792a85d7e36cd6333b5cbc390000000000ffffffff01a05aea0b000000001976a914ffe9c0061097cc3b636f2cb0460fa4fc427d2b4588ac0000000000010120955eea0b0000000017a9146345200f68d189e1adc0df1c4d16ea8f14c0dbeb87210203b1341ccba7683b6af4f1238cd6e97e7167d569fac47f1e48d47541844355bd46304302200424b58effaaa694e1559ea5c93bbfd4a89064224055cdf070b6771469442d07021f5c8eb0fea6516d60b8acb33ad64ede60e8785bfb3aa94b99bdf86151db9a9a010104220020771fd18ad459666dd49f3d564e3dbc42f4c84774e360ada16816a8ed488d5681010547522103b1341ccba7683b6af4f1238cd6e97e7167d569fac47f1e48d47541844355bd462103de55d1e1dac805e3f8a58c1fbf9b94c02f3dbaafe127fefca4995f26f82083bd52ae220603b1341ccba7683b6af4f1238cd6e97e7167d569fac47f1e48d47541844355bd4610b4a6ba67000000800000008004000080220603de55d1e1dac805e3f8a58c1fbf9b94c02f3dbaafe127fefca4995f26f82083bd10b4a6ba670000008000000080050000800000",
+            // input redeem script with key data
+            "70736274ff0100550200000001279a2323a5dfb51fc45f220fa58b0fc13e1e3342792a85d7e36cd6333b5cbc390000000000ffffffff01a05aea0b000000001976a914ffe9c0061097cc3b636f2cb0460fa4fc427d2b4588ac0000000000010120955eea0b0000000017a9146345200f68d189e1adc0df1c4d16ea8f14c0dbeb87220203b1341ccba7683b6af4f1238cd6e97e7167d569fac47f1e48d47541844355bd4646304302200424b58effaaa694e1559ea5c93bbfd4a89064224055cdf070b6771469442d07021f5c8eb0fea6516d60b8acb33ad64ede60e8785bfb3aa94b99bdf86151db9a9a01020400220020771fd18ad459666dd49f3d564e3dbc42f4c84774e360ada16816a8ed488d5681010547522103b1341ccba7683b6af4f1238cd6e97e7167d569fac47f1e48d47541844355bd462103de55d1e1dac805e3f8a58c1fbf9b94c02f3dbaafe127fefca4995f26f82083bd52ae220603b1341ccba7683b6af4f1238cd6e97e7167d569fac47f1e48d47541844355bd4610b4a6ba67000000800000008004000080220603de55d1e1dac805e3f8a58c1fbf9b94c02f3dbaafe127fefca4995f26f82083bd10b4a6ba670000008000000080050000800000",
+            // input witness script with key data
+            "70736274ff0100550200000001279a2323a5dfb51fc45f220fa58b0fc13e1e3342792a85d7e36cd6333b5cbc390000000000ffffffff01a05aea0b000000001976a914ffe9c0061097cc3b636f2cb0460fa4fc427d2b4588ac0000000000010120955eea0b0000000017a9146345200f68d189e1adc0df1c4d16ea8f14c0dbeb87220203b1341ccba7683b6af4f1238cd6e97e7167d569fac47f1e48d47541844355bd4646304302200424b58effaaa694e1559ea5c93bbfd4a89064224055cdf070b6771469442d07021f5c8eb0fea6516d60b8acb33ad64ede60e8785bfb3aa94b99bdf86151db9a9a010104220020771fd18ad459666dd49f3d564e3dbc42f4c84774e360ada16816a8ed488d568102050047522103b1341ccba7683b6af4f1238cd6e97e7167d569fac47f1e48d47541844355bd462103de55d1e1dac805e3f8a58c1fbf9b94c02f3dbaafe127fefca4995f26f82083bd52ae220603b1341ccba7683b6af4f1238cd6e97e7167d569fac47f1e48d47541844355bd4610b4a6ba67000000800000008004000080220603de55d1e1dac805e3f8a58c1fbf9b94c02f3dbaafe127fefca4995f26f82083bd10b4a6ba670000008000000080050000800000",
+            // input bip32 derivation with a 34 byte key
+            "70736274ff0100550200000001279a2323a5dfb51fc45f220fa58b0fc13e1e3342792a85d7e36cd6333b5cbc390000000000ffffffff01a05aea0b000000001976a914ffe9c0061097cc3b636f2cb0460fa4fc427d2b4588ac0000000000010120955eea0b0000000017a9146345200f68d189e1adc0df1c4d16ea8f14c0dbeb87220203b1341ccba7683b6af4f1238cd6e97e7167d569fac47f1e48d47541844355bd4646304302200424b58effaaa694e1559ea5c93bbfd4a89064224055cdf070b6771469442d07021f5c8eb0fea6516d60b8acb33ad64ede60e8785bfb3aa94b99bdf86151db9a9a010104220020771fd18ad459666dd49f3d564e3dbc42f4c84774e360ada16816a8ed488d5681010547522103b1341ccba7683b6af4f1238cd6e97e7167d569fac47f1e48d47541844355bd462103de55d1e1dac805e3f8a58c1fbf9b94c02f3dbaafe127fefca4995f26f82083bd52ae230603b1341ccba7683b6af4f1238cd6e97e7167d569fac47f1e48d47541844355bd460010b4a6ba67000000800000008004000080220603de55d1e1dac805e3f8a58c1fbf9b94c02f3dbaafe127fefca4995f26f82083bd10b4a6ba670000008000000080050000800000",
+            // input non-witness utxo with key data
+            "70736274ff0100750200000001268171371edff285e937adeea4b37b78000c0566cbb3ad64641713ca42171bf60000000000feffffff02d3dff505000000001976a914d0c59903c5bac2868760e90fd521a4665aa7652088ac00e1f5050000000017a9143545e6e33b832c47050f24d3eeb93c9c03948bc787b32e130000020000fda5010100000000010289a3c71eab4d20e0371bbba4cc698fa295c9463afa2e397f8533ccb62f9567e50100000017160014be18d152a9b012039daf3da7de4f53349eecb985ffffffff86f8aa43a71dff1448893a530a7237ef6b4608bbb2dd2d0171e63aec6a4890b40100000017160014fe3e9ef1a745e974d902c4355943abcb34bd5353ffffffff0200c2eb0b000000001976a91485cff1097fd9e008bb34af709c62197b38978a4888ac72fef84e2c00000017a914339725ba21efd62ac753a9bcd067d6c7a6a39d05870247304402202712be22e0270f394f568311dc7ca9a68970b8025fdd3b240229f07f8a5f3a240220018b38d7dcd314e734c9276bd6fb40f673325bc4baa144c800d2f2f02db2765c012103d2e15674941bad4a996372cb87e1856d3652606d98562fe39c5e9e7e413f210502483045022100d12b852d85dcd961d2f5f4ab660654df6eedcc794c0c33ce5cc309ffb5fce58d022067338a8e0e1725c197fb1a88af59f51e44e4255b20167c8684031c05d1f2592a01210223b72beef0965d10be0778efecd61fcac6f79a4ea169393380734464f84f2ab30000000001030401000000000000",
+            // input final scriptSig with key data
+            "70736274ff0100a00200000002ab0949a08c5af7c49b8212f417e2f15ab3f5c33dcf153821a8139f877a5b7be40000000000feffffffab0949a08c5af7c49b8212f417e2f15ab3f5c33dcf153821a8139f877a5b7be40100000000feffffff02603bea0b000000001976a914768a40bbd740cbe81d988e71de2a4d5c71396b1d88ac8e240000000000001976a9146f4620b553fa095e721b9ee0efe9fa039cca459788ac00000000000207006a47304402204759661797c01b036b25928948686218347d89864b719e1f7fcf57d1e511658702205309eabf56aa4d8891ffd111fdf1336f3a29da866d7f8486d75546ceedaf93190121035cdc61fc7ba971c0b501a646a2a83b102cb43881217ca682dc86e2d73fa882920001012000e1f5050000000017a9143545e6e33b832c47050f24d3eeb93c9c03948bc787010416001485d13537f2e265405a34dbafa9e3dda01fb82308000000",
+            // input final witness with key data
+            "70736274ff0100a00200000002ab0949a08c5af7c49b8212f417e2f15ab3f5c33dcf153821a8139f877a5b7be40000000000feffffffab0949a08c5af7c49b8212f417e2f15ab3f5c33dcf153821a8139f877a5b7be40100000000feffffff02603bea0b000000001976a914768a40bbd740cbe81d988e71de2a4d5c71396b1d88ac8e240000000000001976a9146f4620b553fa095e721b9ee0efe9fa039cca459788ac000000000001076a47304402204759661797c01b036b25928948686218347d89864b719e1f7fcf57d1e511658702205309eabf56aa4d8891ffd111fdf1336f3a29da866d7f8486d75546ceedaf93190121035cdc61fc7ba971c0b501a646a2a83b102cb43881217ca682dc86e2d73fa882920208000201000001012000e1f5050000000017a9143545e6e33b832c47050f24d3eeb93c9c03948bc787010416001485d13537f2e265405a34dbafa9e3dda01fb82308000000",
+            // output bip32 derivation with a 32 byte key
+            "70736274ff0100550200000001279a2323a5dfb51fc45f220fa58b0fc13e1e3342792a85d7e36cd6333b5cbc390000000000ffffffff01a05aea0b000000001976a914ffe9c0061097cc3b636f2cb0460fa4fc427d2b4588ac0000000000010120955eea0b0000000017a9146345200f68d189e1adc0df1c4d16ea8f14c0dbeb87220203b1341ccba7683b6af4f1238cd6e97e7167d569fac47f1e48d47541844355bd4646304302200424b58effaaa694e1559ea5c93bbfd4a89064224055cdf070b6771469442d07021f5c8eb0fea6516d60b8acb33ad64ede60e8785bfb3aa94b99bdf86151db9a9a010104220020771fd18ad459666dd49f3d564e3dbc42f4c84774e360ada16816a8ed488d5681010547522103b1341ccba7683b6af4f1238cd6e97e7167d569fac47f1e48d47541844355bd462103de55d1e1dac805e3f8a58c1fbf9b94c02f3dbaafe127fefca4995f26f82083bd52ae220603b1341ccba7683b6af4f1238cd6e97e7167d569fac47f1e48d47541844355bd4610b4a6ba67000000800000008004000080220603de55d1e1dac805e3f8a58c1fbf9b94c02f3dbaafe127fefca4995f26f82083bd10b4a6ba67000000800000008005000080002102021111111111111111111111111111111111111111111111111111111111111110b4a6ba6700000080000000800400008000",
+            // input sighash type with key data
+            "70736274ff0100750200000001268171371edff285e937adeea4b37b78000c0566cbb3ad64641713ca42171bf60000000000feffffff02d3dff505000000001976a914d0c59903c5bac2868760e90fd521a4665aa7652088ac00e1f5050000000017a9143545e6e33b832c47050f24d3eeb93c9c03948bc787b32e1300000100fda5010100000000010289a3c71eab4d20e0371bbba4cc698fa295c9463afa2e397f8533ccb62f9567e50100000017160014be18d152a9b012039daf3da7de4f53349eecb985ffffffff86f8aa43a71dff1448893a530a7237ef6b4608bbb2dd2d0171e63aec6a4890b40100000017160014fe3e9ef1a745e974d902c4355943abcb34bd5353ffffffff0200c2eb0b000000001976a91485cff1097fd9e008bb34af709c62197b38978a4888ac72fef84e2c00000017a914339725ba21efd62ac753a9bcd067d6c7a6a39d05870247304402202712be22e0270f394f568311dc7ca9a68970b8025fdd3b240229f07f8a5f3a240220018b38d7dcd314e734c9276bd6fb40f673325bc4baa144c800d2f2f02db2765c012103d2e15674941bad4a996372cb87e1856d3652606d98562fe39c5e9e7e413f210502483045022100d12b852d85dcd961d2f5f4ab660654df6eedcc794c0c33ce5cc309ffb5fce58d022067338a8e0e1725c197fb1a88af59f51e44e4255b20167c8684031c05d1f2592a01210223b72beef0965d10be0778efecd61fcac6f79a4ea169393380734464f84f2ab3000000000203000401000000000000",
+            // output redeem script with key data
+            "70736274ff0100a00200000002ab0949a08c5af7c49b8212f417e2f15ab3f5c33dcf153821a8139f877a5b7be40000000000feffffffab0949a08c5af7c49b8212f417e2f15ab3f5c33dcf153821a8139f877a5b7be40100000000feffffff02603bea0b000000001976a914768a40bbd740cbe81d988e71de2a4d5c71396b1d88ac8e240000000000001976a9146f4620b553fa095e721b9ee0efe9fa039cca459788ac000000000001076a47304402204759661797c01b036b25928948686218347d89864b719e1f7fcf57d1e511658702205309eabf56aa4d8891ffd111fdf1336f3a29da866d7f8486d75546ceedaf93190121035cdc61fc7ba971c0b501a646a2a83b102cb43881217ca682dc86e2d73fa882920001012000e1f5050000000017a9143545e6e33b832c47050f24d3eeb93c9c03948bc787010416001485d13537f2e265405a34dbafa9e3dda01fb8230800020000160014d85c2b71d0060b09c9886aeb815e50991dda124d0000",
+            // output witness script with key data
+            "70736274ff0100a00200000002ab0949a08c5af7c49b8212f417e2f15ab3f5c33dcf153821a8139f877a5b7be40000000000feffffffab0949a08c5af7c49b8212f417e2f15ab3f5c33dcf153821a8139f877a5b7be40100000000feffffff02603bea0b000000001976a914768a40bbd740cbe81d988e71de2a4d5c71396b1d88ac8e240000000000001976a9146f4620b553fa095e721b9ee0efe9fa039cca459788ac000000000001076a47304402204759661797c01b036b25928948686218347d89864b719e1f7fcf57d1e511658702205309eabf56aa4d8891ffd111fdf1336f3a29da866d7f8486d75546ceedaf93190121035cdc61fc7ba971c0b501a646a2a83b102cb43881217ca682dc86e2d73fa882920001012000e1f5050000000017a9143545e6e33b832c47050f24d3eeb93c9c03948bc787010416001485d13537f2e265405a34dbafa9e3dda01fb823080002010001510000",
+        ] {
+            assert!(matches!(parse_hex(s), Err(PsbtError::InvalidKey(_))));
+        }
+        // and an unsigned tx serialized with witness serialization format
+        assert_eq!(
+            parse_hex("70736274ff01005802000000000101279a2323a5dfb51fc45f220fa58b0fc13e1e3342792a85d7e36cd6333b5cbc390000000000ffffffff01a05aea0b000000001976a914ffe9c0061097cc3b636f2cb0460fa4fc427d2b4588ac000000000000010120955eea0b0000000017a9146345200f68d189e1adc0df1c4d16ea8f14c0dbeb87220203b1341ccba7683b6af4f1238cd6e97e7167d569fac47f1e48d47541844355bd4646304302200424b58effaaa694e1559ea5c93bbfd4a89064224055cdf070b6771469442d07021f5c8eb0fea6516d60b8acb33ad64ede60e8785bfb3aa94b99bdf86151db9a9a010104220020771fd18ad459666dd49f3d564e3dbc42f4c84774e360ada16816a8ed488d5681010547522103b1341ccba7683b6af4f1238cd6e97e7167d569fac47f1e48d47541844355bd462103de55d1e1dac805e3f8a58c1fbf9b94c02f3dbaafe127fefca4995f26f82083bd52ae220603b1341ccba7683b6af4f1238cd6e97e7167d569fac47f1e48d47541844355bd4610b4a6ba67000000800000008004000080220603de55d1e1dac805e3f8a58c1fbf9b94c02f3dbaafe127fefca4995f26f82083bd10b4a6ba670000008000000080050000800000").unwrap_err(),
+            PsbtError::InvalidValue(vec![PSBT_GLOBAL_UNSIGNED_TX as u8])
+        );
+
+        // every key type that takes no key data, in both versions
+        for key_type in PSBT_IN_KEYLESS {
+            let key = vec![*key_type as u8, 0];
+            for version in [0, 2] {
+                assert_eq!(
+                    Input::parse_map(vec![(key.clone(), vec![0])], version),
+                    Err(PsbtError::InvalidKey(key.clone()))
+                );
+            }
+        }
+        for key_type in PSBT_OUT_KEYLESS {
+            let key = vec![*key_type as u8, 0];
+            for version in [0, 2] {
+                assert_eq!(
+                    Output::parse_map(vec![(key.clone(), vec![0])], version),
+                    Err(PsbtError::InvalidKey(key.clone()))
+                );
+            }
+        }
+        // version 2 types are only known in version 2
+        let key = vec![PSBT_IN_SEQUENCE as u8, 0];
+        assert!(Input::parse_map(vec![(key.clone(), vec![0])], 0).is_ok());
+        assert_eq!(
+            Input::parse_map(vec![(key.clone(), vec![0])], 2),
+            Err(PsbtError::InvalidKey(key))
+        );
+        let key = vec![PSBT_OUT_AMOUNT as u8, 0];
+        assert!(Output::parse_map(vec![(key.clone(), vec![0])], 0).is_ok());
+        assert_eq!(
+            Output::parse_map(vec![(key.clone(), vec![0])], 2),
+            Err(PsbtError::InvalidKey(key))
+        );
+    }
+
+    #[test]
+    fn test_parse_valid() {
+        // BIP174 valid vectors 2-6, an uncompressed partial signature key
+        // and proprietary fields
+        for s in [
+            "70736274ff0100a00200000002ab0949a08c5af7c49b8212f417e2f15ab3f5c33dcf153821a8139f877a5b7be40000000000feffffffab0949a08c5af7c49b8212f417e2f15ab3f5c33dcf153821a8139f877a5b7be40100000000feffffff02603bea0b000000001976a914768a40bbd740cbe81d988e71de2a4d5c71396b1d88ac8e240000000000001976a9146f4620b553fa095e721b9ee0efe9fa039cca459788ac000000000001076a47304402204759661797c01b036b25928948686218347d89864b719e1f7fcf57d1e511658702205309eabf56aa4d8891ffd111fdf1336f3a29da866d7f8486d75546ceedaf93190121035cdc61fc7ba971c0b501a646a2a83b102cb43881217ca682dc86e2d73fa882920001012000e1f5050000000017a9143545e6e33b832c47050f24d3eeb93c9c03948bc787010416001485d13537f2e265405a34dbafa9e3dda01fb82308000000",
+            "70736274ff0100750200000001268171371edff285e937adeea4b37b78000c0566cbb3ad64641713ca42171bf60000000000feffffff02d3dff505000000001976a914d0c59903c5bac2868760e90fd521a4665aa7652088ac00e1f5050000000017a9143545e6e33b832c47050f24d3eeb93c9c03948bc787b32e1300000100fda5010100000000010289a3c71eab4d20e0371bbba4cc698fa295c9463afa2e397f8533ccb62f9567e50100000017160014be18d152a9b012039daf3da7de4f53349eecb985ffffffff86f8aa43a71dff1448893a530a7237ef6b4608bbb2dd2d0171e63aec6a4890b40100000017160014fe3e9ef1a745e974d902c4355943abcb34bd5353ffffffff0200c2eb0b000000001976a91485cff1097fd9e008bb34af709c62197b38978a4888ac72fef84e2c00000017a914339725ba21efd62ac753a9bcd067d6c7a6a39d05870247304402202712be22e0270f394f568311dc7ca9a68970b8025fdd3b240229f07f8a5f3a240220018b38d7dcd314e734c9276bd6fb40f673325bc4baa144c800d2f2f02db2765c012103d2e15674941bad4a996372cb87e1856d3652606d98562fe39c5e9e7e413f210502483045022100d12b852d85dcd961d2f5f4ab660654df6eedcc794c0c33ce5cc309ffb5fce58d022067338a8e0e1725c197fb1a88af59f51e44e4255b20167c8684031c05d1f2592a01210223b72beef0965d10be0778efecd61fcac6f79a4ea169393380734464f84f2ab30000000001030401000000000000",
+            "70736274ff0100a00200000002ab0949a08c5af7c49b8212f417e2f15ab3f5c33dcf153821a8139f877a5b7be40000000000feffffffab0949a08c5af7c49b8212f417e2f15ab3f5c33dcf153821a8139f877a5b7be40100000000feffffff02603bea0b000000001976a914768a40bbd740cbe81d988e71de2a4d5c71396b1d88ac8e240000000000001976a9146f4620b553fa095e721b9ee0efe9fa039cca459788ac00000000000100df0200000001268171371edff285e937adeea4b37b78000c0566cbb3ad64641713ca42171bf6000000006a473044022070b2245123e6bf474d60c5b50c043d4c691a5d2435f09a34a7662a9dc251790a022001329ca9dacf280bdf30740ec0390422422c81cb45839457aeb76fc12edd95b3012102657d118d3357b8e0f4c2cd46db7b39f6d9c38d9a70abcb9b2de5dc8dbfe4ce31feffffff02d3dff505000000001976a914d0c59903c5bac2868760e90fd521a4665aa7652088ac00e1f5050000000017a9143545e6e33b832c47050f24d3eeb93c9c03948bc787b32e13000001012000e1f5050000000017a9143545e6e33b832c47050f24d3eeb93c9c03948bc787010416001485d13537f2e265405a34dbafa9e3dda01fb8230800220202ead596687ca806043edc3de116cdf29d5e9257c196cd055cf698c8d02bf24e9910b4a6ba670000008000000080020000800022020394f62be9df19952c5587768aeb7698061ad2c4a25c894f47d8c162b4d7213d0510b4a6ba6700000080010000800200008000",
+            "70736274ff0100550200000001279a2323a5dfb51fc45f220fa58b0fc13e1e3342792a85d7e36cd6333b5cbc390000000000ffffffff01a05aea0b000000001976a914ffe9c0061097cc3b636f2cb0460fa4fc427d2b4588ac0000000000010120955eea0b0000000017a9146345200f68d189e1adc0df1c4d16ea8f14c0dbeb87220203b1341ccba7683b6af4f1238cd6e97e7167d569fac47f1e48d47541844355bd4646304302200424b58effaaa694e1559ea5c93bbfd4a89064224055cdf070b6771469442d07021f5c8eb0fea6516d60b8acb33ad64ede60e8785bfb3aa94b99bdf86151db9a9a010104220020771fd18ad459666dd49f3d564e3dbc42f4c84774e360ada16816a8ed488d5681010547522103b1341ccba7683b6af4f1238cd6e97e7167d569fac47f1e48d47541844355bd462103de55d1e1dac805e3f8a58c1fbf9b94c02f3dbaafe127fefca4995f26f82083bd52ae220603b1341ccba7683b6af4f1238cd6e97e7167d569fac47f1e48d47541844355bd4610b4a6ba67000000800000008004000080220603de55d1e1dac805e3f8a58c1fbf9b94c02f3dbaafe127fefca4995f26f82083bd10b4a6ba670000008000000080050000800000",
+            "70736274ff01003f0200000001ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff0000000000ffffffff010000000000000000036a010000000000000a0f0102030405060708090f0102030405060708090a0b0c0d0e0f0000",
+            "70736274ff01003302000000010000000000000000000000000000000000000000000000000000000000000000ffffffff00ffffffff000000000000420204bb0d5d0cca36e7b9c80f63bc04c1240babb83bcd2803ef7ac8b6e2af594291daec281e856c98d210c5ab14dfd5828761f8ee7d5f45ca21ad3e4c4b41b747a3a047304402204f67e2afb76142d44fae58a2495d33a3419daa26cd0db8d04f3452b63289ac0f022010762a9fb67e94cc5cad9026f6dc99ff7f070f4278d30fbc7d0c869dd38c7fe70100",
+            "70736274ff0100a00200000002ab0949a08c5af7c49b8212f417e2f15ab3f5c33dcf153821a8139f877a5b7be40000000000feffffffab0949a08c5af7c49b8212f417e2f15ab3f5c33dcf153821a8139f877a5b7be40100000000feffffff02603bea0b000000001976a914768a40bbd740cbe81d988e71de2a4d5c71396b1d88ac8e240000000000001976a9146f4620b553fa095e721b9ee0efe9fa039cca459788ac000000000001076a47304402204759661797c01b036b25928948686218347d89864b719e1f7fcf57d1e511658702205309eabf56aa4d8891ffd111fdf1336f3a29da866d7f8486d75546ceedaf93190121035cdc61fc7ba971c0b501a646a2a83b102cb43881217ca682dc86e2d73fa882920001012000e1f5050000000017a9143545e6e33b832c47050f24d3eeb93c9c03948bc787010416001485d13537f2e265405a34dbafa9e3dda01fb82308000000",
+        ] {
+            let raw = hex::decode(s).unwrap();
+            let psbt = Psbt::parse(&mut Cursor::new(raw.clone())).unwrap();
+            assert_eq!(psbt.serialize(), raw);
+            assert_eq!(psbt.to_string().parse::<Psbt>().unwrap(), psbt);
+        }
+        // a version 2 input type with key data is unknown in version 0
+        let psbt = parse_hex("70736274ff01003f0200000001ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff0000000000ffffffff010000000000000000036a010000000000000a0f0102030405060708090f0102030405060708090a0b0c0d0e0f0000").unwrap();
+        assert_eq!(psbt.inputs[0].unknown.len(), 1);
+        // only one of two multisig signatures
+        let mut psbt = parse_hex("70736274ff0100550200000001279a2323a5dfb51fc45f220fa58b0fc13e1e3342792a85d7e36cd6333b5cbc390000000000ffffffff01a05aea0b000000001976a914ffe9c0061097cc3b636f2cb0460fa4fc427d2b4588ac0000000000010120955eea0b0000000017a9146345200f68d189e1adc0df1c4d16ea8f14c0dbeb87220203b1341ccba7683b6af4f1238cd6e97e7167d569fac47f1e48d47541844355bd4646304302200424b58effaaa694e1559ea5c93bbfd4a89064224055cdf070b6771469442d07021f5c8eb0fea6516d60b8acb33ad64ede60e8785bfb3aa94b99bdf86151db9a9a010104220020771fd18ad459666dd49f3d564e3dbc42f4c84774e360ada16816a8ed488d5681010547522103b1341ccba7683b6af4f1238cd6e97e7167d569fac47f1e48d47541844355bd462103de55d1e1dac805e3f8a58c1fbf9b94c02f3dbaafe127fefca4995f26f82083bd52ae220603b1341ccba7683b6af4f1238cd6e97e7167d569fac47f1e48d47541844355bd4610b4a6ba67000000800000008004000080220603de55d1e1dac805e3f8a58c1fbf9b94c02f3dbaafe127fefca4995f26f82083bd10b4a6ba670000008000000080050000800000").unwrap();
+        assert_eq!(psbt.finalize(), Err(PsbtError::CannotFinalize(0)));
+    }
+
+    #[test]
+    fn test_taproot_vectors() {
+        // BIP371 invalid vectors: bad x-only key lengths, taproot signature
+        // sizes, merkle root length and control blocks
+        for s in [
+            "70736274ff010071020000000127744ababf3027fe0d6cf23a96eee2efb188ef52301954585883e69b6624b2420000000000ffffffff02787c01000000000016001483a7e34bd99ff03a4962ef8a1a101bb295461ece606b042a010000001600147ac369df1b20e033d6116623957b0ac49f3c52e8000000000001012b00f2052a010000002251205a2c2cf5b52cf31f83ad2e8da63ff03183ecd8f609c7510ae8a48e03910a075701172102fe349064c98d6e2a853fa3c9b12bd8b304a19c195c60efa7ee2393046d3fa232000000",
+            "70736274ff010071020000000127744ababf3027fe0d6cf23a96eee2efb188ef52301954585883e69b6624b2420000000000ffffffff02787c01000000000016001483a7e34bd99ff03a4962ef8a1a101bb295461ece606b042a010000001600147ac369df1b20e033d6116623957b0ac49f3c52e8000000000001012b00f2052a010000002251205a2c2cf5b52cf31f83ad2e8da63ff03183ecd8f609c7510ae8a48e03910a0757011342173bb3d36c074afb716fec6307a069a2e450b995f3c82785945ab8df0e24260dcd703b0cbf34de399184a9481ac2b3586db6601f026a77f7e4938481bc34751701aa000000",
+            "70736274ff010071020000000127744ababf3027fe0d6cf23a96eee2efb188ef52301954585883e69b6624b2420000000000ffffffff02787c01000000000016001483a7e34bd99ff03a4962ef8a1a101bb295461ece606b042a010000001600147ac369df1b20e033d6116623957b0ac49f3c52e8000000000001012b00f2052a010000002251205a2c2cf5b52cf31f83ad2e8da63ff03183ecd8f609c7510ae8a48e03910a0757221602fe349064c98d6e2a853fa3c9b12bd8b304a19c195c60efa7ee2393046d3fa2321900772b2da75600008001000080000000800100000000000000000000",
+            "70736274ff01007d020000000127744ababf3027fe0d6cf23a96eee2efb188ef52301954585883e69b6624b2420000000000ffffffff02887b0100000000001600142382871c7e8421a00093f754d91281e675874b9f606b042a010000002251205a2c2cf5b52cf31f83ad2e8da63ff03183ecd8f609c7510ae8a48e03910a0757000000000001012b00f2052a010000002251205a2c2cf5b52cf31f83ad2e8da63ff03183ecd8f609c7510ae8a48e03910a0757000001052102fe349064c98d6e2a853fa3c9b12bd8b304a19c195c60efa7ee2393046d3fa23200",
+            "70736274ff01007d020000000127744ababf3027fe0d6cf23a96eee2efb188ef52301954585883e69b6624b2420000000000ffffffff02887b0100000000001600142382871c7e8421a00093f754d91281e675874b9f606b042a010000002251205a2c2cf5b52cf31f83ad2e8da63ff03183ecd8f609c7510ae8a48e03910a0757000000000001012b00f2052a010000002251205a2c2cf5b52cf31f83ad2e8da63ff03183ecd8f609c7510ae8a48e03910a07570000220702fe349064c98d6e2a853fa3c9b12bd8b304a19c195c60efa7ee2393046d3fa2321900772b2da7560000800100008000000080010000000000000000",
+            "70736274ff01005e02000000019bd48765230bf9a72e662001f972556e54f0c6f97feb56bcb5600d817f6995260100000000ffffffff0148e6052a01000000225120030da4fce4f7db28c2cb2951631e003713856597fe963882cb500e68112cca63000000000001012b00f2052a01000000225120c2247efbfd92ac47f6f40b8d42d169175a19fa9fa10e4a25d7f35eb4dd85b6924214022cb13ac68248de806aa6a3659cf3c03eb6821d09c8114a4e868febde865bb6d2cd970e15f53fc0c82f950fd560ffa919b76172be017368a89913af074f400b094089756aa3739ccc689ec0fcf3a360be32cc0b59b16e93a1e8bb4605726b2ca7a3ff706c4176649632b2cc68e1f912b8a578e3719ce7710885c7a966f49bcd43cb0000",
+            "70736274ff01005e02000000019bd48765230bf9a72e662001f972556e54f0c6f97feb56bcb5600d817f6995260100000000ffffffff0148e6052a01000000225120030da4fce4f7db28c2cb2951631e003713856597fe963882cb500e68112cca63000000000001012b00f2052a01000000225120c2247efbfd92ac47f6f40b8d42d169175a19fa9fa10e4a25d7f35eb4dd85b69241142cb13ac68248de806aa6a3659cf3c03eb6821d09c8114a4e868febde865bb6d2cd970e15f53fc0c82f950fd560ffa919b76172be017368a89913af074f400b094289756aa3739ccc689ec0fcf3a360be32cc0b59b16e93a1e8bb4605726b2ca7a3ff706c4176649632b2cc68e1f912b8a578e3719ce7710885c7a966f49bcd43cb01010000",
+            "70736274ff01005e02000000019bd48765230bf9a72e662001f972556e54f0c6f97feb56bcb5600d817f6995260100000000ffffffff0148e6052a01000000225120030da4fce4f7db28c2cb2951631e003713856597fe963882cb500e68112cca63000000000001012b00f2052a01000000225120c2247efbfd92ac47f6f40b8d42d169175a19fa9fa10e4a25d7f35eb4dd85b69241142cb13ac68248de806aa6a3659cf3c03eb6821d09c8114a4e868febde865bb6d2cd970e15f53fc0c82f950fd560ffa919b76172be017368a89913af074f400b093989756aa3739ccc689ec0fcf3a360be32cc0b59b16e93a1e8bb4605726b2ca7a3ff706c4176649632b2cc68e1f912b8a578e3719ce7710885c7a966f49bcd43cb0000",
+            "70736274ff01005e02000000019bd48765230bf9a72e662001f972556e54f0c6f97feb56bcb5600d817f6995260100000000ffffffff0148e6052a01000000225120030da4fce4f7db28c2cb2951631e003713856597fe963882cb500e68112cca63000000000001012b00f2052a01000000225120c2247efbfd92ac47f6f40b8d42d169175a19fa9fa10e4a25d7f35eb4dd85b6926315c150929b74c1a04954b78b4b6035e97a5e078a5a0f28ec96d547bfee9ace803ac06f7d62059e9497a1a4a267569d9876da60101aff38e3529b9b939ce7f91ae970115f2e490af7cc45c4f78511f36057ce5c5a5c56325a29fb44dfc203f356e1f80023202cb13ac68248de806aa6a3659cf3c03eb6821d09c8114a4e868febde865bb6d2acc00000",
+            "70736274ff01005e02000000019bd48765230bf9a72e662001f972556e54f0c6f97feb56bcb5600d817f6995260100000000ffffffff0148e6052a01000000225120030da4fce4f7db28c2cb2951631e003713856597fe963882cb500e68112cca63000000000001012b00f2052a01000000225120c2247efbfd92ac47f6f40b8d42d169175a19fa9fa10e4a25d7f35eb4dd85b6926115c150929b74c1a04954b78b4b6035e97a5e078a5a0f28ec96d547bfee9ace803ac06f7d62059e9497a1a4a267569d9876da60101aff38e3529b9b939ce7f91ae970115f2e490af7cc45c4f78511f36057ce5c5a5c56325a29fb44dfc203f356e123202cb13ac68248de806aa6a3659cf3c03eb6821d09c8114a4e868febde865bb6d2acc00000",
+        ] {
+            assert!(parse_hex(s).is_err());
+        }
+        for s in [
+            "70736274ff010052020000000127744ababf3027fe0d6cf23a96eee2efb188ef52301954585883e69b6624b2420000000000ffffffff0148e6052a01000000160014768e1eeb4cf420866033f80aceff0f9720744969000000000001012b00f2052a010000002251205a2c2cf5b52cf31f83ad2e8da63ff03183ecd8f609c7510ae8a48e03910a07572116fe349064c98d6e2a853fa3c9b12bd8b304a19c195c60efa7ee2393046d3fa2321900772b2da75600008001000080000000800100000000000000011720fe349064c98d6e2a853fa3c9b12bd8b304a19c195c60efa7ee2393046d3fa232002202036b772a6db74d8753c98a827958de6c78ab3312109f37d3e0304484242ece73d818772b2da7540000800100008000000080000000000000000000",
+            "70736274ff010052020000000127744ababf3027fe0d6cf23a96eee2efb188ef52301954585883e69b6624b2420000000000ffffffff0148e6052a01000000160014768e1eeb4cf420866033f80aceff0f9720744969000000000001012b00f2052a010000002251205a2c2cf5b52cf31f83ad2e8da63ff03183ecd8f609c7510ae8a48e03910a0757011340bb53ec917bad9d906af1ba87181c48b86ace5aae2b53605a725ca74625631476fc6f5baedaf4f2ee0f477f36f58f3970d5b8273b7e497b97af2e3f125c97af342116fe349064c98d6e2a853fa3c9b12bd8b304a19c195c60efa7ee2393046d3fa2321900772b2da75600008001000080000000800100000000000000011720fe349064c98d6e2a853fa3c9b12bd8b304a19c195c60efa7ee2393046d3fa232002202036b772a6db74d8753c98a827958de6c78ab3312109f37d3e0304484242ece73d818772b2da7540000800100008000000080000000000000000000",
+            "70736274ff01005e020000000127744ababf3027fe0d6cf23a96eee2efb188ef52301954585883e69b6624b2420000000000ffffffff0148e6052a0100000022512083698e458c6664e1595d75da2597de1e22ee97d798e706c4c0a4b5a9823cd743000000000001012b00f2052a010000002251205a2c2cf5b52cf31f83ad2e8da63ff03183ecd8f609c7510ae8a48e03910a07572116fe349064c98d6e2a853fa3c9b12bd8b304a19c195c60efa7ee2393046d3fa2321900772b2da75600008001000080000000800100000000000000011720fe349064c98d6e2a853fa3c9b12bd8b304a19c195c60efa7ee2393046d3fa232000105201124da7aec92ccd06c954562647f437b138b95721a84be2bf2276bbddab3e67121071124da7aec92ccd06c954562647f437b138b95721a84be2bf2276bbddab3e6711900772b2da7560000800100008000000080000000000500000000",
+            "70736274ff01005e02000000019bd48765230bf9a72e662001f972556e54f0c6f97feb56bcb5600d817f6995260100000000ffffffff0148e6052a0100000022512083698e458c6664e1595d75da2597de1e22ee97d798e706c4c0a4b5a9823cd743000000000001012b00f2052a01000000225120c2247efbfd92ac47f6f40b8d42d169175a19fa9fa10e4a25d7f35eb4dd85b6926215c150929b74c1a04954b78b4b6035e97a5e078a5a0f28ec96d547bfee9ace803ac06f7d62059e9497a1a4a267569d9876da60101aff38e3529b9b939ce7f91ae970115f2e490af7cc45c4f78511f36057ce5c5a5c56325a29fb44dfc203f356e1f823202cb13ac68248de806aa6a3659cf3c03eb6821d09c8114a4e868febde865bb6d2acc04215c150929b74c1a04954b78b4b6035e97a5e078a5a0f28ec96d547bfee9ace803ac097c6e6fea5ff714ff5724499990810e406e98aa10f5bf7e5f6784bc1d0a9a6ce23204320b0bf16f011b53ea7be615924aa7f27e5d29ad20ea1155d848676c3bad1b2acc06215c150929b74c1a04954b78b4b6035e97a5e078a5a0f28ec96d547bfee9ace803ac0cd970e15f53fc0c82f950fd560ffa919b76172be017368a89913af074f400b09115f2e490af7cc45c4f78511f36057ce5c5a5c56325a29fb44dfc203f356e1f82320fa0f7a3cef3b1d0c0a6ce7d26e17ada0b2e5c92d19efad48b41859cb8a451ca9acc021162cb13ac68248de806aa6a3659cf3c03eb6821d09c8114a4e868febde865bb6d23901cd970e15f53fc0c82f950fd560ffa919b76172be017368a89913af074f400b09772b2da7560000800100008002000080000000000000000021164320b0bf16f011b53ea7be615924aa7f27e5d29ad20ea1155d848676c3bad1b23901115f2e490af7cc45c4f78511f36057ce5c5a5c56325a29fb44dfc203f356e1f8772b2da75600008001000080010000800000000000000000211650929b74c1a04954b78b4b6035e97a5e078a5a0f28ec96d547bfee9ace803ac005007c461e5d2116fa0f7a3cef3b1d0c0a6ce7d26e17ada0b2e5c92d19efad48b41859cb8a451ca939016f7d62059e9497a1a4a267569d9876da60101aff38e3529b9b939ce7f91ae970772b2da7560000800100008003000080000000000000000001172050929b74c1a04954b78b4b6035e97a5e078a5a0f28ec96d547bfee9ace803ac0011820f0362e2f75a6f420a5bde3eb221d96ae6720cf25f81890c95b1d775acb515e65000105201124da7aec92ccd06c954562647f437b138b95721a84be2bf2276bbddab3e67121071124da7aec92ccd06c954562647f437b138b95721a84be2bf2276bbddab3e6711900772b2da7560000800100008000000080000000000500000000",
+            "70736274ff01005e020000000127744ababf3027fe0d6cf23a96eee2efb188ef52301954585883e69b6624b2420000000000ffffffff0148e6052a010000002251200a8cbdc86de1ce1c0f9caeb22d6df7ced3683fe423e05d1e402a879341d6f6f5000000000001012b00f2052a010000002251205a2c2cf5b52cf31f83ad2e8da63ff03183ecd8f609c7510ae8a48e03910a07572116fe349064c98d6e2a853fa3c9b12bd8b304a19c195c60efa7ee2393046d3fa2321900772b2da75600008001000080000000800100000000000000011720fe349064c98d6e2a853fa3c9b12bd8b304a19c195c60efa7ee2393046d3fa2320001052050929b74c1a04954b78b4b6035e97a5e078a5a0f28ec96d547bfee9ace803ac001066f02c02220736e572900fe1252589a2143c8f3c79f71a0412d2353af755e9701c782694a02ac02c02220631c5f3b5832b8fbdebfb19704ceeb323c21f40f7a24f43d68ef0cc26b125969ac01c0222044faa49a0338de488c8dfffecdfb6f329f380bd566ef20c8df6d813eab1c4273ac210744faa49a0338de488c8dfffecdfb6f329f380bd566ef20c8df6d813eab1c42733901f06b798b92a10ed9a9d0bbfd3af173a53b1617da3a4159ca008216cd856b2e0e772b2da75600008001000080010000800000000003000000210750929b74c1a04954b78b4b6035e97a5e078a5a0f28ec96d547bfee9ace803ac005007c461e5d2107631c5f3b5832b8fbdebfb19704ceeb323c21f40f7a24f43d68ef0cc26b125969390118ace409889785e0ea70ceebb8e1ca892a7a78eaede0f2e296cf435961a8f4ca772b2da756000080010000800200008000000000030000002107736e572900fe1252589a2143c8f3c79f71a0412d2353af755e9701c782694a02390129a5b4915090162d759afd3fe0f93fa3326056d0b4088cb933cae7826cb8d82c772b2da7560000800100008003000080000000000300000000",
+            "70736274ff01005e02000000019bd48765230bf9a72e662001f972556e54f0c6f97feb56bcb5600d817f6995260100000000ffffffff0148e6052a0100000022512083698e458c6664e1595d75da2597de1e22ee97d798e706c4c0a4b5a9823cd743000000000001012b00f2052a01000000225120c2247efbfd92ac47f6f40b8d42d169175a19fa9fa10e4a25d7f35eb4dd85b69241142cb13ac68248de806aa6a3659cf3c03eb6821d09c8114a4e868febde865bb6d2cd970e15f53fc0c82f950fd560ffa919b76172be017368a89913af074f400b0940bf818d9757d6ffeb538ba057fb4c1fc4e0f5ef186e765beb564791e02af5fd3d5e2551d4e34e33d86f276b82c99c79aed3f0395a081efcd2cc2c65dd7e693d7941144320b0bf16f011b53ea7be615924aa7f27e5d29ad20ea1155d848676c3bad1b2115f2e490af7cc45c4f78511f36057ce5c5a5c56325a29fb44dfc203f356e1f840e1f1ab6fabfa26b236f21833719dc1d428ab768d80f91f9988d8abef47bfb863bb1f2a529f768c15f00ce34ec283cdc07e88f8428be28f6ef64043c32911811a4114fa0f7a3cef3b1d0c0a6ce7d26e17ada0b2e5c92d19efad48b41859cb8a451ca96f7d62059e9497a1a4a267569d9876da60101aff38e3529b9b939ce7f91ae97040ec1f0379206461c83342285423326708ab031f0da4a253ee45aafa5b8c92034d8b605490f8cd13e00f989989b97e215faa36f12dee3693d2daccf3781c1757f66215c150929b74c1a04954b78b4b6035e97a5e078a5a0f28ec96d547bfee9ace803ac06f7d62059e9497a1a4a267569d9876da60101aff38e3529b9b939ce7f91ae970115f2e490af7cc45c4f78511f36057ce5c5a5c56325a29fb44dfc203f356e1f823202cb13ac68248de806aa6a3659cf3c03eb6821d09c8114a4e868febde865bb6d2acc04215c150929b74c1a04954b78b4b6035e97a5e078a5a0f28ec96d547bfee9ace803ac097c6e6fea5ff714ff5724499990810e406e98aa10f5bf7e5f6784bc1d0a9a6ce23204320b0bf16f011b53ea7be615924aa7f27e5d29ad20ea1155d848676c3bad1b2acc06215c150929b74c1a04954b78b4b6035e97a5e078a5a0f28ec96d547bfee9ace803ac0cd970e15f53fc0c82f950fd560ffa919b76172be017368a89913af074f400b09115f2e490af7cc45c4f78511f36057ce5c5a5c56325a29fb44dfc203f356e1f82320fa0f7a3cef3b1d0c0a6ce7d26e17ada0b2e5c92d19efad48b41859cb8a451ca9acc021162cb13ac68248de806aa6a3659cf3c03eb6821d09c8114a4e868febde865bb6d23901cd970e15f53fc0c82f950fd560ffa919b76172be017368a89913af074f400b09772b2da7560000800100008002000080000000000000000021164320b0bf16f011b53ea7be615924aa7f27e5d29ad20ea1155d848676c3bad1b23901115f2e490af7cc45c4f78511f36057ce5c5a5c56325a29fb44dfc203f356e1f8772b2da75600008001000080010000800000000000000000211650929b74c1a04954b78b4b6035e97a5e078a5a0f28ec96d547bfee9ace803ac005007c461e5d2116fa0f7a3cef3b1d0c0a6ce7d26e17ada0b2e5c92d19efad48b41859cb8a451ca939016f7d62059e9497a1a4a267569d9876da60101aff38e3529b9b939ce7f91ae970772b2da7560000800100008003000080000000000000000001172050929b74c1a04954b78b4b6035e97a5e078a5a0f28ec96d547bfee9ace803ac0011820f0362e2f75a6f420a5bde3eb221d96ae6720cf25f81890c95b1d775acb515e65000105201124da7aec92ccd06c954562647f437b138b95721a84be2bf2276bbddab3e67121071124da7aec92ccd06c954562647f437b138b95721a84be2bf2276bbddab3e6711900772b2da7560000800100008000000080000000000500000000",
+        ] {
+            let raw = hex::decode(s).unwrap();
+            let psbt = Psbt::parse(&mut Cursor::new(raw.clone())).unwrap();
+            assert_eq!(psbt.serialize(), raw);
+        }
+
+        let psbt = parse_hex("70736274ff01005e02000000019bd48765230bf9a72e662001f972556e54f0c6f97feb56bcb5600d817f6995260100000000ffffffff0148e6052a0100000022512083698e458c6664e1595d75da2597de1e22ee97d798e706c4c0a4b5a9823cd743000000000001012b00f2052a01000000225120c2247efbfd92ac47f6f40b8d42d169175a19fa9fa10e4a25d7f35eb4dd85b69241142cb13ac68248de806aa6a3659cf3c03eb6821d09c8114a4e868febde865bb6d2cd970e15f53fc0c82f950fd560ffa919b76172be017368a89913af074f400b0940bf818d9757d6ffeb538ba057fb4c1fc4e0f5ef186e765beb564791e02af5fd3d5e2551d4e34e33d86f276b82c99c79aed3f0395a081efcd2cc2c65dd7e693d7941144320b0bf16f011b53ea7be615924aa7f27e5d29ad20ea1155d848676c3bad1b2115f2e490af7cc45c4f78511f36057ce5c5a5c56325a29fb44dfc203f356e1f840e1f1ab6fabfa26b236f21833719dc1d428ab768d80f91f9988d8abef47bfb863bb1f2a529f768c15f00ce34ec283cdc07e88f8428be28f6ef64043c32911811a4114fa0f7a3cef3b1d0c0a6ce7d26e17ada0b2e5c92d19efad48b41859cb8a451ca96f7d62059e9497a1a4a267569d9876da60101aff38e3529b9b939ce7f91ae97040ec1f0379206461c83342285423326708ab031f0da4a253ee45aafa5b8c92034d8b605490f8cd13e00f989989b97e215faa36f12dee3693d2daccf3781c1757f66215c150929b74c1a04954b78b4b6035e97a5e078a5a0f28ec96d547bfee9ace803ac06f7d62059e9497a1a4a267569d9876da60101aff38e3529b9b939ce7f91ae970115f2e490af7cc45c4f78511f36057ce5c5a5c56325a29fb44dfc203f356e1f823202cb13ac68248de806aa6a3659cf3c03eb6821d09c8114a4e868febde865bb6d2acc04215c150929b74c1a04954b78b4b6035e97a5e078a5a0f28ec96d547bfee9ace803ac097c6e6fea5ff714ff5724499990810e406e98aa10f5bf7e5f6784bc1d0a9a6ce23204320b0bf16f011b53ea7be615924aa7f27e5d29ad20ea1155d848676c3bad1b2acc06215c150929b74c1a04954b78b4b6035e97a5e078a5a0f28ec96d547bfee9ace803ac0cd970e15f53fc0c82f950fd560ffa919b76172be017368a89913af074f400b09115f2e490af7cc45c4f78511f36057ce5c5a5c56325a29fb44dfc203f356e1f82320fa0f7a3cef3b1d0c0a6ce7d26e17ada0b2e5c92d19efad48b41859cb8a451ca9acc021162cb13ac68248de806aa6a3659cf3c03eb6821d09c8114a4e868febde865bb6d23901cd970e15f53fc0c82f950fd560ffa919b76172be017368a89913af074f400b09772b2da7560000800100008002000080000000000000000021164320b0bf16f011b53ea7be615924aa7f27e5d29ad20ea1155d848676c3bad1b23901115f2e490af7cc45c4f78511f36057ce5c5a5c56325a29fb44dfc203f356e1f8772b2da75600008001000080010000800000000000000000211650929b74c1a04954b78b4b6035e97a5e078a5a0f28ec96d547bfee9ace803ac005007c461e5d2116fa0f7a3cef3b1d0c0a6ce7d26e17ada0b2e5c92d19efad48b41859cb8a451ca939016f7d62059e9497a1a4a267569d9876da60101aff38e3529b9b939ce7f91ae970772b2da7560000800100008003000080000000000000000001172050929b74c1a04954b78b4b6035e97a5e078a5a0f28ec96d547bfee9ace803ac0011820f0362e2f75a6f420a5bde3eb221d96ae6720cf25f81890c95b1d775acb515e65000105201124da7aec92ccd06c954562647f437b138b95721a84be2bf2276bbddab3e67121071124da7aec92ccd06c954562647f437b138b95721a84be2bf2276bbddab3e6711900772b2da7560000800100008000000080000000000500000000").unwrap();
+        assert!(psbt.inputs[0].tap_merkle_root.is_some());
+        assert_eq!(psbt.inputs[0].tap_leaf_scripts.len(), 3);
+        assert_eq!(psbt.inputs[0].tap_script_sigs.len(), 3);
+
+        // a key path signature finalizes into a one element witness
+        let mut psbt = parse_hex("70736274ff010052020000000127744ababf3027fe0d6cf23a96eee2efb188ef52301954585883e69b6624b2420000000000ffffffff0148e6052a01000000160014768e1eeb4cf420866033f80aceff0f9720744969000000000001012b00f2052a010000002251205a2c2cf5b52cf31f83ad2e8da63ff03183ecd8f609c7510ae8a48e03910a0757011340bb53ec917bad9d906af1ba87181c48b86ace5aae2b53605a725ca74625631476fc6f5baedaf4f2ee0f477f36f58f3970d5b8273b7e497b97af2e3f125c97af342116fe349064c98d6e2a853fa3c9b12bd8b304a19c195c60efa7ee2393046d3fa2321900772b2da75600008001000080000000800100000000000000011720fe349064c98d6e2a853fa3c9b12bd8b304a19c195c60efa7ee2393046d3fa232002202036b772a6db74d8753c98a827958de6c78ab3312109f37d3e0304484242ece73d818772b2da7540000800100008000000080000000000000000000").unwrap();
+        let sig = psbt.inputs[0].tap_key_sig.clone().unwrap();
+        psbt.finalize().unwrap();
+        assert_eq!(psbt.inputs[0].final_script_witness, Some(vec![sig]));
+        assert!(psbt.inputs[0].tap_internal_key.is_none());
+        assert!(psbt.inputs[0].witness_utxo.is_some());
+        let tx = psbt.extract_tx().unwrap();
+        assert_eq!(tx.tx_ins[0].witness.len(), 1);
+    }
+
+    #[test]
+    fn test_roles() {
+        // the BIP143 P2SH-P2WPKH example
+        let tx = Tx::parse(&mut Cursor::new(hex::decode("0100000001db6b1b20aa0fd7b23880be2ecbd4a98130974cf4748fb66092ac4d3ceb1a54770100000000feffffff02b8b4eb0b000000001976a914a457b684d7f0d539a46a45bbc043f35b59d0d96388ac0008af2f000000001976a914fd270b1ee6abcaea97fea7ad0402e8bd8ad6d77c88ac92040000").unwrap())).unwrap();
+        let redeem_script = Script::from_bytes(
+            hex::decode("001479091972186c449eb1ded22b78e40d009bdf0089").unwrap(),
+        );
+        let script_hash = hash160(redeem_script.as_bytes()).try_into().unwrap();
+
+        let mut psbt = Psbt::from_unsigned_tx(tx).unwrap();
+        psbt.inputs[0].witness_utxo = Some(TxOut::new(1_000_000_000, Script::p2sh(&script_hash)));
+        assert_eq!(psbt.finalize(), Err(PsbtError::CannotFinalize(0)));
+        let mut updated = psbt.clone();
+        updated.inputs[0].redeem_script = Some(redeem_script);
+
+        let key = PrivateKey::new(
+            U256::from_str_radix(
+                "eb696a065ef48a2192da5b28b694f87544b30fae8327c4510137a922f32c6dcf",
+                16,
+            )
+            .unwrap(),
+        );
+        // without the redeem script the input cannot be signed
+        assert_eq!(psbt.sign(&key), Ok(0));
+        let mut signed = updated.clone();
+        assert_eq!(signed.sign(&key), Ok(1));
+        assert_eq!(
+            hex::encode(signed.inputs[0].partial_sigs.values().next().unwrap()),
+            "3044022047ac8e878352d3ebbde1c94ce3a10d057c24175747116f8288e5d794d12d482f0220217f36a485cae903c713331d877c1f64677e3622ad4010726870540656fe9dcb01"
+        );
+
+        psbt.combine(signed).unwrap();
+        assert_eq!(psbt.extract_tx(), Err(PsbtError::NotFinalized(0)));
+        psbt.finalize().unwrap();
+        assert!(psbt.inputs[0].partial_sigs.is_empty());
+        assert_eq!(
+            hex::encode(psbt.extract_tx().unwrap().serialize()),
+            "01000000000101db6b1b20aa0fd7b23880be2ecbd4a98130974cf4748fb66092ac4d3ceb1a5477010000001716001479091972186c449eb1ded22b78e40d009bdf0089feffffff02b8b4eb0b000000001976a914a457b684d7f0d539a46a45bbc043f35b59d0d96388ac0008af2f000000001976a914fd270b1ee6abcaea97fea7ad0402e8bd8ad6d77c88ac02473044022047ac8e878352d3ebbde1c94ce3a10d057c24175747116f8288e5d794d12d482f0220217f36a485cae903c713331d877c1f64677e3622ad4010726870540656fe9dcb012103ad1d8e89212f0b92c74d23bb710c00662ad1470198ac48c43f7d6f93a2a2687392040000"
+        );
+
+        let other = Psbt::from_unsigned_tx(Tx::new(2, vec![], vec![], 0)).unwrap();
+        assert_eq!(updated.combine(other), Err(PsbtError::DifferentTx));
+    }
+
+    #[test]
+    fn test_bip174_roles() {
+        let secret = |s| PrivateKey::new(U256::from_str_radix(s, 16).unwrap());
+        let pubkey = |key: &PrivateKey| key.get_point().compressed_sec().to_vec();
+        let source = |i| {
+            KeySource::new(
+                [0xd9, 0x0c, 0x6a, 0x4f],
+                vec![1 << 31, 1 << 31, (1 << 31) + i],
+            )
+        };
+
+        // Creator
+        let tx = Tx::parse(&mut Cursor::new(hex::decode("020000000258e87a21b56daf0c23be8e7070456c336f7cbaa5c8757924f545887bb2abdd750000000000ffffffff838d0427d0ec650a68aa46bb0b098aea4422c071b2ca78352a077959d07cea1d0100000000ffffffff0270aaf00800000000160014d85c2b71d0060b09c9886aeb815e50991dda124d00e1f5050000000016001400aea9a2e5f0f876a588df5546e8742d1d87008f00000000").unwrap())).unwrap();
+        let mut psbt = Psbt::from_unsigned_tx(tx).unwrap();
+        assert_eq!(
+            hex::encode(psbt.serialize()),
+            "70736274ff01009a020000000258e87a21b56daf0c23be8e7070456c336f7cbaa5c8757924f545887bb2abdd750000000000ffffffff838d0427d0ec650a68aa46bb0b098aea4422c071b2ca78352a077959d07cea1d0100000000ffffffff0270aaf00800000000160014d85c2b71d0060b09c9886aeb815e50991dda124d00e1f5050000000016001400aea9a2e5f0f876a588df5546e8742d1d87008f000000000000000000"
+        );
+
+        // Updater, with the keys m/0'/0'/0' to m/0'/0'/5' of
+        // tprv8ZgxMBicQKsPd9TeAdPADNnSyH9SSUUbTVeFszDE23Ki6TBB5nCefAdHkK8Fm3qMQR6sHwA56zqRmKmxnHk37JkiFzvncDqoKmPWubu7hDF
+        // Map entries serialize sorted by key, so their order can differ from
+        // the BIP's serialization but not their content.
+        let keys = [
+            "2c6ba77e9184c5b6c6215f84ef0e00558884dec7d23a027f0573d11bf77aff46",
+            "a4ed1609f90afbb52e37b44a0c548aed5c878bc0f029fc9a1131c4402e9234e0",
+            "68cfa8072f964148cb0dcedae42bbab417872739afef513314b29619ff3de9c4",
+            "11f4a287b28488c18351c5fa1136a5b30c2de63c30827b9460ff62bc246b366d",
+        ]
+        .map(secret);
+        let input = &mut psbt.inputs[0];
+        input.non_witness_utxo = Some(Tx::parse(&mut Cursor::new(hex::decode("0200000001aad73931018bd25f84ae400b68848be09db706eac2ac18298babee71ab656f8b0000000048473044022058f6fc7c6a33e1b31548d481c826c015bd30135aad42cd67790dab66d2ad243b02204a1ced2604c6735b6393e5b41691dd78b00f0c5942fb9f751856faa938157dba01feffffff0280f0fa020000000017a9140fb9463421696b82c833af241c78c17ddbde493487d0f20a270100000017a91429ca74f8a08f81999428185c97b5d852e4063f618765000000").unwrap())).unwrap());
+        input.redeem_script = Some(Script::from_bytes(hex::decode("5221029583bf39ae0a609747ad199addd634fa6108559d6c5cd39b4c2183f1ab96e07f2102dab61ff49a14db6a7d02b0cd1fbb78fc4b18312b5b4e54dae4dba2fbfef536d752ae").unwrap()));
+        let input = &mut psbt.inputs[1];
+        let witness_script = Script::from_bytes(hex::decode("522103089dc10c7ac6db54f91329af617333db388cead0c231f723379d1b99030b02dc21023add904f3d6dcf59ddb906b0dee23529b7ffb9ed50e5e86151926860221f0e7352ae").unwrap());
+        let mut redeem_script = vec![0x00, 0x20];
+        redeem_script.extend(sha256(witness_script.as_bytes()));
+        let script_hash = hash160(&redeem_script).try_into().unwrap();
+        input.witness_utxo = Some(TxOut::new(200_000_000, Script::p2sh(&script_hash)));
+        input.redeem_script = Some(Script::from_bytes(redeem_script));
+        input.witness_script = Some(witness_script);
+        for (i, key) in keys.iter().enumerate() {
+            psbt.inputs[i / 2]
+                .bip32_derivation
+                .insert(pubkey(key), source(i as u32));
+        }
+        for (i, output_key) in [
+            "03a9a4c37f5996d3aa25dbac6b570af0650394492942460b354753ed9eeca58771",
+            "027f6399757d2eff55a136ad02c684b1838b6556e5f1b6b34282a94b6b50051096",
+        ]
+        .iter()
+        .enumerate()
+        {
+            psbt.outputs[i]
+                .bip32_derivation
+                .insert(hex::decode(output_key).unwrap(), source(i as u32 + 4));
+        }
+        assert_eq!(
+            hex::encode(psbt.serialize()),
+            "70736274ff01009a020000000258e87a21b56daf0c23be8e7070456c336f7cbaa5c8757924f545887bb2abdd750000000000ffffffff838d0427d0ec650a68aa46bb0b098aea4422c071b2ca78352a077959d07cea1d0100000000ffffffff0270aaf00800000000160014d85c2b71d0060b09c9886aeb815e50991dda124d00e1f5050000000016001400aea9a2e5f0f876a588df5546e8742d1d87008f00000000000100bb0200000001aad73931018bd25f84ae400b68848be09db706eac2ac18298babee71ab656f8b0000000048473044022058f6fc7c6a33e1b31548d481c826c015bd30135aad42cd67790dab66d2ad243b02204a1ced2604c6735b6393e5b41691dd78b00f0c5942fb9f751856faa938157dba01feffffff0280f0fa020000000017a9140fb9463421696b82c833af241c78c17ddbde493487d0f20a270100000017a91429ca74f8a08f81999428185c97b5d852e4063f6187650000000104475221029583bf39ae0a609747ad199addd634fa6108559d6c5cd39b4c2183f1ab96e07f2102dab61ff49a14db6a7d02b0cd1fbb78fc4b18312b5b4e54dae4dba2fbfef536d752ae2206029583bf39ae0a609747ad199addd634fa6108559d6c5cd39b4c2183f1ab96e07f10d90c6a4f000000800000008000000080220602dab61ff49a14db6a7d02b0cd1fbb78fc4b18312b5b4e54dae4dba2fbfef536d710d90c6a4f0000008000000080010000800001012000c2eb0b0000000017a914b7f5faf40e3d40a5a459b1db3535f2b72fa921e88701042200208c2353173743b595dfb4a07b72ba8e42e3797da74e87fe7d9d7497e3b2028903010547522103089dc10c7ac6db54f91329af617333db388cead0c231f723379d1b99030b02dc21023add904f3d6dcf59ddb906b0dee23529b7ffb9ed50e5e86151926860221f0e7352ae2206023add904f3d6dcf59ddb906b0dee23529b7ffb9ed50e5e86151926860221f0e7310d90c6a4f000000800000008003000080220603089dc10c7ac6db54f91329af617333db388cead0c231f723379d1b99030b02dc10d90c6a4f00000080000000800200008000220203a9a4c37f5996d3aa25dbac6b570af0650394492942460b354753ed9eeca5877110d90c6a4f000000800000008004000080002202027f6399757d2eff55a136ad02c684b1838b6556e5f1b6b34282a94b6b5005109610d90c6a4f00000080000000800500008000"
+        );
+        for input in &mut psbt.inputs {
+            input.sighash_type = Some(SIGHASH_ALL);
+        }
+
+        // Signers, with m/0'/0'/0' and 2', and with 1' and 3'
+        let sigs = [
+            "3044022074018ad4180097b873323c0015720b3684cc8123891048e7dbcd9b55ad679c99022073d369b740e3eb53dcefa33823c8070514ca55a7dd9544f157c167913261118c01",
+            "3045022100f61038b308dc1da865a34852746f015772934208c6d24454393cd99bdf2217770220056e675a675a6d0a02b85b14e5e29074d8a25a9b5760bea2816f661910a006ea01",
+            "3044022062eb7a556107a7c73f45ac4ab5a1dddf6f7075fb1275969a7f383efff784bcb202200c05dbb7470dbf2f08557dd356c7325c1ed30913e996cd3840945db12228da5f01",
+            "3044022065f45ba5998b59a27ffe1a7bed016af1f1f90d54b3aa8f7450aa5f56a25103bd02207f724703ad1edb96680b284b56d4ffcb88f7fb759eabbe08aa30f29b851383d201",
+        ];
+        let mut signers = [psbt.clone(), psbt.clone()];
+        for (i, key) in keys.iter().enumerate() {
+            assert_eq!(signers[i % 2].sign(key), Ok(1));
+            let sig = &signers[i % 2].inputs[i / 2].partial_sigs[&pubkey(key)];
+            if i == 1 {
+                // the vectors predate low R grinding, which changes this one
+                assert_ne!(hex::encode(sig), sigs[i]);
+                assert!(sig.len() <= 71);
+                signers[1].inputs[0]
+                    .partial_sigs
+                    .insert(pubkey(key), hex::decode(sigs[i]).unwrap());
+            } else {
+                assert_eq!(hex::encode(sig), sigs[i]);
+            }
+        }
+
+        // Combiner, Finalizer and Extractor
+        let [mut psbt, other] = signers;
+        psbt.combine(other).unwrap();
+        assert_eq!(
+            hex::encode(psbt.serialize()),
+            "70736274ff01009a020000000258e87a21b56daf0c23be8e7070456c336f7cbaa5c8757924f545887bb2abdd750000000000ffffffff838d0427d0ec650a68aa46bb0b098aea4422c071b2ca78352a077959d07cea1d0100000000ffffffff0270aaf00800000000160014d85c2b71d0060b09c9886aeb815e50991dda124d00e1f5050000000016001400aea9a2e5f0f876a588df5546e8742d1d87008f00000000000100bb0200000001aad73931018bd25f84ae400b68848be09db706eac2ac18298babee71ab656f8b0000000048473044022058f6fc7c6a33e1b31548d481c826c015bd30135aad42cd67790dab66d2ad243b02204a1ced2604c6735b6393e5b41691dd78b00f0c5942fb9f751856faa938157dba01feffffff0280f0fa020000000017a9140fb9463421696b82c833af241c78c17ddbde493487d0f20a270100000017a91429ca74f8a08f81999428185c97b5d852e4063f6187650000002202029583bf39ae0a609747ad199addd634fa6108559d6c5cd39b4c2183f1ab96e07f473044022074018ad4180097b873323c0015720b3684cc8123891048e7dbcd9b55ad679c99022073d369b740e3eb53dcefa33823c8070514ca55a7dd9544f157c167913261118c01220202dab61ff49a14db6a7d02b0cd1fbb78fc4b18312b5b4e54dae4dba2fbfef536d7483045022100f61038b308dc1da865a34852746f015772934208c6d24454393cd99bdf2217770220056e675a675a6d0a02b85b14e5e29074d8a25a9b5760bea2816f661910a006ea01010304010000000104475221029583bf39ae0a609747ad199addd634fa6108559d6c5cd39b4c2183f1ab96e07f2102dab61ff49a14db6a7d02b0cd1fbb78fc4b18312b5b4e54dae4dba2fbfef536d752ae2206029583bf39ae0a609747ad199addd634fa6108559d6c5cd39b4c2183f1ab96e07f10d90c6a4f000000800000008000000080220602dab61ff49a14db6a7d02b0cd1fbb78fc4b18312b5b4e54dae4dba2fbfef536d710d90c6a4f0000008000000080010000800001012000c2eb0b0000000017a914b7f5faf40e3d40a5a459b1db3535f2b72fa921e8872202023add904f3d6dcf59ddb906b0dee23529b7ffb9ed50e5e86151926860221f0e73473044022065f45ba5998b59a27ffe1a7bed016af1f1f90d54b3aa8f7450aa5f56a25103bd02207f724703ad1edb96680b284b56d4ffcb88f7fb759eabbe08aa30f29b851383d201220203089dc10c7ac6db54f91329af617333db388cead0c231f723379d1b99030b02dc473044022062eb7a556107a7c73f45ac4ab5a1dddf6f7075fb1275969a7f383efff784bcb202200c05dbb7470dbf2f08557dd356c7325c1ed30913e996cd3840945db12228da5f010103040100000001042200208c2353173743b595dfb4a07b72ba8e42e3797da74e87fe7d9d7497e3b2028903010547522103089dc10c7ac6db54f91329af617333db388cead0c231f723379d1b99030b02dc21023add904f3d6dcf59ddb906b0dee23529b7ffb9ed50e5e86151926860221f0e7352ae2206023add904f3d6dcf59ddb906b0dee23529b7ffb9ed50e5e86151926860221f0e7310d90c6a4f000000800000008003000080220603089dc10c7ac6db54f91329af617333db388cead0c231f723379d1b99030b02dc10d90c6a4f00000080000000800200008000220203a9a4c37f5996d3aa25dbac6b570af0650394492942460b354753ed9eeca5877110d90c6a4f000000800000008004000080002202027f6399757d2eff55a136ad02c684b1838b6556e5f1b6b34282a94b6b5005109610d90c6a4f00000080000000800500008000"
+        );
+        psbt.finalize().unwrap();
+        assert_eq!(
+            hex::encode(psbt.serialize()),
+            "70736274ff01009a020000000258e87a21b56daf0c23be8e7070456c336f7cbaa5c8757924f545887bb2abdd750000000000ffffffff838d0427d0ec650a68aa46bb0b098aea4422c071b2ca78352a077959d07cea1d0100000000ffffffff0270aaf00800000000160014d85c2b71d0060b09c9886aeb815e50991dda124d00e1f5050000000016001400aea9a2e5f0f876a588df5546e8742d1d87008f00000000000100bb0200000001aad73931018bd25f84ae400b68848be09db706eac2ac18298babee71ab656f8b0000000048473044022058f6fc7c6a33e1b31548d481c826c015bd30135aad42cd67790dab66d2ad243b02204a1ced2604c6735b6393e5b41691dd78b00f0c5942fb9f751856faa938157dba01feffffff0280f0fa020000000017a9140fb9463421696b82c833af241c78c17ddbde493487d0f20a270100000017a91429ca74f8a08f81999428185c97b5d852e4063f6187650000000107da00473044022074018ad4180097b873323c0015720b3684cc8123891048e7dbcd9b55ad679c99022073d369b740e3eb53dcefa33823c8070514ca55a7dd9544f157c167913261118c01483045022100f61038b308dc1da865a34852746f015772934208c6d24454393cd99bdf2217770220056e675a675a6d0a02b85b14e5e29074d8a25a9b5760bea2816f661910a006ea01475221029583bf39ae0a609747ad199addd634fa6108559d6c5cd39b4c2183f1ab96e07f2102dab61ff49a14db6a7d02b0cd1fbb78fc4b18312b5b4e54dae4dba2fbfef536d752ae0001012000c2eb0b0000000017a914b7f5faf40e3d40a5a459b1db3535f2b72fa921e8870107232200208c2353173743b595dfb4a07b72ba8e42e3797da74e87fe7d9d7497e3b20289030108da0400473044022062eb7a556107a7c73f45ac4ab5a1dddf6f7075fb1275969a7f383efff784bcb202200c05dbb7470dbf2f08557dd356c7325c1ed30913e996cd3840945db12228da5f01473044022065f45ba5998b59a27ffe1a7bed016af1f1f90d54b3aa8f7450aa5f56a25103bd02207f724703ad1edb96680b284b56d4ffcb88f7fb759eabbe08aa30f29b851383d20147522103089dc10c7ac6db54f91329af617333db388cead0c231f723379d1b99030b02dc21023add904f3d6dcf59ddb906b0dee23529b7ffb9ed50e5e86151926860221f0e7352ae00220203a9a4c37f5996d3aa25dbac6b570af0650394492942460b354753ed9eeca5877110d90c6a4f000000800000008004000080002202027f6399757d2eff55a136ad02c684b1838b6556e5f1b6b34282a94b6b5005109610d90c6a4f00000080000000800500008000"
+        );
+        assert_eq!(
+            hex::encode(psbt.extract_tx().unwrap().serialize()),
+            "0200000000010258e87a21b56daf0c23be8e7070456c336f7cbaa5c8757924f545887bb2abdd7500000000da00473044022074018ad4180097b873323c0015720b3684cc8123891048e7dbcd9b55ad679c99022073d369b740e3eb53dcefa33823c8070514ca55a7dd9544f157c167913261118c01483045022100f61038b308dc1da865a34852746f015772934208c6d24454393cd99bdf2217770220056e675a675a6d0a02b85b14e5e29074d8a25a9b5760bea2816f661910a006ea01475221029583bf39ae0a609747ad199addd634fa6108559d6c5cd39b4c2183f1ab96e07f2102dab61ff49a14db6a7d02b0cd1fbb78fc4b18312b5b4e54dae4dba2fbfef536d752aeffffffff838d0427d0ec650a68aa46bb0b098aea4422c071b2ca78352a077959d07cea1d01000000232200208c2353173743b595dfb4a07b72ba8e42e3797da74e87fe7d9d7497e3b2028903ffffffff0270aaf00800000000160014d85c2b71d0060b09c9886aeb815e50991dda124d00e1f5050000000016001400aea9a2e5f0f876a588df5546e8742d1d87008f000400473044022062eb7a556107a7c73f45ac4ab5a1dddf6f7075fb1275969a7f383efff784bcb202200c05dbb7470dbf2f08557dd356c7325c1ed30913e996cd3840945db12228da5f01473044022065f45ba5998b59a27ffe1a7bed016af1f1f90d54b3aa8f7450aa5f56a25103bd02207f724703ad1edb96680b284b56d4ffcb88f7fb759eabbe08aa30f29b851383d20147522103089dc10c7ac6db54f91329af617333db388cead0c231f723379d1b99030b02dc21023add904f3d6dcf59ddb906b0dee23529b7ffb9ed50e5e86151926860221f0e7352ae00000000"
+        );
+    }
+
+    #[test]
+    fn test_version_2() {
+        let tx = parse_hex("70736274ff0100750200000001268171371edff285e937adeea4b37b78000c0566cbb3ad64641713ca42171bf60000000000feffffff02d3dff505000000001976a914d0c59903c5bac2868760e90fd521a4665aa7652088ac00e1f5050000000017a9143545e6e33b832c47050f24d3eeb93c9c03948bc787b32e1300000100fda5010100000000010289a3c71eab4d20e0371bbba4cc698fa295c9463afa2e397f8533ccb62f9567e50100000017160014be18d152a9b012039daf3da7de4f53349eecb985ffffffff86f8aa43a71dff1448893a530a7237ef6b4608bbb2dd2d0171e63aec6a4890b40100000017160014fe3e9ef1a745e974d902c4355943abcb34bd5353ffffffff0200c2eb0b000000001976a91485cff1097fd9e008bb34af709c62197b38978a4888ac72fef84e2c00000017a914339725ba21efd62ac753a9bcd067d6c7a6a39d05870247304402202712be22e0270f394f568311dc7ca9a68970b8025fdd3b240229f07f8a5f3a240220018b38d7dcd314e734c9276bd6fb40f673325bc4baa144c800d2f2f02db2765c012103d2e15674941bad4a996372cb87e1856d3652606d98562fe39c5e9e7e413f210502483045022100d12b852d85dcd961d2f5f4ab660654df6eedcc794c0c33ce5cc309ffb5fce58d022067338a8e0e1725c197fb1a88af59f51e44e4255b20167c8684031c05d1f2592a01210223b72beef0965d10be0778efecd61fcac6f79a4ea169393380734464f84f2ab30000000001030401000000000000").unwrap().tx.unwrap();
+        let mut psbt = Psbt::from_unsigned_tx_v2(tx.clone()).unwrap();
+        assert_eq!(psbt.unsigned_tx().unwrap(), tx);
+        let raw = psbt.serialize();
+        assert_eq!(Psbt::parse(&mut Cursor::new(raw.clone())).unwrap(), psbt);
+
+        // required locktimes override the fallback
+        psbt.inputs[0].required_height_locktime = Some(800_000);
+        assert_eq!(psbt.unsigned_tx().unwrap().locktime, 800_000);
+        psbt.inputs[0].required_time_locktime = Some(1_700_000_000);
+        assert_eq!(psbt.unsigned_tx().unwrap().locktime, 800_000);
+        psbt.inputs[0].required_height_locktime = None;
+        assert_eq!(psbt.unsigned_tx().unwrap().locktime, 1_700_000_000);
+
+        // version 2 fields are not allowed in version 0 and vice versa
+        let mut v0 = Psbt::from_unsigned_tx(tx).unwrap().serialize();
+        // before the separators of the empty input and output maps
+        let at = v0.len() - 3;
+        v0.splice(at..at, [1, PSBT_IN_SEQUENCE as u8, 4, 0, 0, 0, 0]);
+        assert_eq!(
+            Psbt::parse(&mut Cursor::new(v0)).unwrap_err(),
+            PsbtError::KeyNotAllowed(vec![PSBT_IN_SEQUENCE as u8])
+        );
+        let mut v2 = raw;
+        v2.splice(5..5, [1, PSBT_GLOBAL_UNSIGNED_TX as u8, 0]);
+        assert_eq!(
+            Psbt::parse(&mut Cursor::new(v2)).unwrap_err(),
+            PsbtError::KeyNotAllowed(vec![PSBT_GLOBAL_UNSIGNED_TX as u8])
+        );
+    }
+}
@@ -0,0 +1,169 @@
+use hmac::{
+    digest::{core_api::BlockSizeUser, Digest},
+    Mac, SimpleHmac,
+};
+use primitive_types::U256;
+
+/// The leftmost `qlen` bits of `b` as an integer (RFC6979 section 2.3.2).
+pub fn bits2int(b: &[u8], qlen: usize) -> U256 {
+    assert!(qlen <= 256);
+    if b.len() * 8 <= qlen {
+        return U256::from_big_endian(b);
+    }
+    let rlen = qlen.div_ceil(8);
+    U256::from_big_endian(&b[..rlen]) >> (rlen * 8 - qlen)
+}
+
+/// `x` as `rlen` big endian bytes (section 2.3.3).
+pub fn int2octets(x: U256, rlen: usize) -> Vec<u8> {
+    let mut bytes = [0u8; 32];
+    x.to_big_endian(&mut bytes);
+    bytes[32 - rlen..].to_vec()
+}
+
+/// The message hash reduced modulo `q`, as bytes (section 2.3.4).
+pub fn bits2octets(b: &[u8], q: U256) -> Vec<u8> {
+    let qlen = q.bits();
+    int2octets(bits2int(b, qlen) % q, qlen.div_ceil(8))
+}
+
+fn hmac<D: Digest + BlockSizeUser + Clone>(key: &[u8], data: &[&[u8]]) -> Vec<u8> {
+    let mut mac = <SimpleHmac<D> as Mac>::new_from_slice(key).unwrap();
+    for d in data {
+        mac.update(d);
+    }
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Deterministic nonce for the private key `x` and message hash `h1` in a
+/// group of order `q`, with `D` as the HMAC hash (RFC6979 section 3.2).
+///
+/// `extra_data` is appended to the key and message when seeding the HMAC
+/// (section 3.6). libsecp256k1 passes its 32 byte `ndata` this way.
+pub fn generate_k<D: Digest + BlockSizeUser + Clone>(
+    q: U256,
+    x: U256,
+    h1: &[u8],
+    extra_data: &[u8],
+) -> U256 {
+    let qlen = q.bits();
+    let x = int2octets(x, qlen.div_ceil(8));
+    let h1 = bits2octets(h1, q);
+    let hlen = <D as Digest>::output_size();
+    let mut v = vec![0x01; hlen];
+    let mut k = vec![0x00; hlen];
+    k = hmac::<D>(&k, &[&v, &[0x00], &x, &h1, extra_data]);
+    v = hmac::<D>(&k, &[&v]);
+    k = hmac::<D>(&k, &[&v, &[0x01], &x, &h1, extra_data]);
+    v = hmac::<D>(&k, &[&v]);
+    loop {
+        let mut t = Vec::new();
+        while t.len() * 8 < qlen {
+            v = hmac::<D>(&k, &[&v]);
+            t.extend(&v);
+        }
+        let candidate = bits2int(&t, qlen);
+        if !candidate.is_zero() && candidate < q {
+            return candidate;
+        }
+        k = hmac::<D>(&k, &[&v, &[0x00]]);
+        v = hmac::<D>(&k, &[&v]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::secp256k1::N;
+    use sha2::{Sha224, Sha256, Sha384, Sha512};
+
+    fn from_hex(s: &str) -> U256 {
+        U256::from_str_radix(s, 16).unwrap()
+    }
+
+    #[test]
+    fn test_p256_vectors() {
+        // The RFC's appendix has no secp256k1 vectors, but k only depends on
+        // the group order, so the P-256 ones (A.2.5) exercise the hash
+        // truncation and padding paths.
+        let q = from_hex("ffffffff00000000ffffffffffffffffbce6faada7179e84f3b9cac2fc632551");
+        let x = from_hex("c9afa9d845ba75166b5c215767b1d6934e50c3db36e89b127b8a622b120f6721");
+        assert_eq!(
+            generate_k::<Sha224>(q, x, &Sha224::digest(b"sample"), &[]),
+            from_hex("103f90ee9dc52e5e7fb5132b7033c63066d194321491862059967c715985d473")
+        );
+        assert_eq!(
+            generate_k::<Sha256>(q, x, &Sha256::digest(b"sample"), &[]),
+            from_hex("a6e3c57dd01abe90086538398355dd4c3b17aa873382b0f24d6129493d8aad60")
+        );
+        assert_eq!(
+            generate_k::<Sha384>(q, x, &Sha384::digest(b"sample"), &[]),
+            from_hex("09f634b188cefd98e7ec88b1aa9852d734d0bc272f7d2a47decc6ebeb375aad4")
+        );
+        assert_eq!(
+            generate_k::<Sha512>(q, x, &Sha512::digest(b"sample"), &[]),
+            from_hex("5fa81c63109badb88c1f367b47da606da28cad69aa22c4fe6ad7df73a7173aa5")
+        );
+        assert_eq!(
+            generate_k::<Sha256>(q, x, &Sha256::digest(b"test"), &[]),
+            from_hex("d16b6ae827f17175e040871a1c7ec3500192c4c92677336ec2537acaee0008e0")
+        );
+        assert_eq!(
+            generate_k::<Sha512>(q, x, &Sha512::digest(b"test"), &[]),
+            from_hex("6915d11632aca3c40d5d51c08daf9c555933819548784480e93499000d9f0b7f")
+        );
+    }
+
+    #[test]
+    fn test_secp256k1_vectors() {
+        // the vectors used by Trezor and python-ecdsa
+        let n = from_hex(N);
+        for (x, message, k) in [
+            (
+                U256::one(),
+                "Satoshi Nakamoto",
+                "8f8a276c19f4149656b280621e358cce24f5f52542772691ee69063b74f15d15",
+            ),
+            (
+                U256::one(),
+                "All those moments will be lost in time, like tears in rain. Time to die...",
+                "38aa22d72376b4dbc472e06c3ba403ee0a394da63fc58d88686c611aba98d6b3",
+            ),
+            (
+                n - 1,
+                "Satoshi Nakamoto",
+                "33a19b60e25fb6f4435af53a3d42d493644827367e6453928554f43e49aa6f90",
+            ),
+            (
+                from_hex("f8b8af8ce3c7cca5e300d33939540c10d45ce001b8f252bfbc57ba0342904181"),
+                "Alan Turing",
+                "525a82b70e67874398067543fd84c83d30c175fdc45fdeee082fe13b1d7cfdf1",
+            ),
+            (
+                from_hex("e91671c46231f833a6406ccbea0e3e392c76c167bac1cb013f6f1013980455c2"),
+                "There is a computer disease that anybody who works with computers knows \
+                 about. It's a very serious disease and it interferes completely with the \
+                 work. The trouble with computers is that you 'play' with them!",
+                "1f4b84c23a86a221d233f2521be018d9318639d5b8bbd6374a8a59232d16ad3d",
+            ),
+        ] {
+            let h1 = Sha256::digest(message.as_bytes());
+            assert_eq!(generate_k::<Sha256>(n, x, &h1, &[]), from_hex(k));
+        }
+    }
+
+    #[test]
+    fn test_bits2octets() {
+        let q = from_hex(N);
+        // hashes at or above the order are reduced
+        assert_eq!(bits2octets(&[0xff; 32], q), int2octets(U256::MAX - q, 32));
+        assert_eq!(bits2octets(&int2octets(q, 32), q), vec![0; 32]);
+        // longer hashes keep their leftmost bits
+        assert_eq!(bits2int(&[0x12; 64], 256), U256::from([0x12; 32]));
+        assert_eq!(bits2int(&[0xff; 32], 255), U256::MAX >> 1);
+        assert_eq!(
+            bits2int(&[0x12; 28], 256),
+            U256::from_big_endian(&[0x12; 28])
+        );
+    }
+}
@@ -0,0 +1,535 @@
+use crate::varint::{encode_varint, read_varint};
+use std::{
+    fmt,
+    io::{self, Cursor, Read},
+};
+
+pub const OP_0: u8 = 0x00;
+pub const OP_PUSHDATA1: u8 = 0x4c;
+pub const OP_PUSHDATA2: u8 = 0x4d;
+pub const OP_PUSHDATA4: u8 = 0x4e;
+pub const OP_1NEGATE: u8 = 0x4f;
+pub const OP_RESERVED: u8 = 0x50;
+pub const OP_1: u8 = 0x51;
+pub const OP_16: u8 = 0x60;
+pub const OP_NOP: u8 = 0x61;
+pub const OP_VER: u8 = 0x62;
+pub const OP_IF: u8 = 0x63;
+pub const OP_NOTIF: u8 = 0x64;
+pub const OP_VERIF: u8 = 0x65;
+pub const OP_VERNOTIF: u8 = 0x66;
+pub const OP_ELSE: u8 = 0x67;
+pub const OP_ENDIF: u8 = 0x68;
+pub const OP_VERIFY: u8 = 0x69;
+pub const OP_RETURN: u8 = 0x6a;
+pub const OP_TOALTSTACK: u8 = 0x6b;
+pub const OP_FROMALTSTACK: u8 = 0x6c;
+pub const OP_2DROP: u8 = 0x6d;
+pub const OP_2DUP: u8 = 0x6e;
+pub const OP_3DUP: u8 = 0x6f;
+pub const OP_2OVER: u8 = 0x70;
+pub const OP_2ROT: u8 = 0x71;
+pub const OP_2SWAP: u8 = 0x72;
+pub const OP_IFDUP: u8 = 0x73;
+pub const OP_DEPTH: u8 = 0x74;
+pub const OP_DROP: u8 = 0x75;
+pub const OP_DUP: u8 = 0x76;
+pub const OP_NIP: u8 = 0x77;
+pub const OP_OVER: u8 = 0x78;
+pub const OP_PICK: u8 = 0x79;
+pub const OP_ROLL: u8 = 0x7a;
+pub const OP_ROT: u8 = 0x7b;
+pub const OP_SWAP: u8 = 0x7c;
+pub const OP_TUCK: u8 = 0x7d;
+pub const OP_CAT: u8 = 0x7e;
+pub const OP_SUBSTR: u8 = 0x7f;
+pub const OP_LEFT: u8 = 0x80;
+pub const OP_RIGHT: u8 = 0x81;
+pub const OP_SIZE: u8 = 0x82;
+pub const OP_INVERT: u8 = 0x83;
+pub const OP_AND: u8 = 0x84;
+pub const OP_OR: u8 = 0x85;
+pub const OP_XOR: u8 = 0x86;
+pub const OP_EQUAL: u8 = 0x87;
+pub const OP_EQUALVERIFY: u8 = 0x88;
+pub const OP_RESERVED1: u8 = 0x89;
+pub const OP_RESERVED2: u8 = 0x8a;
+pub const OP_1ADD: u8 = 0x8b;
+pub const OP_1SUB: u8 = 0x8c;
+pub const OP_2MUL: u8 = 0x8d;
+pub const OP_2DIV: u8 = 0x8e;
+pub const OP_NEGATE: u8 = 0x8f;
+pub const OP_ABS: u8 = 0x90;
+pub const OP_NOT: u8 = 0x91;
+pub const OP_0NOTEQUAL: u8 = 0x92;
+pub const OP_ADD: u8 = 0x93;
+pub const OP_SUB: u8 = 0x94;
+pub const OP_MUL: u8 = 0x95;
+pub const OP_DIV: u8 = 0x96;
+pub const OP_MOD: u8 = 0x97;
+pub const OP_LSHIFT: u8 = 0x98;
+pub const OP_RSHIFT: u8 = 0x99;
+pub const OP_BOOLAND: u8 = 0x9a;
+pub const OP_BOOLOR: u8 = 0x9b;
+pub const OP_NUMEQUAL: u8 = 0x9c;
+pub const OP_NUMEQUALVERIFY: u8 = 0x9d;
+pub const OP_NUMNOTEQUAL: u8 = 0x9e;
+pub const OP_LESSTHAN: u8 = 0x9f;
+pub const OP_GREATERTHAN: u8 = 0xa0;
+pub const OP_LESSTHANOREQUAL: u8 = 0xa1;
+pub const OP_GREATERTHANOREQUAL: u8 = 0xa2;
+pub const OP_MIN: u8 = 0xa3;
+pub const OP_MAX: u8 = 0xa4;
+pub const OP_WITHIN: u8 = 0xa5;
+pub const OP_RIPEMD160: u8 = 0xa6;
+pub const OP_SHA1: u8 = 0xa7;
+pub const OP_SHA256: u8 = 0xa8;
+pub const OP_HASH160: u8 = 0xa9;
+pub const OP_HASH256: u8 = 0xaa;
+pub const OP_CODESEPARATOR: u8 = 0xab;
+pub const OP_CHECKSIG: u8 = 0xac;
+pub const OP_CHECKSIGVERIFY: u8 = 0xad;
+pub const OP_CHECKMULTISIG: u8 = 0xae;
+pub const OP_CHECKMULTISIGVERIFY: u8 = 0xaf;
+pub const OP_NOP1: u8 = 0xb0;
+pub const OP_CHECKLOCKTIMEVERIFY: u8 = 0xb1;
+pub const OP_CHECKSEQUENCEVERIFY: u8 = 0xb2;
+pub const OP_NOP4: u8 = 0xb3;
+pub const OP_NOP10: u8 = 0xb9;
+/// Tapscript only (BIP342).
+pub const OP_CHECKSIGADD: u8 = 0xba;
+
+/// Signature operations counted for a CHECKMULTISIG whose key count is not
+/// known.
+pub const MAX_PUBKEYS_PER_MULTISIG: u32 = 20;
+/// Longest script that can be executed.
+pub const MAX_SCRIPT_SIZE: usize = 10_000;
+
+/// A single script element: either an opcode or pushed data.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Op(u8),
+    Data(Vec<u8>),
+}
+
+impl Command {
+    /// Pushes `n` the way Bitcoin Core's `CScript() << n` does: small
+    /// numbers as `OP_n`, others as minimally encoded data.
+    pub fn int(n: i64) -> Self {
+        match n {
+            0 => Command::Op(OP_0),
+            -1 => Command::Op(OP_1NEGATE),
+            1..=16 => Command::Op(OP_1 + n as u8 - 1),
+            _ => Command::Data(encode_num(n)),
+        }
+    }
+}
+
+/// Raw script bytes.
+///
+/// The bytes are kept as they are so that scripts which do not parse
+/// (e.g. in old coinbases) still round-trip through serialization.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct Script {
+    raw: Vec<u8>,
+}
+
+impl Script {
+    pub fn new(cmds: &[Command]) -> Self {
+        let mut raw = Vec::new();
+        for cmd in cmds {
+            match cmd {
+                Command::Op(op) => raw.push(*op),
+                Command::Data(data) => raw.extend(push_data(data)),
+            }
+        }
+        Self { raw }
+    }
+
+    pub fn from_bytes(raw: Vec<u8>) -> Self {
+        Self { raw }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.raw
+    }
+
+    pub fn len(&self) -> usize {
+        self.raw.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.raw.is_empty()
+    }
+
+    /// Parses a length-prefixed script.
+    pub fn parse<R: Read>(stream: &mut R) -> io::Result<Self> {
+        let length = read_varint(stream)?;
+        let mut raw = Vec::new();
+        stream.take(length).read_to_end(&mut raw)?;
+        if raw.len() as u64 != length {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(Self { raw })
+    }
+
+    /// Serializes the script with its length prefix.
+    pub fn serialize(&self) -> Vec<u8> {
+        [encode_varint(self.raw.len() as u64), self.raw.clone()].concat()
+    }
+
+    /// Splits the script into opcodes and pushed data.
+    pub fn commands(&self) -> io::Result<Vec<Command>> {
+        let mut stream = Cursor::new(&self.raw);
+        let mut cmds = Vec::new();
+        while let Some(cmd) = read_command(&mut stream)? {
+            cmds.push(cmd);
+        }
+        Ok(cmds)
+    }
+
+    /// Data pushed by the script up to the first truncated push, the way
+    /// Bitcoin Core's `GetOp` loops see it.
+    pub fn pushed_data(&self) -> Vec<Vec<u8>> {
+        let mut stream = Cursor::new(&self.raw);
+        let mut ret = Vec::new();
+        while let Ok(Some(cmd)) = read_command(&mut stream) {
+            if let Command::Data(data) = cmd {
+                ret.push(data);
+            }
+        }
+        ret
+    }
+
+    pub fn p2pkh(h160: &[u8; 20]) -> Self {
+        Self::new(&[
+            Command::Op(OP_DUP),
+            Command::Op(OP_HASH160),
+            Command::Data(h160.to_vec()),
+            Command::Op(OP_EQUALVERIFY),
+            Command::Op(OP_CHECKSIG),
+        ])
+    }
+
+    pub fn p2sh(h160: &[u8; 20]) -> Self {
+        Self::new(&[
+            Command::Op(OP_HASH160),
+            Command::Data(h160.to_vec()),
+            Command::Op(OP_EQUAL),
+        ])
+    }
+
+    /// Segwit output script: `OP_n <program>`.
+    pub fn witness_program(version: u8, program: &[u8]) -> Self {
+        let version_op = if version == 0 {
+            OP_0
+        } else {
+            OP_1 + version - 1
+        };
+        Self::new(&[Command::Op(version_op), Command::Data(program.to_vec())])
+    }
+
+    pub fn p2wpkh(h160: &[u8; 20]) -> Self {
+        Self::witness_program(0, h160)
+    }
+
+    pub fn p2wsh(h256: &[u8; 32]) -> Self {
+        Self::witness_program(0, h256)
+    }
+
+    pub fn p2tr(output_key: &[u8; 32]) -> Self {
+        Self::witness_program(1, output_key)
+    }
+
+    pub fn is_p2pkh(&self) -> bool {
+        let r = &self.raw;
+        r.len() == 25
+            && r[0] == OP_DUP
+            && r[1] == OP_HASH160
+            && r[2] == 20
+            && r[23] == OP_EQUALVERIFY
+            && r[24] == OP_CHECKSIG
+    }
+
+    pub fn is_p2sh(&self) -> bool {
+        let r = &self.raw;
+        r.len() == 23 && r[0] == OP_HASH160 && r[1] == 20 && r[22] == OP_EQUAL
+    }
+
+    /// Returns the version and program of a segwit output script (BIP141).
+    pub fn witness_version_and_program(&self) -> Option<(u8, &[u8])> {
+        let r = &self.raw;
+        if r.len() < 4 || r.len() > 42 || r[1] as usize + 2 != r.len() {
+            return None;
+        }
+        match r[0] {
+            OP_0 => Some((0, &r[2..])),
+            OP_1..=OP_16 => Some((r[0] - OP_1 + 1, &r[2..])),
+            _ => None,
+        }
+    }
+
+    pub fn is_p2wpkh(&self) -> bool {
+        matches!(self.witness_version_and_program(), Some((0, p)) if p.len() == 20)
+    }
+
+    pub fn is_p2wsh(&self) -> bool {
+        matches!(self.witness_version_and_program(), Some((0, p)) if p.len() == 32)
+    }
+
+    pub fn is_p2tr(&self) -> bool {
+        matches!(self.witness_version_and_program(), Some((1, p)) if p.len() == 32)
+    }
+
+    pub fn is_op_return(&self) -> bool {
+        self.raw.first() == Some(&OP_RETURN)
+    }
+
+    /// Whether the script only pushes data, as scriptSigs must for P2SH.
+    /// A truncated push is not push only.
+    pub fn is_push_only(&self) -> bool {
+        let mut stream = Cursor::new(&self.raw);
+        loop {
+            match read_command(&mut stream) {
+                Ok(None) => return true,
+                Ok(Some(Command::Op(op))) if op > OP_16 => return false,
+                Ok(Some(_)) => {}
+                Err(_) => return false,
+            }
+        }
+    }
+
+    /// Whether an output with this script can never be spent, so it is
+    /// left out of the UTXO set.
+    pub fn is_unspendable(&self) -> bool {
+        self.is_op_return() || self.raw.len() > MAX_SCRIPT_SIZE
+    }
+
+    /// Counts signature operations like Bitcoin Core's `GetSigOpCount`,
+    /// stopping at a truncated push.
+    ///
+    /// Unless `accurate`, every CHECKMULTISIG counts as 20. Otherwise one
+    /// preceded by `OP_n` counts as n.
+    pub fn sigop_count(&self, accurate: bool) -> u32 {
+        let mut count = 0;
+        let mut last_op = 0xff;
+        let mut i = 0;
+        while i < self.raw.len() {
+            let op = self.raw[i];
+            i += 1;
+            let length = match op {
+                1..=75 => op as usize,
+                OP_PUSHDATA1 | OP_PUSHDATA2 | OP_PUSHDATA4 => {
+                    let size = 1 << (op - OP_PUSHDATA1);
+                    let Some(bytes) = self.raw.get(i..i + size) else {
+                        break;
+                    };
+                    i += size;
+                    bytes
+                        .iter()
+                        .rev()
+                        .fold(0usize, |acc, b| acc << 8 | *b as usize)
+                }
+                _ => 0,
+            };
+            if i + length > self.raw.len() {
+                break;
+            }
+            i += length;
+            match op {
+                OP_CHECKSIG | OP_CHECKSIGVERIFY => count += 1,
+                OP_CHECKMULTISIG | OP_CHECKMULTISIGVERIFY => {
+                    count += if accurate && (OP_1..=OP_16).contains(&last_op) {
+                        (last_op - OP_1 + 1) as u32
+                    } else {
+                        MAX_PUBKEYS_PER_MULTISIG
+                    }
+                }
+                _ => {}
+            }
+            last_op = op;
+        }
+        count
+    }
+}
+
+fn read_command<R: Read>(stream: &mut R) -> io::Result<Option<Command>> {
+    let mut op = [0u8; 1];
+    if stream.read(&mut op)? == 0 {
+        return Ok(None);
+    }
+    let length = match op[0] {
+        1..=75 => op[0] as usize,
+        OP_PUSHDATA1 => {
+            let mut buf = [0u8; 1];
+            stream.read_exact(&mut buf)?;
+            buf[0] as usize
+        }
+        OP_PUSHDATA2 => {
+            let mut buf = [0u8; 2];
+            stream.read_exact(&mut buf)?;
+            u16::from_le_bytes(buf) as usize
+        }
+        OP_PUSHDATA4 => {
+            let mut buf = [0u8; 4];
+            stream.read_exact(&mut buf)?;
+            u32::from_le_bytes(buf) as usize
+        }
+        _ => return Ok(Some(Command::Op(op[0]))),
+    };
+    let mut data = Vec::new();
+    stream.take(length as u64).read_to_end(&mut data)?;
+    if data.len() != length {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(Some(Command::Data(data)))
+}
+
+/// Encodes a script number: little endian magnitude, with the sign in the
+/// top bit of the last byte. Zero is empty.
+pub fn encode_num(n: i64) -> Vec<u8> {
+    let mut ret = Vec::new();
+    let mut abs = n.unsigned_abs();
+    while abs > 0 {
+        ret.push((abs & 0xff) as u8);
+        abs >>= 8;
+    }
+    if let Some(last) = ret.last_mut() {
+        if *last & 0x80 != 0 {
+            ret.push(if n < 0 { 0x80 } else { 0x00 });
+        } else if n < 0 {
+            *last |= 0x80;
+        }
+    }
+    ret
+}
+
+/// Reads the opcode at `*pc` and the data it pushes, if any.
+pub(crate) fn read_op<'a>(raw: &'a [u8], pc: &mut usize) -> Option<(u8, Option<&'a [u8]>)> {
+    let op = *raw.get(*pc)?;
+    *pc += 1;
+    let length = match op {
+        1..=75 => op as usize,
+        OP_PUSHDATA1 | OP_PUSHDATA2 | OP_PUSHDATA4 => {
+            let size = 1 << (op - OP_PUSHDATA1);
+            let bytes = raw.get(*pc..*pc + size)?;
+            *pc += size;
+            bytes
+                .iter()
+                .rev()
+                .fold(0usize, |acc, b| acc << 8 | *b as usize)
+        }
+        OP_0 => 0,
+        _ => return Some((op, None)),
+    };
+    let data = raw.get(*pc..pc.checked_add(length)?)?;
+    *pc += length;
+    Some((op, Some(data)))
+}
+
+/// Encodes `data` as the smallest push opcode that fits it.
+pub fn push_data(data: &[u8]) -> Vec<u8> {
+    let length = data.len();
+    let mut ret = if length == 0 {
+        vec![OP_0]
+    } else if length <= 75 {
+        vec![length as u8]
+    } else if length <= 0xff {
+        vec![OP_PUSHDATA1, length as u8]
+    } else if length <= 0xffff {
+        [&[OP_PUSHDATA2], &(length as u16).to_le_bytes()[..]].concat()
+    } else {
+        [&[OP_PUSHDATA4], &(length as u32).to_le_bytes()[..]].concat()
+    };
+    ret.extend(data);
+    ret
+}
+
+impl fmt::Display for Script {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", hex::encode(&self.raw))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_serialize() {
+        let script_pubkey = hex::decode("6a47304402207899531a52d59a6de200179928ca900254a36b8dff8bb75f5f5d71b1cdc26125022008b422690b8461cb52c3cc30330b23d574351872b7c361e9aae3649071c1a7160121035d5c93d9ac96881f19ba1f686f15f009ded7c62efe85a872e6a19b43c15a2937").unwrap();
+        let script = Script::parse(&mut Cursor::new(&script_pubkey)).unwrap();
+        let cmds = script.commands().unwrap();
+        assert_eq!(cmds.len(), 2);
+        assert_eq!(
+            cmds[0],
+            Command::Data(hex::decode("304402207899531a52d59a6de200179928ca900254a36b8dff8bb75f5f5d71b1cdc26125022008b422690b8461cb52c3cc30330b23d574351872b7c361e9aae3649071c1a71601").unwrap())
+        );
+        assert_eq!(
+            cmds[1],
+            Command::Data(
+                hex::decode("035d5c93d9ac96881f19ba1f686f15f009ded7c62efe85a872e6a19b43c15a2937")
+                    .unwrap()
+            )
+        );
+        assert_eq!(Script::new(&cmds).serialize(), script_pubkey);
+
+        // a truncated push fails to parse but keeps the pushes before it
+        let truncated =
+            Script::from_bytes(vec![0x01, 0xaa, OP_DUP, 0x02, 0xbb, 0xcc, OP_PUSHDATA1]);
+        assert!(truncated.commands().is_err());
+        assert_eq!(truncated.pushed_data(), [vec![0xaa], vec![0xbb, 0xcc]]);
+    }
+
+    #[test]
+    fn test_standard_scripts() {
+        let h160 = [0x11; 20];
+        assert!(Script::p2pkh(&h160).is_p2pkh());
+        assert!(Script::p2sh(&h160).is_p2sh());
+        assert!(Script::p2wpkh(&h160).is_p2wpkh());
+        assert!(Script::p2wsh(&[0x22; 32]).is_p2wsh());
+        assert!(Script::p2tr(&[0x33; 32]).is_p2tr());
+        assert!(!Script::p2wsh(&[0x22; 32]).is_p2tr());
+        assert_eq!(
+            Script::p2tr(&[0x33; 32]).witness_version_and_program(),
+            Some((1, &[0x33; 32][..]))
+        );
+    }
+
+    #[test]
+    fn test_encode_num() {
+        for (n, expected) in [
+            (0, ""),
+            (1, "01"),
+            (-1, "81"),
+            (127, "7f"),
+            (128, "8000"),
+            (-128, "8080"),
+            (255, "ff00"),
+            (256, "0001"),
+            (-32768, "008080"),
+            (500_000, "20a107"),
+        ] {
+            assert_eq!(hex::encode(encode_num(n)), expected);
+        }
+        assert_eq!(Command::int(16), Command::Op(OP_16));
+        assert_eq!(Command::int(17), Command::Data(vec![17]));
+    }
+
+    #[test]
+    fn test_sigop_count() {
+        assert_eq!(Script::p2pkh(&[0; 20]).sigop_count(false), 1);
+        let multisig = Script::new(&[
+            Command::int(2),
+            Command::Data(vec![2; 33]),
+            Command::Data(vec![3; 33]),
+            Command::int(2),
+            Command::Op(OP_CHECKMULTISIG),
+        ]);
+        assert_eq!(multisig.sigop_count(false), 20);
+        assert_eq!(multisig.sigop_count(true), 2);
+        // counting stops at a truncated push
+        let truncated = Script::from_bytes(vec![OP_CHECKSIG, OP_PUSHDATA1, 5, OP_CHECKSIG]);
+        assert_eq!(truncated.sigop_count(false), 1);
+    }
+}
@@ -174,38 +174,41 @@ impl S256Point {
     }
 
     pub fn verify(&self, z: U256, sig: Signature) -> bool {
+        let n = U256::from_str_radix(N, 16).unwrap();
+        if sig.get_r().is_zero() || sig.get_r() >= n || sig.get_s().is_zero() || sig.get_s() >= n {
+            return false;
+        }
         let tmp = FieldElement::new(sig.get_s(), U256::from_str_radix(N, 16).unwrap());
         let s_inv = tmp.get_inverse().get_num();
         let u = U256::try_from(z.full_mul(s_inv) % N).unwrap();
         let v = U256::try_from(sig.get_r().full_mul(s_inv) % N).unwrap();
         let total = u * Self::get_the_generic_point() + v * *self;
-        total.point.get_coordinate().unwrap().get_x().get_num() == sig.get_r()
+        total
+            .point
+            .get_coordinate()
+            .is_some_and(|c| c.get_x().get_num() == sig.get_r())
     }
 
     /// * 非圧縮方式SEC
     ///
-    ///     ナイーブにPointのx座標・y座標をbig endianで16進数に変換してつなげる
-    fn sec(&self) -> [u8; 65] {
+    ///   ナイーブにPointのx座標・y座標をbig endianで16進数に変換してつなげる
+    pub fn sec(&self) -> [u8; 65] {
         let mut ret: [u8; 65] = [b'\x00'; 65];
         ret[0] = b'\x04';
         let mut x_bytes: [u8; 32] = Default::default();
         self.get_x().get_num().to_big_endian(&mut x_bytes);
         let mut y_bytes: [u8; 32] = Default::default();
         self.get_y().get_num().to_big_endian(&mut y_bytes);
-        for i in 0..32 {
-            ret[i + 1] = x_bytes[i];
-        }
-        for i in 0..32 {
-            ret[i + 33] = y_bytes[i];
-        }
+        ret[1..33].copy_from_slice(&x_bytes);
+        ret[33..65].copy_from_slice(&y_bytes);
         ret
     }
 
     /// * 圧縮方式SEC
     ///
-    ///     yの偶奇とxを返す。
-    ///     xに対応する二つのyの偶奇は異なるので、yの偶奇とxからyが復元できる。
-    fn compressed_sec(&self) -> [u8; 33] {
+    ///   yの偶奇とxを返す。
+    ///   xに対応する二つのyの偶奇は異なるので、yの偶奇とxからyが復元できる。
+    pub fn compressed_sec(&self) -> [u8; 33] {
         let mut ret: [u8; 33] = [b'\x00'; 33];
         if self.get_y().get_num().bit(0) {
             ret[0] = b'\x03';
@@ -214,9 +217,7 @@ impl S256Point {
         }
         let mut x_bytes: [u8; 32] = Default::default();
         self.get_x().get_num().to_big_endian(&mut x_bytes);
-        for i in 0..32 {
-            ret[i + 1] = x_bytes[i];
-        }
+        ret[1..33].copy_from_slice(&x_bytes);
         ret
     }
 
@@ -241,28 +242,50 @@ impl S256Point {
             odd_beta = U256::from_str_radix(P, 16).unwrap() - beta;
         }
         if is_even {
-            return S256Point::new(Some(x.get_num()), Some(even_beta));
+            S256Point::new(Some(x.get_num()), Some(even_beta))
         } else {
-            return S256Point::new(Some(x.get_num()), Some(odd_beta));
+            S256Point::new(Some(x.get_num()), Some(odd_beta))
+        }
+    }
+
+    /// Like `parse`, but returns `None` instead of panicking when `sec_bin`
+    /// is not a valid SEC encoding of a point on the curve.
+    pub fn try_parse(sec_bin: &[u8]) -> Option<Self> {
+        let p = U256::from_str_radix(P, 16).unwrap();
+        let x = U256::from_big_endian(sec_bin.get(1..33)?);
+        if x >= p {
+            return None;
+        }
+        let x3_plus_b = S256Field::new(x).pow(U256::from(3)) + S256Field::new(U256::from(B));
+        match (sec_bin[0], sec_bin.len()) {
+            (4, 65) => {
+                let y = U256::from_big_endian(&sec_bin[33..]);
+                if y >= p || S256Field::new(y).pow(U256::from(2)).get_num() != x3_plus_b.get_num() {
+                    return None;
+                }
+            }
+            (2 | 3, 33) => {
+                let y = S256Field::new(x3_plus_b.sqrt());
+                if y.pow(U256::from(2)).get_num() != x3_plus_b.get_num() {
+                    return None;
+                }
+            }
+            _ => return None,
         }
+        Some(Self::parse(sec_bin))
     }
 
     pub fn hash160(&self, compressed: bool) -> Vec<u8> {
         if compressed {
-            return hash160(&self.compressed_sec());
+            hash160(&self.compressed_sec())
         } else {
-            return hash160(&self.sec());
+            hash160(&self.sec())
         }
     }
 
     pub fn address(&self, compressed: bool, testnet: bool) -> String {
         let h160 = self.hash160(compressed);
-        let prefix;
-        if testnet {
-            prefix = b'\x6f';
-        } else {
-            prefix = b'\x00';
-        }
+        let prefix = if testnet { b'\x6f' } else { b'\x00' };
         encode_base58_checksum(&[[prefix].to_vec(), h160].concat())
     }
 }
@@ -306,7 +329,7 @@ impl MulAssign<U256> for S256Point {
 
 impl fmt::Display for S256Point {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        if self.point.get_coordinate() == None {
+        if self.point.get_coordinate().is_none() {
             write!(f, "Identity")
         } else {
             write!(
@@ -387,7 +410,7 @@ mod tests {
             );
             let result = tmp.sec();
             let mut expected: [u8; 65] = [0; 65];
-            hex::decode_to_slice(&expected_str, &mut expected).unwrap();
+            hex::decode_to_slice(expected_str, &mut expected).unwrap();
             assert_eq!(result, expected);
         }
 
@@ -395,17 +418,17 @@ mod tests {
         let expected_str =
             "04ffe558e388852f0120e46af2d1b370f85854a8eb0841811ece0e3e03d282d57c315dc72890a4\
 f10a1481c031b03b351b0dc79901ca18a00cf009dbdb157a1d10";
-        test(secret, &expected_str);
+        test(secret, expected_str);
         let secret = U256::from(2018).pow(U256::from(5));
         let expected_str =
             "04027f3da1918455e03c46f659266a1bb5204e959db7364d2f473bdf8f0a13cc9dff87647fd023\
 c13b4a4994f17691895806e1b40b57f4fd22581a4f46851f3b06";
-        test(secret, &expected_str);
+        test(secret, expected_str);
         let secret = U256::from_str_radix("0xdeadbeef12345", 16).unwrap();
         let expected_str =
             "04d90cd625ee87dd38656dd95cf79f65f60f7273b67d3096e68bd81e4f5342691f842efa762fd5\
 9961d0e99803c61edba8b3e3f7dc3a341836f97733aebf987121";
-        test(secret, &expected_str);
+        test(secret, expected_str);
     }
 
     #[test]
@@ -432,7 +455,7 @@ c13b4a4994f17691895806e1b40b57f4fd22581a4f46851f3b06";
         let ret = p.address(true, true);
         assert_eq!(ret, "mopVkxp8UhXqRYbCYJsbeE1h1fiF64jcoH");
 
-        let e = U256::from(0x12345deadbeef as i64);
+        let e = U256::from(0x12345deadbeef_i64);
         let g = S256Point::get_the_generic_point();
         let p = e * g;
         let ret = p.address(true, false);
@@ -0,0 +1,454 @@
+use crate::{
+    base58::hash256,
+    script::{read_op, Script, OP_CODESEPARATOR},
+    taproot::tagged_hash,
+    tx::{Tx, TxOut},
+    varint::encode_varint,
+};
+use sha2::{Digest, Sha256};
+
+/// Taproot only: signs like SIGHASH_ALL, with a 64 byte signature.
+pub const SIGHASH_DEFAULT: u32 = 0x00;
+pub const SIGHASH_ALL: u32 = 0x01;
+pub const SIGHASH_NONE: u32 = 0x02;
+pub const SIGHASH_SINGLE: u32 = 0x03;
+pub const SIGHASH_ANYONECANPAY: u32 = 0x80;
+
+/// The hash signed by an ECDSA signature in a pre-segwit input.
+///
+/// `script_code` is the script being executed: the output script, or the
+/// redeem script for P2SH. As in Bitcoin Core, an out of range SIGHASH_SINGLE
+/// signs the number one instead of failing.
+pub fn legacy_sighash(
+    tx: &Tx,
+    input_index: usize,
+    script_code: &Script,
+    sighash_type: u32,
+) -> [u8; 32] {
+    let mut one = [0u8; 32];
+    one[0] = 1;
+    let base_type = sighash_type & 0x1f;
+    if input_index >= tx.tx_ins.len()
+        || (base_type == SIGHASH_SINGLE && input_index >= tx.tx_outs.len())
+    {
+        return one;
+    }
+
+    let mut tx_copy = tx.clone();
+    for (i, tx_in) in tx_copy.tx_ins.iter_mut().enumerate() {
+        tx_in.witness.clear();
+        if i == input_index {
+            tx_in.script_sig = remove_codeseparators(script_code);
+        } else {
+            tx_in.script_sig = Script::default();
+            if base_type == SIGHASH_NONE || base_type == SIGHASH_SINGLE {
+                tx_in.sequence = 0;
+            }
+        }
+    }
+    if base_type == SIGHASH_NONE {
+        tx_copy.tx_outs.clear();
+    } else if base_type == SIGHASH_SINGLE {
+        tx_copy.tx_outs.truncate(input_index + 1);
+        for tx_out in &mut tx_copy.tx_outs[..input_index] {
+            *tx_out = TxOut::new(u64::MAX, Script::default());
+        }
+    }
+    if sighash_type & SIGHASH_ANYONECANPAY != 0 {
+        tx_copy.tx_ins = vec![tx_copy.tx_ins.swap_remove(input_index)];
+    }
+
+    let mut preimage = tx_copy.serialize_legacy();
+    preimage.extend(sighash_type.to_le_bytes());
+    hash256(&preimage).try_into().unwrap()
+}
+
+/// The hash signed by an ECDSA signature in a segwit v0 input (BIP143).
+///
+/// `amount` is the value of the output being spent.
+pub fn segwit_v0_sighash(
+    tx: &Tx,
+    input_index: usize,
+    script_code: &Script,
+    amount: u64,
+    sighash_type: u32,
+) -> [u8; 32] {
+    let base_type = sighash_type & 0x1f;
+    let anyone_can_pay = sighash_type & SIGHASH_ANYONECANPAY != 0;
+    let zero = vec![0u8; 32];
+
+    let hash_prevouts = if anyone_can_pay {
+        zero.clone()
+    } else {
+        let prevouts: Vec<u8> = tx
+            .tx_ins
+            .iter()
+            .flat_map(|tx_in| tx_in.prev_out.serialize())
+            .collect();
+        hash256(&prevouts)
+    };
+    let hash_sequence =
+        if anyone_can_pay || base_type == SIGHASH_SINGLE || base_type == SIGHASH_NONE {
+            zero.clone()
+        } else {
+            let sequences: Vec<u8> = tx
+                .tx_ins
+                .iter()
+                .flat_map(|tx_in| tx_in.sequence.to_le_bytes())
+                .collect();
+            hash256(&sequences)
+        };
+    let hash_outputs = if base_type != SIGHASH_SINGLE && base_type != SIGHASH_NONE {
+        let outputs: Vec<u8> = tx.tx_outs.iter().flat_map(|o| o.serialize()).collect();
+        hash256(&outputs)
+    } else if base_type == SIGHASH_SINGLE && input_index < tx.tx_outs.len() {
+        hash256(&tx.tx_outs[input_index].serialize())
+    } else {
+        zero
+    };
+
+    let tx_in = &tx.tx_ins[input_index];
+    let mut preimage = tx.version.to_le_bytes().to_vec();
+    preimage.extend(hash_prevouts);
+    preimage.extend(hash_sequence);
+    preimage.extend(tx_in.prev_out.serialize());
+    preimage.extend(script_code.serialize());
+    preimage.extend(amount.to_le_bytes());
+    preimage.extend(tx_in.sequence.to_le_bytes());
+    preimage.extend(hash_outputs);
+    preimage.extend(tx.locktime.to_le_bytes());
+    preimage.extend(sighash_type.to_le_bytes());
+    hash256(&preimage).try_into().unwrap()
+}
+
+/// The hash signed by a Schnorr signature in a taproot input (BIP341).
+///
+/// `spent_outputs` are the outputs spent by every input of `tx`. `annex` is
+/// the input's annex, if it has one, and `leaf` the tapleaf hash and the
+/// position of the last executed OP_CODESEPARATOR of a script path spend
+/// (BIP342). `None` for an invalid sighash type, or SIGHASH_SINGLE without
+/// a matching output.
+pub fn taproot_sighash(
+    tx: &Tx,
+    input_index: usize,
+    spent_outputs: &[TxOut],
+    sighash_type: u32,
+    annex: Option<&[u8]>,
+    leaf: Option<(&[u8; 32], u32)>,
+) -> Option<[u8; 32]> {
+    if !matches!(sighash_type, 0x00..=0x03 | 0x81..=0x83) {
+        return None;
+    }
+    let base_type = sighash_type & 0x03;
+    let anyone_can_pay = sighash_type & SIGHASH_ANYONECANPAY != 0;
+    let sha256 = |data: Vec<u8>| Sha256::digest(data).to_vec();
+
+    // the epoch, then the message of BIP341's SigMsg
+    let mut preimage = vec![0, sighash_type as u8];
+    preimage.extend(tx.version.to_le_bytes());
+    preimage.extend(tx.locktime.to_le_bytes());
+    if !anyone_can_pay {
+        preimage.extend(sha256(
+            tx.tx_ins
+                .iter()
+                .flat_map(|tx_in| tx_in.prev_out.serialize())
+                .collect(),
+        ));
+        preimage.extend(sha256(
+            spent_outputs
+                .iter()
+                .flat_map(|tx_out| tx_out.amount.to_le_bytes())
+                .collect(),
+        ));
+        preimage.extend(sha256(
+            spent_outputs
+                .iter()
+                .flat_map(|tx_out| tx_out.script_pubkey.serialize())
+                .collect(),
+        ));
+        preimage.extend(sha256(
+            tx.tx_ins
+                .iter()
+                .flat_map(|tx_in| tx_in.sequence.to_le_bytes())
+                .collect(),
+        ));
+    }
+    if base_type != SIGHASH_NONE && base_type != SIGHASH_SINGLE {
+        preimage.extend(sha256(
+            tx.tx_outs.iter().flat_map(|o| o.serialize()).collect(),
+        ));
+    }
+    let spend_type = (leaf.is_some() as u8) << 1 | annex.is_some() as u8;
+    preimage.push(spend_type);
+    if anyone_can_pay {
+        let tx_in = &tx.tx_ins[input_index];
+        preimage.extend(tx_in.prev_out.serialize());
+        preimage.extend(spent_outputs[input_index].serialize());
+        preimage.extend(tx_in.sequence.to_le_bytes());
+    } else {
+        preimage.extend((input_index as u32).to_le_bytes());
+    }
+    if let Some(annex) = annex {
+        preimage.extend(sha256(
+            [encode_varint(annex.len() as u64), annex.to_vec()].concat(),
+        ));
+    }
+    if base_type == SIGHASH_SINGLE {
+        preimage.extend(sha256(tx.tx_outs.get(input_index)?.serialize()));
+    }
+    if let Some((leaf_hash, codeseparator_pos)) = leaf {
+        preimage.extend(leaf_hash);
+        // key version 0, the only one BIP342 defines
+        preimage.push(0);
+        preimage.extend(codeseparator_pos.to_le_bytes());
+    }
+    Some(tagged_hash("TapSighash", &preimage))
+}
+
+/// The script code signed when spending a P2WPKH output (BIP143).
+pub fn p2wpkh_script_code(h160: &[u8; 20]) -> Script {
+    Script::p2pkh(h160)
+}
+
+/// The script code with every OP_CODESEPARATOR removed, as Bitcoin Core
+/// serializes it: all other bytes are copied as they are, so non-minimal
+/// pushes and trailing bytes that don't parse are signed unchanged.
+fn remove_codeseparators(script: &Script) -> Script {
+    let raw = script.as_bytes();
+    let mut ret = Vec::with_capacity(raw.len());
+    let (mut pc, mut copied) = (0, 0);
+    while let Some((op, _)) = read_op(raw, &mut pc) {
+        if op == OP_CODESEPARATOR {
+            ret.extend(&raw[copied..pc - 1]);
+            copied = pc;
+        }
+    }
+    ret.extend(&raw[copied..]);
+    Script::from_bytes(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{secp256k1::S256Point, signature::Signature};
+    use primitive_types::U256;
+    use std::io::Cursor;
+
+    fn parse_tx(s: &str) -> Tx {
+        Tx::parse(&mut Cursor::new(hex::decode(s).unwrap())).unwrap()
+    }
+
+    #[test]
+    fn test_remove_codeseparators() {
+        let cases: [(&str, &str); 5] = [
+            ("ab51ab", "51"),
+            // non-minimal pushes are kept as they are
+            ("4c0107ab76", "4c010776"),
+            ("4c00ab", "4c00"),
+            // 0xab inside pushed data is not an opcode
+            ("01abab", "01ab"),
+            // bytes after a push running past the end are copied unchanged
+            ("ab4c05abab", "4c05abab"),
+        ];
+        for (script, expected) in cases {
+            let script = Script::from_bytes(hex::decode(script).unwrap());
+            assert_eq!(
+                hex::encode(remove_codeseparators(&script).as_bytes()),
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn test_legacy_sighash() {
+        // the first input of 452c629d..., checked against its own signature
+        let tx = parse_tx("0100000001813f79011acb80925dfe69b3def355fe914bd1d96a3f5f71bf8303c6a989c7d1000000006b483045022100ed81ff192e75a3fd2304004dcadb746fa5e24c5031ccfcf21320b0277457c98f02207a986d955c6e0cb35d446a89d3f56100f4d7f67801c31967743a9c8e10615bed01210349fc4e631e3624a545de3f89f5d8684c7b8138bd94bdd531d2e213bf016b278afeffffff02a135ef01000000001976a914bc3b654dca7e56b04dca18f2566cdaf02e8d9ada88ac99c39800000000001976a9141c4bc762dd5423e332166702cb75f40df79fea1288ac19430600");
+        let script_pubkey = Script::from_bytes(
+            hex::decode("76a914a802fc56c704ce87c42d7c92eb75e7896bdc41ae88ac").unwrap(),
+        );
+        let sighash = legacy_sighash(&tx, 0, &script_pubkey, SIGHASH_ALL);
+        assert_eq!(
+            hex::encode(sighash),
+            "27e0c5994dec7824e56dec6b2fcb342eb7cdb0d0957c2fce9882f715e85d81a6"
+        );
+        let point = S256Point::parse(
+            &hex::decode("0349fc4e631e3624a545de3f89f5d8684c7b8138bd94bdd531d2e213bf016b278a")
+                .unwrap(),
+        );
+        let sig = Signature::new(
+            U256::from_str_radix(
+                "ed81ff192e75a3fd2304004dcadb746fa5e24c5031ccfcf21320b0277457c98f",
+                16,
+            )
+            .unwrap(),
+            U256::from_str_radix(
+                "7a986d955c6e0cb35d446a89d3f56100f4d7f67801c31967743a9c8e10615bed",
+                16,
+            )
+            .unwrap(),
+        );
+        assert!(point.verify(U256::from_big_endian(&sighash), sig));
+
+        // SIGHASH_SINGLE without a matching output signs one
+        let mut one = [0u8; 32];
+        one[0] = 1;
+        assert_eq!(legacy_sighash(&tx, 2, &script_pubkey, SIGHASH_SINGLE), one);
+    }
+
+    #[test]
+    fn test_segwit_v0_sighash() {
+        // BIP143 native P2WPKH example, second input
+        let tx = parse_tx("0100000002fff7f7881a8099afa6940d42d1e7f6362bec38171ea3edf433541db4e4ad969f0000000000eeffffffef51e1b804cc89d182d279655c3aa89e815b1b309fe287d9b2b55d57b90ec68a0100000000ffffffff02202cb206000000001976a9148280b37df378db99f66f85c95a783a76ac7a6d5988ac9093510d000000001976a9143bde42dbee7e4dbe6a21b2d50ce2f0167faa815988ac11000000");
+        let h160 = hex::decode("1d0f172a0ecb48aee1be1f2687d2963ae33f71a1").unwrap();
+        let script_code = p2wpkh_script_code(&h160.try_into().unwrap());
+        assert_eq!(
+            hex::encode(segwit_v0_sighash(
+                &tx,
+                1,
+                &script_code,
+                600_000_000,
+                SIGHASH_ALL
+            )),
+            "c37af31116d1b27caf68aae9e3ac82f1477929014d5b917657d0eb49478cb670"
+        );
+    }
+
+    #[test]
+    fn test_segwit_v0_sighash_types() {
+        // BIP143 P2SH-P2WSH 6-of-6 multisig example
+        let tx = parse_tx("010000000136641869ca081e70f394c6948e8af409e18b619df2ed74aa106c1ca29787b96e0100000000ffffffff0200e9a435000000001976a914389ffce9cd9ae88dcc0631e88a821ffdbe9bfe2688acc0832f05000000001976a9147480a33f950689af511e6e84c138dbbd3c3ee41588ac00000000");
+        let witness_script = Script::from_bytes(hex::decode("56210307b8ae49ac90a048e9b53357a2354b3334e9c8bee813ecb98e99a7e07e8c3ba32103b28f0c28bfab54554ae8c658ac5c3e0ce6e79ad336331f78c428dd43eea8449b21034b8113d703413d57761b8b9781957b8c0ac1dfe69f492580ca4195f50376ba4a21033400f6afecb833092a9a21cfdf1ed1376e58c5d1f47de74683123987e967a8f42103a6d48b1131e94ba04d9737d61acdaa1322008af9602b3b14862c07a1789aac162102d8b661b0b3302ee2f162b09e07a55ad5dfbe673a9f01d9f0c19617681024306b56ae").unwrap());
+        for (sighash_type, expected) in [
+            (
+                SIGHASH_ALL,
+                "185c0be5263dce5b4bb50a047973c1b6272bfbd0103a89444597dc40b248ee7c",
+            ),
+            (
+                SIGHASH_NONE,
+                "e9733bc60ea13c95c6527066bb975a2ff29a925e80aa14c213f686cbae5d2f36",
+            ),
+            (
+                SIGHASH_SINGLE,
+                "1e1f1c303dc025bd664acb72e583e933fae4cff9148bf78c157d1e8f78530aea",
+            ),
+            (
+                SIGHASH_ALL | SIGHASH_ANYONECANPAY,
+                "2a67f03e63a6a422125878b40b82da593be8d4efaafe88ee528af6e5a9955c6e",
+            ),
+            (
+                SIGHASH_NONE | SIGHASH_ANYONECANPAY,
+                "781ba15f3779d5542ce8ecb5c18716733a5ee42a6f51488ec96154934e2c890a",
+            ),
+            (
+                SIGHASH_SINGLE | SIGHASH_ANYONECANPAY,
+                "511e8e52ed574121fc1b654970395502128263f62662e076dc6baf05c2e6a99b",
+            ),
+        ] {
+            assert_eq!(
+                hex::encode(segwit_v0_sighash(
+                    &tx,
+                    0,
+                    &witness_script,
+                    987_654_321,
+                    sighash_type
+                )),
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn test_taproot_sighash() {
+        // BIP341 key path spending test vector
+        let tx = parse_tx("02000000097de20cbff686da83a54981d2b9bab3586f4ca7e48f57f5b55963115f3b334e9c010000000000000000d7b7cab57b1393ace2d064f4d4a2cb8af6def61273e127517d44759b6dafdd990000000000fffffffff8e1f583384333689228c5d28eac13366be082dc57441760d957275419a418420000000000fffffffff0689180aa63b30cb162a73c6d2a38b7eeda2a83ece74310fda0843ad604853b0100000000feffffffaa5202bdf6d8ccd2ee0f0202afbbb7461d9264a25e5bfd3c5a52ee1239e0ba6c0000000000feffffff956149bdc66faa968eb2be2d2faa29718acbfe3941215893a2a3446d32acd050000000000000000000e664b9773b88c09c32cb70a2a3e4da0ced63b7ba3b22f848531bbb1d5d5f4c94010000000000000000e9aa6b8e6c9de67619e6a3924ae25696bb7b694bb677a632a74ef7eadfd4eabf0000000000ffffffffa778eb6a263dc090464cd125c466b5a99667720b1c110468831d058aa1b82af10100000000ffffffff0200ca9a3b000000001976a91406afd46bcdfd22ef94ac122aa11f241244a37ecc88ac807840cb0000000020ac9a87f5594be208f8532db38cff670c450ed2fea8fcdefcc9a663f78bab962b0065cd1d");
+        let spent_outputs: Vec<TxOut> = [
+            (
+                420_000_000,
+                "512053a1f6e454df1aa2776a2814a721372d6258050de330b3c6d10ee8f4e0dda343",
+            ),
+            (
+                462_000_000,
+                "5120147c9c57132f6e7ecddba9800bb0c4449251c92a1e60371ee77557b6620f3ea3",
+            ),
+            (
+                294_000_000,
+                "76a914751e76e8199196d454941c45d1b3a323f1433bd688ac",
+            ),
+            (
+                504_000_000,
+                "5120e4d810fd50586274face62b8a807eb9719cef49c04177cc6b76a9a4251d5450e",
+            ),
+            (
+                630_000_000,
+                "512091b64d5324723a985170e4dc5a0f84c041804f2cd12660fa5dec09fc21783605",
+            ),
+            (378_000_000, "00147dd65592d0ab2fe0d0257d571abf032cd9db93dc"),
+            (
+                672_000_000,
+                "512075169f4001aa68f15bbed28b218df1d0a62cbbcf1188c6665110c293c907b831",
+            ),
+            (
+                546_000_000,
+                "5120712447206d7a5238acc7ff53fbe94a3b64539ad291c7cdbc490b7577e4b17df5",
+            ),
+            (
+                588_000_000,
+                "512077e30a5522dd9f894c3f8b8bd4c4b2cf82ca7da8a3ea6a239655c39c050ab220",
+            ),
+        ]
+        .into_iter()
+        .map(|(amount, script)| {
+            TxOut::new(amount, Script::from_bytes(hex::decode(script).unwrap()))
+        })
+        .collect();
+        for (input_index, sighash_type, expected) in [
+            (
+                0,
+                SIGHASH_SINGLE,
+                "2514a6272f85cfa0f45eb907fcb0d121b808ed37c6ea160a5a9046ed5526d555",
+            ),
+            (
+                1,
+                SIGHASH_SINGLE | SIGHASH_ANYONECANPAY,
+                "325a644af47e8a5a2591cda0ab0723978537318f10e6a63d4eed783b96a71a4d",
+            ),
+            (
+                3,
+                SIGHASH_ALL,
+                "bf013ea93474aa67815b1b6cc441d23b64fa310911d991e713cd34c7f5d46669",
+            ),
+            (
+                6,
+                SIGHASH_NONE,
+                "15f25c298eb5cdc7eb1d638dd2d45c97c4c59dcaec6679cfc16ad84f30876b85",
+            ),
+            (
+                7,
+                SIGHASH_NONE | SIGHASH_ANYONECANPAY,
+                "cd292de50313804dabe4685e83f923d2969577191a3e1d2882220dca88cbeb10",
+            ),
+            (
+                8,
+                SIGHASH_ALL | SIGHASH_ANYONECANPAY,
+                "cccb739eca6c13a8a89e6e5cd317ffe55669bbda23f2fd37b0f18755e008edd2",
+            ),
+        ] {
+            let sighash =
+                taproot_sighash(&tx, input_index, &spent_outputs, sighash_type, None, None);
+            assert_eq!(sighash.map(hex::encode).as_deref(), Some(expected));
+        }
+        // undefined types, and SIGHASH_SINGLE without a matching output
+        assert_eq!(
+            taproot_sighash(&tx, 0, &spent_outputs, 0x04, None, None),
+            None
+        );
+        assert_eq!(
+            taproot_sighash(&tx, 0, &spent_outputs, 0x80, None, None),
+            None
+        );
+        assert_eq!(
+            taproot_sighash(&tx, 2, &spent_outputs, SIGHASH_SINGLE, None, None),
+            None
+        );
+    }
+}
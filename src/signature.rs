@@ -1,13 +1,12 @@
 use crate::{
     base58::encode_base58_checksum,
     field_element::FieldElement,
+    rfc6979::generate_k,
     secp256k1::{S256Point, N},
 };
-use hmac::{Hmac, Mac};
 use primitive_types::U256;
 use sha2::Sha256;
 use std::{collections::VecDeque, fmt};
-type HmacSha256 = Hmac<Sha256>;
 pub struct Signature {
     r: U256,
     s: U256,
@@ -23,6 +22,12 @@ impl Signature {
     pub fn get_s(&self) -> U256 {
         self.s
     }
+
+    /// Whether `r` is below 2^255, so its DER encoding needs no padding byte.
+    pub fn has_low_r(&self) -> bool {
+        !self.r.bit(255)
+    }
+
     pub fn der(&self) -> Vec<u8> {
         let mut ret: VecDeque<u8> = Default::default();
         let mut r_bytes: [u8; 32] = Default::default();
@@ -37,12 +42,10 @@ impl Signature {
             if is_negative {
                 ret.push_back(b'\x00');
             }
-            for j in i..r_bytes.len() {
-                ret.push_back(r_bytes[j]);
-            }
-            ret.push_front((r_bytes.len() + is_negative as usize) as u8);
+            ret.extend(&r_bytes[i..]);
+            ret.push_front((r_bytes.len() - i + is_negative as usize) as u8);
         }
-        ret.push_front(2 as u8);
+        ret.push_front(2_u8);
 
         let mut ret2: VecDeque<u8> = Default::default();
 
@@ -58,17 +61,40 @@ impl Signature {
             if is_negative {
                 ret2.push_back(b'\x00');
             }
-            for j in i..s_bytes.len() {
-                ret2.push_back(s_bytes[j]);
-            }
-            ret2.push_front((s_bytes.len() + is_negative as usize) as u8);
+            ret2.extend(&s_bytes[i..]);
+            ret2.push_front((s_bytes.len() - i + is_negative as usize) as u8);
         }
-        ret2.push_front(2 as u8);
+        ret2.push_front(2_u8);
         ret.append(&mut ret2);
         ret.push_front(ret.len() as u8);
         ret.push_front(b'\x30');
         ret.into()
     }
+
+    /// Parses a DER signature. Integers may have extra leading zeros, as
+    /// some signatures from before BIP66 do, but must fit in 256 bits.
+    pub fn parse_der(der: &[u8]) -> Option<Self> {
+        fn read_int(der: &[u8]) -> Option<(U256, &[u8])> {
+            let (&[0x02, length], rest) = der.split_first_chunk()? else {
+                return None;
+            };
+            let (int, rest) = rest.split_at_checked(length as usize)?;
+            let start = int.iter().position(|b| *b != 0).unwrap_or(int.len());
+            if int.len() - start > 32 {
+                return None;
+            }
+            Some((U256::from_big_endian(&int[start..]), rest))
+        }
+        let (&[0x30, length], body) = der.split_first_chunk()? else {
+            return None;
+        };
+        if length as usize != body.len() {
+            return None;
+        }
+        let (r, rest) = read_int(body)?;
+        let (s, rest) = read_int(rest)?;
+        rest.is_empty().then_some(Self::new(r, s))
+    }
 }
 
 impl fmt::Display for Signature {
@@ -76,6 +102,7 @@ impl fmt::Display for Signature {
         write!(f, "Signature({},{})", self.r, self.s)
     }
 }
+#[derive(Clone)]
 pub struct PrivateKey {
     secret: U256,
     point: S256Point,
@@ -89,17 +116,55 @@ impl PrivateKey {
         }
     }
 
+    pub fn get_point(&self) -> S256Point {
+        self.point
+    }
+
+    pub fn get_secret(&self) -> U256 {
+        self.secret
+    }
+
     pub fn sign(&self, z: U256) -> Signature {
-        let k = self.deterministic_k(z);
+        self.sign_with_k(z, self.deterministic_k(z))
+    }
+
+    /// Signs like Bitcoin Core, retrying until `r` is low so the DER
+    /// signature is at most 70 bytes.
+    ///
+    /// The n-th retry passes `n` as 32 bytes of little endian extra data to
+    /// `deterministic_k_with_data`, as libsecp256k1's RFC6979 nonce function
+    /// does, so the result is deterministic and matches Core's.
+    pub fn sign_low_r(&self, z: U256) -> Signature {
+        let mut sig = self.sign(z);
+        let mut counter: u32 = 0;
+        while !sig.has_low_r() {
+            counter += 1;
+            let mut extra_data = [0u8; 32];
+            extra_data[..4].copy_from_slice(&counter.to_le_bytes());
+            sig = self.sign_with_k(z, self.deterministic_k_with_data(z, &extra_data));
+        }
+        sig
+    }
+
+    /// Signs with 32 bytes of additional entropy in the nonce, like
+    /// libsecp256k1's `ndata`. The signature is still valid for `z`.
+    pub fn sign_with_extra_entropy(&self, z: U256, extra_entropy: &[u8; 32]) -> Signature {
+        self.sign_with_k(z, self.deterministic_k_with_data(z, extra_entropy))
+    }
+
+    /// Signs with nonce `k`. Both `z` and the x coordinate of `kG` may be
+    /// at least n, so they are reduced mod n first.
+    fn sign_with_k(&self, z: U256, k: U256) -> Signature {
+        let n = U256::from_str_radix(N, 16).unwrap();
         let r = (k * S256Point::get_the_generic_point())
             .as_point()
             .get_coordinate()
             .unwrap()
             .get_x()
-            .get_num();
-        let n = U256::from_str_radix(N, 16).unwrap();
+            .get_num()
+            % n;
         let k_inv = FieldElement::new(k % n, n).get_inverse();
-        let s = (FieldElement::new(z, n) + r * FieldElement::new(self.secret, n)) * k_inv;
+        let s = (FieldElement::new(z % n, n) + r * FieldElement::new(self.secret, n)) * k_inv;
         let mut s = s.get_num();
         if s > n / U256::from(2) {
             s = n - s;
@@ -108,92 +173,23 @@ impl PrivateKey {
     }
 
     pub fn deterministic_k(&self, z: U256) -> U256 {
-        let mut k = [b'\x00'; 32];
-        let mut v = [b'\x01'; 32];
+        self.deterministic_k_with_data(z, &[])
+    }
+
+    /// RFC6979 nonce for `z` with HMAC-SHA256, mixing in `extra_data` as
+    /// described in `rfc6979::generate_k`.
+    pub fn deterministic_k_with_data(&self, z: U256, extra_data: &[u8]) -> U256 {
         let n = U256::from_str_radix(N, 16).unwrap();
-        let z = if z > n { z - n } else { z };
-        let mut z_bytes: [u8; 32] = Default::default();
-        let mut secret_bytes: [u8; 32] = Default::default();
+        let mut z_bytes = [0u8; 32];
         z.to_big_endian(&mut z_bytes);
-        self.secret.to_big_endian(&mut secret_bytes);
-
-        k = HmacSha256::new_from_slice(&k)
-            .unwrap()
-            .chain_update(&v)
-            .chain_update(&[b'\x00'])
-            .chain_update(&secret_bytes)
-            .chain_update(&z_bytes)
-            .finalize()
-            .into_bytes()
-            .try_into()
-            .unwrap();
-
-        v = HmacSha256::new_from_slice(&k)
-            .unwrap()
-            .chain_update(&v)
-            .finalize()
-            .into_bytes()
-            .try_into()
-            .unwrap();
-
-        k = HmacSha256::new_from_slice(&k)
-            .unwrap()
-            .chain_update(&v)
-            .chain_update(&[b'\x01'])
-            .chain_update(&secret_bytes)
-            .chain_update(&z_bytes)
-            .finalize()
-            .into_bytes()
-            .try_into()
-            .unwrap();
-
-        v = HmacSha256::new_from_slice(&k)
-            .unwrap()
-            .chain_update(&v)
-            .finalize()
-            .into_bytes()
-            .try_into()
-            .unwrap();
-        loop {
-            v = HmacSha256::new_from_slice(&k)
-                .unwrap()
-                .chain_update(&v)
-                .finalize()
-                .into_bytes()
-                .try_into()
-                .unwrap();
-            let candidate = U256::from(v.as_slice());
-            if candidate > U256::one() && candidate < n {
-                return candidate;
-            }
-            k = HmacSha256::new_from_slice(&k)
-                .unwrap()
-                .chain_update(&v)
-                .chain_update(&[b'\x00'])
-                .finalize()
-                .into_bytes()
-                .try_into()
-                .unwrap();
-            v = HmacSha256::new_from_slice(&k)
-                .unwrap()
-                .chain_update(&v)
-                .finalize()
-                .into_bytes()
-                .try_into()
-                .unwrap();
-        }
+        generate_k::<Sha256>(n, self.secret, &z_bytes, extra_data)
     }
 
     pub fn wif(&self, compressed: bool, testnet: bool) -> String {
         let mut secret_bytes: [u8; 32] = Default::default();
         self.secret.to_big_endian(&mut secret_bytes);
         let mut ret: Vec<u8> = Default::default();
-        let prefix;
-        if testnet {
-            prefix = b'\xef';
-        } else {
-            prefix = b'\x80';
-        }
+        let prefix = if testnet { b'\xef' } else { b'\x80' };
         ret.append(&mut [prefix].to_vec());
         ret.append(&mut secret_bytes.to_vec());
         let suffix;
@@ -207,9 +203,8 @@ impl PrivateKey {
 
 #[cfg(test)]
 mod tests {
-    use hex::ToHex;
-
     use super::*;
+    use sha2::Digest;
     #[test]
     fn test_sign() {
         let pk = PrivateKey::new(U256::one());
@@ -223,6 +218,119 @@ mod tests {
             .unwrap()
         );
         assert!(pk.point.verify(z, sig));
+
+        // rust-secp256k1's serde test vector
+        let pk = PrivateKey::new(U256::from([2u8; 32]));
+        let sig = pk.sign(U256::from([1u8; 32]));
+        assert_eq!(
+            hex::encode(sig.der()),
+            "30450221009d0bad576719d32ae76bedb34c774866673cbde3f4e12951555c9408e6ce77\
+4b02202876e7102f204f6bfee26c967c3926ce702cf97d4b010062e193f763190f6776"
+        );
+        assert!(!sig.has_low_r());
+    }
+
+    #[test]
+    fn test_sign_with_extra_entropy() {
+        // Trezor's "Satoshi Nakamoto" vector
+        let pk = PrivateKey::new(U256::one());
+        let z = U256::from(Sha256::digest(b"Satoshi Nakamoto").as_slice());
+        let sig = pk.sign(z);
+        assert_eq!(
+            sig.get_r(),
+            U256::from_str_radix(
+                "934b1ea10a4b3c1757e2b0c017d0b6143ce3c9a7e6a4a49860d7a6ab210ee3d8",
+                16
+            )
+            .unwrap()
+        );
+        assert_eq!(
+            sig.get_s(),
+            U256::from_str_radix(
+                "2442ce9d2b916064108014783e923ec36b49743e2ffa1c4496f01a512aafd9e5",
+                16
+            )
+            .unwrap()
+        );
+        // the nonce key is seeded with x || h1 || ndata, as libsecp256k1's
+        // nonce_function_rfc6979 does
+        let sig = pk.sign_with_extra_entropy(z, &[1; 32]);
+        assert_eq!(
+            sig.get_r(),
+            U256::from_str_radix(
+                "bb6cf569458d507451271380d2863dad30355387836d5c3287a4efbd5ed1ad8e",
+                16
+            )
+            .unwrap()
+        );
+        assert_eq!(
+            sig.get_s(),
+            U256::from_str_radix(
+                "4bb4b7899e803f760fe89027e55f5d93768983d6e28af4b5722f6226b345380e",
+                16
+            )
+            .unwrap()
+        );
+        assert!(pk.point.verify(z, sig));
+    }
+
+    #[test]
+    fn test_sign_reduces_z() {
+        // z >= n signs as z - n, in both the nonce and the signature
+        let pk = PrivateKey::new(U256::one());
+        let sig = pk.sign(U256::MAX);
+        assert_eq!(
+            sig.get_r(),
+            U256::from_str_radix(
+                "7cb38cc5712e9e11a767615f6080dbc111c9cdd613eb98999fd92a86bafd4540",
+                16
+            )
+            .unwrap()
+        );
+        assert_eq!(
+            sig.get_s(),
+            U256::from_str_radix(
+                "7923ca1f4d03471d2866f776ef8a6d3cac099b427331aeb245aa9dafeddcf115",
+                16
+            )
+            .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_sign_low_r() {
+        // rust-secp256k1's test_low_r, which needs four retries
+        let pk = PrivateKey::new(
+            U256::from_str_radix(
+                "57f0148f94d13095cfda539d0da0d1541304b678d8b36e243980aab4e1b7cead",
+                16,
+            )
+            .unwrap(),
+        );
+        let z = U256::from_str_radix(
+            "887d04bb1cf1b1554f1b268dfe62d13064ca67ae45348d50d1392ce2d13418ac",
+            16,
+        )
+        .unwrap();
+        assert!(!pk.sign(z).has_low_r());
+        let sig = pk.sign_low_r(z);
+        assert_eq!(
+            sig.get_r(),
+            U256::from_str_radix(
+                "047dd4d049db02b430d24c41c7925b2725bcd5a85393513bdec04b4dc363632b",
+                16
+            )
+            .unwrap()
+        );
+        assert_eq!(
+            sig.get_s(),
+            U256::from_str_radix(
+                "1054d0180094122b380f4cfa391e6296244da773173e78fc745c1b9c79f7b713",
+                16
+            )
+            .unwrap()
+        );
+        assert_eq!(sig.der().len(), 70);
     }
 
     #[test]
@@ -243,6 +351,23 @@ mod tests {
             "3045022037206a0610995c58074999cb9767b87af4c4978db68c06e8e6e81d282047a7c6022100\
 8ca63759c1157ebeaec0d03cecca119fc9a75bf8e6d0fa65c841c8e2738cdaec"
         );
+
+        // a 31 byte `s` gets a shorter length
+        let r = U256::from_str_radix(
+            "2ffc447100d518c8ba643d11f3e6a83a8640488e7d2537b1954b942408be6ea3",
+            16,
+        )
+        .unwrap();
+        let s = U256::from_str_radix(
+            "26e1248dd1e52160c3a38af9769d91a1a806cab5f9d508c103464d3c02d6e1",
+            16,
+        )
+        .unwrap();
+        assert_eq!(
+            hex::encode(Signature::new(r, s).der()),
+            "304302202ffc447100d518c8ba643d11f3e6a83a8640488e7d2537b1954b942408be6ea3021f\
+26e1248dd1e52160c3a38af9769d91a1a806cab5f9d508c103464d3c02d6e1"
+        );
     }
 
     #[test]
@@ -0,0 +1,451 @@
+use crate::{
+    block_header::BlockHeader,
+    header_chain::{Accepted, ChainParams, HeaderChain, HeaderError},
+    merkle::MerkleBlock,
+    message::{GetHeadersMessage, Inventory, Message, MAX_HEADERS_SIZE, MSG_FILTERED_BLOCK},
+    network::Network,
+    peer::{Peer, PeerConfig, PeerError, PROTOCOL_VERSION},
+    tx::Tx,
+};
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Cursor, Read, Write},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SpvError {
+    Io(io::ErrorKind),
+    Peer(PeerError),
+    /// A peer sent a header that does not validate.
+    Header(HeaderError),
+    /// A `merkleblock` whose proof does not match its header.
+    InvalidProof,
+    /// The peer answered `notfound`.
+    NotFound,
+}
+
+impl From<io::Error> for SpvError {
+    fn from(e: io::Error) -> Self {
+        SpvError::Io(e.kind())
+    }
+}
+
+impl From<PeerError> for SpvError {
+    fn from(e: PeerError) -> Self {
+        SpvError::Peer(e)
+    }
+}
+
+impl From<HeaderError> for SpvError {
+    fn from(e: HeaderError) -> Self {
+        SpvError::Header(e)
+    }
+}
+
+/// A header-only client: downloads headers from peers, validates them with
+/// `HeaderChain` and checks merkle proofs against the most-work chain.
+///
+/// Every accepted header is appended to a file of 80 byte headers, which is
+/// replayed on open, so side chains survive restarts along with the best
+/// chain.
+pub struct SpvClient {
+    chain: HeaderChain,
+    path: PathBuf,
+    file: File,
+}
+
+impl SpvClient {
+    /// Opens or creates the header file at `path`. A partly written last
+    /// header is dropped.
+    pub fn open<P: AsRef<Path>>(path: P, network: Network) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&path)?;
+        let mut raw = Vec::new();
+        file.read_to_end(&mut raw)?;
+        let complete = raw.len() - raw.len() % 80;
+        if complete != raw.len() {
+            file.set_len(complete as u64)?;
+        }
+        let mut chain = HeaderChain::new(ChainParams::new(network));
+        let mut stream = Cursor::new(&raw[..complete]);
+        while (stream.position() as usize) < complete {
+            let header = BlockHeader::parse(&mut stream)?;
+            chain.add_header(header).map_err(|_| {
+                io::Error::new(io::ErrorKind::InvalidData, "invalid header in header file")
+            })?;
+        }
+        Ok(Self { chain, path, file })
+    }
+
+    pub fn get_path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn get_chain(&self) -> &HeaderChain {
+        &self.chain
+    }
+
+    pub fn height(&self) -> u32 {
+        self.chain.height()
+    }
+
+    /// Validates and stores headers in order, returning how many were new.
+    /// Stops at the first invalid header, keeping those before it.
+    pub fn add_headers(&mut self, headers: &[BlockHeader]) -> Result<usize, SpvError> {
+        let mut raw = Vec::new();
+        let mut result = Ok(());
+        for header in headers {
+            match self.chain.add_header(*header) {
+                Ok(Accepted::AlreadyKnown) => {}
+                Ok(_) => raw.extend(header.serialize()),
+                Err(e) => {
+                    result = Err(e.into());
+                    break;
+                }
+            }
+        }
+        self.file.write_all(&raw)?;
+        self.file.sync_data()?;
+        result.map(|_| raw.len() / 80)
+    }
+
+    /// Downloads headers from `peer` until it has no more, returning how
+    /// many were new.
+    pub async fn sync(&mut self, peer: &mut Peer) -> Result<usize, SpvError> {
+        let mut added = 0;
+        loop {
+            let request = GetHeadersMessage::new(PROTOCOL_VERSION as u32, self.chain.locator());
+            peer.send(&Message::GetHeaders(request)).await?;
+            let headers = loop {
+                if let Message::Headers(headers) = peer.receive().await? {
+                    break headers;
+                }
+            };
+            added += self.add_headers(&headers)?;
+            if (headers.len() as u64) < MAX_HEADERS_SIZE {
+                return Ok(added);
+            }
+        }
+    }
+
+    /// Connects to each peer in turn and syncs from it, so the chain ends
+    /// up with the most work any of them has. Returns the new height, or
+    /// the last error if no peer could be synced from.
+    pub async fn sync_peers(
+        &mut self,
+        addrs: &[SocketAddr],
+        config: &PeerConfig,
+    ) -> Result<u32, SpvError> {
+        let mut result = Err(SpvError::Peer(PeerError::Disconnected));
+        for addr in addrs {
+            let mut peer = match Peer::connect(*addr, config.clone()).await {
+                Ok(peer) => peer,
+                Err(e) => {
+                    result = Err(e.into());
+                    continue;
+                }
+            };
+            match self.sync(&mut peer).await {
+                Ok(_) => result = Ok(self.height()),
+                Err(e @ SpvError::Io(_)) => return Err(e),
+                Err(e) => result = Err(e),
+            }
+        }
+        result
+    }
+
+    /// Asks `peer` for the block `hash` filtered by the bloom filter loaded
+    /// on it, returning the proof and the matched transactions, which the
+    /// peer sends right after the `merkleblock`.
+    pub async fn get_filtered_block(
+        peer: &mut Peer,
+        hash: [u8; 32],
+    ) -> Result<(MerkleBlock, Vec<Tx>), SpvError> {
+        let inventory = Inventory::new(MSG_FILTERED_BLOCK, hash);
+        peer.send(&Message::GetData(vec![inventory])).await?;
+        let merkle_block = loop {
+            match peer.receive().await? {
+                Message::MerkleBlock(merkle_block) if merkle_block.header.hash() == hash => {
+                    break merkle_block
+                }
+                Message::NotFound(items) if items.contains(&inventory) => {
+                    return Err(SpvError::NotFound)
+                }
+                _ => {}
+            }
+        };
+        let matches = merkle_block.verify().ok_or(SpvError::InvalidProof)?;
+        let mut txs: Vec<Option<Tx>> = vec![None; matches.len()];
+        while txs.iter().any(Option::is_none) {
+            if let Message::Tx(tx) = peer.receive().await? {
+                if let Some(i) = matches.iter().position(|txid| *txid == tx.txid()) {
+                    txs[i] = Some(tx);
+                }
+            }
+        }
+        Ok((merkle_block, txs.into_iter().flatten().collect()))
+    }
+
+    /// Number of blocks on the best chain from the block `proof` commits to
+    /// up to the tip, counting that block. None if the proof is invalid,
+    /// does not match `txid`, or its block is not on the best chain.
+    pub fn confirmations(&self, txid: &[u8; 32], proof: &MerkleBlock) -> Option<u32> {
+        if !proof.verify()?.contains(txid) {
+            return None;
+        }
+        let hash = proof.header.hash();
+        if !self.chain.is_active(&hash) {
+            return None;
+        }
+        Some(self.chain.height() - self.chain.get_height(&hash)? + 1)
+    }
+
+    /// Whether `txid` is proven by `proof` to be buried under at least
+    /// `depth` blocks of the best chain, counting its own.
+    pub fn is_confirmed(&self, txid: &[u8; 32], proof: &MerkleBlock, depth: u32) -> bool {
+        self.confirmations(txid, proof)
+            .is_some_and(|confirmations| confirmations >= depth)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        block::Block,
+        bloom::{BloomFilter, BLOOM_UPDATE_ALL},
+        merkle::merkle_root,
+        message::{NODE_BLOOM, NODE_NETWORK, NODE_WITNESS},
+        peer::Action,
+        script::Script,
+        tx::{OutPoint, TxIn, TxOut, SEQUENCE_FINAL},
+    };
+    use std::{
+        fs,
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+    use tokio::net::TcpListener;
+
+    fn mine(prev: &BlockHeader, merkle_root: [u8; 32]) -> BlockHeader {
+        let mut header = BlockHeader::new(
+            0x2000_0000,
+            prev.hash(),
+            merkle_root,
+            prev.timestamp + 1,
+            prev.bits,
+            0,
+        );
+        while !header.check_pow() {
+            header.nonce += 1;
+        }
+        header
+    }
+
+    fn build_chain(length: u32, txids_at: u32, txids: &[[u8; 32]]) -> HeaderChain {
+        let mut chain = HeaderChain::new(ChainParams::new(Network::Regtest));
+        for height in 1..=length {
+            let root = if height == txids_at {
+                merkle_root(txids)
+            } else {
+                [height as u8; 32]
+            };
+            chain.add_header(mine(chain.tip(), root)).unwrap();
+        }
+        chain
+    }
+
+    /// A node on localhost answering `getheaders` from `chain`.
+    async fn serve(chain: HeaderChain) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let stream = listener.accept().await.unwrap().0;
+            let mut config = PeerConfig::new(Network::Regtest);
+            config.services = NODE_NETWORK | NODE_WITNESS;
+            config.required_services = 0;
+            let mut peer = Peer::accept(stream, config).await.unwrap();
+            peer.on("getheaders", move |message| {
+                let Message::GetHeaders(request) = message else {
+                    unreachable!();
+                };
+                let fork = request
+                    .locator
+                    .iter()
+                    .find(|hash| chain.is_active(hash))
+                    .and_then(|hash| chain.get_height(hash))
+                    .unwrap_or(0);
+                let headers = (fork + 1..=chain.height())
+                    .take(MAX_HEADERS_SIZE as usize)
+                    .map(|height| *chain.get_header(height).unwrap())
+                    .collect();
+                Action::Reply(vec![Message::Headers(headers)])
+            });
+            let _ = peer.run().await;
+        });
+        addr
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("{}-{}.dat", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn config() -> PeerConfig {
+        let mut config = PeerConfig::new(Network::Regtest);
+        config.handshake_timeout = Duration::from_secs(5);
+        config
+    }
+
+    #[tokio::test]
+    async fn test_sync() {
+        let txids = [[1; 32], [2; 32], [3; 32]];
+        let remote = build_chain(2100, 2090, &txids);
+        let short = build_chain(30, 5, &[[9; 32]]);
+        let path = temp_path("spv-sync");
+
+        let mut client = SpvClient::open(&path, Network::Regtest).unwrap();
+        let addrs = [serve(short).await, serve(remote.clone()).await];
+        assert_eq!(client.sync_peers(&addrs, &config()).await, Ok(2100));
+        assert_eq!(client.get_chain().tip(), remote.tip());
+        // the short chain forks at height 5 and stays on disk
+        assert_eq!(fs::metadata(&path).unwrap().len(), 80 * (2100 + 26));
+
+        let block = *remote.get_header(2090).unwrap();
+        let proof = MerkleBlock::new(block, &txids, &[false, true, false]).unwrap();
+        assert_eq!(client.confirmations(&[2; 32], &proof), Some(11));
+        assert!(client.is_confirmed(&[2; 32], &proof, 6));
+        assert!(!client.is_confirmed(&[2; 32], &proof, 12));
+        assert!(!client.is_confirmed(&[1; 32], &proof, 1));
+
+        // a proof for a block we do not know
+        let mut unknown = proof.clone();
+        unknown.header.nonce += 1;
+        assert_eq!(client.confirmations(&[2; 32], &unknown), None);
+
+        drop(client);
+        let mut raw = fs::read(&path).unwrap();
+        raw.extend([0; 40]);
+        fs::write(&path, raw).unwrap();
+        let client = SpvClient::open(&path, Network::Regtest).unwrap();
+        assert_eq!(client.get_chain().tip(), remote.tip());
+        assert_eq!(fs::metadata(&path).unwrap().len(), 80 * (2100 + 26));
+        fs::remove_file(path).unwrap();
+    }
+
+    /// A BIP37 node on localhost serving filtered copies of `block`.
+    async fn serve_filtered(block: Block) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let stream = listener.accept().await.unwrap().0;
+            let mut config = PeerConfig::new(Network::Regtest);
+            config.services = NODE_NETWORK | NODE_WITNESS | NODE_BLOOM;
+            config.required_services = 0;
+            let mut peer = Peer::accept(stream, config).await.unwrap();
+            let filter = Arc::new(Mutex::new(None));
+            let loaded = filter.clone();
+            peer.on("filterload", move |message| {
+                let Message::FilterLoad(filter) = message else {
+                    unreachable!();
+                };
+                *loaded.lock().unwrap() = Some(filter.clone());
+                Action::Continue
+            });
+            peer.on("getdata", move |message| {
+                let Message::GetData(items) = message else {
+                    unreachable!();
+                };
+                if items[0].hash != block.hash() {
+                    return Action::Reply(vec![Message::NotFound(items.clone())]);
+                }
+                let mut filter = filter.lock().unwrap();
+                let filter = filter.as_mut().unwrap();
+                let matches: Vec<bool> = block
+                    .txs
+                    .iter()
+                    .map(|tx| filter.is_relevant_and_update(tx))
+                    .collect();
+                let merkle_block =
+                    MerkleBlock::new(block.header, &block.txids(), &matches).unwrap();
+                let mut replies = vec![Message::MerkleBlock(merkle_block)];
+                for (tx, matched) in block.txs.iter().zip(matches).rev() {
+                    if matched {
+                        replies.push(Message::Tx(tx.clone()));
+                    }
+                }
+                Action::Reply(replies)
+            });
+            let _ = peer.run().await;
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_filtered_block() {
+        let tx = |prev_out, h160| {
+            Tx::new(
+                1,
+                vec![TxIn::new(prev_out, SEQUENCE_FINAL)],
+                vec![TxOut::new(1000, Script::p2pkh(&h160))],
+                0,
+            )
+        };
+        let coinbase = tx(OutPoint::null(), [1; 20]);
+        let funding = tx(OutPoint::new([9; 32], 0), [2; 20]);
+        let spend = tx(OutPoint::new(funding.txid(), 0), [3; 20]);
+        let genesis = Block::genesis(Network::Regtest);
+        let txs = vec![coinbase, funding.clone(), spend.clone()];
+        let header = mine(
+            &genesis.header,
+            merkle_root(&[txs[0].txid(), funding.txid(), spend.txid()]),
+        );
+        let block = Block::new(header, txs);
+
+        let addr = serve_filtered(block.clone()).await;
+        let mut peer = Peer::connect(addr, config()).await.unwrap();
+        let mut filter = BloomFilter::for_elements(10, 0.000001, 0, BLOOM_UPDATE_ALL);
+        filter.insert(&[2; 20]);
+        peer.send(&Message::FilterLoad(filter)).await.unwrap();
+
+        // the spend matches through the funding outpoint the node added
+        let (merkle_block, txs) = SpvClient::get_filtered_block(&mut peer, block.hash())
+            .await
+            .unwrap();
+        assert_eq!(merkle_block.header, block.header);
+        assert_eq!(txs, [funding, spend]);
+        assert_eq!(
+            SpvClient::get_filtered_block(&mut peer, genesis.hash()).await,
+            Err(SpvError::NotFound)
+        );
+    }
+
+    #[test]
+    fn test_add_headers() {
+        let chain = build_chain(5, 0, &[]);
+        let headers: Vec<BlockHeader> = (1..=5).map(|h| *chain.get_header(h).unwrap()).collect();
+        let path = temp_path("spv-add");
+        let mut client = SpvClient::open(&path, Network::Regtest).unwrap();
+        assert_eq!(client.add_headers(&headers[..2]), Ok(2));
+
+        let mut bad = headers[4];
+        bad.prev_block = [0xaa; 32];
+        assert_eq!(
+            client.add_headers(&[headers[1], headers[2], bad]),
+            Err(SpvError::Header(HeaderError::Orphan))
+        );
+        assert_eq!(client.height(), 3);
+        assert_eq!(
+            SpvClient::open(&path, Network::Regtest).unwrap().height(),
+            3
+        );
+        fs::remove_file(path).unwrap();
+    }
+}
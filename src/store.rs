@@ -0,0 +1,262 @@
+use crate::{
+    base58::hash256,
+    varint::{encode_varint, read_varint},
+};
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{self, Cursor, Read, Write},
+    path::{Path, PathBuf},
+};
+
+/// Puts and deletes that a store applies all or nothing.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WriteBatch {
+    /// Keys with their new value, or `None` to delete them.
+    pub ops: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn put(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        self.ops.push((key, Some(value)));
+    }
+
+    pub fn delete(&mut self, key: Vec<u8>) {
+        self.ops.push((key, None));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    pub fn parse<R: Read>(stream: &mut R) -> io::Result<Self> {
+        let mut ops = Vec::new();
+        for _ in 0..read_varint(stream)? {
+            let mut tag = [0];
+            stream.read_exact(&mut tag)?;
+            let key = read_bytes(stream)?;
+            let value = match tag[0] {
+                0 => None,
+                1 => Some(read_bytes(stream)?),
+                _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "bad batch op")),
+            };
+            ops.push((key, value));
+        }
+        Ok(Self { ops })
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut ret = encode_varint(self.ops.len() as u64);
+        for (key, value) in &self.ops {
+            ret.push(value.is_some() as u8);
+            ret.extend(encode_varint(key.len() as u64));
+            ret.extend(key);
+            if let Some(value) = value {
+                ret.extend(encode_varint(value.len() as u64));
+                ret.extend(value);
+            }
+        }
+        ret
+    }
+}
+
+fn read_bytes<R: Read>(stream: &mut R) -> io::Result<Vec<u8>> {
+    let length = read_varint(stream)?;
+    let mut ret = Vec::new();
+    stream.take(length).read_to_end(&mut ret)?;
+    if ret.len() as u64 != length {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(ret)
+}
+
+/// Storage for `UtxoSet` and anything else keyed by bytes.
+pub trait KeyValueStore {
+    fn get(&self, key: &[u8]) -> io::Result<Option<Vec<u8>>>;
+    fn write(&mut self, batch: WriteBatch) -> io::Result<()>;
+}
+
+fn apply(map: &mut HashMap<Vec<u8>, Vec<u8>>, batch: WriteBatch) {
+    for (key, value) in batch.ops {
+        match value {
+            Some(value) => map.insert(key, value),
+            None => map.remove(&key),
+        };
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct MemoryStore {
+    map: HashMap<Vec<u8>, Vec<u8>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+}
+
+impl KeyValueStore for MemoryStore {
+    fn get(&self, key: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        Ok(self.map.get(key).cloned())
+    }
+
+    fn write(&mut self, batch: WriteBatch) -> io::Result<()> {
+        apply(&mut self.map, batch);
+        Ok(())
+    }
+}
+
+/// A store kept as an append-only log of batches, each framed by its
+/// length and a checksum, and replayed into memory on open.
+///
+/// A batch interrupted mid-write fails its checksum and is dropped with
+/// everything after it, so a crash loses at most the last batch.
+pub struct FileStore {
+    map: HashMap<Vec<u8>, Vec<u8>>,
+    path: PathBuf,
+    file: File,
+}
+
+impl FileStore {
+    /// Opens or creates the log at `path`.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&path)?;
+        let mut raw = Vec::new();
+        file.read_to_end(&mut raw)?;
+        let mut map = HashMap::new();
+        let mut valid = 0;
+        while let Some(record) = raw.get(valid..valid + 8) {
+            let length = u32::from_le_bytes(record[..4].try_into().unwrap()) as usize;
+            let Some(body) = raw.get(valid + 8..valid + 8 + length) else {
+                break;
+            };
+            if hash256(body)[..4] != record[4..] {
+                break;
+            }
+            apply(&mut map, WriteBatch::parse(&mut Cursor::new(body))?);
+            valid += 8 + length;
+        }
+        if valid != raw.len() {
+            file.set_len(valid as u64)?;
+        }
+        Ok(Self { map, path, file })
+    }
+
+    pub fn get_path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    fn record(batch: &WriteBatch) -> Vec<u8> {
+        let body = batch.serialize();
+        let mut ret = (body.len() as u32).to_le_bytes().to_vec();
+        ret.extend(&hash256(&body)[..4]);
+        ret.extend(body);
+        ret
+    }
+
+    /// Rewrites the log as a single batch of the current entries, dropping
+    /// overwritten and deleted ones.
+    pub fn compact(&mut self) -> io::Result<()> {
+        let mut batch = WriteBatch::new();
+        for (key, value) in &self.map {
+            batch.put(key.clone(), value.clone());
+        }
+        let temp = self.path.with_extension("tmp");
+        let mut file = File::create(&temp)?;
+        file.write_all(&Self::record(&batch))?;
+        file.sync_all()?;
+        fs::rename(&temp, &self.path)?;
+        self.file = OpenOptions::new().append(true).open(&self.path)?;
+        Ok(())
+    }
+}
+
+impl KeyValueStore for FileStore {
+    fn get(&self, key: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        Ok(self.map.get(key).cloned())
+    }
+
+    fn write(&mut self, batch: WriteBatch) -> io::Result<()> {
+        self.file.write_all(&Self::record(&batch))?;
+        self.file.sync_data()?;
+        apply(&mut self.map, batch);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file_store() {
+        let path = std::env::temp_dir().join(format!("store-{}.log", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let mut store = FileStore::open(&path).unwrap();
+        let mut batch = WriteBatch::new();
+        batch.put(b"a".to_vec(), b"1".to_vec());
+        batch.put(b"b".to_vec(), b"2".to_vec());
+        store.write(batch).unwrap();
+        let mut batch = WriteBatch::new();
+        batch.delete(b"a".to_vec());
+        batch.put(b"b".to_vec(), vec![3; 300]);
+        store.write(batch).unwrap();
+        drop(store);
+
+        let mut store = FileStore::open(&path).unwrap();
+        assert_eq!(store.len(), 1);
+        assert_eq!(store.get(b"a").unwrap(), None);
+        assert_eq!(store.get(b"b").unwrap(), Some(vec![3; 300]));
+
+        // a torn write loses only the last batch
+        let size = fs::metadata(&path).unwrap().len();
+        let mut batch = WriteBatch::new();
+        batch.put(b"c".to_vec(), b"4".to_vec());
+        store.write(batch).unwrap();
+        drop(store);
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(fs::metadata(&path).unwrap().len() - 1)
+            .unwrap();
+        let mut store = FileStore::open(&path).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().len(), size);
+        assert_eq!(store.get(b"c").unwrap(), None);
+
+        store.compact().unwrap();
+        assert!(fs::metadata(&path).unwrap().len() < size);
+        let mut batch = WriteBatch::new();
+        batch.put(b"d".to_vec(), b"5".to_vec());
+        store.write(batch).unwrap();
+        drop(store);
+        let store = FileStore::open(&path).unwrap();
+        assert_eq!(store.len(), 2);
+        assert_eq!(store.get(b"b").unwrap(), Some(vec![3; 300]));
+        assert_eq!(store.get(b"d").unwrap(), Some(b"5".to_vec()));
+        fs::remove_file(path).unwrap();
+    }
+}
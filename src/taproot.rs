@@ -0,0 +1,575 @@
+use crate::{
+    field_element::FieldElement,
+    script::Script,
+    secp256k1::{S256Point, N, P},
+    signature::PrivateKey,
+};
+use primitive_types::U256;
+use sha2::{Digest, Sha256};
+use std::fmt;
+
+/// Leaf version of BIP342 tapscript.
+pub const TAPROOT_LEAF_TAPSCRIPT: u8 = 0xc0;
+/// Bits of the first control block byte holding the leaf version; the
+/// last bit is the output key parity.
+pub const TAPROOT_LEAF_MASK: u8 = 0xfe;
+/// Deepest a script tree may be, limiting the control block merkle path.
+pub const TAPROOT_CONTROL_MAX_NODE_COUNT: usize = 128;
+/// Size of a control block without its merkle path: the leaf version and
+/// parity byte, and the internal key.
+pub const TAPROOT_CONTROL_BASE_SIZE: usize = 33;
+pub const TAPROOT_CONTROL_NODE_SIZE: usize = 32;
+
+/// BIP340 tagged hash: SHA256 of `data` prefixed with the SHA256 of `tag`
+/// twice.
+pub fn tagged_hash(tag: &str, data: &[u8]) -> [u8; 32] {
+    let tag = Sha256::digest(tag.as_bytes());
+    let mut hasher = Sha256::new();
+    hasher.update(tag);
+    hasher.update(tag);
+    hasher.update(data);
+    hasher.finalize().into()
+}
+
+/// Hash of a script tree leaf (BIP341).
+pub fn tap_leaf_hash(leaf_version: u8, script: &Script) -> [u8; 32] {
+    tagged_hash(
+        "TapLeaf",
+        &[&[leaf_version][..], &script.serialize()].concat(),
+    )
+}
+
+/// Hash of a script tree branch, its children in lexicographic order.
+pub fn tap_branch_hash(a: &[u8; 32], b: &[u8; 32]) -> [u8; 32] {
+    let (a, b) = if a <= b { (a, b) } else { (b, a) };
+    tagged_hash("TapBranch", &[&a[..], &b[..]].concat())
+}
+
+/// The point with x coordinate `x` and an even y (BIP340 `lift_x`).
+pub fn lift_x(x: &[u8; 32]) -> Option<S256Point> {
+    S256Point::try_parse(&[&[2][..], x].concat())
+}
+
+/// The 32 byte x coordinate a BIP340 public key is encoded as.
+pub fn x_only(point: &S256Point) -> [u8; 32] {
+    point.compressed_sec()[1..].try_into().unwrap()
+}
+
+/// Tweaks `internal_key` with the merkle root of its script tree, or with
+/// nothing for a key path only output (BIP341). Returns the x-only output
+/// key and whether its y is odd, or `None` for an invalid key or tweak.
+pub fn tweak_key(
+    internal_key: &[u8; 32],
+    merkle_root: Option<&[u8; 32]>,
+) -> Option<([u8; 32], bool)> {
+    let point = lift_x(internal_key)?;
+    let data = [&internal_key[..], merkle_root.map_or(&[][..], |r| &r[..])].concat();
+    let tweak = U256::from_big_endian(&tagged_hash("TapTweak", &data));
+    if tweak >= U256::from_str_radix(N, 16).unwrap() {
+        return None;
+    }
+    let output_key = point + tweak * S256Point::get_the_generic_point();
+    output_key.as_point().get_coordinate()?;
+    Some((x_only(&output_key), output_key.compressed_sec()[0] == 3))
+}
+
+/// Tweaks a private key the way `tweak_key` tweaks its public key, so it
+/// can make key path signatures for the output key: negated first if its
+/// point has an odd y, then the tweak added. `None` if the tweak is out of
+/// range or gives zero.
+pub fn tweak_private_key(key: &PrivateKey, merkle_root: Option<&[u8; 32]>) -> Option<PrivateKey> {
+    let n = U256::from_str_radix(N, 16).unwrap();
+    let point = key.get_point();
+    let internal_key = x_only(&point);
+    let mut secret = key.get_secret();
+    if point.compressed_sec()[0] == 3 {
+        secret = n - secret;
+    }
+    let data = [&internal_key[..], merkle_root.map_or(&[][..], |r| &r[..])].concat();
+    let tweak = U256::from_big_endian(&tagged_hash("TapTweak", &data));
+    if tweak >= n {
+        return None;
+    }
+    let secret = (FieldElement::new(secret, n) + FieldElement::new(tweak, n)).get_num();
+    if secret.is_zero() {
+        return None;
+    }
+    Some(PrivateKey::new(secret))
+}
+
+/// Signs `msg` with `key` as BIP340 specifies, `aux_rand` being fresh
+/// randomness mixed into the nonce.
+pub fn sign_schnorr(key: &PrivateKey, msg: &[u8], aux_rand: &[u8; 32]) -> [u8; 64] {
+    let n = U256::from_str_radix(N, 16).unwrap();
+    let point = key.get_point();
+    let public_key = x_only(&point);
+    let mut d = key.get_secret();
+    if point.compressed_sec()[0] == 3 {
+        d = n - d;
+    }
+    let mut t = [0u8; 32];
+    d.to_big_endian(&mut t);
+    for (t, a) in t.iter_mut().zip(tagged_hash("BIP0340/aux", aux_rand)) {
+        *t ^= a;
+    }
+    let nonce = tagged_hash("BIP0340/nonce", &[&t[..], &public_key[..], msg].concat());
+    let mut k = U256::from_big_endian(&nonce) % n;
+    let big_r = k * S256Point::get_the_generic_point();
+    if big_r.compressed_sec()[0] == 3 {
+        k = n - k;
+    }
+    let r = x_only(&big_r);
+    let data = [&r[..], &public_key[..], msg].concat();
+    let e = U256::from_big_endian(&tagged_hash("BIP0340/challenge", &data)) % n;
+    let s = (FieldElement::new(k, n) + FieldElement::new(e, n) * FieldElement::new(d, n)).get_num();
+    let mut sig = [0u8; 64];
+    sig[..32].copy_from_slice(&r);
+    s.to_big_endian(&mut sig[32..]);
+    sig
+}
+
+/// A BIP340 public key: the x coordinate of a point whose y is even.
+///
+/// Only made by `parse`, which checks the x is on the curve, or from a
+/// point.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct XOnlyPublicKey([u8; 32]);
+
+impl XOnlyPublicKey {
+    /// `None` unless `bytes` is the x coordinate of a point on the curve.
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        let x: [u8; 32] = bytes.try_into().ok()?;
+        lift_x(&x)?;
+        Some(Self(x))
+    }
+
+    /// The x-only key of `point`, dropping the parity of its y.
+    pub fn from_point(point: &S256Point) -> Self {
+        Self(x_only(point))
+    }
+
+    pub fn serialize(&self) -> [u8; 32] {
+        self.0
+    }
+
+    /// Verifies a BIP340 signature of `msg` by this key.
+    pub fn verify_schnorr(&self, msg: &[u8], sig: &[u8; 64]) -> bool {
+        let p = U256::from_str_radix(P, 16).unwrap();
+        let n = U256::from_str_radix(N, 16).unwrap();
+        let (r, s) = (
+            U256::from_big_endian(&sig[..32]),
+            U256::from_big_endian(&sig[32..]),
+        );
+        let Some(point) = lift_x(&self.0) else {
+            return false;
+        };
+        if r >= p || s >= n {
+            return false;
+        }
+        let data = [&sig[..32], &self.0[..], msg].concat();
+        let e = U256::from_big_endian(&tagged_hash("BIP0340/challenge", &data)) % n;
+        // R = sG - eP
+        let big_r = s * S256Point::get_the_generic_point() + (n - e) * point;
+        big_r
+            .as_point()
+            .get_coordinate()
+            .is_some_and(|c| !c.get_y().get_num().bit(0) && c.get_x().get_num() == r)
+    }
+
+    /// The output key committing to this internal key and the script tree
+    /// with `merkle_root`, with whether its y is odd.
+    pub fn tap_tweak(&self, merkle_root: Option<&[u8; 32]>) -> Option<(XOnlyPublicKey, bool)> {
+        let (output_key, parity) = tweak_key(&self.0, merkle_root)?;
+        Some((Self(output_key), parity))
+    }
+}
+
+impl fmt::Display for XOnlyPublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", hex::encode(self.0))
+    }
+}
+
+/// A script in a taproot script tree.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TapLeaf {
+    pub version: u8,
+    pub script: Script,
+}
+
+impl TapLeaf {
+    /// A tapscript leaf.
+    pub fn new(script: Script) -> Self {
+        Self {
+            version: TAPROOT_LEAF_TAPSCRIPT,
+            script,
+        }
+    }
+
+    pub fn leaf_hash(&self) -> [u8; 32] {
+        tap_leaf_hash(self.version, &self.script)
+    }
+}
+
+/// A taproot script tree.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TapTree {
+    Leaf(TapLeaf),
+    Branch(Box<TapTree>, Box<TapTree>),
+}
+
+impl TapTree {
+    pub fn branch(a: TapTree, b: TapTree) -> Self {
+        TapTree::Branch(Box::new(a), Box::new(b))
+    }
+
+    /// The hash of the root, which the output key commits to.
+    pub fn merkle_root(&self) -> [u8; 32] {
+        match self {
+            TapTree::Leaf(leaf) => leaf.leaf_hash(),
+            TapTree::Branch(a, b) => tap_branch_hash(&a.merkle_root(), &b.merkle_root()),
+        }
+    }
+
+    pub fn depth(&self) -> usize {
+        match self {
+            TapTree::Leaf(_) => 0,
+            TapTree::Branch(a, b) => 1 + a.depth().max(b.depth()),
+        }
+    }
+
+    /// Every leaf, left to right, with the hashes on its path to the root,
+    /// deepest first, as a control block lists them.
+    pub fn leaves(&self) -> Vec<(TapLeaf, Vec<[u8; 32]>)> {
+        match self {
+            TapTree::Leaf(leaf) => vec![(leaf.clone(), Vec::new())],
+            TapTree::Branch(a, b) => {
+                let (hash_a, hash_b) = (a.merkle_root(), b.merkle_root());
+                let mut ret = a.leaves();
+                for (_, path) in ret.iter_mut() {
+                    path.push(hash_b);
+                }
+                for (leaf, mut path) in b.leaves() {
+                    path.push(hash_a);
+                    ret.push((leaf, path));
+                }
+                ret
+            }
+        }
+    }
+}
+
+/// A taproot output and what is needed to spend it by key or by any of its
+/// scripts.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TaprootSpendInfo {
+    pub internal_key: XOnlyPublicKey,
+    pub merkle_root: Option<[u8; 32]>,
+    pub output_key: XOnlyPublicKey,
+    /// Whether the full output key has an odd y.
+    pub output_key_parity: bool,
+    /// The leaves of the script tree with their merkle paths.
+    pub leaves: Vec<(TapLeaf, Vec<[u8; 32]>)>,
+}
+
+impl TaprootSpendInfo {
+    /// Commits `internal_key` to `tree`, or to nothing for an output that
+    /// can only be spent by key. `None` if the tree is too deep or the
+    /// tweak is invalid.
+    pub fn new(internal_key: XOnlyPublicKey, tree: Option<&TapTree>) -> Option<Self> {
+        if tree.is_some_and(|tree| tree.depth() > TAPROOT_CONTROL_MAX_NODE_COUNT) {
+            return None;
+        }
+        let merkle_root = tree.map(|tree| tree.merkle_root());
+        let (output_key, output_key_parity) = internal_key.tap_tweak(merkle_root.as_ref())?;
+        Some(Self {
+            internal_key,
+            merkle_root,
+            output_key,
+            output_key_parity,
+            leaves: tree.map_or_else(Vec::new, |tree| tree.leaves()),
+        })
+    }
+
+    pub fn script_pubkey(&self) -> Script {
+        Script::p2tr(&self.output_key.0)
+    }
+
+    /// The control block for spending `leaf`, if it is in the tree.
+    pub fn control_block(&self, leaf: &TapLeaf) -> Option<ControlBlock> {
+        let (_, path) = self.leaves.iter().find(|(l, _)| l == leaf)?;
+        Some(ControlBlock {
+            leaf_version: leaf.version,
+            output_key_parity: self.output_key_parity,
+            internal_key: self.internal_key,
+            merkle_branch: path.clone(),
+        })
+    }
+}
+
+/// The last witness element of a script path spend (BIP341): the leaf
+/// version, the output key parity, the internal key and the merkle path of
+/// the script being spent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ControlBlock {
+    pub leaf_version: u8,
+    pub output_key_parity: bool,
+    pub internal_key: XOnlyPublicKey,
+    pub merkle_branch: Vec<[u8; 32]>,
+}
+
+impl ControlBlock {
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < TAPROOT_CONTROL_BASE_SIZE
+            || !(bytes.len() - TAPROOT_CONTROL_BASE_SIZE).is_multiple_of(TAPROOT_CONTROL_NODE_SIZE)
+            || bytes.len()
+                > TAPROOT_CONTROL_BASE_SIZE
+                    + TAPROOT_CONTROL_NODE_SIZE * TAPROOT_CONTROL_MAX_NODE_COUNT
+        {
+            return None;
+        }
+        Some(Self {
+            leaf_version: bytes[0] & TAPROOT_LEAF_MASK,
+            output_key_parity: bytes[0] & 1 == 1,
+            internal_key: XOnlyPublicKey::parse(&bytes[1..TAPROOT_CONTROL_BASE_SIZE])?,
+            merkle_branch: bytes[TAPROOT_CONTROL_BASE_SIZE..]
+                .chunks(TAPROOT_CONTROL_NODE_SIZE)
+                .map(|node| node.try_into().unwrap())
+                .collect(),
+        })
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut ret = vec![self.leaf_version | self.output_key_parity as u8];
+        ret.extend(self.internal_key.0);
+        for node in &self.merkle_branch {
+            ret.extend(node);
+        }
+        ret
+    }
+
+    /// Whether spending `script` with this control block is committed to by
+    /// `output_key`, as BIP341's `VerifyTaprootCommitment` checks it.
+    pub fn verify(&self, output_key: &XOnlyPublicKey, script: &Script) -> bool {
+        let merkle_root = self
+            .merkle_branch
+            .iter()
+            .fold(tap_leaf_hash(self.leaf_version, script), |hash, node| {
+                tap_branch_hash(&hash, node)
+            });
+        self.internal_key
+            .tap_tweak(Some(&merkle_root))
+            .is_some_and(|(key, parity)| key == *output_key && parity == self.output_key_parity)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::script::Command;
+
+    fn key(s: &str) -> XOnlyPublicKey {
+        XOnlyPublicKey::parse(&hex::decode(s).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_bip341_outputs() {
+        // wallet test vectors from BIP341
+        let info = TaprootSpendInfo::new(
+            key("d6889cb081036e0faefa3a35157ad71086b123b2b144b649798b494c300a961d"),
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            info.output_key.to_string(),
+            "53a1f6e454df1aa2776a2814a721372d6258050de330b3c6d10ee8f4e0dda343"
+        );
+        assert!(info.output_key_parity);
+
+        let internal_key = key("187791b6f712a8ea41c8ecdd0ee77fab3e85263b37e1ec18a3651926b3a6cf27");
+        let leaf = TapLeaf::new(Script::from_bytes(
+            hex::decode("20d85a959b0290bf19bb89ed43c916be835475d013da4b362117393e25a48229b8ac")
+                .unwrap(),
+        ));
+        assert_eq!(
+            hex::encode(leaf.leaf_hash()),
+            "5b75adecf53548f3ec6ad7d78383bf84cc57b55a3127c72b9a2481752dd88b21"
+        );
+        let info = TaprootSpendInfo::new(internal_key, Some(&TapTree::Leaf(leaf.clone()))).unwrap();
+        assert_eq!(
+            hex::encode(info.script_pubkey().as_bytes()),
+            "5120147c9c57132f6e7ecddba9800bb0c4449251c92a1e60371ee77557b6620f3ea3"
+        );
+        let control_block = info.control_block(&leaf).unwrap();
+        assert_eq!(
+            hex::encode(control_block.serialize()),
+            "c1187791b6f712a8ea41c8ecdd0ee77fab3e85263b37e1ec18a3651926b3a6cf27"
+        );
+        assert!(control_block.verify(&info.output_key, &leaf.script));
+    }
+
+    #[test]
+    fn test_control_blocks() {
+        let leaves: Vec<TapLeaf> = (1..=3)
+            .map(|n| TapLeaf::new(Script::new(&[Command::int(n), Command::int(n)])))
+            .collect();
+        let tree = TapTree::branch(
+            TapTree::Leaf(leaves[0].clone()),
+            TapTree::branch(
+                TapTree::Leaf(leaves[1].clone()),
+                TapTree::Leaf(leaves[2].clone()),
+            ),
+        );
+        let internal_key = key("d6889cb081036e0faefa3a35157ad71086b123b2b144b649798b494c300a961d");
+        let info = TaprootSpendInfo::new(internal_key, Some(&tree)).unwrap();
+        assert_eq!(info.leaves.len(), 3);
+        for leaf in &leaves {
+            let control_block = info.control_block(leaf).unwrap();
+            let bytes = control_block.serialize();
+            assert_eq!(ControlBlock::parse(&bytes), Some(control_block.clone()));
+            assert!(control_block.verify(&info.output_key, &leaf.script));
+            // the control block commits to the script and the output key
+            assert!(!control_block.verify(&info.output_key, &Script::new(&[])));
+            let mut wrong_parity = control_block.clone();
+            wrong_parity.output_key_parity ^= true;
+            assert!(!wrong_parity.verify(&info.output_key, &leaf.script));
+        }
+        assert_eq!(
+            info.control_block(&leaves[0]).unwrap().merkle_branch.len(),
+            1
+        );
+        assert_eq!(
+            info.control_block(&leaves[2]).unwrap().merkle_branch.len(),
+            2
+        );
+        assert_eq!(info.control_block(&TapLeaf::new(Script::new(&[]))), None);
+        assert_eq!(ControlBlock::parse(&[0xc0; 34]), None);
+    }
+
+    #[test]
+    fn test_tweak_private_key() {
+        let key = PrivateKey::new(U256::from(3));
+        let merkle_root = [7; 32];
+        let tweaked = tweak_private_key(&key, Some(&merkle_root)).unwrap();
+        let (output_key, parity) = XOnlyPublicKey::from_point(&key.get_point())
+            .tap_tweak(Some(&merkle_root))
+            .unwrap();
+        let point = tweaked.get_point();
+        assert_eq!(XOnlyPublicKey::from_point(&point), output_key);
+        assert_eq!(point.compressed_sec()[0] == 3, parity);
+    }
+
+    #[test]
+    fn test_sign_schnorr() {
+        // BIP340 test vectors 1 and 2
+        for (secret, aux_rand, msg, expected) in [
+            (
+                "b7e151628aed2a6abf7158809cf4f3c762e7160f38b4da56a784d9045190cfef",
+                "0000000000000000000000000000000000000000000000000000000000000001",
+                "243f6a8885a308d313198a2e03707344a4093822299f31d0082efa98ec4e6c89",
+                "6896bd60eeae296db48a229ff71dfe071bde413e6d43f917dc8dcf8c78de33418906d11ac976abccb20b091292bff4ea897efcb639ea871cfa95f6de339e4b0a",
+            ),
+            (
+                "c90fdaa22168c234c4c6628b80dc1cd129024e088a67cc74020bbea63b14e5c9",
+                "c87aa53824b4d7ae2eb035a2b5bbbccc080e76cdc6d1692c4b0b62d798e6d906",
+                "7e2d58d8b3bcdf1abadec7829054f90dda9805aab56c77333024b9d0a508b75c",
+                "5831aaeed7b44bb74e5eab94ba9d4294c49bcf2a60728d8b4c200f50dd313c1bab745879a5ad954a72c45a91c3a51d3c7adea98d82f8481e0e1e03674a6f3fb7",
+            ),
+        ] {
+            let key = PrivateKey::new(U256::from_str_radix(secret, 16).unwrap());
+            let aux_rand = hex::decode(aux_rand).unwrap().try_into().unwrap();
+            let msg = hex::decode(msg).unwrap();
+            let sig = sign_schnorr(&key, &msg, &aux_rand);
+            assert_eq!(hex::encode(sig), expected);
+            assert!(XOnlyPublicKey::from_point(&key.get_point()).verify_schnorr(&msg, &sig));
+        }
+    }
+
+    #[test]
+    fn test_verify_schnorr() {
+        // BIP340 test vectors 3, 4 and 6 to 13: the public key, message,
+        // signature and whether it is valid
+        let key_6 = "dff1d77f2a671c5f36183726db2341be58feae1da2deced843240f7b502ba659";
+        let msg_6 = "243f6a8885a308d313198a2e03707344a4093822299f31d0082efa98ec4e6c89";
+        for (public_key, msg, sig, valid) in [
+            (
+                "25d1dff95105f5253c4022f628a996ad3a0d95fbf21d468a1b33f8c160d8f517",
+                "ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
+                "7eb0509757e246f19449885651611cb965ecc1a187dd51b64fda1edc9637d5ec97582b9cb13db3933705b32ba982af5af25fd78881ebb32771fc5922efc66ea3",
+                true,
+            ),
+            (
+                "d69c3509bb99e412e68b0fe8544e72837dfa30746d8be2aa65975f29d22dc7b9",
+                "4df3c3f68fcc83b27e9d42c90431a72499f17875c81a599b566c9889b9696703",
+                "00000000000000000000003b78ce563f89a0ed9414f5aa28ad0d96d6795f9c6376afb1548af603b3eb45c9f8207dee1060cb71c04e80f593060b07d28308d7f4",
+                true,
+            ),
+            // R has an odd y
+            (
+                key_6,
+                msg_6,
+                "fff97bd5755eeea420453a14355235d382f6472f8568a18b2f057a14602975563cc27944640ac607cd107ae10923d9ef7a73c643e166be5ebeafa34b1ac553e2",
+                false,
+            ),
+            // the message is negated
+            (
+                key_6,
+                msg_6,
+                "1fa62e331edbc21c394792d2ab1100a7b432b013df3f6ff4f99fcb33e0e1515f28890b3edb6e7189b630448b515ce4f8622a954cfe545735aaea5134fccdb2bd",
+                false,
+            ),
+            // s is negated
+            (
+                key_6,
+                msg_6,
+                "6cff5c3ba86c69ea4b7376f31a9bcb4f74c1976089b2d9963da2e5543e177769961764b3aa9b2ffcb6ef947b6887a226e8d7c93e00c5ed0c1834ff0d0c2e6da6",
+                false,
+            ),
+            // R is the point at infinity
+            (
+                key_6,
+                msg_6,
+                "0000000000000000000000000000000000000000000000000000000000000000123dda8328af9c23a94c1feecfd123ba4fb73476f0d594dcb65c6425bd186051",
+                false,
+            ),
+            (
+                key_6,
+                msg_6,
+                "00000000000000000000000000000000000000000000000000000000000000017615fbaf5ae28864013c099742deadb4dba87f11ac6754f93780d5a1837cf197",
+                false,
+            ),
+            // r is not the x of a point, or not below the field size
+            (
+                key_6,
+                msg_6,
+                "4a298dacae57395a15d0795ddbfd1dcb564da82b0f269bc70a74f8220429ba1d69e89b4c5564d00349106b8497785dd7d1d713a8ae82b32fa79d5f7fc407d39b",
+                false,
+            ),
+            (
+                key_6,
+                msg_6,
+                "fffffffffffffffffffffffffffffffffffffffffffffffffffffffefffffc2f69e89b4c5564d00349106b8497785dd7d1d713a8ae82b32fa79d5f7fc407d39b",
+                false,
+            ),
+            // s is not below the group order
+            (
+                key_6,
+                msg_6,
+                "6cff5c3ba86c69ea4b7376f31a9bcb4f74c1976089b2d9963da2e5543e177769fffffffffffffffffffffffffffffffebaaedce6af48a03bbfd25e8cd0364141",
+                false,
+            ),
+        ] {
+            let msg = hex::decode(msg).unwrap();
+            let sig = hex::decode(sig).unwrap().try_into().unwrap();
+            assert_eq!(key(public_key).verify_schnorr(&msg, &sig), valid, "{sig:?}");
+        }
+        // vectors 5 and 14: the public key is not on the curve, or not below
+        // the field size
+        for key in [
+            "eefdea4cdb677750a420fee807eacf21eb9898ae79b9768766e4faa04a2d4a34",
+            "fffffffffffffffffffffffffffffffffffffffffffffffffffffffefffffc30",
+        ] {
+            assert_eq!(XOnlyPublicKey::parse(&hex::decode(key).unwrap()), None);
+        }
+    }
+}
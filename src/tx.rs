@@ -0,0 +1,515 @@
+use crate::{
+    base58::hash256,
+    block::MAX_BLOCK_WEIGHT,
+    fee::{weight_to_vsize, FeeRate},
+    script::Script,
+    varint::{encode_varint, read_varint},
+};
+use std::{
+    collections::HashSet,
+    fmt,
+    io::{self, Read},
+};
+
+/// 21 million bitcoin, in satoshis.
+pub const MAX_MONEY: u64 = 21_000_000 * 100_000_000;
+
+/// nSequence that disables both nLockTime and replaceability.
+pub const SEQUENCE_FINAL: u32 = 0xffff_ffff;
+/// nSequence that enables nLockTime without signaling replaceability.
+pub const SEQUENCE_ENABLE_LOCKTIME: u32 = 0xffff_fffe;
+/// nSequence that signals BIP125 replaceability.
+pub const SEQUENCE_ENABLE_RBF: u32 = 0xffff_fffd;
+
+/// Why a transaction fails the checks that need no context.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TxError {
+    NoInputs,
+    NoOutputs,
+    Oversize,
+    /// An output, or the sum of the outputs, is above `MAX_MONEY`.
+    OutputTooLarge,
+    DuplicateInput,
+    /// The coinbase scriptSig is not 2 to 100 bytes.
+    BadCoinbaseLength,
+    /// A non-coinbase transaction spends the null outpoint.
+    NullPrevout,
+}
+
+/// A reference to an output of a previous transaction.
+///
+/// `txid` is kept in the internal byte order, i.e. the order it is hashed
+/// and serialized in. The hex shown by block explorers is its reverse.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct OutPoint {
+    pub txid: [u8; 32],
+    pub vout: u32,
+}
+
+impl OutPoint {
+    pub fn new(txid: [u8; 32], vout: u32) -> Self {
+        Self { txid, vout }
+    }
+
+    /// The outpoint spent by coinbase inputs.
+    pub fn null() -> Self {
+        Self {
+            txid: [0; 32],
+            vout: 0xffff_ffff,
+        }
+    }
+
+    pub fn is_null(&self) -> bool {
+        *self == Self::null()
+    }
+
+    pub fn parse<R: Read>(stream: &mut R) -> io::Result<Self> {
+        let mut txid = [0u8; 32];
+        stream.read_exact(&mut txid)?;
+        let mut vout = [0u8; 4];
+        stream.read_exact(&mut vout)?;
+        Ok(Self {
+            txid,
+            vout: u32::from_le_bytes(vout),
+        })
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        [&self.txid[..], &self.vout.to_le_bytes()].concat()
+    }
+}
+
+impl fmt::Display for OutPoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut txid = self.txid;
+        txid.reverse();
+        write!(f, "{}:{}", hex::encode(txid), self.vout)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TxIn {
+    pub prev_out: OutPoint,
+    pub script_sig: Script,
+    pub sequence: u32,
+    pub witness: Vec<Vec<u8>>,
+}
+
+impl TxIn {
+    pub fn new(prev_out: OutPoint, sequence: u32) -> Self {
+        Self {
+            prev_out,
+            script_sig: Script::default(),
+            sequence,
+            witness: Vec::new(),
+        }
+    }
+
+    pub fn parse<R: Read>(stream: &mut R) -> io::Result<Self> {
+        let prev_out = OutPoint::parse(stream)?;
+        let script_sig = Script::parse(stream)?;
+        let mut sequence = [0u8; 4];
+        stream.read_exact(&mut sequence)?;
+        Ok(Self {
+            prev_out,
+            script_sig,
+            sequence: u32::from_le_bytes(sequence),
+            witness: Vec::new(),
+        })
+    }
+
+    /// Serializes the input without its witness.
+    pub fn serialize(&self) -> Vec<u8> {
+        [
+            self.prev_out.serialize(),
+            self.script_sig.serialize(),
+            self.sequence.to_le_bytes().to_vec(),
+        ]
+        .concat()
+    }
+}
+
+/// Parses a witness: the number of stack items followed by each item.
+pub fn parse_witness<R: Read>(stream: &mut R) -> io::Result<Vec<Vec<u8>>> {
+    let items = read_varint(stream)?;
+    let mut witness = Vec::new();
+    for _ in 0..items {
+        let length = read_varint(stream)?;
+        let mut item = Vec::new();
+        stream.take(length).read_to_end(&mut item)?;
+        if item.len() as u64 != length {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        witness.push(item);
+    }
+    Ok(witness)
+}
+
+pub fn serialize_witness(witness: &[Vec<u8>]) -> Vec<u8> {
+    let mut ret = encode_varint(witness.len() as u64);
+    for item in witness {
+        ret.extend(encode_varint(item.len() as u64));
+        ret.extend(item);
+    }
+    ret
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TxOut {
+    pub amount: u64,
+    pub script_pubkey: Script,
+}
+
+impl TxOut {
+    pub fn new(amount: u64, script_pubkey: Script) -> Self {
+        Self {
+            amount,
+            script_pubkey,
+        }
+    }
+
+    pub fn parse<R: Read>(stream: &mut R) -> io::Result<Self> {
+        let mut amount = [0u8; 8];
+        stream.read_exact(&mut amount)?;
+        Ok(Self {
+            amount: u64::from_le_bytes(amount),
+            script_pubkey: Script::parse(stream)?,
+        })
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        [
+            self.amount.to_le_bytes().to_vec(),
+            self.script_pubkey.serialize(),
+        ]
+        .concat()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tx {
+    pub version: u32,
+    pub tx_ins: Vec<TxIn>,
+    pub tx_outs: Vec<TxOut>,
+    pub locktime: u32,
+}
+
+impl Tx {
+    pub fn new(version: u32, tx_ins: Vec<TxIn>, tx_outs: Vec<TxOut>, locktime: u32) -> Self {
+        Self {
+            version,
+            tx_ins,
+            tx_outs,
+            locktime,
+        }
+    }
+
+    /// Parses a transaction in either the legacy or the BIP144 segwit format.
+    pub fn parse<R: Read>(stream: &mut R) -> io::Result<Self> {
+        let mut version = [0u8; 4];
+        stream.read_exact(&mut version)?;
+        let mut num_inputs = read_varint(stream)?;
+        let segwit = num_inputs == 0;
+        if segwit {
+            let mut flag = [0u8; 1];
+            stream.read_exact(&mut flag)?;
+            if flag[0] != 0x01 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "unknown segwit flag",
+                ));
+            }
+            num_inputs = read_varint(stream)?;
+        }
+        let mut tx_ins = Vec::new();
+        for _ in 0..num_inputs {
+            tx_ins.push(TxIn::parse(stream)?);
+        }
+        let num_outputs = read_varint(stream)?;
+        let mut tx_outs = Vec::new();
+        for _ in 0..num_outputs {
+            tx_outs.push(TxOut::parse(stream)?);
+        }
+        if segwit {
+            for tx_in in tx_ins.iter_mut() {
+                tx_in.witness = parse_witness(stream)?;
+            }
+        }
+        let mut locktime = [0u8; 4];
+        stream.read_exact(&mut locktime)?;
+        Ok(Self {
+            version: u32::from_le_bytes(version),
+            tx_ins,
+            tx_outs,
+            locktime: u32::from_le_bytes(locktime),
+        })
+    }
+
+    pub fn has_witness(&self) -> bool {
+        self.tx_ins.iter().any(|tx_in| !tx_in.witness.is_empty())
+    }
+
+    /// Serializes the transaction, including witnesses if there are any.
+    pub fn serialize(&self) -> Vec<u8> {
+        if !self.has_witness() {
+            return self.serialize_legacy();
+        }
+        let mut ret = self.version.to_le_bytes().to_vec();
+        ret.extend([0x00, 0x01]);
+        ret.extend(self.serialize_ins_and_outs());
+        for tx_in in &self.tx_ins {
+            ret.extend(serialize_witness(&tx_in.witness));
+        }
+        ret.extend(self.locktime.to_le_bytes());
+        ret
+    }
+
+    /// Serializes the transaction without witnesses, as hashed for the txid.
+    pub fn serialize_legacy(&self) -> Vec<u8> {
+        [
+            self.version.to_le_bytes().to_vec(),
+            self.serialize_ins_and_outs(),
+            self.locktime.to_le_bytes().to_vec(),
+        ]
+        .concat()
+    }
+
+    fn serialize_ins_and_outs(&self) -> Vec<u8> {
+        let mut ret = encode_varint(self.tx_ins.len() as u64);
+        for tx_in in &self.tx_ins {
+            ret.extend(tx_in.serialize());
+        }
+        ret.extend(encode_varint(self.tx_outs.len() as u64));
+        for tx_out in &self.tx_outs {
+            ret.extend(tx_out.serialize());
+        }
+        ret
+    }
+
+    /// Transaction hash in internal byte order.
+    pub fn txid(&self) -> [u8; 32] {
+        hash256(&self.serialize_legacy()).try_into().unwrap()
+    }
+
+    /// Witness transaction hash in internal byte order (BIP141).
+    pub fn wtxid(&self) -> [u8; 32] {
+        hash256(&self.serialize()).try_into().unwrap()
+    }
+
+    /// Human-readable txid, as shown by block explorers.
+    pub fn id(&self) -> String {
+        let mut txid = self.txid();
+        txid.reverse();
+        hex::encode(txid)
+    }
+
+    pub fn is_coinbase(&self) -> bool {
+        self.tx_ins.len() == 1 && self.tx_ins[0].prev_out.is_null()
+    }
+
+    /// Size without witness data, in bytes.
+    pub fn base_size(&self) -> usize {
+        self.serialize_legacy().len()
+    }
+
+    /// Size with witness data, in bytes.
+    pub fn total_size(&self) -> usize {
+        self.serialize().len()
+    }
+
+    /// Weight as defined in BIP141: witness bytes count once, the rest four times.
+    pub fn weight(&self) -> u64 {
+        (self.base_size() * 3 + self.total_size()) as u64
+    }
+
+    /// Virtual size, the weight divided by 4 and rounded up.
+    pub fn vsize(&self) -> u64 {
+        weight_to_vsize(self.weight())
+    }
+
+    /// Fee paid given the outputs being spent, in input order.
+    ///
+    /// Returns `None` if `prevouts` does not match the inputs or if the
+    /// outputs are worth more than the inputs.
+    pub fn fee(&self, prevouts: &[TxOut]) -> Option<u64> {
+        if prevouts.len() != self.tx_ins.len() {
+            return None;
+        }
+        let input_value = prevouts
+            .iter()
+            .try_fold(0u64, |sum, prevout| sum.checked_add(prevout.amount))?;
+        let output_value = self
+            .tx_outs
+            .iter()
+            .try_fold(0u64, |sum, tx_out| sum.checked_add(tx_out.amount))?;
+        input_value.checked_sub(output_value)
+    }
+
+    pub fn fee_rate(&self, prevouts: &[TxOut]) -> Option<FeeRate> {
+        let fee = self.fee(prevouts)?;
+        FeeRate::from_fee_and_vsize(fee, self.vsize())
+    }
+
+    /// The checks of Bitcoin Core's `CheckTransaction`, which need neither
+    /// the outputs being spent nor the chain.
+    pub fn check(&self) -> Result<(), TxError> {
+        if self.tx_ins.is_empty() {
+            return Err(TxError::NoInputs);
+        }
+        if self.tx_outs.is_empty() {
+            return Err(TxError::NoOutputs);
+        }
+        if self.base_size() as u64 * 4 > MAX_BLOCK_WEIGHT {
+            return Err(TxError::Oversize);
+        }
+        let mut total = 0u64;
+        for tx_out in &self.tx_outs {
+            if tx_out.amount > MAX_MONEY {
+                return Err(TxError::OutputTooLarge);
+            }
+            total += tx_out.amount;
+            if total > MAX_MONEY {
+                return Err(TxError::OutputTooLarge);
+            }
+        }
+        let mut prev_outs = HashSet::new();
+        if !self
+            .tx_ins
+            .iter()
+            .all(|tx_in| prev_outs.insert(tx_in.prev_out))
+        {
+            return Err(TxError::DuplicateInput);
+        }
+        if self.is_coinbase() {
+            if !(2..=100).contains(&self.tx_ins[0].script_sig.len()) {
+                return Err(TxError::BadCoinbaseLength);
+            }
+        } else if self.tx_ins.iter().any(|tx_in| tx_in.prev_out.is_null()) {
+            return Err(TxError::NullPrevout);
+        }
+        Ok(())
+    }
+
+    /// Signature operations in the scriptSigs and output scripts, counted
+    /// the pre-P2SH way.
+    pub fn legacy_sigop_count(&self) -> u32 {
+        let inputs: u32 = self
+            .tx_ins
+            .iter()
+            .map(|tx_in| tx_in.script_sig.sigop_count(false))
+            .sum();
+        let outputs: u32 = self
+            .tx_outs
+            .iter()
+            .map(|tx_out| tx_out.script_pubkey.sigop_count(false))
+            .sum();
+        inputs + outputs
+    }
+}
+
+impl fmt::Display for Tx {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "tx: {}", self.id())?;
+        writeln!(f, "version: {}", self.version)?;
+        writeln!(f, "tx_ins:")?;
+        for tx_in in &self.tx_ins {
+            writeln!(f, "  {} sequence: {:#010x}", tx_in.prev_out, tx_in.sequence)?;
+        }
+        writeln!(f, "tx_outs:")?;
+        for tx_out in &self.tx_outs {
+            writeln!(f, "  {}:{}", tx_out.amount, tx_out.script_pubkey)?;
+        }
+        write!(f, "locktime: {}", self.locktime)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    const LEGACY_TX: &str = "0100000001813f79011acb80925dfe69b3def355fe914bd1d96a3f5f71bf8303c6a989c7d1000000006b483045022100ed81ff192e75a3fd2304004dcadb746fa5e24c5031ccfcf21320b0277457c98f02207a986d955c6e0cb35d446a89d3f56100f4d7f67801c31967743a9c8e10615bed01210349fc4e631e3624a545de3f89f5d8684c7b8138bd94bdd531d2e213bf016b278afeffffff02a135ef01000000001976a914bc3b654dca7e56b04dca18f2566cdaf02e8d9ada88ac99c39800000000001976a9141c4bc762dd5423e332166702cb75f40df79fea1288ac19430600";
+
+    #[test]
+    fn test_parse_legacy() {
+        let raw = hex::decode(LEGACY_TX).unwrap();
+        let tx = Tx::parse(&mut Cursor::new(&raw)).unwrap();
+        assert_eq!(tx.version, 1);
+        assert_eq!(tx.tx_ins.len(), 1);
+        assert_eq!(tx.tx_ins[0].sequence, 0xfffffffe);
+        assert_eq!(tx.tx_ins[0].prev_out.vout, 0);
+        assert_eq!(tx.tx_outs.len(), 2);
+        assert_eq!(tx.tx_outs[0].amount, 32454049);
+        assert_eq!(tx.tx_outs[1].amount, 10011545);
+        assert!(tx.tx_outs[0].script_pubkey.is_p2pkh());
+        assert_eq!(tx.locktime, 410393);
+        assert_eq!(
+            tx.id(),
+            "452c629d67e41baec3ac6f04fe744b4b9617f8f859c63b3002f8684e7a4fee03"
+        );
+        assert_eq!(tx.serialize(), raw);
+        assert_eq!(tx.txid(), tx.wtxid());
+        assert_eq!(tx.base_size(), 226);
+        assert_eq!(tx.total_size(), 226);
+        assert_eq!(tx.weight(), 904);
+        assert_eq!(tx.vsize(), 226);
+    }
+
+    #[test]
+    fn test_parse_segwit() {
+        // P2SH-P2WPKH spend from mainnet
+        let raw = hex::decode("0200000000010166c3d39490dc827a2594c7b17b7d37445e1f4b372179649cd2ce4475e3641bbb0100000017160014e69aa750e9bff1aca1e32e57328b641b611fc817fdffffff01e87c5d010000000017a914f3890da1b99e44cd3d52f7bcea6a1351658ea7be87024830450221009eb97597953dc288de30060ba02d4e91b2bde1af2ecf679c7f5ab5989549aa8002202a98f8c3bd1a5a31c0d72950dd6e2e3870c6c5819a6c3db740e91ebbbc5ef4800121023f3d3b8e74b807e32217dea2c75c8d0bd46b8665b3a2d9b3cb310959de52a09bc9d20700").unwrap();
+        let tx = Tx::parse(&mut Cursor::new(&raw)).unwrap();
+        assert_eq!(tx.version, 2);
+        assert_eq!(tx.tx_ins.len(), 1);
+        assert_eq!(tx.tx_ins[0].script_sig.len(), 23);
+        assert_eq!(tx.tx_ins[0].sequence, 0xfffffffd);
+        assert_eq!(tx.tx_ins[0].witness.len(), 2);
+        assert_eq!(tx.tx_ins[0].witness[1].len(), 33);
+        assert!(tx.tx_outs[0].script_pubkey.is_p2sh());
+        assert_eq!(tx.serialize(), raw);
+        assert_ne!(tx.txid(), tx.wtxid());
+        assert_eq!(tx.base_size(), 106);
+        assert_eq!(tx.total_size(), 216);
+        assert_eq!(tx.weight(), 534);
+        assert_eq!(tx.vsize(), 134);
+    }
+
+    #[test]
+    fn test_fee() {
+        let raw = hex::decode(LEGACY_TX).unwrap();
+        let tx = Tx::parse(&mut Cursor::new(&raw)).unwrap();
+        let output_value = 32454049 + 10011545;
+        let prevouts = vec![TxOut::new(output_value + 22600, Script::p2pkh(&[0; 20]))];
+        assert_eq!(tx.fee(&prevouts), Some(22600));
+        assert_eq!(tx.fee_rate(&prevouts), FeeRate::from_sat_per_vb(100));
+        assert_eq!(tx.fee(&[]), None);
+        assert_eq!(tx.fee(&[prevouts[0].clone(), prevouts[0].clone()]), None);
+        let prevout = TxOut::new(output_value - 1, Script::p2pkh(&[0; 20]));
+        assert_eq!(tx.fee(&[prevout]), None);
+    }
+
+    #[test]
+    fn test_check() {
+        let tx = Tx::parse(&mut Cursor::new(hex::decode(LEGACY_TX).unwrap())).unwrap();
+        assert_eq!(tx.check(), Ok(()));
+        assert_eq!(tx.legacy_sigop_count(), 2);
+
+        let mut bad = tx.clone();
+        bad.tx_outs[0].amount = MAX_MONEY;
+        assert_eq!(bad.check(), Err(TxError::OutputTooLarge));
+        let mut bad = tx.clone();
+        bad.tx_ins.push(bad.tx_ins[0].clone());
+        assert_eq!(bad.check(), Err(TxError::DuplicateInput));
+        let mut bad = tx.clone();
+        bad.tx_ins.push(TxIn::new(OutPoint::null(), 0));
+        assert_eq!(bad.check(), Err(TxError::NullPrevout));
+        let mut coinbase = tx;
+        coinbase.tx_ins[0].prev_out = OutPoint::null();
+        assert_eq!(coinbase.check(), Err(TxError::BadCoinbaseLength));
+        coinbase.tx_ins[0].script_sig = Script::from_bytes(vec![1, 0]);
+        assert_eq!(coinbase.check(), Ok(()));
+        coinbase.tx_ins[0].script_sig = Script::from_bytes(vec![0]);
+        assert_eq!(coinbase.check(), Err(TxError::BadCoinbaseLength));
+    }
+}
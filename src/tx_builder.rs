@@ -0,0 +1,490 @@
+use crate::{
+    address::Address,
+    fee::{dust_threshold, FeeRate},
+    script::Script,
+    tx::{
+        OutPoint, Tx, TxIn, TxOut, SEQUENCE_ENABLE_LOCKTIME, SEQUENCE_ENABLE_RBF, SEQUENCE_FINAL,
+    },
+    varint::encode_varint,
+};
+
+/// Largest ECDSA signature with its sighash byte: a DER encoding whose `r`
+/// needs a leading zero byte.
+pub const MAX_ECDSA_SIG_SIZE: u64 = 72;
+/// Largest ECDSA signature with its sighash byte when the signer grinds for
+/// a low `r`.
+pub const MAX_ECDSA_SIG_SIZE_LOW_R: u64 = 71;
+/// Schnorr signature with the default sighash, which adds no byte.
+pub const SCHNORR_SIG_SIZE: u64 = 64;
+
+/// How an input will be spent, which determines its size once signed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SpendType {
+    P2pkh,
+    P2shP2wpkh,
+    P2wpkh,
+    P2tr,
+    /// Bare `m`-of-`n` CHECKMULTISIG of compressed keys in P2SH.
+    P2shMultisig {
+        m: u8,
+        n: u8,
+    },
+    /// Bare `m`-of-`n` CHECKMULTISIG of compressed keys in P2WSH.
+    P2wshMultisig {
+        m: u8,
+        n: u8,
+    },
+}
+
+impl SpendType {
+    /// Guesses the spend type from the output being spent.
+    ///
+    /// P2SH and P2WSH outputs are ambiguous and return `None`.
+    pub fn from_script_pubkey(script_pubkey: &Script) -> Option<Self> {
+        if script_pubkey.is_p2pkh() {
+            Some(SpendType::P2pkh)
+        } else if script_pubkey.is_p2wpkh() {
+            Some(SpendType::P2wpkh)
+        } else if script_pubkey.is_p2tr() {
+            Some(SpendType::P2tr)
+        } else {
+            None
+        }
+    }
+
+    pub fn is_segwit(&self) -> bool {
+        !matches!(self, SpendType::P2pkh | SpendType::P2shMultisig { .. })
+    }
+
+    /// Worst-case weight of the signed input, witness included, assuming
+    /// high-R ECDSA signatures.
+    pub fn input_weight(&self) -> u64 {
+        self.max_input_weight(false)
+    }
+
+    /// Worst-case weight of the signed input, witness included.
+    ///
+    /// With `low_r` the ECDSA signatures are assumed to be ground to a low
+    /// `r`, which saves a byte per signature.
+    pub fn max_input_weight(&self, low_r: bool) -> u64 {
+        let sig = if low_r {
+            MAX_ECDSA_SIG_SIZE_LOW_R
+        } else {
+            MAX_ECDSA_SIG_SIZE
+        };
+        // outpoint and sequence
+        let base = 32 + 4 + 4;
+        match *self {
+            // <sig> <pubkey>
+            SpendType::P2pkh => (base + script_size(1 + sig + 1 + 33)) * 4,
+            // scriptSig pushes the 22 byte redeem script `0 <h160>`
+            SpendType::P2shP2wpkh => (base + script_size(1 + 22)) * 4 + (1 + 1 + sig + 1 + 33),
+            SpendType::P2wpkh => (base + 1) * 4 + (1 + 1 + sig + 1 + 33),
+            SpendType::P2tr => (base + 1) * 4 + (1 + 1 + SCHNORR_SIG_SIZE),
+            // OP_0 <sig>... <redeem script>
+            SpendType::P2shMultisig { m, n } => {
+                let redeem_script = multisig_script_size(n);
+                let script_sig =
+                    1 + m as u64 * (1 + sig) + push_size(redeem_script) + redeem_script;
+                (base + script_size(script_sig)) * 4
+            }
+            // <> <sig>... <witness script>
+            SpendType::P2wshMultisig { m, n } => {
+                let witness_script = multisig_script_size(n);
+                let witness = varint_size(m as u64 + 2)
+                    + 1
+                    + m as u64 * (1 + sig)
+                    + varint_size(witness_script)
+                    + witness_script;
+                (base + 1) * 4 + witness
+            }
+        }
+    }
+}
+
+/// `OP_m <pubkey>... OP_n OP_CHECKMULTISIG` with compressed keys.
+fn multisig_script_size(n: u8) -> u64 {
+    3 + 34 * n as u64
+}
+
+fn varint_size(n: u64) -> u64 {
+    encode_varint(n).len() as u64
+}
+
+/// Size of a script of `len` bytes with its length prefix.
+fn script_size(len: u64) -> u64 {
+    varint_size(len) + len
+}
+
+/// Size of the push opcode needed for `len` bytes of data.
+fn push_size(len: u64) -> u64 {
+    match len {
+        0..=75 => 1,
+        76..=0xff => 2,
+        0x100..=0xffff => 3,
+        _ => 5,
+    }
+}
+
+/// An output owned by the wallet that can be spent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Utxo {
+    pub outpoint: OutPoint,
+    pub tx_out: TxOut,
+    pub spend_type: SpendType,
+}
+
+impl Utxo {
+    pub fn new(outpoint: OutPoint, tx_out: TxOut, spend_type: SpendType) -> Self {
+        Self {
+            outpoint,
+            tx_out,
+            spend_type,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BuilderError {
+    NoInputs,
+    NoRecipients,
+    /// The recipient at this index would receive a dust amount.
+    DustOutput(usize),
+    InsufficientFunds {
+        needed: u64,
+        available: u64,
+    },
+    /// The inputs, or the outputs plus fee, add up to more than a `u64`.
+    ValueOverflow,
+}
+
+/// An unsigned transaction and what a signer needs to know about it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnsignedTx {
+    pub tx: Tx,
+    /// The outputs being spent, in input order.
+    pub inputs: Vec<Utxo>,
+    pub fee: u64,
+    /// Index of the change output, if one was added.
+    pub change_index: Option<usize>,
+    /// Estimated weight once every input is signed.
+    pub weight: u64,
+}
+
+/// Builds a transaction spending every given UTXO to the recipients, with
+/// the rest going back to the change address.
+///
+/// Change is only added when it is worth more than the dust threshold after
+/// paying for its own output; otherwise it is left to the miner.
+#[derive(Debug, Clone)]
+pub struct TransactionBuilder {
+    utxos: Vec<Utxo>,
+    recipients: Vec<(Address, u64)>,
+    fee_rate: FeeRate,
+    change_address: Address,
+    rbf: bool,
+    locktime: u32,
+    low_r: bool,
+}
+
+impl TransactionBuilder {
+    pub fn new(fee_rate: FeeRate, change_address: Address) -> Self {
+        Self {
+            utxos: Vec::new(),
+            recipients: Vec::new(),
+            fee_rate,
+            change_address,
+            rbf: false,
+            locktime: 0,
+            low_r: false,
+        }
+    }
+
+    pub fn add_utxo(&mut self, utxo: Utxo) -> &mut Self {
+        self.utxos.push(utxo);
+        self
+    }
+
+    pub fn add_recipient(&mut self, address: Address, amount: u64) -> &mut Self {
+        self.recipients.push((address, amount));
+        self
+    }
+
+    /// Signals BIP125 replaceability on every input.
+    pub fn enable_rbf(&mut self, rbf: bool) -> &mut Self {
+        self.rbf = rbf;
+        self
+    }
+
+    pub fn locktime(&mut self, locktime: u32) -> &mut Self {
+        self.locktime = locktime;
+        self
+    }
+
+    /// Estimates ECDSA signatures as low-R, for signers that grind for it.
+    pub fn low_r(&mut self, low_r: bool) -> &mut Self {
+        self.low_r = low_r;
+        self
+    }
+
+    fn sequence(&self) -> u32 {
+        if self.rbf {
+            SEQUENCE_ENABLE_RBF
+        } else if self.locktime != 0 {
+            SEQUENCE_ENABLE_LOCKTIME
+        } else {
+            SEQUENCE_FINAL
+        }
+    }
+
+    pub fn build(&self) -> Result<UnsignedTx, BuilderError> {
+        if self.utxos.is_empty() {
+            return Err(BuilderError::NoInputs);
+        }
+        if self.recipients.is_empty() {
+            return Err(BuilderError::NoRecipients);
+        }
+        let mut tx_outs = Vec::new();
+        for (i, (address, amount)) in self.recipients.iter().enumerate() {
+            let script_pubkey = address.script_pubkey();
+            if *amount < dust_threshold(&script_pubkey) {
+                return Err(BuilderError::DustOutput(i));
+            }
+            tx_outs.push(TxOut::new(*amount, script_pubkey));
+        }
+        let spend_types: Vec<SpendType> = self.utxos.iter().map(|u| u.spend_type).collect();
+        let available = checked_sum(self.utxos.iter().map(|u| u.tx_out.amount))?;
+        let sent = checked_sum(tx_outs.iter().map(|o| o.amount))?;
+
+        let weight = estimate_weight(&spend_types, &tx_outs, self.low_r);
+        let fee = self
+            .fee_rate
+            .fee_for_weight(weight)
+            .ok_or(BuilderError::ValueOverflow)?;
+        let needed = sent.checked_add(fee).ok_or(BuilderError::ValueOverflow)?;
+        if available < needed {
+            return Err(BuilderError::InsufficientFunds { needed, available });
+        }
+
+        let change_script = self.change_address.script_pubkey();
+        let mut with_change = tx_outs.clone();
+        with_change.push(TxOut::new(0, change_script.clone()));
+        let change_weight = estimate_weight(&spend_types, &with_change, self.low_r);
+        let change_fee = self
+            .fee_rate
+            .fee_for_weight(change_weight)
+            .ok_or(BuilderError::ValueOverflow)?;
+        let change = sent
+            .checked_add(change_fee)
+            .and_then(|needed| available.checked_sub(needed));
+        let (tx_outs, fee, change_index, weight) = match change {
+            Some(change) if change >= dust_threshold(&change_script) => {
+                let index = with_change.len() - 1;
+                with_change[index].amount = change;
+                (with_change, change_fee, Some(index), change_weight)
+            }
+            _ => (tx_outs, available - sent, None, weight),
+        };
+
+        let sequence = self.sequence();
+        let tx_ins = self
+            .utxos
+            .iter()
+            .map(|u| TxIn::new(u.outpoint, sequence))
+            .collect();
+        Ok(UnsignedTx {
+            tx: Tx::new(2, tx_ins, tx_outs, self.locktime),
+            inputs: self.utxos.clone(),
+            fee,
+            change_index,
+            weight,
+        })
+    }
+}
+
+fn checked_sum(mut amounts: impl Iterator<Item = u64>) -> Result<u64, BuilderError> {
+    amounts
+        .try_fold(0u64, |total, amount| total.checked_add(amount))
+        .ok_or(BuilderError::ValueOverflow)
+}
+
+/// Worst-case weight of a transaction spending `inputs` to `outputs` once
+/// signed. See `SpendType::max_input_weight` for `low_r`.
+pub fn estimate_weight(inputs: &[SpendType], outputs: &[TxOut], low_r: bool) -> u64 {
+    // version, input and output counts and locktime
+    let mut base = 4
+        + encode_varint(inputs.len() as u64).len() as u64
+        + encode_varint(outputs.len() as u64).len() as u64
+        + 4;
+    base += outputs
+        .iter()
+        .map(|o| o.serialize().len() as u64)
+        .sum::<u64>();
+    let mut weight = base * 4
+        + inputs
+            .iter()
+            .map(|i| i.max_input_weight(low_r))
+            .sum::<u64>();
+    if inputs.iter().any(|i| i.is_segwit()) {
+        // segwit marker and flag, and an empty witness for each legacy input
+        weight += 2 + inputs.iter().filter(|i| !i.is_segwit()).count() as u64;
+    }
+    weight
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{address::Payload, network::Network};
+
+    fn p2wpkh_address(b: u8) -> Address {
+        Address::new(Payload::P2wpkh([b; 20]), Network::Regtest)
+    }
+
+    fn p2wpkh_utxo(amount: u64) -> Utxo {
+        Utxo::new(
+            OutPoint::new([0xaa; 32], 0),
+            TxOut::new(amount, Script::p2wpkh(&[0x01; 20])),
+            SpendType::P2wpkh,
+        )
+    }
+
+    #[test]
+    fn test_build_with_change() {
+        let mut builder =
+            TransactionBuilder::new(FeeRate::from_sat_per_vb(10).unwrap(), p2wpkh_address(3));
+        builder
+            .add_utxo(p2wpkh_utxo(100_000))
+            .add_recipient(p2wpkh_address(2), 50_000);
+        let unsigned = builder.build().unwrap();
+        // 1 P2WPKH input and 2 P2WPKH outputs: 562 WU, i.e. 141 vB
+        assert_eq!(unsigned.weight, 562);
+        assert_eq!(unsigned.fee, 1410);
+        assert_eq!(unsigned.change_index, Some(1));
+        assert_eq!(unsigned.tx.tx_outs[1].amount, 48_590);
+        assert_eq!(
+            unsigned.tx.tx_outs[1].script_pubkey,
+            p2wpkh_address(3).script_pubkey()
+        );
+        assert_eq!(unsigned.tx.tx_ins[0].sequence, SEQUENCE_FINAL);
+        assert_eq!(unsigned.inputs[0], p2wpkh_utxo(100_000));
+    }
+
+    #[test]
+    fn test_build_drops_dust_change() {
+        let mut builder =
+            TransactionBuilder::new(FeeRate::from_sat_per_vb(10).unwrap(), p2wpkh_address(3));
+        builder
+            .add_utxo(p2wpkh_utxo(51_500))
+            .add_recipient(p2wpkh_address(2), 50_000);
+        let unsigned = builder.build().unwrap();
+        // change would be 90 sats, below the 294 sat P2WPKH dust threshold
+        assert_eq!(unsigned.change_index, None);
+        assert_eq!(unsigned.tx.tx_outs.len(), 1);
+        assert_eq!(unsigned.weight, 438);
+        assert_eq!(unsigned.fee, 1_500);
+
+        let mut builder =
+            TransactionBuilder::new(FeeRate::from_sat_per_vb(10).unwrap(), p2wpkh_address(3));
+        builder
+            .add_utxo(p2wpkh_utxo(50_500))
+            .add_recipient(p2wpkh_address(2), 50_000);
+        assert_eq!(
+            builder.build(),
+            Err(BuilderError::InsufficientFunds {
+                needed: 51_100,
+                available: 50_500
+            })
+        );
+    }
+
+    #[test]
+    fn test_sequence_and_dust_recipient() {
+        let mut builder =
+            TransactionBuilder::new(FeeRate::from_sat_per_vb(1).unwrap(), p2wpkh_address(3));
+        builder
+            .add_utxo(p2wpkh_utxo(100_000))
+            .add_recipient(p2wpkh_address(2), 50_000)
+            .locktime(800_000);
+        let unsigned = builder.build().unwrap();
+        assert_eq!(unsigned.tx.locktime, 800_000);
+        assert_eq!(unsigned.tx.tx_ins[0].sequence, SEQUENCE_ENABLE_LOCKTIME);
+        builder.enable_rbf(true);
+        let unsigned = builder.build().unwrap();
+        assert_eq!(unsigned.tx.tx_ins[0].sequence, SEQUENCE_ENABLE_RBF);
+
+        builder.add_recipient(p2wpkh_address(4), 293);
+        assert_eq!(builder.build(), Err(BuilderError::DustOutput(1)));
+    }
+
+    #[test]
+    fn test_value_overflow() {
+        let mut builder =
+            TransactionBuilder::new(FeeRate::from_sat_per_vb(1).unwrap(), p2wpkh_address(3));
+        builder
+            .add_utxo(p2wpkh_utxo(u64::MAX))
+            .add_utxo(p2wpkh_utxo(1))
+            .add_recipient(p2wpkh_address(2), 50_000);
+        assert_eq!(builder.build(), Err(BuilderError::ValueOverflow));
+
+        let mut builder =
+            TransactionBuilder::new(FeeRate::from_sat_per_vb(1).unwrap(), p2wpkh_address(3));
+        builder
+            .add_utxo(p2wpkh_utxo(100_000))
+            .add_recipient(p2wpkh_address(2), u64::MAX);
+        assert_eq!(builder.build(), Err(BuilderError::ValueOverflow));
+    }
+
+    #[test]
+    fn test_estimate_weight() {
+        let outputs = [TxOut::new(0, Script::p2pkh(&[0; 20]))];
+        // the classic 1-in-1-out P2PKH transaction is 192 bytes
+        assert_eq!(
+            estimate_weight(&[SpendType::P2pkh], &outputs, false),
+            192 * 4
+        );
+        assert_eq!(
+            estimate_weight(&[SpendType::P2pkh], &outputs, true),
+            191 * 4
+        );
+        // mixing in a legacy input adds its empty witness
+        assert_eq!(
+            estimate_weight(&[SpendType::P2pkh, SpendType::P2tr], &outputs, false),
+            192 * 4 + 230 + 2 + 1
+        );
+    }
+
+    #[test]
+    fn test_estimate_matches_signed_tx() {
+        // the mainnet P2SH-P2WPKH spend from the tx tests, signed with a
+        // 72 byte high-R signature
+        let raw = hex::decode("0200000000010166c3d39490dc827a2594c7b17b7d37445e1f4b372179649cd2ce4475e3641bbb0100000017160014e69aa750e9bff1aca1e32e57328b641b611fc817fdffffff01e87c5d010000000017a914f3890da1b99e44cd3d52f7bcea6a1351658ea7be87024830450221009eb97597953dc288de30060ba02d4e91b2bde1af2ecf679c7f5ab5989549aa8002202a98f8c3bd1a5a31c0d72950dd6e2e3870c6c5819a6c3db740e91ebbbc5ef4800121023f3d3b8e74b807e32217dea2c75c8d0bd46b8665b3a2d9b3cb310959de52a09bc9d20700").unwrap();
+        let tx = Tx::parse(&mut std::io::Cursor::new(&raw)).unwrap();
+        assert_eq!(
+            estimate_weight(&[SpendType::P2shP2wpkh], &tx.tx_outs, false),
+            tx.weight()
+        );
+        assert_eq!(
+            estimate_weight(&[SpendType::P2shP2wpkh], &tx.tx_outs, true),
+            tx.weight() - 1
+        );
+    }
+
+    #[test]
+    fn test_multisig_input_weight() {
+        // 2-of-3: 105 byte script, scriptSig of 1 + 2 * 73 + 2 + 105 bytes
+        assert_eq!(
+            SpendType::P2shMultisig { m: 2, n: 3 }.input_weight(),
+            (40 + 3 + 254) * 4
+        );
+        // witness: count, empty dummy, two signatures and the script
+        assert_eq!(
+            SpendType::P2wshMultisig { m: 2, n: 3 }.input_weight(),
+            41 * 4 + 1 + 1 + 2 * 73 + 1 + 105
+        );
+        assert_eq!(
+            SpendType::P2wshMultisig { m: 2, n: 3 }.max_input_weight(true),
+            SpendType::P2wshMultisig { m: 2, n: 3 }.input_weight() - 2
+        );
+    }
+}
@@ -0,0 +1,542 @@
+use crate::{
+    block::Block,
+    header_chain::ChainParams,
+    muhash::MuHash3072,
+    store::{KeyValueStore, WriteBatch},
+    tx::{OutPoint, TxOut},
+    varint::{encode_varint, read_varint},
+};
+use std::{
+    collections::HashMap,
+    io::{self, Cursor, Read},
+};
+
+const COIN_PREFIX: u8 = b'c';
+const UNDO_PREFIX: u8 = b'u';
+const STATE_KEY: &[u8] = b"s";
+
+/// From this height BIP34 no longer rules out duplicate transactions, since
+/// coinbases of blocks before it may start with heights this large.
+const BIP34_IMPLIES_BIP30_LIMIT: u32 = 1_983_702;
+
+/// The two mainnet blocks whose coinbases repeat earlier unspent ones,
+/// which they overwrite, as Bitcoin Core's `IsBIP30Repeat`.
+const BIP30_REPEATS: [(u32, &str); 2] = [
+    (
+        91_842,
+        "00000000000a4d0a398161ffc163c503763b1f4360639393e0e4c8e300e0caec",
+    ),
+    (
+        91_880,
+        "00000000000743f190a18c5577a3c2d2a1f610ae9601ac046a38084ccb7cd721",
+    ),
+];
+
+/// Whether a block at `height` must not create outputs that already exist
+/// unspent (BIP30). Once BIP34 puts the height in every coinbase, it cannot
+/// happen until `BIP34_IMPLIES_BIP30_LIMIT`, so the lookups are skipped.
+fn enforce_bip30(block_hash: &[u8; 32], height: u32, params: &ChainParams) -> bool {
+    if height >= BIP34_IMPLIES_BIP30_LIMIT {
+        return true;
+    }
+    let mut id = *block_hash;
+    id.reverse();
+    let id = hex::encode(id);
+    height < params.bip34_height
+        && !BIP30_REPEATS
+            .iter()
+            .any(|(repeat_height, repeat)| height == *repeat_height && id == *repeat)
+}
+
+/// An unspent output with the height of the block that created it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Coin {
+    pub tx_out: TxOut,
+    pub height: u32,
+    pub is_coinbase: bool,
+}
+
+impl Coin {
+    pub fn new(tx_out: TxOut, height: u32, is_coinbase: bool) -> Self {
+        Self {
+            tx_out,
+            height,
+            is_coinbase,
+        }
+    }
+
+    pub fn parse<R: Read>(stream: &mut R) -> io::Result<Self> {
+        let mut code = [0; 4];
+        stream.read_exact(&mut code)?;
+        let code = u32::from_le_bytes(code);
+        Ok(Self {
+            tx_out: TxOut::parse(stream)?,
+            height: code >> 1,
+            is_coinbase: code & 1 == 1,
+        })
+    }
+
+    /// Height and coinbase flag as `height * 2 + is_coinbase`, then the
+    /// output, as Bitcoin Core hashes coins into MuHash.
+    pub fn serialize(&self) -> Vec<u8> {
+        let code = self.height << 1 | self.is_coinbase as u32;
+        [code.to_le_bytes().to_vec(), self.tx_out.serialize()].concat()
+    }
+}
+
+/// The coins a block spent, in the order of its inputs, to put back when it
+/// is disconnected.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BlockUndo {
+    pub spent: Vec<Coin>,
+}
+
+impl BlockUndo {
+    pub fn parse<R: Read>(stream: &mut R) -> io::Result<Self> {
+        let mut spent = Vec::new();
+        for _ in 0..read_varint(stream)? {
+            spent.push(Coin::parse(stream)?);
+        }
+        Ok(Self { spent })
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut ret = encode_varint(self.spent.len() as u64);
+        for coin in &self.spent {
+            ret.extend(coin.serialize());
+        }
+        ret
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UtxoError {
+    Io(io::ErrorKind),
+    /// The block does not build on the best block, or is not the best block
+    /// when disconnecting.
+    NotTip,
+    /// An input spends an output that does not exist or is already spent.
+    MissingInput(OutPoint),
+    /// An output would overwrite an unspent one with the same outpoint.
+    DuplicateOutput(OutPoint),
+    /// Undo data is missing or does not fit the block being disconnected.
+    BadUndo,
+}
+
+impl From<io::Error> for UtxoError {
+    fn from(e: io::Error) -> Self {
+        UtxoError::Io(e.kind())
+    }
+}
+
+fn coin_key(outpoint: &OutPoint) -> Vec<u8> {
+    [&[COIN_PREFIX][..], &outpoint.serialize()].concat()
+}
+
+fn undo_key(block_hash: &[u8; 32]) -> Vec<u8> {
+    [&[UNDO_PREFIX][..], block_hash].concat()
+}
+
+/// The unspent outputs as of the best block, kept in a `KeyValueStore`.
+///
+/// Blocks are connected in order from genesis and disconnected from the
+/// tip using the undo data stored for each, so reorgs can walk back to the
+/// fork point. Each block is written as one batch along with the best
+/// block, the coin count and a MuHash of the set, so the store is never left
+/// halfway through a block.
+///
+/// Scripts are not checked here: spending only needs the coin to exist.
+pub struct UtxoSet<S> {
+    store: S,
+    best_block: [u8; 32],
+    height: Option<u32>,
+    count: u64,
+    muhash: MuHash3072,
+}
+
+impl<S: KeyValueStore> UtxoSet<S> {
+    /// Loads the set from `store`, empty if nothing was connected yet.
+    pub fn open(store: S) -> io::Result<Self> {
+        let mut ret = Self {
+            store,
+            best_block: [0; 32],
+            height: None,
+            count: 0,
+            muhash: MuHash3072::new(),
+        };
+        if let Some(state) = ret.store.get(STATE_KEY)? {
+            let mut stream = Cursor::new(state);
+            let mut best_block = [0; 32];
+            stream.read_exact(&mut best_block)?;
+            let mut height = [0; 4];
+            stream.read_exact(&mut height)?;
+            let mut count = [0; 8];
+            stream.read_exact(&mut count)?;
+            ret.best_block = best_block;
+            ret.height = u32::from_le_bytes(height).checked_sub(1);
+            ret.count = u64::from_le_bytes(count);
+            ret.muhash = MuHash3072::parse(&mut stream)?;
+        }
+        Ok(ret)
+    }
+
+    pub fn get_store(&self) -> &S {
+        &self.store
+    }
+
+    /// The hash of the last connected block, zero before genesis.
+    pub fn get_best_block(&self) -> [u8; 32] {
+        self.best_block
+    }
+
+    pub fn height(&self) -> Option<u32> {
+        self.height
+    }
+
+    /// The number of unspent outputs.
+    pub fn len(&self) -> u64 {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    pub fn get(&self, outpoint: &OutPoint) -> io::Result<Option<Coin>> {
+        self.store
+            .get(&coin_key(outpoint))?
+            .map(|raw| Coin::parse(&mut Cursor::new(raw)))
+            .transpose()
+    }
+
+    pub fn get_undo(&self, block_hash: &[u8; 32]) -> io::Result<Option<BlockUndo>> {
+        self.store
+            .get(&undo_key(block_hash))?
+            .map(|raw| BlockUndo::parse(&mut Cursor::new(raw)))
+            .transpose()
+    }
+
+    /// Bitcoin Core's `muhash` from `gettxoutsetinfo`, in internal byte
+    /// order: each coin hashed as its outpoint followed by the coin.
+    pub fn muhash(&self) -> [u8; 32] {
+        self.muhash.finalize()
+    }
+
+    /// A coin as seen after the pending `changes`.
+    fn lookup(
+        &self,
+        changes: &HashMap<OutPoint, Option<Coin>>,
+        outpoint: &OutPoint,
+    ) -> io::Result<Option<Coin>> {
+        match changes.get(outpoint) {
+            Some(coin) => Ok(coin.clone()),
+            None => self.get(outpoint),
+        }
+    }
+
+    /// Writes `changes` with the new tip, and the undo data to put or
+    /// delete, in one batch.
+    fn commit(
+        &mut self,
+        changes: HashMap<OutPoint, Option<Coin>>,
+        undo: (Vec<u8>, Option<Vec<u8>>),
+        best_block: [u8; 32],
+        height: Option<u32>,
+    ) -> io::Result<()> {
+        let mut muhash = self.muhash;
+        let mut count = self.count;
+        let mut batch = WriteBatch::new();
+        for (outpoint, coin) in changes {
+            let key = coin_key(&outpoint);
+            if let Some(old) = self.get(&outpoint)? {
+                muhash.remove(&[outpoint.serialize(), old.serialize()].concat());
+                count -= 1;
+            }
+            match coin {
+                Some(coin) => {
+                    muhash.insert(&[outpoint.serialize(), coin.serialize()].concat());
+                    count += 1;
+                    batch.put(key, coin.serialize());
+                }
+                None => batch.delete(key),
+            }
+        }
+        batch.ops.push(undo);
+        let mut state = best_block.to_vec();
+        state.extend(height.map_or(0, |height| height + 1).to_le_bytes());
+        state.extend(count.to_le_bytes());
+        state.extend(muhash.serialize());
+        batch.put(STATE_KEY.to_vec(), state);
+        self.store.write(batch)?;
+        self.best_block = best_block;
+        self.height = height;
+        self.count = count;
+        self.muhash = muhash;
+        Ok(())
+    }
+
+    /// Spends the block's inputs and adds its outputs, returning the undo
+    /// data, which is also stored. Nothing changes on error.
+    ///
+    /// Unspendable outputs are never added, nor, as in Bitcoin Core, are
+    /// the genesis block's. Where BIP30 is not enforced, a duplicate output
+    /// overwrites the existing coin.
+    pub fn connect_block(
+        &mut self,
+        block: &Block,
+        params: &ChainParams,
+    ) -> Result<BlockUndo, UtxoError> {
+        if block.header.prev_block != self.best_block {
+            return Err(UtxoError::NotTip);
+        }
+        let height = self.height.map_or(0, |height| height + 1);
+        let bip30 = enforce_bip30(&block.hash(), height, params);
+        let mut changes = HashMap::new();
+        let mut undo = BlockUndo::default();
+        let txs = if height > 0 { &block.txs[..] } else { &[] };
+        for tx in txs {
+            if !tx.is_coinbase() {
+                for tx_in in &tx.tx_ins {
+                    let coin = self
+                        .lookup(&changes, &tx_in.prev_out)?
+                        .ok_or(UtxoError::MissingInput(tx_in.prev_out))?;
+                    undo.spent.push(coin);
+                    changes.insert(tx_in.prev_out, None);
+                }
+            }
+            let txid = tx.txid();
+            for (vout, tx_out) in tx.tx_outs.iter().enumerate() {
+                if tx_out.script_pubkey.is_unspendable() {
+                    continue;
+                }
+                let outpoint = OutPoint::new(txid, vout as u32);
+                if bip30 && self.lookup(&changes, &outpoint)?.is_some() {
+                    return Err(UtxoError::DuplicateOutput(outpoint));
+                }
+                let coin = Coin::new(tx_out.clone(), height, tx.is_coinbase());
+                changes.insert(outpoint, Some(coin));
+            }
+        }
+        let hash = block.hash();
+        let undo_entry = (undo_key(&hash), Some(undo.serialize()));
+        self.commit(changes, undo_entry, hash, Some(height))?;
+        Ok(undo)
+    }
+
+    /// Undoes the best block with its stored undo data, making its parent
+    /// the best block.
+    pub fn disconnect_block(&mut self, block: &Block) -> Result<(), UtxoError> {
+        let hash = block.hash();
+        if hash != self.best_block {
+            return Err(UtxoError::NotTip);
+        }
+        let undo = self.get_undo(&hash)?.ok_or(UtxoError::BadUndo)?;
+        let height = self.height.and_then(|height| height.checked_sub(1));
+        let mut changes = HashMap::new();
+        let mut spent = undo.spent.into_iter().rev();
+        let txs = if self.height > Some(0) {
+            &block.txs[..]
+        } else {
+            &[]
+        };
+        // later transactions may spend earlier ones, so go backwards
+        for tx in txs.iter().rev() {
+            let txid = tx.txid();
+            for (vout, tx_out) in tx.tx_outs.iter().enumerate() {
+                if !tx_out.script_pubkey.is_unspendable() {
+                    changes.insert(OutPoint::new(txid, vout as u32), None);
+                }
+            }
+            if !tx.is_coinbase() {
+                for tx_in in tx.tx_ins.iter().rev() {
+                    let coin = spent.next().ok_or(UtxoError::BadUndo)?;
+                    changes.insert(tx_in.prev_out, Some(coin));
+                }
+            }
+        }
+        if spent.next().is_some() {
+            return Err(UtxoError::BadUndo);
+        }
+        let undo_entry = (undo_key(&hash), None);
+        self.commit(changes, undo_entry, block.header.prev_block, height)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        block_header::BlockHeader,
+        network::Network,
+        script::{Command, Script, OP_RETURN},
+        store::{FileStore, MemoryStore},
+        tx::{Tx, TxIn, SEQUENCE_FINAL},
+    };
+    use std::fs;
+
+    fn child(prev: &Block, height: i64, spends: Vec<Tx>) -> Block {
+        let mut coinbase_in = TxIn::new(OutPoint::null(), SEQUENCE_FINAL);
+        coinbase_in.script_sig = Script::new(&[Command::int(height)]);
+        let coinbase = Tx::new(
+            2,
+            vec![coinbase_in],
+            vec![
+                TxOut::new(50_0000_0000, Script::p2wpkh(&[height as u8; 20])),
+                TxOut::new(0, Script::new(&[Command::Op(OP_RETURN)])),
+            ],
+            0,
+        );
+        let header = BlockHeader::new(4, prev.hash(), [0; 32], 0, 0x207fffff, 0);
+        Block::new(header, [vec![coinbase], spends].concat())
+    }
+
+    fn spend(prev_out: OutPoint, amount: u64) -> Tx {
+        Tx::new(
+            2,
+            vec![TxIn::new(prev_out, SEQUENCE_FINAL)],
+            vec![TxOut::new(amount, Script::p2wpkh(&[9; 20]))],
+            0,
+        )
+    }
+
+    /// Genesis and two blocks, the second spending the first's coinbase
+    /// and, within the block, the output of that spend.
+    fn blocks() -> Vec<Block> {
+        let genesis = Block::genesis(Network::Regtest);
+        let a = child(&genesis, 1, vec![]);
+        let first = spend(OutPoint::new(a.txs[0].txid(), 0), 40_0000_0000);
+        let second = spend(OutPoint::new(first.txid(), 0), 30_0000_0000);
+        let b = child(&a, 2, vec![first, second]);
+        vec![genesis, a, b]
+    }
+
+    #[test]
+    fn test_connect_disconnect() {
+        let blocks = blocks();
+        // BIP30 is enforced below this height
+        let params = ChainParams {
+            bip34_height: 100,
+            ..ChainParams::new(Network::Regtest)
+        };
+        let mut utxos = UtxoSet::open(MemoryStore::new()).unwrap();
+        let empty = utxos.muhash();
+        assert_eq!(utxos.disconnect_block(&blocks[0]), Err(UtxoError::NotTip));
+        assert_eq!(
+            utxos.connect_block(&blocks[1], &params),
+            Err(UtxoError::NotTip)
+        );
+        utxos.connect_block(&blocks[0], &params).unwrap();
+        assert_eq!(utxos.height(), Some(0));
+        assert!(utxos.is_empty());
+        assert_eq!(utxos.muhash(), empty);
+
+        utxos.connect_block(&blocks[1], &params).unwrap();
+        let after_a = utxos.muhash();
+        let coinbase_a = OutPoint::new(blocks[1].txs[0].txid(), 0);
+        assert_eq!(utxos.len(), 1);
+        assert_eq!(
+            utxos.get(&coinbase_a).unwrap(),
+            Some(Coin::new(blocks[1].txs[0].tx_outs[0].clone(), 1, true))
+        );
+        // the OP_RETURN output is left out
+        let op_return = OutPoint::new(blocks[1].txs[0].txid(), 1);
+        assert_eq!(utxos.get(&op_return).unwrap(), None);
+
+        let undo = utxos.connect_block(&blocks[2], &params).unwrap();
+        assert_eq!(utxos.height(), Some(2));
+        assert_eq!(utxos.get_best_block(), blocks[2].hash());
+        assert_eq!(utxos.len(), 2);
+        assert_eq!(utxos.get(&coinbase_a).unwrap(), None);
+        assert_eq!(undo.spent.len(), 2);
+        assert_eq!(undo.spent[0].height, 1);
+        assert_eq!(undo.spent[1].height, 2);
+        assert_eq!(utxos.get_undo(&blocks[2].hash()).unwrap(), Some(undo));
+
+        // the MuHash is of the set, not of the history that built it
+        let mut muhash = MuHash3072::new();
+        for (i, tx) in blocks[2].txs.iter().enumerate().step_by(2) {
+            let coin = Coin::new(tx.tx_outs[0].clone(), 2, i == 0);
+            muhash.insert(&[OutPoint::new(tx.txid(), 0).serialize(), coin.serialize()].concat());
+        }
+        assert_eq!(utxos.muhash(), muhash.finalize());
+
+        utxos.disconnect_block(&blocks[2]).unwrap();
+        assert_eq!(utxos.height(), Some(1));
+        assert_eq!(utxos.len(), 1);
+        assert_eq!(utxos.muhash(), after_a);
+        assert_eq!(utxos.get_undo(&blocks[2].hash()).unwrap(), None);
+        assert!(utxos.get(&coinbase_a).unwrap().is_some());
+
+        // a double spend leaves the set untouched
+        let mut bad = blocks[2].clone();
+        bad.txs.push(spend(coinbase_a, 1));
+        assert_eq!(
+            utxos.connect_block(&bad, &params),
+            Err(UtxoError::MissingInput(coinbase_a))
+        );
+        assert_eq!(utxos.get_best_block(), blocks[1].hash());
+        assert_eq!(utxos.muhash(), after_a);
+        // the same coinbase again
+        let duplicate = child(&blocks[1], 1, vec![]);
+        assert_eq!(
+            utxos.connect_block(&duplicate, &params),
+            Err(UtxoError::DuplicateOutput(coinbase_a))
+        );
+
+        // after BIP34 the check is skipped and the coin is overwritten
+        let params = ChainParams::new(Network::Regtest);
+        utxos.connect_block(&duplicate, &params).unwrap();
+        assert_eq!(utxos.len(), 1);
+        assert_eq!(utxos.get(&coinbase_a).unwrap().unwrap().height, 2);
+    }
+
+    #[test]
+    fn test_bip30_repeats() {
+        let params = ChainParams::new(Network::Mainnet);
+        let hash = |id: &str| {
+            let mut hash: [u8; 32] = hex::decode(id).unwrap().try_into().unwrap();
+            hash.reverse();
+            hash
+        };
+        let (height, id) = BIP30_REPEATS[0];
+        assert!(!enforce_bip30(&hash(id), height, &params));
+        assert!(enforce_bip30(&hash(id), height + 1, &params));
+        assert!(enforce_bip30(&hash(BIP30_REPEATS[1].1), height, &params));
+        let (height, id) = BIP30_REPEATS[1];
+        assert!(!enforce_bip30(&hash(id), height, &params));
+        // skipped from BIP34 activation up to the limit
+        let other = [0; 32];
+        assert!(enforce_bip30(&other, params.bip34_height - 1, &params));
+        assert!(!enforce_bip30(&other, params.bip34_height, &params));
+        assert!(enforce_bip30(&other, BIP34_IMPLIES_BIP30_LIMIT, &params));
+    }
+
+    #[test]
+    fn test_file_backed() {
+        let blocks = blocks();
+        let path = std::env::temp_dir().join(format!("utxo-{}.log", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let params = ChainParams::new(Network::Regtest);
+        let mut utxos = UtxoSet::open(FileStore::open(&path).unwrap()).unwrap();
+        for block in &blocks {
+            utxos.connect_block(block, &params).unwrap();
+        }
+        let muhash = utxos.muhash();
+        drop(utxos);
+
+        let mut utxos = UtxoSet::open(FileStore::open(&path).unwrap()).unwrap();
+        assert_eq!(utxos.height(), Some(2));
+        assert_eq!(utxos.get_best_block(), blocks[2].hash());
+        assert_eq!(utxos.len(), 2);
+        assert_eq!(utxos.muhash(), muhash);
+        for block in blocks.iter().rev() {
+            utxos.disconnect_block(block).unwrap();
+        }
+        assert_eq!(utxos.height(), None);
+        assert_eq!(utxos.get_best_block(), [0; 32]);
+        assert!(utxos.is_empty());
+        assert_eq!(utxos.muhash(), MuHash3072::new().finalize());
+        fs::remove_file(path).unwrap();
+    }
+}
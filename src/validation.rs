@@ -0,0 +1,396 @@
+use crate::{
+    header_chain::{ChainParams, HeaderChain},
+    interpreter::*,
+    store::KeyValueStore,
+    tx::{OutPoint, Tx, TxOut, MAX_MONEY, SEQUENCE_FINAL},
+    utxo::{Coin, UtxoSet},
+};
+use std::{collections::HashMap, io};
+
+/// Blocks a coinbase output has to wait before it can be spent.
+pub const COINBASE_MATURITY: u32 = 100;
+
+/// Where `check_tx_inputs` looks up the coins being spent.
+pub trait UtxoView {
+    fn get_coin(&self, outpoint: &OutPoint) -> io::Result<Option<Coin>>;
+}
+
+impl<S: KeyValueStore> UtxoView for UtxoSet<S> {
+    fn get_coin(&self, outpoint: &OutPoint) -> io::Result<Option<Coin>> {
+        self.get(outpoint)
+    }
+}
+
+impl UtxoView for HashMap<OutPoint, Coin> {
+    fn get_coin(&self, outpoint: &OutPoint) -> io::Result<Option<Coin>> {
+        Ok(self.get(outpoint).cloned())
+    }
+}
+
+/// Why a transaction cannot be spent in a given block. Per-input rules
+/// carry the index of the failing input.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TxValidationError {
+    Io(io::ErrorKind),
+    /// The spending height is zero or more than one past the tip.
+    InvalidHeight(u32),
+    /// The input's coin is from a block after the spending height.
+    CoinFromFuture(usize),
+    /// The input's outpoint is not in the UTXO view, or already spent.
+    MissingInput(usize),
+    /// The input spends a coinbase output fewer than 100 blocks deep.
+    PrematureCoinbaseSpend(usize),
+    /// The input's value, or the running total, exceeds `MAX_MONEY`.
+    InputValueOutOfRange(usize),
+    /// The outputs are worth more than the inputs.
+    InputsBelowOutputs {
+        input_value: u64,
+        output_value: u64,
+    },
+    /// The nLockTime has not passed.
+    NonFinal,
+    /// The input's BIP68 relative lock has not passed.
+    SequenceLocked(usize),
+    /// The input's scripts failed.
+    Script {
+        input: usize,
+        error: ScriptError,
+    },
+}
+
+impl From<io::Error> for TxValidationError {
+    fn from(e: io::Error) -> Self {
+        TxValidationError::Io(e.kind())
+    }
+}
+
+/// The consensus script flags for a block at `height`, like Bitcoin Core's
+/// `GetBlockScriptFlags`. P2SH, segwit and taproot are enforced everywhere,
+/// which the historical chain satisfies except for two mainnet blocks Core
+/// exempts by hash: 00000000000002dc... at 170,060 from P2SH, and
+/// 0000000000000000000f14c3... at 692,261 from taproot.
+pub fn script_flags(params: &ChainParams, height: u32) -> u32 {
+    let mut flags = VERIFY_P2SH | VERIFY_WITNESS | VERIFY_TAPROOT;
+    if height >= params.bip66_height {
+        flags |= VERIFY_DERSIG;
+    }
+    if height >= params.bip65_height {
+        flags |= VERIFY_CHECKLOCKTIMEVERIFY;
+    }
+    if height >= params.csv_height {
+        flags |= VERIFY_CHECKSEQUENCEVERIFY;
+    }
+    if height >= params.segwit_height {
+        flags |= VERIFY_NULLDUMMY;
+    }
+    flags
+}
+
+/// Whether the nLockTime allows `tx` in a block at `height` whose previous
+/// block has median time past `time` (BIP113).
+pub fn is_final(tx: &Tx, height: u32, time: u32) -> bool {
+    let cutoff = if tx.locktime < LOCKTIME_THRESHOLD {
+        height
+    } else {
+        time
+    };
+    tx.locktime < cutoff
+        || tx
+            .tx_ins
+            .iter()
+            .all(|tx_in| tx_in.sequence == SEQUENCE_FINAL)
+}
+
+/// Median time past of the active block at `height`, which callers keep at
+/// most the tip.
+fn median_time_past(chain: &HeaderChain, height: u32) -> Option<u32> {
+    let header = chain.get_header(height)?;
+    Some(chain.median_time_past(&header.hash()))
+}
+
+/// Bitcoin Core's `CheckTxInputs` followed by the script checks: whether
+/// `tx` can spend `view`'s coins in a block at `height` on the active chain
+/// of `chain`, returning the fee.
+///
+/// `height` can be at most one past the tip, the next block for a mempool,
+/// and no coin can be from a later block.
+/// Locktimes are compared against the previous block's median time past,
+/// and BIP68 is only enforced with `VERIFY_CHECKSEQUENCEVERIFY`. Coinbases
+/// are not checked here; their only input is missing.
+pub fn check_tx_inputs<V: UtxoView>(
+    tx: &Tx,
+    view: &V,
+    chain: &HeaderChain,
+    height: u32,
+    flags: u32,
+) -> Result<u64, TxValidationError> {
+    if height == 0 || height > chain.height().saturating_add(1) {
+        return Err(TxValidationError::InvalidHeight(height));
+    }
+    let mut coins = Vec::with_capacity(tx.tx_ins.len());
+    let mut input_value = 0u64;
+    for (i, tx_in) in tx.tx_ins.iter().enumerate() {
+        let coin = view
+            .get_coin(&tx_in.prev_out)?
+            .ok_or(TxValidationError::MissingInput(i))?;
+        if coin.height > height {
+            return Err(TxValidationError::CoinFromFuture(i));
+        }
+        if coin.is_coinbase && height - coin.height < COINBASE_MATURITY {
+            return Err(TxValidationError::PrematureCoinbaseSpend(i));
+        }
+        let amount = coin.tx_out.amount;
+        input_value = match input_value.checked_add(amount) {
+            Some(total) if amount <= MAX_MONEY && total <= MAX_MONEY => total,
+            _ => return Err(TxValidationError::InputValueOutOfRange(i)),
+        };
+        coins.push(coin);
+    }
+    let output_value = tx
+        .tx_outs
+        .iter()
+        .fold(0u64, |sum, tx_out| sum.saturating_add(tx_out.amount));
+    if input_value < output_value {
+        return Err(TxValidationError::InputsBelowOutputs {
+            input_value,
+            output_value,
+        });
+    }
+
+    let time =
+        median_time_past(chain, height - 1).ok_or(TxValidationError::InvalidHeight(height))?;
+    if !is_final(tx, height, time) {
+        return Err(TxValidationError::NonFinal);
+    }
+    if flags & VERIFY_CHECKSEQUENCEVERIFY != 0 && tx.version as i32 >= 2 {
+        for (i, (tx_in, coin)) in tx.tx_ins.iter().zip(&coins).enumerate() {
+            if tx_in.sequence & SEQUENCE_LOCKTIME_DISABLE_FLAG != 0 {
+                continue;
+            }
+            let value = tx_in.sequence & SEQUENCE_LOCKTIME_MASK;
+            let locked = if tx_in.sequence & SEQUENCE_LOCKTIME_TYPE_FLAG != 0 {
+                // counted from the median time past before the coin's block
+                let coin_time = median_time_past(chain, coin.height.saturating_sub(1))
+                    .ok_or(TxValidationError::CoinFromFuture(i))?
+                    as u64;
+                coin_time + ((value as u64) << SEQUENCE_LOCKTIME_GRANULARITY) > time as u64
+            } else {
+                coin.height + value > height
+            };
+            if locked {
+                return Err(TxValidationError::SequenceLocked(i));
+            }
+        }
+    }
+
+    let spent_outputs: Vec<TxOut> = coins.iter().map(|coin| coin.tx_out.clone()).collect();
+    for (i, (tx_in, coin)) in tx.tx_ins.iter().zip(&coins).enumerate() {
+        let checker = TxChecker::with_spent_outputs(tx, i, &spent_outputs);
+        verify_script(
+            &tx_in.script_sig,
+            &coin.tx_out.script_pubkey,
+            &tx_in.witness,
+            flags,
+            &checker,
+        )
+        .map_err(|error| TxValidationError::Script { input: i, error })?;
+    }
+    Ok(input_value - output_value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        block_header::BlockHeader,
+        network::Network,
+        script::{Command, Script},
+        tx::{TxIn, TxOut},
+    };
+
+    /// A regtest chain of 110 blocks ten minutes apart.
+    fn chain() -> HeaderChain {
+        let mut chain = HeaderChain::new(ChainParams::new(Network::Regtest));
+        for _ in 0..110 {
+            let tip = chain.tip();
+            let mut header =
+                BlockHeader::new(4, tip.hash(), [0; 32], tip.timestamp + 600, tip.bits, 0);
+            while !header.check_pow() {
+                header.nonce += 1;
+            }
+            chain.add_header(header).unwrap();
+        }
+        chain
+    }
+
+    fn coin(amount: u64, script_pubkey: Script, height: u32, is_coinbase: bool) -> Coin {
+        Coin::new(TxOut::new(amount, script_pubkey), height, is_coinbase)
+    }
+
+    fn anyone() -> Script {
+        Script::new(&[Command::int(1)])
+    }
+
+    fn spend(prev_outs: &[(OutPoint, u32)], amount: u64, locktime: u32) -> Tx {
+        let tx_ins = prev_outs
+            .iter()
+            .map(|(prev_out, sequence)| TxIn::new(*prev_out, *sequence))
+            .collect();
+        Tx::new(2, tx_ins, vec![TxOut::new(amount, anyone())], locktime)
+    }
+
+    #[test]
+    fn test_check_tx_inputs() {
+        let chain = chain();
+        let flags = script_flags(chain.get_params(), 111);
+        let coinbase = OutPoint::new([1; 32], 0);
+        let plain = OutPoint::new([2; 32], 0);
+        let burned = OutPoint::new([3; 32], 0);
+        let view: HashMap<_, _> = [
+            (coinbase, coin(50_0000_0000, anyone(), 5, true)),
+            (plain, coin(1_0000_0000, anyone(), 100, false)),
+            (
+                burned,
+                coin(1000, Script::new(&[Command::int(0)]), 100, false),
+            ),
+        ]
+        .into();
+
+        let tx = spend(
+            &[(plain, SEQUENCE_FINAL), (coinbase, SEQUENCE_FINAL)],
+            1000,
+            0,
+        );
+        assert_eq!(
+            check_tx_inputs(&tx, &view, &chain, 104, flags),
+            Err(TxValidationError::PrematureCoinbaseSpend(1))
+        );
+        assert_eq!(
+            check_tx_inputs(&tx, &view, &chain, 105, flags),
+            Ok(51_0000_0000 - 1000)
+        );
+        let tx = spend(
+            &[(plain, SEQUENCE_FINAL), (OutPoint::new([4; 32], 0), 0)],
+            0,
+            0,
+        );
+        assert_eq!(
+            check_tx_inputs(&tx, &view, &chain, 111, flags),
+            Err(TxValidationError::MissingInput(1))
+        );
+        let tx = spend(&[(plain, SEQUENCE_FINAL)], 1_0000_0001, 0);
+        assert_eq!(
+            check_tx_inputs(&tx, &view, &chain, 111, flags),
+            Err(TxValidationError::InputsBelowOutputs {
+                input_value: 1_0000_0000,
+                output_value: 1_0000_0001
+            })
+        );
+        let mut huge = view.clone();
+        huge.insert(plain, coin(MAX_MONEY + 1, anyone(), 100, false));
+        let tx = spend(&[(plain, SEQUENCE_FINAL)], 0, 0);
+        assert_eq!(
+            check_tx_inputs(&tx, &huge, &chain, 111, flags),
+            Err(TxValidationError::InputValueOutOfRange(0))
+        );
+        // the running total is checked on every input, without overflowing
+        huge.insert(plain, coin(MAX_MONEY, anyone(), 100, false));
+        huge.insert(burned, coin(u64::MAX, anyone(), 100, false));
+        let tx = spend(&[(plain, SEQUENCE_FINAL), (burned, SEQUENCE_FINAL)], 0, 0);
+        assert_eq!(
+            check_tx_inputs(&tx, &huge, &chain, 111, flags),
+            Err(TxValidationError::InputValueOutOfRange(1))
+        );
+        huge.insert(burned, coin(1, anyone(), 100, false));
+        assert_eq!(
+            check_tx_inputs(&tx, &huge, &chain, 111, flags),
+            Err(TxValidationError::InputValueOutOfRange(1))
+        );
+
+        // heights the chain cannot have, or coins from later blocks
+        let tx = spend(&[(plain, SEQUENCE_FINAL)], 0, 0);
+        for height in [0, 112, u32::MAX] {
+            assert_eq!(
+                check_tx_inputs(&tx, &view, &chain, height, flags),
+                Err(TxValidationError::InvalidHeight(height))
+            );
+        }
+        assert_eq!(
+            check_tx_inputs(&tx, &view, &chain, 99, flags),
+            Err(TxValidationError::CoinFromFuture(0))
+        );
+
+        let tx = spend(&[(plain, SEQUENCE_FINAL), (burned, SEQUENCE_FINAL)], 0, 0);
+        assert_eq!(
+            check_tx_inputs(&tx, &view, &chain, 111, flags),
+            Err(TxValidationError::Script {
+                input: 1,
+                error: ScriptError::EvalFalse
+            })
+        );
+    }
+
+    #[test]
+    fn test_locktimes() {
+        let chain = chain();
+        let flags = script_flags(chain.get_params(), 111);
+        let prev_out = OutPoint::new([1; 32], 0);
+        let view: HashMap<_, _> = [(prev_out, coin(1000, anyone(), 100, false))].into();
+        let check = |tx: &Tx, flags| check_tx_inputs(tx, &view, &chain, 111, flags);
+
+        let disabled = SEQUENCE_LOCKTIME_DISABLE_FLAG;
+        assert!(check(&spend(&[(prev_out, disabled)], 0, 110), flags).is_ok());
+        assert_eq!(
+            check(&spend(&[(prev_out, disabled)], 0, 111), flags),
+            Err(TxValidationError::NonFinal)
+        );
+        assert!(check(&spend(&[(prev_out, SEQUENCE_FINAL)], 0, 111), flags).is_ok());
+        // timestamps are against the median time past of the tip
+        let time = chain.median_time_past(&chain.tip().hash());
+        assert!(check(&spend(&[(prev_out, disabled)], 0, time - 1), flags).is_ok());
+        assert_eq!(
+            check(&spend(&[(prev_out, disabled)], 0, time), flags),
+            Err(TxValidationError::NonFinal)
+        );
+
+        // BIP68: 11 blocks after block 100 is block 111
+        assert!(check(&spend(&[(prev_out, 11)], 0, 0), flags).is_ok());
+        let locked = spend(&[(prev_out, 12)], 0, 0);
+        assert_eq!(
+            check(&locked, flags),
+            Err(TxValidationError::SequenceLocked(0))
+        );
+        assert!(check(&locked, flags & !VERIFY_CHECKSEQUENCEVERIFY).is_ok());
+        let mut version_one = locked;
+        version_one.version = 1;
+        assert!(check(&version_one, flags).is_ok());
+        // 6600 seconds between the median times past of blocks 99 and 110
+        let by_time = |units| spend(&[(prev_out, SEQUENCE_LOCKTIME_TYPE_FLAG | units)], 0, 0);
+        assert!(check(&by_time(12), flags).is_ok());
+        assert_eq!(
+            check(&by_time(13), flags),
+            Err(TxValidationError::SequenceLocked(0))
+        );
+    }
+
+    #[test]
+    fn test_script_flags() {
+        let params = ChainParams::new(Network::Mainnet);
+        assert_eq!(
+            script_flags(&params, 0),
+            VERIFY_P2SH | VERIFY_WITNESS | VERIFY_TAPROOT
+        );
+        assert_eq!(
+            script_flags(&params, 400_000),
+            VERIFY_P2SH
+                | VERIFY_WITNESS
+                | VERIFY_TAPROOT
+                | VERIFY_DERSIG
+                | VERIFY_CHECKLOCKTIMEVERIFY
+        );
+        assert_eq!(
+            script_flags(&params, 481_824) & VERIFY_NULLDUMMY,
+            VERIFY_NULLDUMMY
+        );
+    }
+}
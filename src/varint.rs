@@ -0,0 +1,60 @@
+use std::io::{self, Read};
+
+/// Reads a variable length integer (CompactSize) from the stream.
+pub fn read_varint<R: Read>(stream: &mut R) -> io::Result<u64> {
+    let mut prefix = [0u8; 1];
+    stream.read_exact(&mut prefix)?;
+    match prefix[0] {
+        0xfd => {
+            let mut buf = [0u8; 2];
+            stream.read_exact(&mut buf)?;
+            Ok(u16::from_le_bytes(buf) as u64)
+        }
+        0xfe => {
+            let mut buf = [0u8; 4];
+            stream.read_exact(&mut buf)?;
+            Ok(u32::from_le_bytes(buf) as u64)
+        }
+        0xff => {
+            let mut buf = [0u8; 8];
+            stream.read_exact(&mut buf)?;
+            Ok(u64::from_le_bytes(buf))
+        }
+        i => Ok(i as u64),
+    }
+}
+
+/// Encodes an integer as a variable length integer (CompactSize).
+pub fn encode_varint(i: u64) -> Vec<u8> {
+    if i < 0xfd {
+        vec![i as u8]
+    } else if i <= 0xffff {
+        [&[0xfd], &(i as u16).to_le_bytes()[..]].concat()
+    } else if i <= 0xffff_ffff {
+        [&[0xfe], &(i as u32).to_le_bytes()[..]].concat()
+    } else {
+        [&[0xff], &i.to_le_bytes()[..]].concat()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_varint() {
+        for (i, expected) in [
+            (0x00, "00"),
+            (0xfc, "fc"),
+            (0xfd, "fdfd00"),
+            (0x1234, "fd3412"),
+            (0x1234_5678, "fe78563412"),
+            (0x1234_5678_9abc, "ffbc9a785634120000"),
+        ] {
+            let encoded = encode_varint(i);
+            assert_eq!(hex::encode(&encoded), expected);
+            assert_eq!(read_varint(&mut Cursor::new(encoded)).unwrap(), i);
+        }
+    }
+}
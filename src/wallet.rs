@@ -0,0 +1,1243 @@
+use crate::{
+    address::Address,
+    bip32::Bip32Error,
+    block::Block,
+    block_filter::BlockFilter,
+    coin_selection::{select_coins, CoinSelectionParams},
+    descriptor::{Descriptor, DescriptorError, DescriptorKey},
+    fee::FeeRate,
+    network::Network,
+    random::Rng,
+    script::{Command, Script},
+    sighash::{
+        legacy_sighash, p2wpkh_script_code, segwit_v0_sighash, taproot_sighash, SIGHASH_ALL,
+        SIGHASH_DEFAULT,
+    },
+    taproot::{sign_schnorr, tweak_private_key},
+    tx::{OutPoint, Tx, TxOut},
+    tx_builder::{estimate_weight, BuilderError, SpendType, TransactionBuilder, Utxo},
+    validation::COINBASE_MATURITY,
+    varint::{encode_varint, read_varint},
+};
+use primitive_types::U256;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::{self, File},
+    io::{self, Cursor, Read, Write},
+    path::{Path, PathBuf},
+};
+
+/// Unused addresses kept derived past the last used one on each keychain.
+pub const DEFAULT_GAP_LIMIT: u32 = 20;
+/// Blocks kept undo data for, so a reorg this deep can still be followed.
+pub const MAX_REORG_DEPTH: u32 = 100;
+/// Version byte leading the wallet file.
+const WALLET_VERSION: u8 = 2;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WalletError {
+    Io(io::ErrorKind),
+    Bip32(Bip32Error),
+    Descriptor(DescriptorError),
+    Builder(BuilderError),
+    /// The wallet file is not one this version can read.
+    Corrupt,
+    /// Only ranged `pkh()`, `wpkh()`, `sh(wpkh())` and `tr()` without a
+    /// tree of a compressed key are supported.
+    UnsupportedDescriptor,
+    /// The descriptors given to unlock are not the wallet's, or hold no
+    /// private keys.
+    DescriptorMismatch,
+    /// The wallet holds no private keys until unlocked.
+    WatchOnly,
+    /// The block does not build on the last scanned one.
+    UnconnectedBlock,
+    /// The block to disconnect is past the undo data kept.
+    ReorgTooDeep,
+    /// No pending transaction has this txid.
+    UnknownTransaction,
+    /// A recipient address belongs to another network.
+    WrongNetwork,
+    /// Coin selection found no set of spendable coins paying for the
+    /// recipients and fees.
+    InsufficientFunds {
+        needed: u64,
+        available: u64,
+    },
+    /// The broadcast callback rejected the transaction.
+    Broadcast(String),
+}
+
+impl From<io::Error> for WalletError {
+    fn from(e: io::Error) -> Self {
+        WalletError::Io(e.kind())
+    }
+}
+
+impl From<Bip32Error> for WalletError {
+    fn from(e: Bip32Error) -> Self {
+        WalletError::Bip32(e)
+    }
+}
+
+impl From<DescriptorError> for WalletError {
+    fn from(e: DescriptorError) -> Self {
+        WalletError::Descriptor(e)
+    }
+}
+
+impl From<BuilderError> for WalletError {
+    fn from(e: BuilderError) -> Self {
+        WalletError::Builder(e)
+    }
+}
+
+/// The two chains of addresses a wallet derives.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Keychain {
+    /// Addresses handed out to receive payments.
+    Receive = 0,
+    /// Addresses the wallet pays its own change to.
+    Change = 1,
+}
+
+impl Keychain {
+    fn from_byte(b: u8) -> Option<Self> {
+        match b {
+            0 => Some(Keychain::Receive),
+            1 => Some(Keychain::Change),
+            _ => None,
+        }
+    }
+}
+
+fn network_byte(network: Network) -> u8 {
+    match network {
+        Network::Mainnet => 0,
+        Network::Testnet => 1,
+        Network::Regtest => 2,
+    }
+}
+
+fn network_from_byte(b: u8) -> Option<Network> {
+    match b {
+        0 => Some(Network::Mainnet),
+        1 => Some(Network::Testnet),
+        2 => Some(Network::Regtest),
+        _ => None,
+    }
+}
+
+fn read_bytes<R: Read, const N: usize>(stream: &mut R) -> io::Result<[u8; N]> {
+    let mut buf = [0; N];
+    stream.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+fn read_string<R: Read>(stream: &mut R) -> Result<String, WalletError> {
+    let length = read_varint(stream)?;
+    let mut raw = Vec::new();
+    stream.take(length).read_to_end(&mut raw)?;
+    if raw.len() as u64 != length {
+        return Err(WalletError::Corrupt);
+    }
+    String::from_utf8(raw).map_err(|_| WalletError::Corrupt)
+}
+
+/// The key of a descriptor the wallet can derive addresses from and sign
+/// for, and how its outputs are spent.
+fn single_key(descriptor: &Descriptor) -> Option<(&DescriptorKey, SpendType)> {
+    let (key, spend_type) = match descriptor {
+        Descriptor::Pkh(key) => (key, SpendType::P2pkh),
+        Descriptor::Wpkh(key) => (key, SpendType::P2wpkh),
+        Descriptor::Sh(inner) => match &**inner {
+            Descriptor::Wpkh(key) => (key, SpendType::P2shP2wpkh),
+            _ => return None,
+        },
+        Descriptor::Tr {
+            internal_key,
+            tree: None,
+        } => (internal_key, SpendType::P2tr),
+        _ => return None,
+    };
+    (key.is_ranged() && key.is_compressed()).then_some((key, spend_type))
+}
+
+/// An output paying one of the wallet's keys.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WalletUtxo {
+    pub outpoint: OutPoint,
+    pub tx_out: TxOut,
+    /// Height of the block that confirmed it, `None` while unconfirmed.
+    pub height: Option<u32>,
+    pub is_coinbase: bool,
+    pub keychain: Keychain,
+    pub index: u32,
+    /// The pending transaction spending it, if any.
+    pub spent_by: Option<[u8; 32]>,
+}
+
+impl WalletUtxo {
+    /// Blocks on top of and including the one that confirmed it, when the
+    /// best block is at `tip`.
+    pub fn confirmations(&self, tip: Option<u32>) -> u32 {
+        match (self.height, tip) {
+            (Some(height), Some(tip)) if tip >= height => tip - height + 1,
+            _ => 0,
+        }
+    }
+
+    /// Whether a coinbase output is still too young to spend. Like Bitcoin
+    /// Core's wallet this waits one block past consensus maturity.
+    pub fn is_immature(&self, tip: Option<u32>) -> bool {
+        self.is_coinbase && self.confirmations(tip) <= COINBASE_MATURITY
+    }
+
+    pub fn parse<R: Read>(stream: &mut R) -> io::Result<Self> {
+        let outpoint = OutPoint::parse(stream)?;
+        let tx_out = TxOut::parse(stream)?;
+        let [flags] = read_bytes(stream)?;
+        let height = u32::from_le_bytes(read_bytes(stream)?);
+        let [keychain] = read_bytes(stream)?;
+        let keychain = Keychain::from_byte(keychain)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidData))?;
+        let index = u32::from_le_bytes(read_bytes(stream)?);
+        let spent_by = if flags & 4 == 4 {
+            Some(read_bytes(stream)?)
+        } else {
+            None
+        };
+        Ok(Self {
+            outpoint,
+            tx_out,
+            height: (flags & 1 == 1).then_some(height),
+            is_coinbase: flags & 2 == 2,
+            keychain,
+            index,
+            spent_by,
+        })
+    }
+
+    /// Outpoint, output, flags for being confirmed, being a coinbase and
+    /// being spent, height, keychain, index and the spending txid.
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut ret = self.outpoint.serialize();
+        ret.extend(self.tx_out.serialize());
+        ret.push(
+            self.height.is_some() as u8
+                | (self.is_coinbase as u8) << 1
+                | (self.spent_by.is_some() as u8) << 2,
+        );
+        ret.extend(self.height.unwrap_or(0).to_le_bytes());
+        ret.push(self.keychain as u8);
+        ret.extend(self.index.to_le_bytes());
+        if let Some(txid) = self.spent_by {
+            ret.extend(txid);
+        }
+        ret
+    }
+}
+
+/// The wallet's unspent coins by state. Unconfirmed coins are the wallet's
+/// own change, or were in a block a reorg took out.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Balance {
+    pub confirmed: u64,
+    pub unconfirmed: u64,
+    /// Coinbase outputs waiting to mature.
+    pub immature: u64,
+}
+
+/// The wallet's transactions in a block, with the coins each spent.
+type BlockUndo = Vec<(Tx, Vec<WalletUtxo>)>;
+
+/// A wallet deriving addresses from a ranged `pkh()`, `wpkh()`,
+/// `sh(wpkh())` or `tr()` descriptor, or from one for receive addresses
+/// and one for change. With a single descriptor change goes to its
+/// addresses too.
+///
+/// Blocks, or the compact filters of blocks to fetch, are scanned in order
+/// for outputs paying derived keys and for spends of the wallet's coins.
+/// `gap_limit` unused keys are kept derived past the last used one on each
+/// keychain so payments to addresses handed out later are still found.
+/// The last `MAX_REORG_DEPTH` blocks can be disconnected in a reorg.
+///
+/// The file holds the public forms of the descriptors, the derived scripts,
+/// the coins, the transactions not yet confirmed and the undo data, and is
+/// replaced atomically by `save`. Private keys are never written: an opened
+/// wallet is watch-only until `unlock` is given the private descriptors.
+pub struct Wallet {
+    path: PathBuf,
+    network: Network,
+    /// Public forms of the receive and, if separate, change descriptors.
+    descriptors: Vec<Descriptor>,
+    /// The descriptors with their private keys, while unlocked.
+    signers: Option<Vec<Descriptor>>,
+    gap_limit: u32,
+    /// The derived output scripts, by descriptor and index.
+    derived: Vec<Vec<Script>>,
+    /// The next address to hand out from each descriptor.
+    next_index: Vec<u32>,
+    scripts: HashMap<Script, (Keychain, u32)>,
+    utxos: BTreeMap<OutPoint, WalletUtxo>,
+    /// Transactions sent, or unconfirmed by a reorg, by txid.
+    pending: BTreeMap<[u8; 32], Tx>,
+    undo: BTreeMap<u32, BlockUndo>,
+    best_block: [u8; 32],
+    height: Option<u32>,
+}
+
+impl Wallet {
+    /// Creates a wallet paying to `receive`, and its change to `change` if
+    /// given, and writes it to `path`, which must not exist yet. The wallet
+    /// is unlocked if the descriptors hold private keys.
+    pub fn create<P: AsRef<Path>>(
+        path: P,
+        receive: &Descriptor,
+        change: Option<&Descriptor>,
+        network: Network,
+        gap_limit: u32,
+    ) -> Result<Self, WalletError> {
+        let path = path.as_ref().to_path_buf();
+        if path.exists() {
+            return Err(WalletError::Io(io::ErrorKind::AlreadyExists));
+        }
+        let given: Vec<Descriptor> = [Some(receive), change]
+            .into_iter()
+            .flatten()
+            .cloned()
+            .collect();
+        let mut descriptors = Vec::new();
+        for descriptor in &given {
+            if single_key(descriptor).is_none() {
+                return Err(WalletError::UnsupportedDescriptor);
+            }
+            descriptors.push(descriptor.to_public()?);
+        }
+        let mut wallet = Self::new(path, network, descriptors, gap_limit.max(1));
+        if given.iter().all(|d| d.has_private_keys()) {
+            wallet.signers = Some(given);
+        }
+        for chain in 0..wallet.descriptors.len() {
+            wallet.top_up(chain)?;
+        }
+        wallet.save()?;
+        Ok(wallet)
+    }
+
+    /// Opens the wallet saved at `path`, watch-only until unlocked.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, WalletError> {
+        let path = path.as_ref().to_path_buf();
+        let raw = fs::read(&path)?;
+        let mut stream = Cursor::new(&raw[..]);
+        let mut wallet = Self::parse(&mut stream, path).map_err(|e| match e {
+            WalletError::Io(io::ErrorKind::UnexpectedEof | io::ErrorKind::InvalidData) => {
+                WalletError::Corrupt
+            }
+            e => e,
+        })?;
+        if stream.position() != raw.len() as u64 {
+            return Err(WalletError::Corrupt);
+        }
+        for chain in 0..wallet.derived.len() {
+            for (index, script) in wallet.derived[chain].iter().enumerate() {
+                let keychain = Keychain::from_byte(chain as u8).unwrap();
+                wallet
+                    .scripts
+                    .insert(script.clone(), (keychain, index as u32));
+            }
+        }
+        Ok(wallet)
+    }
+
+    fn new(path: PathBuf, network: Network, descriptors: Vec<Descriptor>, gap_limit: u32) -> Self {
+        let chains = descriptors.len();
+        Self {
+            path,
+            network,
+            descriptors,
+            signers: None,
+            gap_limit,
+            derived: vec![Vec::new(); chains],
+            next_index: vec![0; chains],
+            scripts: HashMap::new(),
+            utxos: BTreeMap::new(),
+            pending: BTreeMap::new(),
+            undo: BTreeMap::new(),
+            best_block: [0; 32],
+            height: None,
+        }
+    }
+
+    /// Gives the wallet the private keys to sign with. `receive` and
+    /// `change` must be the descriptors it was created from.
+    pub fn unlock(
+        &mut self,
+        receive: &Descriptor,
+        change: Option<&Descriptor>,
+    ) -> Result<(), WalletError> {
+        let given: Vec<Descriptor> = [Some(receive), change]
+            .into_iter()
+            .flatten()
+            .cloned()
+            .collect();
+        let matches = given.len() == self.descriptors.len()
+            && given.iter().zip(&self.descriptors).all(|(d, public)| {
+                d.has_private_keys()
+                    && d.to_public()
+                        .is_ok_and(|d| d.to_string() == public.to_string())
+            });
+        if !matches {
+            return Err(WalletError::DescriptorMismatch);
+        }
+        self.signers = Some(given);
+        Ok(())
+    }
+
+    /// Drops the private keys, leaving the wallet watch-only.
+    pub fn lock(&mut self) {
+        self.signers = None;
+    }
+
+    pub fn is_watch_only(&self) -> bool {
+        self.signers.is_none()
+    }
+
+    /// Public forms of the receive and, if separate, change descriptors.
+    pub fn descriptors(&self) -> &[Descriptor] {
+        &self.descriptors
+    }
+
+    pub fn get_network(&self) -> Network {
+        self.network
+    }
+
+    pub fn get_best_block(&self) -> [u8; 32] {
+        self.best_block
+    }
+
+    /// Height of the last scanned block.
+    pub fn height(&self) -> Option<u32> {
+        self.height
+    }
+
+    pub fn utxos(&self) -> impl Iterator<Item = &WalletUtxo> {
+        self.utxos.values()
+    }
+
+    /// Transactions sent, or taken out of the best chain by a reorg, that
+    /// are not yet in a block.
+    pub fn pending_transactions(&self) -> impl Iterator<Item = &Tx> {
+        self.pending.values()
+    }
+
+    /// The descriptor deriving `keychain`.
+    fn chain(&self, keychain: Keychain) -> usize {
+        (keychain as usize).min(self.descriptors.len() - 1)
+    }
+
+    fn spend_type(&self, chain: usize) -> SpendType {
+        single_key(&self.descriptors[chain]).unwrap().1
+    }
+
+    /// Derives scripts until `gap_limit` of them follow the next address to
+    /// hand out.
+    fn top_up(&mut self, chain: usize) -> Result<(), WalletError> {
+        let keychain = Keychain::from_byte(chain as u8).unwrap();
+        let target = self.next_index[chain] + self.gap_limit;
+        while (self.derived[chain].len() as u32) < target {
+            let index = self.derived[chain].len() as u32;
+            let script = self.descriptors[chain].script_pubkeys(index)?.remove(0);
+            self.scripts.insert(script.clone(), (keychain, index));
+            self.derived[chain].push(script);
+        }
+        Ok(())
+    }
+
+    /// Moves the next address past `index` once it has been handed out or
+    /// seen paid.
+    fn mark_used(&mut self, keychain: Keychain, index: u32) -> Result<(), WalletError> {
+        let chain = self.chain(keychain);
+        if index >= self.next_index[chain] {
+            self.next_index[chain] = index + 1;
+            self.top_up(chain)?;
+        }
+        Ok(())
+    }
+
+    fn address(&self, chain: usize, index: u32) -> Address {
+        Address::from_script_pubkey(&self.derived[chain][index as usize], self.network).unwrap()
+    }
+
+    /// Hands out the next receive address.
+    pub fn new_address(&mut self) -> Result<Address, WalletError> {
+        let index = self.next_index[0];
+        self.mark_used(Keychain::Receive, index)?;
+        Ok(self.address(0, index))
+    }
+
+    /// The address change will go to, which stays the same until a
+    /// transaction paying it is seen.
+    pub fn change_address(&self) -> Address {
+        let chain = self.chain(Keychain::Change);
+        self.address(chain, self.next_index[chain])
+    }
+
+    /// Whether a BIP158 basic filter may match a block paying or spending
+    /// from the wallet, so the block should be fetched and scanned.
+    pub fn matches_filter(&self, filter: &BlockFilter, block_hash: &[u8; 32]) -> io::Result<bool> {
+        let items: Vec<&[u8]> = self.scripts.keys().map(|s| s.as_bytes()).collect();
+        filter.match_any(block_hash, &items)
+    }
+
+    /// Records the coins `tx` creates for and spends from the wallet, as
+    /// confirmed at `height` or as pending. A confirmed spend abandons any
+    /// pending one of the same coin. Returns the coins a confirmed `tx`
+    /// spent, or `None` if it does not touch the wallet.
+    fn add_tx(
+        &mut self,
+        tx: &Tx,
+        height: Option<u32>,
+    ) -> Result<Option<Vec<WalletUtxo>>, WalletError> {
+        let txid = tx.txid();
+        let is_coinbase = tx.is_coinbase();
+        let mut relevant = false;
+        let mut spent = Vec::new();
+        for tx_in in tx.tx_ins.iter().filter(|_| !is_coinbase) {
+            if height.is_none() {
+                if let Some(utxo) = self.utxos.get_mut(&tx_in.prev_out) {
+                    utxo.spent_by = Some(txid);
+                    relevant = true;
+                }
+            } else if let Some(utxo) = self.utxos.remove(&tx_in.prev_out) {
+                if let Some(conflict) = utxo.spent_by.filter(|&t| t != txid) {
+                    self.abandon(conflict);
+                }
+                spent.push(utxo);
+                relevant = true;
+            }
+        }
+        for (vout, tx_out) in tx.tx_outs.iter().enumerate() {
+            let Some(&(keychain, index)) = self.scripts.get(&tx_out.script_pubkey) else {
+                continue;
+            };
+            relevant = true;
+            let outpoint = OutPoint::new(txid, vout as u32);
+            match self.utxos.get_mut(&outpoint) {
+                // a pending transaction confirming keeps its coins' spends
+                Some(utxo) => utxo.height = height,
+                None => {
+                    self.utxos.insert(
+                        outpoint,
+                        WalletUtxo {
+                            outpoint,
+                            tx_out: tx_out.clone(),
+                            height,
+                            is_coinbase,
+                            keychain,
+                            index,
+                            spent_by: None,
+                        },
+                    );
+                }
+            }
+            self.mark_used(keychain, index)?;
+        }
+        if height.is_some() {
+            self.pending.remove(&txid);
+        } else if relevant {
+            self.pending.insert(txid, tx.clone());
+        }
+        Ok(relevant.then_some(spent))
+    }
+
+    /// Forgets pending `txid`, freeing the coins it spent, and the pending
+    /// transactions spending its outputs.
+    fn abandon(&mut self, txid: [u8; 32]) {
+        let Some(tx) = self.pending.remove(&txid) else {
+            return;
+        };
+        for tx_in in &tx.tx_ins {
+            if let Some(utxo) = self.utxos.get_mut(&tx_in.prev_out) {
+                if utxo.spent_by == Some(txid) {
+                    utxo.spent_by = None;
+                }
+            }
+        }
+        for vout in 0..tx.tx_outs.len() {
+            if let Some(utxo) = self.utxos.remove(&OutPoint::new(txid, vout as u32)) {
+                if let Some(child) = utxo.spent_by {
+                    self.abandon(child);
+                }
+            }
+        }
+    }
+
+    /// Forgets a pending transaction that will not confirm, and any pending
+    /// transactions spending its change, so the coins it spent can be spent
+    /// again, and saves the wallet.
+    pub fn abandon_transaction(&mut self, txid: &[u8; 32]) -> Result<(), WalletError> {
+        if !self.pending.contains_key(txid) {
+            return Err(WalletError::UnknownTransaction);
+        }
+        self.abandon(*txid);
+        self.save()?;
+        Ok(())
+    }
+
+    /// Scans the block at `height`, the next one after the last scanned.
+    pub fn scan_block(&mut self, block: &Block, height: u32) -> Result<(), WalletError> {
+        if self.height.is_some() && block.header.prev_block != self.best_block {
+            return Err(WalletError::UnconnectedBlock);
+        }
+        let mut undo = Vec::new();
+        for tx in &block.txs {
+            if let Some(spent) = self.add_tx(tx, Some(height))? {
+                undo.push((tx.clone(), spent));
+            }
+        }
+        self.connect(block.hash(), height, undo);
+        Ok(())
+    }
+
+    /// Moves the best block past one whose filter did not match, without
+    /// fetching it.
+    pub fn skip_block(&mut self, block_hash: [u8; 32], height: u32) {
+        self.connect(block_hash, height, Vec::new());
+    }
+
+    fn connect(&mut self, block_hash: [u8; 32], height: u32, undo: BlockUndo) {
+        self.undo.insert(height, undo);
+        self.undo.retain(|&h, _| h + MAX_REORG_DEPTH > height);
+        self.best_block = block_hash;
+        self.height = Some(height);
+    }
+
+    /// Undoes the last scanned block, whose parent is `prev_block_hash`, as
+    /// a reorg takes it out of the best chain. Its transactions touching
+    /// the wallet go back to pending, except the coinbase, whose coins are
+    /// dropped along with pending transactions spending them.
+    pub fn disconnect_block(&mut self, prev_block_hash: [u8; 32]) -> Result<(), WalletError> {
+        let height = self.height.ok_or(WalletError::ReorgTooDeep)?;
+        let undo = self.undo.remove(&height).ok_or(WalletError::ReorgTooDeep)?;
+        let created: Vec<WalletUtxo> = self
+            .utxos
+            .values()
+            .filter(|u| u.height == Some(height))
+            .cloned()
+            .collect();
+        for utxo in &created {
+            self.utxos.remove(&utxo.outpoint);
+        }
+        for utxo in undo.iter().flat_map(|(_, spent)| spent) {
+            if utxo.height != Some(height) {
+                let utxo = WalletUtxo {
+                    spent_by: None,
+                    ..utxo.clone()
+                };
+                self.utxos.insert(utxo.outpoint, utxo);
+            }
+        }
+        for (tx, _) in undo.iter().filter(|(tx, _)| !tx.is_coinbase()) {
+            self.add_tx(tx, None)?;
+        }
+        // spends of the block's outputs went with them
+        for (txid, tx) in &self.pending {
+            for tx_in in &tx.tx_ins {
+                if let Some(utxo) = self.utxos.get_mut(&tx_in.prev_out) {
+                    utxo.spent_by = Some(*txid);
+                }
+            }
+        }
+        for utxo in created.iter().filter(|u| u.is_coinbase) {
+            if let Some(child) = utxo.spent_by {
+                self.abandon(child);
+            }
+        }
+        self.best_block = prev_block_hash;
+        self.height = height.checked_sub(1);
+        Ok(())
+    }
+
+    pub fn balance(&self) -> Balance {
+        let mut balance = Balance::default();
+        for utxo in self.utxos.values().filter(|u| u.spent_by.is_none()) {
+            if utxo.height.is_none() {
+                balance.unconfirmed += utxo.tx_out.amount;
+            } else if utxo.is_immature(self.height) {
+                balance.immature += utxo.tx_out.amount;
+            } else {
+                balance.confirmed += utxo.tx_out.amount;
+            }
+        }
+        balance
+    }
+
+    /// Confirmed, unspent coins that are not immature coinbase outputs.
+    fn spendable(&self) -> Vec<Utxo> {
+        self.utxos
+            .values()
+            .filter(|u| u.height.is_some() && u.spent_by.is_none() && !u.is_immature(self.height))
+            .map(|u| {
+                let spend_type = self.spend_type(self.chain(u.keychain));
+                Utxo::new(u.outpoint, u.tx_out.clone(), spend_type)
+            })
+            .collect()
+    }
+
+    /// Creates and signs a transaction paying `recipients` at `fee_rate`,
+    /// selecting from the spendable coins the way Bitcoin Core does. The
+    /// transaction signals replaceability.
+    pub fn create_transaction(
+        &self,
+        recipients: &[(Address, u64)],
+        fee_rate: FeeRate,
+        rng: &mut Rng,
+    ) -> Result<Tx, WalletError> {
+        let signers = self.signers.as_ref().ok_or(WalletError::WatchOnly)?;
+        if recipients
+            .iter()
+            .any(|(a, _)| a.get_network() != self.network)
+        {
+            return Err(WalletError::WrongNetwork);
+        }
+        let change_address = self.change_address();
+        let params = CoinSelectionParams::new(
+            fee_rate,
+            fee_rate,
+            &change_address.script_pubkey(),
+            self.spend_type(self.chain(Keychain::Change)),
+        )
+        .ok_or(WalletError::Builder(BuilderError::ValueOverflow))?;
+        let tx_outs: Vec<TxOut> = recipients
+            .iter()
+            .map(|(a, amount)| TxOut::new(*amount, a.script_pubkey()))
+            .collect();
+        let target = fee_rate
+            .fee_for_weight(estimate_weight(&[], &tx_outs, true))
+            .and_then(|fee| {
+                recipients
+                    .iter()
+                    .try_fold(fee, |sum, (_, amount)| sum.checked_add(*amount))
+            })
+            .ok_or(WalletError::Builder(BuilderError::ValueOverflow))?;
+        let spendable = self.spendable();
+        let selection = select_coins(&spendable, target, &params, rng).ok_or(
+            WalletError::InsufficientFunds {
+                needed: target,
+                available: spendable.iter().map(|u| u.tx_out.amount).sum(),
+            },
+        )?;
+
+        let mut builder = TransactionBuilder::new(fee_rate, change_address);
+        builder.low_r(true).enable_rbf(true);
+        for utxo in selection.selected {
+            builder.add_utxo(utxo);
+        }
+        for (address, amount) in recipients {
+            builder.add_recipient(*address, *amount);
+        }
+        let unsigned = builder.build()?;
+
+        let spent_outputs: Vec<TxOut> = unsigned.inputs.iter().map(|u| u.tx_out.clone()).collect();
+        let mut tx = unsigned.tx;
+        for (index, input) in unsigned.inputs.iter().enumerate() {
+            let coin = &self.utxos[&input.outpoint];
+            let (key, spend_type) = single_key(&signers[self.chain(coin.keychain)])
+                .ok_or(WalletError::UnsupportedDescriptor)?;
+            let key = key
+                .derive_private(coin.index)?
+                .ok_or(WalletError::WatchOnly)?;
+            let point = key.get_point();
+            let h160: [u8; 20] = point.hash160(true).try_into().unwrap();
+            let ecdsa = |sighash: [u8; 32]| {
+                let mut sig = key.sign_low_r(U256::from_big_endian(&sighash)).der();
+                sig.push(SIGHASH_ALL as u8);
+                sig
+            };
+            let tx_in = match spend_type {
+                SpendType::P2pkh => {
+                    let sighash =
+                        legacy_sighash(&tx, index, &input.tx_out.script_pubkey, SIGHASH_ALL);
+                    let script_sig = Script::new(&[
+                        Command::Data(ecdsa(sighash)),
+                        Command::Data(point.compressed_sec().to_vec()),
+                    ]);
+                    (script_sig, Vec::new())
+                }
+                SpendType::P2wpkh | SpendType::P2shP2wpkh => {
+                    let sighash = segwit_v0_sighash(
+                        &tx,
+                        index,
+                        &p2wpkh_script_code(&h160),
+                        input.tx_out.amount,
+                        SIGHASH_ALL,
+                    );
+                    let script_sig = if spend_type == SpendType::P2shP2wpkh {
+                        let redeem_script = Script::p2wpkh(&h160);
+                        Script::new(&[Command::Data(redeem_script.as_bytes().to_vec())])
+                    } else {
+                        Script::default()
+                    };
+                    let witness = vec![ecdsa(sighash), point.compressed_sec().to_vec()];
+                    (script_sig, witness)
+                }
+                SpendType::P2tr => {
+                    let tweaked = tweak_private_key(&key, None)
+                        .ok_or(WalletError::Descriptor(DescriptorError::InvalidKey))?;
+                    let sighash =
+                        taproot_sighash(&tx, index, &spent_outputs, SIGHASH_DEFAULT, None, None)
+                            .unwrap();
+                    let mut aux_rand = [0; 32];
+                    for chunk in aux_rand.chunks_mut(8) {
+                        chunk.copy_from_slice(&rng.next_u64().to_le_bytes());
+                    }
+                    let sig = sign_schnorr(&tweaked, &sighash, &aux_rand);
+                    (Script::default(), vec![sig.to_vec()])
+                }
+                _ => return Err(WalletError::UnsupportedDescriptor),
+            };
+            (tx.tx_ins[index].script_sig, tx.tx_ins[index].witness) = tx_in;
+        }
+        Ok(tx)
+    }
+
+    /// Creates a transaction, hands it to `broadcast` and, once accepted
+    /// there, records it as pending, with its change unconfirmed and the
+    /// coins it spends marked spent, and saves the wallet.
+    pub fn send<F>(
+        &mut self,
+        recipients: &[(Address, u64)],
+        fee_rate: FeeRate,
+        rng: &mut Rng,
+        broadcast: F,
+    ) -> Result<Tx, WalletError>
+    where
+        F: FnOnce(&Tx) -> Result<(), String>,
+    {
+        let tx = self.create_transaction(recipients, fee_rate, rng)?;
+        broadcast(&tx).map_err(WalletError::Broadcast)?;
+        self.add_tx(&tx, None)?;
+        self.save()?;
+        Ok(tx)
+    }
+
+    fn parse<R: Read>(stream: &mut R, path: PathBuf) -> Result<Self, WalletError> {
+        let [version, network, chains] = read_bytes(stream)?;
+        if version != WALLET_VERSION || !(1..=2).contains(&chains) {
+            return Err(WalletError::Corrupt);
+        }
+        let network = network_from_byte(network).ok_or(WalletError::Corrupt)?;
+        let mut descriptors = Vec::new();
+        let mut next_index = Vec::new();
+        let mut derived = Vec::new();
+        for _ in 0..chains {
+            let descriptor =
+                Descriptor::parse(&read_string(stream)?).map_err(|_| WalletError::Corrupt)?;
+            if descriptor.has_private_keys() || single_key(&descriptor).is_none() {
+                return Err(WalletError::Corrupt);
+            }
+            descriptors.push(descriptor);
+            next_index.push(u32::from_le_bytes(read_bytes(stream)?));
+            let mut scripts = Vec::new();
+            for _ in 0..read_varint(stream)? {
+                scripts.push(Script::parse(stream)?);
+            }
+            derived.push(scripts);
+        }
+        let gap_limit = u32::from_le_bytes(read_bytes(stream)?);
+        let mut wallet = Self::new(path, network, descriptors, gap_limit);
+        wallet.next_index = next_index;
+        wallet.derived = derived;
+        let [scanned] = read_bytes(stream)?;
+        let height = u32::from_le_bytes(read_bytes(stream)?);
+        wallet.height = (scanned == 1).then_some(height);
+        wallet.best_block = read_bytes(stream)?;
+        for _ in 0..read_varint(stream)? {
+            let utxo = WalletUtxo::parse(stream)?;
+            wallet.utxos.insert(utxo.outpoint, utxo);
+        }
+        for _ in 0..read_varint(stream)? {
+            let tx = Tx::parse(stream)?;
+            wallet.pending.insert(tx.txid(), tx);
+        }
+        for _ in 0..read_varint(stream)? {
+            let height = u32::from_le_bytes(read_bytes(stream)?);
+            let mut undo = Vec::new();
+            for _ in 0..read_varint(stream)? {
+                let tx = Tx::parse(stream)?;
+                let mut spent = Vec::new();
+                for _ in 0..read_varint(stream)? {
+                    spent.push(WalletUtxo::parse(stream)?);
+                }
+                undo.push((tx, spent));
+            }
+            wallet.undo.insert(height, undo);
+        }
+        Ok(wallet)
+    }
+
+    /// The public descriptors with their derived scripts, then the coins,
+    /// the pending transactions and the undo data.
+    fn serialize(&self) -> Vec<u8> {
+        let mut ret = vec![
+            WALLET_VERSION,
+            network_byte(self.network),
+            self.descriptors.len() as u8,
+        ];
+        for (chain, descriptor) in self.descriptors.iter().enumerate() {
+            let descriptor = descriptor.to_string();
+            ret.extend(encode_varint(descriptor.len() as u64));
+            ret.extend(descriptor.as_bytes());
+            ret.extend(self.next_index[chain].to_le_bytes());
+            ret.extend(encode_varint(self.derived[chain].len() as u64));
+            for script in &self.derived[chain] {
+                ret.extend(script.serialize());
+            }
+        }
+        ret.extend(self.gap_limit.to_le_bytes());
+        ret.push(self.height.is_some() as u8);
+        ret.extend(self.height.unwrap_or(0).to_le_bytes());
+        ret.extend(self.best_block);
+        ret.extend(encode_varint(self.utxos.len() as u64));
+        for utxo in self.utxos.values() {
+            ret.extend(utxo.serialize());
+        }
+        ret.extend(encode_varint(self.pending.len() as u64));
+        for tx in self.pending.values() {
+            ret.extend(tx.serialize());
+        }
+        ret.extend(encode_varint(self.undo.len() as u64));
+        for (height, undo) in &self.undo {
+            ret.extend(height.to_le_bytes());
+            ret.extend(encode_varint(undo.len() as u64));
+            for (tx, spent) in undo {
+                ret.extend(tx.serialize());
+                ret.extend(encode_varint(spent.len() as u64));
+                for utxo in spent {
+                    ret.extend(utxo.serialize());
+                }
+            }
+        }
+        ret
+    }
+
+    /// Writes the wallet to a temporary file and renames it over the old
+    /// one, so a crash leaves either the old or the new state.
+    pub fn save(&self) -> io::Result<()> {
+        let temp = self.path.with_extension("tmp");
+        let mut file = File::create(&temp)?;
+        file.write_all(&self.serialize())?;
+        file.sync_all()?;
+        fs::rename(&temp, &self.path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        address::Payload,
+        bip32::ExtendedPrivateKey,
+        block::MAX_BLOCK_WEIGHT,
+        header_chain::{Accepted, ChainParams, HeaderChain},
+        interpreter::{verify_script, TxChecker, VERIFY_P2SH, VERIFY_TAPROOT, VERIFY_WITNESS},
+        mempool::{BlockTemplate, Mempool, MempoolLimits},
+        miner::{generate_block, COINBASE_RESERVED_WEIGHT},
+        script::Command,
+        store::MemoryStore,
+        tx::TxIn,
+        utxo::UtxoSet,
+    };
+    use sha2::{Digest, Sha256};
+
+    /// A fresh path in the temp directory for the wallet file of a test.
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("{}-{}.dat", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    /// The BIP84 receive and change descriptors of the first account.
+    fn bip84(master: &ExtendedPrivateKey) -> (Descriptor, Descriptor) {
+        let chain = |c| Descriptor::parse(&format!("wpkh({}/84'/1'/0'/{}/*)", master, c)).unwrap();
+        (chain(0), chain(1))
+    }
+
+    #[test]
+    fn test_wallet() {
+        let path = temp_path("wallet");
+        let master = ExtendedPrivateKey::new_master(&[7; 32], Network::Regtest).unwrap();
+        let (receive, change_descriptor) = bip84(&master);
+        let mut wallet = Wallet::create(
+            &path,
+            &receive,
+            Some(&change_descriptor),
+            Network::Regtest,
+            1,
+        )
+        .unwrap();
+        assert!(!wallet.is_watch_only());
+        assert_eq!(
+            Wallet::create(&path, &receive, None, Network::Regtest, 1).err(),
+            Some(WalletError::Io(io::ErrorKind::AlreadyExists))
+        );
+        // the file holds no private keys
+        let raw = fs::read(&path).unwrap();
+        assert!(!raw.windows(4).any(|w| w == b"tprv"));
+        let address = wallet.new_address().unwrap();
+        assert_eq!(address.get_network(), Network::Regtest);
+        assert!(address.to_string().starts_with("bcrt1q"));
+
+        // mine the wallet a coinbase, then mature it with blocks paying a
+        // P2WSH of OP_1
+        let witness_script = Script::new(&[Command::int(1)]);
+        let h256 = Sha256::digest(witness_script.as_bytes()).into();
+        let other = Address::new(Payload::P2wsh(h256), Network::Regtest);
+        let mut chain = HeaderChain::new(ChainParams::new(Network::Regtest));
+        let mut utxos = UtxoSet::open(MemoryStore::new()).unwrap();
+        utxos
+            .connect_block(&Block::genesis(Network::Regtest), chain.get_params())
+            .unwrap();
+        let mut mempool = Mempool::new(MempoolLimits::default());
+        for i in 0..101 {
+            let to = if i == 0 { &address } else { &other };
+            let template = BlockTemplate::default();
+            let block = generate_block(&chain, &template, to, chain.tip().timestamp + 600);
+            assert_eq!(chain.add_header(block.header), Ok(Accepted::Extended));
+            utxos.connect_block(&block, chain.get_params()).unwrap();
+            wallet.scan_block(&block, chain.height()).unwrap();
+            if i == 99 {
+                let balance = wallet.balance();
+                assert_eq!(balance.immature, 50_0000_0000);
+                assert_eq!(balance.confirmed, 0);
+            }
+        }
+        assert_eq!(wallet.balance().confirmed, 50_0000_0000);
+        assert_eq!(
+            wallet
+                .utxos()
+                .next()
+                .unwrap()
+                .confirmations(wallet.height()),
+            101
+        );
+
+        let change = wallet.change_address();
+        let mut rng = Rng::new(1);
+        let tx = wallet
+            .send(
+                &[(other, 10_0000_0000)],
+                FeeRate::from_sat_per_vb(2).unwrap(),
+                &mut rng,
+                |tx| {
+                    mempool
+                        .accept(tx.clone(), &utxos, &chain)
+                        .map(|_| ())
+                        .map_err(|e| format!("{:?}", e))
+                },
+            )
+            .unwrap();
+        let fee = 50_0000_0000 - tx.tx_outs.iter().map(|o| o.amount).sum::<u64>();
+        assert_eq!(
+            wallet.balance(),
+            Balance {
+                confirmed: 0,
+                unconfirmed: 40_0000_0000 - fee,
+                immature: 0,
+            }
+        );
+        // the change address moved on once it was paid
+        assert_ne!(wallet.change_address(), change);
+        assert_eq!(wallet.pending_transactions().collect::<Vec<_>>(), [&tx]);
+
+        // the send was saved, and the reopened wallet can't sign until
+        // unlocked with the same descriptors
+        let mut opened = Wallet::open(&path).unwrap();
+        assert_eq!(opened.balance(), wallet.balance());
+        assert_eq!(opened.pending_transactions().collect::<Vec<_>>(), [&tx]);
+        assert!(opened.is_watch_only());
+        assert_eq!(
+            opened.create_transaction(
+                &[(other, 1000)],
+                FeeRate::from_sat_per_vb(2).unwrap(),
+                &mut rng
+            ),
+            Err(WalletError::WatchOnly)
+        );
+        assert_eq!(
+            opened.unlock(&change_descriptor, Some(&receive)),
+            Err(WalletError::DescriptorMismatch)
+        );
+        assert_eq!(
+            opened.unlock(&opened.descriptors()[0].clone(), Some(&change_descriptor)),
+            Err(WalletError::DescriptorMismatch)
+        );
+        opened.unlock(&receive, Some(&change_descriptor)).unwrap();
+        assert!(!opened.is_watch_only());
+        // abandoning frees the spent coin and drops the change
+        opened.abandon_transaction(&tx.txid()).unwrap();
+        assert_eq!(opened.pending_transactions().count(), 0);
+        assert_eq!(opened.balance().confirmed, 50_0000_0000);
+        assert_eq!(
+            opened.abandon_transaction(&tx.txid()),
+            Err(WalletError::UnknownTransaction)
+        );
+        assert_eq!(
+            wallet.create_transaction(
+                &[(other, 10_0000_0000)],
+                FeeRate::from_sat_per_vb(2).unwrap(),
+                &mut rng
+            ),
+            Err(WalletError::InsufficientFunds {
+                needed: 10_0000_0000 + 2 * 53,
+                available: 0
+            })
+        );
+
+        let template = mempool.block_template(MAX_BLOCK_WEIGHT - COINBASE_RESERVED_WEIGHT);
+        let block = generate_block(&chain, &template, &other, 0);
+        let spent = [address.script_pubkey()];
+        let filter = BlockFilter::basic(&block, &spent);
+        assert!(wallet.matches_filter(&filter, &block.hash()).unwrap());
+        let empty = BlockFilter::basic(&Block::genesis(Network::Regtest), &[]);
+        let genesis_hash = Block::genesis(Network::Regtest).hash();
+        assert!(!wallet.matches_filter(&empty, &genesis_hash).unwrap());
+        wallet.scan_block(&block, 102).unwrap();
+        assert_eq!(wallet.balance().confirmed, 40_0000_0000 - fee);
+        assert_eq!(wallet.pending_transactions().count(), 0);
+        assert_eq!(
+            wallet.scan_block(&block, 103),
+            Err(WalletError::UnconnectedBlock)
+        );
+
+        // a reorg puts the transaction back to pending
+        wallet.disconnect_block(block.header.prev_block).unwrap();
+        assert_eq!(wallet.height(), Some(101));
+        assert_eq!(wallet.get_best_block(), block.header.prev_block);
+        assert_eq!(wallet.pending_transactions().collect::<Vec<_>>(), [&tx]);
+        assert_eq!(
+            wallet.balance(),
+            Balance {
+                confirmed: 0,
+                unconfirmed: 40_0000_0000 - fee,
+                immature: 0,
+            }
+        );
+        wallet.scan_block(&block, 102).unwrap();
+        assert_eq!(wallet.balance().confirmed, 40_0000_0000 - fee);
+        wallet.save().unwrap();
+
+        let opened = Wallet::open(&path).unwrap();
+        assert_eq!(opened.balance(), wallet.balance());
+        assert_eq!(opened.get_network(), Network::Regtest);
+        assert_eq!(opened.get_best_block(), block.hash());
+        assert_eq!(opened.change_address(), wallet.change_address());
+        assert_eq!(
+            opened.utxos().collect::<Vec<_>>(),
+            wallet.utxos().collect::<Vec<_>>()
+        );
+
+        // undo data is kept for the last MAX_REORG_DEPTH blocks
+        let mut opened = opened;
+        for _ in 0..MAX_REORG_DEPTH {
+            opened.disconnect_block([0; 32]).unwrap();
+        }
+        assert_eq!(opened.height(), Some(2));
+        assert_eq!(
+            opened.disconnect_block([0; 32]),
+            Err(WalletError::ReorgTooDeep)
+        );
+
+        // a truncated file claiming a huge descriptor is corrupt, without
+        // allocating what it claims
+        let mut raw = vec![WALLET_VERSION, 2, 1, 0xff];
+        raw.extend(u64::MAX.to_le_bytes());
+        raw.extend(b"wpkh(");
+        fs::write(&path, raw).unwrap();
+        assert_eq!(Wallet::open(&path).err(), Some(WalletError::Corrupt));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_spend_types() {
+        let master = ExtendedPrivateKey::new_master(&[9; 32], Network::Regtest).unwrap();
+        let other = Address::new(Payload::P2wsh([5; 32]), Network::Regtest);
+        let flags = VERIFY_P2SH | VERIFY_WITNESS | VERIFY_TAPROOT;
+        for (i, wrapper) in ["pkh(KEY)", "sh(wpkh(KEY))", "tr(KEY)"].iter().enumerate() {
+            let path = temp_path(&format!("wallet-spend-{}", i));
+            let descriptor =
+                Descriptor::parse(&wrapper.replace("KEY", &format!("{}/1/*", master))).unwrap();
+            let mut wallet = Wallet::create(&path, &descriptor, None, Network::Regtest, 1).unwrap();
+            // with one descriptor change goes to its next address
+            let address = wallet.new_address().unwrap();
+            assert_eq!(wallet.change_address(), wallet.address(0, 1));
+
+            let funding = Tx::new(
+                2,
+                vec![TxIn::new(OutPoint::new([1; 32], 0), 0)],
+                vec![TxOut::new(100_000, address.script_pubkey())],
+                0,
+            );
+            wallet.add_tx(&funding, Some(1)).unwrap();
+            let mut rng = Rng::new(2);
+            let tx = wallet
+                .create_transaction(
+                    &[(other, 50_000)],
+                    FeeRate::from_sat_per_vb(1).unwrap(),
+                    &mut rng,
+                )
+                .unwrap();
+            let spent_outputs = [funding.tx_outs[0].clone()];
+            let checker = TxChecker::with_spent_outputs(&tx, 0, &spent_outputs);
+            assert_eq!(
+                verify_script(
+                    &tx.tx_ins[0].script_sig,
+                    &spent_outputs[0].script_pubkey,
+                    &tx.tx_ins[0].witness,
+                    flags,
+                    &checker
+                ),
+                Ok(()),
+                "{}",
+                wrapper
+            );
+            fs::remove_file(&path).unwrap();
+        }
+
+        let path = temp_path("wallet-unsupported");
+        let descriptor = Descriptor::parse(&format!("wsh(pk({}/*))", master)).unwrap();
+        assert_eq!(
+            Wallet::create(&path, &descriptor, None, Network::Regtest, 1).err(),
+            Some(WalletError::UnsupportedDescriptor)
+        );
+    }
+
+    #[test]
+    fn test_wallet_utxo_serialization() {
+        let utxo = WalletUtxo {
+            outpoint: OutPoint::new([1; 32], 2),
+            tx_out: TxOut::new(5000, Script::p2wpkh(&[3; 20])),
+            height: Some(100),
+            is_coinbase: true,
+            keychain: Keychain::Change,
+            index: 7,
+            spent_by: None,
+        };
+        let raw = utxo.serialize();
+        assert_eq!(WalletUtxo::parse(&mut Cursor::new(&raw)).unwrap(), utxo);
+        assert!(utxo.is_immature(Some(199)));
+        assert!(!utxo.is_immature(Some(200)));
+        let unconfirmed = WalletUtxo {
+            height: None,
+            spent_by: Some([4; 32]),
+            ..utxo
+        };
+        assert_eq!(unconfirmed.confirmations(Some(200)), 0);
+        let raw = unconfirmed.serialize();
+        assert_eq!(
+            WalletUtxo::parse(&mut Cursor::new(&raw)).unwrap(),
+            unconfirmed
+        );
+    }
+}